@@ -0,0 +1,57 @@
+//! Example: step fan-out statistics from the pipeline IR
+//!
+//! Run with `cargo run --example pipeline-ir-fanout --features ir`.
+
+use rust_buildkite::ir::{PipelineIr, StepKind};
+use rust_buildkite::pipeline_ir;
+
+fn main() {
+    let ir = pipeline_ir! {
+        steps: [
+            command(cmd!("cargo build --release")).label("Build").key("build"),
+            group("Tests")
+                .key("tests")
+                .steps([
+                    command(cmd!("cargo test --lib")).label("Unit").key("unit").depends_on("build"),
+                    command(cmd!("cargo test --doc")).label("Doc").key("doc").depends_on("build"),
+                    command(cmd!("cargo clippy")).label("Lint").key("lint").depends_on("build")
+                ]),
+            block("Release?").key("approve").depends_on("tests"),
+            command(cmd!("cargo publish")).label("Publish").depends_on("approve")
+        ]
+    };
+
+    print_fanout(&ir);
+}
+
+fn print_fanout(ir: &PipelineIr) {
+    let steps = ir.all_steps();
+    let commands: usize = steps.iter().map(|step| step.commands.len()).sum();
+    let groups = steps
+        .iter()
+        .filter(|step| step.kind == StepKind::Group)
+        .count();
+    println!(
+        "{} steps ({} groups, {} commands)",
+        steps.len(),
+        groups,
+        commands
+    );
+
+    let mut fanout: Vec<(&str, usize)> = steps
+        .iter()
+        .filter_map(|step| step.key.as_deref())
+        .map(|key| (key, ir.dependents(key).len()))
+        .collect();
+    fanout.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+    println!("fan-out by key:");
+    for (key, dependents) in &fanout {
+        println!("  {key:<10} {dependents}");
+    }
+    let roots = steps
+        .iter()
+        .filter(|step| step.depends_on.is_empty())
+        .count();
+    println!("steps without depends_on: {roots}");
+}
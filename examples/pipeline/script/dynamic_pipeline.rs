@@ -28,6 +28,7 @@ fn main() {
         },
         expect_env: [SHELL_ENV, BUILDKITE_ENV],
         expect_paths: ["./scripts/deploy.sh"],
+        lints: { parallelism_without_sharding: allow },
         steps: [
             command {
                 command: cmd!("cargo fmt --check"),
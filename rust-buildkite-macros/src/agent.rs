@@ -0,0 +1,182 @@
+//! `buildkite-agent` calls found in command text.
+//!
+//! Commands are scanned word by word rather than parsed as shell, which is
+//! enough for the checks built on this: what meta-data steps read and write.
+//! Quotes and the `$(...)` or backticks of a command substitution around a
+//! word are stripped, and a call ends at a shell operator or at the end of
+//! the substitution it's in.
+
+use std::ops::Range;
+
+/// The agent binary, as written in commands
+const AGENT: &str = "buildkite-agent";
+
+/// Flags of agent subcommands that take the following word as their value
+const VALUE_FLAGS: &[&str] = &["default", "job", "step", "build"];
+
+/// Words that end one shell command and start the next
+const OPERATORS: &[&str] = &[";", "&&", "||", "|", "&"];
+
+/// One `buildkite-agent <command> <subcommand> ...` call, such as
+/// `buildkite-agent meta-data get release-version`.
+pub struct AgentCall<'a> {
+    /// Agent command, e.g. `meta-data`
+    pub command: &'a str,
+    /// Subcommand, e.g. `get`
+    pub subcommand: &'a str,
+    /// Positional arguments with their byte ranges in the command text.
+    /// Flags and the values they take are left out.
+    pub args: Vec<(&'a str, Range<usize>)>,
+}
+
+impl<'a> AgentCall<'a> {
+    /// The key read by `buildkite-agent meta-data get <key>`.
+    pub fn metadata_get(&self) -> Option<&(&'a str, Range<usize>)> {
+        self.metadata_key("get")
+    }
+
+    /// The key written by `buildkite-agent meta-data set <key> ...`.
+    pub fn metadata_set(&self) -> Option<&(&'a str, Range<usize>)> {
+        self.metadata_key("set")
+    }
+
+    fn metadata_key(&self, subcommand: &str) -> Option<&(&'a str, Range<usize>)> {
+        if self.command == "meta-data" && self.subcommand == subcommand {
+            self.args.first()
+        } else {
+            None
+        }
+    }
+}
+
+/// A word of the command with the quoting around it stripped.
+struct Word<'a> {
+    text: &'a str,
+    range: Range<usize>,
+    /// Whether the raw word ended a command, e.g. `key)` or `key;`
+    ends_command: bool,
+}
+
+fn words(text: &str) -> Vec<Word<'_>> {
+    let mut words = Vec::new();
+    let mut start = None;
+    for (index, c) in text.char_indices().chain([(text.len(), ' ')]) {
+        match (c.is_whitespace(), start) {
+            (false, None) => start = Some(index),
+            (true, Some(from)) => {
+                words.push(word(text, from..index));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    words
+}
+
+fn word(text: &str, range: Range<usize>) -> Word<'_> {
+    let raw = &text[range.clone()];
+    let body = raw.trim_end_matches(['"', '\'', ')', '`', ';']);
+    let closers = &raw[body.len()..];
+    // Start after the last `$(` or backtick, e.g. in `V=$(buildkite-agent`
+    let opened = body.rfind(['(', '`']).map_or(0, |i| i + 1);
+    let unquoted = body[opened..].trim_start_matches(['"', '\'', '$']);
+    let start = range.start + body.len() - unquoted.len();
+    Word {
+        text: unquoted,
+        range: start..start + unquoted.len(),
+        ends_command: closers.contains([')', '`', ';']) || OPERATORS.contains(&raw),
+    }
+}
+
+/// Every `buildkite-agent` call in `text`, in order.
+pub fn calls(text: &str) -> Vec<AgentCall<'_>> {
+    let words = words(text);
+    let mut calls = Vec::new();
+    let mut index = 0;
+    while index < words.len() {
+        if words[index].text != AGENT || words[index].ends_command {
+            index += 1;
+            continue;
+        }
+        let mut rest = words[index + 1..].iter();
+        let mut positional = Vec::new();
+        while let Some(word) = rest.next() {
+            if OPERATORS.contains(&word.text) {
+                break;
+            }
+            match word.text.strip_prefix("--") {
+                Some(flag) if VALUE_FLAGS.contains(&flag) => {
+                    if word.ends_command || rest.next().is_some_and(|value| value.ends_command) {
+                        break;
+                    }
+                }
+                Some(_) => {}
+                None => positional.push((word.text, word.range.clone())),
+            }
+            if word.ends_command {
+                break;
+            }
+        }
+        index = words.len() - rest.as_slice().len();
+        let mut positional = positional.into_iter();
+        if let (Some((command, _)), Some((subcommand, _))) = (positional.next(), positional.next())
+        {
+            calls.push(AgentCall {
+                command,
+                subcommand,
+                args: positional.collect(),
+            });
+        }
+    }
+    calls
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gets(text: &str) -> Vec<&str> {
+        calls(text)
+            .iter()
+            .filter_map(|call| call.metadata_get().map(|(key, _)| *key))
+            .collect()
+    }
+
+    #[test]
+    fn test_metadata_get() {
+        assert_eq!(gets("buildkite-agent meta-data get release"), ["release"]);
+        assert_eq!(
+            gets(r#"./deploy.sh "$(buildkite-agent meta-data get 'release-name')""#),
+            ["release-name"]
+        );
+        assert_eq!(
+            gets("buildkite-agent meta-data get --default none env && echo done"),
+            ["env"]
+        );
+        assert_eq!(
+            gets("V=`buildkite-agent meta-data get a`; buildkite-agent meta-data get b"),
+            ["a", "b"]
+        );
+        assert!(gets("buildkite-agent meta-data exists release").is_empty());
+        assert!(gets("echo buildkite-agent").is_empty());
+    }
+
+    #[test]
+    fn test_ranges_point_at_the_key() {
+        let text = r#"echo "$(buildkite-agent meta-data get release)""#;
+        let calls = calls(text);
+        let (key, range) = calls[0].metadata_get().unwrap();
+        assert_eq!(&text[range.clone()], *key);
+        assert_eq!(*key, "release");
+    }
+
+    #[test]
+    fn test_metadata_set() {
+        let calls = calls("buildkite-agent meta-data set 'version' '1.4'");
+        assert_eq!(
+            calls[0].metadata_set().map(|(key, _)| *key),
+            Some("version")
+        );
+        assert_eq!(calls[0].args.len(), 2);
+    }
+}
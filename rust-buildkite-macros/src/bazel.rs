@@ -2,14 +2,77 @@
 
 use crate::debug::debug_log;
 use crate::targets;
+use rust_buildkite_validation::bazel::flags;
+use rust_buildkite_validation::{
+    BAZEL_WORKSPACE_MARKERS, find_bazel_workspace_root, is_bazel_workspace_root,
+};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::fs;
-use std::path::Path;
-use std::sync::Mutex;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
 use std::time::{Instant, UNIX_EPOCH};
 
-static FLAGS_CACHE: Mutex<Option<FlagsCache>> = Mutex::new(None);
+pub use rust_buildkite_validation::bazel::flags::check_flag_for_verb;
+pub use rust_buildkite_validation::bazel::query::{
+    expression_from_args, is_query_verb, validate_query_expression,
+};
+
+static FLAGS_CACHE: Mutex<Option<HashMap<PathBuf, FlagsCache>>> = Mutex::new(None);
+static QUERY_CACHE: Mutex<Option<HashMap<PathBuf, QueryCache>>> = Mutex::new(None);
+static RC_CONFIGS: Mutex<Option<HashMap<PathBuf, BTreeSet<String>>>> = Mutex::new(None);
+static INFO_CACHE: Mutex<Option<HashMap<(PathBuf, String), String>>> = Mutex::new(None);
+
+const FLAGS_CACHE_FILE: &str = "bazel-flags-cache.json";
+const QUERY_CACHE_FILE: &str = "bazel-query-cache.json";
+
+/// Keys `bazel info` prints. Bazel adds keys over time, so others only warn.
+pub const INFO_KEYS: &[&str] = &[
+    "bazel-bin",
+    "bazel-genfiles",
+    "bazel-testlogs",
+    "build-language",
+    "character-encoding",
+    "client-env",
+    "command_log",
+    "committed-heap-size",
+    "default-package-path",
+    "execution_root",
+    "gc-count",
+    "gc-time",
+    "install_base",
+    "java-home",
+    "java-runtime",
+    "java-vm",
+    "local_resources",
+    "max-heap-size",
+    "output_base",
+    "output_path",
+    "package_path",
+    "peak-heap-size",
+    "release",
+    "repository_cache",
+    "server_log",
+    "server_pid",
+    "starlark-semantics",
+    "used-heap-size",
+    "used-heap-size-after-gc",
+    "workspace",
+];
+
+/// Files whose contents decide what `bazel query` can return.
+const BUILD_INPUT_FILES: &[&str] = &[
+    "BUILD",
+    "BUILD.bazel",
+    "WORKSPACE",
+    "WORKSPACE.bazel",
+    "MODULE.bazel",
+    "REPO.bazel",
+];
+
+/// Directories of build output or vendored dependencies, which never hold
+/// packages of the workspace.
+const SKIPPED_DIRS: &[&str] = &["target", "node_modules"];
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 struct FlagsCache {
@@ -23,39 +86,88 @@ struct FlagsCacheEntry {
     canonical_flags: Vec<String>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+struct QueryCache {
+    /// Hash of the workspace's BUILD, WORKSPACE, MODULE.bazel, REPO.bazel and .bzl files
+    workspace_hash: u64,
+    /// Query expression -> rule label -> rule kind
+    entries: HashMap<String, HashMap<String, String>>,
+}
+
+/// Whether the on-disk Bazel caches are used; `RUST_BUILDKITE_BAZEL_CACHE=off`
+/// disables them.
+fn disk_cache_enabled() -> bool {
+    !matches!(
+        std::env::var("RUST_BUILDKITE_BAZEL_CACHE").as_deref(),
+        Ok("off")
+    )
+}
+
+/// The program compile-time checks run for `binary`, the pipeline's
+/// `bazel_binary`. A relative path is resolved against the workspace, where
+/// the emitted command runs it.
+pub fn program(binary: &str, workspace: &Path) -> PathBuf {
+    if binary.contains('/') {
+        workspace.join(binary.strip_prefix("./").unwrap_or(binary))
+    } else {
+        PathBuf::from(binary)
+    }
+}
+
+/// A command running [`program`] in `workspace`.
+pub fn command(workspace: &Path, binary: &str) -> std::process::Command {
+    let mut cmd = std::process::Command::new(program(binary, workspace));
+    cmd.current_dir(workspace);
+    cmd
+}
+
 /// Find Bazel workspace from env vars.
 pub fn find_bazel_workspace_from_env() -> Result<std::path::PathBuf, String> {
     let (workspace, _) = find_bazel_workspace_and_script_dir()?;
     Ok(workspace)
 }
 
-/// Find Bazel workspace and script directory.
+/// Find Bazel workspace and script directory. A `workspace` set on the step
+/// or pipeline is resolved against the repository root; otherwise the
+/// nearest workspace above the script directory is used.
 pub fn find_bazel_workspace_and_script_dir()
 -> Result<(std::path::PathBuf, std::path::PathBuf), String> {
-    use std::path::PathBuf;
-
     let start = std::env::var("RUST_SCRIPT_BASE_PATH")
         .or_else(|_| std::env::var("CARGO_MANIFEST_DIR"))
         .map(PathBuf::from)
         .map_err(|_| "Neither RUST_SCRIPT_BASE_PATH nor CARGO_MANIFEST_DIR is set".to_string())?;
 
-    let script_dir = start.clone();
-
-    let mut dir = start.as_path();
-    loop {
-        if dir.join("MODULE.bazel").exists() || dir.join("WORKSPACE").exists() {
-            return Ok((dir.to_path_buf(), script_dir));
-        }
-        match dir.parent() {
-            Some(parent) => dir = parent,
-            None => break,
+    if let Some(relative) = crate::bazel_defaults::workspace() {
+        let workspace = repo_root(&start).join(&relative);
+        if !is_bazel_workspace_root(&workspace) {
+            return Err(format!(
+                "workspace '{}' is not a bazel workspace (looked for {} in {})",
+                relative,
+                BAZEL_WORKSPACE_MARKERS.join(", "),
+                workspace.display()
+            ));
         }
+        return Ok((workspace, start));
     }
 
-    Err(format!(
-        "Could not find bazel workspace (MODULE.bazel or WORKSPACE) above {}",
-        start.display()
-    ))
+    match find_bazel_workspace_root(&start) {
+        Some(workspace) => Ok((workspace, start)),
+        None => Err(format!(
+            "Could not find bazel workspace (looked for {} in {} and its parents)",
+            BAZEL_WORKSPACE_MARKERS.join(", "),
+            start.display()
+        )),
+    }
+}
+
+/// The repository root `workspace:` paths are relative to: the nearest
+/// directory above `start` holding `.git`, or `start` itself outside a repo.
+fn repo_root(start: &Path) -> PathBuf {
+    start
+        .ancestors()
+        .find(|dir| dir.join(".git").exists())
+        .unwrap_or(start)
+        .to_path_buf()
 }
 
 /// Validate targets using BUILD file parsing.
@@ -78,7 +190,13 @@ pub fn fast_validate_targets(
         debug_log!("bazel", "Validating target: {}", target);
         let start = Instant::now();
 
-        if let Err(e) = targets::validate_target_exists(workspace, &target, current_pkg) {
+        let checked = targets::validate_target_exists(workspace, &target, current_pkg);
+        if let Ok((pkg, _)) = targets::resolve_label(&target, current_pkg)
+            && let Ok(build_file) = targets::find_build_file(workspace, &pkg)
+        {
+            crate::track::track_file(&build_file);
+        }
+        if let Err(e) = checked {
             debug_log!(
                 "bazel",
                 "Target not found in {:.2?}: {}",
@@ -103,26 +221,329 @@ pub fn validate_with_query(
     verb: &str,
     args: &[&str],
     workspace: &Path,
+    binary: &str,
     current_pkg: Option<&str>,
 ) -> Result<HashMap<String, QueryResult>, String> {
+    let query = TargetQuery {
+        verb,
+        args: args.to_vec(),
+        current_pkg,
+    };
+    validate_queries(&[query], workspace, binary)
+        .pop()
+        .unwrap_or_else(|| Ok(HashMap::new()))
+}
+
+/// The targets of one bazel command, checked as part of a combined query.
+pub struct TargetQuery<'a> {
+    pub verb: &'a str,
+    pub args: Vec<&'a str>,
+    pub current_pkg: Option<&'a str>,
+}
+
+/// Validate the targets of several bazel commands with a single `bazel query`
+/// over the union of their patterns, run with `binary`. Returns one result
+/// per query, in order.
+pub fn validate_queries(
+    queries: &[TargetQuery],
+    workspace: &Path,
+    binary: &str,
+) -> Vec<Result<HashMap<String, QueryResult>, String>> {
     if crate::should_skip_comptime_validation() {
-        return Ok(HashMap::new());
+        return queries.iter().map(|_| Ok(HashMap::new())).collect();
     }
-    use std::process::Command;
 
-    let target_args = targets::extract_targets_from_args(args);
-    if target_args.is_empty() {
-        return Ok(HashMap::new());
+    let resolved: Vec<Vec<String>> = queries
+        .iter()
+        .map(|q| resolve_query_targets(&q.args, q.current_pkg))
+        .collect();
+    // Each single label brings its package's rules along, so a missing
+    // target can be matched against its siblings without another query
+    let packages: Vec<String> = resolved
+        .iter()
+        .flatten()
+        .filter(|t| !targets::is_wildcard_pattern(t) && !targets::is_external_repo(t))
+        .filter_map(|t| split_label(t))
+        .map(|(package, _)| format!("{}:all", package))
+        .collect();
+    let mut patterns: Vec<&str> = Vec::new();
+    for pattern in resolved.iter().flatten().chain(&packages) {
+        if !patterns.contains(&pattern.as_str()) {
+            patterns.push(pattern);
+        }
+    }
+    if patterns.is_empty() {
+        return queries.iter().map(|_| Ok(HashMap::new())).collect();
     }
 
-    let resolved_targets: Vec<String> = target_args
+    debug_log!(
+        "bazel",
+        "Validating {} bazel commands with one query over {} patterns",
+        queries.len(),
+        patterns.len()
+    );
+    let labels = match cached_query(workspace, binary, &patterns.join(" + ")) {
+        Ok(labels) => labels,
+        Err(e) => return queries.iter().map(|_| Err(e.clone())).collect(),
+    };
+
+    queries
         .iter()
+        .zip(&resolved)
+        .map(|(query, targets)| {
+            let mut results = HashMap::new();
+            for target in targets {
+                let matched: Vec<(&String, &String)> = labels
+                    .iter()
+                    .filter(|(label, _)| pattern_matches(target, label))
+                    .collect();
+                if matched.is_empty() && !target.starts_with('@') {
+                    return Err(missing_target_error(target, &labels));
+                }
+                for (label, kind) in matched {
+                    results.insert(
+                        label.clone(),
+                        QueryResult {
+                            kind: Some(kind.clone()),
+                        },
+                    );
+                }
+            }
+            check_query_results(query.verb, &results)?;
+            Ok(results)
+        })
+        .collect()
+}
+
+/// The query expression for the targets a command selects: its patterns
+/// joined with `+`, minus any `-//pattern` subtractions, on either side of a
+/// `--`. `None` when the command names no targets.
+fn target_universe(args: &[&str], current_pkg: Option<&str>) -> Option<String> {
+    let mut included = Vec::new();
+    let mut excluded = Vec::new();
+    for word in args.iter().filter(|w| **w != "--") {
+        match word.strip_prefix('-') {
+            Some(pattern)
+                if pattern.starts_with("//")
+                    || pattern.starts_with('@')
+                    || pattern.starts_with(':') =>
+            {
+                excluded.push(pattern)
+            }
+            Some(_) => {}
+            None => included.push(*word),
+        }
+    }
+    let included = resolve_query_targets(&included, current_pkg);
+    if included.is_empty() {
+        return None;
+    }
+    let mut universe = included.join(" + ");
+    for pattern in resolve_query_targets(&excluded, current_pkg) {
+        universe.push_str(" - ");
+        universe.push_str(&pattern);
+    }
+    Some(universe)
+}
+
+/// Positive `--test_tag_filters` tags that none of the command's tests
+/// carry. Negative (`-tag`) filters never select anything on their own and
+/// are skipped. Queries go through the query cache, so an unchanged
+/// workspace needs no bazel call; a failed query reports nothing.
+pub fn unmatched_test_tags(
+    args: &[&str],
+    workspace: &Path,
+    binary: &str,
+    current_pkg: Option<&str>,
+) -> Vec<String> {
+    let mut tags = Vec::new();
+    let mut words = args.iter().take_while(|a| **a != "--");
+    while let Some(word) = words.next() {
+        let filters = match word.strip_prefix("--test_tag_filters") {
+            Some("") => match words.next() {
+                Some(value) => *value,
+                None => break,
+            },
+            Some(value) => match value.strip_prefix('=') {
+                Some(value) => value,
+                None => continue,
+            },
+            None => continue,
+        };
+        tags.extend(
+            filters
+                .trim_matches(|c| c == '\'' || c == '"')
+                .split(',')
+                .map(|tag| tag.trim().trim_start_matches('+'))
+                .filter(|tag| !tag.is_empty() && !tag.starts_with('-') && !tag.contains('$'))
+                .map(str::to_string),
+        );
+    }
+    if tags.is_empty() {
+        return Vec::new();
+    }
+    let Some(universe) = target_universe(args, current_pkg) else {
+        return Vec::new();
+    };
+
+    tags.into_iter()
+        .filter(|tag| {
+            // tags print as `[a, b]`, so match a whole list element
+            let expr = format!(
+                "attr(tags, '[\\[ ]{}[,\\]]', tests({}))",
+                regex_escape(tag),
+                universe
+            );
+            cached_query(workspace, binary, &expr).is_ok_and(|labels| labels.is_empty())
+        })
+        .collect()
+}
+
+fn regex_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Split `//pkg:name` (or the `//pkg/name` shorthand) into package and name.
+fn split_label(label: &str) -> Option<(&str, &str)> {
+    if !label.starts_with("//") {
+        return None;
+    }
+    match label.rsplit_once(':') {
+        Some((package, name)) => Some((package, name)),
+        None => Some((label, label.rsplit('/').next()?)),
+    }
+}
+
+/// "no such target" for `target`, with hints drawn from the rules the same
+/// query returned for its package: a forgotten `_test` suffix, a file name
+/// used in place of a rule, or up to three close names.
+fn missing_target_error(target: &str, labels: &HashMap<String, String>) -> String {
+    let mut message = format!("no such target '{}'", target);
+    let Some((package, name)) = split_label(target) else {
+        return message;
+    };
+    let siblings: Vec<&str> = labels
+        .keys()
+        .filter_map(|label| label.strip_prefix(package)?.strip_prefix(':'))
+        .collect();
+    if siblings.is_empty() {
+        return message;
+    }
+
+    let test_name = format!("{}_test", name);
+    if siblings.contains(&test_name.as_str()) {
+        message.push_str(&format!(
+            "; did you mean {}:{}? Test rules usually end in `_test`",
+            package, test_name
+        ));
+        return message;
+    }
+
+    let mut wanted = name;
+    if let Some((stem, extension)) = name.rsplit_once('.')
+        && !stem.is_empty()
+        && !extension.is_empty()
+    {
+        message.push_str(&format!(
+            "; '{}' looks like a file name, but bazel commands take rule names",
+            name
+        ));
+        if siblings.contains(&stem) {
+            message.push_str(&format!(". Did you mean {}:{}?", package, stem));
+            return message;
+        }
+        wanted = stem;
+    }
+
+    let limit = (wanted.chars().count() / 3).max(2);
+    let close: Vec<String> = crate::closest_matches(wanted, siblings.iter().copied(), 3)
+        .into_iter()
+        .filter(|s| crate::edit_distance(wanted, s) <= limit)
+        .map(|s| format!("{}:{}", package, s))
+        .collect();
+    if !close.is_empty() {
+        message.push_str(&format!("; did you mean {}?", close.join(", ")));
+    }
+    message
+}
+
+/// Test targets selected by `patterns`, each weighted by its `size`
+/// attribute (small 1, medium 5, large 15, enormous 60, in line with the
+/// default test timeouts) so shards can be balanced by expected runtime.
+pub fn query_test_targets(
+    patterns: &str,
+    workspace: &Path,
+    binary: &str,
+    current_pkg: Option<&str>,
+) -> Result<Vec<(String, u32)>, String> {
+    let words: Vec<&str> = patterns.split_whitespace().collect();
+    let Some(universe) = target_universe(&words, current_pkg) else {
+        return Err(format!("no target patterns in '{}'", patterns));
+    };
+    let tests_expr = format!("tests({})", universe);
+
+    let mut weights: HashMap<String, u32> = cached_query(workspace, binary, &tests_expr)?
+        .into_keys()
+        .map(|label| (label, 1))
+        .collect();
+    for (size, weight) in [("medium", 5), ("large", 15), ("enormous", 60)] {
+        let sized = cached_query(
+            workspace,
+            binary,
+            &format!("attr(size, {}, {})", size, tests_expr),
+        )?;
+        for label in sized.into_keys() {
+            if let Some(w) = weights.get_mut(&label) {
+                *w = weight;
+            }
+        }
+    }
+    let mut targets: Vec<(String, u32)> = weights.into_iter().collect();
+    targets.sort();
+    Ok(targets)
+}
+
+/// Split weighted targets into `shards` buckets of roughly equal total
+/// weight, placing the heaviest targets first. Labels within a bucket are
+/// sorted so the generated commands are stable.
+pub fn partition_targets(mut targets: Vec<(String, u32)>, shards: usize) -> Vec<Vec<String>> {
+    targets.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    let mut buckets: Vec<(u32, Vec<String>)> = vec![(0, Vec::new()); shards];
+    for (label, weight) in targets {
+        if let Some((total, labels)) = buckets
+            .iter_mut()
+            .min_by_key(|(total, labels)| (*total, labels.len()))
+        {
+            *total += weight;
+            labels.push(label);
+        }
+    }
+    buckets
+        .into_iter()
+        .map(|(_, mut labels)| {
+            labels.sort();
+            labels
+        })
+        .collect()
+}
+
+/// Turn the target arguments of a command into absolute query patterns.
+fn resolve_query_targets(args: &[&str], current_pkg: Option<&str>) -> Vec<String> {
+    targets::extract_targets_from_args(args)
+        .into_iter()
         .map(|t| {
-            if targets::is_wildcard_pattern(t)
-                || targets::is_external_repo(t)
+            if targets::is_wildcard_pattern(&t)
+                || targets::is_external_repo(&t)
                 || t.starts_with("//")
             {
-                t.clone()
+                t
             } else {
                 let pkg = current_pkg.unwrap_or("");
                 if let Some(name) = t.strip_prefix(':') {
@@ -132,13 +553,131 @@ pub fn validate_with_query(
                 }
             }
         })
-        .collect();
+        .collect()
+}
 
-    let query_expr = resolved_targets.join(" + ");
+/// Whether `label` (as printed by `bazel query`) is selected by `pattern`.
+fn pattern_matches(pattern: &str, label: &str) -> bool {
+    if let Some(prefix) = pattern.strip_suffix("/...") {
+        return label.starts_with(&format!("{}/", prefix))
+            || label.starts_with(&format!("{}:", prefix));
+    }
+    if pattern.ends_with("...") {
+        // `//...` or `@repo//...`
+        return label.starts_with(pattern.trim_end_matches("..."));
+    }
+    if let Some((package, name)) = pattern.rsplit_once(':') {
+        if matches!(name, "all" | "*" | "all-targets") {
+            return label
+                .rsplit_once(':')
+                .is_some_and(|(label_package, _)| label_package == package);
+        }
+        return label == pattern;
+    }
+    // `//foo/bar` is shorthand for `//foo/bar:bar`
+    let name = pattern.rsplit('/').next().unwrap_or_default();
+    label == format!("{}:{}", pattern, name)
+}
 
-    let mut cmd = Command::new("bazel");
-    cmd.current_dir(workspace);
-    cmd.args(["query", &query_expr, "--output=label_kind"]);
+fn check_query_results(verb: &str, results: &HashMap<String, QueryResult>) -> Result<(), String> {
+    match verb {
+        "run" => {
+            if results.len() != 1 {
+                return Err(format!(
+                    "bazel run requires exactly one target, found {}",
+                    results.len()
+                ));
+            }
+            for (target, result) in results {
+                if let Some(kind) = &result.kind {
+                    validate_verb_target_compatibility(verb, target, kind)?;
+                }
+            }
+        }
+        "test" => {
+            let has_test_target = results.values().any(|r| {
+                r.kind
+                    .as_ref()
+                    .map(|k| k.contains("_test"))
+                    .unwrap_or(false)
+            });
+            if !has_test_target && !results.is_empty() {
+                let targets: Vec<_> = results.keys().collect();
+                return Err(format!(
+                    "No test targets found. Targets {:?} are not test targets.",
+                    targets
+                ));
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Run `bazel query`, reusing a previous result for the same expression while
+/// the workspace's build files are unchanged.
+fn cached_query(
+    workspace: &Path,
+    binary: &str,
+    query_expr: &str,
+) -> Result<HashMap<String, String>, String> {
+    if !disk_cache_enabled() {
+        return run_query(workspace, binary, query_expr);
+    }
+
+    let start = Instant::now();
+    let hash = workspace_hash(workspace);
+    debug_log!(
+        "bazel",
+        "Hashed workspace build files in {:.2?}",
+        start.elapsed()
+    );
+
+    {
+        let mut guard = QUERY_CACHE.lock().unwrap_or_else(PoisonError::into_inner);
+        let caches = guard.get_or_insert_with(HashMap::new);
+        if caches
+            .get(workspace)
+            .is_none_or(|cache| cache.workspace_hash != hash)
+        {
+            caches.insert(
+                workspace.to_path_buf(),
+                load_query_cache(&cache_file_path(workspace, QUERY_CACHE_FILE), hash),
+            );
+        }
+        if let Some(cache) = caches.get(workspace)
+            && let Some(labels) = cache.entries.get(query_expr)
+        {
+            debug_log!("bazel", "Query cache hit for {}", query_expr);
+            return Ok(labels.clone());
+        }
+    }
+
+    let labels = run_query(workspace, binary, query_expr)?;
+
+    {
+        let mut guard = QUERY_CACHE.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(cache) = guard.as_mut().and_then(|caches| caches.get_mut(workspace))
+            && cache.workspace_hash == hash
+        {
+            cache.entries.insert(query_expr.to_string(), labels.clone());
+            save_query_cache(&cache_file_path(workspace, QUERY_CACHE_FILE), cache);
+        }
+    }
+
+    Ok(labels)
+}
+
+/// Run `bazel query --output=label_kind`, returning rule label -> rule kind.
+fn run_query(
+    workspace: &Path,
+    binary: &str,
+    query_expr: &str,
+) -> Result<HashMap<String, String>, String> {
+    let mut cmd = command(workspace, binary);
+    // --keep_going returns every target that does exist (exit code 3) when
+    // some patterns in a combined query don't resolve
+    cmd.args(["query", query_expr, "--output=label_kind", "--keep_going"]);
 
     debug_log!("bazel", "Running: {:?}", cmd);
     let start = Instant::now();
@@ -149,7 +688,8 @@ pub fn validate_with_query(
 
     debug_log!("bazel", "Query completed in {:.2?}", start.elapsed());
 
-    if !output.status.success() {
+    let partial = output.status.code() == Some(3);
+    if !output.status.success() && !partial {
         let stderr = String::from_utf8_lossy(&output.stderr);
         if stderr.contains("no such target") || stderr.contains("no such package") {
             for line in stderr.lines() {
@@ -162,7 +702,7 @@ pub fn validate_with_query(
     }
 
     let stdout = String::from_utf8_lossy(&output.stdout);
-    let mut results = HashMap::new();
+    let mut labels = HashMap::new();
 
     for line in stdout.lines() {
         let line = line.trim();
@@ -171,45 +711,219 @@ pub fn validate_with_query(
         }
         let parts: Vec<&str> = line.splitn(3, ' ').collect();
         if parts.len() >= 3 && parts[1] == "rule" {
-            let kind = parts[0].to_string();
-            let label = parts[2].to_string();
-            results.insert(label, QueryResult { kind: Some(kind) });
+            labels.insert(parts[2].to_string(), parts[0].to_string());
         }
     }
 
-    match verb {
-        "run" => {
-            if results.len() != 1 {
-                return Err(format!(
-                    "bazel run requires exactly one target, found {}",
-                    results.len()
-                ));
-            }
-            for (target, result) in &results {
-                if let Some(kind) = &result.kind {
-                    validate_verb_target_compatibility(verb, target, kind)?;
-                }
+    Ok(labels)
+}
+
+/// Hash every BUILD, WORKSPACE, MODULE.bazel, REPO.bazel and .bzl file bazel
+/// can load packages from, along with `.bazelignore`. Each file is tracked,
+/// since query results depend on all of them.
+fn workspace_hash(workspace: &Path) -> u64 {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+
+    let ignored = bazelignore(workspace);
+    let mut files = Vec::new();
+    collect_build_inputs(workspace, &ignored, &mut files);
+    let ignore_file = workspace.join(".bazelignore");
+    if ignore_file.is_file() {
+        files.push(ignore_file);
+    }
+    files.sort();
+
+    let mut hasher = DefaultHasher::new();
+    for file in files {
+        file.strip_prefix(workspace)
+            .unwrap_or(&file)
+            .hash(&mut hasher);
+        fs::read(&file).unwrap_or_default().hash(&mut hasher);
+        crate::track::track_file(&file);
+    }
+    hasher.finish()
+}
+
+/// Directories listed in the workspace's `.bazelignore`, which bazel never
+/// loads packages from.
+fn bazelignore(workspace: &Path) -> Vec<PathBuf> {
+    fs::read_to_string(workspace.join(".bazelignore"))
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| workspace.join(line.trim_end_matches('/')))
+        .collect()
+}
+
+/// Collect the build files under `dir`, skipping hidden directories,
+/// `bazel-*` output symlinks, vendored trees such as `node_modules`,
+/// `ignored` directories and nested workspaces, none of which hold packages
+/// of this workspace.
+fn collect_build_inputs(dir: &Path, ignored: &[PathBuf], files: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if file_type.is_dir() {
+            let path = entry.path();
+            if !(name.starts_with('.')
+                || name.starts_with("bazel-")
+                || SKIPPED_DIRS.contains(&name.as_ref())
+                || ignored.contains(&path)
+                || is_bazel_workspace_root(&path))
+            {
+                collect_build_inputs(&path, ignored, files);
             }
+        } else if file_type.is_file()
+            && (BUILD_INPUT_FILES.contains(&name.as_ref()) || name.ends_with(".bzl"))
+        {
+            files.push(entry.path());
         }
-        "test" => {
-            let has_test_target = results.values().any(|r| {
-                r.kind
-                    .as_ref()
-                    .map(|k| k.contains("_test"))
-                    .unwrap_or(false)
-            });
-            if !has_test_target && !results.is_empty() {
-                let targets: Vec<_> = results.keys().collect();
-                return Err(format!(
-                    "No test targets found. Targets {:?} are not test targets.",
-                    targets
-                ));
-            }
+    }
+}
+
+fn load_query_cache(path: &Path, workspace_hash: u64) -> QueryCache {
+    if let Ok(contents) = fs::read_to_string(path)
+        && let Ok(cache) = serde_json::from_str::<QueryCache>(&contents)
+    {
+        if cache.workspace_hash == workspace_hash {
+            debug_log!(
+                "bazel",
+                "Loaded query cache with {} entries",
+                cache.entries.len()
+            );
+            return cache;
         }
-        _ => {}
+        debug_log!(
+            "bazel",
+            "Query cache invalidated: build files changed ({:x} -> {:x})",
+            cache.workspace_hash,
+            workspace_hash
+        );
+    }
+
+    QueryCache {
+        workspace_hash,
+        entries: HashMap::new(),
+    }
+}
+
+fn save_query_cache(path: &Path, cache: &QueryCache) {
+    if let Some(parent) = path.parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+
+    if let Ok(json) = serde_json::to_string_pretty(cache) {
+        if let Err(e) = fs::write(path, json) {
+            debug_log!("bazel", "Failed to save query cache: {}", e);
+        } else {
+            debug_log!(
+                "bazel",
+                "Saved query cache with {} entries",
+                cache.entries.len()
+            );
+        }
+    }
+}
+
+/// Run `bazel info <key>` in the workspace with `binary`, once per key for
+/// the life of the compiler process, returning the trimmed output or the
+/// error with stderr.
+pub fn info(key: &str, binary: &str) -> Result<String, String> {
+    let workspace = find_bazel_workspace_from_env()?;
+    let cache_key = (workspace.clone(), key.to_string());
+    if let Ok(guard) = INFO_CACHE.lock()
+        && let Some(value) = guard.as_ref().and_then(|cache| cache.get(&cache_key))
+    {
+        return Ok(value.clone());
+    }
+
+    let mut cmd = command(&workspace, binary);
+    cmd.args(["info", key]);
+    debug_log!("bazel", "Running: {:?}", cmd);
+    let output = cmd
+        .output()
+        .map_err(|e| format!("Failed to run bazel info {}: {}", key, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "bazel info {} failed (exit {}): {}",
+            key,
+            output.status.code().unwrap_or(-1),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    if let Ok(mut guard) = INFO_CACHE.lock() {
+        guard
+            .get_or_insert_with(HashMap::new)
+            .insert(cache_key, value.clone());
+    }
+    Ok(value)
+}
+
+/// Track the workspace's bazelrc files, and any files they import.
+fn track_rc_files(workspace: &Path) {
+    for file in rust_buildkite_validation::bazel::bazelrc::rc_files(workspace) {
+        crate::track::track_file(&file);
     }
+}
+
+/// Config names defined by the workspace's bazelrc files, read once per
+/// workspace for the life of the compiler process.
+pub fn config_names(workspace: &Path) -> BTreeSet<String> {
+    track_rc_files(workspace);
+    let Ok(mut guard) = RC_CONFIGS.lock() else {
+        return rust_buildkite_validation::bazel::bazelrc::config_names(workspace);
+    };
+    guard
+        .get_or_insert_with(HashMap::new)
+        .entry(workspace.to_path_buf())
+        .or_insert_with(|| rust_buildkite_validation::bazel::bazelrc::config_names(workspace))
+        .clone()
+}
 
-    Ok(results)
+/// Check that the labels given to `--platforms`, `--extra_toolchains` and
+/// similar options exist, from BUILD files first and with `bazel query` for
+/// anything BUILD-file parsing can't find.
+pub fn check_platform_labels(
+    args: &[&str],
+    workspace: &Path,
+    binary: &str,
+    current_pkg: Option<&str>,
+) -> Result<(), String> {
+    let labels = flags::option_values(args, flags::LABEL_LIST_FLAGS);
+    if labels.is_empty() {
+        return Ok(());
+    }
+    let labels: Vec<&str> = labels.iter().map(|l| l.as_str()).collect();
+    if fast_validate_targets(&labels, workspace, current_pkg).is_ok() {
+        return Ok(());
+    }
+    validate_with_query("build", &labels, workspace, binary, current_pkg)
+        .map(|_| ())
+        .map_err(|e| format!("platform or toolchain label not found: {}", e))
+}
+
+/// `--cpu` and `--host_cpu` values that are neither common CPUs nor set
+/// anywhere in the workspace's bazelrc files.
+pub fn unknown_cpus(args: &[&str], workspace: &Path) -> Vec<String> {
+    let mut cpus = flags::option_values(args, flags::CPU_FLAGS);
+    cpus.retain(|cpu| !cpu.contains('$') && !flags::COMMON_CPUS.contains(&cpu.as_str()));
+    if cpus.is_empty() {
+        return cpus;
+    }
+    track_rc_files(workspace);
+    let configured =
+        rust_buildkite_validation::bazel::bazelrc::option_values(workspace, flags::CPU_FLAGS);
+    cpus.retain(|cpu| !configured.contains(cpu));
+    cpus
 }
 
 pub fn validate_verb_target_compatibility(
@@ -249,14 +963,25 @@ fn make_cache_key(verb: &str, flags: &[&str]) -> String {
     format!("{}_{:x}", verb, hash)
 }
 
-fn get_cache_file_path(workspace: &Path) -> std::path::PathBuf {
+/// Cache file name for `workspace` in a directory shared by several
+/// workspaces: `bazel-query-cache.json` becomes `bazel-query-cache-<hash>.json`.
+fn shared_cache_file_name(workspace: &Path, name: &str) -> String {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+    workspace.hash(&mut hasher);
+    let (stem, extension) = name.rsplit_once('.').unwrap_or((name, "json"));
+    format!("{}-{:016x}.{}", stem, hasher.finish(), extension)
+}
+
+fn cache_file_path(workspace: &Path, name: &str) -> PathBuf {
     if let Ok(out_dir) = std::env::var("OUT_DIR") {
-        let path = std::path::PathBuf::from(&out_dir).join("bazel-flags-cache.json");
+        let path = PathBuf::from(&out_dir).join(shared_cache_file_name(workspace, name));
         debug_log!("bazel", "Using OUT_DIR cache path: {}", path.display());
         return path;
     }
 
-    let fallback = workspace.join(".buildkite").join(".bazel-flags-cache.json");
+    let fallback = workspace.join(".buildkite").join(format!(".{}", name));
     debug_log!(
         "bazel",
         "OUT_DIR not set, using fallback cache path: {}",
@@ -266,10 +991,11 @@ fn get_cache_file_path(workspace: &Path) -> std::path::PathBuf {
 }
 
 fn load_flags_cache(workspace: &Path) -> FlagsCache {
-    let cache_path = get_cache_file_path(workspace);
+    let cache_path = cache_file_path(workspace, FLAGS_CACHE_FILE);
     let current_mtime = get_bazelrc_mtime(workspace);
 
-    if let Ok(contents) = fs::read_to_string(&cache_path)
+    if disk_cache_enabled()
+        && let Ok(contents) = fs::read_to_string(&cache_path)
         && let Ok(cache) = serde_json::from_str::<FlagsCache>(&contents)
     {
         if cache.bazelrc_mtime == current_mtime {
@@ -296,7 +1022,10 @@ fn load_flags_cache(workspace: &Path) -> FlagsCache {
 }
 
 fn save_flags_cache(workspace: &Path, cache: &FlagsCache) {
-    let cache_path = get_cache_file_path(workspace);
+    if !disk_cache_enabled() {
+        return;
+    }
+    let cache_path = cache_file_path(workspace, FLAGS_CACHE_FILE);
     if let Some(parent) = cache_path.parent() {
         let _ = fs::create_dir_all(parent);
     }
@@ -316,13 +1045,14 @@ fn save_flags_cache(workspace: &Path, cache: &FlagsCache) {
 
 pub fn canonicalize_flags(
     verb: &str,
+    startup: &[&str],
     args: &[&str],
     workspace: &Path,
+    binary: &str,
 ) -> Result<Vec<String>, String> {
     if crate::should_skip_comptime_validation() {
         return Ok(Vec::new());
     }
-    use std::process::Command;
 
     let args_before_separator: &[&str] = match args.iter().position(|&a| a == "--") {
         Some(pos) => &args[..pos],
@@ -344,23 +1074,22 @@ pub fn canonicalize_flags(
         return Ok(Vec::new());
     }
 
-    let cache_key = make_cache_key(verb, &flags);
+    let keyed: Vec<&str> = startup.iter().chain(&flags).copied().collect();
+    let cache_key = make_cache_key(verb, &keyed);
     {
-        let mut guard = FLAGS_CACHE.lock().unwrap();
-        if guard.is_none() {
-            *guard = Some(load_flags_cache(workspace));
-        }
-
-        if let Some(ref cache) = *guard
-            && let Some(entry) = cache.entries.get(&cache_key)
-        {
+        let mut guard = FLAGS_CACHE.lock().unwrap_or_else(PoisonError::into_inner);
+        let cache = guard
+            .get_or_insert_with(HashMap::new)
+            .entry(workspace.to_path_buf())
+            .or_insert_with(|| load_flags_cache(workspace));
+        if let Some(entry) = cache.entries.get(&cache_key) {
             debug_log!("bazel", "Cache hit for {} ({} flags)", verb, flags.len());
             return Ok(entry.canonical_flags.clone());
         }
     }
 
-    let mut cmd = Command::new("bazel");
-    cmd.current_dir(workspace);
+    let mut cmd = command(workspace, binary);
+    cmd.args(startup);
     cmd.arg("canonicalize-flags");
     cmd.arg(format!("--for_command={}", verb));
     cmd.arg("--");
@@ -397,8 +1126,8 @@ pub fn canonicalize_flags(
         .collect();
 
     {
-        let mut guard = FLAGS_CACHE.lock().unwrap();
-        if let Some(ref mut cache) = *guard {
+        let mut guard = FLAGS_CACHE.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(cache) = guard.as_mut().and_then(|caches| caches.get_mut(workspace)) {
             cache.entries.insert(
                 cache_key,
                 FlagsCacheEntry {
@@ -415,6 +1144,273 @@ pub fn canonicalize_flags(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_program_resolves_paths_against_the_workspace() {
+        let workspace = Path::new("/repo");
+        assert_eq!(program("bazelisk", workspace), PathBuf::from("bazelisk"));
+        assert_eq!(
+            program("./tools/bazel", workspace),
+            PathBuf::from("/repo/tools/bazel")
+        );
+        assert_eq!(
+            program("/opt/bazel", workspace),
+            PathBuf::from("/opt/bazel")
+        );
+    }
+
+    fn fixture_workspace() -> tempfile::TempDir {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("MODULE.bazel"),
+            "module(name = \"fixture\")\n",
+        )
+        .unwrap();
+        fs::create_dir_all(dir.path().join("app")).unwrap();
+        fs::write(
+            dir.path().join("app/BUILD.bazel"),
+            "cc_binary(name = \"main\", srcs = [\"main.cc\"])\n",
+        )
+        .unwrap();
+        fs::write(dir.path().join("app/main.cc"), "int main() {}\n").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_partition_targets_balances_weight() {
+        let targets = vec![
+            ("//a:large".to_string(), 15),
+            ("//a:small1".to_string(), 1),
+            ("//a:small2".to_string(), 1),
+            ("//b:medium".to_string(), 5),
+            ("//b:medium2".to_string(), 5),
+            ("//b:small3".to_string(), 1),
+        ];
+        let buckets = partition_targets(targets, 2);
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0], vec!["//a:large"]);
+        assert_eq!(
+            buckets[1],
+            vec![
+                "//a:small1",
+                "//a:small2",
+                "//b:medium",
+                "//b:medium2",
+                "//b:small3"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_partition_targets_by_count() {
+        let targets: Vec<(String, u32)> = (1..=7).map(|i| (format!("//t:{}", i), 1)).collect();
+        let sizes: Vec<usize> = partition_targets(targets, 3)
+            .iter()
+            .map(|b| b.len())
+            .collect();
+        assert_eq!(sizes, vec![3, 2, 2]);
+    }
+
+    fn package_labels(names: &[&str]) -> HashMap<String, String> {
+        names
+            .iter()
+            .map(|n| (format!("//app:{}", n), "rust_binary".to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_missing_target_suggests_close_names() {
+        let labels = package_labels(&["server", "server_lib", "client"]);
+        assert_eq!(
+            missing_target_error("//app:sever", &labels),
+            "no such target '//app:sever'; did you mean //app:server?"
+        );
+        assert_eq!(
+            missing_target_error("//app:unrelated_name", &labels),
+            "no such target '//app:unrelated_name'"
+        );
+    }
+
+    #[test]
+    fn test_missing_target_hints_test_suffix() {
+        let labels = package_labels(&["server_test", "server_lib"]);
+        assert_eq!(
+            missing_target_error("//app:server", &labels),
+            "no such target '//app:server'; did you mean //app:server_test? Test rules usually end in `_test`"
+        );
+    }
+
+    #[test]
+    fn test_missing_target_without_package_rules() {
+        assert_eq!(
+            missing_target_error("//app:server", &HashMap::new()),
+            "no such target '//app:server'"
+        );
+        assert_eq!(
+            missing_target_error("//app/sever", &package_labels(&["server"])),
+            "no such target '//app/sever'"
+        );
+    }
+
+    #[test]
+    fn test_missing_target_hints_file_name() {
+        let labels = package_labels(&["server", "client"]);
+        assert_eq!(
+            missing_target_error("//app:server.rs", &labels),
+            "no such target '//app:server.rs'; 'server.rs' looks like a file name, but bazel commands take rule names. Did you mean //app:server?"
+        );
+    }
+
+    #[test]
+    fn test_target_universe() {
+        assert_eq!(
+            target_universe(&["--config=ci", "//app/...", "-//app/slow:all"], None),
+            Some("//app/... - //app/slow:all".to_string())
+        );
+        assert_eq!(
+            target_universe(&["--", "//...", "-//third_party/..."], None),
+            Some("//... - //third_party/...".to_string())
+        );
+        assert_eq!(
+            target_universe(&[":server_test"], Some("app")),
+            Some("//app:server_test".to_string())
+        );
+        assert_eq!(target_universe(&["--keep_going"], None), None);
+    }
+
+    #[test]
+    fn test_unmatched_test_tags_skips_negative_filters() {
+        let dir = tempdir().unwrap();
+        assert!(
+            unmatched_test_tags(
+                &["--test_tag_filters=-flaky,-manual", "//..."],
+                dir.path(),
+                "bazel",
+                None
+            )
+            .is_empty()
+        );
+        assert!(unmatched_test_tags(&["//..."], dir.path(), "bazel", None).is_empty());
+    }
+
+    #[test]
+    fn test_regex_escape() {
+        assert_eq!(regex_escape("smoke"), "smoke");
+        assert_eq!(regex_escape("requires-gpu.v2"), "requires-gpu\\.v2");
+    }
+
+    #[test]
+    fn test_pattern_matches() {
+        assert!(pattern_matches("//app:main", "//app:main"));
+        assert!(!pattern_matches("//app:main", "//app:main_test"));
+        assert!(pattern_matches("//app", "//app:app"));
+        assert!(pattern_matches("//app/...", "//app/sub:lib"));
+        assert!(pattern_matches("//app/...", "//app:main"));
+        assert!(!pattern_matches("//app/...", "//application:main"));
+        assert!(pattern_matches("//...", "//app:main"));
+        assert!(pattern_matches("//app:all", "//app:main"));
+        assert!(!pattern_matches("//app:all", "//app/sub:lib"));
+    }
+
+    #[test]
+    fn test_resolve_query_targets() {
+        assert_eq!(
+            resolve_query_targets(
+                &["--config=ci", ":main", "//lib:all", "--", "//ignored"],
+                Some("app")
+            ),
+            vec!["//app:main".to_string(), "//lib:all".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_shared_cache_file_name_is_per_workspace() {
+        let frontend = shared_cache_file_name(Path::new("/repo/frontend"), QUERY_CACHE_FILE);
+        let backend = shared_cache_file_name(Path::new("/repo/backend"), QUERY_CACHE_FILE);
+        assert_ne!(frontend, backend);
+        assert!(frontend.starts_with("bazel-query-cache-"));
+        assert!(frontend.ends_with(".json"));
+        assert_eq!(
+            frontend,
+            shared_cache_file_name(Path::new("/repo/frontend"), QUERY_CACHE_FILE)
+        );
+    }
+
+    #[test]
+    fn test_repo_root() {
+        let dir = tempdir().unwrap();
+        std::fs::create_dir(dir.path().join(".git")).unwrap();
+        let nested = dir.path().join("ci/pipeline");
+        std::fs::create_dir_all(&nested).unwrap();
+        assert_eq!(repo_root(&nested), dir.path());
+        let outside = tempdir().unwrap();
+        assert_eq!(repo_root(outside.path()), outside.path());
+    }
+
+    #[test]
+    fn test_workspace_hash_tracks_build_files_only() {
+        let dir = fixture_workspace();
+        let original = workspace_hash(dir.path());
+
+        fs::write(dir.path().join("app/main.cc"), "int main() { return 1; }\n").unwrap();
+        fs::create_dir_all(dir.path().join("bazel-out/app")).unwrap();
+        fs::write(dir.path().join("bazel-out/app/BUILD"), "").unwrap();
+        assert_eq!(workspace_hash(dir.path()), original);
+
+        fs::write(
+            dir.path().join("app/BUILD.bazel"),
+            "cc_binary(name = \"main2\", srcs = [\"main.cc\"])\n",
+        )
+        .unwrap();
+        assert_ne!(workspace_hash(dir.path()), original);
+    }
+
+    #[test]
+    fn test_workspace_hash_skips_vendored_ignored_and_nested_dirs() {
+        let dir = fixture_workspace();
+        fs::write(
+            dir.path().join(".bazelignore"),
+            "# generated\nthird_party/js/\n",
+        )
+        .unwrap();
+        let original = workspace_hash(dir.path());
+
+        for vendored in ["node_modules/left-pad", "third_party/js", "examples/demo"] {
+            fs::create_dir_all(dir.path().join(vendored)).unwrap();
+            fs::write(dir.path().join(vendored).join("BUILD.bazel"), "").unwrap();
+        }
+        fs::write(dir.path().join("examples/demo/MODULE.bazel"), "").unwrap();
+        assert_eq!(workspace_hash(dir.path()), original);
+
+        fs::write(dir.path().join(".bazelignore"), "").unwrap();
+        assert_ne!(workspace_hash(dir.path()), original);
+    }
+
+    #[test]
+    fn test_query_cache_round_trip_and_invalidation() {
+        let dir = fixture_workspace();
+        let path = dir.path().join("cache/bazel-query-cache.json");
+        let hash = workspace_hash(dir.path());
+
+        let mut cache = load_query_cache(&path, hash);
+        assert!(cache.entries.is_empty());
+        cache.entries.insert(
+            "//app:main".to_string(),
+            HashMap::from([("//app:main".to_string(), "cc_binary".to_string())]),
+        );
+        save_query_cache(&path, &cache);
+
+        let reloaded = load_query_cache(&path, hash);
+        assert_eq!(
+            reloaded.entries["//app:main"]["//app:main"],
+            "cc_binary".to_string()
+        );
+
+        fs::write(dir.path().join("app/BUILD.bazel"), "").unwrap();
+        let invalidated = load_query_cache(&path, workspace_hash(dir.path()));
+        assert!(invalidated.entries.is_empty());
+    }
 
     #[test]
     fn test_verb_target_compatibility() {
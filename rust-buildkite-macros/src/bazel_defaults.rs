@@ -0,0 +1,265 @@
+//! Pipeline-level bazel settings: `bazel_defaults`, flags prepended to every
+//! bazel command with a given verb, `bazel_binary`, the program emitted
+//! commands run, `bazel_workspace`, the workspace commands run in, and
+//! `bazel_exclusive_configs`, `--config` names that can't be combined.
+//!
+//! `pipeline!` opens a [`scope`] before parsing and installs the parsed
+//! settings with [`set`], [`set_binary`], [`set_workspace`] and
+//! [`set_exclusive_configs`]. Every `BazelExpr` built while the scope is open
+//! picks them up through [`flags_for`], [`binary`], [`workspace`] and
+//! [`exclusive_configs`], so structured bazel steps,
+//! steps in groups and inline `bazel!` commands all see the same settings. A
+//! standalone `bazel!` invocation never does. A step's own `workspace:` is
+//! installed with [`step_workspace`] while the rest of the step is parsed.
+
+use rust_buildkite_validation::flags::REPEATABLE_FLAGS;
+use std::cell::RefCell;
+use syn::LitStr;
+
+thread_local! {
+    static DEFAULTS: RefCell<Vec<(String, Vec<LitStr>)>> = const { RefCell::new(Vec::new()) };
+    static BINARY: RefCell<Option<String>> = const { RefCell::new(None) };
+    static WORKSPACE: RefCell<Option<String>> = const { RefCell::new(None) };
+    static STEP_WORKSPACE: RefCell<Option<String>> = const { RefCell::new(None) };
+    static EXCLUSIVE_CONFIGS: RefCell<Vec<Vec<String>>> = const { RefCell::new(Vec::new()) };
+}
+
+fn reset() {
+    DEFAULTS.with(|d| d.borrow_mut().clear());
+    BINARY.with(|b| *b.borrow_mut() = None);
+    WORKSPACE.with(|w| *w.borrow_mut() = None);
+    STEP_WORKSPACE.with(|w| *w.borrow_mut() = None);
+    EXCLUSIVE_CONFIGS.with(|c| c.borrow_mut().clear());
+}
+
+/// Clears the installed settings when dropped.
+pub struct Scope(());
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        reset();
+    }
+}
+
+/// Start with no settings; they are dropped again when the scope ends, even
+/// if parsing fails part-way.
+pub fn scope() -> Scope {
+    reset();
+    Scope(())
+}
+
+/// Install the binary emitted commands run instead of `bazel`.
+pub fn set_binary(binary: String) {
+    BINARY.with(|b| *b.borrow_mut() = Some(binary));
+}
+
+/// The binary emitted commands run: `bazel` unless `bazel_binary` is set.
+pub fn binary() -> String {
+    BINARY.with(|b| b.borrow().clone().unwrap_or_else(|| "bazel".to_string()))
+}
+
+/// Install the workspace, relative to the repository root, that bazel
+/// commands are validated against and run in.
+pub fn set_workspace(workspace: String) {
+    WORKSPACE.with(|w| *w.borrow_mut() = Some(workspace));
+}
+
+/// The workspace bazel commands use: the current step's `workspace`, else
+/// the pipeline's `bazel_workspace`. `None` keeps env-based detection.
+pub fn workspace() -> Option<String> {
+    STEP_WORKSPACE
+        .with(|w| w.borrow().clone())
+        .or_else(|| WORKSPACE.with(|w| w.borrow().clone()))
+}
+
+/// Install groups of `--config` names that can't be used together.
+pub fn set_exclusive_configs(groups: Vec<Vec<String>>) {
+    EXCLUSIVE_CONFIGS.with(|c| *c.borrow_mut() = groups);
+}
+
+/// Groups of `--config` names declared mutually exclusive.
+pub fn exclusive_configs() -> Vec<Vec<String>> {
+    EXCLUSIVE_CONFIGS.with(|c| c.borrow().clone())
+}
+
+/// Restores the previous step workspace when dropped.
+pub struct StepWorkspace(Option<String>);
+
+impl Drop for StepWorkspace {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        STEP_WORKSPACE.with(|w| *w.borrow_mut() = previous);
+    }
+}
+
+/// Use `workspace` for the step being parsed, until the guard is dropped.
+/// `None` leaves the pipeline's workspace in effect.
+pub fn step_workspace(workspace: Option<String>) -> StepWorkspace {
+    let previous = STEP_WORKSPACE.with(|w| {
+        let mut current = w.borrow_mut();
+        let previous = current.clone();
+        if workspace.is_some() {
+            *current = workspace;
+        }
+        previous
+    });
+    StepWorkspace(previous)
+}
+
+/// Install defaults keyed by verb, with `all` applying to every built-in verb.
+pub fn set(defaults: Vec<(String, Vec<LitStr>)>) {
+    DEFAULTS.with(|d| *d.borrow_mut() = defaults);
+}
+
+/// Default flags for `verb`: the `all` list (unless `verb` is a custom verb)
+/// followed by the verb's own list.
+pub fn flags_for(verb: &str, custom: bool) -> Vec<LitStr> {
+    DEFAULTS.with(|d| {
+        let defaults = d.borrow();
+        let all = defaults
+            .iter()
+            .filter(|(v, _)| v == "all" && !custom)
+            .flat_map(|(_, flags)| flags.iter().cloned());
+        let own = defaults
+            .iter()
+            .filter(|(v, _)| v == verb)
+            .flat_map(|(_, flags)| flags.iter().cloned());
+        all.chain(own).collect()
+    })
+}
+
+/// Insert `defaults` right after the verb of `command`, leaving out any the
+/// command already sets. `--name=value` and `--[no]name` in the command win
+/// over a default for the same option, except for repeatable options such as
+/// `--config`, where only an identical default is dropped.
+pub fn merge(command: &str, defaults: &[String]) -> String {
+    let mut words = command.split_whitespace();
+    let Some(verb) = words.next() else {
+        return command.to_string();
+    };
+    let rest: Vec<&str> = words.collect();
+    let options: Vec<&str> = rest
+        .iter()
+        .take_while(|w| **w != "--")
+        .filter(|w| w.starts_with('-'))
+        .copied()
+        .collect();
+
+    let mut merged = vec![verb.to_string()];
+    for default in defaults.iter().flat_map(|d| d.split_whitespace()) {
+        let overridden = options.iter().any(|option| {
+            *option == default
+                || (!REPEATABLE_FLAGS.contains(&option_name(default))
+                    && option_name(option) == option_name(default))
+        });
+        if !overridden && !merged.iter().any(|m| m == default) {
+            merged.push(default.to_string());
+        }
+    }
+    merged.extend(rest.iter().map(|w| w.to_string()));
+    merged.join(" ")
+}
+
+/// `--[no]name[=value]` -> `name`
+fn option_name(option: &str) -> &str {
+    let name = option.trim_start_matches('-');
+    let name = name.split_once('=').map_or(name, |(name, _)| name);
+    name.strip_prefix("no").unwrap_or(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(flags: &[&str]) -> Vec<String> {
+        flags.iter().map(|f| f.to_string()).collect()
+    }
+
+    #[test]
+    fn test_merge_prepends_defaults() {
+        assert_eq!(
+            merge(
+                "test //...",
+                &strings(&["--test_output=errors", "--keep_going"])
+            ),
+            "test --test_output=errors --keep_going //..."
+        );
+    }
+
+    #[test]
+    fn test_merge_command_flags_win() {
+        assert_eq!(
+            merge(
+                "test --test_output=all --nokeep_going //...",
+                &strings(&["--test_output=errors", "--keep_going"])
+            ),
+            "test --test_output=all --nokeep_going //..."
+        );
+    }
+
+    #[test]
+    fn test_merge_keeps_repeatable_defaults() {
+        assert_eq!(
+            merge("build --config=debug //app", &strings(&["--config=remote"])),
+            "build --config=remote --config=debug //app"
+        );
+        assert_eq!(
+            merge(
+                "build --config=remote //app",
+                &strings(&["--config=remote"])
+            ),
+            "build --config=remote //app"
+        );
+    }
+
+    #[test]
+    fn test_merge_ignores_program_args() {
+        assert_eq!(
+            merge("run //tools:fmt -- --jobs=1", &strings(&["--jobs=8"])),
+            "run --jobs=8 //tools:fmt -- --jobs=1"
+        );
+    }
+
+    #[test]
+    fn test_flags_for_verb() {
+        let _scope = scope();
+        let lit = |s: &str| LitStr::new(s, proc_macro2::Span::call_site());
+        set(vec![
+            ("test".to_string(), vec![lit("--test_output=errors")]),
+            ("all".to_string(), vec![lit("--announce_rc")]),
+        ]);
+        let values = |flags: Vec<LitStr>| flags.iter().map(|f| f.value()).collect::<Vec<_>>();
+        assert_eq!(
+            values(flags_for("test", false)),
+            vec!["--announce_rc", "--test_output=errors"]
+        );
+        assert_eq!(values(flags_for("build", false)), vec!["--announce_rc"]);
+        assert!(flags_for("deploy", true).is_empty());
+    }
+
+    #[test]
+    fn test_binary_resets_with_scope() {
+        {
+            let _scope = scope();
+            set_binary("bazelisk".to_string());
+            assert_eq!(binary(), "bazelisk");
+        }
+        assert_eq!(binary(), "bazel");
+    }
+
+    #[test]
+    fn test_step_workspace_overrides_pipeline() {
+        let _scope = scope();
+        assert_eq!(workspace(), None);
+        set_workspace("frontend".to_string());
+        {
+            let _step = step_workspace(Some("backend".to_string()));
+            assert_eq!(workspace().as_deref(), Some("backend"));
+        }
+        assert_eq!(workspace().as_deref(), Some("frontend"));
+        {
+            let _step = step_workspace(None);
+            assert_eq!(workspace().as_deref(), Some("frontend"));
+        }
+    }
+}
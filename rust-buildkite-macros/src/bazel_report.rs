@@ -0,0 +1,187 @@
+//! Dry-run report for bazel validation.
+//!
+//! Commands parsed with `dry_run: true` record what validation did with them:
+//! the targets they name, the labels those resolved to, and every check that
+//! passed, failed or was skipped, with the reason. `pipeline!` and `bazel!`
+//! call [`finish`] once validation is done, which appends the report as one
+//! JSON line to the file named by `RUST_BUILDKITE_BAZEL_REPORT` and queues a
+//! one-line summary through [`crate::diag`].
+
+use proc_macro2::Span;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::io::Write;
+
+thread_local! {
+    static ENTRIES: RefCell<Vec<Entry>> = const { RefCell::new(Vec::new()) };
+}
+
+/// What validation did with one bazel command.
+#[derive(Serialize)]
+pub struct Entry {
+    /// `file:line` of the command
+    pub location: String,
+    pub verb: String,
+    pub command: String,
+    /// Target patterns as written
+    pub targets: Vec<String>,
+    /// Labels the patterns matched, when a `bazel query` resolved them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolved: Option<Vec<String>>,
+    pub checks: Vec<Check>,
+    #[serde(skip)]
+    span: Span,
+}
+
+#[derive(Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Passed,
+    Failed,
+    Skipped,
+}
+
+impl Entry {
+    pub fn new(span: Span, verb: &str, command: &str, targets: Vec<String>) -> Self {
+        let start = span.start();
+        Self {
+            location: format!("{}:{}", span.file(), start.line),
+            verb: verb.to_string(),
+            command: command.to_string(),
+            targets,
+            resolved: None,
+            checks: Vec::new(),
+            span,
+        }
+    }
+
+    pub fn check(&mut self, name: &'static str, status: Status, detail: Option<String>) {
+        self.checks.push(Check {
+            name,
+            status,
+            detail,
+        });
+    }
+}
+
+/// Note a check on an entry that is being recorded, if any.
+pub fn note(entry: &mut Option<Entry>, name: &'static str, status: Status, detail: Option<String>) {
+    if let Some(entry) = entry {
+        entry.check(name, status, detail);
+    }
+}
+
+/// Drop recorded entries, e.g. at the start of an expansion.
+pub fn clear() {
+    ENTRIES.with(|e| e.borrow_mut().clear());
+}
+
+pub fn record(entry: Entry) {
+    ENTRIES.with(|e| e.borrow_mut().push(entry));
+}
+
+/// Write the recorded entries and queue a summary note at the first one.
+pub fn finish() {
+    let entries = ENTRIES.with(|e| std::mem::take(&mut *e.borrow_mut()));
+    let Some(first) = entries.first() else {
+        return;
+    };
+    let count = |status| {
+        entries
+            .iter()
+            .flat_map(|e| &e.checks)
+            .filter(|c| c.status == status)
+            .count()
+    };
+    let resolved: usize = entries
+        .iter()
+        .filter_map(|e| e.resolved.as_ref())
+        .map(Vec::len)
+        .sum();
+    let destination = match std::env::var("RUST_BUILDKITE_BAZEL_REPORT") {
+        Ok(path) if !path.is_empty() => match write(&path, &entries) {
+            Ok(()) => format!("report appended to {}", path),
+            Err(e) => format!("could not write report to {}: {}", path, e),
+        },
+        _ => "set RUST_BUILDKITE_BAZEL_REPORT to write the full report".to_string(),
+    };
+    crate::diag::warn(
+        first.span,
+        format!(
+            "bazel dry run: {} command{}, {} target{} resolved, {} checks passed, {} failed, {} skipped; {}",
+            entries.len(),
+            if entries.len() == 1 { "" } else { "s" },
+            resolved,
+            if resolved == 1 { "" } else { "s" },
+            count(Status::Passed),
+            count(Status::Failed),
+            count(Status::Skipped),
+            destination
+        ),
+    );
+}
+
+fn write(path: &str, entries: &[Entry]) -> std::io::Result<()> {
+    let line = serde_json::to_string(&serde_json::json!({ "commands": entries }))?;
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{}", line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry_serialization() {
+        let mut entry = Entry::new(
+            Span::call_site(),
+            "test",
+            "test //app/...",
+            vec!["//app/...".to_string()],
+        );
+        entry.resolved = Some(vec!["//app:app_test".to_string()]);
+        entry.check("targets", Status::Passed, None);
+        entry.check(
+            "dry_run",
+            Status::Skipped,
+            Some("bazel_validation is off".to_string()),
+        );
+        let json = serde_json::to_value(&entry).unwrap();
+        assert_eq!(json["verb"], "test");
+        assert_eq!(json["resolved"][0], "//app:app_test");
+        assert_eq!(json["checks"][0]["status"], "passed");
+        assert!(json["checks"][0].get("detail").is_none());
+        assert_eq!(json["checks"][1]["detail"], "bazel_validation is off");
+        assert!(json.get("span").is_none());
+    }
+
+    #[test]
+    fn test_write_appends_json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("report.jsonl");
+        let mut entry = Entry::new(Span::call_site(), "build", "build //...", vec![]);
+        entry.check("canonicalize_flags", Status::Passed, None);
+        write(path.to_str().unwrap(), &[entry]).unwrap();
+        write(path.to_str().unwrap(), &[]).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let first: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(
+            first["commands"][0]["checks"][0]["name"],
+            "canonicalize_flags"
+        );
+    }
+}
@@ -67,6 +67,11 @@ pub mod options {
 
 use build_event_stream::{BuildEvent, build_event::Payload, build_event_id::Id};
 
+/// Option that makes bazel write build events as newline-delimited JSON.
+pub const JSON_FILE_FLAG: &str = "--build_event_json_file";
+/// Option that makes bazel write build events as length-delimited protos.
+pub const BINARY_FILE_FLAG: &str = "--build_event_binary_file";
+
 #[derive(Debug, Default)]
 pub struct DryRunResult {
     pub expanded_targets: Vec<String>,
@@ -202,13 +207,17 @@ fn process_event(event: &BuildEvent, result: &mut DryRunResult) {
     }
 }
 
-pub fn dry_run(verb: &str, args: &[&str], workspace: &Path) -> Result<DryRunResult, String> {
-    use std::process::Command;
-
+pub fn dry_run(
+    verb: &str,
+    startup: &[&str],
+    args: &[&str],
+    workspace: &Path,
+    binary: &str,
+) -> Result<DryRunResult, String> {
     let bep_file = std::env::temp_dir().join(format!("bep_dry_run_{}.bin", std::process::id()));
 
-    let mut cmd = Command::new("bazel");
-    cmd.current_dir(workspace);
+    let mut cmd = crate::bazel::command(workspace, binary);
+    cmd.args(startup);
     cmd.arg(verb);
 
     let using_nobuild = matches!(verb, "build" | "test" | "run" | "coverage");
@@ -216,8 +225,15 @@ pub fn dry_run(verb: &str, args: &[&str], workspace: &Path) -> Result<DryRunResu
         cmd.arg("--nobuild");
     }
 
-    cmd.args(args);
-    cmd.arg(format!("--build_event_binary_file={}", bep_file.display()));
+    // The BEP flag must come before `--`, or `bazel run` hands it to the program
+    let (bazel_args, program_args) = match args.iter().position(|a| *a == "--") {
+        Some(pos) => args.split_at(pos),
+        None => (args, &[][..]),
+    };
+    // A `bazel_bep` JSON file would be written by the dry run too; skip it
+    cmd.args(bazel_args.iter().filter(|a| !a.starts_with(JSON_FILE_FLAG)));
+    cmd.arg(format!("{}={}", BINARY_FILE_FLAG, bep_file.display()));
+    cmd.args(program_args);
 
     debug_log!("bep", "Dry run: {:?}", cmd);
     let start = Instant::now();
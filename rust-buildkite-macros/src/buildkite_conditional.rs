@@ -1,10 +1,13 @@
+use std::collections::HashMap;
 use std::iter::Peekable;
 use std::str::Chars;
+use std::sync::Mutex;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     True,
     False,
+    Null,
     Integer(i64),
     String(String),
     Regex(String, String),
@@ -274,6 +277,7 @@ impl<'a> Lexer<'a> {
                     match ident.as_str() {
                         "true" => Ok(Token::True),
                         "false" => Ok(Token::False),
+                        "null" => Ok(Token::Null),
                         _ => Ok(Token::Ident(ident)),
                     }
                 }
@@ -378,6 +382,7 @@ impl<'a> Parser<'a> {
         match &self.current {
             Token::True
             | Token::False
+            | Token::Null
             | Token::Integer(_)
             | Token::String(_)
             | Token::Regex(_, _) => {
@@ -486,7 +491,30 @@ impl<'a> Parser<'a> {
     }
 }
 
-pub fn validate_condition(expr: &str) -> Result<(), Vec<String>> {
+type ConditionOutcome = Result<(), Vec<String>>;
+
+/// Results of previously validated conditions; pipelines commonly repeat the
+/// same `if:` on many steps.
+static CONDITION_CACHE: Mutex<Option<HashMap<String, ConditionOutcome>>> = Mutex::new(None);
+
+pub fn validate_condition(expr: &str) -> ConditionOutcome {
+    if let Some(cached) = CONDITION_CACHE
+        .lock()
+        .ok()
+        .and_then(|cache| cache.as_ref()?.get(expr).cloned())
+    {
+        return cached;
+    }
+    let outcome = parse_condition(expr);
+    if let Ok(mut cache) = CONDITION_CACHE.lock() {
+        cache
+            .get_or_insert_with(HashMap::new)
+            .insert(expr.to_string(), outcome.clone());
+    }
+    outcome
+}
+
+fn parse_condition(expr: &str) -> ConditionOutcome {
     let mut parser = Parser::new(expr).map_err(|e| vec![e])?;
     parser.parse()
 }
@@ -501,6 +529,12 @@ mod tests {
         assert!(validate_condition("build.branch == \"main\"").is_ok());
     }
 
+    #[test]
+    fn test_null_comparison() {
+        assert!(validate_condition("build.pull_request.id == null").is_ok());
+        assert!(validate_condition("build.tag != null").is_ok());
+    }
+
     #[test]
     fn test_regex_match() {
         assert!(validate_condition("build.branch =~ /^feature\\//").is_ok());
@@ -554,4 +588,18 @@ mod tests {
     fn test_incomplete_expression() {
         assert!(validate_condition("build.branch ==").is_err());
     }
+
+    #[test]
+    fn test_repeated_condition_uses_cached_result() {
+        let expr = "build.branch == 'main' || build.branch == 'develop'";
+        let first = validate_condition(expr);
+        let second = validate_condition(expr);
+        assert_eq!(first, second);
+        assert!(first.is_ok());
+        assert_eq!(first, parse_condition(expr));
+
+        let invalid = "cached.unknown == 'x'";
+        assert_eq!(validate_condition(invalid), validate_condition(invalid));
+        assert!(validate_condition(invalid).is_err());
+    }
 }
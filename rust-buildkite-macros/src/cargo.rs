@@ -0,0 +1,336 @@
+//! Typed cargo commands: `cargo_test!`, `cargo_build!` and `cargo_clippy!`.
+//!
+//! Each helper takes `name: value` options and assembles the command string,
+//! e.g. `cargo_test!(package: "api", features: ["postgres"])` is
+//! `cargo test --package api --features postgres`. Options cargo rejects
+//! together, such as `all_features` with `no_default_features`, are compile
+//! errors. The result is an ordinary command string: in command position it
+//! goes through the same validation as `cmd!`, and the emitted step is the
+//! same as writing the string out by hand.
+//!
+//! `extra_args: [...]` is appended verbatim for anything the helpers don't
+//! cover, including a `--` section passed on to the test binary or clippy.
+
+use proc_macro2::Span;
+use syn::parse::ParseStream;
+use syn::{Error, Ident, LitBool, LitInt, LitStr, Result, Token, bracketed};
+
+/// Names of the helper macros
+pub const HELPERS: &[&str] = &["cargo_test", "cargo_build", "cargo_clippy"];
+
+/// Options every helper accepts, with the flag each one adds
+const COMMON: &[(&str, &str)] = &[
+    ("package", "--package"),
+    ("workspace", "--workspace"),
+    ("exclude", "--exclude"),
+    ("features", "--features"),
+    ("all_features", "--all-features"),
+    ("no_default_features", "--no-default-features"),
+    ("release", "--release"),
+    ("profile", "--profile"),
+    ("target", "--target"),
+    ("all_targets", "--all-targets"),
+    ("lib", "--lib"),
+    ("bins", "--bins"),
+    ("tests", "--tests"),
+    ("examples", "--examples"),
+    ("jobs", "--jobs"),
+    ("locked", "--locked"),
+    ("frozen", "--frozen"),
+    ("offline", "--offline"),
+    ("extra_args", ""),
+];
+
+/// Options only `cargo_test!` accepts
+const TEST_ONLY: &[(&str, &str)] = &[("no_run", "--no-run"), ("doc", "--doc")];
+
+/// Pairs of options cargo rejects together
+const EXCLUSIVE: &[(&str, &str)] = &[
+    ("all_features", "no_default_features"),
+    ("all_features", "features"),
+    ("release", "profile"),
+    ("package", "workspace"),
+    ("locked", "frozen"),
+    ("doc", "no_run"),
+    ("doc", "all_targets"),
+];
+
+/// Whether `name` is one of the cargo helpers.
+pub fn is_helper(name: &Ident) -> bool {
+    HELPERS.contains(&name.to_string().as_str())
+}
+
+/// A parsed option value.
+enum Value {
+    Flag(bool),
+    Word(Vec<LitStr>),
+    Int(LitInt),
+    /// `extra_args`, kept verbatim
+    Raw(Vec<LitStr>),
+}
+
+/// Parse the helper's options and assemble the cargo command, spanned at
+/// `name` so validation errors point at the helper.
+pub fn parse(name: &Ident, input: ParseStream) -> Result<LitStr> {
+    let subcommand = name
+        .to_string()
+        .strip_prefix("cargo_")
+        .unwrap_or_default()
+        .to_string();
+    let test_only: &[(&str, &str)] = if subcommand == "test" { TEST_ONLY } else { &[] };
+    let known: Vec<(&str, &str)> = COMMON.iter().chain(test_only).copied().collect();
+
+    let mut options: Vec<(Ident, Value)> = Vec::new();
+    while !input.is_empty() {
+        let option: Ident = input.parse()?;
+        input.parse::<Token![:]>()?;
+        let key = option.to_string();
+        if !known.iter().any(|(k, _)| *k == key) {
+            return Err(unknown_option(&option, name, &known));
+        }
+        if options.iter().any(|(o, _)| *o == key) {
+            return Err(Error::new(
+                option.span(),
+                format!("{} is set more than once", key),
+            ));
+        }
+        let value = match key.as_str() {
+            "package" | "exclude" | "features" => Value::Word(parse_words(input, &key)?),
+            "profile" | "target" => {
+                let lit: LitStr = input.parse()?;
+                check_word(&lit, &key)?;
+                Value::Word(vec![lit])
+            }
+            "jobs" => Value::Int(input.parse()?),
+            "extra_args" => {
+                let content;
+                bracketed!(content in input);
+                let args =
+                    content.parse_terminated(<LitStr as syn::parse::Parse>::parse, Token![,])?;
+                Value::Raw(args.into_iter().collect())
+            }
+            _ => Value::Flag(input.parse::<LitBool>()?.value()),
+        };
+        options.push((option, value));
+        if input.peek(Token![,]) {
+            input.parse::<Token![,]>()?;
+        }
+    }
+
+    let is_set = |key: &str| {
+        options
+            .iter()
+            .any(|(o, v)| *o == key && !matches!(v, Value::Flag(false)))
+    };
+    for &(a, b) in EXCLUSIVE {
+        if is_set(a) && is_set(b) {
+            let span = options.iter().find(|(o, _)| o == b).unwrap().0.span();
+            return Err(Error::new(
+                span,
+                format!("{} and {} can't be used together", a, b),
+            ));
+        }
+    }
+    if is_set("exclude") && !is_set("workspace") {
+        let span = options
+            .iter()
+            .find(|(o, _)| o == "exclude")
+            .unwrap()
+            .0
+            .span();
+        return Err(Error::new(span, "exclude requires workspace: true"));
+    }
+
+    let mut command = format!("cargo {}", subcommand);
+    let mut extra = Vec::new();
+    // nb: flags go out in table order, not the order written, so the same
+    // options always give the same command
+    for (key, flag) in &known {
+        let Some((_, value)) = options.iter().find(|(o, _)| o == key) else {
+            continue;
+        };
+        match value {
+            Value::Flag(true) => {
+                command.push(' ');
+                command.push_str(flag);
+            }
+            Value::Flag(false) => {}
+            Value::Word(words) if *key == "features" => {
+                let features: Vec<String> = words.iter().map(LitStr::value).collect();
+                command.push_str(&format!(" {} {}", flag, features.join(",")));
+            }
+            Value::Word(words) => {
+                for word in words {
+                    command.push_str(&format!(" {} {}", flag, word.value()));
+                }
+            }
+            Value::Int(jobs) => {
+                let n: u32 = jobs.base10_parse()?;
+                if n == 0 {
+                    return Err(Error::new(jobs.span(), "jobs must be at least 1"));
+                }
+                command.push_str(&format!(" {} {}", flag, n));
+            }
+            Value::Raw(args) => extra = args.iter().map(LitStr::value).collect(),
+        }
+    }
+    for arg in extra {
+        command.push(' ');
+        command.push_str(&arg);
+    }
+    Ok(LitStr::new(&command, name.span()))
+}
+
+/// A string or a list of strings, each a single shell-safe word.
+fn parse_words(input: ParseStream, key: &str) -> Result<Vec<LitStr>> {
+    let words: Vec<LitStr> = if input.peek(syn::token::Bracket) {
+        let content;
+        bracketed!(content in input);
+        content
+            .parse_terminated(<LitStr as syn::parse::Parse>::parse, Token![,])?
+            .into_iter()
+            .collect()
+    } else {
+        vec![input.parse()?]
+    };
+    if words.is_empty() {
+        return Err(Error::new(input.span(), format!("{} is empty", key)));
+    }
+    for word in &words {
+        check_word(word, key)?;
+    }
+    Ok(words)
+}
+
+/// Reject names cargo wouldn't accept, which also keeps them free of
+/// anything the shell would need quoted.
+fn check_word(lit: &LitStr, key: &str) -> Result<()> {
+    let value = lit.value();
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '+' | '/');
+    if value.is_empty() || !value.chars().all(valid) || value.starts_with('-') {
+        return Err(Error::new(
+            lit.span(),
+            format!(
+                "invalid {} name {:?}: use letters, digits, '-', '_', '.', '+' and '/'",
+                key.trim_end_matches('s'),
+                value
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn unknown_option(option: &Ident, helper: &Ident, known: &[(&str, &str)]) -> Error {
+    Error::new(
+        option.span(),
+        crate::fields::unknown(
+            &format!("{}! option", helper),
+            &option.to_string(),
+            known.iter().map(|(k, _)| *k),
+        ),
+    )
+}
+
+/// Expand a helper outside `pipeline!`, where it is a plain command string.
+pub fn expand_standalone(name: &str, input: proc_macro2::TokenStream) -> Result<LitStr> {
+    let ident = Ident::new(name, Span::call_site());
+    syn::parse::Parser::parse2(|input: ParseStream| parse(&ident, input), input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(helper: &str, options: &str) -> Result<String> {
+        let ident = Ident::new(helper, Span::call_site());
+        syn::parse::Parser::parse_str(|input: ParseStream| parse(&ident, input), options)
+            .map(|lit| lit.value())
+    }
+
+    #[test]
+    fn test_assembles_flags() {
+        assert_eq!(
+            command(
+                "cargo_test",
+                r#"package: "api", features: ["postgres", "tls"], no_default_features: true"#
+            )
+            .unwrap(),
+            "cargo test --package api --features postgres,tls --no-default-features"
+        );
+        assert_eq!(
+            command(
+                "cargo_build",
+                r#"release: true, target: "x86_64-unknown-linux-gnu", jobs: 4"#
+            )
+            .unwrap(),
+            "cargo build --release --target x86_64-unknown-linux-gnu --jobs 4"
+        );
+        assert_eq!(
+            command(
+                "cargo_clippy",
+                r#"workspace: true, all_targets: true, extra_args: ["--", "-D", "warnings"]"#
+            )
+            .unwrap(),
+            "cargo clippy --workspace --all-targets -- -D warnings"
+        );
+        assert_eq!(command("cargo_build", "").unwrap(), "cargo build");
+        assert_eq!(
+            command("cargo_test", "release: false").unwrap(),
+            "cargo test"
+        );
+    }
+
+    #[test]
+    fn test_rejects_exclusive_options() {
+        let err = command(
+            "cargo_test",
+            "all_features: true, no_default_features: true",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(
+            err.contains("all_features and no_default_features"),
+            "{}",
+            err
+        );
+        let err = command("cargo_build", r#"release: true, profile: "ci""#)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("release and profile"), "{}", err);
+        assert!(
+            command(
+                "cargo_build",
+                "all_features: false, no_default_features: true"
+            )
+            .is_ok()
+        );
+        let err = command("cargo_build", r#"exclude: "docs""#)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("requires workspace"), "{}", err);
+    }
+
+    #[test]
+    fn test_rejects_unknown_and_invalid_options() {
+        let err = command("cargo_test", "no_default_feature: true")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("did you mean 'no_default_features'?"),
+            "{}",
+            err
+        );
+        let err = command("cargo_build", "no_run: true")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("unknown cargo_build! option"), "{}", err);
+        let err = command("cargo_test", r#"features: ["a b"]"#)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("invalid feature name"), "{}", err);
+        let err = command("cargo_test", "release: true, release: true")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("more than once"), "{}", err);
+    }
+}
@@ -0,0 +1,302 @@
+//! Stable codes for errors raised by the validation pass.
+//!
+//! Every validation error is prefixed with its code, e.g. `[RBK0007]`, so
+//! tooling can match on it, and each code but [`UNKNOWN_FIELD`], a parse
+//! error, can be given a level in the `lints:` field. Codes are never renumbered or reused for a different
+//! check once published; a retired check keeps its entry here.
+
+use proc_macro2::Span;
+use syn::Error;
+
+/// A stable identifier for one class of validation error.
+#[derive(Clone, Copy)]
+pub struct Code {
+    /// The `RBKnnnn` identifier shown in messages and accepted by `lints:`
+    pub id: &'static str,
+    /// Snake-case name; for lints this is also the `lints:` field name
+    pub name: &'static str,
+    /// One-line summary of the check
+    pub description: &'static str,
+}
+
+impl Code {
+    /// Build an error at `span` with the message prefixed by this code.
+    pub fn error(&self, span: Span, message: impl std::fmt::Display) -> Error {
+        Error::new(span, format!("[{}] {}", self.id, message))
+    }
+}
+
+pub const INVALID_STEP_KEY: Code = Code {
+    id: "RBK0001",
+    name: "invalid_step_key",
+    description: "step key is empty or looks like a UUID",
+};
+
+pub const UNKNOWN_DEPENDENCY: Code = Code {
+    id: "RBK0002",
+    name: "unknown_dependency",
+    description: "depends_on names a key no step defines",
+};
+
+pub const INVALID_CONDITION: Code = Code {
+    id: "RBK0003",
+    name: "invalid_condition",
+    description: "`if` is not a valid Buildkite conditional expression",
+};
+
+pub const MISSING_PATH: Code = Code {
+    id: "RBK0004",
+    name: "missing_path",
+    description: "path-based command or local plugin does not exist at compile time",
+};
+
+pub const UNDEFINED_ENV_VAR: Code = Code {
+    id: "RBK0005",
+    name: "undefined_env_var",
+    description: "command reads an environment variable that is never defined",
+};
+
+pub const CONCURRENCY_GROUP_POLICY: Code = Code {
+    id: "RBK0006",
+    name: "concurrency_group_policy",
+    description: "concurrency_group does not satisfy concurrency_group_policy",
+};
+
+pub const DUPLICATE_STEP_KEY: Code = Code {
+    id: "RBK0007",
+    name: "duplicate_step_key",
+    description: "two steps share the same key",
+};
+
+pub const UNDEFINED_CONCURRENCY_GROUP_VAR: Code = Code {
+    id: "RBK0008",
+    name: "undefined_concurrency_group_var",
+    description: "concurrency_group interpolates an environment variable that is never defined",
+};
+
+pub const NO_STRUCTURE: Code = Code {
+    id: "RBK0009",
+    name: "no_structure",
+    description: "many command steps but no wait steps or depends_on",
+};
+
+pub const REDUNDANT_DEPENDS_ON: Code = Code {
+    id: "RBK0010",
+    name: "redundant_depends_on",
+    description: "depends_on edge already implied by a wait step",
+};
+
+pub const PARALLELISM_WITH_MATRIX: Code = Code {
+    id: "RBK0011",
+    name: "parallelism_with_matrix",
+    description: "step sets both parallelism and matrix",
+};
+
+pub const DISALLOWED_COMMAND: Code = Code {
+    id: "RBK0012",
+    name: "disallowed_command",
+    description: "command is not in allowed_commands (or on PATH)",
+};
+
+pub const PARALLEL_VARS_WITHOUT_PARALLELISM: Code = Code {
+    id: "RBK0013",
+    name: "parallel_vars_without_parallelism",
+    description: "command reads BUILDKITE_PARALLEL_JOB(_COUNT) on a step without parallelism",
+};
+
+pub const PARALLELISM_WITHOUT_SHARDING: Code = Code {
+    id: "RBK0014",
+    name: "parallelism_without_sharding",
+    description: "parallel step whose commands never shard their work",
+};
+
+pub const STEP_LIMIT: Code = Code {
+    id: "RBK0015",
+    name: "step_limit",
+    description: "pipeline expands to more steps than max_steps",
+};
+
+pub const GROUP_STEP_LIMIT: Code = Code {
+    id: "RBK0016",
+    name: "group_step_limit",
+    description: "group expands to more steps than max_group_steps",
+};
+
+pub const BAZEL_TEST_TIMEOUT: Code = Code {
+    id: "RBK0017",
+    name: "bazel_test_timeout",
+    description: "bazel test step sets neither timeout_in_minutes nor --test_timeout",
+};
+
+pub const DUPLICATE_BAZEL_TARGETS: Code = Code {
+    id: "RBK0018",
+    name: "duplicate_bazel_targets",
+    description: "two steps run the same bazel command over the same targets",
+};
+
+pub const UNMATCHED_ARTIFACT_DOWNLOAD: Code = Code {
+    id: "RBK0019",
+    name: "unmatched_artifact_download",
+    description: "needs_artifacts names a step whose artifact_paths don't match",
+};
+
+pub const UNKNOWN_LABEL_VAR: Code = Code {
+    id: "RBK0020",
+    name: "unknown_label_var",
+    description: "step label interpolates a variable nothing defines",
+};
+
+pub const MALFORMED_LABEL_INTERPOLATION: Code = Code {
+    id: "RBK0021",
+    name: "malformed_label_interpolation",
+    description: "step label has an empty or unterminated `${`",
+};
+
+pub const UNREACHABLE_CONDITION: Code = Code {
+    id: "RBK0022",
+    name: "unreachable_condition",
+    description: "step `if` or branches filter contradicts pipeline_context",
+};
+
+pub const UNKNOWN_COMPOSE_SERVICE: Code = Code {
+    id: "RBK0023",
+    name: "unknown_compose_service",
+    description: "docker_compose names a service its compose file doesn't define",
+};
+
+pub const SHADOWED_ENV: Code = Code {
+    id: "RBK0024",
+    name: "shadowed_env",
+    description: "step env sets a variable the pipeline env already sets",
+};
+
+pub const LOWERCASE_ENV_NAME: Code = Code {
+    id: "RBK0025",
+    name: "lowercase_env_name",
+    description: "env var name has lowercase letters",
+};
+
+pub const BARE_IDENT_VALUE: Code = Code {
+    id: "RBK0026",
+    name: "bare_ident_value",
+    description: "bare identifier in retry, matrix, notify or build is read as a string",
+};
+
+pub const SHELL_INFO: Code = Code {
+    id: "RBK0027",
+    name: "shell_info",
+    description: "bashrs info-level diagnostic for a command",
+};
+
+pub const UNKEYED_BLOCK_FIELDS: Code = Code {
+    id: "RBK0028",
+    name: "unkeyed_block_fields",
+    description: "block or input step without a key has fields a later step reads",
+};
+
+pub const UNKNOWN_META_DATA: Code = Code {
+    id: "RBK0029",
+    name: "unknown_meta_data",
+    description: "command reads meta-data no field, meta-data set or external_meta_data provides",
+};
+
+pub const UNREAD_META_DATA: Code = Code {
+    id: "RBK0030",
+    name: "unread_meta_data",
+    description: "block or input field no command reads with meta-data get",
+};
+
+pub const UNCHECKED_COMMAND: Code = Code {
+    id: "RBK0031",
+    name: "unchecked_command",
+    description: "unchecked_cmd! without allow_unchecked_commands",
+};
+
+pub const UNACKNOWLEDGED_RUNTIME_COMMAND: Code = Code {
+    id: "RBK0032",
+    name: "unacknowledged_runtime_command",
+    description: "runtime command on a step without skip_validation",
+};
+
+pub const BAZEL_TARGETS: Code = Code {
+    id: "RBK0033",
+    name: "bazel_targets",
+    description: "bazel query could not resolve a command's targets",
+};
+
+pub const LAZY_RUNTIME_VALUE: Code = Code {
+    id: "RBK0034",
+    name: "lazy_runtime_value",
+    description: "lazy pipeline has a value computed at runtime",
+};
+
+pub const UNKNOWN_FIELD: Code = Code {
+    id: "RBK0035",
+    name: "unknown_field",
+    description: "field, method or option the macro doesn't recognize",
+};
+
+/// Every published code, in numeric order.
+pub const ALL: &[Code] = &[
+    INVALID_STEP_KEY,
+    UNKNOWN_DEPENDENCY,
+    INVALID_CONDITION,
+    MISSING_PATH,
+    UNDEFINED_ENV_VAR,
+    CONCURRENCY_GROUP_POLICY,
+    DUPLICATE_STEP_KEY,
+    UNDEFINED_CONCURRENCY_GROUP_VAR,
+    NO_STRUCTURE,
+    REDUNDANT_DEPENDS_ON,
+    PARALLELISM_WITH_MATRIX,
+    DISALLOWED_COMMAND,
+    PARALLEL_VARS_WITHOUT_PARALLELISM,
+    PARALLELISM_WITHOUT_SHARDING,
+    STEP_LIMIT,
+    GROUP_STEP_LIMIT,
+    BAZEL_TEST_TIMEOUT,
+    DUPLICATE_BAZEL_TARGETS,
+    UNMATCHED_ARTIFACT_DOWNLOAD,
+    UNKNOWN_LABEL_VAR,
+    MALFORMED_LABEL_INTERPOLATION,
+    UNREACHABLE_CONDITION,
+    UNKNOWN_COMPOSE_SERVICE,
+    SHADOWED_ENV,
+    LOWERCASE_ENV_NAME,
+    BARE_IDENT_VALUE,
+    SHELL_INFO,
+    UNKEYED_BLOCK_FIELDS,
+    UNKNOWN_META_DATA,
+    UNREAD_META_DATA,
+    UNCHECKED_COMMAND,
+    UNACKNOWLEDGED_RUNTIME_COMMAND,
+    BAZEL_TARGETS,
+    LAZY_RUNTIME_VALUE,
+    UNKNOWN_FIELD,
+];
+
+/// Look up a code by its `RBKnnnn` identifier.
+pub fn lookup(id: &str) -> Option<&'static Code> {
+    ALL.iter().find(|code| code.id == id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes_are_unique_and_sequential() {
+        for (index, code) in ALL.iter().enumerate() {
+            assert_eq!(code.id, format!("RBK{:04}", index + 1));
+        }
+    }
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(
+            lookup("RBK0007").map(|c| c.name),
+            Some("duplicate_step_key")
+        );
+        assert!(lookup("RBK9999").is_none());
+    }
+}
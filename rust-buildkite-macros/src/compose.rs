@@ -0,0 +1,415 @@
+//! `.docker_compose(...)`: the docker-compose plugin with checked options.
+//!
+//! `.docker_compose(run: "app", config: "docker-compose.ci.yml")` adds the
+//! same plugin entry as writing it out with `.plugin(...)`, with snake_case
+//! option names turned into the plugin's hyphenated ones. Unknown options and
+//! values of the wrong shape are errors.
+//!
+//! During validation, each compose file named by `config` is read and the
+//! services named by `run`, `build` and `push` are looked up in it. A missing
+//! compose file is a missing path, which `expect_paths` can allow. Without
+//! `config` the plugin reads `docker-compose.yml`, which is checked only if it
+//! exists.
+//!
+//! The plugin version is [`DEFAULT_VERSION`] unless the pipeline pins one with
+//! `plugin_versions: { "docker-compose": "..." }`.
+
+use crate::{NestedValue, closest_matches, edit_distance};
+use proc_macro2::Span;
+use serde_yaml::Value;
+use std::path::Path;
+use syn::parse::ParseStream;
+use syn::{Error, Ident, LitStr, Result, Token};
+
+/// Plugin name, without a version
+pub const PLUGIN: &str = "docker-compose";
+
+/// Version used when the pipeline doesn't set one in `plugin_versions`
+pub const DEFAULT_VERSION: &str = "v5.4.1";
+
+/// Compose file the plugin reads when `config` isn't set
+const DEFAULT_CONFIG: &str = "docker-compose.yml";
+
+/// The shape an option's value must have.
+#[derive(Clone, Copy, PartialEq)]
+enum Shape {
+    String,
+    Bool,
+    Int,
+    List,
+    StringOrList,
+}
+
+/// Options the helper accepts, as written in the macro
+const OPTIONS: &[(&str, Shape)] = &[
+    ("run", Shape::String),
+    ("build", Shape::StringOrList),
+    ("push", Shape::StringOrList),
+    ("config", Shape::StringOrList),
+    ("env", Shape::List),
+    ("environment", Shape::List),
+    ("volumes", Shape::List),
+    ("pull", Shape::StringOrList),
+    ("command", Shape::List),
+    ("entrypoint", Shape::String),
+    ("workdir", Shape::String),
+    ("user", Shape::String),
+    ("image_repository", Shape::String),
+    ("cache_from", Shape::List),
+    ("args", Shape::List),
+    ("target", Shape::String),
+    ("dependencies", Shape::Bool),
+    ("mount_buildkite_agent", Shape::Bool),
+    ("mount_checkout", Shape::Bool),
+    ("propagate_environment", Shape::Bool),
+    ("propagate_uid_gid", Shape::Bool),
+    ("skip_checkout", Shape::Bool),
+    ("service_ports", Shape::Bool),
+    ("tty", Shape::Bool),
+    ("rm", Shape::Bool),
+    ("graceful_shutdown", Shape::Bool),
+    ("cli_version", Shape::Int),
+];
+
+/// What validation needs to check one `.docker_compose(...)` call.
+#[derive(Clone)]
+pub struct ComposeCheck {
+    /// Index of the plugin in the step's plugin list, for `plugin_versions`
+    pub plugin_index: usize,
+    /// Compose files from `config`; empty when it isn't set
+    pub files: Vec<LitStr>,
+    /// Services named by `run`, `build` and `push`
+    pub services: Vec<LitStr>,
+    /// The `.docker_compose` method, for errors about the default compose file
+    pub span: Span,
+}
+
+/// `docker-compose#<version>`
+pub fn plugin_key(version: &str) -> String {
+    format!("{}#{}", PLUGIN, version)
+}
+
+/// Parse the options of `.docker_compose(...)` into the plugin entry and the
+/// checks to run on it. `plugin_index` is where the step will store it.
+pub fn parse(
+    input: ParseStream,
+    span: Span,
+    plugin_index: usize,
+) -> Result<(NestedValue, ComposeCheck)> {
+    let mut pairs: Vec<(String, NestedValue)> = Vec::new();
+    let mut check = ComposeCheck {
+        plugin_index,
+        files: Vec::new(),
+        services: Vec::new(),
+        span,
+    };
+    let mut seen: Vec<String> = Vec::new();
+    while !input.is_empty() {
+        let option: Ident = input.parse()?;
+        input.parse::<Token![:]>()?;
+        let name = option.to_string();
+        let Some(&(_, shape)) = OPTIONS.iter().find(|(o, _)| *o == name) else {
+            return Err(unknown_option(&option));
+        };
+        if seen.contains(&name) {
+            return Err(Error::new(
+                option.span(),
+                format!("{} is set more than once", name),
+            ));
+        }
+        seen.push(name.clone());
+
+        let value_span = input.span();
+        let (value, strings) = parse_value(input, shape, &name, value_span)?;
+        match name.as_str() {
+            "config" => check.files = strings,
+            "run" | "build" => check.services.extend(strings),
+            // nb: push entries are `service` or `service:image[:tag]`
+            "push" => check.services.extend(strings.iter().map(|s| {
+                let service = s.value();
+                let service = service.split(':').next().unwrap_or_default();
+                LitStr::new(service, s.span())
+            })),
+            _ => {}
+        }
+        pairs.push((name.replace('_', "-"), value));
+        if input.peek(Token![,]) {
+            input.parse::<Token![,]>()?;
+        }
+    }
+    if !["run", "build", "push"]
+        .iter()
+        .any(|o| seen.iter().any(|s| s == o))
+    {
+        return Err(Error::new(
+            span,
+            "docker_compose needs at least one of run, build or push",
+        ));
+    }
+    let plugin = NestedValue::Object(vec![(
+        plugin_key(DEFAULT_VERSION),
+        NestedValue::Object(pairs),
+    )]);
+    Ok((plugin, check))
+}
+
+/// Parse one option value of `shape`, returning it with any string literals
+/// it holds.
+fn parse_value(
+    input: ParseStream,
+    shape: Shape,
+    name: &str,
+    span: Span,
+) -> Result<(NestedValue, Vec<LitStr>)> {
+    let expected = match shape {
+        Shape::String => "a string",
+        Shape::Bool => "true or false",
+        Shape::Int => "an integer",
+        Shape::List => "a list of strings",
+        Shape::StringOrList => "a string or a list of strings",
+    };
+    let wrong = || Error::new(span, format!("{} must be {}", name, expected));
+    if input.peek(syn::token::Bracket) {
+        if !matches!(shape, Shape::List | Shape::StringOrList) {
+            return Err(wrong());
+        }
+        let content;
+        syn::bracketed!(content in input);
+        let items = content
+            .parse_terminated(<LitStr as syn::parse::Parse>::parse, Token![,])
+            .map_err(|_| wrong())?;
+        let items: Vec<LitStr> = items.into_iter().collect();
+        let value = NestedValue::Array(
+            items
+                .iter()
+                .map(|lit| NestedValue::String(lit.value()))
+                .collect(),
+        );
+        Ok((value, items))
+    } else if input.peek(LitStr) {
+        if !matches!(shape, Shape::String | Shape::StringOrList) {
+            return Err(wrong());
+        }
+        let lit: LitStr = input.parse()?;
+        Ok((NestedValue::String(lit.value()), vec![lit]))
+    } else if input.peek(syn::LitBool) {
+        if shape != Shape::Bool {
+            return Err(wrong());
+        }
+        let lit: syn::LitBool = input.parse()?;
+        Ok((NestedValue::Bool(lit.value()), Vec::new()))
+    } else if input.peek(syn::LitInt) {
+        if shape != Shape::Int {
+            return Err(wrong());
+        }
+        let lit: syn::LitInt = input.parse()?;
+        Ok((NestedValue::Int(lit.base10_parse()?), Vec::new()))
+    } else {
+        Err(wrong())
+    }
+}
+
+fn unknown_option(option: &Ident) -> Error {
+    Error::new(
+        option.span(),
+        crate::fields::unknown(
+            "docker_compose option",
+            &option.to_string(),
+            OPTIONS.iter().map(|(o, _)| *o),
+        ),
+    )
+}
+
+/// A problem found by [`check`]: whether it is a missing file, where to
+/// report it, and the message.
+pub struct Problem {
+    pub missing_file: bool,
+    pub span: Span,
+    pub message: String,
+}
+
+/// Read the compose files and check the named services are defined in them.
+pub fn check(check: &ComposeCheck, allow_missing: &[&str], base: Option<&Path>) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let files: Vec<(String, Span, bool)> = if check.files.is_empty() {
+        vec![(DEFAULT_CONFIG.to_string(), check.span, true)]
+    } else {
+        check
+            .files
+            .iter()
+            .map(|f| (f.value(), f.span(), false))
+            .collect()
+    };
+
+    let mut services: Vec<String> = Vec::new();
+    for (file, span, is_default) in &files {
+        let path = crate::CmdExpr::resolve_path(file, base);
+        let path = std::path::absolute(&path).unwrap_or(path);
+        crate::track::track_file(&path);
+        let Ok(contents) = std::fs::read_to_string(&path) else {
+            if *is_default || allow_missing.contains(&file.as_str()) {
+                return problems;
+            }
+            problems.push(Problem {
+                missing_file: true,
+                span: *span,
+                message: format!(
+                    "Compose file '{}' does not exist (checked {}).\n\
+                     If this path will exist at runtime, add it to expect_paths.",
+                    file,
+                    path.display()
+                ),
+            });
+            continue;
+        };
+        match service_names(&contents) {
+            Ok(names) => services.extend(names),
+            Err(e) => problems.push(Problem {
+                missing_file: false,
+                span: *span,
+                message: format!("could not parse compose file '{}': {}", file, e),
+            }),
+        }
+    }
+    if !problems.is_empty() {
+        return problems;
+    }
+
+    for service in &check.services {
+        let name = service.value();
+        if services.contains(&name) {
+            continue;
+        }
+        let suggestion = closest_matches(&name, services.iter().map(String::as_str), 1)
+            .into_iter()
+            .find(|s| edit_distance(&name, s) <= 3)
+            .map(|s| format!(" Did you mean '{}'?", s))
+            .unwrap_or_default();
+        let file_list: Vec<&str> = files.iter().map(|(f, _, _)| f.as_str()).collect();
+        problems.push(Problem {
+            missing_file: false,
+            span: service.span(),
+            message: format!(
+                "service '{}' is not defined in {}.{} Services: {}",
+                name,
+                file_list.join(", "),
+                suggestion,
+                if services.is_empty() {
+                    "none".to_string()
+                } else {
+                    services.join(", ")
+                }
+            ),
+        });
+    }
+    problems
+}
+
+/// The service names a compose file defines.
+fn service_names(contents: &str) -> std::result::Result<Vec<String>, serde_yaml::Error> {
+    let doc: Value = serde_yaml::from_str(contents)?;
+    // nb: version 1 files put services at the top level
+    let services = match doc.get("services") {
+        Some(services) => services,
+        None => &doc,
+    };
+    Ok(services
+        .as_mapping()
+        .map(|map| {
+            map.keys()
+                .filter_map(Value::as_str)
+                .filter(|name| doc.get("services").is_some() || *name != "version")
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(source: &str) -> Result<(NestedValue, ComposeCheck)> {
+        syn::parse::Parser::parse_str(
+            |input: ParseStream| parse(input, Span::call_site(), 0),
+            source,
+        )
+    }
+
+    #[test]
+    fn test_matches_handwritten_plugin() {
+        let (plugin, check) = parse_str(
+            r#"run: "app", config: ["docker-compose.yml", "docker-compose.ci.yml"], mount_buildkite_agent: true, cli_version: 2"#,
+        )
+        .unwrap();
+        let handwritten = syn::parse::Parser::parse_str(
+            NestedValue::parse,
+            &format!(
+                r#"{{ "docker-compose#{}": {{
+                    run: "app",
+                    config: ["docker-compose.yml", "docker-compose.ci.yml"],
+                    "mount-buildkite-agent": true,
+                    "cli-version": 2
+                }} }}"#,
+                DEFAULT_VERSION
+            ),
+        )
+        .unwrap();
+        assert_eq!(
+            plugin.to_json_tokens().to_string(),
+            handwritten.to_json_tokens().to_string()
+        );
+        assert_eq!(check.files.len(), 2);
+        assert_eq!(check.services[0].value(), "app");
+    }
+
+    #[test]
+    fn test_rejects_bad_options() {
+        let err = |source: &str| parse_str(source).err().unwrap().to_string();
+        assert!(err(r#"rn: "app""#).contains("did you mean 'run'?"));
+        assert!(err(r#"run: ["app"]"#).contains("run must be a string"));
+        assert!(err(r#"run: "app", tty: "yes""#).contains("tty must be true or false"));
+        assert!(err(r#"config: "ci.yml""#).contains("at least one of run, build or push"));
+        assert!(err(r#"run: "app", run: "web""#).contains("more than once"));
+    }
+
+    #[test]
+    fn test_checks_services() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("docker-compose.ci.yml"),
+            "services:\n  app:\n    image: app\n  db:\n    image: postgres\n",
+        )
+        .unwrap();
+        let (_, check_ok) = parse_str(
+            r#"run: "app", push: "db:registry/db:latest", config: "docker-compose.ci.yml""#,
+        )
+        .unwrap();
+        assert!(check(&check_ok, &[], Some(dir.path())).is_empty());
+
+        let (_, typo) = parse_str(r#"run: "ap", config: "docker-compose.ci.yml""#).unwrap();
+        let problems = check(&typo, &[], Some(dir.path()));
+        assert_eq!(problems.len(), 1);
+        assert!(
+            problems[0]
+                .message
+                .contains("service 'ap' is not defined in docker-compose.ci.yml. Did you mean 'app'? Services: app, db"),
+            "{}",
+            problems[0].message
+        );
+
+        let (_, missing) = parse_str(r#"run: "app", config: "compose.prod.yml""#).unwrap();
+        let problems = check(&missing, &[], Some(dir.path()));
+        assert!(problems[0].missing_file);
+        assert!(
+            problems[0]
+                .message
+                .contains(&dir.path().join("compose.prod.yml").display().to_string())
+        );
+        assert!(check(&missing, &["compose.prod.yml"], Some(dir.path())).is_empty());
+
+        // The default compose file is only checked when it exists
+        let (_, default) = parse_str(r#"run: "app""#).unwrap();
+        assert!(check(&default, &[], Some(dir.path())).is_empty());
+    }
+}
@@ -0,0 +1,315 @@
+//! Helpers for writing `if` conditionals without hand-quoting them.
+//!
+//! `on_branch!("main")`, `not_pull_request!()`, `on_tag!()` and
+//! `pr_base_branch!("release/*")` each stand for one common conditional, and
+//! `all!`/`any!` join conditions with `&&`/`||`. Inside `pipeline!` an `if`
+//! field takes a helper in place of its string literal; the helper is
+//! resolved to a literal while parsing, so the usual conditional validation
+//! still runs on the result. Outside the macro, the same helpers expand to a
+//! `&str` literal.
+//!
+//! Branch and tag arguments are matched exactly unless they contain `*`,
+//! which matches any run of characters and turns the test into a regex.
+//!
+//! `.skip_if(...)` and `.skip_unless(...)` on a command step take the same
+//! conditions and emit them as the step's `if`, since Buildkite's `skip` only
+//! takes a bool or a reason. `skip_unless(c)` runs the step when `c` holds, so
+//! it emits `c` unchanged; `skip_if(c)` emits [`negate`]`(c)`.
+
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use syn::parse::{ParseStream, Parser};
+use syn::punctuated::Punctuated;
+use syn::spanned::Spanned;
+use syn::{Error, Ident, LitStr, Result, Token};
+
+/// Names of the helper macros, for error messages.
+const HELPERS: &[&str] = &[
+    "on_branch",
+    "not_pull_request",
+    "on_tag",
+    "pr_base_branch",
+    "all",
+    "any",
+];
+
+/// Parse a condition: a string literal, or a helper invocation resolved to
+/// one.
+pub fn parse(input: ParseStream) -> Result<LitStr> {
+    if input.peek(Ident) && input.peek2(Token![!]) {
+        let mac: syn::Macro = input.parse()?;
+        let name = mac
+            .path
+            .get_ident()
+            .map(Ident::to_string)
+            .unwrap_or_default();
+        let value = resolve(&name, mac.path.span(), mac.tokens.clone())?;
+        Ok(LitStr::new(&value, mac.span()))
+    } else {
+        input.parse()
+    }
+}
+
+/// The conditional for helper `name` called with `args`.
+pub fn resolve(name: &str, span: Span, args: TokenStream2) -> Result<String> {
+    match name {
+        "on_branch" => pattern_test("build.branch", &single_arg(name, span, args)?),
+        "pr_base_branch" => pattern_test(
+            "build.pull_request.base_branch",
+            &single_arg(name, span, args)?,
+        ),
+        "on_tag" => {
+            if args.is_empty() {
+                Ok("build.tag != null".to_string())
+            } else {
+                pattern_test("build.tag", &single_arg(name, span, args)?)
+            }
+        }
+        "not_pull_request" => {
+            if !args.is_empty() {
+                return Err(Error::new_spanned(
+                    args,
+                    "not_pull_request! takes no arguments",
+                ));
+            }
+            Ok("build.pull_request.id == null".to_string())
+        }
+        "all" => combine(name, "&&", span, args),
+        "any" => combine(name, "||", span, args),
+        other => Err(Error::new(
+            span,
+            format!(
+                "expected a string literal or a condition helper ({}), found `{}!`",
+                HELPERS
+                    .iter()
+                    .map(|h| format!("{}!", h))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                other
+            ),
+        )),
+    }
+}
+
+/// Message for combining `.skip_if`/`.skip_unless` with another condition.
+pub const SKIP_CONDITION_CONFLICT: &str = "skip_if and skip_unless set the step's `if`, so a step \
+     can have only one of them and no `.if(...)`; join the conditions with all! or any! instead";
+
+/// Parse the condition of `.skip_if(...)` (`negated`) or `.skip_unless(...)`
+/// and return the `if` to emit. The condition is validated before negating,
+/// so a malformed one can't change meaning inside the parentheses.
+pub fn parse_skip(input: ParseStream, negated: bool) -> Result<LitStr> {
+    let condition = parse(input)?;
+    if let Err(problems) = crate::buildkite_conditional::validate_condition(&condition.value()) {
+        return Err(Error::new(
+            condition.span(),
+            format!("Invalid Buildkite conditional: {}", problems.join("; ")),
+        ));
+    }
+    if negated {
+        Ok(LitStr::new(&negate(&condition.value()), condition.span()))
+    } else {
+        Ok(condition)
+    }
+}
+
+/// The negation rule for `skip_if`: `c` becomes `!(c)`. The parentheses keep
+/// `!` applying to the whole condition rather than its first operand.
+pub fn negate(condition: &str) -> String {
+    format!("!({})", condition.trim())
+}
+
+fn single_arg(name: &str, span: Span, args: TokenStream2) -> Result<LitStr> {
+    let parsed = Punctuated::<LitStr, Token![,]>::parse_terminated
+        .parse2(args)
+        .map_err(|_| {
+            Error::new(
+                span,
+                format!(
+                    "{}! requires a string literal, e.g. {}!(\"main\")",
+                    name, name
+                ),
+            )
+        })?;
+    match parsed.len() {
+        1 => Ok(parsed.into_iter().next().unwrap()),
+        _ => Err(Error::new(
+            span,
+            format!("{}! takes exactly one string literal", name),
+        )),
+    }
+}
+
+/// `subject == 'value'`, or a regex match when `value` has a `*` wildcard.
+fn pattern_test(subject: &str, value: &LitStr) -> Result<String> {
+    let value_str = value.value();
+    if value_str.is_empty() {
+        return Err(Error::new(value.span(), "branch or tag name is empty"));
+    }
+    if value_str.contains('*') {
+        Ok(format!("{} =~ /^{}$/", subject, glob_regex(&value_str)))
+    } else {
+        let quoted = value_str.replace('\\', "\\\\").replace('\'', "\\'");
+        Ok(format!("{} == '{}'", subject, quoted))
+    }
+}
+
+/// A regex body matching `glob`, where `*` matches any run of characters.
+fn glob_regex(glob: &str) -> String {
+    let mut out = String::new();
+    for c in glob.chars() {
+        match c {
+            '*' => out.push_str(".*"),
+            '\\' | '.' | '+' | '?' | '(' | ')' | '[' | ']' | '{' | '}' | '|' | '^' | '$' | '/' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Join conditions with `op`, parenthesizing any that are compound.
+fn combine(name: &str, op: &str, span: Span, args: TokenStream2) -> Result<String> {
+    let conditions =
+        (|input: ParseStream| Punctuated::<LitStr, Token![,]>::parse_terminated_with(input, parse))
+            .parse2(args)?;
+    if conditions.is_empty() {
+        return Err(Error::new(
+            span,
+            format!("{}! needs at least one condition", name),
+        ));
+    }
+    let parts: Vec<String> = conditions
+        .iter()
+        .map(|c| {
+            let c = c.value();
+            if conditions.len() > 1 && (c.contains("&&") || c.contains("||")) {
+                format!("({})", c)
+            } else {
+                c
+            }
+        })
+        .collect();
+    Ok(parts.join(&format!(" {} ", op)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn condition(source: &str) -> Result<String> {
+        parse.parse_str(source).map(|lit| lit.value())
+    }
+
+    #[test]
+    fn test_helpers_quote_their_arguments() {
+        assert_eq!(
+            condition(r#"on_branch!("main")"#).unwrap(),
+            "build.branch == 'main'"
+        );
+        assert_eq!(
+            condition("not_pull_request!()").unwrap(),
+            "build.pull_request.id == null"
+        );
+        assert_eq!(condition("on_tag!()").unwrap(), "build.tag != null");
+        assert_eq!(
+            condition(r#"on_branch!("it's")"#).unwrap(),
+            r"build.branch == 'it\'s'"
+        );
+        assert_eq!(
+            condition(r#""build.tag == null""#).unwrap(),
+            "build.tag == null"
+        );
+    }
+
+    #[test]
+    fn test_wildcards_become_regexes() {
+        assert_eq!(
+            condition(r#"pr_base_branch!("release/*")"#).unwrap(),
+            r"build.pull_request.base_branch =~ /^release\/.*$/"
+        );
+        assert_eq!(
+            condition(r#"on_tag!("v1.*")"#).unwrap(),
+            r"build.tag =~ /^v1\..*$/"
+        );
+    }
+
+    #[test]
+    fn test_combinators_nest() {
+        assert_eq!(
+            condition(r#"all!(on_branch!("main"), not_pull_request!())"#).unwrap(),
+            "build.branch == 'main' && build.pull_request.id == null"
+        );
+        assert_eq!(
+            condition(r#"any!(all!(on_branch!("main"), not_pull_request!()), on_tag!())"#).unwrap(),
+            "(build.branch == 'main' && build.pull_request.id == null) || build.tag != null"
+        );
+        assert_eq!(
+            condition(r#"all!("build.source == 'schedule'")"#).unwrap(),
+            "build.source == 'schedule'"
+        );
+    }
+
+    #[test]
+    fn test_results_are_valid_conditionals() {
+        for source in [
+            r#"on_branch!("main")"#,
+            r#"on_branch!("it's")"#,
+            r#"on_branch!("feature/*")"#,
+            r#"pr_base_branch!("release/*")"#,
+            r#"on_tag!("v1.*")"#,
+            r#"any!(all!(on_branch!("main"), not_pull_request!()), on_tag!())"#,
+        ] {
+            let value = condition(source).unwrap();
+            assert!(
+                crate::buildkite_conditional::validate_condition(&value).is_ok(),
+                "{} -> {}",
+                source,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_skip_conditions() {
+        let skip = |source: &str, negated: bool| {
+            (|input: ParseStream| parse_skip(input, negated))
+                .parse_str(source)
+                .map(|lit| lit.value())
+        };
+        assert_eq!(
+            skip(r#""build.branch == 'main'""#, false).unwrap(),
+            "build.branch == 'main'"
+        );
+        assert_eq!(
+            skip(r#""build.pull_request.draft == true""#, true).unwrap(),
+            "!(build.pull_request.draft == true)"
+        );
+        assert_eq!(
+            skip(r#"any!(on_tag!(), on_branch!("main"))"#, true).unwrap(),
+            "!(build.tag != null || build.branch == 'main')"
+        );
+        let value = skip(r#""build.tag == null && build.branch == 'main'""#, true).unwrap();
+        assert!(crate::buildkite_conditional::validate_condition(&value).is_ok());
+        // Wrapping would otherwise balance these parentheses
+        let err = skip(r#""build.tag == null) || (build.branch == 'main'""#, true)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Invalid Buildkite conditional"), "{}", err);
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = condition("on_branch!()").unwrap_err().to_string();
+        assert!(err.contains("exactly one"), "{}", err);
+        let err = condition("on_branch!(main)").unwrap_err().to_string();
+        assert!(err.contains("requires a string literal"), "{}", err);
+        let err = condition("all!()").unwrap_err().to_string();
+        assert!(err.contains("at least one"), "{}", err);
+        let err = condition(r#"on_brnch!("main")"#).unwrap_err().to_string();
+        assert!(err.contains("`on_brnch!`"), "{}", err);
+        let err = condition(r#"on_branch!("")"#).unwrap_err().to_string();
+        assert!(err.contains("empty"), "{}", err);
+    }
+}
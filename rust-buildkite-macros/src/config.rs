@@ -0,0 +1,341 @@
+//! Workspace configuration from `.rust-buildkite.toml`.
+//!
+//! The file is found like the Bazel workspace: the nearest one in the script
+//! directory or its parents, unless `RUST_BUILDKITE_CONFIG` names another
+//! file. It supplies baselines underneath each `pipeline!` and `bazel!`:
+//!
+//! ```toml
+//! [pipeline]
+//! additional_commands = ["ci/tools/notify"]
+//! expect_env = ["SHELL_ENV", "DEPLOY_TOKEN"]
+//! path_base = "ci"
+//!
+//! [lints]
+//! redundant_depends_on = "deny"
+//! max_steps = 200
+//!
+//! [bazel]
+//! custom_verbs = ["lint", "format"]
+//! ```
+//!
+//! Settings given in the macro win. `allowed_commands`, `expect_env` and
+//! `path_base` from the macro replace the file's; `additional_commands`,
+//! `expect_paths` and `custom_verbs` add to them, and lints are set one by
+//! one on top of the file's levels.
+//!
+//! Unknown keys are errors, so a misspelled setting fails the build instead
+//! of being ignored. Expansions that read the file track it, so an edit to
+//! it triggers a rebuild.
+
+use crate::lints::{LintConfig, Setting};
+use crate::{ExpectEnvItem, PipelineDef, expand_known_env_list};
+use proc_macro2::Span;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+pub const FILE_NAME: &str = ".rust-buildkite.toml";
+
+/// Environment variable naming a config file to use instead of the discovered one
+pub const PATH_ENV: &str = "RUST_BUILDKITE_CONFIG";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub pipeline: PipelineConfig,
+    #[serde(default)]
+    pub lints: BTreeMap<String, LintValue>,
+    #[serde(default)]
+    pub bazel: BazelConfig,
+    /// Directory holding the file, which `path_base` is relative to
+    #[serde(skip)]
+    pub dir: PathBuf,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PipelineConfig {
+    #[serde(default)]
+    pub allowed_commands: Option<Vec<String>>,
+    #[serde(default)]
+    pub additional_commands: Vec<String>,
+    #[serde(default)]
+    pub expect_env: Option<Vec<String>>,
+    #[serde(default)]
+    pub expect_paths: Vec<String>,
+    #[serde(default)]
+    pub path_base: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BazelConfig {
+    /// Verbs accepted by every `bazel!` and pipeline, as if listed in
+    /// `custom_verbs`
+    #[serde(default)]
+    #[cfg_attr(not(feature = "bazel"), allow(dead_code))]
+    pub custom_verbs: Vec<String>,
+}
+
+/// A `[lints]` value: a level name or a threshold.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum LintValue {
+    Level(String),
+    Count(usize),
+}
+
+/// The config file for the code being compiled, if there is one.
+pub fn find() -> Option<PathBuf> {
+    if let Ok(path) = std::env::var(PATH_ENV)
+        && !path.is_empty()
+    {
+        return Some(PathBuf::from(path));
+    }
+    let start = std::env::var("RUST_SCRIPT_BASE_PATH")
+        .or_else(|_| std::env::var("CARGO_MANIFEST_DIR"))
+        .ok()?;
+    find_from(Path::new(&start))
+}
+
+fn find_from(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .map(|dir| dir.join(FILE_NAME))
+        .find(|path| path.is_file())
+}
+
+/// Load the config file, or the empty config when there is none.
+pub fn load() -> Result<Config, String> {
+    match find() {
+        Some(path) => load_from(&path),
+        None => Ok(Config::default()),
+    }
+}
+
+fn load_from(path: &Path) -> Result<Config, String> {
+    crate::track::track_file(path);
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    let mut config = parse(&contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+    config.dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    Ok(config)
+}
+
+/// Parse the file's contents. Errors carry the TOML line and column.
+fn parse(contents: &str) -> Result<Config, String> {
+    toml::from_str(contents).map_err(|e: toml::de::Error| e.to_string().trim_end().to_string())
+}
+
+impl Config {
+    /// The lint levels pipelines start from before their own `lints:`.
+    pub fn lints(&self) -> Result<LintConfig, String> {
+        let mut config = LintConfig::default();
+        for (name, value) in &self.lints {
+            let setting = match value {
+                LintValue::Level(level) => Setting::Level(level),
+                LintValue::Count(count) => Setting::Count(*count),
+            };
+            config
+                .set(name, setting, Span::call_site())
+                .map_err(|e| format!("[lints] {}", e))?;
+        }
+        Ok(config)
+    }
+
+    /// Merge the `[pipeline]` table underneath what the macro set.
+    pub fn apply(&self, pipeline: &mut PipelineDef) {
+        let file = &self.pipeline;
+        if pipeline.allowed_commands.is_none()
+            && let Some(commands) = &file.allowed_commands
+        {
+            pipeline.allowed_commands = Some(
+                commands
+                    .iter()
+                    .map(|c| (c.clone(), Span::call_site()))
+                    .collect(),
+            );
+        }
+        pipeline
+            .additional_commands
+            .splice(0..0, file.additional_commands.iter().cloned());
+        pipeline
+            .expect_paths
+            .splice(0..0, file.expect_paths.iter().cloned());
+        if pipeline.expect_env.is_none()
+            && let Some(vars) = &file.expect_env
+        {
+            let mut items = Vec::new();
+            for var in vars {
+                match expand_known_env_list(var) {
+                    Some(known) => {
+                        items.extend(known.iter().map(|v| ExpectEnvItem::Literal(v.to_string())))
+                    }
+                    None => items.push(ExpectEnvItem::Literal(var.clone())),
+                }
+            }
+            pipeline.expect_env = Some(items);
+        }
+        if pipeline.path_base.is_none()
+            && let Some(base) = &file.path_base
+        {
+            pipeline.path_base = Some(self.dir.join(base));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pipeline(source: &str) -> PipelineDef {
+        syn::parse_str(source).unwrap()
+    }
+
+    #[test]
+    fn test_parse_custom_verbs() {
+        let config = parse("[bazel]\ncustom_verbs = [\"lint\", \"format\"]\n").unwrap();
+        assert_eq!(config.bazel.custom_verbs, vec!["lint", "format"]);
+        assert!(parse("").unwrap().bazel.custom_verbs.is_empty());
+    }
+
+    #[test]
+    fn test_parse_rejects_unknown_keys() {
+        let err = parse("[bazel]\ncustom_verb = [\"lint\"]\n").unwrap_err();
+        assert!(err.contains("unknown field `custom_verb`"), "{}", err);
+        let err = parse("[bazle]\n").unwrap_err();
+        assert!(err.contains("unknown field `bazle`"), "{}", err);
+        let err = parse("[pipeline]\nallowed_command = []\n").unwrap_err();
+        assert!(err.contains("unknown field `allowed_command`"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_error_has_location() {
+        let err = parse("[pipeline]\nexpect_paths = [\"a\"\n").unwrap_err();
+        assert!(err.contains("line 2"), "{}", err);
+    }
+
+    #[test]
+    fn test_find_from_parents() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("ci/pipelines");
+        std::fs::create_dir_all(&nested).unwrap();
+        assert_eq!(find_from(&nested), None);
+
+        std::fs::write(dir.path().join(FILE_NAME), "[bazel]\n").unwrap();
+        assert_eq!(find_from(&nested), Some(dir.path().join(FILE_NAME)));
+    }
+
+    #[test]
+    fn test_load_from_sets_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(FILE_NAME);
+        std::fs::write(&path, "[pipeline]\npath_base = \"ci\"\n").unwrap();
+        let config = load_from(&path).unwrap();
+        assert_eq!(config.dir, dir.path());
+
+        std::fs::write(&path, "[pipeline\n").unwrap();
+        let err = load_from(&path).unwrap_err();
+        assert!(err.starts_with(&path.display().to_string()), "{}", err);
+    }
+
+    #[test]
+    fn test_file_fills_unset_settings() {
+        let mut config = parse(
+            "[pipeline]\n\
+             allowed_commands = [\"make\"]\n\
+             expect_env = [\"DEPLOY_TOKEN\"]\n\
+             path_base = \"ci\"\n",
+        )
+        .unwrap();
+        config.dir = PathBuf::from("/repo");
+        let mut def = pipeline(r#"steps: [command(cmd!("make"))]"#);
+        config.apply(&mut def);
+        let allowed: Vec<_> = def
+            .allowed_commands
+            .unwrap()
+            .into_iter()
+            .map(|(c, _)| c)
+            .collect();
+        assert_eq!(allowed, vec!["make"]);
+        assert!(matches!(
+            def.expect_env.as_deref(),
+            Some([ExpectEnvItem::Literal(v)]) if v == "DEPLOY_TOKEN"
+        ));
+        assert_eq!(def.path_base, Some(PathBuf::from("/repo/ci")));
+    }
+
+    #[test]
+    fn test_macro_settings_win() {
+        let config = parse(
+            "[pipeline]\n\
+             allowed_commands = [\"make\"]\n\
+             expect_env = [\"DEPLOY_TOKEN\"]\n\
+             path_base = \"ci\"\n",
+        )
+        .unwrap();
+        let mut def = pipeline(
+            r#"allowed_commands: ["cargo"], expect_env: ["HOME"], path_base: "scripts",
+               steps: [command(cmd!("cargo test"))]"#,
+        );
+        let path_base = def.path_base.clone();
+        config.apply(&mut def);
+        let allowed: Vec<_> = def
+            .allowed_commands
+            .unwrap()
+            .into_iter()
+            .map(|(c, _)| c)
+            .collect();
+        assert_eq!(allowed, vec!["cargo"]);
+        assert!(matches!(
+            def.expect_env.as_deref(),
+            Some([ExpectEnvItem::Literal(v)]) if v == "HOME"
+        ));
+        assert_eq!(def.path_base, path_base);
+    }
+
+    #[test]
+    fn test_lists_extend_file_entries() {
+        let config = parse(
+            "[pipeline]\n\
+             additional_commands = [\"notify\"]\n\
+             expect_paths = [\"./ci/generated.sh\"]\n",
+        )
+        .unwrap();
+        let mut def = pipeline(
+            r#"additional_commands: ["deploy"], expect_paths: ["./out/run.sh"],
+               steps: [command(cmd!("make"))]"#,
+        );
+        config.apply(&mut def);
+        assert_eq!(def.additional_commands, vec!["notify", "deploy"]);
+        assert_eq!(def.expect_paths, vec!["./ci/generated.sh", "./out/run.sh"]);
+    }
+
+    #[test]
+    fn test_expect_env_expands_known_lists() {
+        let config = parse("[pipeline]\nexpect_env = [\"BUILDKITE_ENV\"]\n").unwrap();
+        let mut def = pipeline(r#"steps: [command(cmd!("make"))]"#);
+        config.apply(&mut def);
+        let vars = def.expect_env.unwrap();
+        assert!(vars.len() > 1);
+        assert!(
+            vars.iter()
+                .any(|v| matches!(v, ExpectEnvItem::Literal(v) if v == "BUILDKITE_BRANCH"))
+        );
+    }
+
+    #[test]
+    fn test_lints_from_file() {
+        let config = parse("[lints]\nredundant_depends_on = \"deny\"\nmax_steps = 200\n").unwrap();
+        let lints = config.lints().unwrap();
+        assert!(lints.redundant_depends_on.level == crate::lints::LintLevel::Deny);
+        assert_eq!(lints.max_steps, 200);
+
+        let config = parse("[lints]\nredundant_depends_on = \"loud\"\n").unwrap();
+        let err = config.lints().unwrap_err();
+        assert!(err.contains("unknown lint level: loud"), "{}", err);
+        let config = parse("[lints]\nno_such_lint = \"warn\"\n").unwrap();
+        assert!(config.lints().unwrap_err().contains("unknown lint"));
+    }
+}
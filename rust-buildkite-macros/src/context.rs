@@ -0,0 +1,545 @@
+//! How the pipeline is triggered, from `pipeline_context: { ... }`, and the
+//! checks behind the `unreachable_condition` lint.
+//!
+//! Buildkite's pipeline settings can restrict builds to some branches or to
+//! tags, and a step conditional that contradicts them never runs. Nothing in
+//! the pipeline definition records those settings, so they are declared:
+//!
+//! ```ignore
+//! pipeline_context: { branches: ["main", "release/*"], tag_builds: false }
+//! ```
+//!
+//! Conditions are evaluated with three-valued logic, where a comparison the
+//! context says nothing about is unknown. Only a condition that is false
+//! whatever the unknowns turn out to be is reported.
+
+use crate::buildkite_conditional::{Lexer, Token};
+use syn::parse::ParseStream;
+use syn::{Error, Ident, LitStr, Result, Token as SynToken, braced, bracketed};
+
+/// The declared trigger settings of a pipeline.
+#[derive(Default)]
+pub struct PipelineContext {
+    /// Branch filter patterns, as in the pipeline settings; `*` matches any
+    /// run of characters and a leading `!` excludes. Empty when undeclared.
+    pub branches: Vec<String>,
+    /// Every build is a tag build
+    pub tag_builds_only: bool,
+    /// Whether tags are built at all; `None` when undeclared
+    pub tag_builds: Option<bool>,
+}
+
+impl PipelineContext {
+    /// Parse pipeline_context: { branches: [...], tag_builds_only: bool, tag_builds: bool }
+    pub fn parse(input: ParseStream) -> Result<Self> {
+        let content;
+        braced!(content in input);
+        let mut context = PipelineContext::default();
+        let mut tag_builds_span = None;
+
+        while !content.is_empty() {
+            let field: Ident = content.parse()?;
+            content.parse::<SynToken![:]>()?;
+
+            match field.to_string().as_str() {
+                "branches" => {
+                    let list;
+                    bracketed!(list in content);
+                    while !list.is_empty() {
+                        let branch: LitStr = list.parse()?;
+                        if branch.value().trim().is_empty() {
+                            return Err(Error::new(branch.span(), "branch pattern is empty"));
+                        }
+                        context.branches.push(branch.value());
+                        if list.peek(SynToken![,]) {
+                            list.parse::<SynToken![,]>()?;
+                        }
+                    }
+                }
+                "tag_builds_only" => {
+                    let val: syn::LitBool = content.parse()?;
+                    context.tag_builds_only = val.value();
+                }
+                "tag_builds" => {
+                    let val: syn::LitBool = content.parse()?;
+                    context.tag_builds = Some(val.value());
+                    tag_builds_span = Some(val.span());
+                }
+                other => {
+                    return Err(Error::new(
+                        field.span(),
+                        format!(
+                            "unknown pipeline_context field: {}. Expected: branches, tag_builds_only, tag_builds",
+                            other
+                        ),
+                    ));
+                }
+            }
+
+            if content.peek(SynToken![,]) {
+                content.parse::<SynToken![,]>()?;
+            }
+        }
+
+        if context.tag_builds_only
+            && context.tag_builds == Some(false)
+            && let Some(span) = tag_builds_span
+        {
+            return Err(Error::new(
+                span,
+                "tag_builds: false contradicts tag_builds_only: true",
+            ));
+        }
+        Ok(context)
+    }
+
+    /// Why `condition` can never be true in this context, or `None` when it
+    /// might be. Conditions that don't parse are left to `invalid_condition`.
+    pub fn contradiction(&self, condition: &str) -> Option<String> {
+        let mut lexer = Lexer::new(condition);
+        let mut tokens = Vec::new();
+        loop {
+            match lexer.next_token().ok()? {
+                Token::Eof => break,
+                token => tokens.push(token),
+            }
+        }
+        let mut eval = Eval {
+            context: self,
+            tokens: &tokens,
+            pos: 0,
+        };
+        let truth = eval.or();
+        if eval.pos != tokens.len() || truth.value != Some(false) {
+            return None;
+        }
+        let mut reasons = truth.reasons;
+        reasons.dedup();
+        Some(reasons.join("; "))
+    }
+
+    /// Why a step with branch filter `filters` never runs in this context,
+    /// or `None` when it might. Only decided when every declared branch is a
+    /// plain name.
+    pub fn filtered_out(&self, filters: &[String]) -> Option<String> {
+        let names = self.literal_branches()?;
+        if filters.is_empty() || names.iter().any(|name| allowed(filters, name)) {
+            return None;
+        }
+        Some(format!(
+            "it matches none of the pipeline's branches ({})",
+            self.branches.join(", ")
+        ))
+    }
+
+    /// The declared branches, when all are plain names.
+    fn literal_branches(&self) -> Option<&[String]> {
+        let plain = !self.branches.is_empty()
+            && self
+                .branches
+                .iter()
+                .all(|b| !b.contains('*') && !b.starts_with('!'));
+        plain.then_some(self.branches.as_slice())
+    }
+
+    /// The truth of `reference op value`.
+    fn compare(&self, reference: &str, op: &Token, value: &Token) -> Truth {
+        match (reference, op, value) {
+            ("build.branch", Token::Eq, Token::String(name)) => {
+                if !self.branches.is_empty() && !allowed(&self.branches, name) {
+                    return Truth::never(format!(
+                        "'{}' is not one of the pipeline's branches ({})",
+                        name,
+                        self.branches.join(", ")
+                    ));
+                }
+                Truth::unknown()
+            }
+            ("build.branch", Token::NotEq, Token::String(name)) => match self.literal_branches() {
+                Some([only]) if only == name => {
+                    Truth::never(format!("the pipeline only builds '{}'", name))
+                }
+                _ => Truth::unknown(),
+            },
+            ("build.branch", Token::Match | Token::NotMatch, Token::Regex(pattern, flags)) => {
+                let (Some(names), Some(regex)) = (self.literal_branches(), regex(pattern, flags))
+                else {
+                    return Truth::unknown();
+                };
+                let wanted = *op == Token::Match;
+                if names.iter().any(|name| regex.is_match(name) == wanted) {
+                    return Truth::unknown();
+                }
+                Truth::never(format!(
+                    "/{}/ {} the pipeline's branches ({})",
+                    pattern,
+                    if wanted {
+                        "matches none of"
+                    } else {
+                        "matches all of"
+                    },
+                    names.join(", ")
+                ))
+            }
+            ("build.tag", Token::Eq, Token::Null)
+            | ("build.pull_request.id", Token::NotEq, Token::Null)
+                if self.tag_builds_only =>
+            {
+                Truth::never("the pipeline only builds tags".to_string())
+            }
+            ("build.tag", Token::NotEq, Token::Null)
+            | ("build.pull_request.id", Token::Eq, Token::Null)
+                if self.tag_builds_only =>
+            {
+                Truth::known(true)
+            }
+            ("build.tag", Token::Eq, Token::Null) if self.tag_builds == Some(false) => {
+                Truth::known(true)
+            }
+            ("build.tag", Token::NotEq, Token::Null)
+            | ("build.tag", Token::Eq, Token::String(_))
+            | ("build.tag", Token::Match, Token::Regex(..))
+                if self.tag_builds == Some(false) =>
+            {
+                Truth::never("the pipeline never builds tags".to_string())
+            }
+            _ => Truth::unknown(),
+        }
+    }
+}
+
+/// Whether branch `name` passes the filter `patterns`.
+fn allowed(patterns: &[String], name: &str) -> bool {
+    let (excluded, included): (Vec<&String>, Vec<&String>) =
+        patterns.iter().partition(|p| p.starts_with('!'));
+    let included = included.is_empty() || included.iter().any(|p| glob_match(p, name));
+    included
+        && !excluded
+            .iter()
+            .any(|p| glob_match(p.trim_start_matches('!'), name))
+}
+
+/// Match `name` against `pattern`, where `*` matches any run of characters.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+fn regex(pattern: &str, flags: &str) -> Option<regex::Regex> {
+    let pattern = if flags.contains('i') {
+        format!("(?i){}", pattern)
+    } else {
+        pattern.to_string()
+    };
+    regex::Regex::new(&pattern).ok()
+}
+
+/// A three-valued result, with the reasons behind a known `false`.
+struct Truth {
+    value: Option<bool>,
+    reasons: Vec<String>,
+}
+
+impl Truth {
+    fn unknown() -> Self {
+        Truth {
+            value: None,
+            reasons: Vec::new(),
+        }
+    }
+
+    fn known(value: bool) -> Self {
+        Truth {
+            value: Some(value),
+            reasons: Vec::new(),
+        }
+    }
+
+    fn never(reason: String) -> Self {
+        Truth {
+            value: Some(false),
+            reasons: vec![reason],
+        }
+    }
+}
+
+/// Recursive-descent evaluation over the condition's tokens. Anything that
+/// isn't a comparison of a build reference with a literal is unknown.
+struct Eval<'a> {
+    context: &'a PipelineContext,
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl Eval<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn or(&mut self) -> Truth {
+        let mut truth = self.and();
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            let rhs = self.and();
+            truth = match (truth.value, rhs.value) {
+                (Some(true), _) | (_, Some(true)) => Truth::known(true),
+                (Some(false), Some(false)) => {
+                    truth.reasons.extend(rhs.reasons);
+                    truth
+                }
+                _ => Truth::unknown(),
+            };
+        }
+        truth
+    }
+
+    fn and(&mut self) -> Truth {
+        let mut truth = self.unary();
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            let rhs = self.unary();
+            truth = match (truth.value, rhs.value) {
+                (Some(false), _) => truth,
+                (_, Some(false)) => rhs,
+                (Some(true), Some(true)) => Truth::known(true),
+                _ => Truth::unknown(),
+            };
+        }
+        truth
+    }
+
+    fn unary(&mut self) -> Truth {
+        if self.peek() == Some(&Token::Not) {
+            self.pos += 1;
+            // nb: a negated fact has no reason to give, so it's only known
+            // false when the operand is known true
+            return match self.unary().value {
+                Some(true) => Truth::never("the negated condition always holds".to_string()),
+                Some(false) => Truth::known(true),
+                None => Truth::unknown(),
+            };
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Truth {
+        if self.peek() == Some(&Token::LParen) {
+            self.pos += 1;
+            let truth = self.or();
+            if self.peek() == Some(&Token::RParen) {
+                self.pos += 1;
+                return truth;
+            }
+            self.skip();
+            return Truth::unknown();
+        }
+        if let Some(truth) = self.comparison() {
+            return truth;
+        }
+        self.skip();
+        Truth::unknown()
+    }
+
+    /// `build.x op literal`, evaluated against the context.
+    fn comparison(&mut self) -> Option<Truth> {
+        let start = self.pos;
+        let mut reference = match self.peek() {
+            Some(Token::Ident(name)) => name.clone(),
+            _ => return None,
+        };
+        self.pos += 1;
+        while self.peek() == Some(&Token::Dot) {
+            match self.tokens.get(self.pos + 1) {
+                Some(Token::Ident(part)) => {
+                    reference.push('.');
+                    reference.push_str(part);
+                    self.pos += 2;
+                }
+                _ => break,
+            }
+        }
+        let op = self.peek().cloned();
+        let value = self.tokens.get(self.pos + 1).cloned();
+        let ends = matches!(
+            self.tokens.get(self.pos + 2),
+            None | Some(Token::And | Token::Or | Token::RParen)
+        );
+        match (op, value) {
+            (
+                Some(op @ (Token::Eq | Token::NotEq | Token::Match | Token::NotMatch)),
+                Some(value @ (Token::String(_) | Token::Null | Token::Regex(..))),
+            ) if ends => {
+                self.pos += 2;
+                Some(self.context.compare(&reference, &op, &value))
+            }
+            _ => {
+                self.pos = start;
+                None
+            }
+        }
+    }
+
+    /// Skip an operand this evaluator doesn't understand, up to the next
+    /// `&&`, `||` or unmatched `)`.
+    fn skip(&mut self) {
+        let mut depth = 0usize;
+        while let Some(token) = self.peek() {
+            match token {
+                Token::LParen | Token::LBracket => depth += 1,
+                Token::RParen | Token::RBracket if depth == 0 => return,
+                Token::RParen | Token::RBracket => depth -= 1,
+                Token::And | Token::Or if depth == 0 => return,
+                _ => {}
+            }
+            self.pos += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(source: &str) -> PipelineContext {
+        syn::parse::Parser::parse_str(PipelineContext::parse, source).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let ctx = context(r#"{ branches: ["main", "release/*"], tag_builds: false }"#);
+        assert_eq!(ctx.branches, vec!["main", "release/*"]);
+        assert_eq!(ctx.tag_builds, Some(false));
+        assert!(!ctx.tag_builds_only);
+
+        let err = syn::parse::Parser::parse_str(
+            PipelineContext::parse,
+            "{ tag_builds_only: true, tag_builds: false }",
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("contradicts"), "{}", err);
+        let err = syn::parse::Parser::parse_str(PipelineContext::parse, "{ branch: [] }")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("unknown pipeline_context field"));
+    }
+
+    #[test]
+    fn test_branch_equality_outside_declared_set() {
+        let ctx = context(r#"{ branches: ["main", "release/*"] }"#);
+        let reason = ctx.contradiction("build.branch == 'develop'").unwrap();
+        assert!(reason.contains("'develop' is not one of"), "{}", reason);
+        assert!(ctx.contradiction("build.branch == 'main'").is_none());
+        assert!(ctx.contradiction("build.branch == 'release/1.2'").is_none());
+        assert!(ctx.contradiction("build.branch != 'develop'").is_none());
+    }
+
+    #[test]
+    fn test_exclusions() {
+        let ctx = context(r#"{ branches: ["*", "!gh-pages"] }"#);
+        assert!(ctx.contradiction("build.branch == 'gh-pages'").is_some());
+        assert!(ctx.contradiction("build.branch == 'anything'").is_none());
+    }
+
+    #[test]
+    fn test_tag_builds() {
+        let only = context("{ tag_builds_only: true }");
+        assert!(only.contradiction("build.tag == null").is_some());
+        assert!(
+            only.contradiction("build.pull_request.id != null")
+                .is_some()
+        );
+        assert!(only.contradiction("build.tag != null").is_none());
+        assert!(only.contradiction("!(build.tag != null)").is_some());
+
+        let never = context("{ tag_builds: false }");
+        let reason = never.contradiction("build.tag != null").unwrap();
+        assert_eq!(reason, "the pipeline never builds tags");
+        assert!(never.contradiction("build.tag =~ /^v/").is_some());
+        assert!(never.contradiction("build.tag == null").is_none());
+    }
+
+    #[test]
+    fn test_regex_against_literal_branches() {
+        let ctx = context(r#"{ branches: ["main", "develop"] }"#);
+        assert!(ctx.contradiction("build.branch =~ /^release\\//").is_some());
+        assert!(ctx.contradiction("build.branch =~ /^dev/").is_none());
+        assert!(
+            ctx.contradiction("build.branch !~ /^(main|develop)$/")
+                .is_some()
+        );
+        // a glob in the declared set could match anything the regex does
+        let glob = context(r#"{ branches: ["release/*"] }"#);
+        assert!(glob.contradiction("build.branch =~ /^hotfix/").is_none());
+    }
+
+    #[test]
+    fn test_logic_is_conservative() {
+        let ctx = context(r#"{ branches: ["main"], tag_builds: false }"#);
+        assert!(
+            ctx.contradiction("build.branch == 'develop' && build.message =~ /deploy/")
+                .is_some()
+        );
+        assert!(
+            ctx.contradiction("build.branch == 'develop' || build.message =~ /deploy/")
+                .is_none()
+        );
+        let reason = ctx
+            .contradiction("build.branch == 'develop' || build.tag != null")
+            .unwrap();
+        assert!(
+            reason.contains("develop") && reason.contains("tags"),
+            "{}",
+            reason
+        );
+        assert!(
+            ctx.contradiction(
+                "(build.branch == 'develop' || build.branch == 'main') && build.tag == null"
+            )
+            .is_none()
+        );
+        assert!(ctx.contradiction("meta-data('x') == 'develop'").is_none());
+        assert!(ctx.contradiction("!(build.branch == 'develop')").is_none());
+        assert!(ctx.contradiction("build.branch == 'develop").is_none());
+    }
+
+    #[test]
+    fn test_branch_filters() {
+        let ctx = context(r#"{ branches: ["main", "develop"] }"#);
+        let filters = |f: &[&str]| f.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert!(ctx.filtered_out(&filters(&["release/*"])).is_some());
+        assert!(ctx.filtered_out(&filters(&["main"])).is_none());
+        assert!(ctx.filtered_out(&filters(&["!main"])).is_none());
+        assert!(ctx.filtered_out(&filters(&["!main", "!develop"])).is_some());
+        assert!(
+            context(r#"{ branches: ["release/*"] }"#)
+                .filtered_out(&filters(&["hotfix"]))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("release/*", "release/1.0"));
+        assert!(glob_match("*-stable", "v2-stable"));
+        assert!(glob_match("a*b*c", "aXXbYYc"));
+        assert!(!glob_match("a*b*c", "aXXcYYb"));
+        assert!(glob_match("main", "main"));
+        assert!(!glob_match("main", "mainline"));
+        assert!(glob_match("*", ""));
+    }
+}
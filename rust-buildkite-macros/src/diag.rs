@@ -0,0 +1,69 @@
+//! Compile-time warnings from `pipeline!`.
+//!
+//! Proc macros can't emit warnings on stable, so each warning becomes a
+//! uniquely named `#[deprecated]` unit struct plus a use of it at the
+//! finding's span, inside the expansion's block. Cargo then prints a
+//! deprecation warning carrying the message, and `#[allow(deprecated)]`
+//! silences it. With the `nightly` feature, warnings are emitted through
+//! `proc_macro::Diagnostic` instead.
+//!
+//! Validators call [`warn`] at any point during parsing or validation;
+//! `pipeline!` drains the queue with [`take`] once expansion is done.
+
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use std::cell::RefCell;
+
+thread_local! {
+    static WARNINGS: RefCell<Vec<(Span, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Queue a warning pointing at `span`.
+pub fn warn(span: Span, message: impl Into<String>) {
+    WARNINGS.with(|w| w.borrow_mut().push((span, message.into())));
+}
+
+/// Drop queued warnings, e.g. when expansion fails and only errors are shown.
+pub fn clear() {
+    WARNINGS.with(|w| w.borrow_mut().clear());
+}
+
+fn drain() -> Vec<(Span, String)> {
+    WARNINGS.with(|w| std::mem::take(&mut *w.borrow_mut()))
+}
+
+/// Emit queued warnings, returning any tokens the expansion must include.
+#[cfg(not(feature = "nightly"))]
+pub fn take() -> TokenStream2 {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+
+    drain()
+        .into_iter()
+        .enumerate()
+        .map(|(index, (span, message))| {
+            let mut hasher = DefaultHasher::new();
+            message.hash(&mut hasher);
+            let name = format!(
+                "__rust_buildkite_warning_{}_{:016x}",
+                index,
+                hasher.finish()
+            );
+            let def = syn::Ident::new(&name, Span::call_site());
+            let usage = syn::Ident::new(&name, span);
+            quote::quote! {
+                #[deprecated(note = #message)]
+                #[allow(non_camel_case_types)]
+                struct #def;
+                let _ = #usage;
+            }
+        })
+        .collect()
+}
+
+/// Emit queued warnings, returning any tokens the expansion must include.
+#[cfg(feature = "nightly")]
+pub fn take() -> TokenStream2 {
+    for (span, message) in drain() {
+        proc_macro::Diagnostic::spanned(span.unwrap(), proc_macro::Level::Warning, message).emit();
+    }
+    TokenStream2::new()
+}
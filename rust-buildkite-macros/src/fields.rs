@@ -0,0 +1,374 @@
+//! Field and method names each parser accepts, for suggesting the closest
+//! one when an unknown name is used. Keep each list in step with the match
+//! arms of its parser.
+
+use crate::codes;
+use crate::{closest_matches, edit_distance};
+
+/// Fields of `pipeline! { ... }`
+pub const PIPELINE: &[&str] = &[
+    "steps",
+    "env",
+    "agents",
+    "queue",
+    "notify",
+    "image",
+    "priority",
+    "secrets",
+    "expect_env",
+    "expect_paths",
+    "external_meta_data",
+    "allowed_commands",
+    "additional_commands",
+    "allow_unchecked_commands",
+    "shell",
+    "path_base",
+    "emit_yaml",
+    "lazy",
+    "lints",
+    "key_prefix",
+    "auto_keys",
+    "default_plugins",
+    "description_env",
+    "plugin_versions",
+    "pipeline_context",
+    "profiles",
+    "profile",
+    "concurrency_group_policy",
+    "custom_verbs",
+    "bazel_validation",
+    "bazel_defaults",
+    "bazel_binary",
+    "bazel_bep",
+    "bazel_exclusive_configs",
+    "bazel_workspace",
+];
+
+/// Methods of `command(...)`
+pub const COMMAND_METHODS: &[&str] = &[
+    "label",
+    "key",
+    "description",
+    "command",
+    "env",
+    "depends_on",
+    "agents",
+    "queue",
+    "artifact_paths",
+    "branches",
+    "if",
+    "skip_if",
+    "skip_unless",
+    "skip",
+    "skip_validation",
+    "shell",
+    "soft_fail",
+    "timeout_in_minutes",
+    "parallelism",
+    "matrix",
+    "retry",
+    "retry_automatic",
+    "retry_flaky",
+    "retry_on_agent_loss",
+    "plugin",
+    "plugin_yaml",
+    "docker_compose",
+    "cache",
+    "concurrency",
+    "concurrency_group",
+    "priority",
+    "allow_dependency_failure",
+    "needs_artifacts",
+    "set_metadata",
+    "notify_slack",
+    "only_profiles",
+    "bep",
+];
+
+/// Fields of `command { ... }`
+pub const COMMAND_FIELDS: &[&str] = &[
+    "command",
+    "commands",
+    "label",
+    "key",
+    "description",
+    "env",
+    "depends_on",
+    "agents",
+    "queue",
+    "artifact_paths",
+    "branches",
+    "if",
+    "condition",
+    "skip",
+    "skip_validation",
+    "shell",
+    "soft_fail",
+    "timeout_in_minutes",
+    "parallelism",
+    "matrix",
+    "retry",
+    "plugins",
+    "plugins_yaml",
+    "cache",
+    "concurrency",
+    "concurrency_group",
+    "priority",
+    "allow_dependency_failure",
+    "notify",
+    "only_profiles",
+    "bep",
+];
+
+/// Fields of `command { ... }` that need the `bazel` feature
+pub const COMMAND_BAZEL_FIELDS: &[&str] = &["custom_verbs", "validate_targets", "dry_run"];
+
+/// Fields of the structured bazel steps, e.g. `bazel_test { ... }`
+#[cfg(feature = "bazel")]
+pub const BAZEL_STEP_FIELDS: &[&str] = &[
+    "verb",
+    "target_patterns",
+    "flags",
+    "config",
+    "compilation_mode",
+    "startup_options",
+    "build_tag_filters",
+    "test_tag_filters",
+    "args",
+    "workspace",
+    "shards",
+    "label",
+    "key",
+    "description",
+    "env",
+    "depends_on",
+    "agents",
+    "queue",
+    "artifact_paths",
+    "branches",
+    "if",
+    "condition",
+    "skip",
+    "soft_fail",
+    "timeout_in_minutes",
+    "parallelism",
+    "retry",
+    "retry_preset",
+    "plugins",
+    "plugins_yaml",
+    "allow_dependency_failure",
+    "bep",
+    "custom_verbs",
+    "validate_targets",
+    "dry_run",
+];
+
+/// Fields of each entry in a bazel step's `commands: [...]`
+#[cfg(feature = "bazel")]
+pub const BAZEL_COMMAND_FIELDS: &[&str] = &[
+    "verb",
+    "target_patterns",
+    "flags",
+    "config",
+    "compilation_mode",
+    "startup_options",
+    "build_tag_filters",
+    "test_tag_filters",
+    "args",
+    "validate_targets",
+    "dry_run",
+];
+
+/// Fields of `wait { ... }`
+pub const WAIT_FIELDS: &[&str] = &[
+    "key",
+    "if",
+    "depends_on",
+    "allow_dependency_failure",
+    "continue_on_failure",
+    "branches",
+    "notify",
+    "notify_slack",
+];
+
+/// Methods of `block(...)` and `input(...)`
+pub const BLOCK_METHODS: &[&str] = &[
+    "key",
+    "prompt",
+    "field",
+    "fields",
+    "blocked_state",
+    "allowed_teams",
+    "if",
+    "depends_on",
+    "allow_dependency_failure",
+    "branches",
+    "notify",
+    "notify_slack",
+];
+
+/// Fields of `block { ... }`
+pub const BLOCK_FIELDS: &[&str] = &[
+    "block",
+    "key",
+    "prompt",
+    "fields",
+    "blocked_state",
+    "allowed_teams",
+    "if",
+    "depends_on",
+    "allow_dependency_failure",
+    "branches",
+    "notify",
+    "notify_slack",
+];
+
+/// Fields of `input { ... }`
+pub const INPUT_FIELDS: &[&str] = &[
+    "input",
+    "key",
+    "prompt",
+    "fields",
+    "blocked_state",
+    "allowed_teams",
+    "if",
+    "depends_on",
+    "allow_dependency_failure",
+    "branches",
+    "notify",
+    "notify_slack",
+];
+
+/// Methods of `trigger(...)`
+pub const TRIGGER_METHODS: &[&str] = &[
+    "label",
+    "key",
+    "build",
+    "async",
+    "if",
+    "skip",
+    "soft_fail",
+    "depends_on",
+    "allow_dependency_failure",
+    "branches",
+    "notify",
+    "notify_slack",
+];
+
+/// Fields of `trigger { ... }`
+pub const TRIGGER_FIELDS: &[&str] = &[
+    "trigger",
+    "label",
+    "key",
+    "build",
+    "async",
+    "if",
+    "skip",
+    "soft_fail",
+    "depends_on",
+    "allow_dependency_failure",
+    "branches",
+    "notify",
+    "notify_slack",
+];
+
+/// Methods of `group(...)`
+pub const GROUP_METHODS: &[&str] = &[
+    "key",
+    "step",
+    "steps",
+    "defaults",
+    "key_prefix",
+    "if",
+    "skip",
+    "depends_on",
+    "allow_dependency_failure",
+    "notify",
+    "notify_slack",
+    "only_profiles",
+];
+
+/// Fields of `group { ... }`
+pub const GROUP_FIELDS: &[&str] = &[
+    "group",
+    "key",
+    "steps",
+    "defaults",
+    "key_prefix",
+    "if",
+    "skip",
+    "depends_on",
+    "allow_dependency_failure",
+    "notify",
+    "only_profiles",
+];
+
+/// Fields of a `github_commit_status` notification
+pub const GITHUB_COMMIT_STATUS_FIELDS: &[&str] = &["context"];
+
+/// Fields of a `retry` object
+pub const RETRY_FIELDS: &[&str] = &["automatic", "manual"];
+
+/// Fields of a `notify` entry: its type, one of which is required, and `if`
+pub const NOTIFY_FIELDS: &[&str] = &[
+    "slack",
+    "email",
+    "webhook",
+    "pagerduty_change_event",
+    "github_commit_status",
+    "github_check",
+    "basecamp_campfire",
+    "if",
+];
+
+/// Properties of a `text` field in block and input steps
+pub const TEXT_FIELD_PROPERTIES: &[&str] = &[
+    "text", "key", "label", "hint", "required", "default", "format",
+];
+
+/// Properties of a `select` field in block and input steps
+pub const SELECT_FIELD_PROPERTIES: &[&str] = &[
+    "select", "key", "label", "hint", "required", "default", "multiple", "options",
+];
+
+/// Message for `name`, an unknown `what` (e.g. "command step field"), naming
+/// the closest of `known` when it is near enough to be a typo.
+pub fn unknown<'a>(what: &str, name: &str, known: impl IntoIterator<Item = &'a str>) -> String {
+    let max_distance = 2.max(name.chars().count() / 3);
+    let id = codes::UNKNOWN_FIELD.id;
+    match closest_matches(name, known, 1)
+        .into_iter()
+        .find(|candidate| edit_distance(name, candidate) <= max_distance)
+    {
+        Some(candidate) => format!(
+            "[{}] unknown {}: {}; did you mean '{}'?",
+            id, what, name, candidate
+        ),
+        None => format!("[{}] unknown {}: {}", id, what, name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suggests_close_names() {
+        assert_eq!(
+            unknown(
+                "command step field",
+                "timout_in_minutes",
+                COMMAND_FIELDS.iter().copied()
+            ),
+            "[RBK0035] unknown command step field: timout_in_minutes; did you mean 'timeout_in_minutes'?"
+        );
+        assert_eq!(
+            unknown("pipeline field", "enviroment", PIPELINE.iter().copied()),
+            "[RBK0035] unknown pipeline field: enviroment"
+        );
+        assert_eq!(
+            unknown("group step method", "stpes", GROUP_METHODS.iter().copied()),
+            "[RBK0035] unknown group step method: stpes; did you mean 'steps'?"
+        );
+    }
+}
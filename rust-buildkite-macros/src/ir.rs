@@ -0,0 +1,320 @@
+//! Debug dump of the parsed pipeline, enabled with `RUST_BUILDKITE_DEBUG=ir`.
+//!
+//! The dump goes to stderr, or is appended to a file with
+//! `RUST_BUILDKITE_DEBUG=ir:/path/to/file`. It lists every step with its key,
+//! commands, extracted command names, undefined vars and depends_on edges,
+//! followed by the effective allowed-commands and env sets used by validation.
+//! Secret values are never printed.
+//!
+//! With the `ir` feature, the same walk also produces the JSON that
+//! `pipeline_ir!` deserializes into `rust_buildkite::ir::PipelineIr`.
+
+use crate::{DynamicValue, PipelineDef, SecretsValue, StepDef};
+use quote::ToTokens;
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::io::Write as _;
+
+/// Where the dump should go, if `RUST_BUILDKITE_DEBUG` asks for one.
+enum Target {
+    Stderr,
+    File(String),
+}
+
+fn target() -> Option<Target> {
+    let value = std::env::var("RUST_BUILDKITE_DEBUG").ok()?;
+    match value.split_once(':') {
+        Some(("ir", path)) if !path.is_empty() => Some(Target::File(path.to_string())),
+        None if value == "ir" => Some(Target::Stderr),
+        _ => None,
+    }
+}
+
+/// Print the pipeline IR when `RUST_BUILDKITE_DEBUG=ir` is set.
+pub fn dump_if_requested(pipeline: &PipelineDef, allowed_commands: &HashSet<&str>) {
+    let Some(target) = target() else {
+        return;
+    };
+    let text = dump(pipeline, allowed_commands);
+    match target {
+        Target::Stderr => eprint!("{}", text),
+        Target::File(path) => {
+            let written = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .and_then(|mut file| file.write_all(text.as_bytes()));
+            if let Err(e) = written {
+                eprintln!("warning: could not write pipeline IR to {}: {}", path, e);
+            }
+        }
+    }
+}
+
+/// A literal value quoted, or the expression that computes it.
+fn dynamic_text(value: &DynamicValue) -> String {
+    match value {
+        DynamicValue::Literal(s) | DynamicValue::Computed(s, _) => format!("{:?}", s),
+        DynamicValue::Comptime(expr) | DynamicValue::Runtime(expr) => {
+            expr.to_token_stream().to_string()
+        }
+    }
+}
+
+fn dump(pipeline: &PipelineDef, allowed_commands: &HashSet<&str>) -> String {
+    let mut out = String::from("pipeline IR:\n");
+    if let Some(env) = &pipeline.env {
+        let _ = writeln!(out, "  env:");
+        for (name, value) in env {
+            let _ = writeln!(out, "    {}: {}", name, dynamic_text(value));
+        }
+    }
+    match &pipeline.secrets {
+        Some(SecretsValue::Array(names)) => {
+            let _ = writeln!(out, "  secrets: {:?}", names);
+        }
+        Some(SecretsValue::Object(pairs)) => {
+            let _ = writeln!(out, "  secrets:");
+            for (name, _) in pairs {
+                let _ = writeln!(out, "    {}: <redacted>", name);
+            }
+        }
+        None => {}
+    }
+    let _ = writeln!(out, "  steps:");
+    for step in &pipeline.steps {
+        dump_step(&mut out, step, 2);
+    }
+
+    let mut commands: Vec<&str> = allowed_commands.iter().copied().collect();
+    commands.sort();
+    let _ = writeln!(
+        out,
+        "  allowed commands ({}): {:?}",
+        commands.len(),
+        commands
+    );
+    match pipeline.env_var_sets() {
+        Some((allowed, _)) => {
+            let mut vars: Vec<&String> = allowed.iter().collect();
+            vars.sort();
+            let _ = writeln!(out, "  allowed env vars ({}): {:?}", vars.len(), vars);
+        }
+        None => {
+            let _ = writeln!(
+                out,
+                "  allowed env vars: unknown (expect_env references a const)"
+            );
+        }
+    }
+    out
+}
+
+fn dump_step(out: &mut String, step: &StepDef, depth: usize) {
+    let indent = "  ".repeat(depth);
+    let _ = write!(out, "{}- {}", indent, step.kind());
+    if let Some((key, _)) = step.get_key() {
+        let _ = write!(out, " key={:?}", key);
+    }
+    let label = match step {
+        StepDef::Command(c) => c.label.as_ref(),
+        StepDef::Trigger(t) => t.label.as_ref(),
+        StepDef::Group(g) => g.label.as_ref(),
+        StepDef::Wait(_) | StepDef::Block(_) | StepDef::Input(_) => None,
+    };
+    if let Some(label) = label {
+        let _ = write!(out, " label={}", label.to_token_stream());
+    }
+    let _ = writeln!(out);
+
+    if let Some(description) = step.description() {
+        let _ = writeln!(out, "{}    description: {:?}", indent, description);
+    }
+    let depends_on: Vec<String> = step.get_depends_on().into_iter().map(|(k, _)| k).collect();
+    if !depends_on.is_empty() {
+        let _ = writeln!(out, "{}    depends_on: {:?}", indent, depends_on);
+    }
+    if let Some(condition) = step.if_condition() {
+        let _ = writeln!(out, "{}    if: {:?}", indent, condition.value());
+    }
+
+    match step {
+        StepDef::Command(c) => {
+            for command in &c.commands {
+                let text = command.command_text().unwrap_or("<dynamic>");
+                let _ = writeln!(
+                    out,
+                    "{}    command: {:?} (name: {})",
+                    indent,
+                    text,
+                    command.get_command_name()
+                );
+                let undefined = command.get_undefined_vars();
+                if !undefined.is_empty() {
+                    let _ = writeln!(out, "{}      undefined vars: {:?}", indent, undefined);
+                }
+            }
+            for (name, value) in &c.env {
+                let _ = writeln!(out, "{}    env {}: {}", indent, name, dynamic_text(value));
+            }
+            if let Some(group) = &c.concurrency_group {
+                let _ = writeln!(out, "{}    concurrency_group: {:?}", indent, group.value());
+            }
+            if let Some(parallelism) = &c.parallelism {
+                let _ = writeln!(out, "{}    parallelism: {}", indent, parallelism);
+            }
+            if c.skip_validation {
+                let _ = writeln!(out, "{}    skip_validation", indent);
+            }
+            if let Some(shell) = c.shell {
+                let _ = writeln!(out, "{}    shell: {}", indent, shell.name());
+            }
+        }
+        StepDef::Group(g) => {
+            for nested in &g.steps {
+                dump_step(out, nested, depth + 2);
+            }
+        }
+        StepDef::Wait(_) | StepDef::Block(_) | StepDef::Input(_) | StepDef::Trigger(_) => {}
+    }
+}
+
+/// Shape of the serialized IR; kept in step with `rust_buildkite::ir::IR_VERSION`.
+#[cfg(feature = "ir")]
+const IR_VERSION: u32 = 2;
+
+/// Serialize the validated pipeline in the shape of `rust_buildkite::ir::PipelineIr`.
+#[cfg(feature = "ir")]
+pub fn to_json(pipeline: &PipelineDef) -> String {
+    use serde_json::json;
+
+    let env: Vec<String> = pipeline
+        .env
+        .iter()
+        .flatten()
+        .map(|(name, _)| name.to_string())
+        .collect();
+    let allowed_env = pipeline.env_var_sets().map(|(allowed, _)| {
+        let mut vars: Vec<String> = allowed.into_iter().collect();
+        vars.sort();
+        vars
+    });
+    let steps: Vec<serde_json::Value> = pipeline.steps.iter().map(step_json).collect();
+    json!({
+        "version": IR_VERSION,
+        "steps": steps,
+        "env": env,
+        "allowed_env": allowed_env,
+    })
+    .to_string()
+}
+
+#[cfg(feature = "ir")]
+fn step_json(step: &StepDef) -> serde_json::Value {
+    use serde_json::json;
+
+    let label = match step {
+        StepDef::Command(c) => c.label.as_ref(),
+        StepDef::Trigger(t) => t.label.as_ref(),
+        StepDef::Group(g) => g.label.as_ref(),
+        StepDef::Wait(_) | StepDef::Block(_) | StepDef::Input(_) => None,
+    }
+    .map(|label| match label {
+        syn::Expr::Lit(syn::ExprLit {
+            lit: syn::Lit::Str(s),
+            ..
+        }) => s.value(),
+        other => other.to_token_stream().to_string(),
+    });
+    let commands: Vec<serde_json::Value> = match step {
+        StepDef::Command(c) => c
+            .commands
+            .iter()
+            .map(|command| {
+                json!({
+                    "text": command.command_text(),
+                    "name": command.get_command_name(),
+                    "undefined_vars": command.get_undefined_vars(),
+                })
+            })
+            .collect(),
+        StepDef::Wait(_)
+        | StepDef::Block(_)
+        | StepDef::Input(_)
+        | StepDef::Trigger(_)
+        | StepDef::Group(_) => Vec::new(),
+    };
+    let nested: Vec<serde_json::Value> = match step {
+        StepDef::Group(g) => g.steps.iter().map(step_json).collect(),
+        StepDef::Command(_)
+        | StepDef::Wait(_)
+        | StepDef::Block(_)
+        | StepDef::Input(_)
+        | StepDef::Trigger(_) => Vec::new(),
+    };
+    let depends_on: Vec<String> = step.get_depends_on().into_iter().map(|(k, _)| k).collect();
+    json!({
+        "kind": step.kind(),
+        "key": step.get_key().map(|(key, _)| key),
+        "label": label,
+        "description": step.description(),
+        "depends_on": depends_on,
+        "condition": step.if_condition().map(|c| c.value()),
+        "commands": commands,
+        "steps": nested,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dumps_steps_and_redacts_secrets() {
+        let pipeline: PipelineDef = syn::parse_str(
+            r#"
+            secrets: { API_TOKEN: "ci/api-token" },
+            steps: [
+                command(cmd!("cargo build")).key("build"),
+                group("Tests").step(command(cmd!("cargo test")).key("test").depends_on("build"))
+            ]
+            "#,
+        )
+        .unwrap();
+        let allowed: HashSet<&str> = ["cargo"].into_iter().collect();
+        let text = dump(&pipeline, &allowed);
+        assert!(text.contains("- command key=\"build\""));
+        assert!(text.contains("command: \"cargo test\" (name: cargo)"));
+        assert!(text.contains("depends_on: [\"build\"]"));
+        assert!(text.contains("allowed commands (1): [\"cargo\"]"));
+        assert!(text.contains("API_TOKEN: <redacted>"));
+        assert!(!text.contains("ci/api-token"));
+    }
+
+    #[cfg(feature = "ir")]
+    #[test]
+    fn serializes_steps_with_validation_metadata() {
+        let pipeline: PipelineDef = syn::parse_str(
+            r#"
+            env: { CI: "true" },
+            steps: [
+                command(cmd!("cargo build")).key("build").label("Build"),
+                group("Tests").step(command(cmd!("cargo test")).key("test").depends_on("build"))
+            ]
+            "#,
+        )
+        .unwrap();
+        let ir: serde_json::Value = serde_json::from_str(&to_json(&pipeline)).unwrap();
+        assert_eq!(ir["version"], IR_VERSION);
+        assert_eq!(ir["env"], serde_json::json!(["CI"]));
+        assert_eq!(ir["steps"][0]["kind"], "command");
+        assert_eq!(ir["steps"][0]["label"], "Build");
+        assert_eq!(ir["steps"][0]["commands"][0]["name"], "cargo");
+        assert_eq!(ir["steps"][1]["kind"], "group");
+        assert_eq!(ir["steps"][1]["steps"][0]["key"], "test");
+        assert_eq!(
+            ir["steps"][1]["steps"][0]["depends_on"],
+            serde_json::json!(["build"])
+        );
+    }
+}
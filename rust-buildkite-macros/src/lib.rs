@@ -14,36 +14,156 @@
 //!
 //! Set `BUILDKITE_SKIP_COMPTIME_VALIDATION=1` to skip all compile-time checks.
 //! Runtime validation is still performed when the pipeline binary runs.
+//!
+//! Error Codes
+//!
+//! Validation errors start with a stable code that tooling can match on. A
+//! code can be used as a name in the `lints:` field to set that check to
+//! `allow`, `warn` or `deny`, e.g. `lints: { RBK0005: warn }`. Codes are never
+//! reused for a different check.
+//!
+//! | Code | Check |
+//! |------|-------|
+//! | `RBK0001` | step key is empty or looks like a UUID |
+//! | `RBK0002` | depends_on names a key no step defines |
+//! | `RBK0003` | `if` is not a valid Buildkite conditional expression |
+//! | `RBK0004` | path-based command or local plugin does not exist at compile time |
+//! | `RBK0005` | command reads an environment variable that is never defined |
+//! | `RBK0006` | concurrency_group does not satisfy concurrency_group_policy |
+//! | `RBK0007` | two steps share the same key |
+//! | `RBK0008` | concurrency_group interpolates an undefined environment variable |
+//! | `RBK0009` | lint `no_structure` |
+//! | `RBK0010` | lint `redundant_depends_on` |
+//! | `RBK0011` | lint `parallelism_with_matrix` |
+//! | `RBK0012` | command is not in allowed_commands (or on PATH) |
+//! | `RBK0013` | lint `parallel_vars_without_parallelism` |
+//! | `RBK0014` | lint `parallelism_without_sharding` |
+//! | `RBK0015` | pipeline expands to more steps than `max_steps` |
+//! | `RBK0016` | group expands to more steps than `max_group_steps` |
+//! | `RBK0017` | lint `bazel_test_timeout` |
+//! | `RBK0018` | lint `duplicate_bazel_targets` |
+//! | `RBK0019` | needs_artifacts names a step whose artifact_paths don't match |
+//! | `RBK0020` | lint `unknown_label_var` |
+//! | `RBK0021` | step label has an empty or unterminated `${` |
+//! | `RBK0022` | lint `unreachable_condition` |
+//! | `RBK0023` | docker_compose names a service its compose file doesn't define |
+//! | `RBK0024` | lint `shadowed_env` |
+//! | `RBK0025` | lint `lowercase_env_name` |
+//! | `RBK0026` | lint `bare_ident_value` |
+//! | `RBK0027` | lint `shell_info` |
+//! | `RBK0028` | lint `unkeyed_block_fields` |
+//! | `RBK0029` | lint `unknown_meta_data` |
+//! | `RBK0030` | lint `unread_meta_data` |
+//! | `RBK0031` | `unchecked_cmd!` without `allow_unchecked_commands: true` |
+//! | `RBK0032` | `runtime!` command on a step without `skip_validation` |
+//! | `RBK0033` | bazel query could not resolve a command's targets |
+//! | `RBK0034` | `lazy` pipeline has a value computed at runtime |
+//! | `RBK0035` | field, method or option the macro doesn't recognize |
+//!
+//! Errors raised while parsing the DSL itself (syntax, malformed values,
+//! shell lint errors from `cmd!`) have no code and can't be configured. An
+//! unknown field is coded so tooling can match it, but it's still a parse
+//! error and can't be configured either.
+//! Every check that runs on the parsed pipeline reports through a code.
 
 #![allow(clippy::unused_enumerate_index)]
+#![cfg_attr(feature = "nightly", feature(proc_macro_diagnostic, track_path))]
 
 #[cfg(feature = "bazel")]
 mod bazel;
 
+#[cfg(feature = "bazel")]
+mod bazel_defaults;
+
+#[cfg(feature = "bazel")]
+mod bazel_report;
+
 #[cfg(feature = "bazel")]
 mod bep;
 
+mod config;
+
 #[cfg(feature = "bazel")]
 mod debug;
 
 #[cfg(feature = "bazel")]
 mod targets;
 
+mod agent;
 mod buildkite_conditional;
+mod cargo;
+mod codes;
+mod compose;
+mod conditions;
+mod context;
+mod diag;
+mod fields;
+mod ir;
+mod lints;
+mod plugins;
+mod shell;
+mod timing;
+mod track;
+mod yaml;
 
 use proc_macro::TokenStream;
 use proc_macro2::TokenStream as TokenStream2;
 use quote::{ToTokens, quote};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
 #[cfg(feature = "bazel")]
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use syn::{
     Error, Ident, LitStr, Result, Token, braced, bracketed,
     parse::{Parse, ParseStream},
     parse_macro_input,
-    punctuated::Punctuated,
 };
 
+/// What bashrs found in a command: the undefined vars (SC2154), and the
+/// errors and warnings that fail the command unless its `allow:` list names them
+#[derive(Clone)]
+struct BashLintOutcome {
+    undefined_vars: Vec<String>,
+    issues: Vec<ShellIssue>,
+}
+
+/// A bashrs error or warning
+#[derive(Clone)]
+struct ShellIssue {
+    /// ShellCheck code, e.g. `SC2086`
+    code: String,
+    /// The issue as a line of the lint error, with its caret snippet
+    message: String,
+    /// Byte range of the issue within the linted text
+    range: Option<std::ops::Range<usize>>,
+}
+
+impl BashLintOutcome {
+    /// Undefined vars when every issue is in `allow`, or else the lint
+    /// message and the byte range of the first issue left.
+    fn check(
+        self,
+        allow: &[String],
+    ) -> std::result::Result<Vec<String>, (String, Option<std::ops::Range<usize>>)> {
+        let issues: Vec<ShellIssue> = self
+            .issues
+            .into_iter()
+            .filter(|issue| !allow.contains(&issue.code))
+            .collect();
+        if issues.is_empty() {
+            return Ok(self.undefined_vars);
+        }
+        let first = issues.iter().find_map(|issue| issue.range.clone());
+        let lines: Vec<&str> = issues.iter().map(|issue| issue.message.as_str()).collect();
+        Err((format!("Shell lint issues:\n{}", lines.join("\n")), first))
+    }
+}
+
+/// bashrs results keyed by the linted command and the shell it was linted for
+static BASH_LINT_CACHE: Mutex<Option<HashMap<(String, StepShell), BashLintOutcome>>> =
+    Mutex::new(None);
+
 #[cfg(feature = "bazel")]
 static WARNED_NO_WORKSPACE: AtomicBool = AtomicBool::new(false);
 
@@ -62,44 +182,51 @@ fn should_skip_comptime_validation() -> bool {
     std::env::var("BUILDKITE_SKIP_COMPTIME_VALIDATION").is_ok()
 }
 
-const SHELL_BUILTINS: &[&str] = &[
-    // nb: POSIX builtins
-    ".",
-    ":",
-    "[",
-    "alias",
-    "bg",
-    "cd",
-    "command",
-    "eval",
-    "exec",
-    "exit",
-    "export",
-    "fc",
-    "fg",
-    "getopts",
-    "hash",
-    "jobs",
-    "kill",
-    "newgrp",
-    "pwd",
-    "read",
-    "readonly",
-    "return",
-    "set",
-    "shift",
-    "source",
-    "test",
-    "times",
-    "trap",
-    "type",
-    "ulimit",
-    "umask",
-    "unalias",
-    "unset",
-    "wait",
-    // nb: bash builtins because we always force bash. need to extend this if
-    // support for other shells is added.
+/// Print full candidate lists in errors instead of the closest matches.
+fn verbose_errors() -> bool {
+    std::env::var("RUST_BUILDKITE_VERBOSE_ERRORS").is_ok()
+}
+
+/// Levenshtein distance between two strings, counted in chars.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b_chars: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b_chars.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut curr = vec![i + 1];
+        for (j, cb) in b_chars.iter().enumerate() {
+            let substitute = prev.get(j).copied().unwrap_or_default() + usize::from(ca != *cb);
+            let delete = prev.get(j + 1).copied().unwrap_or_default() + 1;
+            let insert = curr.get(j).copied().unwrap_or_default() + 1;
+            curr.push(substitute.min(delete).min(insert));
+        }
+        prev = curr;
+    }
+    prev.last().copied().unwrap_or_default()
+}
+
+/// Up to `limit` candidates nearest to `target` by edit distance, closest first.
+fn closest_matches<'a>(
+    target: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+    limit: usize,
+) -> Vec<&'a str> {
+    let mut scored: Vec<(usize, &str)> = candidates
+        .into_iter()
+        .map(|c| (edit_distance(target, c), c))
+        .collect();
+    scored.sort();
+    scored.into_iter().take(limit).map(|(_, c)| c).collect()
+}
+
+/// Builtins every POSIX shell has, so `shell: "sh"` steps may use them
+const POSIX_BUILTINS: &[&str] = &[
+    ".", ":", "[", "alias", "bg", "cd", "command", "eval", "exec", "exit", "export", "fc", "fg",
+    "getopts", "hash", "jobs", "kill", "newgrp", "pwd", "read", "readonly", "return", "set",
+    "shift", "test", "times", "trap", "type", "ulimit", "umask", "unalias", "unset", "wait",
+];
+
+/// Builtins only bash has, allowed on steps validated as bash (the default)
+const BASH_BUILTINS: &[&str] = &[
     "bind",
     "builtin",
     "caller",
@@ -121,10 +248,63 @@ const SHELL_BUILTINS: &[&str] = &[
     "pushd",
     "readarray",
     "shopt",
+    "source",
     "suspend",
     "typeset",
 ];
 
+/// The shell a step's commands are validated for, from `shell:`. Buildkite's
+/// agent decides which shell actually runs them, so this only changes the
+/// bashrs lint and which builtins the allowlist accepts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+enum StepShell {
+    #[default]
+    Bash,
+    Sh,
+}
+
+impl StepShell {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let lit: LitStr = input.parse()?;
+        match lit.value().as_str() {
+            "bash" => Ok(StepShell::Bash),
+            "sh" => Ok(StepShell::Sh),
+            other => Err(Error::new(
+                lit.span(),
+                format!(
+                    "unknown shell \"{}\", expected \"sh\" or \"bash\". shell only changes how \
+                     commands are validated; the agent's shell setting decides what runs them",
+                    other
+                ),
+            )),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            StepShell::Bash => "bash",
+            StepShell::Sh => "sh",
+        }
+    }
+
+    /// Shebang bashrs lints the command under; `#!/bin/sh` makes it flag
+    /// bash-isms.
+    fn shebang(self) -> &'static str {
+        match self {
+            StepShell::Bash => "#!/bin/bash",
+            StepShell::Sh => "#!/bin/sh",
+        }
+    }
+
+    fn builtins(self) -> impl Iterator<Item = &'static str> {
+        let bash: &[&str] = match self {
+            StepShell::Bash => BASH_BUILTINS,
+            StepShell::Sh => &[],
+        };
+        POSIX_BUILTINS.iter().chain(bash).copied()
+    }
+}
+
 /// Expand known expect_env keywords to their literal values.
 /// These are recognized by the macro and expanded inline.
 fn expand_known_env_list(ident: &str) -> Option<&'static [&'static str]> {
@@ -192,17 +372,14 @@ fn discover_host_env_vars() -> HashSet<String> {
     std::env::vars().map(|(k, _)| k).collect()
 }
 
-/// Discover all executable commands in the host machine's PATH at compile time,
-/// plus shell builtins. This provides the default allowed_commands list.
+/// Discover all executable commands in the host machine's PATH at compile time.
+/// This provides the default allowed_commands list.
 fn discover_host_path_commands() -> HashSet<String> {
     use std::fs;
     #[cfg(unix)]
     use std::os::unix::fs::PermissionsExt;
 
     let mut commands = HashSet::new();
-    for builtin in SHELL_BUILTINS {
-        commands.insert((*builtin).to_string());
-    }
 
     if let Ok(path) = std::env::var("PATH") {
         for dir in path.split(':') {
@@ -239,443 +416,1705 @@ fn discover_host_path_commands() -> HashSet<String> {
     commands
 }
 
-/// Strip the `r#` prefix from raw identifiers.
-/// This allows users to write `r#if` or `r#async` to use Rust keywords as field names.
-fn strip_raw_ident(s: &str) -> &str {
-    s.strip_prefix("r#").unwrap_or(s)
+/// Why `bazel_binary` can't run the compile-time checks in `workspace`: a
+/// program name that isn't on PATH, or a path that doesn't exist there.
+#[cfg(feature = "bazel")]
+fn missing_bazel_binary(binary: &str, workspace: &std::path::Path) -> Option<String> {
+    if binary.contains('/') {
+        let program = bazel::program(binary, workspace);
+        (!program.exists()).then(|| format!("{} does not exist", program.display()))
+    } else {
+        (!discover_host_path_commands().contains(binary))
+            .then(|| format!("{} is not on PATH", binary))
+    }
 }
 
-/// Represents a nested value that can be a literal, object, or array.
-/// Used for parsing complex fields like retry, plugins, build, etc.
-#[derive(Clone)]
-enum NestedValue {
-    String(String),
-    Int(i64),
-    Bool(bool),
-    Object(Vec<(String, NestedValue)>),
-    Array(Vec<NestedValue>),
+/// Validate a `workspace:` or `bazel_workspace` directory: a path relative to
+/// the repository root that can be used unquoted in `cd`.
+#[cfg(feature = "bazel")]
+fn parse_workspace_dir(lit: &LitStr, field: &str) -> Result<String> {
+    let value = lit.value();
+    let value = value.trim_end_matches('/');
+    let valid = !value.is_empty()
+        && !value.starts_with('/')
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '_' | '-' | '.'))
+        && !value.split('/').any(|part| part.is_empty() || part == "..");
+    if !valid {
+        return Err(Error::new(
+            lit.span(),
+            format!(
+                "{} must be a directory relative to the repository root, e.g. \"backend\" or \"services/api\"",
+                field
+            ),
+        ));
+    }
+    Ok(value.to_string())
 }
 
-impl NestedValue {
-    /// Parse a nested value from a ParseStream
-    fn parse(input: ParseStream) -> Result<Self> {
-        if input.peek(syn::token::Brace) {
-            let content;
-            braced!(content in input);
-            let mut pairs = Vec::new();
-            while !content.is_empty() {
-                let key = if content.peek(LitStr) {
-                    let lit: LitStr = content.parse()?;
-                    lit.value()
-                } else {
-                    let ident: Ident = content.parse()?;
-                    // Strip r# prefix for raw identifiers (e.g., r#if -> if)
-                    strip_raw_ident(&ident.to_string()).to_string()
-                };
-                content.parse::<Token![:]>()?;
-                let value = NestedValue::parse(&content)?;
-                pairs.push((key, value));
-                if content.peek(Token![,]) {
-                    content.parse::<Token![,]>()?;
-                }
-            }
-            Ok(NestedValue::Object(pairs))
-        } else if input.peek(syn::token::Bracket) {
-            let content;
-            bracketed!(content in input);
-            let mut items = Vec::new();
-            while !content.is_empty() {
-                items.push(NestedValue::parse(&content)?);
-                if content.peek(Token![,]) {
-                    content.parse::<Token![,]>()?;
-                }
-            }
-            Ok(NestedValue::Array(items))
-        } else if input.peek(LitStr) {
-            let lit: LitStr = input.parse()?;
-            Ok(NestedValue::String(lit.value()))
-        } else if input.peek(syn::LitInt) {
-            let lit: syn::LitInt = input.parse()?;
-            Ok(NestedValue::Int(lit.base10_parse()?))
-        } else if input.peek(syn::LitBool) {
-            let lit: syn::LitBool = input.parse()?;
-            Ok(NestedValue::Bool(lit.value()))
-        } else if input.peek(Ident) {
-            let ident: Ident = input.parse()?;
-            match ident.to_string().as_str() {
-                "true" => Ok(NestedValue::Bool(true)),
-                "false" => Ok(NestedValue::Bool(false)),
-                other => Ok(NestedValue::String(other.to_string())),
+/// Parse `bazel_exclusive_configs: [["remote", "local"], ...]`: groups of
+/// `--config` names a command may use at most one of.
+#[cfg(feature = "bazel")]
+fn parse_exclusive_configs(input: ParseStream) -> Result<Vec<Vec<String>>> {
+    let content;
+    bracketed!(content in input);
+    let mut groups = Vec::new();
+    while !content.is_empty() {
+        let names;
+        let bracket = bracketed!(names in content);
+        let mut group: Vec<String> = Vec::new();
+        while !names.is_empty() {
+            let name: LitStr = names.parse()?;
+            if group.contains(&name.value()) {
+                return Err(Error::new(name.span(), "config listed twice in this group"));
+            }
+            group.push(name.value());
+            if names.peek(Token![,]) {
+                names.parse::<Token![,]>()?;
             }
-        } else {
-            Err(Error::new(
-                input.span(),
-                "expected value (string, number, bool, object, or array)",
-            ))
+        }
+        if group.len() < 2 {
+            return Err(Error::new(
+                bracket.span.join(),
+                "each bazel_exclusive_configs group needs at least two config names",
+            ));
+        }
+        groups.push(group);
+        if content.peek(Token![,]) {
+            content.parse::<Token![,]>()?;
         }
     }
+    Ok(groups)
+}
 
-    /// Convert to a serde_json::Value TokenStream
-    fn to_json_tokens(&self) -> TokenStream2 {
-        match self {
-            NestedValue::String(s) => {
-                quote! { ::rust_buildkite::serde_json::Value::String(#s.to_string()) }
-            }
-            NestedValue::Int(i) => {
-                quote! { ::rust_buildkite::serde_json::Value::Number(::rust_buildkite::serde_json::Number::from(#i)) }
-            }
-            NestedValue::Bool(b) => {
-                quote! { ::rust_buildkite::serde_json::Value::Bool(#b) }
-            }
-            NestedValue::Object(pairs) => {
-                let inserts: Vec<TokenStream2> = pairs
-                    .iter()
-                    .map(|(k, v)| {
-                        let value_tokens = v.to_json_tokens();
-                        quote! { __obj.insert(#k.to_string(), #value_tokens); }
-                    })
-                    .collect();
-                quote! {
-                    {
-                        let mut __obj = ::rust_buildkite::serde_json::Map::new();
-                        #(#inserts)*
-                        ::rust_buildkite::serde_json::Value::Object(__obj)
-                    }
-                }
-            }
-            NestedValue::Array(items) => {
-                let item_tokens: Vec<TokenStream2> =
-                    items.iter().map(|v| v.to_json_tokens()).collect();
-                quote! {
-                    ::rust_buildkite::serde_json::Value::Array(vec![#(#item_tokens),*])
+/// Run `command` from `workspace_dir`. The subshell keeps the `cd` from
+/// leaking into the step's later commands, which Buildkite runs in one shell.
+#[cfg(feature = "bazel")]
+fn in_workspace(workspace_dir: Option<&str>, command: String) -> String {
+    match workspace_dir {
+        Some(dir) => format!("(cd {} && {})", dir, command),
+        None => command,
+    }
+}
+
+/// Error for bazel-only syntax used in a build without the `bazel` feature.
+#[cfg(not(feature = "bazel"))]
+fn bazel_feature_required(span: proc_macro2::Span, what: &str) -> Error {
+    Error::new(
+        span,
+        format!(
+            "{} the 'bazel' feature; add features = [\"bazel\"] to rust-buildkite in Cargo.toml",
+            what
+        ),
+    )
+}
+
+/// Join the `///` comments (`#[doc = "..."]` attributes) before a step into
+/// one description, returned with the span of the first comment.
+fn parse_doc_comments(input: ParseStream) -> Result<Option<(String, proc_macro2::Span)>> {
+    let attrs = input.call(syn::Attribute::parse_outer)?;
+    let mut lines: Vec<String> = Vec::new();
+    for attr in &attrs {
+        let text = match &attr.meta {
+            syn::Meta::NameValue(nv) if nv.path.is_ident("doc") => match &nv.value {
+                syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Str(text),
+                    ..
+                }) => text.value(),
+                other => {
+                    return Err(Error::new_spanned(
+                        other,
+                        "step doc comments must be string literals",
+                    ));
                 }
+            },
+            _ => {
+                return Err(Error::new_spanned(
+                    attr,
+                    "only doc comments (`///`) can go before a step",
+                ));
             }
+        };
+        for line in text.lines() {
+            lines.push(
+                line.strip_prefix(' ')
+                    .unwrap_or(line)
+                    .trim_end()
+                    .to_string(),
+            );
         }
     }
+    while lines.last().is_some_and(|l| l.is_empty()) {
+        lines.pop();
+    }
+    let first = lines.iter().position(|l| !l.is_empty());
+    Ok(first.map(|first| (lines[first..].join("\n"), attrs[0].pound_token.span)))
 }
 
-/// A declarative macro for building type-safe Buildkite pipelines.
-///
-/// # Example
-///
-/// ```ignore
-/// use rust_buildkite::pipeline;
-///
-/// let p = pipeline! {
-///     env: { CI: "true" },
-///     expect_env: [SHELL_ENV, BUILDKITE_ENV],
-///     steps: [
-///         command(cmd!("echo hello")).label("Say Hello").key("hello"),
-///         command(cmd!("npm test")).label("Tests").key("tests").depends_on("hello"),
-///         wait,
-///         block("Deploy to Production?"),
-///         command(cmd!("./deploy.sh")).depends_on("tests")
-///     ]
-/// };
-/// ```
-#[proc_macro]
-pub fn pipeline(input: TokenStream) -> TokenStream {
-    let pipeline_def = parse_macro_input!(input as PipelineDef);
+/// Error for `notify` on step types whose schema has no notify property.
+fn notify_unsupported(span: proc_macro2::Span, step_type: &str) -> Error {
+    Error::new(
+        span,
+        format!(
+            "notify is not supported on {} steps. Buildkite only accepts notify on command and group steps; wrap the step in a group to be notified",
+            step_type
+        ),
+    )
+}
 
-    match pipeline_def.generate() {
-        Ok(tokens) => tokens.into(),
-        Err(err) => err.to_compile_error().into(),
+/// Fields Buildkite only accepts on command steps.
+const COMMAND_ONLY_FIELDS: &[&str] = &[
+    "agents",
+    "artifact_paths",
+    "cache",
+    "cancel_on_build_failing",
+    "command",
+    "commands",
+    "concurrency",
+    "concurrency_group",
+    "concurrency_method",
+    "env",
+    "image",
+    "matrix",
+    "parallelism",
+    "plugins",
+    "priority",
+    "queue",
+    "retry",
+    "secrets",
+    "timeout_in_minutes",
+];
+
+/// An error for a field the Buildkite schema doesn't allow on `step_type`
+/// steps, or None when `field` isn't a known step field at all.
+fn field_unsupported(span: proc_macro2::Span, field: &str, step_type: &str) -> Option<Error> {
+    let hint = match (field, step_type) {
+        ("branches", "group") => {
+            "set branches on the steps inside the group, or filter the group with `if: \"build.branch == ...\"`"
+        }
+        ("agents" | "queue" | "env" | "timeout_in_minutes" | "retry", "group") => {
+            "set it in the group's `defaults: { ... }` to apply it to every step inside"
+        }
+        ("priority", _) => {
+            "priority only orders command jobs; set it on the command steps, or pipeline-wide with `priority:`"
+        }
+        ("soft_fail", "wait" | "block" | "input" | "group") => {
+            "Buildkite only accepts soft_fail on command and trigger steps"
+        }
+        ("skip", "wait" | "block" | "input") => "use `if` to leave the step out instead",
+        ("shell", _) => {
+            "shell only sets how a command step's commands are validated; set it on the command steps, or pipeline-wide with `shell:`"
+        }
+        (field, _) if COMMAND_ONLY_FIELDS.contains(&field) => {
+            "Buildkite only accepts it on command steps"
+        }
+        _ => return None,
+    };
+    Some(Error::new(
+        span,
+        format!(
+            "{} is not supported on {} steps: {}",
+            field, step_type, hint
+        ),
+    ))
+}
+
+/// Largest parallelism a single step may request; Buildkite rejects uploads
+/// that create more jobs than this.
+const MAX_PARALLELISM: u64 = 500;
+
+/// Parse a `parallelism` literal, rejecting values that are invalid (0),
+/// pointless (1), or above the Buildkite maximum.
+fn parse_parallelism(input: ParseStream) -> Result<syn::LitInt> {
+    let lit: syn::LitInt = input.parse()?;
+    let value: u64 = lit.base10_parse()?;
+    if !(2..=MAX_PARALLELISM).contains(&value) {
+        let reason = match value {
+            0 => "0 is invalid".to_string(),
+            1 => "1 runs a single job, so it has no effect".to_string(),
+            _ => format!("the Buildkite maximum is {}", MAX_PARALLELISM),
+        };
+        return Err(Error::new(
+            lit.span(),
+            format!(
+                "parallelism must be between 2 and {}: {}",
+                MAX_PARALLELISM, reason
+            ),
+        ));
     }
+    Ok(lit)
 }
 
-enum ExpectEnvItem {
-    Literal(String),
-    ConstRef(syn::Path),
+/// Longest `timeout_in_minutes` that isn't flagged: Buildkite caps job
+/// duration, so anything longer is almost certainly a unit mistake.
+const MAX_TIMEOUT_MINUTES: u64 = 24 * 60;
+
+/// Parse a `timeout_in_minutes` literal, which must be at least 1. Values
+/// above a day are accepted with a warning.
+fn parse_timeout(input: ParseStream) -> Result<syn::LitInt> {
+    let lit: syn::LitInt = input.parse()?;
+    let value: u64 = lit.base10_parse()?;
+    if value == 0 {
+        return Err(Error::new(
+            lit.span(),
+            "timeout_in_minutes must be at least 1: 0 is invalid",
+        ));
+    }
+    if value > MAX_TIMEOUT_MINUTES {
+        diag::warn(
+            lit.span(),
+            format!(
+                "timeout_in_minutes: {} is over 24 hours ({} minutes); Buildkite caps how long a job may run",
+                value, MAX_TIMEOUT_MINUTES
+            ),
+        );
+    }
+    Ok(lit)
 }
 
-#[derive(Clone)]
-enum NotifyValue {
-    Slack {
-        channel: String,
-        if_: Option<String>,
-    },
-    Email {
-        email: String,
-        if_: Option<String>,
-    },
-    Webhook {
-        url: String,
-        if_: Option<String>,
-    },
-    Pagerduty {
-        service: String,
-        if_: Option<String>,
-    },
-    GithubCommitStatus {
-        context: Option<String>,
-        if_: Option<String>,
-    },
-    GithubCheck,
-    Basecamp {
-        url: String,
-        if_: Option<String>,
-    },
+/// Parse a `concurrency_group` literal, which must be non-empty and contain no whitespace.
+fn parse_concurrency_group(input: ParseStream) -> Result<LitStr> {
+    let lit: LitStr = input.parse()?;
+    let value = lit.value();
+    if value.trim().is_empty() {
+        return Err(Error::new(
+            lit.span(),
+            "concurrency_group must not be empty",
+        ));
+    }
+    if value.chars().any(char::is_whitespace) {
+        return Err(Error::new(
+            lit.span(),
+            format!("concurrency_group '{}' must not contain whitespace", value),
+        ));
+    }
+    Ok(lit)
 }
 
-impl NotifyValue {
-    fn parse(input: ParseStream) -> Result<Self> {
+/// Parse `allowed_teams`, either one team slug or a bracketed list of them.
+/// Buildkite team slugs are lowercase letters, digits and dashes; an entry
+/// that isn't one, or an empty list, leaves nobody able to unblock the step.
+fn parse_allowed_teams(input: ParseStream) -> Result<Vec<String>> {
+    let mut teams = Vec::new();
+    if input.peek(syn::token::Bracket) {
         let content;
-        braced!(content in input);
-
-        let first_key: Ident = content.parse()?;
-        content.parse::<Token![:]>()?;
-
-        match strip_raw_ident(&first_key.to_string()) {
-            "slack" => {
-                let channel: LitStr = content.parse()?;
-                let if_ = Self::parse_optional_if(&content)?;
-                Ok(NotifyValue::Slack {
-                    channel: channel.value(),
-                    if_,
-                })
-            }
-            "email" => {
-                let email: LitStr = content.parse()?;
-                let if_ = Self::parse_optional_if(&content)?;
-                Ok(NotifyValue::Email {
-                    email: email.value(),
-                    if_,
-                })
-            }
-            "webhook" => {
-                let url: LitStr = content.parse()?;
-                let if_ = Self::parse_optional_if(&content)?;
-                Ok(NotifyValue::Webhook {
-                    url: url.value(),
-                    if_,
-                })
-            }
-            "pagerduty_change_event" => {
-                let service: LitStr = content.parse()?;
-                let if_ = Self::parse_optional_if(&content)?;
-                Ok(NotifyValue::Pagerduty {
-                    service: service.value(),
-                    if_,
-                })
-            }
-            "github_commit_status" => {
-                let nested = NestedValue::parse(&content)?;
-                let context = if let NestedValue::Object(pairs) = nested {
-                    pairs
-                        .iter()
-                        .find(|(k, _)| k == "context")
-                        .and_then(|(_, v)| {
-                            if let NestedValue::String(s) = v {
-                                Some(s.clone())
-                            } else {
-                                None
-                            }
-                        })
-                } else {
-                    None
-                };
-                let if_ = Self::parse_optional_if(&content)?;
-                Ok(NotifyValue::GithubCommitStatus { context, if_ })
-            }
-            "github_check" => {
-                let _ = NestedValue::parse(&content)?;
-                Ok(NotifyValue::GithubCheck)
-            }
-            "basecamp_campfire" => {
-                let url: LitStr = content.parse()?;
-                let if_ = Self::parse_optional_if(&content)?;
-                Ok(NotifyValue::Basecamp {
-                    url: url.value(),
-                    if_,
-                })
+        let bracket = bracketed!(content in input);
+        while !content.is_empty() {
+            teams.push(parse_team_slug(&content)?);
+            if content.peek(Token![,]) {
+                content.parse::<Token![,]>()?;
             }
-            other => Err(Error::new(
-                first_key.span(),
-                format!("unknown notify type: {}", other),
-            )),
         }
+        if teams.is_empty() {
+            return Err(Error::new(
+                bracket.span.join(),
+                "allowed_teams is empty, so nobody could unblock this step; \
+                 remove the field to allow everyone",
+            ));
+        }
+    } else {
+        teams.push(parse_team_slug(input)?);
+    }
+    Ok(teams)
+}
+
+fn parse_team_slug(input: ParseStream) -> Result<String> {
+    let lit: LitStr = input.parse()?;
+    let value = lit.value();
+    if value.is_empty() {
+        return Err(Error::new(
+            lit.span(),
+            "allowed_teams entry must not be empty",
+        ));
+    }
+    let valid = value
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if !valid {
+        let suggestion = value
+            .trim()
+            .to_ascii_lowercase()
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join("-");
+        let hint = if suggestion.is_empty() {
+            String::new()
+        } else {
+            format!("\nDid you mean '{}'?", suggestion)
+        };
+        return Err(Error::new(
+            lit.span(),
+            format!(
+                "allowed_teams entry '{}' is not a team slug: use lowercase letters, digits and dashes{}",
+                value, hint
+            ),
+        ));
     }
+    Ok(value)
+}
 
-    fn parse_optional_if(content: ParseStream) -> Result<Option<String>> {
+/// Longest skip reason Buildkite's schema accepts
+const SKIP_REASON_MAX: usize = 70;
+
+/// Parse a bracketed list of step keys: `["build", "lint"]`.
+fn parse_dep_list(input: ParseStream) -> Result<Vec<(String, proc_macro2::Span)>> {
+    let content;
+    bracketed!(content in input);
+    let mut deps = Vec::new();
+    while !content.is_empty() {
+        let dep: LitStr = content.parse()?;
+        deps.push((dep.value(), dep.span()));
         if content.peek(Token![,]) {
             content.parse::<Token![,]>()?;
-            if content.peek(Ident) {
-                let key: Ident = content.parse()?;
-                if strip_raw_ident(&key.to_string()) == "if" {
-                    content.parse::<Token![:]>()?;
-                    let val: LitStr = content.parse()?;
-                    if content.peek(Token![,]) {
-                        content.parse::<Token![,]>()?;
-                    }
-                    return Ok(Some(val.value()));
-                }
-            }
         }
-        Ok(None)
     }
+    Ok(deps)
+}
 
-    fn to_tokens(&self) -> TokenStream2 {
-        match self {
-            NotifyValue::Slack { channel, if_ } => {
-                let if_tokens = match if_ {
-                    Some(c) => quote! { Some(::rust_buildkite::If(#c.to_string())) },
-                    None => quote! { None },
-                };
-                quote! {
-                    ::rust_buildkite::BuildNotifyItem::Slack(::rust_buildkite::NotifySlack {
-                        slack: Some(::rust_buildkite::NotifySlackSlack::String(#channel.to_string())),
-                        if_: #if_tokens,
-                    })
-                }
+/// Parse a step label. A label known at expansion time, such as
+/// `comptime_shell!(...)` or `env!(...)`, becomes a string literal so it's
+/// validated like one, and its origin is kept for the errors. `comptime!` and
+/// `runtime!` are unwrapped, leaving a runtime expression to build the label.
+fn parse_label(input: ParseStream) -> Result<(syn::Expr, Option<ValueOrigin>)> {
+    label_expr(input.parse()?)
+}
+
+fn label_expr(expr: syn::Expr) -> Result<(syn::Expr, Option<ValueOrigin>)> {
+    let syn::Expr::Macro(mac) = &expr else {
+        return Ok((expr, None));
+    };
+    let macro_name = mac.mac.path.segments.last().map(|s| s.ident.to_string());
+    if matches!(macro_name.as_deref(), Some("comptime" | "runtime")) {
+        return label_expr(mac.mac.parse_body()?);
+    }
+    match DynamicValue::from_expr(expr.clone())? {
+        DynamicValue::Computed(value, origin) => {
+            let lit = LitStr::new(&value, origin.span);
+            let label = syn::Expr::Lit(syn::ExprLit {
+                attrs: Vec::new(),
+                lit: syn::Lit::Str(lit),
+            });
+            Ok((label, Some(origin)))
+        }
+        _ => Ok((expr, None)),
+    }
+}
+
+/// Parse a skip reason: a string literal, or a value known at expansion time
+/// such as `comptime_shell!(...)`. Its length is checked here, since
+/// Buildkite rejects a reason over 70 characters.
+fn parse_skip_reason(input: ParseStream) -> Result<SkipValue> {
+    let span = input.span();
+    let value = DynamicValue::parse(input)?;
+    let Some(reason) = value.as_literal() else {
+        return Err(Error::new(
+            span,
+            "skip reason must be known at expansion time: use a string literal, env!(...) or comptime_shell!(...)",
+        ));
+    };
+    let len = reason.chars().count();
+    if len > SKIP_REASON_MAX {
+        return Err(Error::new(
+            span,
+            format!(
+                "skip reason is {} characters, but Buildkite allows at most {}{}",
+                len,
+                SKIP_REASON_MAX,
+                value.origin_note()
+            ),
+        ));
+    }
+    Ok(SkipValue::Reason(reason.to_string()))
+}
+
+/// Parse the reason given to `skip_validation`. The reason isn't emitted
+/// anywhere; requiring it documents why the step is exempt at the call site.
+fn parse_skip_validation_reason(input: ParseStream, span: proc_macro2::Span) -> Result<()> {
+    const USAGE: &str =
+        "skip_validation requires a reason, e.g. skip_validation(\"only exists on gpu agents\")";
+    if !input.peek(LitStr) {
+        let span = if input.is_empty() { span } else { input.span() };
+        return Err(Error::new(span, USAGE));
+    }
+    let lit: LitStr = input.parse()?;
+    if lit.value().trim().is_empty() {
+        return Err(Error::new(lit.span(), USAGE));
+    }
+    Ok(())
+}
+
+/// Byte range of the first malformed `${...}` in a Buildkite-interpolated
+/// string: an empty `${}`, or a `${` with no closing brace, from the `$` to
+/// the end of the name.
+fn malformed_interpolation(value: &str) -> Option<std::ops::Range<usize>> {
+    let mut rest = value.char_indices().peekable();
+    while let Some((i, c)) = rest.next() {
+        if c != '$' {
+            continue;
+        }
+        match rest.peek() {
+            Some((_, '$')) => {
+                rest.next();
             }
-            NotifyValue::Email { email, if_ } => {
-                let if_tokens = match if_ {
-                    Some(c) => quote! { Some(::rust_buildkite::If(#c.to_string())) },
-                    None => quote! { None },
-                };
-                quote! {
-                    ::rust_buildkite::BuildNotifyItem::Email(::rust_buildkite::NotifyEmail {
-                        email: Some(#email.to_string()),
-                        if_: #if_tokens,
-                    })
+            Some((_, '{')) => {
+                let open = &value[i + 2..];
+                if open.starts_with('}') {
+                    return Some(i..i + 3);
                 }
-            }
-            NotifyValue::Webhook { url, if_ } => {
-                let if_tokens = match if_ {
-                    Some(c) => quote! { Some(::rust_buildkite::If(#c.to_string())) },
-                    None => quote! { None },
-                };
-                quote! {
-                    ::rust_buildkite::BuildNotifyItem::Webhook(::rust_buildkite::NotifyWebhook {
-                        webhook: Some(#url.to_string()),
-                        if_: #if_tokens,
-                    })
+                if !open.contains('}') {
+                    let name = open
+                        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                        .unwrap_or(open.len());
+                    return Some(i..i + 2 + name);
                 }
             }
-            NotifyValue::Pagerduty { service, if_ } => {
-                let if_tokens = match if_ {
-                    Some(c) => quote! { Some(::rust_buildkite::If(#c.to_string())) },
-                    None => quote! { None },
-                };
-                quote! {
-                    ::rust_buildkite::BuildNotifyItem::Pagerduty(::rust_buildkite::NotifyPagerduty {
-                        pagerduty_change_event: Some(#service.to_string()),
-                        if_: #if_tokens,
-                    })
-                }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Names of `$VAR` and `${VAR}` references in a Buildkite-interpolated string.
+/// `$$` is Buildkite's escape for a literal dollar sign and is skipped.
+fn interpolated_vars(value: &str) -> Vec<String> {
+    let mut vars = Vec::new();
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            continue;
+        }
+        let braced = match chars.peek() {
+            Some('$') => {
+                chars.next();
+                continue;
             }
-            NotifyValue::GithubCommitStatus { context, if_ } => {
-                let if_tokens = match if_ {
-                    Some(c) => quote! { Some(::rust_buildkite::If(#c.to_string())) },
-                    None => quote! { None },
-                };
-                let context_tokens = match context {
-                    Some(c) => {
-                        quote! { Some(::rust_buildkite::NotifyGithubCommitStatusGithubCommitStatus { context: Some(#c.to_string()) }) }
-                    }
-                    None => quote! { None },
-                };
-                quote! {
-                    ::rust_buildkite::BuildNotifyItem::GithubCommitStatus(::rust_buildkite::NotifyGithubCommitStatus {
-                        github_commit_status: #context_tokens,
-                        if_: #if_tokens,
-                    })
-                }
+            Some('{') => {
+                chars.next();
+                true
             }
-            NotifyValue::GithubCheck => {
-                quote! {
-                    ::rust_buildkite::BuildNotifyItem::GithubCheck(::rust_buildkite::NotifyGithubCheck {
-                        github_check: None,
-                    })
-                }
+            _ => false,
+        };
+        let mut name = String::new();
+        while let Some(&next) = chars.peek() {
+            if next.is_ascii_alphanumeric() || next == '_' {
+                name.push(next);
+                chars.next();
+            } else {
+                break;
             }
-            NotifyValue::Basecamp { url, if_ } => {
-                let if_tokens = match if_ {
-                    Some(c) => quote! { Some(::rust_buildkite::If(#c.to_string())) },
-                    None => quote! { None },
-                };
-                quote! {
-                    ::rust_buildkite::BuildNotifyItem::Basecamp(::rust_buildkite::NotifyBasecamp {
-                        basecamp_campfire: Some(#url.to_string()),
-                        if_: #if_tokens,
-                    })
+        }
+        if braced {
+            // Skip any `${VAR:-default}` modifier up to the closing brace
+            for next in chars.by_ref() {
+                if next == '}' {
+                    break;
                 }
             }
         }
+        if !name.is_empty() {
+            vars.push(name);
+        }
     }
+    vars
 }
 
-#[derive(Clone)]
-enum SecretsValue {
-    Array(Vec<String>),
-    Object(Vec<(String, String)>),
+/// Maximum number of validation errors reported for a single pipeline
+const MAX_REPORTED_ERRORS: usize = 20;
+
+/// Combine accumulated validation errors into one, so every problem is
+/// reported in a single compile. Output is capped at MAX_REPORTED_ERRORS.
+fn combine_errors(errors: Vec<Error>) -> Option<Error> {
+    let total = errors.len();
+    let mut iter = errors.into_iter();
+    let mut combined = iter.next()?;
+    for err in iter.take(MAX_REPORTED_ERRORS - 1) {
+        combined.combine(err);
+    }
+    if total > MAX_REPORTED_ERRORS {
+        combined.combine(Error::new(
+            proc_macro2::Span::call_site(),
+            format!("... and {} more errors", total - MAX_REPORTED_ERRORS),
+        ));
+    }
+    Some(combined)
 }
 
-struct PipelineDef {
-    allowed_commands: Option<Vec<(String, proc_macro2::Span)>>,
-    additional_commands: Vec<String>,
-    expect_paths: Vec<String>,
-    expect_env: Option<Vec<ExpectEnvItem>>,
-    /// Custom Bazel verbs to allow (enables bazel_<verb> shorthand macros)
-    #[cfg(feature = "bazel")]
-    #[allow(dead_code)]
-    custom_verbs: Vec<String>,
-    env: Option<Vec<(Ident, LitStr)>>,
-    steps: Vec<StepDef>,
-    agents: Vec<(String, String)>,
-    notify: Vec<NotifyValue>,
-    image: Option<String>,
-    secrets: Option<SecretsValue>,
-    priority: Option<i64>,
-    default_plugins: Vec<NestedValue>,
+/// Sub-span of a string literal covering `range` of its unescaped value.
+/// Returns None when the literal contains escapes (so offsets don't line up)
+/// or the toolchain doesn't support sub-spans.
+fn literal_subspan(
+    literal: &proc_macro2::Literal,
+    value: &str,
+    range: std::ops::Range<usize>,
+) -> Option<proc_macro2::Span> {
+    let repr = literal.to_string();
+    let body_start = if repr.starts_with('"') {
+        1
+    } else if repr.starts_with('r') {
+        repr.find('"')? + 1
+    } else {
+        return None;
+    };
+    if repr.get(body_start..body_start + value.len())? != value {
+        return None;
+    }
+    literal.subspan(body_start + range.start..body_start + range.end)
 }
 
-impl Parse for PipelineDef {
-    fn parse(input: ParseStream) -> Result<Self> {
-        let mut allowed_commands = None;
-        let mut additional_commands = Vec::new();
-        let mut expect_paths = Vec::new();
-        let mut expect_env = None;
-        #[cfg(feature = "bazel")]
-        let mut custom_verbs = Vec::new();
-        let mut env = None;
-        let mut steps = Vec::new();
-        let mut agents = Vec::new();
-        let mut notify = Vec::new();
-        let mut image = None;
-        let mut secrets = None;
-        let mut priority = None;
-        let mut default_plugins = Vec::new();
+/// Render the line of `text` containing `range` with carets under it, indented
+/// for inclusion in an error message.
+fn caret_snippet(text: &str, range: std::ops::Range<usize>) -> String {
+    let Some(before) = text.get(..range.start) else {
+        return String::new();
+    };
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let line_end = text
+        .get(range.start..)
+        .and_then(|rest| rest.find('\n'))
+        .map_or(text.len(), |i| range.start + i);
+    let line = text.get(line_start..line_end).unwrap_or_default();
+    let column = text
+        .get(line_start..range.start)
+        .map_or(0, |prefix| prefix.chars().count());
+    let width = text
+        .get(range.start..range.end.min(line_end))
+        .map_or(1, |marked| marked.chars().count().max(1));
+    format!(
+        "\n    {}\n    {}{}",
+        line,
+        " ".repeat(column),
+        "^".repeat(width)
+    )
+}
 
-        while !input.is_empty() {
-            let key: Ident = input.parse()?;
-            input.parse::<Token![:]>()?;
+/// Byte offset of a 0-based line and 1-based character column in `text`.
+fn line_col_offset(text: &str, line: usize, column: usize) -> Option<usize> {
+    let mut line_start = 0;
+    for _ in 0..line {
+        line_start += text.get(line_start..)?.find('\n')? + 1;
+    }
+    let line_text = text.get(line_start..)?;
+    let line_text = line_text.split('\n').next().unwrap_or_default();
+    let offset = line_text
+        .char_indices()
+        .nth(column.saturating_sub(1))
+        .map_or(line_text.len(), |(i, _)| i);
+    Some(line_start + offset)
+}
 
-            match key.to_string().as_str() {
-                "expect_paths" => {
-                    let content;
-                    bracketed!(content in input);
-                    while !content.is_empty() {
-                        let lit: LitStr = content.parse()?;
-                        expect_paths.push(lit.value());
-                        if content.peek(Token![,]) {
-                            content.parse::<Token![,]>()?;
-                        }
-                    }
+/// Byte range in `command` of a bashrs diagnostic running from `start` to
+/// `end` (line, column). bashrs positions are 1-based and count the
+/// `#!/bin/bash` line prepended before linting.
+fn diagnostic_range(
+    command: &str,
+    start: (usize, usize),
+    end: (usize, usize),
+) -> Option<std::ops::Range<usize>> {
+    let from = line_col_offset(command, start.0.checked_sub(2)?, start.1)?;
+    let to = if end.0 == start.0 {
+        line_col_offset(command, end.0.saturating_sub(2), end.1)
+            .filter(|&to| to > from)
+            .unwrap_or(from + 1)
+    } else {
+        from + 1
+    };
+    Some(from..to.min(command.len()).max(from))
+}
+
+/// Byte range of the first `$var` or `${var}` reference in `text`.
+fn find_var_reference(text: &str, var: &str) -> Option<std::ops::Range<usize>> {
+    let mut search_from = 0;
+    while let Some(found) = text.get(search_from..)?.find('$') {
+        let start = search_from + found;
+        let rest = text.get(start + 1..)?;
+        let braced = rest.starts_with('{');
+        let name_start = if braced { 1 } else { 0 };
+        let name = rest.get(name_start..)?;
+        if let Some(after) = name.strip_prefix(var) {
+            let boundary = !after.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_');
+            if boundary {
+                let close = if braced {
+                    after.find('}').map_or(0, |i| i + 1)
+                } else {
+                    0
+                };
+                return Some(start..start + 1 + name_start + var.len() + close);
+            }
+        }
+        search_from = start + 1;
+    }
+    None
+}
+
+/// Strip the `r#` prefix from raw identifiers.
+/// This allows users to write `r#if` or `r#async` to use Rust keywords as field names.
+fn strip_raw_ident(s: &str) -> &str {
+    s.strip_prefix("r#").unwrap_or(s)
+}
+
+/// Parse an agent tag name: an identifier, or a string literal for tags
+/// that aren't valid identifiers, such as `"aws:instance-type"`.
+fn parse_agent_key(input: ParseStream) -> Result<String> {
+    if input.peek(LitStr) {
+        let lit: LitStr = input.parse()?;
+        if lit.value().is_empty() {
+            return Err(Error::new(lit.span(), "agent tag name is empty"));
+        }
+        Ok(lit.value())
+    } else {
+        let ident: Ident = input.parse()?;
+        Ok(ident.to_string())
+    }
+}
+
+/// Parse an `agents` value: an object of tags, `{ queue: "default" }`, or
+/// Buildkite's list form, `["queue=default", "os=linux"]`. Returns the tags
+/// and whether they were written as a list, so they're emitted as one.
+fn parse_agents(input: ParseStream) -> Result<(Vec<(String, LitStr)>, bool)> {
+    let mut tags = Vec::new();
+    if input.peek(syn::token::Bracket) {
+        let content;
+        bracketed!(content in input);
+        let entries = syn::punctuated::Punctuated::<LitStr, Token![,]>::parse_terminated(&content)?;
+        for entry in entries {
+            let text = entry.value();
+            let (key, value) = match text.split_once('=') {
+                Some((key, value)) if !value.contains('=') => (key, value),
+                _ => {
+                    return Err(Error::new(
+                        entry.span(),
+                        format!(
+                            "agents entry \"{}\" must be key=value with exactly one '='",
+                            text
+                        ),
+                    ));
                 }
-                "expect_env" => {
-                    let content;
-                    bracketed!(content in input);
-                    let mut vars = Vec::new();
-                    while !content.is_empty() {
-                        if content.peek(LitStr) {
-                            let lit: LitStr = content.parse()?;
-                            vars.push(ExpectEnvItem::Literal(lit.value()));
-                        } else {
-                            let path: syn::Path = content.parse()?;
-                            let ident_str = path.get_ident().map(|i| i.to_string());
+            };
+            if key.is_empty() || value.is_empty() {
+                return Err(Error::new(
+                    entry.span(),
+                    format!(
+                        "agents entry \"{}\" has an empty {}; expected key=value",
+                        text,
+                        if key.is_empty() { "key" } else { "value" }
+                    ),
+                ));
+            }
+            tags.push((key.to_string(), LitStr::new(value, entry.span())));
+        }
+        return Ok((tags, true));
+    }
+    let content;
+    braced!(content in input);
+    while !content.is_empty() {
+        let key = parse_agent_key(&content)?;
+        content.parse::<Token![:]>()?;
+        let value: LitStr = content.parse()?;
+        tags.push((key, value));
+        if content.peek(Token![,]) {
+            content.parse::<Token![,]>()?;
+        }
+    }
+    Ok((tags, false))
+}
 
-                            if let Some(ref name) = ident_str {
+/// Parse the value of the `queue` shorthand for `agents: { queue: "..." }`.
+/// `other` is the span of an `agents` field already set alongside it, which
+/// is an error.
+fn parse_queue(
+    input: ParseStream,
+    field: proc_macro2::Span,
+    other: Option<proc_macro2::Span>,
+) -> Result<(String, LitStr)> {
+    if other.is_some() {
+        return Err(queue_conflict(field));
+    }
+    let queue: LitStr = input.parse()?;
+    if queue.value().is_empty() {
+        return Err(Error::new(queue.span(), "queue is empty"));
+    }
+    Ok(("queue".to_string(), queue))
+}
+
+/// The error for setting both `queue` and `agents`, at the second of them.
+fn queue_conflict(span: proc_macro2::Span) -> Error {
+    Error::new(
+        span,
+        "both queue and agents are set; queue is shorthand for agents: { queue: \"...\" }, \
+         so move the queue into agents",
+    )
+}
+
+/// Set `agents` to `tags`, in the list form when they were written as one.
+fn set_agents<'a>(
+    fields: &mut yaml::Fields,
+    tags: impl Iterator<Item = (&'a str, &'a str)>,
+    list: bool,
+) {
+    let tags: Vec<(&str, &str)> = tags.collect();
+    if tags.is_empty() {
+        return;
+    }
+    if list {
+        let entries: Vec<String> = tags.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        fields.set(
+            "agents",
+            Ok(yaml::strings(entries.iter().map(String::as_str))),
+            quote! { .agents(Some(::rust_buildkite::helpers::agents_list(&[#(#entries),*]))) },
+        );
+    } else {
+        let pairs = tags.iter().map(|(k, v)| quote! { (#k, #v) });
+        let value = yaml::mapping(
+            tags.iter()
+                .map(|(k, v)| (k.to_string(), Ok(yaml::string(v)))),
+        );
+        fields.set(
+            "agents",
+            value,
+            quote! { .agents(Some(::rust_buildkite::helpers::agents_map(&[#(#pairs),*]))) },
+        );
+    }
+}
+
+/// Object fields that may be written alone to mean `true`, like their
+/// zero-argument fluent methods: `command { ..., soft_fail }`.
+const FLAG_FIELDS: &[&str] = &[
+    "soft_fail",
+    "allow_dependency_failure",
+    "async",
+    "continue_on_failure",
+];
+
+/// Consume the `:` after an object field name, unless the field is a flag
+/// written alone. Returns whether it was.
+fn parse_field_colon(input: ParseStream, field: &Ident) -> Result<bool> {
+    let name = field.to_string();
+    if FLAG_FIELDS.contains(&strip_raw_ident(&name)) && (input.is_empty() || input.peek(Token![,]))
+    {
+        return Ok(true);
+    }
+    input.parse::<Token![:]>()?;
+    Ok(false)
+}
+
+/// A flag field's value: `true` when written alone, else a bool literal.
+fn parse_flag_value(input: ParseStream, shorthand: bool) -> Result<bool> {
+    if shorthand {
+        return Ok(true);
+    }
+    Ok(input.parse::<syn::LitBool>()?.value())
+}
+
+/// Represents a nested value that can be a literal, object, or array.
+/// Used for parsing complex fields like retry, plugins, build, etc.
+#[derive(Clone)]
+enum NestedValue {
+    String(String),
+    Int(i64),
+    Bool(bool),
+    Object(Vec<(String, NestedValue)>),
+    Array(Vec<NestedValue>),
+}
+
+impl NestedValue {
+    /// Parse a nested value from a ParseStream. A bare identifier is read as
+    /// a string, so plugin configs can write `image: node`; use
+    /// [`NestedValue::parse_field`] where that's more likely a typo.
+    fn parse(input: ParseStream) -> Result<Self> {
+        Self::parse_in(input, None)
+    }
+
+    /// Parse the value of `field`, noting each bare identifier read as a
+    /// string for the bare_ident_value lint.
+    fn parse_field(input: ParseStream, field: &'static str) -> Result<Self> {
+        Self::parse_in(input, Some(field))
+    }
+
+    fn parse_in(input: ParseStream, field: Option<&'static str>) -> Result<Self> {
+        if input.peek(syn::token::Brace) {
+            let content;
+            braced!(content in input);
+            let mut pairs = Vec::new();
+            while !content.is_empty() {
+                let key = if content.peek(LitStr) {
+                    let lit: LitStr = content.parse()?;
+                    lit.value()
+                } else {
+                    let ident: Ident = content.parse()?;
+                    // Strip r# prefix for raw identifiers (e.g., r#if -> if)
+                    strip_raw_ident(&ident.to_string()).to_string()
+                };
+                content.parse::<Token![:]>()?;
+                let value = NestedValue::parse_in(&content, field)?;
+                pairs.push((key, value));
+                if content.peek(Token![,]) {
+                    content.parse::<Token![,]>()?;
+                }
+            }
+            Ok(NestedValue::Object(pairs))
+        } else if input.peek(syn::token::Bracket) {
+            let content;
+            bracketed!(content in input);
+            let mut items = Vec::new();
+            while !content.is_empty() {
+                if shell::peek(&content) {
+                    let cmd = shell::parse_invocation(&content)?;
+                    items.extend(cmd.run_lines()?.into_iter().map(NestedValue::String));
+                } else {
+                    items.push(NestedValue::parse_in(&content, field)?);
+                }
+                if content.peek(Token![,]) {
+                    content.parse::<Token![,]>()?;
+                }
+            }
+            Ok(NestedValue::Array(items))
+        } else if input.peek(LitStr) {
+            let lit: LitStr = input.parse()?;
+            Ok(NestedValue::String(lit.value()))
+        } else if input.peek(syn::LitInt) {
+            let lit: syn::LitInt = input.parse()?;
+            Ok(NestedValue::Int(lit.base10_parse()?))
+        } else if input.peek(syn::LitBool) {
+            let lit: syn::LitBool = input.parse()?;
+            Ok(NestedValue::Bool(lit.value()))
+        } else if shell::peek(input) {
+            let cmd = shell::parse_invocation(input)?;
+            if cmd.is_list() {
+                let lines = cmd.run_lines()?;
+                Ok(NestedValue::Array(
+                    lines.into_iter().map(NestedValue::String).collect(),
+                ))
+            } else {
+                cmd.run().map(NestedValue::String)
+            }
+        } else if input.peek(Ident) {
+            let ident: Ident = input.parse()?;
+            match ident.to_string().as_str() {
+                "true" => Ok(NestedValue::Bool(true)),
+                "false" => Ok(NestedValue::Bool(false)),
+                other => {
+                    if let Some(field) = field {
+                        lints::record_bare_ident(field, &ident);
+                    }
+                    Ok(NestedValue::String(other.to_string()))
+                }
+            }
+        } else {
+            Err(Error::new(
+                input.span(),
+                "expected value (string, number, bool, object, or array)",
+            ))
+        }
+    }
+
+    /// Convert to a serde_json::Value TokenStream
+    fn to_json_tokens(&self) -> TokenStream2 {
+        match self {
+            NestedValue::String(s) => {
+                quote! { ::rust_buildkite::serde_json::Value::String(#s.to_string()) }
+            }
+            NestedValue::Int(i) => {
+                quote! { ::rust_buildkite::serde_json::Value::Number(::rust_buildkite::serde_json::Number::from(#i)) }
+            }
+            NestedValue::Bool(b) => {
+                quote! { ::rust_buildkite::serde_json::Value::Bool(#b) }
+            }
+            NestedValue::Object(pairs) => {
+                let inserts: Vec<TokenStream2> = pairs
+                    .iter()
+                    .map(|(k, v)| {
+                        let value_tokens = v.to_json_tokens();
+                        quote! { __obj.insert(#k.to_string(), #value_tokens); }
+                    })
+                    .collect();
+                quote! {
+                    {
+                        let mut __obj = ::rust_buildkite::serde_json::Map::new();
+                        #(#inserts)*
+                        ::rust_buildkite::serde_json::Value::Object(__obj)
+                    }
+                }
+            }
+            NestedValue::Array(items) => {
+                let item_tokens: Vec<TokenStream2> =
+                    items.iter().map(|v| v.to_json_tokens()).collect();
+                quote! {
+                    ::rust_buildkite::serde_json::Value::Array(vec![#(#item_tokens),*])
+                }
+            }
+        }
+    }
+}
+
+/// A declarative macro for building type-safe Buildkite pipelines.
+///
+/// # Example
+///
+/// ```ignore
+/// use rust_buildkite::pipeline;
+///
+/// let p = pipeline! {
+///     env: { CI: "true" },
+///     expect_env: [SHELL_ENV, BUILDKITE_ENV],
+///     steps: [
+///         command(cmd!("echo hello")).label("Say Hello").key("hello"),
+///         command(cmd!("npm test")).label("Tests").key("tests").depends_on("hello"),
+///         wait,
+///         block("Deploy to Production?"),
+///         command(cmd!("./deploy.sh")).depends_on("tests")
+///     ]
+/// };
+/// ```
+///
+/// Steps whose values are only known at runtime (e.g. a `runtime!` key) can
+/// still fail to build; `pipeline!` panics in that case. Use [`try_pipeline!`]
+/// to get a `Result` instead.
+#[proc_macro]
+pub fn pipeline(input: TokenStream) -> TokenStream {
+    expand_pipeline(input, Expansion::Pipeline)
+}
+
+/// Like [`pipeline!`], but evaluates to
+/// `Result<JsonSchemaForBuildkitePipelineConfigurationFiles, rust_buildkite::BuildError>`
+/// instead of panicking when a step fails to build at runtime. The error
+/// names the failing step by key or label.
+///
+/// # Example
+///
+/// ```ignore
+/// use rust_buildkite::try_pipeline;
+///
+/// let key = std::env::var("STEP_KEY")?;
+/// let p = try_pipeline! {
+///     steps: [
+///         command(cmd!("cargo test")).key(runtime!(key))
+///     ]
+/// }?;
+/// ```
+#[proc_macro]
+pub fn try_pipeline(input: TokenStream) -> TokenStream {
+    expand_pipeline(input, Expansion::TryPipeline)
+}
+
+/// Takes the same input as [`pipeline!`] and runs the same validation, but
+/// evaluates to a `rust_buildkite::ir::PipelineIr` describing the steps
+/// instead of the pipeline itself. Requires the `ir` feature.
+///
+/// # Example
+///
+/// ```ignore
+/// use rust_buildkite::pipeline_ir;
+///
+/// let ir = pipeline_ir! {
+///     steps: [
+///         command(cmd!("cargo build")).key("build"),
+///         command(cmd!("cargo test")).depends_on("build")
+///     ]
+/// };
+/// for step in ir.all_steps() {
+///     println!("{:?} {:?}", step.kind, step.key);
+/// }
+/// ```
+#[cfg(feature = "ir")]
+#[proc_macro]
+pub fn pipeline_ir(input: TokenStream) -> TokenStream {
+    expand_pipeline(input, Expansion::Ir)
+}
+
+/// What a pipeline macro evaluates to.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Expansion {
+    /// The pipeline, panicking if a step fails to build at runtime
+    Pipeline,
+    /// `Result<pipeline, BuildError>`
+    TryPipeline,
+    /// The serialized `PipelineIr`
+    #[cfg(feature = "ir")]
+    Ir,
+}
+
+fn expand_pipeline(input: TokenStream, expansion: Expansion) -> TokenStream {
+    // nb: a previous expansion that failed to parse may have left warnings queued
+    diag::clear();
+    track::clear();
+    lints::clear_bare_idents();
+    #[cfg(feature = "bazel")]
+    bazel_report::clear();
+    let mut timings = timing::Timings::from_env();
+    let parsed = timings.time("parsing", || syn::parse::<PipelineDef>(input));
+    let mut pipeline_def = match parsed {
+        Ok(def) => def,
+        Err(err) => return err.to_compile_error().into(),
+    };
+    if let Err(err) = timings.time("shell linting", || pipeline_def.lint_commands()) {
+        diag::clear();
+        track::clear();
+        return err.to_compile_error().into();
+    }
+    if expansion == Expansion::TryPipeline
+        && let Some(span) = pipeline_def.lazy
+    {
+        return Error::new(
+            span,
+            "try_pipeline! can't be lazy: a lazy pipeline has no runtime values to fail on, so use pipeline!",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    let generated = pipeline_def.generate(&mut timings);
+    #[cfg(feature = "bazel")]
+    bazel_report::finish();
+    timings.finish(pipeline_def.span);
+    match generated {
+        Ok(tokens) => {
+            let panic_on_error = quote! {
+                macro_rules! __rbk_built {
+                    ($result:expr) => {
+                        match $result {
+                            ::std::result::Result::Ok(value) => value,
+                            ::std::result::Result::Err(e) => panic!("{}", e),
+                        }
+                    };
+                }
+            };
+            let tokens = match expansion {
+                Expansion::Pipeline if pipeline_def.lazy.is_some() => {
+                    // nb: a fn item can't capture locals, so only consts and
+                    // statics can leak into the shared pipeline
+                    quote! {
+                        {
+                            fn __rbk_build() -> ::rust_buildkite::JsonSchemaForBuildkitePipelineConfigurationFiles {
+                                #panic_on_error
+                                #tokens
+                            }
+                            static __RBK_PIPELINE: ::std::sync::LazyLock<
+                                ::rust_buildkite::JsonSchemaForBuildkitePipelineConfigurationFiles,
+                            > = ::std::sync::LazyLock::new(__rbk_build);
+                            &*__RBK_PIPELINE
+                        }
+                    }
+                }
+                Expansion::Pipeline => quote! {
+                    {
+                        #panic_on_error
+                        #tokens
+                    }
+                },
+                // nb: the error breaks out of a labeled block rather than a
+                // closure, so `?`, `return` and `.await` in runtime values
+                // still belong to the enclosing fn, as under `pipeline!`
+                Expansion::TryPipeline => quote! {
+                    '__rbk_try: {
+                        macro_rules! __rbk_built {
+                            ($result:expr) => {
+                                match $result {
+                                    ::std::result::Result::Ok(value) => value,
+                                    ::std::result::Result::Err(e) => {
+                                        break '__rbk_try ::std::result::Result::Err(e)
+                                    }
+                                }
+                            };
+                        }
+                        ::std::result::Result::<
+                            ::rust_buildkite::JsonSchemaForBuildkitePipelineConfigurationFiles,
+                            ::rust_buildkite::BuildError,
+                        >::Ok(#tokens)
+                    }
+                },
+                #[cfg(feature = "ir")]
+                Expansion::Ir => {
+                    let json = ir::to_json(&pipeline_def);
+                    quote! { ::rust_buildkite::ir::PipelineIr::__from_expansion(#json) }
+                }
+            };
+            let tracked = track::take();
+            let warnings = diag::take();
+            let warnings = quote! { #tracked #warnings };
+            if warnings.is_empty() {
+                return tokens.into();
+            }
+            quote! {
+                {
+                    #warnings
+                    #tokens
+                }
+            }
+            .into()
+        }
+        Err(err) => {
+            diag::clear();
+            track::clear();
+            err.to_compile_error().into()
+        }
+    }
+}
+
+enum ExpectEnvItem {
+    Literal(String),
+    ConstRef(syn::Path),
+}
+
+#[derive(Clone)]
+enum NotifyValue {
+    Slack {
+        channel: String,
+        if_: Option<String>,
+    },
+    Email {
+        email: String,
+        if_: Option<String>,
+    },
+    Webhook {
+        url: String,
+        if_: Option<String>,
+    },
+    Pagerduty {
+        service: String,
+        if_: Option<String>,
+    },
+    GithubCommitStatus {
+        context: Option<String>,
+        if_: Option<String>,
+    },
+    GithubCheck,
+    Basecamp {
+        url: String,
+        if_: Option<String>,
+    },
+}
+
+impl NotifyValue {
+    /// Parse a `{ ... }` notify entry: exactly one type field giving the
+    /// target, and an optional `r#if`, in any order.
+    fn parse(input: ParseStream) -> Result<Self> {
+        let content;
+        let brace = braced!(content in input);
+
+        let mut seen: Vec<String> = Vec::new();
+        let mut value: Option<(Ident, NotifyValue)> = None;
+        let mut condition: Option<(Ident, String)> = None;
+        while !content.is_empty() {
+            let key: Ident = content.parse()?;
+            let name = strip_raw_ident(&key.to_string()).to_string();
+            if seen.contains(&name) {
+                return Err(Error::new(
+                    key.span(),
+                    format!("notify field {} is set more than once", name),
+                ));
+            }
+            seen.push(name.clone());
+            content.parse::<Token![:]>()?;
+
+            let parsed = match name.as_str() {
+                "if" => {
+                    condition = Some((key, conditions::parse(&content)?.value()));
+                    None
+                }
+                "slack" => Some(NotifyValue::Slack {
+                    channel: content.parse::<LitStr>()?.value(),
+                    if_: None,
+                }),
+                "email" => Some(NotifyValue::Email {
+                    email: content.parse::<LitStr>()?.value(),
+                    if_: None,
+                }),
+                "webhook" => Some(NotifyValue::Webhook {
+                    url: content.parse::<LitStr>()?.value(),
+                    if_: None,
+                }),
+                "pagerduty_change_event" => Some(NotifyValue::Pagerduty {
+                    service: content.parse::<LitStr>()?.value(),
+                    if_: None,
+                }),
+                "github_commit_status" => {
+                    let context = Self::parse_github_fields(
+                        &content,
+                        "github_commit_status",
+                        fields::GITHUB_COMMIT_STATUS_FIELDS,
+                    )?
+                    .pop()
+                    .map(|(_, context)| context);
+                    Some(NotifyValue::GithubCommitStatus { context, if_: None })
+                }
+                "github_check" => {
+                    Self::parse_github_fields(&content, "github_check", &[])?;
+                    Some(NotifyValue::GithubCheck)
+                }
+                "basecamp_campfire" => Some(NotifyValue::Basecamp {
+                    url: content.parse::<LitStr>()?.value(),
+                    if_: None,
+                }),
+                _ => {
+                    return Err(Error::new(
+                        key.span(),
+                        fields::unknown(
+                            "notify field",
+                            &name,
+                            fields::NOTIFY_FIELDS.iter().copied(),
+                        ),
+                    ));
+                }
+            };
+            if let Some(parsed) = parsed {
+                if let Some((first, _)) = &value {
+                    return Err(Error::new(
+                        key.span(),
+                        format!(
+                            "a notify entry has one type, but this one has both {} and {}; \
+                             use a separate entry for each",
+                            strip_raw_ident(&first.to_string()),
+                            name
+                        ),
+                    ));
+                }
+                value = Some((key, parsed));
+            }
+
+            if !content.is_empty() {
+                content.parse::<Token![,]>()?;
+            }
+        }
+
+        let Some((_, mut value)) = value else {
+            return Err(Error::new(
+                brace.span.join(),
+                "notify entry has no type; expected one of slack, email, webhook, \
+                 pagerduty_change_event, github_commit_status, github_check or basecamp_campfire",
+            ));
+        };
+        if let Some((key, c)) = condition {
+            match &mut value {
+                NotifyValue::Slack { if_, .. }
+                | NotifyValue::Email { if_, .. }
+                | NotifyValue::Webhook { if_, .. }
+                | NotifyValue::Pagerduty { if_, .. }
+                | NotifyValue::GithubCommitStatus { if_, .. }
+                | NotifyValue::Basecamp { if_, .. } => *if_ = Some(c),
+                NotifyValue::GithubCheck => {
+                    return Err(Error::new(
+                        key.span(),
+                        "github_check notifications don't take an if",
+                    ));
+                }
+            }
+        }
+        Ok(value)
+    }
+
+    /// Parse the object given to `kind`, github_commit_status or github_check,
+    /// whose fields are `known` and take strings.
+    fn parse_github_fields(
+        input: ParseStream,
+        kind: &str,
+        known: &[&str],
+    ) -> Result<Vec<(String, String)>> {
+        if !input.peek(syn::token::Brace) {
+            let example = known
+                .iter()
+                .map(|field| format!(" {}: \"...\" ", field))
+                .collect::<String>();
+            return Err(Error::new(
+                input.span(),
+                format!("{} expects an object, e.g. {}: {{{}}}", kind, kind, example),
+            ));
+        }
+        let content;
+        braced!(content in input);
+        let mut values = Vec::new();
+        while !content.is_empty() {
+            let key: Ident = content.parse()?;
+            let name = key.to_string();
+            if !known.contains(&name.as_str()) {
+                return Err(Error::new(
+                    key.span(),
+                    fields::unknown(&format!("{} field", kind), &name, known.iter().copied()),
+                ));
+            }
+            content.parse::<Token![:]>()?;
+            values.push((name, content.parse::<LitStr>()?.value()));
+            if !content.is_empty() {
+                content.parse::<Token![,]>()?;
+            }
+        }
+        Ok(values)
+    }
+
+    /// Parse a group step notification. Group steps only notify through
+    /// Basecamp, GitHub and Slack; the other kinds are build-level only.
+    fn parse_for_group(input: ParseStream) -> Result<Self> {
+        let span = input.span();
+        let value = Self::parse(input)?;
+        match value {
+            NotifyValue::Slack { .. }
+            | NotifyValue::GithubCommitStatus { .. }
+            | NotifyValue::GithubCheck
+            | NotifyValue::Basecamp { .. } => Ok(value),
+            NotifyValue::Email { .. }
+            | NotifyValue::Webhook { .. }
+            | NotifyValue::Pagerduty { .. } => Err(Error::new(
+                span,
+                format!(
+                    "{} notifications are not supported on group steps. Group steps accept \
+                         basecamp_campfire, github_check, github_commit_status and slack; \
+                         move this to the pipeline-level notify",
+                    value.kind()
+                ),
+            )),
+        }
+    }
+
+    /// The key naming this notification kind.
+    fn kind(&self) -> &'static str {
+        match self {
+            NotifyValue::Slack { .. } => "slack",
+            NotifyValue::Email { .. } => "email",
+            NotifyValue::Webhook { .. } => "webhook",
+            NotifyValue::Pagerduty { .. } => "pagerduty_change_event",
+            NotifyValue::GithubCommitStatus { .. } => "github_commit_status",
+            NotifyValue::GithubCheck => "github_check",
+            NotifyValue::Basecamp { .. } => "basecamp_campfire",
+        }
+    }
+
+    /// The entry `to_tokens` builds, as `emit_yaml` writes it.
+    fn to_value(&self) -> yaml::Value {
+        let (kind, value, if_) = match self {
+            NotifyValue::Slack { channel, if_ } => ("slack", yaml::string(channel), if_),
+            NotifyValue::Email { email, if_ } => ("email", yaml::string(email), if_),
+            NotifyValue::Webhook { url, if_ } => ("webhook", yaml::string(url), if_),
+            NotifyValue::Pagerduty { service, if_ } => {
+                ("pagerduty_change_event", yaml::string(service), if_)
+            }
+            NotifyValue::GithubCommitStatus { context, if_ } => {
+                let status = context
+                    .iter()
+                    .map(|c| (yaml::string("context"), yaml::string(c)))
+                    .collect();
+                ("github_commit_status", yaml::Value::Mapping(status), if_)
+            }
+            NotifyValue::GithubCheck => (
+                "github_check",
+                yaml::Value::Mapping(Default::default()),
+                &None,
+            ),
+            NotifyValue::Basecamp { url, if_ } => ("basecamp_campfire", yaml::string(url), if_),
+        };
+        let mut entry = serde_yaml::Mapping::new();
+        entry.insert(yaml::string(kind), value);
+        if let Some(condition) = if_ {
+            entry.insert(yaml::string("if"), yaml::string(condition));
+        }
+        yaml::Value::Mapping(entry)
+    }
+
+    fn to_tokens(&self) -> TokenStream2 {
+        match self {
+            NotifyValue::Slack { channel, if_ } => {
+                let if_tokens = match if_ {
+                    Some(c) => quote! { Some(::rust_buildkite::If(#c.to_string())) },
+                    None => quote! { None },
+                };
+                quote! {
+                    ::rust_buildkite::BuildNotifyItem::Slack(::rust_buildkite::NotifySlack {
+                        slack: Some(::rust_buildkite::NotifySlackSlack::String(#channel.to_string())),
+                        if_: #if_tokens,
+                    })
+                }
+            }
+            NotifyValue::Email { email, if_ } => {
+                let if_tokens = match if_ {
+                    Some(c) => quote! { Some(::rust_buildkite::If(#c.to_string())) },
+                    None => quote! { None },
+                };
+                quote! {
+                    ::rust_buildkite::BuildNotifyItem::Email(::rust_buildkite::NotifyEmail {
+                        email: Some(#email.to_string()),
+                        if_: #if_tokens,
+                    })
+                }
+            }
+            NotifyValue::Webhook { url, if_ } => {
+                let if_tokens = match if_ {
+                    Some(c) => quote! { Some(::rust_buildkite::If(#c.to_string())) },
+                    None => quote! { None },
+                };
+                quote! {
+                    ::rust_buildkite::BuildNotifyItem::Webhook(::rust_buildkite::NotifyWebhook {
+                        webhook: Some(#url.to_string()),
+                        if_: #if_tokens,
+                    })
+                }
+            }
+            NotifyValue::Pagerduty { service, if_ } => {
+                let if_tokens = match if_ {
+                    Some(c) => quote! { Some(::rust_buildkite::If(#c.to_string())) },
+                    None => quote! { None },
+                };
+                quote! {
+                    ::rust_buildkite::BuildNotifyItem::Pagerduty(::rust_buildkite::NotifyPagerduty {
+                        pagerduty_change_event: Some(#service.to_string()),
+                        if_: #if_tokens,
+                    })
+                }
+            }
+            NotifyValue::GithubCommitStatus { context, if_ } => {
+                let if_tokens = match if_ {
+                    Some(c) => quote! { Some(::rust_buildkite::If(#c.to_string())) },
+                    None => quote! { None },
+                };
+                let context_tokens = match context {
+                    Some(c) => {
+                        quote! { Some(::rust_buildkite::NotifyGithubCommitStatusGithubCommitStatus { context: Some(#c.to_string()) }) }
+                    }
+                    None => quote! { None },
+                };
+                quote! {
+                    ::rust_buildkite::BuildNotifyItem::GithubCommitStatus(::rust_buildkite::NotifyGithubCommitStatus {
+                        github_commit_status: #context_tokens,
+                        if_: #if_tokens,
+                    })
+                }
+            }
+            NotifyValue::GithubCheck => {
+                quote! {
+                    ::rust_buildkite::BuildNotifyItem::GithubCheck(::rust_buildkite::NotifyGithubCheck {
+                        github_check: ::rust_buildkite::serde_json::Map::new(),
+                    })
+                }
+            }
+            NotifyValue::Basecamp { url, if_ } => {
+                let if_tokens = match if_ {
+                    Some(c) => quote! { Some(::rust_buildkite::If(#c.to_string())) },
+                    None => quote! { None },
+                };
+                quote! {
+                    ::rust_buildkite::BuildNotifyItem::Basecamp(::rust_buildkite::NotifyBasecamp {
+                        basecamp_campfire: Some(#url.to_string()),
+                        if_: #if_tokens,
+                    })
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+enum SecretsValue {
+    Array(Vec<String>),
+    Object(Vec<(String, String)>),
+}
+
+struct PipelineDef {
+    allowed_commands: Option<Vec<(String, proc_macro2::Span)>>,
+    additional_commands: Vec<String>,
+    expect_paths: Vec<String>,
+    expect_env: Option<Vec<ExpectEnvItem>>,
+    /// `expect_env` names written as literals, for the lowercase_env_name lint
+    expect_env_names: Vec<LitStr>,
+    /// Meta-data keys set outside this pipeline, which commands may read
+    /// without a block or input field defining them
+    external_meta_data: Vec<LitStr>,
+    /// Directory relative command paths are checked against
+    path_base: Option<std::path::PathBuf>,
+    /// Whether steps may use `unchecked_cmd!`
+    allow_unchecked_commands: bool,
+    /// Shell commands are validated for when a step doesn't set `shell`
+    shell: StepShell,
+    /// Span of the pipeline's first field, where settings it lacks are reported
+    span: proc_macro2::Span,
+    /// Custom Bazel verbs to allow (enables bazel_<verb> shorthand macros)
+    #[cfg(feature = "bazel")]
+    #[allow(dead_code)]
+    custom_verbs: Vec<String>,
+    /// How failures to reach bazel or the workspace are reported
+    #[cfg(feature = "bazel")]
+    bazel_validation: BazelValidation,
+    env: Option<Vec<(EnvKey, DynamicValue)>>,
+    steps: Vec<StepDef>,
+    agents: Vec<(String, String)>,
+    /// Whether `agents` was written in the `["key=value"]` list form
+    agents_list: bool,
+    notify: Vec<NotifyValue>,
+    image: Option<String>,
+    secrets: Option<SecretsValue>,
+    priority: Option<i64>,
+    default_plugins: Vec<NestedValue>,
+    /// Local `default_plugins`, with the span of the name
+    default_local_plugins: Vec<(String, proc_macro2::Span)>,
+    /// Write the static pipeline as YAML to this path during expansion
+    emit_yaml: Option<LitStr>,
+    /// Set by `lazy: true`: build the pipeline once and hand out a
+    /// `&'static` reference to it
+    lazy: Option<proc_macro2::Span>,
+    lints: lints::LintConfig,
+    concurrency_group_policy: ConcurrencyGroupPolicy,
+    /// How Buildkite triggers the pipeline, from `pipeline_context:`
+    pipeline_context: Option<context::PipelineContext>,
+}
+
+/// Naming rules every concurrency_group in the pipeline must follow
+#[derive(Default)]
+struct ConcurrencyGroupPolicy {
+    /// Variable the group name must interpolate, e.g. BUILDKITE_PIPELINE_SLUG
+    require_var: Option<LitStr>,
+    /// Literal prefix the group name must start with
+    require_prefix: Option<LitStr>,
+}
+
+impl ConcurrencyGroupPolicy {
+    /// Parse concurrency_group_policy: { require_var: "...", require_prefix: "..." }
+    fn parse(input: ParseStream) -> Result<Self> {
+        let content;
+        braced!(content in input);
+        let mut policy = ConcurrencyGroupPolicy::default();
+
+        while !content.is_empty() {
+            let field: Ident = content.parse()?;
+            content.parse::<Token![:]>()?;
+
+            match field.to_string().as_str() {
+                "require_var" => policy.require_var = Some(content.parse()?),
+                "require_prefix" => policy.require_prefix = Some(content.parse()?),
+                other => {
+                    return Err(Error::new(
+                        field.span(),
+                        format!(
+                            "unknown concurrency_group_policy field: {}. Expected: require_var, require_prefix",
+                            other
+                        ),
+                    ));
+                }
+            }
+
+            if content.peek(Token![,]) {
+                content.parse::<Token![,]>()?;
+            }
+        }
+
+        Ok(policy)
+    }
+
+    fn check(&self, group: &LitStr) -> std::result::Result<(), String> {
+        let value = group.value();
+        if let Some(var) = &self.require_var {
+            let var = var.value();
+            if !interpolated_vars(&value).contains(&var) {
+                return Err(format!(
+                    "concurrency_group '{}' must reference ${} to avoid lock collisions across pipelines, e.g. \"${}/{}\"",
+                    value, var, var, value
+                ));
+            }
+        }
+        if let Some(prefix) = &self.require_prefix {
+            let prefix = prefix.value();
+            if !value.starts_with(&prefix) {
+                return Err(format!(
+                    "concurrency_group '{}' must start with '{}'",
+                    value, prefix
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Parse for PipelineDef {
+    fn parse(input: ParseStream) -> Result<Self> {
+        #[cfg(feature = "bazel")]
+        let _bazel_defaults = bazel_defaults::scope();
+        let mut allowed_commands = None;
+        let mut additional_commands = Vec::new();
+        let mut expect_paths = Vec::new();
+        let mut external_meta_data = Vec::new();
+        let mut expect_env = None;
+        let mut expect_env_names = Vec::new();
+        let mut path_base = None;
+        let mut allow_unchecked_commands = false;
+        let mut shell = StepShell::default();
+        let span = input.span();
+        let config = config::load().map_err(|e| Error::new(input.span(), e))?;
+        #[cfg(feature = "bazel")]
+        let mut custom_verbs = config.bazel.custom_verbs.clone();
+        #[cfg(feature = "bazel")]
+        let mut bazel_validation = BazelValidation::default();
+        let mut env = None;
+        let mut profiles: Vec<Profile> = Vec::new();
+        let mut profile: Option<(String, proc_macro2::Span)> = None;
+        let mut steps = Vec::new();
+        let mut agents = Vec::new();
+        let mut agents_list = false;
+        let mut agents_span = None;
+        let mut queue_span = None;
+        let mut notify = Vec::new();
+        let mut image = None;
+        let mut secrets = None;
+        let mut priority = None;
+        let mut default_plugins = Vec::new();
+        let mut default_local_plugins = Vec::new();
+        let mut emit_yaml = None;
+        let mut lazy = None;
+        let mut lints = config.lints().map_err(|e| Error::new(input.span(), e))?;
+        let mut concurrency_group_policy = ConcurrencyGroupPolicy::default();
+        let mut pipeline_context = None;
+        let mut key_prefix: Option<LitStr> = None;
+        let mut auto_keys = false;
+        let mut description_env = false;
+        let mut plugin_versions: Vec<(LitStr, LitStr)> = Vec::new();
+        #[cfg(feature = "bazel")]
+        let mut bazel_bep: Option<BazelBep> = None;
+
+        while !input.is_empty() {
+            let key: Ident = input.parse()?;
+            input.parse::<Token![:]>()?;
+
+            match key.to_string().as_str() {
+                "expect_paths" => {
+                    let content;
+                    bracketed!(content in input);
+                    while !content.is_empty() {
+                        let lit: LitStr = content.parse()?;
+                        expect_paths.push(lit.value());
+                        if content.peek(Token![,]) {
+                            content.parse::<Token![,]>()?;
+                        }
+                    }
+                }
+                "external_meta_data" => {
+                    let content;
+                    bracketed!(content in input);
+                    while !content.is_empty() {
+                        external_meta_data.push(content.parse::<LitStr>()?);
+                        if content.peek(Token![,]) {
+                            content.parse::<Token![,]>()?;
+                        }
+                    }
+                }
+                "expect_env" => {
+                    let content;
+                    bracketed!(content in input);
+                    let mut vars = Vec::new();
+                    while !content.is_empty() {
+                        if content.peek(LitStr) {
+                            let lit: LitStr = content.parse()?;
+                            check_env_name(&lit.value())
+                                .map_err(|msg| Error::new(lit.span(), msg))?;
+                            vars.push(ExpectEnvItem::Literal(lit.value()));
+                            expect_env_names.push(lit);
+                        } else if shell::peek(&content) {
+                            let span = content.span();
+                            for name in shell::parse_invocation(&content)?.run_lines()? {
+                                check_env_name(&name).map_err(|msg| Error::new(span, msg))?;
+                                expect_env_names.push(LitStr::new(&name, span));
+                                vars.push(ExpectEnvItem::Literal(name));
+                            }
+                        } else {
+                            let path: syn::Path = content.parse()?;
+                            let ident_str = path.get_ident().map(|i| i.to_string());
+
+                            if let Some(ref name) = ident_str {
                                 if let Some(known_vars) = expand_known_env_list(name) {
                                     for var in known_vars {
                                         vars.push(ExpectEnvItem::Literal(var.to_string()));
@@ -705,6 +2144,104 @@ impl Parse for PipelineDef {
                         }
                     }
                 }
+                #[cfg(not(feature = "bazel"))]
+                "custom_verbs" => {
+                    return Err(bazel_feature_required(key.span(), "custom_verbs requires"));
+                }
+                #[cfg(feature = "bazel")]
+                "bazel_validation" => {
+                    bazel_validation = BazelValidation::parse(input)?;
+                }
+                #[cfg(not(feature = "bazel"))]
+                "bazel_validation" => {
+                    return Err(bazel_feature_required(
+                        key.span(),
+                        "bazel_validation requires",
+                    ));
+                }
+                #[cfg(feature = "bazel")]
+                "bazel_defaults" => {
+                    if !steps.is_empty() {
+                        return Err(Error::new(
+                            key.span(),
+                            "bazel_defaults must come before steps",
+                        ));
+                    }
+                    bazel_defaults::set(BazelExpr::parse_defaults(input, &custom_verbs)?);
+                }
+                #[cfg(feature = "bazel")]
+                "bazel_binary" => {
+                    let binary: LitStr = input.parse()?;
+                    let value = binary.value();
+                    if value.is_empty() || value.contains(char::is_whitespace) {
+                        return Err(Error::new(
+                            binary.span(),
+                            "bazel_binary must be a single program name or path, e.g. \"bazelisk\" or \"./tools/bazel\"",
+                        ));
+                    }
+                    if !steps.is_empty() {
+                        return Err(Error::new(
+                            key.span(),
+                            "bazel_binary must come before steps",
+                        ));
+                    }
+                    bazel_defaults::set_binary(value);
+                }
+                #[cfg(feature = "bazel")]
+                "bazel_bep" => {
+                    bazel_bep = Some(BazelBep::parse(input)?);
+                }
+                #[cfg(feature = "bazel")]
+                "bazel_exclusive_configs" => {
+                    if !steps.is_empty() {
+                        return Err(Error::new(
+                            key.span(),
+                            "bazel_exclusive_configs must come before steps",
+                        ));
+                    }
+                    bazel_defaults::set_exclusive_configs(parse_exclusive_configs(input)?);
+                }
+                #[cfg(not(feature = "bazel"))]
+                "bazel_exclusive_configs" => {
+                    return Err(bazel_feature_required(
+                        key.span(),
+                        "bazel_exclusive_configs requires",
+                    ));
+                }
+                #[cfg(feature = "bazel")]
+                "bazel_workspace" => {
+                    let workspace: LitStr = input.parse()?;
+                    let value = parse_workspace_dir(&workspace, "bazel_workspace")?;
+                    if !steps.is_empty() {
+                        return Err(Error::new(
+                            key.span(),
+                            "bazel_workspace must come before steps",
+                        ));
+                    }
+                    bazel_defaults::set_workspace(value);
+                }
+                #[cfg(not(feature = "bazel"))]
+                "bazel_workspace" => {
+                    return Err(bazel_feature_required(
+                        key.span(),
+                        "bazel_workspace requires",
+                    ));
+                }
+                #[cfg(not(feature = "bazel"))]
+                "bazel_bep" => {
+                    return Err(bazel_feature_required(key.span(), "bazel_bep requires"));
+                }
+                #[cfg(not(feature = "bazel"))]
+                "bazel_binary" => {
+                    return Err(bazel_feature_required(key.span(), "bazel_binary requires"));
+                }
+                #[cfg(not(feature = "bazel"))]
+                "bazel_defaults" => {
+                    return Err(bazel_feature_required(
+                        key.span(),
+                        "bazel_defaults requires",
+                    ));
+                }
                 "allowed_commands" => {
                     let content;
                     bracketed!(content in input);
@@ -792,14 +2329,23 @@ impl Parse for PipelineDef {
                         }
                     }
                 }
+                "allow_unchecked_commands" => {
+                    let val: syn::LitBool = input.parse()?;
+                    allow_unchecked_commands = val.value();
+                }
+                "shell" => shell = StepShell::parse(input)?,
+                "path_base" => {
+                    let lit: LitStr = input.parse()?;
+                    path_base = Some(shell::script_dir().join(lit.value()));
+                }
                 "env" => {
                     let content;
                     braced!(content in input);
                     let mut env_vars = Vec::new();
                     while !content.is_empty() {
-                        let var_name: Ident = content.parse()?;
+                        let var_name = EnvKey::parse(&content)?;
                         content.parse::<Token![:]>()?;
-                        let var_value: LitStr = content.parse()?;
+                        let var_value = DynamicValue::parse(&content)?;
                         env_vars.push((var_name, var_value));
                         if content.peek(Token![,]) {
                             content.parse::<Token![,]>()?;
@@ -807,38 +2353,37 @@ impl Parse for PipelineDef {
                     }
                     env = Some(env_vars);
                 }
+                "profiles" => {
+                    profiles = Profile::parse_list(input)?;
+                }
+                "profile" => {
+                    profile = Some(parse_profile_selector(input)?);
+                }
                 "steps" => {
                     let content;
                     bracketed!(content in input);
                     #[cfg(feature = "bazel")]
                     {
-                        while !content.is_empty() {
-                            let step = StepDef::parse_with_custom_verbs(&content, &custom_verbs)?;
-                            steps.push(step);
-                            if content.peek(Token![,]) {
-                                content.parse::<Token![,]>()?;
-                            }
-                        }
+                        steps = StepDef::parse_list(&content, &custom_verbs)?;
                     }
                     #[cfg(not(feature = "bazel"))]
                     {
-                        let step_list: Punctuated<StepDef, Token![,]> =
-                            Punctuated::parse_terminated(&content)?;
-                        steps = step_list.into_iter().collect();
+                        steps = StepDef::parse_list(&content, &[])?;
                     }
                 }
                 "agents" => {
-                    let content;
-                    braced!(content in input);
-                    while !content.is_empty() {
-                        let k: Ident = content.parse()?;
-                        content.parse::<Token![:]>()?;
-                        let v: LitStr = content.parse()?;
-                        agents.push((k.to_string(), v.value()));
-                        if content.peek(Token![,]) {
-                            content.parse::<Token![,]>()?;
-                        }
+                    if queue_span.is_some() {
+                        return Err(queue_conflict(key.span()));
                     }
+                    let (tags, list) = parse_agents(input)?;
+                    agents.extend(tags.into_iter().map(|(k, v)| (k, v.value())));
+                    agents_list = list;
+                    agents_span = Some(key.span());
+                }
+                "queue" => {
+                    let (k, v) = parse_queue(input, key.span(), agents_span)?;
+                    agents.push((k, v.value()));
+                    queue_span = Some(key.span());
                 }
                 "notify" => {
                     let content;
@@ -891,17 +2436,52 @@ impl Parse for PipelineDef {
                     let content;
                     bracketed!(content in input);
                     while !content.is_empty() {
+                        let span = plugins::key_span(&content);
                         let plugin = NestedValue::parse(&content)?;
+                        if let Some(path) = plugins::local_path(&plugin) {
+                            default_local_plugins.push((path.to_string(), span));
+                        }
                         default_plugins.push(plugin);
                         if content.peek(Token![,]) {
                             content.parse::<Token![,]>()?;
                         }
                     }
                 }
+                "emit_yaml" => {
+                    let lit: LitStr = input.parse()?;
+                    emit_yaml = Some(lit);
+                }
+                "lazy" => {
+                    let val: syn::LitBool = input.parse()?;
+                    lazy = val.value().then(|| val.span());
+                }
+                "key_prefix" => {
+                    key_prefix = Some(input.parse()?);
+                }
+                "concurrency_group_policy" => {
+                    concurrency_group_policy = ConcurrencyGroupPolicy::parse(input)?;
+                }
+                "pipeline_context" => {
+                    pipeline_context = Some(context::PipelineContext::parse(input)?);
+                }
+                "lints" => {
+                    lints.parse_into(input)?;
+                }
+                "auto_keys" => {
+                    let val: syn::LitBool = input.parse()?;
+                    auto_keys = val.value();
+                }
+                "description_env" => {
+                    let val: syn::LitBool = input.parse()?;
+                    description_env = val.value();
+                }
+                "plugin_versions" => {
+                    plugin_versions = parse_plugin_versions(input)?;
+                }
                 other => {
                     return Err(Error::new(
                         key.span(),
-                        format!("unknown pipeline field: {}", other),
+                        fields::unknown("pipeline field", other, fields::PIPELINE.iter().copied()),
                     ));
                 }
             }
@@ -911,96 +2491,210 @@ impl Parse for PipelineDef {
             }
         }
 
-        Ok(PipelineDef {
+        apply_profile(&profiles, profile.as_ref(), &mut env, &mut steps)?;
+        if auto_keys {
+            derive_auto_keys(&mut steps)?;
+        }
+        if description_env {
+            enable_description_env(&mut steps);
+        }
+        if let Some((_, version)) = plugin_versions.first() {
+            pin_helper_plugins(&mut steps, &version.value());
+        }
+        #[cfg(feature = "bazel")]
+        if let Some(bep) = &bazel_bep {
+            bep.apply(&mut steps);
+        }
+        if let Some(prefix) = &key_prefix {
+            apply_key_prefix(&mut steps, &prefix.value())?;
+        }
+
+        let mut pipeline = PipelineDef {
             allowed_commands,
             additional_commands,
             expect_paths,
             expect_env,
+            expect_env_names,
+            external_meta_data,
+            path_base,
+            allow_unchecked_commands,
+            shell,
+            span,
             #[cfg(feature = "bazel")]
             custom_verbs,
+            #[cfg(feature = "bazel")]
+            bazel_validation,
             env,
             steps,
             agents,
+            agents_list,
             notify,
             image,
             secrets,
             priority,
             default_plugins,
-        })
+            default_local_plugins,
+            emit_yaml,
+            lazy,
+            lints,
+            concurrency_group_policy,
+            pipeline_context,
+        };
+        config.apply(&mut pipeline);
+        Ok(pipeline)
     }
 }
 
 impl PipelineDef {
-    fn generate(&self) -> Result<TokenStream2> {
+    /// Lint the shell commands of every step with bashrs. Parsing only
+    /// collects them, so they can be linted in parallel.
+    fn lint_commands(&mut self) -> Result<()> {
+        let mut commands = Vec::new();
+        for step in &mut self.steps {
+            step.collect_shell_commands_mut(self.shell, &mut commands);
+        }
+        lint_commands(&mut commands)
+    }
+
+    fn generate(&self, timings: &mut timing::Timings) -> Result<TokenStream2> {
+        if timings.is_enabled() {
+            let mut lint_times = Vec::new();
+            for step in &self.steps {
+                step.collect_lint_times(&mut lint_times);
+            }
+            timings.record_commands(lint_times);
+        }
+
+        let mut errors: Vec<Error> = Vec::new();
         let mut keys: HashSet<String> = HashSet::new();
         let mut key_spans: Vec<(String, proc_macro2::Span)> = Vec::new();
 
+        let started = std::time::Instant::now();
+        let mut defined = Vec::new();
         for step in &self.steps {
-            if let Some((key, span)) = step.get_key() {
-                if keys.contains(&key) {
-                    return Err(Error::new(span, format!("duplicate step key: '{}'", key)));
-                }
-                keys.insert(key.clone());
-                key_spans.push((key, span));
+            step.collect_keys(&mut defined);
+        }
+        for (key, span) in defined {
+            if let Err(msg) = validate_step_key(&key) {
+                self.lints
+                    .report(&mut errors, &codes::INVALID_STEP_KEY, span, msg);
+            }
+            if let Some((_, first)) = key_spans.iter().find(|(k, _)| *k == key) {
+                let mut duplicate = Vec::new();
+                self.lints.report(
+                    &mut duplicate,
+                    &codes::DUPLICATE_STEP_KEY,
+                    *first,
+                    format!("duplicate step key: '{}', first defined here", key),
+                );
+                self.lints.report(
+                    &mut duplicate,
+                    &codes::DUPLICATE_STEP_KEY,
+                    span,
+                    format!("duplicate step key: '{}', duplicated here", key),
+                );
+                errors.extend(combine_errors(duplicate));
+                continue;
             }
+            keys.insert(key.clone());
+            key_spans.push((key, span));
         }
+        let mut dependencies = Vec::new();
         for step in &self.steps {
-            for (dep, span) in step.get_depends_on() {
-                if !keys.contains(&dep) {
-                    let available: Vec<_> = keys.iter().collect();
-                    return Err(Error::new(
-                        span,
-                        format!(
-                            "unknown step key '{}' in depends_on. Available keys: {:?}",
-                            dep, available
-                        ),
-                    ));
-                }
+            step.collect_depends_on(&mut dependencies);
+        }
+        for (dep, span) in dependencies {
+            if !keys.contains(&dep) {
+                let mut available: Vec<_> = keys.iter().collect();
+                available.sort();
+                self.lints.report(
+                    &mut errors,
+                    &codes::UNKNOWN_DEPENDENCY,
+                    span,
+                    format!(
+                        "unknown step key '{}' in depends_on. Available keys: {:?}",
+                        dep, available
+                    ),
+                );
             }
         }
+        timings.record("step keys", started.elapsed());
+        timings.time("conditions", || {
+            self.validate_conditions(&self.steps, &mut errors)
+        });
         let allow_missing: Vec<&str> = self.expect_paths.iter().map(|s| s.as_str()).collect();
-        self.validate_paths(&self.steps, &allow_missing)?;
+        timings.time("path checks", || {
+            if !should_skip_comptime_validation() {
+                self.validate_plugin_paths(
+                    &self.default_local_plugins,
+                    &allow_missing,
+                    &mut errors,
+                );
+            }
+            self.validate_paths(&self.steps, &allow_missing, &mut errors)
+        });
         let mut allowed_names: HashSet<String> = if let Some(allowed) = &self.allowed_commands {
             allowed.iter().map(|(s, _)| s.clone()).collect()
         } else {
-            discover_host_path_commands()
+            timings.time("PATH discovery", discover_host_path_commands)
         };
         for cmd in &self.additional_commands {
             allowed_names.insert(cmd.clone());
         }
+        // Builtins are part of the shell, so an explicit allowlist doesn't list
+        // them; bash-only builtins are added per step by validate_commands
+        allowed_names.extend(POSIX_BUILTINS.iter().map(|b| b.to_string()));
         let allowed_refs: HashSet<&str> = allowed_names.iter().map(|s| s.as_str()).collect();
-        self.validate_commands(&self.steps, &allowed_refs)?;
-        self.validate_env_vars(&self.steps)?;
+        ir::dump_if_requested(self, &allowed_refs);
+        timings.time("command allowlist", || {
+            self.validate_unchecked_commands(&mut errors);
+            self.validate_runtime_commands(&mut errors);
+            self.validate_commands(&self.steps, &allowed_refs, &mut errors);
+        });
+        #[cfg(feature = "bazel")]
+        timings.time("bazel validation", || {
+            self.validate_bazel_commands(&mut errors)
+        });
+        timings.time("env checks", || {
+            self.validate_env_vars(&self.steps, &mut errors)
+        });
+        timings.time("lints", || {
+            self.validate_concurrency_groups(&self.steps, &mut errors);
+            self.validate_artifact_needs(&mut errors);
+            self.validate_lazy(&mut errors);
+            lints::check(self, &mut errors);
+        });
+        if let Some(err) = combine_errors(errors) {
+            return Err(err);
+        }
 
-        let step_tokens: Vec<TokenStream2> = self
-            .steps
-            .iter()
-            .map(|s| s.to_tokens_with_default_plugins(&self.default_plugins))
-            .collect();
-        let env_tokens = if let Some(env_vars) = &self.env {
-            let env_inserts: Vec<TokenStream2> = env_vars
-                .iter()
-                .map(|(k, v)| {
-                    let key_str = k.to_string();
-                    quote! {
-                        __env_map.insert(
-                            #key_str.to_string(),
-                            ::rust_buildkite::serde_json::Value::String(#v.to_string())
-                        );
-                    }
-                })
-                .collect();
+        let expanded = timings.time("token generation", || self.expand());
+        if let Some(target) = &self.emit_yaml {
+            let value = expanded.value?;
+            timings.time("emit_yaml", || yaml::emit(&value, target))?;
+        }
+        Ok(expanded.tokens)
+    }
 
-            quote! {
-                {
-                    let mut __env_map = ::rust_buildkite::serde_json::Map::new();
-                    #(#env_inserts)*
-                    Some(::rust_buildkite::Env(__env_map))
-                }
-            }
-        } else {
-            quote! { None }
-        };
+    /// The expression that builds the validated pipeline, and the value
+    /// `emit_yaml` writes for it.
+    fn expand(&self) -> yaml::Expanded {
+        let mut fields = yaml::Fields::default();
+        if let Some(env_vars) = &self.env {
+            let pairs = env_vars
+                .iter()
+                .map(|(k, v)| string_pair_tokens(&k.to_string(), v));
+            let value = yaml::mapping(
+                env_vars
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), yaml::dynamic(v, "a pipeline env value"))),
+            );
+            fields.set(
+                "env",
+                value,
+                quote! { .env(Some(::rust_buildkite::helpers::env_map(&[#(#pairs),*]))) },
+            );
+        }
 
         // nb: code to "use" any const refs to suppress unused import warnings
         let const_ref_uses: Vec<TokenStream2> = if let Some(expect_env) = &self.expect_env {
@@ -1018,40 +2712,39 @@ impl PipelineDef {
             Vec::new()
         };
 
-        let agents_tokens = if !self.agents.is_empty() {
-            let inserts: Vec<TokenStream2> = self.agents.iter().map(|(k, v)| {
-                quote! { __agents_map.insert(#k.to_string(), ::rust_buildkite::serde_json::Value::String(#v.to_string())); }
-            }).collect();
-            quote! {
-                .agents({
-                    let mut __agents_map = ::rust_buildkite::serde_json::Map::new();
-                    #(#inserts)*
-                    Some(::rust_buildkite::Agents::Object(::rust_buildkite::AgentsObject(__agents_map)))
-                })
-            }
-        } else {
-            quote! {}
-        };
+        set_agents(
+            &mut fields,
+            self.agents.iter().map(|(k, v)| (k.as_str(), v.as_str())),
+            self.agents_list,
+        );
 
-        let notify_tokens = if !self.notify.is_empty() {
+        if !self.notify.is_empty() {
             let items: Vec<TokenStream2> = self.notify.iter().map(|n| n.to_tokens()).collect();
-            quote! {
-                .notify(Some(::rust_buildkite::BuildNotify(vec![#(#items),*])))
-            }
-        } else {
-            quote! {}
-        };
+            let values = self.notify.iter().map(NotifyValue::to_value).collect();
+            fields.set(
+                "notify",
+                Ok(yaml::Value::Sequence(values)),
+                quote! { .notify(Some(::rust_buildkite::BuildNotify(vec![#(#items),*]))) },
+            );
+        }
 
-        let image_tokens = match &self.image {
-            Some(i) => quote! { .image(Some(::rust_buildkite::Image(#i.to_string()))) },
-            None => quote! {},
-        };
+        if let Some(i) = &self.image {
+            fields.set(
+                "image",
+                Ok(yaml::string(i)),
+                quote! { .image(Some(::rust_buildkite::Image(#i.to_string()))) },
+            );
+        }
 
-        let secrets_tokens = match &self.secrets {
+        match &self.secrets {
             Some(SecretsValue::Array(items)) => {
-                quote! {
-                    .secrets(Some(::rust_buildkite::Secrets::Array(vec![#(#items.to_string()),*])))
-                }
+                fields.set(
+                    "secrets",
+                    Ok(yaml::strings(items.iter().map(String::as_str))),
+                    quote! {
+                        .secrets(Some(::rust_buildkite::Secrets::Array(vec![#(#items.to_string()),*])))
+                    },
+                );
             }
             Some(SecretsValue::Object(pairs)) => {
                 let inserts: Vec<TokenStream2> = pairs
@@ -1060,21 +2753,42 @@ impl PipelineDef {
                         quote! { __secrets_map.insert(#k.to_string(), #v.to_string()); }
                     })
                     .collect();
-                quote! {
-                    .secrets({
-                        let mut __secrets_map = ::std::collections::HashMap::new();
-                        #(#inserts)*
-                        Some(::rust_buildkite::Secrets::Object(__secrets_map))
-                    })
-                }
+                let value =
+                    yaml::mapping(pairs.iter().map(|(k, v)| (k.clone(), Ok(yaml::string(v)))));
+                fields.set(
+                    "secrets",
+                    value,
+                    quote! {
+                        .secrets({
+                            let mut __secrets_map = ::std::collections::HashMap::new();
+                            #(#inserts)*
+                            Some(::rust_buildkite::Secrets::Object(__secrets_map))
+                        })
+                    },
+                );
             }
-            None => quote! {},
-        };
+            None => {}
+        }
 
-        let priority_tokens = match &self.priority {
-            Some(p) => quote! { .priority(Some(::rust_buildkite::Priority(#p))) },
-            None => quote! {},
-        };
+        if let Some(p) = &self.priority {
+            fields.set(
+                "priority",
+                Ok(yaml::Value::from(*p)),
+                quote! { .priority(Some(::rust_buildkite::Priority(#p))) },
+            );
+        }
+
+        let (step_tokens, steps) = yaml::sequence(
+            self.steps
+                .iter()
+                .map(|s| s.expand(StepTarget::Pipeline, &self.default_plugins)),
+        );
+        fields.set(
+            "steps",
+            steps,
+            quote! { .steps(::rust_buildkite::PipelineSteps(vec![#(#step_tokens),*])) },
+        );
+        let (calls, value) = fields.finish();
 
         let path_validations: Vec<TokenStream2> = self
             .expect_paths
@@ -1082,35 +2796,57 @@ impl PipelineDef {
             .map(|p| quote! { ::rust_buildkite::validation::validate_path(#p); })
             .collect();
 
+        // `runtime_checked!` commands are checked against the same allowlist
+        // the compile-time check uses
+        let allowlist_binding = if self
+            .runtime_commands()
+            .iter()
+            .any(|(_, c)| matches!(c.0, CommandSource::Runtime { checked: true, .. }))
+        {
+            let names: Vec<String> = match &self.allowed_commands {
+                Some(allowed) => allowed
+                    .iter()
+                    .map(|(name, _)| name.clone())
+                    .chain(self.additional_commands.iter().cloned())
+                    .chain(self.shell.builtins().map(str::to_string))
+                    .collect(),
+                None => Vec::new(),
+            };
+            quote! { let __rbk_allowed_commands: &[&str] = &[#(#names),*]; }
+        } else {
+            quote! {}
+        };
+
         let used_env_vars = self.collect_used_env_vars(&self.steps);
         let env_validations: Vec<TokenStream2> = used_env_vars
             .iter()
             .map(|var| quote! { ::rust_buildkite::validation::validate_env_var(#var); })
             .collect();
 
-        Ok(quote! {
+        // Every fallible builder call goes through `__rbk_built!`, which the
+        // caller defines: `pipeline!` panics on the error in place and
+        // `try_pipeline!` returns it. The block stays plain so `?`, `return`
+        // and `.await` in runtime values belong to the enclosing fn.
+        let tokens = quote! {
             {
+                use ::rust_buildkite::IntoBuildResult as _;
                 ::rust_buildkite::validation::init();
                 #(#path_validations)*
                 #(#env_validations)*
                 #(#const_ref_uses)*
+                #allowlist_binding
 
-                let __result: ::rust_buildkite::JsonSchemaForBuildkitePipelineConfigurationFiles =
+                let __rbk_step = "pipeline";
+                let __result: ::rust_buildkite::JsonSchemaForBuildkitePipelineConfigurationFiles = __rbk_built!(
                     ::rust_buildkite::JsonSchemaForBuildkitePipelineConfigurationFiles::builder()
-                        .steps(::rust_buildkite::PipelineSteps(vec![
-                            #(#step_tokens),*
-                        ]))
-                        .env(#env_tokens)
-                        #agents_tokens
-                        #notify_tokens
-                        #image_tokens
-                        #secrets_tokens
-                        #priority_tokens
+                        #calls
                         .try_into()
-                        .expect("pipeline construction failed");
+                        .into_build_result(__rbk_step, "construction failed")
+                );
                 __result
             }
-        })
+        };
+        yaml::Expanded { tokens, value }
     }
 
     /// Validate all command steps against the allowed commands list.
@@ -1118,14 +2854,22 @@ impl PipelineDef {
     /// Note: Raw strings are already rejected at parse time - cmd!() is always required.
     /// Note: Path-based commands (./script, /path/to/cmd, relative/path) bypass allowlist -
     ///       they're validated separately by validate_paths() for existence.
-    fn validate_commands(&self, steps: &[StepDef], allowed: &HashSet<&str>) -> Result<()> {
+    fn validate_commands(
+        &self,
+        steps: &[StepDef],
+        allowed: &HashSet<&str>,
+        errors: &mut Vec<Error>,
+    ) {
         if should_skip_comptime_validation() {
-            return Ok(());
+            return;
         }
         for step in steps {
             match step {
+                StepDef::Command(cmd_step) if cmd_step.skip_validation => {}
                 StepDef::Command(cmd_step) => {
-                    for (cmd_name, span) in cmd_step.get_command_names() {
+                    let shell = cmd_step.shell.unwrap_or(self.shell);
+                    for cmd_value in &cmd_step.commands {
+                        let cmd_name = cmd_value.get_command_name();
                         // Skip path-based commands - they're validated by validate_paths()
                         // Paths start with /, ./ or contain / (relative paths like dir/script.sh)
                         if cmd_name.starts_with('/')
@@ -1134,67 +2878,545 @@ impl PipelineDef {
                         {
                             continue;
                         }
+                        if cmd_value.is_helper() {
+                            continue;
+                        }
 
-                        if !allowed.contains(cmd_name.as_str()) {
-                            let mut available: Vec<_> = allowed.iter().copied().collect();
-                            available.sort();
-                            return Err(Error::new(
+                        if !allowed.contains(cmd_name.as_str())
+                            && !shell.builtins().any(|b| b == cmd_name)
+                        {
+                            if BASH_BUILTINS.contains(&cmd_name.as_str()) {
+                                let (span, snippet) = cmd_value.locate_command_name();
+                                self.lints.report(
+                                    errors,
+                                    &codes::DISALLOWED_COMMAND,
+                                    span,
+                                    format!(
+                                        "'{}' is a bash builtin, but this step is validated as `shell: \"{}\"`.{}\n\
+                                         shell only changes validation; the agent's shell setting decides what \
+                                         runs the command. Use a POSIX alternative, or set `shell: \"bash\"` if \
+                                         the agent runs bash.",
+                                        cmd_name,
+                                        shell.name(),
+                                        snippet
+                                    ),
+                                );
+                                continue;
+                            }
+                            let source = if self.allowed_commands.is_some() {
+                                "from allowed_commands"
+                            } else {
+                                "discovered from PATH"
+                            };
+                            // Builtins are always allowed, so they're suggested separately
+                            let programs: Vec<&str> = allowed
+                                .iter()
+                                .copied()
+                                .filter(|c| !POSIX_BUILTINS.contains(c))
+                                .collect();
+                            let candidates = if verbose_errors() {
+                                let mut available = programs.clone();
+                                available.sort();
+                                format!("Available commands: {:?}", available)
+                            } else {
+                                format!(
+                                    "Closest allowed commands: {:?} ({} allowed, {}; set RUST_BUILDKITE_VERBOSE_ERRORS=1 to list all)",
+                                    closest_matches(&cmd_name, programs.iter().copied(), 5),
+                                    programs.len(),
+                                    source
+                                )
+                            };
+                            let builtin = closest_matches(&cmd_name, shell.builtins(), 1)
+                                .into_iter()
+                                .find(|b| {
+                                    edit_distance(&cmd_name, b) <= (cmd_name.len() / 4).max(1)
+                                })
+                                .map(|b| format!("\nDid you mean the shell builtin '{}'?", b))
+                                .unwrap_or_default();
+                            let (span, snippet) = cmd_value.locate_command_name();
+                            self.lints.report(
+                                errors,
+                                &codes::DISALLOWED_COMMAND,
                                 span,
                                 format!(
-                                    "Command '{}' is not in allowed_commands list.\n\
-                                     Available commands: {:?}\n\
+                                    "Command '{}' is not in allowed_commands list.{}{}\n\
+                                     {}\n\
                                      Add '{}' to allowed_commands or use a different command.",
-                                    cmd_name, available, cmd_name
+                                    cmd_name, snippet, builtin, candidates, cmd_name
                                 ),
-                            ));
+                            );
+                        }
+                    }
+                }
+                StepDef::Group(group) => {
+                    self.validate_commands(&group.steps, allowed, errors);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Reject `unchecked_cmd!` unless the pipeline sets
+    /// `allow_unchecked_commands: true`, pointing at each use and at the
+    /// pipeline where the setting goes.
+    fn validate_unchecked_commands(&self, errors: &mut Vec<Error>) {
+        fn collect(steps: &[StepDef], spans: &mut Vec<proc_macro2::Span>) {
+            for step in steps {
+                match step {
+                    StepDef::Command(cmd_step) => spans.extend(
+                        cmd_step
+                            .commands
+                            .iter()
+                            .filter(|c| c.is_unchecked())
+                            .map(|c| c.span()),
+                    ),
+                    StepDef::Group(group) => collect(&group.steps, spans),
+                    _ => {}
+                }
+            }
+        }
+
+        if self.allow_unchecked_commands {
+            return;
+        }
+        let mut spans = Vec::new();
+        collect(&self.steps, &mut spans);
+        if spans.is_empty() {
+            return;
+        }
+        for span in spans {
+            self.lints.report(
+                errors,
+                &codes::UNCHECKED_COMMAND,
+                span,
+                "unchecked_cmd! skips shell linting and must be enabled for the pipeline \
+                 with `allow_unchecked_commands: true`"
+                    .to_string(),
+            );
+        }
+        self.lints.report(
+            errors,
+            &codes::UNCHECKED_COMMAND,
+            self.span,
+            "add `allow_unchecked_commands: true` to this pipeline to allow unchecked_cmd!"
+                .to_string(),
+        );
+    }
+
+    /// Require `skip_validation("reason")` on every step with a `runtime!` or
+    /// `runtime_checked!` command, since none of the compile-time command
+    /// checks can see it.
+    fn validate_runtime_commands(&self, errors: &mut Vec<Error>) {
+        for (step, command) in self.runtime_commands() {
+            if !step.skip_validation {
+                self.lints.report(
+                    errors,
+                    &codes::UNACKNOWLEDGED_RUNTIME_COMMAND,
+                    command.span(),
+                    "this command is only known at runtime, so it isn't linted, checked \
+                     against allowed_commands, or checked for env vars and paths; \
+                     acknowledge that with `skip_validation(\"reason\")` on the step"
+                        .to_string(),
+                );
+            }
+        }
+    }
+
+    /// Every runtime command in the pipeline, with the step it belongs to.
+    fn runtime_commands(&self) -> Vec<(&CommandStepDef, &CommandValue)> {
+        fn collect<'a>(
+            steps: &'a [StepDef],
+            out: &mut Vec<(&'a CommandStepDef, &'a CommandValue)>,
+        ) {
+            for step in steps {
+                match step {
+                    StepDef::Command(cmd_step) => out.extend(
+                        cmd_step
+                            .commands
+                            .iter()
+                            .filter(|c| matches!(c.0, CommandSource::Runtime { .. }))
+                            .map(|c| (cmd_step, c)),
+                    ),
+                    StepDef::Group(group) => collect(&group.steps, out),
+                    _ => {}
+                }
+            }
+        }
+
+        let mut out = Vec::new();
+        collect(&self.steps, &mut out);
+        out
+    }
+
+    /// Run the checks that invoke bazel for every bazel command in the
+    /// pipeline. Targets BUILD-file parsing couldn't find are confirmed with one
+    /// `bazel query` per workspace rather than one per step; failures point at
+    /// the step's command. `bazel_validation` decides whether a missing bazel
+    /// binary or workspace is an error, a warning, or never looked for.
+    #[cfg(feature = "bazel")]
+    fn validate_bazel_commands(&self, errors: &mut Vec<Error>) {
+        fn collect_bazel<'a>(steps: &'a [StepDef], exprs: &mut Vec<&'a BazelExpr>) {
+            for step in steps {
+                match step {
+                    StepDef::Command(cmd_step) => {
+                        exprs.extend(cmd_step.commands.iter().filter_map(|c| c.bazel_expr()))
+                    }
+                    StepDef::Group(group) => collect_bazel(&group.steps, exprs),
+                    _ => {}
+                }
+            }
+        }
+
+        let mut all = Vec::new();
+        collect_bazel(&self.steps, &mut all);
+        let skip_all = |reason: &str| {
+            for expr in &all {
+                expr.report_skipped(reason);
+            }
+        };
+        let mode = self.bazel_validation.effective();
+        if mode == BazelValidation::Off {
+            skip_all("bazel_validation is off or RUST_BUILDKITE_BAZEL_OFFLINE=1 is set");
+            return;
+        }
+        if should_skip_comptime_validation() {
+            skip_all("BUILDKITE_SKIP_COMPTIME_VALIDATION is set");
+            return;
+        }
+        let pending: Vec<&BazelExpr> = all
+            .iter()
+            .copied()
+            .filter(|e| e.deferred.is_some())
+            .collect();
+        let Some(first) = pending.first() else {
+            skip_all("nothing to check with bazel");
+            return;
+        };
+
+        if mode == BazelValidation::Warn {
+            let missing = if pending
+                .iter()
+                .any(|e| e.deferred.as_ref().is_some_and(|d| d.workspace.is_none()))
+            {
+                Some(format!(
+                    "no Bazel workspace ({}) was found",
+                    rust_buildkite_validation::BAZEL_WORKSPACE_MARKERS.join(", ")
+                ))
+            } else {
+                pending.iter().find_map(|e| {
+                    let workspace = e.deferred.as_ref()?.workspace.as_deref()?;
+                    missing_bazel_binary(&e.binary, workspace)
+                })
+            };
+            if let Some(reason) = missing {
+                diag::warn(
+                    first.span,
+                    format!(
+                        "{}; skipping Bazel target and flag validation (bazel_validation: \"warn\")",
+                        reason
+                    ),
+                );
+                skip_all(&reason);
+                return;
+            }
+        }
+        for expr in all.iter().filter(|e| e.deferred.is_none()) {
+            expr.report_skipped(if expr.undefined_vars.is_empty() {
+                "the verb names no targets or flags to check"
+            } else {
+                "the command uses shell variables, resolved at runtime"
+            });
+        }
+
+        let mut by_workspace: Vec<(&std::path::Path, Vec<&BazelExpr>)> = Vec::new();
+        for &expr in &pending {
+            let Some(workspace) = expr.deferred.as_ref().and_then(|d| d.workspace.as_deref())
+            else {
+                continue;
+            };
+            if !expr.deferred.as_ref().is_some_and(|d| d.query_targets) {
+                continue;
+            }
+            match by_workspace.iter_mut().find(|(w, _)| *w == workspace) {
+                Some((_, exprs)) => exprs.push(expr),
+                None => by_workspace.push((workspace, vec![expr])),
+            }
+        }
+
+        let mut failed: Vec<&BazelExpr> = Vec::new();
+        let mut expanded: Vec<(&BazelExpr, std::collections::BTreeSet<String>)> = Vec::new();
+        for (workspace, exprs) in by_workspace {
+            let queries: Vec<bazel::TargetQuery> = exprs
+                .iter()
+                .map(|expr| bazel::TargetQuery {
+                    verb: &expr.verb,
+                    args: expr.args(),
+                    current_pkg: expr
+                        .deferred
+                        .as_ref()
+                        .and_then(|d| d.current_pkg.as_deref()),
+                })
+                .collect();
+            for (expr, result) in exprs.iter().zip(bazel::validate_queries(
+                &queries,
+                workspace,
+                &exprs[0].binary,
+            )) {
+                match result {
+                    Ok(labels) => expanded.push((*expr, labels.into_keys().collect())),
+                    Err(e) => {
+                        if expr.dry_run {
+                            let mut entry = expr.report_entry();
+                            entry.check("targets", bazel_report::Status::Failed, Some(e.clone()));
+                            bazel_report::record(entry);
+                        }
+                        self.lints.report(
+                            errors,
+                            &codes::BAZEL_TARGETS,
+                            expr.span,
+                            format!("Target validation failed: {}", e),
+                        );
+                        failed.push(*expr);
+                    }
+                }
+            }
+        }
+        lints::check_duplicate_bazel_targets(&self.steps, &expanded, &self.lints, errors);
+
+        for expr in pending {
+            if failed.iter().any(|f| std::ptr::eq(*f, expr)) {
+                continue;
+            }
+            let resolved = expanded
+                .iter()
+                .find(|(e, _)| std::ptr::eq(*e, expr))
+                .map(|(_, labels)| labels.iter().cloned().collect());
+            if let Err(e) = expr.run_bazel_checks(false, resolved) {
+                errors.push(e);
+            }
+        }
+    }
+
+    /// Validate that path-based commands (./script.sh, /usr/bin/env, dir/script.sh) and
+    /// local plugins (./.buildkite/plugins/deploy) exist at compile time.
+    /// Paths in allow_missing are skipped (for runtime-only paths).
+    fn validate_paths(&self, steps: &[StepDef], allow_missing: &[&str], errors: &mut Vec<Error>) {
+        if should_skip_comptime_validation() {
+            return;
+        }
+        for step in steps {
+            match step {
+                StepDef::Command(cmd_step) if cmd_step.skip_validation => {}
+                StepDef::Command(cmd_step) => {
+                    for (cmd_name, span) in cmd_step.get_command_names() {
+                        // Check paths: absolute (/path), explicit relative (./path), or implicit relative (dir/path)
+                        if (cmd_name.starts_with('/')
+                            || cmd_name.starts_with("./")
+                            || cmd_name.contains('/'))
+                            && let Err(e) = CmdExpr::validate_path_exists(
+                                &cmd_name,
+                                allow_missing,
+                                self.path_base.as_deref(),
+                            )
+                        {
+                            self.lints.report(errors, &codes::MISSING_PATH, span, e);
+                        }
+                    }
+                    self.validate_plugin_paths(&cmd_step.local_plugins, allow_missing, errors);
+                    for check in &cmd_step.compose_checks {
+                        for problem in
+                            compose::check(check, allow_missing, self.path_base.as_deref())
+                        {
+                            let code = if problem.missing_file {
+                                &codes::MISSING_PATH
+                            } else {
+                                &codes::UNKNOWN_COMPOSE_SERVICE
+                            };
+                            self.lints
+                                .report(errors, code, problem.span, problem.message);
                         }
                     }
                 }
                 StepDef::Group(group) => {
-                    self.validate_commands(&group.steps, allowed)?;
+                    self.validate_paths(&group.steps, allow_missing, errors);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Check that each local plugin is a directory with a plugin.yml.
+    fn validate_plugin_paths(
+        &self,
+        local_plugins: &[(String, proc_macro2::Span)],
+        allow_missing: &[&str],
+        errors: &mut Vec<Error>,
+    ) {
+        for (path, span) in local_plugins {
+            if let Err(e) =
+                CmdExpr::validate_plugin_path(path, allow_missing, self.path_base.as_deref())
+            {
+                self.lints.report(errors, &codes::MISSING_PATH, *span, e);
+            }
+        }
+    }
+
+    /// A `lazy` pipeline is built once and shared by every call, so nothing
+    /// in it may be computed at runtime.
+    fn validate_lazy(&self, errors: &mut Vec<Error>) {
+        if self.lazy.is_none() {
+            return;
+        }
+        let mut found = Vec::new();
+        for (_, value) in self.env.iter().flatten() {
+            runtime_value(value, "the pipeline env value", &mut found);
+        }
+        for step in &self.steps {
+            step.collect_runtime_values(&mut found);
+        }
+        for (tokens, what) in found {
+            self.lints.report(
+                errors,
+                &codes::LAZY_RUNTIME_VALUE,
+                syn::spanned::Spanned::span(&tokens),
+                format!(
+                    "lazy: true builds the pipeline once and reuses it, but {} `{}` is computed at runtime; \
+                     make it static or remove lazy",
+                    what, tokens
+                ),
+            );
+        }
+    }
+
+    /// Check that every `needs_artifacts` names a command step whose
+    /// artifact_paths upload something matching the requested glob.
+    fn validate_artifact_needs(&self, errors: &mut Vec<Error>) {
+        fn collect<'a>(
+            steps: &'a [StepDef],
+            uploads: &mut HashMap<String, Option<Vec<String>>>,
+            needs: &mut Vec<&'a ArtifactNeed>,
+        ) {
+            for step in steps {
+                if let Some((key, _)) = step.get_key() {
+                    let paths = match step {
+                        StepDef::Command(c) => Some(
+                            c.artifact_paths
+                                .iter()
+                                .flat_map(|p| {
+                                    p.value()
+                                        .split(';')
+                                        .map(|path| path.trim().to_string())
+                                        .collect::<Vec<_>>()
+                                })
+                                .filter(|path| !path.is_empty())
+                                .collect(),
+                        ),
+                        _ => None,
+                    };
+                    uploads.insert(key, paths);
+                }
+                match step {
+                    StepDef::Command(c) => needs.extend(&c.needs_artifacts),
+                    StepDef::Group(g) => collect(&g.steps, uploads, needs),
+                    _ => {}
                 }
-                _ => {}
             }
         }
-        Ok(())
-    }
 
-    /// Validate that path-based commands (./script.sh, /usr/bin/env, dir/script.sh) exist at compile time.
-    /// Paths in allow_missing are skipped (for runtime-only paths).
-    fn validate_paths(&self, steps: &[StepDef], allow_missing: &[&str]) -> Result<()> {
-        if should_skip_comptime_validation() {
-            return Ok(());
+        let mut uploads = HashMap::new();
+        let mut needs = Vec::new();
+        collect(&self.steps, &mut uploads, &mut needs);
+        for need in needs {
+            // An unknown key is reported through the depends_on edge
+            let Some(paths) = uploads.get(&need.key) else {
+                continue;
+            };
+            let message = match paths {
+                None => format!(
+                    "needs_artifacts names step '{}', which is not a command step and uploads no artifacts",
+                    need.key
+                ),
+                Some(paths) if paths.is_empty() => format!(
+                    "needs_artifacts expects '{}' from step '{}', which sets no artifact_paths.\n\
+                     Add artifact_paths: [\"{}\"] to step '{}'",
+                    need.glob, need.key, need.glob, need.key
+                ),
+                Some(paths) if !paths.iter().any(|path| globs_overlap(path, &need.glob)) => {
+                    format!(
+                        "needs_artifacts expects '{}' from step '{}', but its artifact_paths {:?} don't match it",
+                        need.glob, need.key, paths
+                    )
+                }
+                Some(_) => continue,
+            };
+            self.lints.report(
+                errors,
+                &codes::UNMATCHED_ARTIFACT_DOWNLOAD,
+                need.span,
+                message,
+            );
         }
+    }
+
+    /// Check every concurrency_group against the pipeline's concurrency_group_policy.
+    fn validate_concurrency_groups(&self, steps: &[StepDef], errors: &mut Vec<Error>) {
         for step in steps {
             match step {
                 StepDef::Command(cmd_step) => {
-                    for (cmd_name, span) in cmd_step.get_command_names() {
-                        // Check paths: absolute (/path), explicit relative (./path), or implicit relative (dir/path)
-                        if (cmd_name.starts_with('/')
-                            || cmd_name.starts_with("./")
-                            || cmd_name.contains('/'))
-                            && let Err(e) = CmdExpr::validate_path_exists(&cmd_name, allow_missing)
-                        {
-                            return Err(Error::new(span, e));
-                        }
+                    if let Some(group) = &cmd_step.concurrency_group
+                        && let Err(msg) = self.concurrency_group_policy.check(group)
+                    {
+                        self.lints.report(
+                            errors,
+                            &codes::CONCURRENCY_GROUP_POLICY,
+                            group.span(),
+                            msg,
+                        );
                     }
                 }
                 StepDef::Group(group) => {
-                    self.validate_paths(&group.steps, allow_missing)?;
+                    self.validate_concurrency_groups(&group.steps, errors);
                 }
                 _ => {}
             }
         }
-        Ok(())
+    }
+
+    /// Validate `if` conditionals on every step, including nested group steps.
+    fn validate_conditions(&self, steps: &[StepDef], errors: &mut Vec<Error>) {
+        for step in steps {
+            if let Some(condition) = step.if_condition()
+                && let Err(problems) = buildkite_conditional::validate_condition(&condition.value())
+            {
+                self.lints.report(
+                    errors,
+                    &codes::INVALID_CONDITION,
+                    condition.span(),
+                    format!("Invalid Buildkite conditional: {}", problems.join("; ")),
+                );
+            }
+            if let StepDef::Group(group) = step {
+                self.validate_conditions(&group.steps, errors);
+            }
+        }
     }
 
     /// Validate that environment variables used in commands are defined.
     /// Variables must be explicitly defined in: pipeline env block, step env, or expect_env list.
     /// If expect_env is not specified, defaults to host environment variables.
-    fn validate_env_vars(&self, steps: &[StepDef]) -> Result<()> {
+    fn validate_env_vars(&self, steps: &[StepDef], errors: &mut Vec<Error>) {
         if should_skip_comptime_validation() {
-            return Ok(());
+            return;
+        }
+        if let Some((allowed_vars, declared)) = self.env_var_sets() {
+            self.validate_env_vars_in_steps(steps, &allowed_vars, &declared, errors);
         }
+    }
+
+    /// Env vars commands may reference at pipeline level, and the subset the
+    /// pipeline declares itself. None when expect_env names a const, whose
+    /// contents aren't known during expansion.
+    fn env_var_sets(&self) -> Option<(HashSet<String>, HashSet<String>)> {
         let mut has_const_refs = false;
 
         let mut allowed_vars: HashSet<String> = HashSet::new();
@@ -1204,11 +3426,14 @@ impl PipelineDef {
             }
         }
 
+        // Names the pipeline declares itself; host variables are never suggested
+        let mut declared = allowed_vars.clone();
         if let Some(expect_env) = &self.expect_env {
             for item in expect_env {
                 match item {
                     ExpectEnvItem::Literal(name) => {
                         allowed_vars.insert(name.clone());
+                        declared.insert(name.clone());
                     }
                     ExpectEnvItem::ConstRef(_) => {
                         has_const_refs = true;
@@ -1220,51 +3445,133 @@ impl PipelineDef {
         }
 
         if has_const_refs {
-            return Ok(());
+            return None;
         }
-
-        self.validate_env_vars_in_steps(steps, &allowed_vars)
+        Some((allowed_vars, declared))
     }
 
     fn validate_env_vars_in_steps(
         &self,
         steps: &[StepDef],
         allowed: &HashSet<String>,
-    ) -> Result<()> {
+        declared: &HashSet<String>,
+        errors: &mut Vec<Error>,
+    ) {
         for step in steps {
             match step {
                 StepDef::Command(cmd_step) => {
                     let mut step_allowed = allowed.clone();
+                    let mut step_declared = declared.clone();
                     for (name, _) in &cmd_step.env {
                         step_allowed.insert(name.clone());
+                        step_declared.insert(name.clone());
+                    }
+
+                    if let Some(group) = &cmd_step.concurrency_group {
+                        for var in interpolated_vars(&group.value()) {
+                            if !step_allowed.contains(&var) {
+                                self.lints.report(
+                                    errors,
+                                    &codes::UNDEFINED_CONCURRENCY_GROUP_VAR,
+                                    group.span(),
+                                    format!(
+                                        "Environment variable '{}' in concurrency_group is not defined.\n\
+                                         Add it to pipeline env: env: {{ {}: \"value\" }}\n\
+                                         Or allow it: expect_env: [\"{}\"]",
+                                        var, var, var
+                                    ),
+                                );
+                            }
+                        }
                     }
 
+                    if cmd_step.skip_validation {
+                        continue;
+                    }
                     for cmd_value in &cmd_step.commands {
-                        let span = cmd_value.span();
                         let undefined_vars = cmd_value.get_undefined_vars();
 
                         for var in undefined_vars {
                             if !step_allowed.contains(var) {
-                                return Err(Error::new(
+                                let (span, snippet) = cmd_value.locate_var(var);
+                                let suggestion = closest_matches(
+                                    var,
+                                    step_declared.iter().map(|s| s.as_str()),
+                                    1,
+                                )
+                                .into_iter()
+                                .find(|name| edit_distance(var, name) <= (var.len() / 4).max(1))
+                                .map(|name| format!("\nDid you mean '{}'?", name))
+                                .unwrap_or_default();
+                                self.lints.report(
+                                    errors,
+                                    &codes::UNDEFINED_ENV_VAR,
                                     span,
                                     format!(
-                                        "Environment variable '{}' is not defined.\n\
+                                        "Environment variable '{}' is not defined.{}{}\n\
                                          Add it to pipeline env: env: {{ {}: \"value\" }}\n\
                                          Or allow it: expect_env: [\"{}\"]",
-                                        var, var, var
+                                        var, snippet, suggestion, var, var
                                     ),
-                                ));
+                                );
                             }
                         }
                     }
                 }
+                StepDef::Trigger(trigger) => {
+                    if let Some(build) = &trigger.build {
+                        self.validate_trigger_build_vars(build, allowed, declared, errors);
+                    }
+                }
                 StepDef::Group(group) => {
-                    self.validate_env_vars_in_steps(&group.steps, allowed)?;
+                    self.validate_env_vars_in_steps(&group.steps, allowed, declared, errors);
                 }
                 _ => {}
             }
         }
-        Ok(())
+    }
+
+    /// Check `$VAR` references in a trigger's build fields, which Buildkite
+    /// interpolates at upload time. Buildkite's own variables are always set
+    /// there, on top of the pipeline's allowed variables.
+    fn validate_trigger_build_vars(
+        &self,
+        build: &TriggerBuildConfig,
+        allowed: &HashSet<String>,
+        declared: &HashSet<String>,
+        errors: &mut Vec<Error>,
+    ) {
+        let buildkite_vars = expand_known_env_list("BUILDKITE_ENV").unwrap_or_default();
+        for (text, span) in &build.interpolated {
+            for var in interpolated_vars(text) {
+                if allowed.contains(&var) || buildkite_vars.contains(&var.as_str()) {
+                    continue;
+                }
+                let snippet = find_var_reference(text, &var)
+                    .map(|range| caret_snippet(text, range))
+                    .unwrap_or_default();
+                let candidates = declared
+                    .iter()
+                    .map(|s| s.as_str())
+                    .chain(buildkite_vars.iter().copied());
+                let suggestion = closest_matches(&var, candidates, 1)
+                    .into_iter()
+                    .find(|name| edit_distance(&var, name) <= (var.len() / 4).max(1))
+                    .map(|name| format!("\nDid you mean '{}'?", name))
+                    .unwrap_or_default();
+                self.lints.report(
+                    errors,
+                    &codes::UNDEFINED_ENV_VAR,
+                    *span,
+                    format!(
+                        "Environment variable '{}' in trigger build is not defined.{}{}\n\
+                         Add it to pipeline env: env: {{ {}: \"value\" }}\n\
+                         Or allow it: expect_env: [\"{}\"]",
+                        var, snippet, suggestion, var, var
+                    ),
+                );
+            }
+        }
     }
 
     fn collect_used_env_vars(&self, steps: &[StepDef]) -> HashSet<String> {
@@ -1288,6 +3595,7 @@ impl PipelineDef {
     ) {
         for step in steps {
             match step {
+                StepDef::Command(cmd_step) if cmd_step.skip_validation => {}
                 StepDef::Command(cmd_step) => {
                     let mut step_allowed = allowed.clone();
                     for (name, _) in &cmd_step.env {
@@ -1327,6 +3635,37 @@ impl Parse for StepDef {
 }
 
 impl StepDef {
+    /// Parse a comma-separated list of steps, e.g. the contents of `steps: [...]`
+    /// in a pipeline or group, so nested bazel steps see the pipeline's
+    /// custom verbs too.
+    fn parse_list(input: ParseStream, custom_verbs: &[String]) -> Result<Vec<Self>> {
+        let mut steps = Vec::new();
+        while !input.is_empty() {
+            steps.push(Self::parse_documented(input, custom_verbs)?);
+            if input.peek(Token![,]) {
+                input.parse::<Token![,]>()?;
+            }
+        }
+        Ok(steps)
+    }
+
+    /// Parse a step, taking `///` comments before it as its description.
+    fn parse_documented(input: ParseStream, custom_verbs: &[String]) -> Result<Self> {
+        let docs = parse_doc_comments(input)?;
+        let mut step = Self::parse_with_custom_verbs(input, custom_verbs)?;
+        if let Some((text, span)) = docs {
+            let slot = step.description_slot();
+            if slot.is_some() {
+                return Err(Error::new(
+                    span,
+                    "step has both a doc comment and a description; keep one",
+                ));
+            }
+            *slot = Some(text);
+        }
+        Ok(step)
+    }
+
     #[cfg(feature = "bazel")]
     fn parse_with_custom_verbs(input: ParseStream, custom_verbs: &[String]) -> Result<Self> {
         let ident: Ident = input.parse()?;
@@ -1352,6 +3691,9 @@ impl StepDef {
                     Ok(StepDef::Wait(WaitStepDef::default()))
                 }
             }
+            "wait_for" => Self::parse_wait_for(input, ident.span()),
+            "metadata" => Self::parse_metadata_step(input),
+            "plugin_step" => Self::parse_plugin_step(input),
             "block" => {
                 if input.peek(syn::token::Brace) {
                     Self::parse_block_object_literal(input)
@@ -1390,9 +3732,9 @@ impl StepDef {
             }
             "group" => {
                 if input.peek(syn::token::Brace) {
-                    Self::parse_group_object_literal(input)
+                    Self::parse_group_object_literal(input, custom_verbs)
                 } else if input.peek(syn::token::Paren) {
-                    Self::parse_group_fluent(input)
+                    Self::parse_group_fluent(input, custom_verbs)
                 } else {
                     Err(Error::new(
                         ident.span(),
@@ -1441,7 +3783,7 @@ impl StepDef {
             other => Err(Error::new(
                 ident.span(),
                 format!(
-                    "unknown step type: '{}'. Expected: command, wait, block, input, trigger, group, bazel_command, bazel_build, bazel_test, bazel_run",
+                    "unknown step type: '{}'. Expected: command, wait, wait_for, block, input, trigger, group, metadata, plugin_step, bazel_command, bazel_build, bazel_test, bazel_run",
                     other
                 ),
             )),
@@ -1473,6 +3815,9 @@ impl StepDef {
                     Ok(StepDef::Wait(WaitStepDef::default()))
                 }
             }
+            "wait_for" => Self::parse_wait_for(input, ident.span()),
+            "metadata" => Self::parse_metadata_step(input),
+            "plugin_step" => Self::parse_plugin_step(input),
             "block" => {
                 if input.peek(syn::token::Brace) {
                     Self::parse_block_object_literal(input)
@@ -1511,9 +3856,9 @@ impl StepDef {
             }
             "group" => {
                 if input.peek(syn::token::Brace) {
-                    Self::parse_group_object_literal(input)
+                    Self::parse_group_object_literal(input, _custom_verbs)
                 } else if input.peek(syn::token::Paren) {
-                    Self::parse_group_fluent(input)
+                    Self::parse_group_fluent(input, _custom_verbs)
                 } else {
                     Err(Error::new(
                         ident.span(),
@@ -1521,10 +3866,14 @@ impl StepDef {
                     ))
                 }
             }
+            other if other.starts_with("bazel_") => Err(bazel_feature_required(
+                ident.span(),
+                &format!("'{}': bazel steps require", other),
+            )),
             other => Err(Error::new(
                 ident.span(),
                 format!(
-                    "unknown step type: '{}'. Expected: command, wait, block, input, trigger, group",
+                    "unknown step type: '{}'. Expected: command, wait, wait_for, block, input, trigger, group, metadata, plugin_step",
                     other
                 ),
             )),
@@ -1538,11 +3887,62 @@ impl StepDef {
             StepDef::Input(i) => i.key.as_ref(),
             StepDef::Trigger(t) => t.key.as_ref(),
             StepDef::Group(g) => g.key.as_ref(),
-            StepDef::Wait(_) => None,
+            StepDef::Wait(w) => w.key.as_ref(),
         };
         key_value.and_then(|kv| kv.as_literal().map(|(s, span)| (s.to_string(), span)))
     }
 
+    fn kind(&self) -> &'static str {
+        match self {
+            StepDef::Command(_) => "command",
+            StepDef::Wait(_) => "wait",
+            StepDef::Block(_) => "block",
+            StepDef::Input(_) => "input",
+            StepDef::Trigger(_) => "trigger",
+            StepDef::Group(_) => "group",
+        }
+    }
+
+    /// How runtime build errors refer to this step: by key, else by a literal
+    /// label or prompt, e.g. `command step 'build'`.
+    fn describe(&self) -> String {
+        fn expr_str_literal(expr: &syn::Expr) -> Option<String> {
+            match expr {
+                syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Str(s),
+                    ..
+                }) => Some(s.value()),
+                _ => None,
+            }
+        }
+
+        let label = match self {
+            StepDef::Command(c) => c.label.as_ref().and_then(expr_str_literal),
+            StepDef::Trigger(t) => t.label.as_ref().and_then(expr_str_literal),
+            StepDef::Group(g) => g.label.as_ref().and_then(expr_str_literal),
+            StepDef::Block(b) => b.prompt.as_ref().and_then(expr_str_literal),
+            StepDef::Input(i) => i.prompt.as_ref().and_then(expr_str_literal),
+            StepDef::Wait(_) => None,
+        };
+        match (self.get_key(), label) {
+            (Some((key, _)), _) => format!("{} step '{}'", self.kind(), key),
+            (None, Some(label)) => format!("{} step \"{}\"", self.kind(), label),
+            (None, None) => format!("{} step", self.kind()),
+        }
+    }
+
+    /// Bind `__rbk_step` around a step's tokens so the fallible builder calls
+    /// inside report which step failed.
+    fn with_build_context(&self, tokens: TokenStream2) -> TokenStream2 {
+        let context = self.describe();
+        quote! {
+            {
+                let __rbk_step = #context;
+                #tokens
+            }
+        }
+    }
+
     fn get_depends_on(&self) -> Vec<(String, proc_macro2::Span)> {
         match self {
             StepDef::Command(c) => c.depends_on.clone(),
@@ -1554,14 +3954,251 @@ impl StepDef {
         }
     }
 
+    fn if_condition(&self) -> Option<&LitStr> {
+        match self {
+            StepDef::Command(c) => c.if_condition.as_ref(),
+            StepDef::Block(b) => b.if_condition.as_ref(),
+            StepDef::Input(i) => i.if_condition.as_ref(),
+            StepDef::Trigger(t) => t.if_condition.as_ref(),
+            StepDef::Group(g) => g.if_condition.as_ref(),
+            StepDef::Wait(w) => w.if_condition.as_ref(),
+        }
+    }
+
+    fn description(&self) -> Option<&str> {
+        match self {
+            StepDef::Command(c) => c.description.as_deref(),
+            StepDef::Wait(w) => w.description.as_deref(),
+            StepDef::Block(b) => b.description.as_deref(),
+            StepDef::Input(i) => i.description.as_deref(),
+            StepDef::Trigger(t) => t.description.as_deref(),
+            StepDef::Group(g) => g.description.as_deref(),
+        }
+    }
+
+    fn description_slot(&mut self) -> &mut Option<String> {
+        match self {
+            StepDef::Command(c) => &mut c.description,
+            StepDef::Wait(w) => &mut w.description,
+            StepDef::Block(b) => &mut b.description,
+            StepDef::Input(i) => &mut i.description,
+            StepDef::Trigger(t) => &mut t.description,
+            StepDef::Group(g) => &mut g.description,
+        }
+    }
+
+    fn key_slot(&mut self) -> Option<&mut Option<KeyValue>> {
+        match self {
+            StepDef::Command(c) => Some(&mut c.key),
+            StepDef::Block(b) => Some(&mut b.key),
+            StepDef::Input(i) => Some(&mut i.key),
+            StepDef::Trigger(t) => Some(&mut t.key),
+            StepDef::Group(g) => Some(&mut g.key),
+            StepDef::Wait(w) => Some(&mut w.key),
+        }
+    }
+
+    fn only_profiles(&self) -> &[LitStr] {
+        match self {
+            StepDef::Command(c) => &c.only_profiles,
+            StepDef::Group(g) => &g.only_profiles,
+            _ => &[],
+        }
+    }
+
+    fn key_mut(&mut self) -> Option<&mut KeyValue> {
+        self.key_slot().and_then(|key| key.as_mut())
+    }
+
+    /// Static label a key can be derived from when `auto_keys` is enabled.
+    /// Groups, waits and runtime labels never get a derived key.
+    fn auto_key_label(&self) -> Option<(String, proc_macro2::Span)> {
+        let label = match self {
+            StepDef::Command(c) => c.label.as_ref(),
+            StepDef::Trigger(t) => t.label.as_ref(),
+            StepDef::Block(b) => b.prompt.as_ref(),
+            StepDef::Input(i) => i.prompt.as_ref(),
+            StepDef::Group(_) | StepDef::Wait(_) => None,
+        };
+        match label {
+            Some(syn::Expr::Lit(syn::ExprLit {
+                lit: syn::Lit::Str(s),
+                ..
+            })) => Some((s.value(), s.span())),
+            _ => None,
+        }
+    }
+
+    /// The step's branch filter; empty for steps that can't have one.
+    fn branch_filters(&self) -> &[LitStr] {
+        match self {
+            StepDef::Command(c) => &c.branches,
+            StepDef::Wait(w) => &w.branches,
+            StepDef::Block(b) => &b.branches,
+            StepDef::Input(i) => &i.branches,
+            StepDef::Trigger(t) => &t.branches,
+            StepDef::Group(_) => &[],
+        }
+    }
+
+    /// Where the label came from, when it was computed at expansion time.
+    fn label_origin(&self) -> Option<&ValueOrigin> {
+        match self {
+            StepDef::Command(c) => c.label_origin.as_ref(),
+            StepDef::Trigger(t) => t.label_origin.as_ref(),
+            StepDef::Group(g) => g.label_origin.as_ref(),
+            StepDef::Block(b) => b.prompt_origin.as_ref(),
+            StepDef::Input(i) => i.prompt_origin.as_ref(),
+            StepDef::Wait(_) => None,
+        }
+    }
+
+    /// The step's label when written as a string literal: `label` on command,
+    /// trigger and group steps, the block or input text otherwise.
+    fn label_lit(&self) -> Option<&LitStr> {
+        let label = match self {
+            StepDef::Command(c) => c.label.as_ref(),
+            StepDef::Trigger(t) => t.label.as_ref(),
+            StepDef::Group(g) => g.label.as_ref(),
+            StepDef::Block(b) => b.prompt.as_ref(),
+            StepDef::Input(i) => i.prompt.as_ref(),
+            StepDef::Wait(_) => None,
+        };
+        match label {
+            Some(syn::Expr::Lit(syn::ExprLit {
+                lit: syn::Lit::Str(s),
+                ..
+            })) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn depends_on_mut(&mut self) -> &mut Vec<(String, proc_macro2::Span)> {
+        match self {
+            StepDef::Command(c) => &mut c.depends_on,
+            StepDef::Block(b) => &mut b.depends_on,
+            StepDef::Input(i) => &mut i.depends_on,
+            StepDef::Trigger(t) => &mut t.depends_on,
+            StepDef::Group(g) => &mut g.depends_on,
+            StepDef::Wait(w) => &mut w.depends_on,
+        }
+    }
+
+    /// Collect the shell commands in this step (and nested steps), for
+    /// [`lint_commands`].
+    fn collect_shell_commands_mut<'a>(
+        &'a mut self,
+        shell: StepShell,
+        out: &mut Vec<&'a mut CmdExpr>,
+    ) {
+        match self {
+            StepDef::Command(c) => {
+                let shell = c.shell.unwrap_or(shell);
+                for cmd in c.commands.iter_mut().filter_map(|cmd| cmd.shell_mut()) {
+                    cmd.shell = shell;
+                    out.push(cmd);
+                }
+            }
+            StepDef::Group(g) => {
+                for step in &mut g.steps {
+                    step.collect_shell_commands_mut(shell, out);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Collect how long each shell command in this step (and nested steps)
+    /// took to lint, for the profiling report.
+    fn collect_lint_times(&self, out: &mut Vec<(String, std::time::Duration)>) {
+        match self {
+            StepDef::Command(c) => out.extend(
+                c.commands
+                    .iter()
+                    .filter_map(|cmd| Some((cmd.command_text()?.to_string(), cmd.lint_time()))),
+            ),
+            StepDef::Group(g) => {
+                for step in &g.steps {
+                    step.collect_lint_times(out);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Collect values in this step (and nested steps) that are computed at
+    /// runtime, as (expression, description).
+    fn collect_runtime_values(&self, out: &mut Vec<(TokenStream2, &'static str)>) {
+        let key = match self {
+            StepDef::Command(c) => c.key.as_ref(),
+            StepDef::Block(b) => b.key.as_ref(),
+            StepDef::Input(i) => i.key.as_ref(),
+            StepDef::Trigger(t) => t.key.as_ref(),
+            StepDef::Group(g) => g.key.as_ref(),
+            StepDef::Wait(w) => w.key.as_ref(),
+        };
+        if let Some(KeyValue::Runtime(expr)) = key {
+            out.push((runtime_expr_tokens(expr), "the step key"));
+        }
+        let label = match self {
+            StepDef::Command(c) => c.label.as_ref(),
+            StepDef::Trigger(t) => t.label.as_ref(),
+            StepDef::Group(g) => g.label.as_ref(),
+            StepDef::Wait(_) | StepDef::Block(_) | StepDef::Input(_) => None,
+        };
+        if let Some(label @ syn::Expr::Macro(mac)) = label
+            && mac.mac.path.is_ident("runtime")
+        {
+            out.push((runtime_expr_tokens(label), "the step label"));
+        }
+        match self {
+            StepDef::Command(c) => {
+                for (_, value) in &c.env {
+                    runtime_value(value, "the env value", out);
+                }
+                for command in &c.commands {
+                    command.collect_runtime_values(out);
+                }
+            }
+            StepDef::Group(g) => {
+                for nested in &g.steps {
+                    nested.collect_runtime_values(out);
+                }
+            }
+            StepDef::Wait(_) | StepDef::Block(_) | StepDef::Input(_) | StepDef::Trigger(_) => {}
+        }
+    }
+
+    /// Collect literal keys of this step and any steps nested in it.
+    fn collect_keys(&self, out: &mut Vec<(String, proc_macro2::Span)>) {
+        if let Some(key) = self.get_key() {
+            out.push(key);
+        }
+        if let StepDef::Group(g) = self {
+            for nested in &g.steps {
+                nested.collect_keys(out);
+            }
+        }
+    }
+
+    /// Collect depends_on entries of this step and any steps nested in it.
+    fn collect_depends_on(&self, out: &mut Vec<(String, proc_macro2::Span)>) {
+        out.extend(self.get_depends_on());
+        if let StepDef::Group(g) = self {
+            for nested in &g.steps {
+                nested.collect_depends_on(out);
+            }
+        }
+    }
+
     /// Parse command step with fluent syntax: command(cmd!("...")).method()
     /// Raw strings are not allowed - must use cmd!() for bashrs validation.
     fn parse_command_fluent(input: ParseStream) -> Result<Self> {
         let content;
         syn::parenthesized!(content in input);
-        let mut step = if content.peek(Ident) {
+        let step = if content.peek(Ident) {
             let ident: Ident = content.parse()?;
-            if ident == "cmd" || ident == "bazel" {
+            if ident == "cmd" || ident == "unchecked_cmd" || ident == "bazel" {
                 content.parse::<Token![!]>()?;
                 let cmd_content;
                 syn::parenthesized!(cmd_content in content);
@@ -1572,7 +4209,11 @@ impl StepDef {
                     )
                 })?;
                 if ident == "cmd" {
-                    let cmd_expr = CmdExpr::from_lit_str(&lit)?;
+                    let mut cmd_expr = CmdExpr::parse_lit_str(&lit)?;
+                    cmd_expr.parse_allow(&cmd_content)?;
+                    CommandStepDef::new_with_cmd(cmd_expr)
+                } else if ident == "unchecked_cmd" {
+                    let cmd_expr = CmdExpr::unchecked_from_lit_str(&lit)?;
                     CommandStepDef::new_with_cmd(cmd_expr)
                 } else {
                     #[cfg(feature = "bazel")]
@@ -1588,6 +4229,17 @@ impl StepDef {
                         ));
                     }
                 }
+            } else if cargo::is_helper(&ident) {
+                content.parse::<Token![!]>()?;
+                let args;
+                syn::parenthesized!(args in content);
+                let lit = cargo::parse(&ident, &args)?;
+                CommandStepDef::new_with_cmd(CmdExpr::parse_lit_str(&lit)?)
+            } else if CommandValue::is_runtime_macro(&ident) {
+                let mut step = CommandStepDef::new_empty();
+                step.commands
+                    .push(CommandValue::parse_runtime(&ident, &content)?);
+                step
             } else {
                 return Err(Error::new(
                     ident.span(),
@@ -1604,7 +4256,61 @@ impl StepDef {
         } else {
             return Err(Error::new(content.span(), "expected cmd!(\"...\")"));
         };
+        Self::parse_command_methods(input, step)
+    }
+
+    /// Parse a `metadata("key", value)` step: a command step that only sets
+    /// build meta-data, keyed after the meta-data key. Command step methods
+    /// can follow, e.g. `.depends_on("release")`.
+    fn parse_metadata_step(input: ParseStream) -> Result<Self> {
+        let content;
+        syn::parenthesized!(content in input);
+        let key: LitStr = content.parse()?;
+        content.parse::<Token![,]>()?;
+        let value = DynamicValue::parse(&content)?;
+        let cmd_expr = CmdExpr::metadata_set(&key, &value)?;
+
+        let mut step = CommandStepDef::new_with_cmd(cmd_expr);
+        let slug = slugify_label(&key.value());
+        let step_key = if slug.is_empty() {
+            "set-metadata".to_string()
+        } else {
+            format!("set-metadata-{}", slug)
+        };
+        step.key = Some(KeyValue::Literal(step_key, key.span()));
+        let label = LitStr::new(&format!("Set meta-data {}", key.value()), key.span());
+        step.label = Some(syn::Expr::Lit(syn::ExprLit {
+            attrs: Vec::new(),
+            lit: syn::Lit::Str(label),
+        }));
+        Self::parse_command_methods(input, step)
+    }
+
+    /// Parse a `plugin_step("name", { ... })` step: a command step with no
+    /// command, run entirely by its plugin. Command step methods can follow,
+    /// e.g. `.key("app").plugin(...)`.
+    fn parse_plugin_step(input: ParseStream) -> Result<Self> {
+        let content;
+        syn::parenthesized!(content in input);
+        let name: LitStr = content.parse()?;
+        content.parse::<Token![,]>()?;
+        let config = NestedValue::parse(&content)?;
+
+        let mut step = CommandStepDef::new_empty();
+        step.push_plugin(
+            NestedValue::Object(vec![(name.value(), config)]),
+            name.span(),
+        );
+        Self::parse_command_methods(input, step)
+    }
 
+    /// Parse the `.method(...)` calls following a fluent command step.
+    fn parse_command_methods(input: ParseStream, mut step: CommandStepDef) -> Result<Self> {
+        let mut explicit_retry = false;
+        let mut retry_preset = false;
+        let mut skip_condition = false;
+        let mut agents_span = None;
+        let mut queue_span = None;
         while input.peek(Token![.]) {
             input.parse::<Token![.]>()?;
             let method: Ident = input.parse()?;
@@ -1613,25 +4319,32 @@ impl StepDef {
 
             match strip_raw_ident(&method.to_string()) {
                 "label" => {
-                    step.label = Some(args.parse()?);
+                    let (label, origin) = parse_label(&args)?;
+                    step.label = Some(label);
+                    step.label_origin = origin;
                 }
                 "key" => {
                     step.key = Some(KeyValue::parse(&args)?);
                 }
+                "description" => {
+                    let text: LitStr = args.parse()?;
+                    step.description = Some(text.value());
+                }
                 "depends_on" => {
                     let dep: LitStr = args.parse()?;
                     step.depends_on.push((dep.value(), dep.span()));
                 }
                 "env" => {
-                    let var_name: Ident = args.parse()?;
+                    let var_name = EnvKey::parse(&args)?;
                     args.parse::<Token![,]>()?;
                     let var_value = DynamicValue::parse(&args)?;
                     step.env.push((var_name.to_string(), var_value));
+                    step.env_spans.push(var_name.span());
                 }
                 "command" => {
                     // Add another command to the step
                     let ident: Ident = args.parse()?;
-                    if ident == "cmd" || ident == "bazel" {
+                    if ident == "cmd" || ident == "unchecked_cmd" || ident == "bazel" {
                         args.parse::<Token![!]>()?;
                         let cmd_content;
                         syn::parenthesized!(cmd_content in args);
@@ -1639,7 +4352,11 @@ impl StepDef {
                             Error::new(cmd_content.span(), "cmd! requires a string literal")
                         })?;
                         if ident == "cmd" {
-                            let cmd_expr = CmdExpr::from_lit_str(&lit)?;
+                            let mut cmd_expr = CmdExpr::parse_lit_str(&lit)?;
+                            cmd_expr.parse_allow(&cmd_content)?;
+                            step.commands.push(CommandValue::from_cmd(cmd_expr));
+                        } else if ident == "unchecked_cmd" {
+                            let cmd_expr = CmdExpr::unchecked_from_lit_str(&lit)?;
                             step.commands.push(CommandValue::from_cmd(cmd_expr));
                         } else {
                             #[cfg(feature = "bazel")]
@@ -1655,6 +4372,9 @@ impl StepDef {
                                 ));
                             }
                         }
+                    } else if CommandValue::is_runtime_macro(&ident) {
+                        step.commands
+                            .push(CommandValue::parse_runtime(&ident, &args)?);
                     } else {
                         return Err(Error::new(
                             ident.span(),
@@ -1663,75 +4383,91 @@ impl StepDef {
                     }
                 }
                 "timeout_in_minutes" => {
-                    let timeout: syn::LitInt = args.parse()?;
-                    step.timeout_in_minutes = Some(timeout);
+                    step.timeout_in_minutes = Some(parse_timeout(&args)?);
                 }
                 "soft_fail" => {
                     step.soft_fail = true;
                 }
+                "bep" => {
+                    let val: syn::LitBool = args.parse()?;
+                    step.bep = val.value();
+                }
                 "parallelism" => {
-                    let p: syn::LitInt = args.parse()?;
-                    step.parallelism = Some(p);
+                    step.parallelism = Some(parse_parallelism(&args)?);
                 }
                 "artifact_paths" => {
                     let path: LitStr = args.parse()?;
                     step.artifact_paths.push(path);
                 }
                 "agents" => {
-                    if args.peek(syn::token::Brace) {
-                        let agents_content;
-                        braced!(agents_content in args);
-                        while !agents_content.is_empty() {
-                            let agent_key: Ident = agents_content.parse()?;
-                            agents_content.parse::<Token![:]>()?;
-                            let agent_value: LitStr = agents_content.parse()?;
-                            step.agents.push((agent_key.to_string(), agent_value));
-                            if agents_content.peek(Token![,]) {
-                                agents_content.parse::<Token![,]>()?;
-                            }
-                        }
+                    if queue_span.is_some() {
+                        return Err(queue_conflict(method.span()));
+                    }
+                    agents_span = Some(method.span());
+                    if args.peek(syn::token::Brace) || args.peek(syn::token::Bracket) {
+                        let (tags, list) = parse_agents(&args)?;
+                        step.agents.extend(tags);
+                        step.agents_list = list;
                     } else {
-                        let agent_key: Ident = args.parse()?;
+                        let agent_key = parse_agent_key(&args)?;
                         args.parse::<Token![,]>()?;
                         let agent_value: LitStr = args.parse()?;
-                        step.agents.push((agent_key.to_string(), agent_value));
+                        step.agents.push((agent_key, agent_value));
                     }
                 }
+                "queue" => {
+                    step.agents
+                        .push(parse_queue(&args, method.span(), agents_span)?);
+                    queue_span = Some(method.span());
+                }
                 "branches" => {
                     let branch: LitStr = args.parse()?;
                     step.branches.push(branch);
                 }
+                "if" if skip_condition => {
+                    return Err(Error::new(
+                        method.span(),
+                        conditions::SKIP_CONDITION_CONFLICT,
+                    ));
+                }
+                "skip_if" | "skip_unless" if step.if_condition.is_some() => {
+                    return Err(Error::new(
+                        method.span(),
+                        conditions::SKIP_CONDITION_CONFLICT,
+                    ));
+                }
                 "if" => {
-                    let condition: LitStr = args.parse()?;
-                    if let Err(errors) =
-                        buildkite_conditional::validate_condition(&condition.value())
-                    {
-                        return Err(Error::new(
-                            condition.span(),
-                            format!("Invalid Buildkite conditional: {}", errors.join("; ")),
-                        ));
-                    }
+                    let condition = conditions::parse(&args)?;
                     step.if_condition = Some(condition);
                 }
+                "skip_if" | "skip_unless" => {
+                    skip_condition = true;
+                    step.if_condition = Some(conditions::parse_skip(&args, method == "skip_if")?);
+                }
                 "cache" => {
                     let path: LitStr = args.parse()?;
                     step.cache.push(path);
                 }
+                "retry" | "retry_automatic" if retry_preset => {
+                    return Err(Error::new(method.span(), RETRY_PRESET_CONFLICT));
+                }
+                "retry_on_agent_loss" | "retry_flaky" if explicit_retry => {
+                    return Err(Error::new(method.span(), RETRY_PRESET_CONFLICT));
+                }
+                "retry_on_agent_loss" => {
+                    retry_preset = true;
+                    RetryPreset::AgentLoss.apply(&mut step.retry);
+                }
+                "retry_flaky" => {
+                    retry_preset = true;
+                    RetryPreset::flaky(&args)?.apply(&mut step.retry);
+                }
                 "retry" => {
-                    let retry_value = NestedValue::parse(&args)?;
-                    if let NestedValue::Object(pairs) = retry_value {
-                        let mut config = RetryConfig::default();
-                        for (k, v) in pairs {
-                            match k.as_str() {
-                                "automatic" => config.automatic = Some(v),
-                                "manual" => config.manual = Some(v),
-                                _ => {}
-                            }
-                        }
-                        step.retry = Some(config);
-                    }
+                    explicit_retry = true;
+                    step.retry = Some(RetryConfig::parse(&args)?);
                 }
                 "retry_automatic" => {
+                    explicit_retry = true;
                     let limit: syn::LitInt = args.parse()?;
                     let limit_val: i64 = limit.base10_parse()?;
                     let config = RetryConfig {
@@ -1747,8 +4483,19 @@ impl StepDef {
                     let name: LitStr = args.parse()?;
                     args.parse::<Token![,]>()?;
                     let config = NestedValue::parse(&args)?;
-                    step.plugins
-                        .push(NestedValue::Object(vec![(name.value(), config)]));
+                    step.push_plugin(
+                        NestedValue::Object(vec![(name.value(), config)]),
+                        name.span(),
+                    );
+                }
+                "plugin_yaml" => {
+                    let span = args.span();
+                    step.push_plugin(plugins::parse_plugin(&args)?, span);
+                }
+                "docker_compose" => {
+                    let (plugin, check) = compose::parse(&args, method.span(), step.plugins.len())?;
+                    step.plugins.push(plugin);
+                    step.compose_checks.push(check);
                 }
                 "notify_slack" => {
                     let channel: LitStr = args.parse()?;
@@ -1758,7 +4505,7 @@ impl StepDef {
                     )]));
                 }
                 "matrix" => {
-                    let matrix_value = NestedValue::parse(&args)?;
+                    let matrix_value = NestedValue::parse_field(&args, "matrix")?;
                     step.matrix = Some(matrix_value);
                 }
                 "concurrency" => {
@@ -1766,15 +4513,13 @@ impl StepDef {
                     step.concurrency = Some(c);
                 }
                 "concurrency_group" => {
-                    let group: LitStr = args.parse()?;
-                    step.concurrency_group = Some(group);
+                    step.concurrency_group = Some(parse_concurrency_group(&args)?);
                 }
                 "skip" => {
                     if args.is_empty() {
                         step.skip = Some(SkipValue::Bool(true));
                     } else {
-                        let reason: LitStr = args.parse()?;
-                        step.skip = Some(SkipValue::Reason(reason.value()));
+                        step.skip = Some(parse_skip_reason(&args)?);
                     }
                 }
                 "priority" => {
@@ -1784,10 +4529,53 @@ impl StepDef {
                 "allow_dependency_failure" => {
                     step.allow_dependency_failure = true;
                 }
+                "skip_validation" => {
+                    parse_skip_validation_reason(&args, method.span())?;
+                    step.skip_validation = true;
+                }
+                "shell" => step.shell = Some(StepShell::parse(&args)?),
+                "needs_artifacts" => {
+                    let key: LitStr = args.parse()?;
+                    args.parse::<Token![,]>()?;
+                    let glob: LitStr = args.parse()?;
+                    if glob.value().is_empty() {
+                        return Err(Error::new(
+                            glob.span(),
+                            "needs_artifacts requires a non-empty artifact path or glob",
+                        ));
+                    }
+                    let need = ArtifactNeed {
+                        key: key.value(),
+                        glob: glob.value(),
+                        span: method.span(),
+                    };
+                    let download = CmdExpr::artifact_download(&need)?;
+                    let position = step.needs_artifacts.len();
+                    step.commands
+                        .insert(position, CommandValue::from_cmd(download));
+                    if !step.depends_on.iter().any(|(dep, _)| *dep == need.key) {
+                        step.depends_on.push((need.key.clone(), method.span()));
+                    }
+                    step.needs_artifacts.push(need);
+                }
+                "only_profiles" => {
+                    step.only_profiles = parse_only_profiles(&args)?;
+                }
+                "set_metadata" => {
+                    let key: LitStr = args.parse()?;
+                    args.parse::<Token![,]>()?;
+                    let value = DynamicValue::parse(&args)?;
+                    let cmd_expr = CmdExpr::metadata_set(&key, &value)?;
+                    step.commands.push(CommandValue::from_cmd(cmd_expr));
+                }
                 other => {
                     return Err(Error::new(
                         method.span(),
-                        format!("unknown command step method: {}", other),
+                        fields::unknown(
+                            "command step method",
+                            other,
+                            fields::COMMAND_METHODS.iter().copied(),
+                        ),
                     ));
                 }
             }
@@ -1805,6 +4593,8 @@ impl StepDef {
         braced!(content in input);
 
         let mut step = CommandStepDef::new_empty();
+        let mut agents_span = None;
+        let mut queue_span = None;
         #[cfg(feature = "bazel")]
         let mut step_custom_verbs: Vec<String> = Vec::new();
         #[cfg(feature = "bazel")]
@@ -1814,13 +4604,13 @@ impl StepDef {
 
         while !content.is_empty() {
             let field: Ident = content.parse()?;
-            content.parse::<Token![:]>()?;
+            let shorthand = parse_field_colon(&content, &field)?;
 
             match strip_raw_ident(&field.to_string()) {
                 "command" => {
                     if content.peek(Ident) {
                         let ident: Ident = content.parse()?;
-                        if ident == "cmd" || ident == "bazel" {
+                        if ident == "cmd" || ident == "unchecked_cmd" || ident == "bazel" {
                             content.parse::<Token![!]>()?;
                             let cmd_content;
                             syn::parenthesized!(cmd_content in content);
@@ -1831,7 +4621,11 @@ impl StepDef {
                                 )
                             })?;
                             if ident == "cmd" {
-                                let cmd_expr = CmdExpr::from_lit_str(&lit)?;
+                                let mut cmd_expr = CmdExpr::parse_lit_str(&lit)?;
+                                cmd_expr.parse_allow(&cmd_content)?;
+                                step.commands.push(CommandValue::from_cmd(cmd_expr));
+                            } else if ident == "unchecked_cmd" {
+                                let cmd_expr = CmdExpr::unchecked_from_lit_str(&lit)?;
                                 step.commands.push(CommandValue::from_cmd(cmd_expr));
                             } else {
                                 #[cfg(feature = "bazel")]
@@ -1848,6 +4642,16 @@ impl StepDef {
                                     ));
                                 }
                             }
+                        } else if cargo::is_helper(&ident) {
+                            content.parse::<Token![!]>()?;
+                            let args;
+                            syn::parenthesized!(args in content);
+                            let lit = cargo::parse(&ident, &args)?;
+                            step.commands
+                                .push(CommandValue::from_cmd(CmdExpr::parse_lit_str(&lit)?));
+                        } else if CommandValue::is_runtime_macro(&ident) {
+                            step.commands
+                                .push(CommandValue::parse_runtime(&ident, &content)?);
                         } else {
                             return Err(Error::new(
                                 ident.span(),
@@ -1875,7 +4679,10 @@ impl StepDef {
                         if cmds_content.peek(Ident) {
                             let ident: Ident = cmds_content.parse()?;
                             let ident_str = ident.to_string();
-                            if ident_str == "cmd" || ident_str == "bazel" {
+                            if ident_str == "cmd"
+                                || ident_str == "unchecked_cmd"
+                                || ident_str == "bazel"
+                            {
                                 cmds_content.parse::<Token![!]>()?;
                                 let cmd_content;
                                 syn::parenthesized!(cmd_content in cmds_content);
@@ -1883,7 +4690,11 @@ impl StepDef {
                                     Error::new(cmd_content.span(), "cmd! requires a string literal")
                                 })?;
                                 if ident_str == "cmd" {
-                                    let cmd_expr = CmdExpr::from_lit_str(&lit)?;
+                                    let mut cmd_expr = CmdExpr::parse_lit_str(&lit)?;
+                                    cmd_expr.parse_allow(&cmd_content)?;
+                                    step.commands.push(CommandValue::from_cmd(cmd_expr));
+                                } else if ident_str == "unchecked_cmd" {
+                                    let cmd_expr = CmdExpr::unchecked_from_lit_str(&lit)?;
                                     step.commands.push(CommandValue::from_cmd(cmd_expr));
                                 } else {
                                     #[cfg(feature = "bazel")]
@@ -1923,6 +4734,16 @@ impl StepDef {
                                         "bazel_* commands require the 'bazel' feature. Add `features = [\"bazel\"]` to your dependency.",
                                     ));
                                 }
+                            } else if cargo::is_helper(&ident) {
+                                cmds_content.parse::<Token![!]>()?;
+                                let args;
+                                syn::parenthesized!(args in cmds_content);
+                                let lit = cargo::parse(&ident, &args)?;
+                                step.commands
+                                    .push(CommandValue::from_cmd(CmdExpr::parse_lit_str(&lit)?));
+                            } else if CommandValue::is_runtime_macro(&ident) {
+                                step.commands
+                                    .push(CommandValue::parse_runtime(&ident, &cmds_content)?);
                             } else {
                                 return Err(Error::new(
                                     ident.span(),
@@ -1944,19 +4765,26 @@ impl StepDef {
                     }
                 }
                 "label" => {
-                    step.label = Some(content.parse()?);
+                    let (label, origin) = parse_label(&content)?;
+                    step.label = Some(label);
+                    step.label_origin = origin;
                 }
                 "key" => {
                     step.key = Some(KeyValue::parse(&content)?);
                 }
+                "description" => {
+                    let text: LitStr = content.parse()?;
+                    step.description = Some(text.value());
+                }
                 "env" => {
                     let env_content;
                     braced!(env_content in content);
                     while !env_content.is_empty() {
-                        let var_name: Ident = env_content.parse()?;
+                        let var_name = EnvKey::parse(&env_content)?;
                         env_content.parse::<Token![:]>()?;
                         let var_value = DynamicValue::parse(&env_content)?;
                         step.env.push((var_name.to_string(), var_value));
+                        step.env_spans.push(var_name.span());
                         if env_content.peek(Token![,]) {
                             env_content.parse::<Token![,]>()?;
                         }
@@ -1974,16 +4802,17 @@ impl StepDef {
                     }
                 }
                 "timeout_in_minutes" => {
-                    let timeout: syn::LitInt = content.parse()?;
-                    step.timeout_in_minutes = Some(timeout);
+                    step.timeout_in_minutes = Some(parse_timeout(&content)?);
                 }
                 "soft_fail" => {
+                    step.soft_fail = parse_flag_value(&content, shorthand)?;
+                }
+                "bep" => {
                     let val: syn::LitBool = content.parse()?;
-                    step.soft_fail = val.value();
+                    step.bep = val.value();
                 }
                 "parallelism" => {
-                    let p: syn::LitInt = content.parse()?;
-                    step.parallelism = Some(p);
+                    step.parallelism = Some(parse_parallelism(&content)?);
                 }
                 "artifact_paths" => {
                     let paths_content;
@@ -1997,17 +4826,18 @@ impl StepDef {
                     }
                 }
                 "agents" => {
-                    let agents_content;
-                    braced!(agents_content in content);
-                    while !agents_content.is_empty() {
-                        let agent_key: Ident = agents_content.parse()?;
-                        agents_content.parse::<Token![:]>()?;
-                        let agent_value: LitStr = agents_content.parse()?;
-                        step.agents.push((agent_key.to_string(), agent_value));
-                        if agents_content.peek(Token![,]) {
-                            agents_content.parse::<Token![,]>()?;
-                        }
+                    if queue_span.is_some() {
+                        return Err(queue_conflict(field.span()));
                     }
+                    let (tags, list) = parse_agents(&content)?;
+                    step.agents.extend(tags);
+                    step.agents_list = list;
+                    agents_span = Some(field.span());
+                }
+                "queue" => {
+                    step.agents
+                        .push(parse_queue(&content, field.span(), agents_span)?);
+                    queue_span = Some(field.span());
                 }
                 "branches" => {
                     let branches_content;
@@ -2021,15 +4851,7 @@ impl StepDef {
                     }
                 }
                 "condition" | "if" => {
-                    let condition: LitStr = content.parse()?;
-                    if let Err(errors) =
-                        buildkite_conditional::validate_condition(&condition.value())
-                    {
-                        return Err(Error::new(
-                            condition.span(),
-                            format!("Invalid Buildkite conditional: {}", errors.join("; ")),
-                        ));
-                    }
+                    let condition = conditions::parse(&content)?;
                     step.if_condition = Some(condition);
                 }
                 "cache" => {
@@ -2044,35 +4866,30 @@ impl StepDef {
                     }
                 }
                 "retry" => {
-                    let retry_value = NestedValue::parse(&content)?;
-                    if let NestedValue::Object(pairs) = retry_value {
-                        let mut config = RetryConfig::default();
-                        for (k, v) in pairs {
-                            match k.as_str() {
-                                "automatic" => config.automatic = Some(v),
-                                "manual" => config.manual = Some(v),
-                                _ => {}
-                            }
-                        }
-                        step.retry = Some(config);
-                    }
+                    step.retry = Some(RetryConfig::parse(&content)?);
                 }
                 "plugins" => {
                     let plugins_content;
                     bracketed!(plugins_content in content);
                     while !plugins_content.is_empty() {
+                        let span = plugins::key_span(&plugins_content);
                         let plugin = NestedValue::parse(&plugins_content)?;
-                        step.plugins.push(plugin);
+                        step.push_plugin(plugin, span);
                         if plugins_content.peek(Token![,]) {
                             plugins_content.parse::<Token![,]>()?;
                         }
                     }
                 }
+                "plugins_yaml" => {
+                    for (plugin, span) in plugins::parse_plugin_list(&content)? {
+                        step.push_plugin(plugin, span);
+                    }
+                }
                 "notify" => {
                     let notify_content;
                     bracketed!(notify_content in content);
                     while !notify_content.is_empty() {
-                        let notification = NestedValue::parse(&notify_content)?;
+                        let notification = NestedValue::parse_field(&notify_content, "notify")?;
                         step.notify.push(notification);
                         if notify_content.peek(Token![,]) {
                             notify_content.parse::<Token![,]>()?;
@@ -2080,7 +4897,7 @@ impl StepDef {
                     }
                 }
                 "matrix" => {
-                    let matrix_value = NestedValue::parse(&content)?;
+                    let matrix_value = NestedValue::parse_field(&content, "matrix")?;
                     step.matrix = Some(matrix_value);
                 }
                 "concurrency" => {
@@ -2088,16 +4905,14 @@ impl StepDef {
                     step.concurrency = Some(c);
                 }
                 "concurrency_group" => {
-                    let group: LitStr = content.parse()?;
-                    step.concurrency_group = Some(group);
+                    step.concurrency_group = Some(parse_concurrency_group(&content)?);
                 }
                 "skip" => {
                     if content.peek(syn::LitBool) {
                         let val: syn::LitBool = content.parse()?;
                         step.skip = Some(SkipValue::Bool(val.value()));
                     } else {
-                        let reason: LitStr = content.parse()?;
-                        step.skip = Some(SkipValue::Reason(reason.value()));
+                        step.skip = Some(parse_skip_reason(&content)?);
                     }
                 }
                 "priority" => {
@@ -2105,8 +4920,15 @@ impl StepDef {
                     step.priority = Some(p);
                 }
                 "allow_dependency_failure" => {
-                    let val: syn::LitBool = content.parse()?;
-                    step.allow_dependency_failure = val.value();
+                    step.allow_dependency_failure = parse_flag_value(&content, shorthand)?;
+                }
+                "skip_validation" => {
+                    parse_skip_validation_reason(&content, field.span())?;
+                    step.skip_validation = true;
+                }
+                "shell" => step.shell = Some(StepShell::parse(&content)?),
+                "only_profiles" => {
+                    step.only_profiles = parse_only_profiles(&content)?;
                 }
                 #[cfg(feature = "bazel")]
                 "custom_verbs" => {
@@ -2131,9 +4953,18 @@ impl StepDef {
                     dry_run = val.value();
                 }
                 other => {
+                    let bazel_fields: &[&str] = if cfg!(feature = "bazel") {
+                        fields::COMMAND_BAZEL_FIELDS
+                    } else {
+                        &[]
+                    };
                     return Err(Error::new(
                         field.span(),
-                        format!("unknown command step field: {}", other),
+                        fields::unknown(
+                            "command step field",
+                            other,
+                            fields::COMMAND_FIELDS.iter().chain(bazel_fields).copied(),
+                        ),
                     ));
                 }
             }
@@ -2141,10 +4972,11 @@ impl StepDef {
                 content.parse::<Token![,]>()?;
             }
         }
-        if step.commands.is_empty() {
+        if step.commands.is_empty() && step.plugins.is_empty() {
             return Err(Error::new(
                 input.span(),
-                "command step requires 'command' or 'commands' field",
+                "command step requires 'command' or 'commands' field, unless it runs plugins: \
+                 a step with `plugins: [...]` and no command is run by its plugins alone",
             ));
         }
 
@@ -2175,6 +5007,22 @@ impl StepDef {
         Ok(())
     }
 
+    /// Check `target_patterns:`, which query verbs treat as a query expression
+    /// and `run` requires to be a single target. Unknown query functions are
+    /// reported as warnings by `BazelExpr`.
+    #[cfg(feature = "bazel")]
+    fn check_target_patterns(pattern: &str, verb: &str) -> std::result::Result<(), String> {
+        if bazel::is_query_verb(verb) {
+            return bazel::validate_query_expression(pattern).map(|_| ());
+        }
+        Self::validate_target_patterns(pattern)?;
+        if verb == "run" {
+            let words: Vec<&str> = pattern.split_whitespace().collect();
+            targets::validate_run_target(&words)?;
+        }
+        Ok(())
+    }
+
     #[cfg(feature = "bazel")]
     fn parse_bazel_command_step(
         input: ParseStream,
@@ -2186,19 +5034,28 @@ impl StepDef {
         braced!(content in input);
 
         let mut step = CommandStepDef::new_empty();
+        let mut agents_span = None;
+        let mut queue_span = None;
         let mut verb: Option<String> = fixed_verb.map(|s| s.to_string());
         let mut target_patterns: Option<DynamicValue> = None;
         let mut target_patterns_span: Option<proc_macro2::Span> = None;
         let mut flags_value: Option<DynamicValue> = None;
+        let mut flag_literals: Vec<LitStr> = Vec::new();
         let mut extra_flags: Vec<String> = Vec::new();
         let mut args: Vec<DynamicValue> = Vec::new();
+        let mut spliced: Vec<shell::Spliced> = Vec::new();
         let mut validate_targets = true;
         let mut dry_run = false;
         let mut step_custom_verbs: Vec<String> = Vec::new();
+        let mut shards: Option<syn::LitInt> = None;
+        let mut workspace: Option<String> = None;
+        let mut startup: Vec<String> = Vec::new();
+        let mut explicit_retry = false;
+        let mut retry_preset = false;
 
         while !content.is_empty() {
             let field: Ident = content.parse()?;
-            content.parse::<Token![:]>()?;
+            let shorthand = parse_field_colon(&content, &field)?;
 
             match strip_raw_ident(&field.to_string()) {
                 "verb" => {
@@ -2213,7 +5070,8 @@ impl StepDef {
                 }
                 "target_patterns" => {
                     target_patterns_span = Some(field.span());
-                    target_patterns = Some(DynamicValue::parse(&content)?);
+                    target_patterns =
+                        Some(DynamicValue::parse_spliced(&content, true, &mut spliced)?);
                 }
                 "flags" => {
                     if content.peek(syn::token::Bracket) {
@@ -2223,6 +5081,7 @@ impl StepDef {
                         while !flags_content.is_empty() {
                             let flag: LitStr = flags_content.parse()?;
                             flag_parts.push(flag.value());
+                            flag_literals.push(flag);
                             if flags_content.peek(Token![,]) {
                                 flags_content.parse::<Token![,]>()?;
                             }
@@ -2230,7 +5089,8 @@ impl StepDef {
                         let joined = flag_parts.join(" ");
                         flags_value = Some(DynamicValue::Literal(joined));
                     } else {
-                        flags_value = Some(DynamicValue::parse(&content)?);
+                        flags_value =
+                            Some(DynamicValue::parse_spliced(&content, false, &mut spliced)?);
                     }
                 }
                 "config" => {
@@ -2297,19 +5157,34 @@ impl StepDef {
                     let val: syn::LitBool = content.parse()?;
                     dry_run = val.value();
                 }
+                "startup_options" => {
+                    startup = BazelExpr::parse_startup_options(&content)?;
+                }
+                "workspace" => {
+                    let dir: LitStr = content.parse()?;
+                    workspace = Some(parse_workspace_dir(&dir, "workspace")?);
+                }
+                "shards" => {
+                    let count: syn::LitInt = content.parse()?;
+                    if count.base10_parse::<usize>()? < 2 {
+                        return Err(Error::new(count.span(), "shards must be at least 2"));
+                    }
+                    shards = Some(count);
+                }
                 "args" => {
                     if content.peek(syn::token::Bracket) {
                         let args_content;
                         bracketed!(args_content in content);
                         while !args_content.is_empty() {
-                            let arg = DynamicValue::parse(&args_content)?;
+                            let arg =
+                                DynamicValue::parse_spliced(&args_content, false, &mut spliced)?;
                             args.push(arg);
                             if args_content.peek(Token![,]) {
                                 args_content.parse::<Token![,]>()?;
                             }
                         }
                     } else {
-                        let arg = DynamicValue::parse(&content)?;
+                        let arg = DynamicValue::parse_spliced(&content, false, &mut spliced)?;
                         args.push(arg);
                     }
                 }
@@ -2325,19 +5200,26 @@ impl StepDef {
                     }
                 }
                 "label" => {
-                    step.label = Some(content.parse()?);
+                    let (label, origin) = parse_label(&content)?;
+                    step.label = Some(label);
+                    step.label_origin = origin;
                 }
                 "key" => {
                     step.key = Some(KeyValue::parse(&content)?);
                 }
+                "description" => {
+                    let text: LitStr = content.parse()?;
+                    step.description = Some(text.value());
+                }
                 "env" => {
                     let env_content;
                     braced!(env_content in content);
                     while !env_content.is_empty() {
-                        let var_name: Ident = env_content.parse()?;
+                        let var_name = EnvKey::parse(&env_content)?;
                         env_content.parse::<Token![:]>()?;
                         let var_value = DynamicValue::parse(&env_content)?;
                         step.env.push((var_name.to_string(), var_value));
+                        step.env_spans.push(var_name.span());
                         if env_content.peek(Token![,]) {
                             env_content.parse::<Token![,]>()?;
                         }
@@ -2355,16 +5237,17 @@ impl StepDef {
                     }
                 }
                 "timeout_in_minutes" => {
-                    let timeout: syn::LitInt = content.parse()?;
-                    step.timeout_in_minutes = Some(timeout);
+                    step.timeout_in_minutes = Some(parse_timeout(&content)?);
                 }
                 "soft_fail" => {
+                    step.soft_fail = parse_flag_value(&content, shorthand)?;
+                }
+                "bep" => {
                     let val: syn::LitBool = content.parse()?;
-                    step.soft_fail = val.value();
+                    step.bep = val.value();
                 }
                 "parallelism" => {
-                    let p: syn::LitInt = content.parse()?;
-                    step.parallelism = Some(p);
+                    step.parallelism = Some(parse_parallelism(&content)?);
                 }
                 "artifact_paths" => {
                     let paths_content;
@@ -2378,17 +5261,18 @@ impl StepDef {
                     }
                 }
                 "agents" => {
-                    let agents_content;
-                    braced!(agents_content in content);
-                    while !agents_content.is_empty() {
-                        let agent_key: Ident = agents_content.parse()?;
-                        agents_content.parse::<Token![:]>()?;
-                        let agent_value: LitStr = agents_content.parse()?;
-                        step.agents.push((agent_key.to_string(), agent_value));
-                        if agents_content.peek(Token![,]) {
-                            agents_content.parse::<Token![,]>()?;
-                        }
+                    if queue_span.is_some() {
+                        return Err(queue_conflict(field.span()));
                     }
+                    let (tags, list) = parse_agents(&content)?;
+                    step.agents.extend(tags);
+                    step.agents_list = list;
+                    agents_span = Some(field.span());
+                }
+                "queue" => {
+                    step.agents
+                        .push(parse_queue(&content, field.span(), agents_span)?);
+                    queue_span = Some(field.span());
                 }
                 "branches" => {
                     let branches_content;
@@ -2402,51 +5286,72 @@ impl StepDef {
                     }
                 }
                 "condition" | "if" => {
-                    let condition: LitStr = content.parse()?;
+                    let condition = conditions::parse(&content)?;
                     step.if_condition = Some(condition);
                 }
-                "retry" => {
-                    let retry_value = NestedValue::parse(&content)?;
-                    if let NestedValue::Object(pairs) = retry_value {
-                        let mut config = RetryConfig::default();
-                        for (k, v) in pairs {
-                            match k.as_str() {
-                                "automatic" => config.automatic = Some(v),
-                                "manual" => config.manual = Some(v),
-                                _ => {}
+                "retry" if retry_preset => {
+                    return Err(Error::new(field.span(), RETRY_PRESET_CONFLICT));
+                }
+                "retry_preset" if explicit_retry => {
+                    return Err(Error::new(field.span(), RETRY_PRESET_CONFLICT));
+                }
+                "retry_preset" => {
+                    retry_preset = true;
+                    if content.peek(syn::token::Bracket) {
+                        let presets_content;
+                        bracketed!(presets_content in content);
+                        while !presets_content.is_empty() {
+                            let name: LitStr = presets_content.parse()?;
+                            RetryPreset::from_lit(&name)?.apply(&mut step.retry);
+                            if presets_content.peek(Token![,]) {
+                                presets_content.parse::<Token![,]>()?;
                             }
                         }
-                        step.retry = Some(config);
+                    } else {
+                        let name: LitStr = content.parse()?;
+                        RetryPreset::from_lit(&name)?.apply(&mut step.retry);
                     }
                 }
+                "retry" => {
+                    explicit_retry = true;
+                    step.retry = Some(RetryConfig::parse(&content)?);
+                }
                 "skip" => {
                     if content.peek(syn::LitBool) {
                         let val: syn::LitBool = content.parse()?;
                         step.skip = Some(SkipValue::Bool(val.value()));
                     } else {
-                        let reason: LitStr = content.parse()?;
-                        step.skip = Some(SkipValue::Reason(reason.value()));
+                        step.skip = Some(parse_skip_reason(&content)?);
                     }
                 }
                 "allow_dependency_failure" => {
-                    let val: syn::LitBool = content.parse()?;
-                    step.allow_dependency_failure = val.value();
+                    step.allow_dependency_failure = parse_flag_value(&content, shorthand)?;
                 }
                 "plugins" => {
                     let plugins_content;
                     bracketed!(plugins_content in content);
                     while !plugins_content.is_empty() {
+                        let span = plugins::key_span(&plugins_content);
                         let plugin = NestedValue::parse(&plugins_content)?;
-                        step.plugins.push(plugin);
+                        step.push_plugin(plugin, span);
                         if plugins_content.peek(Token![,]) {
                             plugins_content.parse::<Token![,]>()?;
                         }
                     }
                 }
+                "plugins_yaml" => {
+                    for (plugin, span) in plugins::parse_plugin_list(&content)? {
+                        step.push_plugin(plugin, span);
+                    }
+                }
                 other => {
                     return Err(Error::new(
                         field.span(),
-                        format!("unknown bazel step field: {}", other),
+                        fields::unknown(
+                            "bazel step field",
+                            other,
+                            fields::BAZEL_STEP_FIELDS.iter().copied(),
+                        ),
                     ));
                 }
             }
@@ -2457,13 +5362,36 @@ impl StepDef {
 
         let verb =
             verb.ok_or_else(|| Error::new(step_span, "bazel_command requires 'verb' field"))?;
+        if !pipeline_custom_verbs.contains(&verb) && !step_custom_verbs.contains(&verb) {
+            BazelExpr::check_flag_literals(&verb, &flag_literals)?;
+        }
+        let _workspace = bazel_defaults::step_workspace(workspace);
+        if let Some(count) = &shards
+            && verb != "test"
+        {
+            return Err(Error::new(
+                count.span(),
+                format!("shards only applies to bazel_test, not `bazel {}`", verb),
+            ));
+        }
 
         let has_dynamic = target_patterns.as_ref().is_some_and(|t| t.is_dynamic())
             || flags_value.as_ref().is_some_and(|f| f.is_dynamic())
             || args.iter().any(|a| a.is_dynamic());
 
+        if let Some(count) = &shards
+            && has_dynamic
+        {
+            return Err(Error::new(
+                count.span(),
+                "shards needs literal target_patterns, flags and args so the test targets can be partitioned at compile time",
+            ));
+        }
+
         if has_dynamic {
+            Self::check_spliced(&spliced, &verb, None)?;
             step.commands.push(CommandValue::from_dynamic_bazel(
+                startup,
                 verb.clone(),
                 flags_value,
                 extra_flags.clone(),
@@ -2471,15 +5399,25 @@ impl StepDef {
                 args,
             ));
         } else {
-            let target_str = target_patterns
+            let mut target_str = target_patterns
                 .as_ref()
                 .and_then(|t| t.as_literal())
                 .map(|s| s.to_string());
+            if verb == "run"
+                && let Some(t) = &target_str
+            {
+                let (patterns, program_args) = targets::split_run_args(t);
+                if !program_args.is_empty() {
+                    args.splice(0..0, program_args.into_iter().map(DynamicValue::Literal));
+                    target_str = Some(patterns);
+                }
+            }
 
+            let is_query = bazel::is_query_verb(&verb);
             if let Some(ref t) = target_str
                 && validate_targets
                 && !t.is_empty()
-                && let Err(e) = Self::validate_target_patterns(t)
+                && let Err(e) = Self::check_target_patterns(t, &verb)
             {
                 return Err(Error::new(target_patterns_span.unwrap_or(step_span), e));
             }
@@ -2487,6 +5425,7 @@ impl StepDef {
             if validate_targets
                 && let Some(ref t) = target_str
                 && !t.is_empty()
+                && Self::validate_target_patterns(t).is_ok()
                 && let Ok((workspace, script_dir)) = bazel::find_bazel_workspace_and_script_dir()
             {
                 let current_pkg = targets::get_current_package(&workspace, &script_dir);
@@ -2498,48 +5437,74 @@ impl StepDef {
                 }
             }
 
-            let has_subtraction = target_str.as_ref().is_some_and(|t| {
-                t.split_whitespace()
-                    .any(|p| p.starts_with("-//") || p.starts_with("-@") || p.starts_with("-:"))
-            });
-
             let flags_have_separator = flags_value
                 .as_ref()
                 .and_then(|fv| fv.as_literal())
                 .is_some_and(|s| s.split_whitespace().any(|f| f == "--"))
                 || extra_flags.iter().any(|f| f == "--");
 
-            let mut cmd_parts = vec![verb.clone()];
+            let command_for = |targets: Option<&str>| {
+                let mut cmd_parts = startup.clone();
+                cmd_parts.push(verb.clone());
 
-            if let Some(ref fv) = flags_value
-                && let Some(flags_str) = fv.as_literal()
-            {
-                for flag in flags_str.split_whitespace() {
-                    cmd_parts.push(flag.to_string());
+                if let Some(ref fv) = flags_value
+                    && let Some(flags_str) = fv.as_literal()
+                {
+                    for flag in flags_str.split_whitespace() {
+                        cmd_parts.push(flag.to_string());
+                    }
                 }
-            }
-            cmd_parts.extend(extra_flags.clone());
+                cmd_parts.extend(extra_flags.clone());
 
-            if let Some(ref t) = target_str {
-                if has_subtraction && !flags_have_separator {
+                if let Some(t) = targets {
+                    let has_subtraction = t.split_whitespace().any(|p| {
+                        p.starts_with("-//") || p.starts_with("-@") || p.starts_with("-:")
+                    });
+                    if has_subtraction && !flags_have_separator {
+                        cmd_parts.push("--".to_string());
+                    }
+                    if is_query && Self::validate_target_patterns(t).is_err() {
+                        cmd_parts.push(BazelExpr::quote_query_expression(t));
+                    } else {
+                        cmd_parts.push(t.to_string());
+                    }
+                }
+
+                if !args.is_empty() {
                     cmd_parts.push("--".to_string());
+                    cmd_parts.extend(
+                        args.iter()
+                            .filter_map(|a| a.as_literal().map(|s| s.to_string())),
+                    );
                 }
-                cmd_parts.push(t.clone());
-            }
 
-            if !args.is_empty() {
-                cmd_parts.push("--".to_string());
-                cmd_parts.extend(
-                    args.iter()
-                        .filter_map(|a| a.as_literal().map(|s| s.to_string())),
-                );
-            }
+                cmd_parts.join(" ")
+            };
 
-            let bazel_cmd = cmd_parts.join(" ");
+            Self::check_spliced(&spliced, &verb, Some(&command_for(target_str.as_deref())))?;
 
-            let lit = LitStr::new(&bazel_cmd, step_span);
             let mut all_custom_verbs: Vec<String> = pipeline_custom_verbs.to_vec();
             all_custom_verbs.extend(step_custom_verbs);
+
+            if let Some(count) = shards {
+                let buckets = Self::shard_test_targets(target_str.as_deref(), &count)?;
+                let mut shard_steps = Vec::with_capacity(buckets.len());
+                for bucket in &buckets {
+                    let lit = LitStr::new(&command_for(Some(&bucket.join(" "))), step_span);
+                    let bazel_expr = BazelExpr::from_lit_str(
+                        &lit,
+                        validate_targets,
+                        dry_run,
+                        &all_custom_verbs,
+                    )?;
+                    let mut shard = step.clone();
+                    shard.commands.push(CommandValue::from_bazel(bazel_expr));
+                    shard_steps.push(shard);
+                }
+                return GroupStepDef::from_shards(step, shard_steps, &count).map(StepDef::Group);
+            }
+
+            let lit = LitStr::new(&command_for(target_str.as_deref()), step_span);
             let bazel_expr =
                 BazelExpr::from_lit_str(&lit, validate_targets, dry_run, &all_custom_verbs)?;
             step.commands.push(CommandValue::from_bazel(bazel_expr));
@@ -2548,6 +5513,72 @@ impl StepDef {
         Ok(StepDef::Command(step))
     }
 
+    /// Check `comptime_shell!` output spliced into a bazel command against
+    /// `command`, or against `bazel <verb> <output>` when the rest of the
+    /// command is only known at runtime.
+    #[cfg(feature = "bazel")]
+    fn check_spliced(spliced: &[shell::Spliced], verb: &str, command: Option<&str>) -> Result<()> {
+        let is_query = bazel::is_query_verb(verb);
+        for part in spliced {
+            let command = match command {
+                Some(command) => command.to_string(),
+                None if part.target && is_query => format!(
+                    "bazel {} {}",
+                    verb,
+                    BazelExpr::quote_query_expression(&part.output)
+                ),
+                None => format!("bazel {} {}", verb, part.output),
+            };
+            part.check(&command, is_query)?;
+        }
+        Ok(())
+    }
+
+    /// Query the test targets behind a sharded `bazel_test` and split them into
+    /// `count` buckets.
+    #[cfg(feature = "bazel")]
+    fn shard_test_targets(patterns: Option<&str>, count: &syn::LitInt) -> Result<Vec<Vec<String>>> {
+        let shards: usize = count.base10_parse()?;
+        let Some(patterns) = patterns.filter(|t| !t.trim().is_empty()) else {
+            return Err(Error::new(
+                count.span(),
+                "shards needs target_patterns to select the tests to split",
+            ));
+        };
+        let (workspace, script_dir) = bazel::find_bazel_workspace_and_script_dir().map_err(|e| {
+            Error::new(
+                count.span(),
+                format!(
+                    "shards needs a Bazel workspace to partition test targets at compile time: {}",
+                    e
+                ),
+            )
+        })?;
+        let current_pkg = targets::get_current_package(&workspace, &script_dir);
+        let binary = bazel_defaults::binary();
+        let tests =
+            bazel::query_test_targets(patterns, &workspace, &binary, current_pkg.as_deref())
+                .map_err(|e| {
+                    Error::new(
+                        count.span(),
+                        format!("shards could not query the test targets to split: {}", e),
+                    )
+                })?;
+        if tests.len() < shards {
+            return Err(Error::new(
+                count.span(),
+                format!(
+                    "'{}' matches {} test target{}, too few for {} shards",
+                    patterns,
+                    tests.len(),
+                    if tests.len() == 1 { "" } else { "s" },
+                    shards
+                ),
+            ));
+        }
+        Ok(bazel::partition_targets(tests, shards))
+    }
+
     /// Parse a structured bazel command (`bazel_build { ... }`, etc.) from a commands array.
     #[cfg(feature = "bazel")]
     fn parse_structured_bazel_in_commands(
@@ -2579,10 +5610,13 @@ impl StepDef {
         let mut target_patterns: Option<DynamicValue> = None;
         let mut target_patterns_span: Option<proc_macro2::Span> = None;
         let mut flags_value: Option<DynamicValue> = None;
+        let mut flag_literals: Vec<LitStr> = Vec::new();
         let mut extra_flags: Vec<String> = Vec::new();
         let mut cmd_args: Vec<DynamicValue> = Vec::new();
+        let mut spliced: Vec<shell::Spliced> = Vec::new();
         let mut cmd_validate_targets = validate_targets_default;
         let mut cmd_dry_run = dry_run_default;
+        let mut startup: Vec<String> = Vec::new();
 
         while !cmd_content.is_empty() {
             let cmd_field: Ident = cmd_content.parse()?;
@@ -2601,7 +5635,14 @@ impl StepDef {
                 }
                 "target_patterns" => {
                     target_patterns_span = Some(cmd_field.span());
-                    target_patterns = Some(DynamicValue::parse(&cmd_content)?);
+                    target_patterns = Some(DynamicValue::parse_spliced(
+                        &cmd_content,
+                        true,
+                        &mut spliced,
+                    )?);
+                }
+                "startup_options" => {
+                    startup = BazelExpr::parse_startup_options(&cmd_content)?;
                 }
                 "flags" => {
                     if cmd_content.peek(syn::token::Bracket) {
@@ -2611,6 +5652,7 @@ impl StepDef {
                         while !flags_content.is_empty() {
                             let flag: LitStr = flags_content.parse()?;
                             flag_parts.push(flag.value());
+                            flag_literals.push(flag);
                             if flags_content.peek(Token![,]) {
                                 flags_content.parse::<Token![,]>()?;
                             }
@@ -2618,7 +5660,11 @@ impl StepDef {
                         let joined = flag_parts.join(" ");
                         flags_value = Some(DynamicValue::Literal(joined));
                     } else {
-                        flags_value = Some(DynamicValue::parse(&cmd_content)?);
+                        flags_value = Some(DynamicValue::parse_spliced(
+                            &cmd_content,
+                            false,
+                            &mut spliced,
+                        )?);
                     }
                 }
                 "config" => {
@@ -2690,14 +5736,15 @@ impl StepDef {
                         let args_content;
                         bracketed!(args_content in cmd_content);
                         while !args_content.is_empty() {
-                            let arg = DynamicValue::parse(&args_content)?;
+                            let arg =
+                                DynamicValue::parse_spliced(&args_content, false, &mut spliced)?;
                             cmd_args.push(arg);
                             if args_content.peek(Token![,]) {
                                 args_content.parse::<Token![,]>()?;
                             }
                         }
                     } else {
-                        let arg = DynamicValue::parse(&cmd_content)?;
+                        let arg = DynamicValue::parse_spliced(&cmd_content, false, &mut spliced)?;
                         cmd_args.push(arg);
                     }
                 }
@@ -2705,8 +5752,12 @@ impl StepDef {
                     return Err(Error::new(
                         cmd_field.span(),
                         format!(
-                            "unknown bazel command field: {}. Use step-level fields (label, key, etc.) outside the commands array.",
-                            other
+                            "{}\nUse step-level fields (label, key, etc.) outside the commands array.",
+                            fields::unknown(
+                                "bazel command field",
+                                other,
+                                fields::BAZEL_COMMAND_FIELDS.iter().copied()
+                            )
                         ),
                     ));
                 }
@@ -2718,13 +5769,18 @@ impl StepDef {
 
         let verb = cmd_verb
             .ok_or_else(|| Error::new(cmd_ident.span(), "bazel_command requires 'verb' field"))?;
+        if !pipeline_custom_verbs.contains(&verb) && !step_custom_verbs.contains(&verb) {
+            BazelExpr::check_flag_literals(&verb, &flag_literals)?;
+        }
 
         let has_dynamic = target_patterns.as_ref().is_some_and(|t| t.is_dynamic())
             || flags_value.as_ref().is_some_and(|f| f.is_dynamic())
             || cmd_args.iter().any(|a| a.is_dynamic());
 
         if has_dynamic {
+            Self::check_spliced(&spliced, &verb, None)?;
             Ok(CommandValue::from_dynamic_bazel(
+                startup,
                 verb.clone(),
                 flags_value,
                 extra_flags.clone(),
@@ -2732,15 +5788,25 @@ impl StepDef {
                 cmd_args,
             ))
         } else {
-            let target_str = target_patterns
+            let mut target_str = target_patterns
                 .as_ref()
                 .and_then(|t| t.as_literal())
                 .map(|s| s.to_string());
+            if verb == "run"
+                && let Some(t) = &target_str
+            {
+                let (patterns, program_args) = targets::split_run_args(t);
+                if !program_args.is_empty() {
+                    cmd_args.splice(0..0, program_args.into_iter().map(DynamicValue::Literal));
+                    target_str = Some(patterns);
+                }
+            }
 
+            let is_query = bazel::is_query_verb(&verb);
             if let Some(ref t) = target_str
                 && cmd_validate_targets
                 && !t.is_empty()
-                && let Err(e) = Self::validate_target_patterns(t)
+                && let Err(e) = Self::check_target_patterns(t, &verb)
             {
                 return Err(Error::new(target_patterns_span.unwrap_or(step_span), e));
             }
@@ -2756,7 +5822,8 @@ impl StepDef {
                 .is_some_and(|s| s.split_whitespace().any(|f| f == "--"))
                 || extra_flags.iter().any(|f| f == "--");
 
-            let mut cmd_parts = vec![verb.clone()];
+            let mut cmd_parts = startup;
+            cmd_parts.push(verb.clone());
 
             if let Some(ref fv) = flags_value
                 && let Some(flags_str) = fv.as_literal()
@@ -2771,7 +5838,11 @@ impl StepDef {
                 if has_subtraction && !flags_have_separator {
                     cmd_parts.push("--".to_string());
                 }
-                cmd_parts.push(t.clone());
+                if is_query && Self::validate_target_patterns(t).is_err() {
+                    cmd_parts.push(BazelExpr::quote_query_expression(t));
+                } else {
+                    cmd_parts.push(t.clone());
+                }
             }
 
             if !cmd_args.is_empty() {
@@ -2784,6 +5855,7 @@ impl StepDef {
             }
 
             let bazel_cmd = cmd_parts.join(" ");
+            Self::check_spliced(&spliced, &verb, Some(&bazel_cmd))?;
 
             let lit = LitStr::new(&bazel_cmd, step_span);
             let mut all_custom_verbs: Vec<String> = pipeline_custom_verbs.to_vec();
@@ -2807,34 +5879,57 @@ impl StepDef {
 
         while !content.is_empty() {
             let field: Ident = content.parse()?;
-            content.parse::<Token![:]>()?;
+            let shorthand = parse_field_colon(&content, &field)?;
 
             match strip_raw_ident(&field.to_string()) {
+                "key" => {
+                    step.key = Some(KeyValue::parse(&content)?);
+                }
                 "continue_on_failure" => {
-                    let val: syn::LitBool = content.parse()?;
-                    step.continue_on_failure = val.value();
+                    step.continue_on_failure = parse_flag_value(&content, shorthand)?;
                 }
                 "depends_on" => {
-                    let dep: LitStr = content.parse()?;
-                    step.depends_on.push((dep.value(), dep.span()));
+                    if content.peek(syn::token::Bracket) {
+                        step.depends_on.extend(parse_dep_list(&content)?);
+                    } else {
+                        let dep: LitStr = content.parse()?;
+                        step.depends_on.push((dep.value(), dep.span()));
+                    }
                 }
                 "if" => {
-                    let condition: LitStr = content.parse()?;
-                    if let Err(errors) =
-                        buildkite_conditional::validate_condition(&condition.value())
-                    {
-                        return Err(Error::new(
-                            condition.span(),
-                            format!("Invalid Buildkite conditional: {}", errors.join("; ")),
-                        ));
+                    let condition = conditions::parse(&content)?;
+                    step.if_condition = Some(condition);
+                }
+                "branches" => {
+                    let branches_content;
+                    bracketed!(branches_content in content);
+                    while !branches_content.is_empty() {
+                        let branch: LitStr = branches_content.parse()?;
+                        step.branches.push(branch);
+                        if branches_content.peek(Token![,]) {
+                            branches_content.parse::<Token![,]>()?;
+                        }
                     }
-                    step.if_condition = Some(condition.value());
+                }
+                "allow_dependency_failure" => {
+                    step.allow_dependency_failure = parse_flag_value(&content, shorthand)?;
+                }
+                "notify" | "notify_slack" => {
+                    return Err(notify_unsupported(field.span(), "wait"));
                 }
                 other => {
-                    return Err(Error::new(
-                        field.span(),
-                        format!("unknown wait step field: {}", other),
-                    ));
+                    return Err(
+                        field_unsupported(field.span(), other, "wait").unwrap_or_else(|| {
+                            Error::new(
+                                field.span(),
+                                fields::unknown(
+                                    "wait step field",
+                                    other,
+                                    fields::WAIT_FIELDS.iter().copied(),
+                                ),
+                            )
+                        }),
+                    );
                 }
             }
 
@@ -2846,12 +5941,38 @@ impl StepDef {
         Ok(StepDef::Wait(step))
     }
 
+    /// Parse `wait_for(["build", "lint"])`: a wait step that depends on the
+    /// listed steps, keyed after them, e.g. `wait-for-build-lint`.
+    fn parse_wait_for(input: ParseStream, span: proc_macro2::Span) -> Result<Self> {
+        let content;
+        syn::parenthesized!(content in input);
+        let depends_on = parse_dep_list(&content)?;
+        if depends_on.is_empty() {
+            return Err(Error::new(
+                span,
+                "wait_for needs at least one step key to wait for; use `wait` to wait for every earlier step",
+            ));
+        }
+        let slugs: Vec<String> = depends_on
+            .iter()
+            .map(|(dep, _)| slugify_label(dep))
+            .filter(|slug| !slug.is_empty())
+            .collect();
+        let key = format!("wait-for-{}", slugs.join("-"));
+        Ok(StepDef::Wait(WaitStepDef {
+            key: Some(KeyValue::Literal(key, span)),
+            depends_on,
+            ..WaitStepDef::default()
+        }))
+    }
+
     /// Parse block step with fluent syntax: block("...").method()
     fn parse_block_fluent(input: ParseStream) -> Result<Self> {
         let content;
         syn::parenthesized!(content in input);
-        let prompt: LitStr = content.parse()?;
+        let (prompt, origin) = parse_label(&content)?;
         let mut step = BlockStepDef::new(prompt);
+        step.prompt_origin = origin;
 
         while input.peek(Token![.]) {
             input.parse::<Token![.]>()?;
@@ -2868,8 +5989,7 @@ impl StepDef {
                     step.depends_on.push((dep.value(), dep.span()));
                 }
                 "allowed_teams" => {
-                    let team: LitStr = args.parse()?;
-                    step.allowed_teams.push(team.value());
+                    step.allowed_teams.extend(parse_allowed_teams(&args)?);
                 }
                 "blocked_state" => {
                     let state: LitStr = args.parse()?;
@@ -2880,19 +6000,11 @@ impl StepDef {
                     step.branches.push(branch);
                 }
                 "if" => {
-                    let condition: LitStr = args.parse()?;
-                    if let Err(errors) =
-                        buildkite_conditional::validate_condition(&condition.value())
-                    {
-                        return Err(Error::new(
-                            condition.span(),
-                            format!("Invalid Buildkite conditional: {}", errors.join("; ")),
-                        ));
-                    }
+                    let condition = conditions::parse(&args)?;
                     step.if_condition = Some(condition);
                 }
                 "prompt" => {
-                    let p: LitStr = args.parse()?;
+                    let (p, _) = parse_label(&args)?;
                     step.prompt_text = Some(p);
                 }
                 "allow_dependency_failure" => {
@@ -2902,11 +6014,33 @@ impl StepDef {
                     let field = FieldDef::parse(&args)?;
                     step.fields.push(field);
                 }
+                "fields" => {
+                    let fields_content;
+                    bracketed!(fields_content in args);
+                    while !fields_content.is_empty() {
+                        let field_def = FieldDef::parse(&fields_content)?;
+                        step.fields.push(field_def);
+                        if fields_content.peek(Token![,]) {
+                            fields_content.parse::<Token![,]>()?;
+                        }
+                    }
+                }
+                "notify" | "notify_slack" => {
+                    return Err(notify_unsupported(method.span(), "block"));
+                }
                 other => {
-                    return Err(Error::new(
-                        method.span(),
-                        format!("unknown block step method: {}", other),
-                    ));
+                    return Err(
+                        field_unsupported(method.span(), other, "block").unwrap_or_else(|| {
+                            Error::new(
+                                method.span(),
+                                fields::unknown(
+                                    "block step method",
+                                    other,
+                                    fields::BLOCK_METHODS.iter().copied(),
+                                ),
+                            )
+                        }),
+                    );
                 }
             }
         }
@@ -2923,12 +6057,13 @@ impl StepDef {
 
         while !content.is_empty() {
             let field: Ident = content.parse()?;
-            content.parse::<Token![:]>()?;
+            let shorthand = parse_field_colon(&content, &field)?;
 
             match strip_raw_ident(&field.to_string()) {
                 "block" => {
-                    let prompt: LitStr = content.parse()?;
+                    let (prompt, origin) = parse_label(&content)?;
                     step.prompt = Some(prompt);
+                    step.prompt_origin = origin;
                 }
                 "key" => {
                     step.key = Some(KeyValue::parse(&content)?);
@@ -2956,20 +6091,7 @@ impl StepDef {
                     }
                 }
                 "allowed_teams" => {
-                    if content.peek(syn::token::Bracket) {
-                        let teams_content;
-                        bracketed!(teams_content in content);
-                        while !teams_content.is_empty() {
-                            let team: LitStr = teams_content.parse()?;
-                            step.allowed_teams.push(team.value());
-                            if teams_content.peek(Token![,]) {
-                                teams_content.parse::<Token![,]>()?;
-                            }
-                        }
-                    } else {
-                        let team: LitStr = content.parse()?;
-                        step.allowed_teams.push(team.value());
-                    }
+                    step.allowed_teams.extend(parse_allowed_teams(&content)?);
                 }
                 "blocked_state" => {
                     let state: LitStr = content.parse()?;
@@ -2987,30 +6109,32 @@ impl StepDef {
                     }
                 }
                 "if" => {
-                    let condition: LitStr = content.parse()?;
-                    if let Err(errors) =
-                        buildkite_conditional::validate_condition(&condition.value())
-                    {
-                        return Err(Error::new(
-                            condition.span(),
-                            format!("Invalid Buildkite conditional: {}", errors.join("; ")),
-                        ));
-                    }
+                    let condition = conditions::parse(&content)?;
                     step.if_condition = Some(condition);
                 }
                 "prompt" => {
-                    let p: LitStr = content.parse()?;
+                    let (p, _) = parse_label(&content)?;
                     step.prompt_text = Some(p);
                 }
                 "allow_dependency_failure" => {
-                    let val: syn::LitBool = content.parse()?;
-                    step.allow_dependency_failure = val.value();
+                    step.allow_dependency_failure = parse_flag_value(&content, shorthand)?;
+                }
+                "notify" | "notify_slack" => {
+                    return Err(notify_unsupported(field.span(), "block"));
                 }
                 other => {
-                    return Err(Error::new(
-                        field.span(),
-                        format!("unknown block step field: {}", other),
-                    ));
+                    return Err(
+                        field_unsupported(field.span(), other, "block").unwrap_or_else(|| {
+                            Error::new(
+                                field.span(),
+                                fields::unknown(
+                                    "block step field",
+                                    other,
+                                    fields::BLOCK_FIELDS.iter().copied(),
+                                ),
+                            )
+                        }),
+                    );
                 }
             }
 
@@ -3033,8 +6157,9 @@ impl StepDef {
     fn parse_input_fluent(input: ParseStream) -> Result<Self> {
         let content;
         syn::parenthesized!(content in input);
-        let prompt: LitStr = content.parse()?;
+        let (prompt, origin) = parse_label(&content)?;
         let mut step = InputStepDef::new(prompt);
+        step.prompt_origin = origin;
 
         while input.peek(Token![.]) {
             input.parse::<Token![.]>()?;
@@ -3051,8 +6176,7 @@ impl StepDef {
                     step.depends_on.push((dep.value(), dep.span()));
                 }
                 "allowed_teams" => {
-                    let team: LitStr = args.parse()?;
-                    step.allowed_teams.push(team.value());
+                    step.allowed_teams.extend(parse_allowed_teams(&args)?);
                 }
                 "blocked_state" => {
                     let state: LitStr = args.parse()?;
@@ -3063,19 +6187,11 @@ impl StepDef {
                     step.branches.push(branch);
                 }
                 "if" => {
-                    let condition: LitStr = args.parse()?;
-                    if let Err(errors) =
-                        buildkite_conditional::validate_condition(&condition.value())
-                    {
-                        return Err(Error::new(
-                            condition.span(),
-                            format!("Invalid Buildkite conditional: {}", errors.join("; ")),
-                        ));
-                    }
+                    let condition = conditions::parse(&args)?;
                     step.if_condition = Some(condition);
                 }
                 "prompt" => {
-                    let p: LitStr = args.parse()?;
+                    let (p, _) = parse_label(&args)?;
                     step.prompt_text = Some(p);
                 }
                 "allow_dependency_failure" => {
@@ -3085,11 +6201,33 @@ impl StepDef {
                     let field = FieldDef::parse(&args)?;
                     step.fields.push(field);
                 }
+                "fields" => {
+                    let fields_content;
+                    bracketed!(fields_content in args);
+                    while !fields_content.is_empty() {
+                        let field_def = FieldDef::parse(&fields_content)?;
+                        step.fields.push(field_def);
+                        if fields_content.peek(Token![,]) {
+                            fields_content.parse::<Token![,]>()?;
+                        }
+                    }
+                }
+                "notify" | "notify_slack" => {
+                    return Err(notify_unsupported(method.span(), "input"));
+                }
                 other => {
-                    return Err(Error::new(
-                        method.span(),
-                        format!("unknown input step method: {}", other),
-                    ));
+                    return Err(
+                        field_unsupported(method.span(), other, "input").unwrap_or_else(|| {
+                            Error::new(
+                                method.span(),
+                                fields::unknown(
+                                    "input step method",
+                                    other,
+                                    fields::BLOCK_METHODS.iter().copied(),
+                                ),
+                            )
+                        }),
+                    );
                 }
             }
         }
@@ -3106,12 +6244,13 @@ impl StepDef {
 
         while !content.is_empty() {
             let field: Ident = content.parse()?;
-            content.parse::<Token![:]>()?;
+            let shorthand = parse_field_colon(&content, &field)?;
 
             match strip_raw_ident(&field.to_string()) {
                 "input" => {
-                    let prompt: LitStr = content.parse()?;
+                    let (prompt, origin) = parse_label(&content)?;
                     step.prompt = Some(prompt);
+                    step.prompt_origin = origin;
                 }
                 "key" => {
                     step.key = Some(KeyValue::parse(&content)?);
@@ -3139,20 +6278,7 @@ impl StepDef {
                     }
                 }
                 "allowed_teams" => {
-                    if content.peek(syn::token::Bracket) {
-                        let teams_content;
-                        bracketed!(teams_content in content);
-                        while !teams_content.is_empty() {
-                            let team: LitStr = teams_content.parse()?;
-                            step.allowed_teams.push(team.value());
-                            if teams_content.peek(Token![,]) {
-                                teams_content.parse::<Token![,]>()?;
-                            }
-                        }
-                    } else {
-                        let team: LitStr = content.parse()?;
-                        step.allowed_teams.push(team.value());
-                    }
+                    step.allowed_teams.extend(parse_allowed_teams(&content)?);
                 }
                 "blocked_state" => {
                     let state: LitStr = content.parse()?;
@@ -3170,30 +6296,32 @@ impl StepDef {
                     }
                 }
                 "if" => {
-                    let condition: LitStr = content.parse()?;
-                    if let Err(errors) =
-                        buildkite_conditional::validate_condition(&condition.value())
-                    {
-                        return Err(Error::new(
-                            condition.span(),
-                            format!("Invalid Buildkite conditional: {}", errors.join("; ")),
-                        ));
-                    }
+                    let condition = conditions::parse(&content)?;
                     step.if_condition = Some(condition);
                 }
                 "prompt" => {
-                    let p: LitStr = content.parse()?;
+                    let (p, _) = parse_label(&content)?;
                     step.prompt_text = Some(p);
                 }
                 "allow_dependency_failure" => {
-                    let val: syn::LitBool = content.parse()?;
-                    step.allow_dependency_failure = val.value();
+                    step.allow_dependency_failure = parse_flag_value(&content, shorthand)?;
+                }
+                "notify" | "notify_slack" => {
+                    return Err(notify_unsupported(field.span(), "input"));
                 }
                 other => {
-                    return Err(Error::new(
-                        field.span(),
-                        format!("unknown input step field: {}", other),
-                    ));
+                    return Err(
+                        field_unsupported(field.span(), other, "input").unwrap_or_else(|| {
+                            Error::new(
+                                field.span(),
+                                fields::unknown(
+                                    "input step field",
+                                    other,
+                                    fields::INPUT_FIELDS.iter().copied(),
+                                ),
+                            )
+                        }),
+                    );
                 }
             }
 
@@ -3234,78 +6362,29 @@ impl StepDef {
                     step.depends_on.push((dep.value(), dep.span()));
                 }
                 "label" => {
-                    step.label = Some(args.parse()?);
-                }
-                "async" => {
-                    step.async_trigger = true;
-                }
-                "build" => {
-                    let build_value = NestedValue::parse(&args)?;
-                    if let NestedValue::Object(pairs) = build_value {
-                        let mut config = TriggerBuildConfig::default();
-                        for (k, v) in pairs {
-                            match k.as_str() {
-                                "branch" => {
-                                    if let NestedValue::String(s) = v {
-                                        config.branch = Some(s);
-                                    }
-                                }
-                                "commit" => {
-                                    if let NestedValue::String(s) = v {
-                                        config.commit = Some(s);
-                                    }
-                                }
-                                "message" => {
-                                    if let NestedValue::String(s) = v {
-                                        config.message = Some(s);
-                                    }
-                                }
-                                "env" => {
-                                    if let NestedValue::Object(env_pairs) = v {
-                                        for (ek, ev) in env_pairs {
-                                            if let NestedValue::String(es) = ev {
-                                                config.env.push((ek, es));
-                                            }
-                                        }
-                                    }
-                                }
-                                "meta_data" => {
-                                    if let NestedValue::Object(md_pairs) = v {
-                                        for (mk, mv) in md_pairs {
-                                            if let NestedValue::String(ms) = mv {
-                                                config.meta_data.push((mk, ms));
-                                            }
-                                        }
-                                    }
-                                }
-                                _ => {}
-                            }
-                        }
-                        step.build = Some(config);
-                    }
+                    let (label, origin) = parse_label(&args)?;
+                    step.label = Some(label);
+                    step.label_origin = origin;
+                }
+                "async" => {
+                    step.async_trigger = true;
+                }
+                "build" => {
+                    step.build = Some(TriggerBuildConfig::parse(&args)?);
                 }
                 "branches" => {
                     let branch: LitStr = args.parse()?;
                     step.branches.push(branch);
                 }
                 "if" => {
-                    let condition: LitStr = args.parse()?;
-                    if let Err(errors) =
-                        buildkite_conditional::validate_condition(&condition.value())
-                    {
-                        return Err(Error::new(
-                            condition.span(),
-                            format!("Invalid Buildkite conditional: {}", errors.join("; ")),
-                        ));
-                    }
+                    let condition = conditions::parse(&args)?;
                     step.if_condition = Some(condition);
                 }
                 "skip" => {
                     if args.is_empty() {
                         step.skip = Some(SkipValue::Bool(true));
                     } else {
-                        let reason: LitStr = args.parse()?;
-                        step.skip = Some(SkipValue::Reason(reason.value()));
+                        step.skip = Some(parse_skip_reason(&args)?);
                     }
                 }
                 "soft_fail" => {
@@ -3314,11 +6393,22 @@ impl StepDef {
                 "allow_dependency_failure" => {
                     step.allow_dependency_failure = true;
                 }
+                "notify" | "notify_slack" => {
+                    return Err(notify_unsupported(method.span(), "trigger"));
+                }
                 other => {
-                    return Err(Error::new(
-                        method.span(),
-                        format!("unknown trigger step method: {}", other),
-                    ));
+                    return Err(
+                        field_unsupported(method.span(), other, "trigger").unwrap_or_else(|| {
+                            Error::new(
+                                method.span(),
+                                fields::unknown(
+                                    "trigger step method",
+                                    other,
+                                    fields::TRIGGER_METHODS.iter().copied(),
+                                ),
+                            )
+                        }),
+                    );
                 }
             }
         }
@@ -3335,7 +6425,7 @@ impl StepDef {
 
         while !content.is_empty() {
             let field: Ident = content.parse()?;
-            content.parse::<Token![:]>()?;
+            let shorthand = parse_field_colon(&content, &field)?;
 
             match strip_raw_ident(&field.to_string()) {
                 "trigger" => {
@@ -3343,14 +6433,15 @@ impl StepDef {
                     step.pipeline = Some(pipeline);
                 }
                 "label" => {
-                    step.label = Some(content.parse()?);
+                    let (label, origin) = parse_label(&content)?;
+                    step.label = Some(label);
+                    step.label_origin = origin;
                 }
                 "key" => {
                     step.key = Some(KeyValue::parse(&content)?);
                 }
                 "async" => {
-                    let val: syn::LitBool = content.parse()?;
-                    step.async_trigger = val.value();
+                    step.async_trigger = parse_flag_value(&content, shorthand)?;
                 }
                 "depends_on" => {
                     let deps_content;
@@ -3364,49 +6455,7 @@ impl StepDef {
                     }
                 }
                 "build" => {
-                    let build_value = NestedValue::parse(&content)?;
-                    if let NestedValue::Object(pairs) = build_value {
-                        let mut config = TriggerBuildConfig::default();
-                        for (k, v) in pairs {
-                            match k.as_str() {
-                                "branch" => {
-                                    if let NestedValue::String(s) = v {
-                                        config.branch = Some(s);
-                                    }
-                                }
-                                "commit" => {
-                                    if let NestedValue::String(s) = v {
-                                        config.commit = Some(s);
-                                    }
-                                }
-                                "message" => {
-                                    if let NestedValue::String(s) = v {
-                                        config.message = Some(s);
-                                    }
-                                }
-                                "env" => {
-                                    if let NestedValue::Object(env_pairs) = v {
-                                        for (ek, ev) in env_pairs {
-                                            if let NestedValue::String(es) = ev {
-                                                config.env.push((ek, es));
-                                            }
-                                        }
-                                    }
-                                }
-                                "meta_data" => {
-                                    if let NestedValue::Object(md_pairs) = v {
-                                        for (mk, mv) in md_pairs {
-                                            if let NestedValue::String(ms) = mv {
-                                                config.meta_data.push((mk, ms));
-                                            }
-                                        }
-                                    }
-                                }
-                                _ => {}
-                            }
-                        }
-                        step.build = Some(config);
-                    }
+                    step.build = Some(TriggerBuildConfig::parse(&content)?);
                 }
                 "branches" => {
                     let branches_content;
@@ -3420,15 +6469,7 @@ impl StepDef {
                     }
                 }
                 "if" => {
-                    let condition: LitStr = content.parse()?;
-                    if let Err(errors) =
-                        buildkite_conditional::validate_condition(&condition.value())
-                    {
-                        return Err(Error::new(
-                            condition.span(),
-                            format!("Invalid Buildkite conditional: {}", errors.join("; ")),
-                        ));
-                    }
+                    let condition = conditions::parse(&content)?;
                     step.if_condition = Some(condition);
                 }
                 "skip" => {
@@ -3436,23 +6477,31 @@ impl StepDef {
                         let val: syn::LitBool = content.parse()?;
                         step.skip = Some(SkipValue::Bool(val.value()));
                     } else {
-                        let reason: LitStr = content.parse()?;
-                        step.skip = Some(SkipValue::Reason(reason.value()));
+                        step.skip = Some(parse_skip_reason(&content)?);
                     }
                 }
                 "soft_fail" => {
-                    let val: syn::LitBool = content.parse()?;
-                    step.soft_fail = val.value();
+                    step.soft_fail = parse_flag_value(&content, shorthand)?;
                 }
                 "allow_dependency_failure" => {
-                    let val: syn::LitBool = content.parse()?;
-                    step.allow_dependency_failure = val.value();
+                    step.allow_dependency_failure = parse_flag_value(&content, shorthand)?;
+                }
+                "notify" | "notify_slack" => {
+                    return Err(notify_unsupported(field.span(), "trigger"));
                 }
                 other => {
-                    return Err(Error::new(
-                        field.span(),
-                        format!("unknown trigger step field: {}", other),
-                    ));
+                    return Err(
+                        field_unsupported(field.span(), other, "trigger").unwrap_or_else(|| {
+                            Error::new(
+                                field.span(),
+                                fields::unknown(
+                                    "trigger step field",
+                                    other,
+                                    fields::TRIGGER_FIELDS.iter().copied(),
+                                ),
+                            )
+                        }),
+                    );
                 }
             }
 
@@ -3472,11 +6521,12 @@ impl StepDef {
     }
 
     /// Parse group step with fluent syntax: group("...").steps([...])
-    fn parse_group_fluent(input: ParseStream) -> Result<Self> {
+    fn parse_group_fluent(input: ParseStream, custom_verbs: &[String]) -> Result<Self> {
         let content;
         syn::parenthesized!(content in input);
-        let label: syn::Expr = content.parse()?;
+        let (label, origin) = parse_label(&content)?;
         let mut step = GroupStepDef::new(label);
+        step.label_origin = origin;
 
         while input.peek(Token![.]) {
             input.parse::<Token![.]>()?;
@@ -3495,62 +6545,83 @@ impl StepDef {
                 "steps" => {
                     let nested;
                     bracketed!(nested in args);
-                    let nested_steps: Punctuated<StepDef, Token![,]> =
-                        Punctuated::parse_terminated(&nested)?;
-                    step.steps = nested_steps.into_iter().collect();
+                    step.steps
+                        .extend(StepDef::parse_list(&nested, custom_verbs)?);
+                }
+                "step" => {
+                    let nested = StepDef::parse_documented(&args, custom_verbs)?;
+                    step.steps.push(nested);
+                }
+                "defaults" => {
+                    step.defaults = Some(StepDefaults::parse(&args)?);
+                }
+                "key_prefix" => {
+                    step.key_prefix = Some(args.parse()?);
                 }
                 "if" => {
-                    let condition: LitStr = args.parse()?;
-                    if let Err(errors) =
-                        buildkite_conditional::validate_condition(&condition.value())
-                    {
-                        return Err(Error::new(
-                            condition.span(),
-                            format!("Invalid Buildkite conditional: {}", errors.join("; ")),
-                        ));
-                    }
+                    let condition = conditions::parse(&args)?;
                     step.if_condition = Some(condition);
                 }
                 "skip" => {
                     if args.is_empty() {
                         step.skip = Some(SkipValue::Bool(true));
                     } else {
-                        let reason: LitStr = args.parse()?;
-                        step.skip = Some(SkipValue::Reason(reason.value()));
+                        step.skip = Some(parse_skip_reason(&args)?);
                     }
                 }
                 "notify_slack" => {
                     let channel: LitStr = args.parse()?;
-                    step.notify.push(NestedValue::Object(vec![(
-                        "slack".to_string(),
-                        NestedValue::String(channel.value()),
-                    )]));
+                    step.notify.push(NotifyValue::Slack {
+                        channel: channel.value(),
+                        if_: None,
+                    });
                 }
                 "notify" => {
-                    let notify_value = NestedValue::parse(&args)?;
-                    if let NestedValue::Array(items) = notify_value {
-                        step.notify.extend(items);
+                    if args.peek(syn::token::Bracket) {
+                        let notify_content;
+                        bracketed!(notify_content in args);
+                        while !notify_content.is_empty() {
+                            step.notify
+                                .push(NotifyValue::parse_for_group(&notify_content)?);
+                            if notify_content.peek(Token![,]) {
+                                notify_content.parse::<Token![,]>()?;
+                            }
+                        }
                     } else {
-                        step.notify.push(notify_value);
+                        step.notify.push(NotifyValue::parse_for_group(&args)?);
                     }
                 }
                 "allow_dependency_failure" => {
                     step.allow_dependency_failure = true;
                 }
+                "only_profiles" => {
+                    step.only_profiles = parse_only_profiles(&args)?;
+                }
                 other => {
-                    return Err(Error::new(
-                        method.span(),
-                        format!("unknown group step method: {}", other),
-                    ));
+                    return Err(
+                        field_unsupported(method.span(), other, "group").unwrap_or_else(|| {
+                            Error::new(
+                                method.span(),
+                                fields::unknown(
+                                    "group step method",
+                                    other,
+                                    fields::GROUP_METHODS.iter().copied(),
+                                ),
+                            )
+                        }),
+                    );
                 }
             }
         }
 
+        step.reject_sharded_steps()?;
+        step.apply_defaults();
+        step.apply_key_prefix()?;
         Ok(StepDef::Group(step))
     }
 
     /// Parse group step with object-literal syntax: group { group: "...", steps: [...] }
-    fn parse_group_object_literal(input: ParseStream) -> Result<Self> {
+    fn parse_group_object_literal(input: ParseStream, custom_verbs: &[String]) -> Result<Self> {
         let content;
         braced!(content in input);
 
@@ -3558,12 +6629,13 @@ impl StepDef {
 
         while !content.is_empty() {
             let field: Ident = content.parse()?;
-            content.parse::<Token![:]>()?;
+            let shorthand = parse_field_colon(&content, &field)?;
 
             match strip_raw_ident(&field.to_string()) {
                 "group" => {
-                    let label: syn::Expr = content.parse()?;
+                    let (label, origin) = parse_label(&content)?;
                     step.label = Some(label);
+                    step.label_origin = origin;
                 }
                 "key" => {
                     step.key = Some(KeyValue::parse(&content)?);
@@ -3582,20 +6654,10 @@ impl StepDef {
                 "steps" => {
                     let nested;
                     bracketed!(nested in content);
-                    let nested_steps: Punctuated<StepDef, Token![,]> =
-                        Punctuated::parse_terminated(&nested)?;
-                    step.steps = nested_steps.into_iter().collect();
+                    step.steps = StepDef::parse_list(&nested, custom_verbs)?;
                 }
                 "if" => {
-                    let condition: LitStr = content.parse()?;
-                    if let Err(errors) =
-                        buildkite_conditional::validate_condition(&condition.value())
-                    {
-                        return Err(Error::new(
-                            condition.span(),
-                            format!("Invalid Buildkite conditional: {}", errors.join("; ")),
-                        ));
-                    }
+                    let condition = conditions::parse(&content)?;
                     step.if_condition = Some(condition);
                 }
                 "skip" => {
@@ -3603,15 +6665,14 @@ impl StepDef {
                         let val: syn::LitBool = content.parse()?;
                         step.skip = Some(SkipValue::Bool(val.value()));
                     } else {
-                        let reason: LitStr = content.parse()?;
-                        step.skip = Some(SkipValue::Reason(reason.value()));
+                        step.skip = Some(parse_skip_reason(&content)?);
                     }
                 }
                 "notify" => {
                     let notify_content;
                     bracketed!(notify_content in content);
                     while !notify_content.is_empty() {
-                        let notification = NestedValue::parse(&notify_content)?;
+                        let notification = NotifyValue::parse_for_group(&notify_content)?;
                         step.notify.push(notification);
                         if notify_content.peek(Token![,]) {
                             notify_content.parse::<Token![,]>()?;
@@ -3619,14 +6680,30 @@ impl StepDef {
                     }
                 }
                 "allow_dependency_failure" => {
-                    let val: syn::LitBool = content.parse()?;
-                    step.allow_dependency_failure = val.value();
+                    step.allow_dependency_failure = parse_flag_value(&content, shorthand)?;
+                }
+                "defaults" => {
+                    step.defaults = Some(StepDefaults::parse(&content)?);
+                }
+                "key_prefix" => {
+                    step.key_prefix = Some(content.parse()?);
+                }
+                "only_profiles" => {
+                    step.only_profiles = parse_only_profiles(&content)?;
                 }
                 other => {
-                    return Err(Error::new(
-                        field.span(),
-                        format!("unknown group step field: {}", other),
-                    ));
+                    return Err(
+                        field_unsupported(field.span(), other, "group").unwrap_or_else(|| {
+                            Error::new(
+                                field.span(),
+                                fields::unknown(
+                                    "group step field",
+                                    other,
+                                    fields::GROUP_FIELDS.iter().copied(),
+                                ),
+                            )
+                        }),
+                    );
                 }
             }
 
@@ -3641,1918 +6718,1767 @@ impl StepDef {
                 "group step requires 'group' field",
             ));
         }
-
-        Ok(StepDef::Group(step))
-    }
-}
-
-impl ToTokens for StepDef {
-    fn to_tokens(&self, tokens: &mut TokenStream2) {
-        let step_tokens = match self {
-            StepDef::Command(c) => c.to_tokens_inner(),
-            StepDef::Wait(w) => w.to_tokens_inner(),
-            StepDef::Block(b) => b.to_tokens_inner(),
-            StepDef::Input(i) => i.to_tokens_inner(),
-            StepDef::Trigger(t) => t.to_tokens_inner(),
-            StepDef::Group(g) => g.to_tokens_inner(),
-        };
-        tokens.extend(step_tokens);
-    }
-}
-
-impl StepDef {
-    /// Generate tokens for this step as a GroupStepsItem (used for nested steps in groups)
-    fn to_group_step_tokens(&self) -> TokenStream2 {
-        match self {
-            StepDef::Command(c) => c.to_group_step_tokens(),
-            StepDef::Wait(w) => w.to_group_step_tokens(),
-            StepDef::Block(b) => b.to_group_step_tokens(),
-            StepDef::Input(i) => i.to_group_step_tokens(),
-            StepDef::Trigger(t) => t.to_group_step_tokens(),
-            StepDef::Group(_) => {
-                quote! { compile_error!("Groups cannot be nested inside other groups") }
-            }
-        }
-    }
-
-    /// Generate tokens for this step with default plugins merged in
-    fn to_tokens_with_default_plugins(&self, default_plugins: &[NestedValue]) -> TokenStream2 {
-        match self {
-            StepDef::Command(c) => c.to_tokens_with_default_plugins(default_plugins),
-            StepDef::Wait(w) => w.to_tokens_inner(),
-            StepDef::Block(b) => b.to_tokens_inner(),
-            StepDef::Input(i) => i.to_tokens_inner(),
-            StepDef::Trigger(t) => t.to_tokens_inner(),
-            StepDef::Group(g) => g.to_tokens_with_default_plugins(default_plugins),
-        }
-    }
-
-    /// Generate tokens for this step as a GroupStepsItem with default plugins
-    fn to_group_step_tokens_with_default_plugins(
-        &self,
-        default_plugins: &[NestedValue],
-    ) -> TokenStream2 {
-        match self {
-            StepDef::Command(c) => c.to_group_step_tokens_with_default_plugins(default_plugins),
-            StepDef::Wait(w) => w.to_group_step_tokens(),
-            StepDef::Block(b) => b.to_group_step_tokens(),
-            StepDef::Input(i) => i.to_group_step_tokens(),
-            StepDef::Trigger(t) => t.to_group_step_tokens(),
-            StepDef::Group(_) => {
-                quote! { compile_error!("Groups cannot be nested inside other groups") }
-            }
-        }
-    }
-}
-#[derive(Default)]
-struct WaitStepDef {
-    continue_on_failure: bool,
-    depends_on: Vec<(String, proc_macro2::Span)>,
-    if_condition: Option<String>,
-}
-
-impl WaitStepDef {
-    fn to_tokens_inner(&self) -> TokenStream2 {
-        if self.continue_on_failure || self.if_condition.is_some() {
-            let continue_on_failure_tokens = if self.continue_on_failure {
-                quote! { .continue_on_failure(true) }
-            } else {
-                quote! {}
-            };
-
-            let if_tokens = if let Some(condition) = &self.if_condition {
-                quote! { .if_(::rust_buildkite::If(#condition.to_string())) }
-            } else {
-                quote! {}
-            };
-
-            quote! {
-                ::rust_buildkite::PipelineStepsItem::WaitStep(
-                    ::rust_buildkite::WaitStep::builder()
-                        #continue_on_failure_tokens
-                        #if_tokens
-                        .try_into()
-                        .expect("wait step construction failed")
-                )
-            }
-        } else {
-            quote! {
-                ::rust_buildkite::PipelineStepsItem::StringWaitStep(
-                    ::rust_buildkite::StringWaitStep::Wait
-                )
-            }
-        }
-    }
-
-    fn to_group_step_tokens(&self) -> TokenStream2 {
-        if self.continue_on_failure || self.if_condition.is_some() {
-            let continue_on_failure_tokens = if self.continue_on_failure {
-                quote! { .continue_on_failure(true) }
-            } else {
-                quote! {}
-            };
-
-            let if_tokens = if let Some(condition) = &self.if_condition {
-                quote! { .if_(::rust_buildkite::If(#condition.to_string())) }
-            } else {
-                quote! {}
-            };
-
-            quote! {
-                ::rust_buildkite::GroupStepsItem::WaitStep(
-                    ::rust_buildkite::WaitStep::builder()
-                        #continue_on_failure_tokens
-                        #if_tokens
-                        .try_into()
-                        .expect("wait step construction failed")
-                )
-            }
-        } else {
-            quote! {
-                ::rust_buildkite::GroupStepsItem::StringWaitStep(
-                    ::rust_buildkite::StringWaitStep::Wait
-                )
-            }
-        }
-    }
-}
-#[derive(Clone)]
-#[allow(clippy::large_enum_variant)]
-enum CommandSource {
-    Shell(CmdExpr),
-    #[cfg(feature = "bazel")]
-    Bazel(BazelExpr),
-    #[cfg(feature = "bazel")]
-    DynamicBazel {
-        base_cmd: String,
-        flags: Option<DynamicValue>,
-        extra_flags: Vec<String>,
-        target: Option<DynamicValue>,
-        args: Vec<DynamicValue>,
-    },
-}
-
-#[derive(Clone)]
-enum DynamicValue {
-    Literal(String),
-    Comptime(syn::Expr),
-    Runtime(syn::Expr),
-}
-
-impl DynamicValue {
-    fn parse(input: ParseStream) -> Result<Self> {
-        if input.peek(LitStr) {
-            let lit: LitStr = input.parse()?;
-            Ok(DynamicValue::Literal(lit.value()))
-        } else {
-            let expr: syn::Expr = input.parse()?;
-            Ok(Self::from_expr(expr))
-        }
-    }
-
-    fn from_expr(expr: syn::Expr) -> Self {
-        if let syn::Expr::Macro(ref mac) = expr {
-            let macro_name = mac.mac.path.segments.last().map(|s| s.ident.to_string());
-            match macro_name.as_deref() {
-                Some("comptime") => DynamicValue::Comptime(expr),
-                Some("runtime") => DynamicValue::Runtime(expr),
-                Some("comptime_shell") => {
-                    if let Ok(cmd) = syn::parse2::<LitStr>(mac.mac.tokens.clone()) {
-                        match std::process::Command::new("sh")
-                            .arg("-c")
-                            .arg(cmd.value())
-                            .output()
-                        {
-                            Ok(out) if out.status.success() => {
-                                let stdout =
-                                    String::from_utf8_lossy(&out.stdout).trim().to_string();
-                                DynamicValue::Literal(stdout)
-                            }
-                            Ok(out) => {
-                                let stderr = String::from_utf8_lossy(&out.stderr);
-                                let code = out.status.code().unwrap_or(-1);
-                                panic!("comptime_shell command failed (exit {}): {}", code, stderr);
-                            }
-                            Err(e) => {
-                                panic!("comptime_shell failed to run command: {}", e);
-                            }
-                        }
-                    } else {
-                        panic!("comptime_shell! requires a string literal argument");
-                    }
-                }
-                _ => DynamicValue::Runtime(expr),
-            }
-        } else {
-            DynamicValue::Runtime(expr)
-        }
-    }
-
-    #[cfg(feature = "bazel")]
-    fn is_dynamic(&self) -> bool {
-        !matches!(self, DynamicValue::Literal(_))
-    }
-
-    #[cfg(feature = "bazel")]
-    fn as_literal(&self) -> Option<&str> {
-        match self {
-            DynamicValue::Literal(s) => Some(s),
-            _ => None,
-        }
-    }
-
-    fn to_tokens(&self) -> TokenStream2 {
-        match self {
-            DynamicValue::Literal(s) => quote! { #s },
-            DynamicValue::Comptime(expr) | DynamicValue::Runtime(expr) => {
-                if let syn::Expr::Macro(mac) = expr {
-                    let macro_name = mac.mac.path.segments.last().map(|s| s.ident.to_string());
-                    match macro_name.as_deref() {
-                        Some("runtime") | Some("comptime") => {
-                            let inner = &mac.mac.tokens;
-                            quote! { #inner }
-                        }
-                        _ => quote! { #expr },
-                    }
-                } else {
-                    quote! { #expr }
-                }
-            }
-        }
-    }
-
-    #[cfg(feature = "bazel")]
-    fn to_tokens_with_target_validation(&self, var_name: &str) -> (TokenStream2, TokenStream2) {
-        let var_ident = syn::Ident::new(var_name, proc_macro2::Span::call_site());
-        let inner = self.to_tokens();
-        let validation = quote! {
-            let #var_ident = {
-                let __val: String = (#inner).to_string();
-                ::rust_buildkite::validation::validate_targets(&__val);
-                __val
-            };
-        };
-        let usage = quote! { #var_ident };
-        (validation, usage)
-    }
-
-    #[cfg(feature = "bazel")]
-    fn to_tokens_with_flags_validation(
-        &self,
-        verb: &str,
-        var_name: &str,
-    ) -> (TokenStream2, TokenStream2) {
-        let var_ident = syn::Ident::new(var_name, proc_macro2::Span::call_site());
-        let inner = self.to_tokens();
-        let validation = quote! {
-            let #var_ident = {
-                let __val: String = (#inner).to_string();
-                ::rust_buildkite::validation::validate_flags_str(#verb, &__val);
-                __val
-            };
-        };
-        let usage = quote! { #var_ident };
-        (validation, usage)
+
+        step.reject_sharded_steps()?;
+        step.apply_defaults();
+        step.apply_key_prefix()?;
+        Ok(StepDef::Group(step))
     }
 }
 
-/// Key value that can be literal (validated) or runtime (not validated).
-#[derive(Clone)]
-enum KeyValue {
-    Literal(String, proc_macro2::Span),
-    Runtime(Box<syn::Expr>),
+impl ToTokens for StepDef {
+    fn to_tokens(&self, tokens: &mut TokenStream2) {
+        tokens.extend(self.expand(StepTarget::Pipeline, &[]).tokens);
+    }
 }
 
-impl KeyValue {
-    fn parse(input: ParseStream) -> Result<Self> {
-        let span = input.span();
-        if input.peek(LitStr) {
-            let lit: LitStr = input.parse()?;
-            Ok(KeyValue::Literal(lit.value(), lit.span()))
-        } else {
-            let expr: syn::Expr = input.parse()?;
-            if let syn::Expr::Macro(ref mac) = expr {
-                let macro_name = mac.mac.path.segments.last().map(|s| s.ident.to_string());
-                if macro_name.as_deref() == Some("runtime") {
-                    return Ok(KeyValue::Runtime(Box::new(expr)));
-                }
-            }
-            Err(syn::Error::new(
-                span,
-                "key must be a string literal or runtime!(expr)",
-            ))
-        }
-    }
+/// The steps list a generated step is an item of
+#[derive(Clone, Copy)]
+enum StepTarget {
+    /// Top-level `PipelineStepsItem`
+    Pipeline,
+    /// `GroupStepsItem` nested in a group step
+    Group,
+}
 
-    fn as_literal(&self) -> Option<(&str, proc_macro2::Span)> {
+impl StepTarget {
+    fn item_type(self) -> TokenStream2 {
         match self {
-            KeyValue::Literal(s, span) => Some((s, *span)),
-            KeyValue::Runtime(_) => None,
+            StepTarget::Pipeline => quote! { ::rust_buildkite::PipelineStepsItem },
+            StepTarget::Group => quote! { ::rust_buildkite::GroupStepsItem },
         }
     }
+}
 
-    fn to_tokens(&self) -> TokenStream2 {
-        match self {
-            KeyValue::Literal(s, _) => quote! { #s.to_string() },
-            KeyValue::Runtime(expr) => {
-                if let syn::Expr::Macro(mac) = expr.as_ref() {
-                    let inner = &mac.mac.tokens;
-                    quote! { #inner.to_string() }
-                } else {
-                    quote! { #expr.to_string() }
-                }
-            }
+impl StepDef {
+    /// The expression that builds this step as an item of `target`'s steps,
+    /// with `default_plugins` added to its command steps, and the value
+    /// `emit_yaml` writes for it.
+    fn expand(&self, target: StepTarget, default_plugins: &[NestedValue]) -> yaml::Expanded {
+        let expanded = match self {
+            StepDef::Command(c) => c.expand(target, default_plugins),
+            StepDef::Wait(w) => w.expand(target),
+            StepDef::Block(b) => b.expand(target),
+            StepDef::Input(i) => i.expand(target),
+            StepDef::Trigger(t) => t.expand(target),
+            StepDef::Group(g) => match target {
+                StepTarget::Pipeline => g.expand(default_plugins),
+                StepTarget::Group => yaml::Expanded {
+                    tokens: quote! { compile_error!("Groups cannot be nested inside other groups") },
+                    value: Ok(yaml::Value::Null),
+                },
+            },
+        };
+        yaml::Expanded {
+            tokens: self.with_build_context(expanded.tokens),
+            value: expanded.value,
         }
     }
 }
+#[derive(Default)]
+struct WaitStepDef {
+    key: Option<KeyValue>,
+    description: Option<String>,
+    continue_on_failure: bool,
+    depends_on: Vec<(String, proc_macro2::Span)>,
+    if_condition: Option<LitStr>,
+    branches: Vec<LitStr>,
+    allow_dependency_failure: bool,
+}
 
-#[derive(Clone)]
-struct CommandValue(CommandSource);
+impl WaitStepDef {
+    /// The step as an item of `target`'s steps: a plain `wait` string unless
+    /// it sets something, in which case a `WaitStep`.
+    fn expand(&self, target: StepTarget) -> yaml::Expanded {
+        let item = target.item_type();
+        if self.key.is_none()
+            && !self.continue_on_failure
+            && self.depends_on.is_empty()
+            && self.if_condition.is_none()
+            && self.branches.is_empty()
+            && !self.allow_dependency_failure
+        {
+            return yaml::Expanded {
+                tokens: quote! {
+                    #item::StringWaitStep(::rust_buildkite::StringWaitStep::Wait)
+                },
+                value: Ok(yaml::string("wait")),
+            };
+        }
 
-impl CommandValue {
-    fn from_cmd(cmd: CmdExpr) -> Self {
-        Self(CommandSource::Shell(cmd))
-    }
+        let mut fields = yaml::Fields::default();
+        // nb: `wait` marks the object as a wait step; without it Buildkite
+        // can't tell what kind of step the other fields belong to
+        fields.set(
+            "wait",
+            Ok(yaml::string("")),
+            quote! { .wait(Some("".to_string())) },
+        );
 
-    #[cfg(feature = "bazel")]
-    fn from_bazel(bazel: BazelExpr) -> Self {
-        Self(CommandSource::Bazel(bazel))
+        if let Some(key) = &self.key {
+            let key_value = key.to_tokens();
+            fields.set(
+                "key",
+                yaml::key(key),
+                quote! { .key(Some(__rbk_built!(#key_value.try_into().into_build_result(__rbk_step, "invalid key")))) },
+            );
+        }
+
+        if self.continue_on_failure {
+            fields.set(
+                "continue_on_failure",
+                Ok(yaml::Value::Bool(true)),
+                quote! { .continue_on_failure(true) },
+            );
+        }
+
+        if !self.depends_on.is_empty() {
+            let deps: Vec<_> = self.depends_on.iter().map(|(d, _)| d).collect();
+            fields.set(
+                "depends_on",
+                Ok(yaml::depends_on(&self.depends_on)),
+                quote! {
+                    .depends_on(Some(::rust_buildkite::helpers::string_deps(&[#(#deps),*])))
+                },
+            );
+        }
+
+        if let Some(condition) = &self.if_condition {
+            fields.set(
+                "if",
+                Ok(yaml::string(&condition.value())),
+                quote! { .if_(::rust_buildkite::If(#condition.to_string())) },
+            );
+        }
+
+        if !self.branches.is_empty() {
+            let branches = &self.branches;
+            fields.set(
+                "branches",
+                Ok(yaml::lit_strs(branches)),
+                quote! {
+                    .branches(Some(::rust_buildkite::Branches::Array(::rust_buildkite::helpers::string_vec(&[#(#branches),*]))))
+                },
+            );
+        }
+
+        if self.allow_dependency_failure {
+            fields.set(
+                "allow_dependency_failure",
+                Ok(yaml::Value::Bool(true)),
+                quote! { .allow_dependency_failure(Some(::rust_buildkite::AllowDependencyFailure(true))) },
+            );
+        }
+
+        let (calls, value) = fields.finish();
+        let tokens = quote! {
+            #item::WaitStep(
+                __rbk_built!(
+                    ::rust_buildkite::WaitStep::builder()
+                        #calls
+                        .try_into()
+                        .into_build_result(__rbk_step, "construction failed")
+                )
+            )
+        };
+        yaml::Expanded { tokens, value }
     }
+}
 
+#[derive(Clone)]
+#[allow(clippy::large_enum_variant)]
+enum CommandSource {
+    Shell(CmdExpr),
+    /// `runtime!(expr)` or `runtime_checked!(expr)`: a command the generator
+    /// computes, which compile-time validation can't see
+    Runtime {
+        expr: syn::Expr,
+        span: proc_macro2::Span,
+        /// `runtime_checked!`: checked against the allowlist when built
+        checked: bool,
+    },
     #[cfg(feature = "bazel")]
-    fn from_dynamic_bazel(
+    Bazel(BazelExpr),
+    #[cfg(feature = "bazel")]
+    DynamicBazel {
+        /// `bazel_binary` in effect when the step was parsed
+        binary: String,
+        /// Startup options from the step's `startup_options`
+        startup: Vec<String>,
+        /// Workspace directory the command runs in, if not the default
+        workspace_dir: Option<String>,
         base_cmd: String,
         flags: Option<DynamicValue>,
         extra_flags: Vec<String>,
         target: Option<DynamicValue>,
         args: Vec<DynamicValue>,
-    ) -> Self {
-        Self(CommandSource::DynamicBazel {
-            base_cmd,
-            flags,
-            extra_flags,
-            target,
-            args,
-        })
-    }
+    },
+}
 
-    /// Get the command string value (for static commands only)
-    #[cfg(feature = "bazel")]
-    fn get_command_string(&self) -> String {
-        match &self.0 {
-            CommandSource::Shell(cmd) => cmd.command.clone(),
-            #[cfg(feature = "bazel")]
-            CommandSource::Bazel(bazel) => format!("bazel {}", bazel.command),
-            #[cfg(feature = "bazel")]
-            CommandSource::DynamicBazel {
-                base_cmd,
-                flags,
-                extra_flags,
-                target,
-                args,
-            } => {
-                let flags_str = match flags {
-                    Some(DynamicValue::Literal(s)) => s.clone(),
-                    Some(_) => "<dynamic-flags>".to_string(),
-                    None if !extra_flags.is_empty() => extra_flags.join(" "),
-                    None => String::new(),
-                };
-                let target_str = match target {
-                    Some(DynamicValue::Literal(s)) => s.clone(),
-                    Some(_) => "<dynamic-targets>".to_string(),
-                    None => String::new(),
-                };
-                let args_str = if args.is_empty() {
-                    String::new()
-                } else {
-                    let arg_strs: Vec<String> = args
-                        .iter()
-                        .map(|a| match a {
-                            DynamicValue::Literal(s) => s.clone(),
-                            _ => "<dynamic-arg>".to_string(),
-                        })
-                        .collect();
-                    format!("-- {}", arg_strs.join(" "))
-                };
-                format!(
-                    "bazel {} {} {} {}",
-                    base_cmd.trim(),
-                    flags_str,
-                    target_str,
-                    args_str
-                )
-                .split_whitespace()
-                .collect::<Vec<_>>()
-                .join(" ")
-            }
-        }
+/// Record `value` in `out` if it is computed at runtime.
+fn runtime_value(
+    value: &DynamicValue,
+    what: &'static str,
+    out: &mut Vec<(TokenStream2, &'static str)>,
+) {
+    if let DynamicValue::Runtime(expr) = value {
+        out.push((runtime_expr_tokens(expr), what));
     }
+}
 
-    /// Get the command name for allowlist validation
-    fn get_command_name(&self) -> String {
-        match &self.0 {
-            CommandSource::Shell(cmd) => cmd.command_name.clone(),
-            #[cfg(feature = "bazel")]
-            CommandSource::Bazel(_) => "bazel".to_string(),
-            #[cfg(feature = "bazel")]
-            CommandSource::DynamicBazel { .. } => "bazel".to_string(),
-        }
+/// The expression inside `runtime!(...)`, or `expr` itself.
+fn runtime_expr_tokens(expr: &syn::Expr) -> TokenStream2 {
+    match expr {
+        syn::Expr::Macro(mac) if mac.mac.path.is_ident("runtime") => mac.mac.tokens.clone(),
+        other => other.to_token_stream(),
     }
+}
 
-    /// Get undefined vars flagged by bashrs (SC2154)
-    /// These are vars that aren't defined inline in the script
-    fn get_undefined_vars(&self) -> &[String] {
-        match &self.0 {
-            CommandSource::Shell(cmd) => &cmd.undefined_vars,
-            #[cfg(feature = "bazel")]
-            CommandSource::Bazel(bazel) => &bazel.undefined_vars,
-            #[cfg(feature = "bazel")]
-            CommandSource::DynamicBazel { .. } => &[],
-        }
+/// An env var name: an identifier, or a string literal for names that aren't
+/// Rust identifiers, such as `"my.dotted.key"`.
+#[derive(Clone)]
+struct EnvKey {
+    name: String,
+    span: proc_macro2::Span,
+}
+
+impl EnvKey {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let (name, span) = if input.peek(LitStr) {
+            let lit: LitStr = input.parse()?;
+            (lit.value(), lit.span())
+        } else {
+            let ident: Ident = input.parse()?;
+            (ident.to_string(), ident.span())
+        };
+        check_env_name(&name).map_err(|msg| Error::new(span, msg))?;
+        Ok(Self { name, span })
     }
 
-    /// Get span for error reporting
     fn span(&self) -> proc_macro2::Span {
-        match &self.0 {
-            CommandSource::Shell(cmd) => cmd.span,
-            #[cfg(feature = "bazel")]
-            CommandSource::Bazel(bazel) => bazel.span,
-            #[cfg(feature = "bazel")]
-            CommandSource::DynamicBazel { .. } => proc_macro2::Span::call_site(),
-        }
+        self.span
     }
+}
 
-    /// Check if this is a bazel command
-    #[allow(dead_code)]
-    fn is_bazel(&self) -> bool {
-        match &self.0 {
-            CommandSource::Shell(_) => false,
-            #[cfg(feature = "bazel")]
-            CommandSource::Bazel(_) => true,
-            #[cfg(feature = "bazel")]
-            CommandSource::DynamicBazel { .. } => true,
-        }
+impl std::fmt::Display for EnvKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.name)
     }
+}
 
-    /// Check if this is a runtime expression (can't be validated at compile time)
-    #[allow(dead_code)]
-    fn is_runtime(&self) -> bool {
-        match &self.0 {
-            #[cfg(feature = "bazel")]
-            CommandSource::DynamicBazel { .. } => true,
-            _ => false,
-        }
+impl PartialEq for EnvKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
     }
+}
 
-    /// Get the bazel verb if this is a bazel command
-    #[cfg(feature = "bazel")]
-    #[allow(dead_code)]
-    fn bazel_verb(&self) -> Option<&str> {
-        match &self.0 {
-            CommandSource::Shell(_) => None,
-            CommandSource::Bazel(bazel) => Some(&bazel.verb),
-            CommandSource::DynamicBazel { base_cmd, .. } => Some(base_cmd.trim()),
-        }
+impl PartialEq<String> for EnvKey {
+    fn eq(&self, other: &String) -> bool {
+        self.name == *other
     }
+}
 
-    #[cfg(feature = "bazel")]
-    fn to_bazel_tokens_with_validation(&self, cmd_idx: usize) -> TokenStream2 {
-        match &self.0 {
-            CommandSource::Bazel(bazel) => {
-                let cmd_string = format!("bazel {}", bazel.command);
-                let verb = &bazel.verb;
-                let command = &bazel.command;
+/// Reject env var names no process could be given: empty names, names with
+/// whitespace, `=` or NUL, and names starting with a digit, which POSIX
+/// shells can't reference. Other punctuation is allowed, since an agent
+/// passes any name through to the job's environment.
+fn check_env_name(name: &str) -> std::result::Result<(), String> {
+    if name.is_empty() {
+        return Err("env var name is empty".to_string());
+    }
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        return Err(format!(
+            "env var name '{}' starts with a digit, which POSIX doesn't allow",
+            name
+        ));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| c.is_whitespace() || matches!(c, '=' | '\0'))
+    {
+        return Err(format!(
+            "env var name '{}' contains {}, which can't appear in an env var name",
+            name,
+            match c {
+                '=' => "'='".to_string(),
+                '\0' => "a NUL byte".to_string(),
+                _ => "whitespace".to_string(),
+            }
+        ));
+    }
+    Ok(())
+}
 
-                let args: Vec<&str> = command.split_whitespace().collect();
-                let targets = crate::targets::extract_targets_from_args(&args);
-                let targets_str = targets.join(" ");
+/// `(key, value)` tuple for the `helpers::env_map` argument slice. Literals
+/// are passed through; other values are formatted, as env values always are.
+fn string_pair_tokens(key: &str, value: &DynamicValue) -> TokenStream2 {
+    match value {
+        DynamicValue::Literal(s) | DynamicValue::Computed(s, _) => quote! { (#key, #s) },
+        DynamicValue::Comptime(_) | DynamicValue::Runtime(_) => {
+            let v_tokens = value.to_tokens();
+            quote! { (#key, &*(#v_tokens).to_string()) }
+        }
+    }
+}
 
-                let flags: Vec<&str> = args
-                    .iter()
-                    .filter(|a| a.starts_with('-') && !a.starts_with("-//") && !a.starts_with("-@"))
-                    .copied()
-                    .collect();
-                let flags_str = flags.join(" ");
+#[derive(Clone)]
+enum DynamicValue {
+    Literal(String),
+    /// Known at expansion time from `comptime_shell!`, `env!` or
+    /// `bazel_info!`, so it's validated like a literal
+    Computed(String, ValueOrigin),
+    Comptime(syn::Expr),
+    Runtime(syn::Expr),
+}
+
+/// Where a computed value came from. Errors about the value quote it, since
+/// what was checked isn't what's written in the source.
+#[derive(Clone)]
+struct ValueOrigin {
+    /// The invocation, e.g. `comptime_shell!("git log -1 --format=%s")`
+    call: String,
+    span: proc_macro2::Span,
+}
 
-                if targets_str.is_empty() && flags_str.is_empty() {
-                    quote! { #cmd_string.to_string() }
-                } else {
-                    quote! {
-                        {
-                            ::rust_buildkite::validation::validate_targets(#targets_str);
-                            ::rust_buildkite::validation::validate_flags_str(#verb, #flags_str);
-                            #cmd_string.to_string()
-                        }
-                    }
-                }
-            }
-            CommandSource::DynamicBazel {
-                base_cmd,
-                flags,
-                extra_flags,
-                target,
-                args,
-            } => {
-                let verb = base_cmd.trim();
-                let flags_var = format!("__flags_{}", cmd_idx);
-                let target_var = format!("__target_{}", cmd_idx);
+impl ValueOrigin {
+    fn of(mac: &syn::Macro) -> Self {
+        let ident = mac.path.segments.last().map(|s| &s.ident);
+        let name = ident.map_or_else(String::new, Ident::to_string);
+        // nb: options after the first literal, like comptime_shell!'s cwd,
+        // don't change which value is meant, so they're left out
+        let first = mac.tokens.clone().into_iter().next();
+        let args = match first {
+            Some(proc_macro2::TokenTree::Literal(lit)) => lit.to_string(),
+            _ => mac.tokens.to_string(),
+        };
+        ValueOrigin {
+            call: format!("{}!({})", name, args),
+            span: ident.map_or_else(proc_macro2::Span::call_site, Ident::span),
+        }
+    }
 
-                let (flags_validation, flags_tokens) = match flags {
-                    Some(dv) => dv.to_tokens_with_flags_validation(verb, &flags_var),
-                    None if !extra_flags.is_empty() => {
-                        let joined = extra_flags.join(" ");
-                        let flags_ident =
-                            syn::Ident::new(&flags_var, proc_macro2::Span::call_site());
-                        let validation = quote! {
-                            let #flags_ident = {
-                                let __val: String = #joined.to_string();
-                                ::rust_buildkite::validation::validate_flags_str(#verb, &__val);
-                                __val
-                            };
-                        };
-                        (validation, quote! { #flags_ident })
-                    }
-                    None => (quote! {}, quote! { "" }),
-                };
+    /// "value computed by ... at line L, column C evaluated to '...'"
+    fn describe(&self, value: &str) -> String {
+        let start = self.span.start();
+        format!(
+            "value computed by {} at line {}, column {} evaluated to '{}'",
+            self.call,
+            start.line,
+            start.column + 1,
+            value
+        )
+    }
+}
 
-                let (target_validation, target_tokens) = match target {
-                    Some(dv) => dv.to_tokens_with_target_validation(&target_var),
-                    None => (quote! {}, quote! { "" }),
-                };
+impl DynamicValue {
+    fn parse(input: ParseStream) -> Result<Self> {
+        if input.peek(LitStr) {
+            let lit: LitStr = input.parse()?;
+            Ok(DynamicValue::Literal(lit.value()))
+        } else {
+            let expr: syn::Expr = input.parse()?;
+            Self::from_expr(expr)
+        }
+    }
 
-                let args_tokens = if args.is_empty() {
-                    quote! { String::new() }
-                } else {
-                    let arg_exprs: Vec<TokenStream2> = args.iter().map(|a| a.to_tokens()).collect();
-                    quote! {
-                        {
-                            let __args: Vec<String> = vec![
-                                "--".to_string(),
-                                #(ToString::to_string(&#arg_exprs)),*
-                            ];
-                            __args.join(" ")
-                        }
-                    }
-                };
+    /// Parse a bazel `target_patterns`, `flags` or `args` value, noting
+    /// `comptime_shell!` output in `spliced` so it can be checked once the
+    /// command is assembled.
+    #[cfg(feature = "bazel")]
+    fn parse_spliced(
+        input: ParseStream,
+        target: bool,
+        spliced: &mut Vec<shell::Spliced>,
+    ) -> Result<Self> {
+        let from_shell = shell::peek(input);
+        let span = input.span();
+        let value = Self::parse(input)?;
+        if from_shell && let DynamicValue::Computed(output, _) = &value {
+            spliced.push(shell::Spliced {
+                output: output.clone(),
+                span,
+                target,
+            });
+        }
+        Ok(value)
+    }
 
-                let args_var = syn::Ident::new(
-                    &format!("__args_{}", cmd_idx),
-                    proc_macro2::Span::call_site(),
-                );
-                quote! {
-                    {
-                        #flags_validation
-                        #target_validation
-                        let #args_var = #args_tokens;
-                        format!("bazel {} {} {} {}", #base_cmd, #flags_tokens, #target_tokens, #args_var)
-                            .split_whitespace().collect::<Vec<_>>().join(" ")
-                    }
+    fn from_expr(expr: syn::Expr) -> Result<Self> {
+        if let syn::Expr::Macro(ref mac) = expr {
+            let macro_name = mac.mac.path.segments.last().map(|s| s.ident.to_string());
+            match macro_name.as_deref() {
+                Some("comptime") => Ok(DynamicValue::Comptime(expr)),
+                Some("runtime") => Ok(DynamicValue::Runtime(expr)),
+                Some("env") => resolve_env_macro(&mac.mac)
+                    .map(|value| DynamicValue::Computed(value, ValueOrigin::of(&mac.mac))),
+                Some("option_env") => Err(Error::new_spanned(
+                    &mac.mac,
+                    "option_env! gives an Option; add a default, e.g. option_env!(\"NAME\").unwrap_or(\"unknown\")",
+                )),
+                Some("comptime_shell") => mac
+                    .mac
+                    .parse_body::<shell::ShellCommand>()?
+                    .run()
+                    .map(|value| DynamicValue::Computed(value, ValueOrigin::of(&mac.mac))),
+                #[cfg(feature = "bazel")]
+                Some("bazel_info") => {
+                    let key = syn::parse2::<LitStr>(mac.mac.tokens.clone()).map_err(|_| {
+                        Error::new_spanned(&mac.mac, "bazel_info! requires a string literal key")
+                    })?;
+                    run_bazel_info(&key)
+                        .map(|value| DynamicValue::Computed(value, ValueOrigin::of(&mac.mac)))
                 }
+                _ => Ok(DynamicValue::Runtime(expr)),
             }
-            _ => {
-                let cmd_string = self.get_command_string();
-                quote! { #cmd_string.to_string() }
-            }
+        } else {
+            Ok(DynamicValue::Runtime(expr))
         }
     }
 
-    fn to_shell_tokens_with_validation(&self) -> TokenStream2 {
-        match &self.0 {
-            CommandSource::Shell(cmd) => {
-                let cmd_string = &cmd.command;
-                let cmd_name = &cmd.command_name;
+    #[cfg(feature = "bazel")]
+    fn is_dynamic(&self) -> bool {
+        self.as_literal().is_none()
+    }
 
-                if cmd_name.starts_with('/') || cmd_name.starts_with("./") || cmd_name.contains('/')
-                {
-                    quote! {
-                        {
-                            ::rust_buildkite::validation::validate_path(#cmd_name);
-                            #cmd_string.to_string()
+    /// The value, when it's known at expansion time.
+    fn as_literal(&self) -> Option<&str> {
+        match self {
+            DynamicValue::Literal(s) | DynamicValue::Computed(s, _) => Some(s),
+            DynamicValue::Comptime(_) | DynamicValue::Runtime(_) => None,
+        }
+    }
+
+    /// A line for errors about a computed value saying where it came from,
+    /// or nothing for any other value.
+    fn origin_note(&self) -> String {
+        match self {
+            DynamicValue::Computed(value, origin) => format!("\n{}", origin.describe(value)),
+            _ => String::new(),
+        }
+    }
+
+    fn to_tokens(&self) -> TokenStream2 {
+        match self {
+            DynamicValue::Literal(s) | DynamicValue::Computed(s, _) => quote! { #s },
+            DynamicValue::Comptime(expr) | DynamicValue::Runtime(expr) => {
+                if let syn::Expr::Macro(mac) = expr {
+                    let macro_name = mac.mac.path.segments.last().map(|s| s.ident.to_string());
+                    match macro_name.as_deref() {
+                        Some("runtime") | Some("comptime") => {
+                            let inner = &mac.mac.tokens;
+                            quote! { #inner }
                         }
+                        _ => quote! { #expr },
                     }
                 } else {
-                    quote! { #cmd_string.to_string() }
+                    quote! { #expr }
                 }
             }
-            #[cfg(feature = "bazel")]
-            _ => {
-                let cmd_string = self.get_command_string();
-                quote! { #cmd_string.to_string() }
-            }
         }
     }
-}
-
-struct CommandStepDef {
-    commands: Vec<CommandValue>,
-    label: Option<syn::Expr>,
-    key: Option<KeyValue>,
-    depends_on: Vec<(String, proc_macro2::Span)>,
-    env: Vec<(String, DynamicValue)>,
-    timeout_in_minutes: Option<syn::LitInt>,
-    soft_fail: bool,
-    parallelism: Option<syn::LitInt>,
-    artifact_paths: Vec<LitStr>,
-    agents: Vec<(String, LitStr)>,
-    branches: Vec<LitStr>,
-    if_condition: Option<LitStr>,
-    cache: Vec<LitStr>,
-    retry: Option<RetryConfig>,
-    plugins: Vec<NestedValue>,
-    notify: Vec<NestedValue>,
-    matrix: Option<NestedValue>,
-    concurrency: Option<syn::LitInt>,
-    concurrency_group: Option<LitStr>,
-    skip: Option<SkipValue>,
-    priority: Option<syn::LitInt>,
-    allow_dependency_failure: bool,
-}
 
-/// Retry configuration for command steps
-#[derive(Clone, Default)]
-struct RetryConfig {
-    automatic: Option<NestedValue>,
-    manual: Option<NestedValue>,
-}
+    #[cfg(feature = "bazel")]
+    fn to_tokens_with_target_validation(&self, var_name: &str) -> (TokenStream2, TokenStream2) {
+        let var_ident = syn::Ident::new(var_name, proc_macro2::Span::call_site());
+        let inner = self.to_tokens();
+        let validation = quote! {
+            let #var_ident = {
+                let __val: String = (#inner).to_string();
+                ::rust_buildkite::validation::validate_targets(&__val);
+                __val
+            };
+        };
+        let usage = quote! { #var_ident };
+        (validation, usage)
+    }
 
-/// Skip value - can be bool or string reason
-#[derive(Clone)]
-enum SkipValue {
-    Bool(bool),
-    Reason(String),
+    #[cfg(feature = "bazel")]
+    fn to_tokens_with_flags_validation(
+        &self,
+        verb: &str,
+        var_name: &str,
+    ) -> (TokenStream2, TokenStream2) {
+        let var_ident = syn::Ident::new(var_name, proc_macro2::Span::call_site());
+        let inner = self.to_tokens();
+        let validation = quote! {
+            let #var_ident = {
+                let __val: String = (#inner).to_string();
+                ::rust_buildkite::validation::validate_flags_str(#verb, &__val);
+                __val
+            };
+        };
+        let usage = quote! { #var_ident };
+        (validation, usage)
+    }
 }
 
-/// Field definition for block/input steps
+/// Key value that can be literal (validated) or runtime (not validated).
 #[derive(Clone)]
-enum FieldDef {
-    Text(TextFieldDef),
-    Select(SelectFieldDef),
+enum KeyValue {
+    Literal(String, proc_macro2::Span),
+    Runtime(Box<syn::Expr>),
 }
 
-/// Text field for block/input steps
-#[derive(Clone, Default)]
-struct TextFieldDef {
-    key: String,
-    text: Option<String>,
-    hint: Option<String>,
-    required: Option<bool>,
-    default: Option<String>,
-    format: Option<String>,
-}
+impl KeyValue {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let span = input.span();
+        if input.peek(LitStr) {
+            let lit: LitStr = input.parse()?;
+            Ok(KeyValue::Literal(lit.value(), lit.span()))
+        } else {
+            let expr: syn::Expr = input.parse()?;
+            if let syn::Expr::Macro(ref mac) = expr {
+                let macro_name = mac.mac.path.segments.last().map(|s| s.ident.to_string());
+                if macro_name.as_deref() == Some("runtime") {
+                    return Ok(KeyValue::Runtime(Box::new(expr)));
+                }
+            }
+            Err(syn::Error::new(
+                span,
+                "key must be a string literal or runtime!(expr)",
+            ))
+        }
+    }
 
-/// Select field for block/input steps
-#[derive(Clone, Default)]
-struct SelectFieldDef {
-    key: String,
-    select: Option<String>,
-    hint: Option<String>,
-    required: Option<bool>,
-    default: Option<String>,
-    multiple: Option<bool>,
-    options: Vec<SelectFieldOptionDef>,
+    fn as_literal(&self) -> Option<(&str, proc_macro2::Span)> {
+        match self {
+            KeyValue::Literal(s, span) => Some((s, *span)),
+            KeyValue::Runtime(_) => None,
+        }
+    }
+
+    fn to_tokens(&self) -> TokenStream2 {
+        match self {
+            KeyValue::Literal(s, _) => quote! { #s.to_string() },
+            KeyValue::Runtime(expr) => {
+                if let syn::Expr::Macro(mac) = expr.as_ref() {
+                    let inner = &mac.mac.tokens;
+                    quote! { #inner.to_string() }
+                } else {
+                    quote! { #expr.to_string() }
+                }
+            }
+        }
+    }
 }
 
-/// Option for select field
 #[derive(Clone)]
-struct SelectFieldOptionDef {
-    label: String,
-    value: String,
-}
+struct CommandValue(CommandSource);
 
-impl FieldDef {
-    fn parse(input: ParseStream) -> Result<Self> {
-        let field_type: Ident = input.parse()?;
+impl CommandValue {
+    fn from_cmd(cmd: CmdExpr) -> Self {
+        Self(CommandSource::Shell(cmd))
+    }
+
+    /// Whether `name` starts a command computed at runtime.
+    fn is_runtime_macro(name: &Ident) -> bool {
+        name == "runtime" || name == "runtime_checked"
+    }
+
+    /// Parse the rest of `runtime!(expr)` or `runtime_checked!(expr)` after
+    /// `name`.
+    fn parse_runtime(name: &Ident, input: ParseStream) -> Result<Self> {
+        input.parse::<Token![!]>()?;
         let content;
-        braced!(content in input);
+        syn::parenthesized!(content in input);
+        let expr: syn::Expr = content.parse().map_err(|_| {
+            Error::new(
+                name.span(),
+                format!("{}! requires an expression giving the command", name),
+            )
+        })?;
+        Ok(Self(CommandSource::Runtime {
+            expr,
+            span: name.span(),
+            checked: name == "runtime_checked",
+        }))
+    }
 
-        match field_type.to_string().as_str() {
-            "text" => {
-                let mut field = TextFieldDef::default();
-                while !content.is_empty() {
-                    let key: Ident = content.parse()?;
-                    content.parse::<Token![:]>()?;
-                    match key.to_string().as_str() {
-                        "key" => {
-                            let k: LitStr = content.parse()?;
-                            field.key = k.value();
-                        }
-                        "text" | "label" => {
-                            let t: LitStr = content.parse()?;
-                            field.text = Some(t.value());
-                        }
-                        "hint" => {
-                            let h: LitStr = content.parse()?;
-                            field.hint = Some(h.value());
-                        }
-                        "required" => {
-                            let r: syn::LitBool = content.parse()?;
-                            field.required = Some(r.value());
-                        }
-                        "default" => {
-                            let d: LitStr = content.parse()?;
-                            field.default = Some(d.value());
-                        }
-                        "format" => {
-                            let f: LitStr = content.parse()?;
-                            field.format = Some(f.value());
-                        }
-                        other => {
-                            return Err(Error::new(
-                                key.span(),
-                                format!("unknown text field property: {}", other),
-                            ));
-                        }
-                    }
-                    if content.peek(Token![,]) {
-                        content.parse::<Token![,]>()?;
-                    }
+    #[cfg(feature = "bazel")]
+    fn from_bazel(bazel: BazelExpr) -> Self {
+        Self(CommandSource::Bazel(bazel))
+    }
+
+    #[cfg(feature = "bazel")]
+    fn from_dynamic_bazel(
+        startup: Vec<String>,
+        base_cmd: String,
+        flags: Option<DynamicValue>,
+        extra_flags: Vec<String>,
+        target: Option<DynamicValue>,
+        args: Vec<DynamicValue>,
+    ) -> Self {
+        Self(CommandSource::DynamicBazel {
+            binary: bazel_defaults::binary(),
+            startup,
+            workspace_dir: bazel_defaults::workspace(),
+            base_cmd,
+            flags,
+            extra_flags,
+            target,
+            args,
+        })
+    }
+
+    /// Add `flag` (`--build_event_json_file=<path>`) to a bazel command whose
+    /// verb writes build events. Returns false, leaving the command alone, for
+    /// shell commands, other verbs and commands that already set the option.
+    #[cfg(feature = "bazel")]
+    fn add_bep_flag(&mut self, flag: &str) -> bool {
+        const BEP_VERBS: &[&str] = &["build", "test", "run", "coverage"];
+        match &mut self.0 {
+            CommandSource::Shell(_) | CommandSource::Runtime { .. } => false,
+            CommandSource::Bazel(bazel) => {
+                if !BEP_VERBS.contains(&bazel.verb.as_str())
+                    || bazel.command.contains(bep::JSON_FILE_FLAG)
+                {
+                    return false;
                 }
-                Ok(FieldDef::Text(field))
+                bazel.command = bazel_defaults::merge(&bazel.command, &[flag.to_string()]);
+                true
             }
-            "select" => {
-                let mut field = SelectFieldDef::default();
-                while !content.is_empty() {
-                    let key: Ident = content.parse()?;
-                    content.parse::<Token![:]>()?;
-                    match key.to_string().as_str() {
-                        "key" => {
-                            let k: LitStr = content.parse()?;
-                            field.key = k.value();
-                        }
-                        "select" | "label" => {
-                            let s: LitStr = content.parse()?;
-                            field.select = Some(s.value());
-                        }
-                        "hint" => {
-                            let h: LitStr = content.parse()?;
-                            field.hint = Some(h.value());
-                        }
-                        "required" => {
-                            let r: syn::LitBool = content.parse()?;
-                            field.required = Some(r.value());
-                        }
-                        "default" => {
-                            let d: LitStr = content.parse()?;
-                            field.default = Some(d.value());
-                        }
-                        "multiple" => {
-                            let m: syn::LitBool = content.parse()?;
-                            field.multiple = Some(m.value());
-                        }
-                        "options" => {
-                            let options_content;
-                            bracketed!(options_content in content);
-                            while !options_content.is_empty() {
-                                let opt_content;
-                                braced!(opt_content in options_content);
-                                let mut label = String::new();
-                                let mut value = String::new();
-                                while !opt_content.is_empty() {
-                                    let opt_key: Ident = opt_content.parse()?;
-                                    opt_content.parse::<Token![:]>()?;
-                                    match opt_key.to_string().as_str() {
-                                        "label" => {
-                                            let l: LitStr = opt_content.parse()?;
-                                            label = l.value();
-                                        }
-                                        "value" => {
-                                            let v: LitStr = opt_content.parse()?;
-                                            value = v.value();
-                                        }
-                                        _ => {}
-                                    }
-                                    if opt_content.peek(Token![,]) {
-                                        opt_content.parse::<Token![,]>()?;
-                                    }
-                                }
-                                field.options.push(SelectFieldOptionDef { label, value });
-                                if options_content.peek(Token![,]) {
-                                    options_content.parse::<Token![,]>()?;
-                                }
-                            }
-                        }
-                        other => {
-                            return Err(Error::new(
-                                key.span(),
-                                format!("unknown select field property: {}", other),
-                            ));
-                        }
-                    }
-                    if content.peek(Token![,]) {
-                        content.parse::<Token![,]>()?;
-                    }
+            CommandSource::DynamicBazel {
+                base_cmd,
+                extra_flags,
+                ..
+            } => {
+                if !BEP_VERBS.contains(&base_cmd.trim())
+                    || extra_flags.iter().any(|f| f.contains(bep::JSON_FILE_FLAG))
+                {
+                    return false;
                 }
-                Ok(FieldDef::Select(field))
+                extra_flags.push(flag.to_string());
+                true
             }
-            other => Err(Error::new(
-                field_type.span(),
-                format!("unknown field type: {}. Expected 'text' or 'select'", other),
-            )),
         }
     }
 
-    fn to_tokens_inner(&self) -> TokenStream2 {
-        match self {
-            FieldDef::Text(f) => {
-                let key = &f.key;
-                let text_tokens = if let Some(t) = &f.text {
-                    quote! { .text(Some(#t.to_string())) }
-                } else {
-                    quote! {}
-                };
-                let hint_tokens = if let Some(h) = &f.hint {
-                    quote! { .hint(Some(#h.to_string())) }
-                } else {
-                    quote! {}
-                };
-                let required_tokens = if let Some(r) = f.required {
-                    quote! { .required(#r) }
-                } else {
-                    quote! {}
+    /// Get the command string value (for static commands only)
+    #[cfg(feature = "bazel")]
+    fn get_command_string(&self) -> String {
+        match &self.0 {
+            CommandSource::Shell(cmd) => cmd.command.clone(),
+            CommandSource::Runtime { .. } => "<runtime-command>".to_string(),
+            #[cfg(feature = "bazel")]
+            CommandSource::Bazel(bazel) => {
+                in_workspace(bazel.workspace_dir.as_deref(), bazel.invocation())
+            }
+            #[cfg(feature = "bazel")]
+            CommandSource::DynamicBazel {
+                binary,
+                startup,
+                workspace_dir,
+                base_cmd,
+                flags,
+                extra_flags,
+                target,
+                args,
+            } => {
+                let flags_str = match flags {
+                    Some(DynamicValue::Literal(s) | DynamicValue::Computed(s, _)) => s.clone(),
+                    Some(_) => "<dynamic-flags>".to_string(),
+                    None if !extra_flags.is_empty() => extra_flags.join(" "),
+                    None => String::new(),
                 };
-                let default_tokens = if let Some(d) = &f.default {
-                    quote! { .default(Some(#d.to_string())) }
-                } else {
-                    quote! {}
+                let target_str = match target {
+                    Some(DynamicValue::Literal(s) | DynamicValue::Computed(s, _)) => s.clone(),
+                    Some(_) => "<dynamic-targets>".to_string(),
+                    None => String::new(),
                 };
-                let format_tokens = if let Some(fmt) = &f.format {
-                    quote! { .format(Some(#fmt.to_string())) }
+                let args_str = if args.is_empty() {
+                    String::new()
                 } else {
-                    quote! {}
+                    let arg_strs: Vec<String> = args
+                        .iter()
+                        .map(|a| match a {
+                            DynamicValue::Literal(s) | DynamicValue::Computed(s, _) => s.clone(),
+                            _ => "<dynamic-arg>".to_string(),
+                        })
+                        .collect();
+                    format!("-- {}", arg_strs.join(" "))
                 };
-                quote! {
-                    ::rust_buildkite::FieldsItem::TextField(
-                        ::rust_buildkite::TextField::builder()
-                            .key(#key.parse::<::rust_buildkite::TextFieldKey>().expect("invalid key"))
-                            #text_tokens
-                            #hint_tokens
-                            #required_tokens
-                            #default_tokens
-                            #format_tokens
-                            .try_into()
-                            .expect("text field construction failed")
-                    )
-                }
+                let command = format!(
+                    "{} {} {} {} {} {}",
+                    binary,
+                    startup.join(" "),
+                    base_cmd.trim(),
+                    flags_str,
+                    target_str,
+                    args_str
+                )
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ");
+                in_workspace(workspace_dir.as_deref(), command)
+            }
+        }
+    }
+
+    /// Get the command name for allowlist validation
+    fn get_command_name(&self) -> String {
+        match &self.0 {
+            CommandSource::Shell(cmd) => cmd.command_name.clone(),
+            CommandSource::Runtime { .. } => String::new(),
+            #[cfg(feature = "bazel")]
+            CommandSource::Bazel(bazel) => bazel.binary.clone(),
+            #[cfg(feature = "bazel")]
+            CommandSource::DynamicBazel { binary, .. } => binary.clone(),
+        }
+    }
+
+    /// Get undefined vars flagged by bashrs (SC2154)
+    /// These are vars that aren't defined inline in the script
+    fn get_undefined_vars(&self) -> &[String] {
+        match &self.0 {
+            CommandSource::Shell(cmd) => &cmd.undefined_vars,
+            CommandSource::Runtime { .. } => &[],
+            #[cfg(feature = "bazel")]
+            CommandSource::Bazel(bazel) => &bazel.undefined_vars,
+            #[cfg(feature = "bazel")]
+            CommandSource::DynamicBazel { .. } => &[],
+        }
+    }
+
+    /// Span and caret snippet locating the command name, for allowlist errors
+    fn locate_command_name(&self) -> (proc_macro2::Span, String) {
+        match &self.0 {
+            CommandSource::Shell(cmd) => cmd.locate_command_name(),
+            CommandSource::Runtime { span, .. } => (*span, String::new()),
+            #[cfg(feature = "bazel")]
+            CommandSource::Bazel(_) | CommandSource::DynamicBazel { .. } => {
+                (self.span(), String::new())
             }
-            FieldDef::Select(f) => {
-                let key = &f.key;
-                let select_tokens = if let Some(s) = &f.select {
-                    quote! { .select(Some(#s.to_string())) }
-                } else {
-                    quote! {}
-                };
-                let hint_tokens = if let Some(h) = &f.hint {
-                    quote! { .hint(Some(#h.to_string())) }
-                } else {
-                    quote! {}
-                };
-                let required_tokens = if let Some(r) = f.required {
-                    quote! { .required(#r) }
-                } else {
-                    quote! {}
-                };
-                let default_tokens = if let Some(d) = &f.default {
-                    quote! { .default(::rust_buildkite::SelectFieldDefault::String(#d.to_string())) }
-                } else {
-                    quote! {}
-                };
-                let multiple_tokens = if let Some(m) = f.multiple {
-                    quote! { .multiple(#m) }
-                } else {
-                    quote! {}
-                };
-                let options: Vec<TokenStream2> = f
-                    .options
-                    .iter()
-                    .map(|opt| {
-                        let label = &opt.label;
-                        let value = &opt.value;
-                        quote! {
-                            ::rust_buildkite::SelectFieldOption::builder()
-                                .label(#label.to_string())
-                                .value(#value.to_string())
-                                .try_into()
-                                .expect("select option construction failed")
-                        }
-                    })
-                    .collect();
-                quote! {
-                    ::rust_buildkite::FieldsItem::SelectField(
-                        ::rust_buildkite::SelectField::builder()
-                            .key(#key.parse::<::rust_buildkite::SelectFieldKey>().expect("invalid key"))
-                            #select_tokens
-                            #hint_tokens
-                            #required_tokens
-                            #default_tokens
-                            #multiple_tokens
-                            .options(vec![#(#options),*])
-                            .try_into()
-                            .expect("select field construction failed")
-                    )
-                }
+        }
+    }
+
+    /// Span and caret snippet locating a `$var` reference, for env errors
+    fn locate_var(&self, var: &str) -> (proc_macro2::Span, String) {
+        match &self.0 {
+            CommandSource::Shell(cmd) => cmd.locate_var(var),
+            CommandSource::Runtime { span, .. } => (*span, String::new()),
+            #[cfg(feature = "bazel")]
+            CommandSource::Bazel(_) | CommandSource::DynamicBazel { .. } => {
+                (self.span(), String::new())
             }
         }
     }
-}
 
-impl CommandStepDef {
-    fn new_with_cmd(cmd_expr: CmdExpr) -> Self {
-        Self {
-            commands: vec![CommandValue::from_cmd(cmd_expr)],
-            label: None,
-            key: None,
-            depends_on: Vec::new(),
-            env: Vec::new(),
-            timeout_in_minutes: None,
-            soft_fail: false,
-            parallelism: None,
-            artifact_paths: Vec::new(),
-            agents: Vec::new(),
-            branches: Vec::new(),
-            if_condition: None,
-            cache: Vec::new(),
-            retry: None,
-            plugins: Vec::new(),
-            notify: Vec::new(),
-            matrix: None,
-            concurrency: None,
-            concurrency_group: None,
-            skip: None,
-            priority: None,
-            allow_dependency_failure: false,
+    /// Span and caret snippet for `range` of the static command text.
+    fn locate(&self, range: std::ops::Range<usize>) -> (proc_macro2::Span, String) {
+        match &self.0 {
+            CommandSource::Shell(cmd) => (
+                cmd.span_of(range.clone()),
+                caret_snippet(&cmd.command, range),
+            ),
+            _ => (self.span(), String::new()),
         }
     }
 
-    #[cfg(feature = "bazel")]
-    fn new_with_bazel(bazel_expr: BazelExpr) -> Self {
-        Self {
-            commands: vec![CommandValue::from_bazel(bazel_expr)],
-            label: None,
-            key: None,
-            depends_on: Vec::new(),
-            env: Vec::new(),
-            timeout_in_minutes: None,
-            soft_fail: false,
-            parallelism: None,
-            artifact_paths: Vec::new(),
-            agents: Vec::new(),
-            branches: Vec::new(),
-            if_condition: None,
-            cache: Vec::new(),
-            retry: None,
-            plugins: Vec::new(),
-            notify: Vec::new(),
-            matrix: None,
-            concurrency: None,
-            concurrency_group: None,
-            skip: None,
-            priority: None,
-            allow_dependency_failure: false,
+    /// Static command text, if known at compile time
+    fn command_text(&self) -> Option<&str> {
+        match &self.0 {
+            CommandSource::Shell(cmd) => Some(&cmd.command),
+            CommandSource::Runtime { .. } => None,
+            #[cfg(feature = "bazel")]
+            CommandSource::Bazel(bazel) => Some(&bazel.command),
+            #[cfg(feature = "bazel")]
+            CommandSource::DynamicBazel { .. } => None,
         }
     }
 
-    fn new_empty() -> Self {
-        Self {
-            commands: Vec::new(),
-            label: None,
-            key: None,
-            depends_on: Vec::new(),
-            env: Vec::new(),
-            timeout_in_minutes: None,
-            soft_fail: false,
-            parallelism: None,
-            artifact_paths: Vec::new(),
-            agents: Vec::new(),
-            branches: Vec::new(),
-            if_condition: None,
-            cache: Vec::new(),
-            retry: None,
-            plugins: Vec::new(),
-            notify: Vec::new(),
-            matrix: None,
-            concurrency: None,
-            concurrency_group: None,
-            skip: None,
-            priority: None,
-            allow_dependency_failure: false,
+    /// bashrs info-level diagnostics for a shell command, for the
+    /// shell_info lint
+    fn info_diagnostics(&self) -> Vec<(proc_macro2::Span, String)> {
+        match &self.0 {
+            CommandSource::Shell(cmd) => cmd.info_diagnostics(),
+            _ => Vec::new(),
         }
     }
 
-    fn get_command_names(&self) -> Vec<(String, proc_macro2::Span)> {
-        self.commands
-            .iter()
-            .map(|cv| (cv.get_command_name(), cv.span()))
-            .collect()
+    fn shell_mut(&mut self) -> Option<&mut CmdExpr> {
+        match &mut self.0 {
+            CommandSource::Shell(cmd) => Some(cmd),
+            CommandSource::Runtime { .. } => None,
+            #[cfg(feature = "bazel")]
+            CommandSource::Bazel(_) | CommandSource::DynamicBazel { .. } => None,
+        }
     }
 
-    fn to_tokens_inner(&self) -> TokenStream2 {
-        assert!(!self.commands.is_empty(), "commands must not be empty");
+    /// Time spent linting the command with bashrs; zero for bazel and
+    /// runtime commands
+    fn lint_time(&self) -> std::time::Duration {
+        match &self.0 {
+            CommandSource::Shell(cmd) => cmd.lint_time,
+            CommandSource::Runtime { .. } => std::time::Duration::ZERO,
+            #[cfg(feature = "bazel")]
+            CommandSource::Bazel(_) | CommandSource::DynamicBazel { .. } => {
+                std::time::Duration::ZERO
+            }
+        }
+    }
 
-        let cmd_token_list: Vec<TokenStream2> = self
-            .commands
-            .iter()
-            .enumerate()
-            .map(|(_idx, cmd_value)| {
-                #[cfg(feature = "bazel")]
-                if matches!(
-                    &cmd_value.0,
-                    CommandSource::Bazel(_) | CommandSource::DynamicBazel { .. }
-                ) {
-                    return cmd_value.to_bazel_tokens_with_validation(_idx);
+    /// Collect parts of the command that are computed at runtime.
+    fn collect_runtime_values(&self, out: &mut Vec<(TokenStream2, &'static str)>) {
+        match &self.0 {
+            CommandSource::Shell(cmd) => {
+                for (_, value) in &cmd.bound_args {
+                    out.push((value.clone(), "the command value"));
                 }
-                cmd_value.to_shell_tokens_with_validation()
-            })
-            .collect();
-
-        let command_tokens = if cmd_token_list.len() == 1 {
-            let cmd = &cmd_token_list[0];
-            quote! { .command(Some(::rust_buildkite::CommandStepCommand::String(#cmd))) }
-        } else {
-            quote! {
-                .commands(Some(::rust_buildkite::CommandStepCommand::Array(vec![
-                    #(#cmd_token_list),*
-                ])))
             }
-        };
+            CommandSource::Runtime { expr, .. } => {
+                out.push((expr.to_token_stream(), "the command"));
+            }
+            #[cfg(feature = "bazel")]
+            CommandSource::Bazel(_) => {}
+            #[cfg(feature = "bazel")]
+            CommandSource::DynamicBazel {
+                flags,
+                target,
+                args,
+                ..
+            } => {
+                for value in flags.iter().chain(target).chain(args) {
+                    runtime_value(value, "the bazel command value", out);
+                }
+            }
+        }
+    }
 
-        let label_tokens = if let Some(label) = &self.label {
-            quote! { .label(Some(::rust_buildkite::Label(#label.to_string()))) }
-        } else {
-            quote! {}
-        };
+    /// Whether this command was written with `unchecked_cmd!`
+    fn is_unchecked(&self) -> bool {
+        matches!(&self.0, CommandSource::Shell(cmd) if cmd.unchecked)
+    }
 
-        let key_tokens = if let Some(key) = &self.key {
-            let key_value = key.to_tokens();
-            quote! { .key(Some(#key_value.try_into().expect("invalid key"))) }
-        } else {
-            quote! {}
-        };
+    /// Whether this command was generated by `set_metadata`, `metadata` or
+    /// `needs_artifacts`
+    fn is_helper(&self) -> bool {
+        matches!(&self.0, CommandSource::Shell(cmd) if cmd.helper)
+    }
 
-        let depends_on_tokens = if !self.depends_on.is_empty() {
-            let deps: Vec<_> = self.depends_on.iter().map(|(d, _)| d).collect();
-            quote! {
-                .depends_on(Some(::rust_buildkite::DependsOn::DependsOnList(
-                    ::rust_buildkite::DependsOnList(vec![
-                        #(::rust_buildkite::DependsOnListItem::String(#deps.to_string())),*
-                    ])
-                )))
-            }
-        } else {
-            quote! {}
-        };
+    /// Get span for error reporting
+    fn span(&self) -> proc_macro2::Span {
+        match &self.0 {
+            CommandSource::Shell(cmd) => cmd.span,
+            CommandSource::Runtime { span, .. } => *span,
+            #[cfg(feature = "bazel")]
+            CommandSource::Bazel(bazel) => bazel.span,
+            #[cfg(feature = "bazel")]
+            CommandSource::DynamicBazel { .. } => proc_macro2::Span::call_site(),
+        }
+    }
 
-        let timeout_tokens = if let Some(timeout) = &self.timeout_in_minutes {
-            quote! { .timeout_in_minutes(Some(::std::num::NonZeroU64::new(#timeout).expect("timeout must be > 0"))) }
-        } else {
-            quote! {}
-        };
+    /// Check if this is a bazel command
+    fn is_bazel(&self) -> bool {
+        match &self.0 {
+            CommandSource::Shell(_) | CommandSource::Runtime { .. } => false,
+            #[cfg(feature = "bazel")]
+            CommandSource::Bazel(_) => true,
+            #[cfg(feature = "bazel")]
+            CommandSource::DynamicBazel { .. } => true,
+        }
+    }
 
-        let soft_fail_tokens = if self.soft_fail {
-            quote! { .soft_fail(Some(::rust_buildkite::SoftFail::Boolean(true))) }
-        } else {
-            quote! {}
-        };
+    /// Check if this is a runtime expression (can't be validated at compile time)
+    #[allow(dead_code)]
+    fn is_runtime(&self) -> bool {
+        match &self.0 {
+            CommandSource::Runtime { .. } => true,
+            #[cfg(feature = "bazel")]
+            CommandSource::DynamicBazel { .. } => true,
+            _ => false,
+        }
+    }
 
-        let parallelism_tokens = if let Some(p) = &self.parallelism {
-            quote! { .parallelism(Some(#p)) }
-        } else {
-            quote! {}
-        };
+    /// Workspace directory a bazel command runs in, if not the default
+    #[cfg(feature = "bazel")]
+    fn workspace_dir(&self) -> Option<&str> {
+        match &self.0 {
+            CommandSource::Shell(_) | CommandSource::Runtime { .. } => None,
+            CommandSource::Bazel(bazel) => bazel.workspace_dir.as_deref(),
+            CommandSource::DynamicBazel { workspace_dir, .. } => workspace_dir.as_deref(),
+        }
+    }
 
-        let artifact_tokens = if !self.artifact_paths.is_empty() {
-            let paths = &self.artifact_paths;
-            quote! {
-                .artifact_paths(Some(::rust_buildkite::CommandStepArtifactPaths::Array(vec![
-                    #(#paths.to_string()),*
-                ])))
-            }
-        } else {
-            quote! {}
-        };
+    /// The parsed bazel command, if this is a static bazel command
+    #[cfg(feature = "bazel")]
+    fn bazel_expr(&self) -> Option<&BazelExpr> {
+        match &self.0 {
+            CommandSource::Bazel(bazel) => Some(bazel),
+            _ => None,
+        }
+    }
 
-        let env_tokens = if !self.env.is_empty() {
-            let env_inserts: Vec<TokenStream2> = self
-                .env
-                .iter()
-                .map(|(k, v)| {
-                    let v_tokens = v.to_tokens();
-                    quote! {
-                        __step_env.insert(#k.to_string(), ::rust_buildkite::serde_json::Value::String((#v_tokens).to_string()));
-                    }
-                })
-                .collect();
+    /// The parsed bazel command, if it still has checks that invoke bazel
+    #[cfg(feature = "bazel")]
+    fn pending_bazel_checks(&self) -> Option<&BazelExpr> {
+        match &self.0 {
+            CommandSource::Bazel(bazel) if bazel.deferred.is_some() => Some(bazel),
+            _ => None,
+        }
+    }
 
-            quote! {
-                .env({
-                    let mut __step_env = ::rust_buildkite::serde_json::Map::new();
-                    #(#env_inserts)*
-                    Some(::rust_buildkite::Env(__step_env))
-                })
-            }
-        } else {
-            quote! {}
-        };
+    /// Get the bazel verb if this is a bazel command
+    #[cfg(feature = "bazel")]
+    #[allow(dead_code)]
+    fn bazel_verb(&self) -> Option<&str> {
+        match &self.0 {
+            CommandSource::Shell(_) | CommandSource::Runtime { .. } => None,
+            CommandSource::Bazel(bazel) => Some(&bazel.verb),
+            CommandSource::DynamicBazel { base_cmd, .. } => Some(base_cmd.trim()),
+        }
+    }
+
+    #[cfg(feature = "bazel")]
+    fn to_bazel_tokens_with_validation(&self, cmd_idx: usize) -> TokenStream2 {
+        match &self.0 {
+            CommandSource::Bazel(bazel) => {
+                let cmd_string = in_workspace(bazel.workspace_dir.as_deref(), bazel.invocation());
+                let verb = &bazel.verb;
+                let command = &bazel.command;
+
+                let args: Vec<&str> = command.split_whitespace().collect();
+                let targets = crate::targets::extract_targets_from_args(&args);
+                let targets_str = targets.join(" ");
 
-        let agents_tokens = if !self.agents.is_empty() {
-            let agent_inserts: Vec<TokenStream2> = self
-                .agents
-                .iter()
-                .map(|(k, v)| {
+                let flags: Vec<&str> = args
+                    .iter()
+                    .filter(|a| a.starts_with('-') && !a.starts_with("-//") && !a.starts_with("-@"))
+                    .copied()
+                    .collect();
+                let flags_str = flags.join(" ");
+
+                if targets_str.is_empty() && flags_str.is_empty() {
+                    quote! { #cmd_string.to_string() }
+                } else {
                     quote! {
-                        __step_agents.insert(#k.to_string(), ::rust_buildkite::serde_json::Value::String(#v.to_string()));
+                        {
+                            ::rust_buildkite::validation::validate_targets(#targets_str);
+                            ::rust_buildkite::validation::validate_flags_str(#verb, #flags_str);
+                            #cmd_string.to_string()
+                        }
                     }
-                })
-                .collect();
-
-            quote! {
-                .agents({
-                    let mut __step_agents = ::rust_buildkite::serde_json::Map::new();
-                    #(#agent_inserts)*
-                    Some(::rust_buildkite::Agents::Object(::rust_buildkite::AgentsObject(__step_agents)))
-                })
+                }
             }
-        } else {
-            quote! {}
-        };
+            CommandSource::DynamicBazel {
+                binary,
+                startup,
+                workspace_dir,
+                base_cmd,
+                flags,
+                extra_flags,
+                target,
+                args,
+            } => {
+                let verb = base_cmd.trim();
+                let startup = startup.join(" ");
+                let flags_var = format!("__flags_{}", cmd_idx);
+                let target_var = format!("__target_{}", cmd_idx);
 
-        let branches_tokens = if !self.branches.is_empty() {
-            let branches = &self.branches;
-            quote! {
-                .branches(Some(::rust_buildkite::Branches::Array(vec![
-                    #(#branches.to_string()),*
-                ])))
-            }
-        } else {
-            quote! {}
-        };
+                let (flags_validation, flags_tokens) = match flags {
+                    Some(dv) => dv.to_tokens_with_flags_validation(verb, &flags_var),
+                    None if !extra_flags.is_empty() => {
+                        let joined = extra_flags.join(" ");
+                        let flags_ident =
+                            syn::Ident::new(&flags_var, proc_macro2::Span::call_site());
+                        let validation = quote! {
+                            let #flags_ident = {
+                                let __val: String = #joined.to_string();
+                                ::rust_buildkite::validation::validate_flags_str(#verb, &__val);
+                                __val
+                            };
+                        };
+                        (validation, quote! { #flags_ident })
+                    }
+                    None => (quote! {}, quote! { "" }),
+                };
 
-        let if_tokens = if let Some(condition) = &self.if_condition {
-            quote! { .if_(Some(::rust_buildkite::If(#condition.to_string()))) }
-        } else {
-            quote! {}
-        };
+                let (target_validation, target_tokens) = match target {
+                    Some(dv) => dv.to_tokens_with_target_validation(&target_var),
+                    None => (quote! {}, quote! { "" }),
+                };
 
-        let cache_tokens = if !self.cache.is_empty() {
-            let paths = &self.cache;
-            quote! {
-                .cache(Some(::rust_buildkite::Cache::Array(vec![
-                    #(#paths.to_string()),*
-                ])))
-            }
-        } else {
-            quote! {}
-        };
+                let args_tokens = if args.is_empty() {
+                    quote! { String::new() }
+                } else {
+                    let arg_exprs: Vec<TokenStream2> = args.iter().map(|a| a.to_tokens()).collect();
+                    quote! {
+                        {
+                            let __args: Vec<String> = vec![
+                                "--".to_string(),
+                                #(ToString::to_string(&#arg_exprs)),*
+                            ];
+                            __args.join(" ")
+                        }
+                    }
+                };
 
-        let retry_tokens = if let Some(retry) = &self.retry {
-            let automatic_tokens = if let Some(auto) = &retry.automatic {
-                let auto_json = auto.to_json_tokens();
-                quote! {
-                    __retry_obj.insert("automatic".to_string(), #auto_json);
-                }
-            } else {
-                quote! {}
-            };
-            let manual_tokens = if let Some(manual) = &retry.manual {
-                let manual_json = manual.to_json_tokens();
+                let args_var = syn::Ident::new(
+                    &format!("__args_{}", cmd_idx),
+                    proc_macro2::Span::call_site(),
+                );
+                let command = quote! {
+                    format!("{} {} {} {} {} {}", #binary, #startup, #base_cmd, #flags_tokens, #target_tokens, #args_var)
+                        .split_whitespace().collect::<Vec<_>>().join(" ")
+                };
+                let command = match workspace_dir {
+                    Some(dir) => quote! { format!("(cd {} && {})", #dir, #command) },
+                    None => command,
+                };
                 quote! {
-                    __retry_obj.insert("manual".to_string(), #manual_json);
+                    {
+                        #flags_validation
+                        #target_validation
+                        let #args_var = #args_tokens;
+                        #command
+                    }
                 }
-            } else {
-                quote! {}
-            };
-            quote! {
-                .retry({
-                    let mut __retry_obj = ::rust_buildkite::serde_json::Map::new();
-                    #automatic_tokens
-                    #manual_tokens
-                    let __retry_value = ::rust_buildkite::serde_json::Value::Object(__retry_obj);
-                    Some(::rust_buildkite::serde_json::from_value(__retry_value).expect("invalid retry config"))
-                })
-            }
-        } else {
-            quote! {}
-        };
-
-        let plugins_tokens = if !self.plugins.is_empty() {
-            let plugin_values: Vec<TokenStream2> =
-                self.plugins.iter().map(|p| p.to_json_tokens()).collect();
-            quote! {
-                .plugins({
-                    let __plugins_array = vec![#(#plugin_values),*];
-                    Some(::rust_buildkite::Plugins::List(
-                        ::rust_buildkite::PluginsList(__plugins_array.into_iter().map(|v| {
-                            ::rust_buildkite::serde_json::from_value(v).expect("invalid plugin")
-                        }).collect())
-                    ))
-                })
-            }
-        } else {
-            quote! {}
-        };
-
-        let notify_tokens = if !self.notify.is_empty() {
-            let notify_values: Vec<TokenStream2> =
-                self.notify.iter().map(|n| n.to_json_tokens()).collect();
-            quote! {
-                .notify({
-                    let __notify_array = vec![#(#notify_values),*];
-                    Some(::rust_buildkite::CommandStepNotify(__notify_array.into_iter().map(|v| {
-                        ::rust_buildkite::serde_json::from_value(v).expect("invalid notify")
-                    }).collect()))
-                })
             }
-        } else {
-            quote! {}
-        };
-
-        let matrix_tokens = if let Some(matrix) = &self.matrix {
-            let matrix_json = matrix.to_json_tokens();
-            quote! {
-                .matrix({
-                    let __matrix_value = #matrix_json;
-                    Some(::rust_buildkite::serde_json::from_value(__matrix_value).expect("invalid matrix"))
-                })
+            _ => {
+                let cmd_string = self.get_command_string();
+                quote! { #cmd_string.to_string() }
             }
-        } else {
-            quote! {}
-        };
-
-        let concurrency_tokens = if let Some(c) = &self.concurrency {
-            quote! { .concurrency(Some(#c)) }
-        } else {
-            quote! {}
-        };
-
-        let concurrency_group_tokens = if let Some(group) = &self.concurrency_group {
-            quote! { .concurrency_group(Some(#group.to_string())) }
-        } else {
-            quote! {}
-        };
+        }
+    }
 
-        let skip_tokens = match &self.skip {
-            Some(SkipValue::Bool(true)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::Boolean(true))) }
-            }
-            Some(SkipValue::Bool(false)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::Boolean(false))) }
-            }
-            Some(SkipValue::Reason(reason)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::String(#reason.parse().expect("invalid skip reason")))) }
+    fn to_shell_tokens_with_validation(&self) -> TokenStream2 {
+        match &self.0 {
+            CommandSource::Shell(cmd) => cmd.to_tokens(),
+            CommandSource::Runtime {
+                expr,
+                checked: false,
+                ..
+            } => quote! { (#expr).to_string() },
+            CommandSource::Runtime {
+                expr,
+                checked: true,
+                ..
+            } => quote! {
+                {
+                    let __command: String = (#expr).to_string();
+                    __rbk_built!(
+                        ::rust_buildkite::validation::check_command(&__command, __rbk_allowed_commands)
+                            .into_build_result(__rbk_step, "invalid runtime command")
+                    );
+                    __command
+                }
+            },
+            #[cfg(feature = "bazel")]
+            _ => {
+                let cmd_string = self.get_command_string();
+                quote! { #cmd_string.to_string() }
             }
-            None => quote! {},
-        };
-
-        let priority_tokens = if let Some(p) = &self.priority {
-            quote! { .priority(Some(::rust_buildkite::Priority(#p))) }
-        } else {
-            quote! {}
-        };
-
-        let allow_dependency_failure_tokens = if self.allow_dependency_failure {
-            quote! { .allow_dependency_failure(Some(::rust_buildkite::AllowDependencyFailure(true))) }
-        } else {
-            quote! {}
-        };
-
-        quote! {
-            ::rust_buildkite::PipelineStepsItem::CommandStep(
-                ::rust_buildkite::CommandStep::builder()
-                    #command_tokens
-                    #label_tokens
-                    #key_tokens
-                    #depends_on_tokens
-                    #timeout_tokens
-                    #soft_fail_tokens
-                    #parallelism_tokens
-                    #artifact_tokens
-                    #env_tokens
-                    #agents_tokens
-                    #branches_tokens
-                    #if_tokens
-                    #cache_tokens
-                    #retry_tokens
-                    #plugins_tokens
-                    #notify_tokens
-                    #matrix_tokens
-                    #concurrency_tokens
-                    #concurrency_group_tokens
-                    #skip_tokens
-                    #priority_tokens
-                    #allow_dependency_failure_tokens
-                    .try_into()
-                    .expect("command step construction failed")
-            )
         }
     }
+}
 
-    fn to_tokens_with_default_plugins(&self, default_plugins: &[NestedValue]) -> TokenStream2 {
-        if default_plugins.is_empty() {
-            return self.to_tokens_inner();
-        }
+#[derive(Clone)]
+struct CommandStepDef {
+    commands: Vec<CommandValue>,
+    label: Option<syn::Expr>,
+    /// Set when the label was computed, e.g. by `comptime_shell!`
+    label_origin: Option<ValueOrigin>,
+    key: Option<KeyValue>,
+    /// From doc comments on the step, or `description`
+    description: Option<String>,
+    /// Emit the description as STEP_DESCRIPTION; set by `description_env`
+    description_env: bool,
+    depends_on: Vec<(String, proc_macro2::Span)>,
+    env: Vec<(String, DynamicValue)>,
+    /// Span of each env key written on the step, in the order of `env`
+    env_spans: Vec<proc_macro2::Span>,
+    timeout_in_minutes: Option<syn::LitInt>,
+    soft_fail: bool,
+    parallelism: Option<syn::LitInt>,
+    artifact_paths: Vec<LitStr>,
+    agents: Vec<(String, LitStr)>,
+    /// Whether `agents` was written in the `["key=value"]` list form
+    agents_list: bool,
+    branches: Vec<LitStr>,
+    if_condition: Option<LitStr>,
+    cache: Vec<LitStr>,
+    retry: Option<RetryConfig>,
+    plugins: Vec<NestedValue>,
+    /// Plugins named by a relative path, with the span of the name
+    local_plugins: Vec<(String, proc_macro2::Span)>,
+    /// Compose files and services to check for each `.docker_compose(...)`
+    compose_checks: Vec<compose::ComposeCheck>,
+    notify: Vec<NestedValue>,
+    matrix: Option<NestedValue>,
+    concurrency: Option<syn::LitInt>,
+    concurrency_group: Option<LitStr>,
+    skip: Option<SkipValue>,
+    priority: Option<syn::LitInt>,
+    allow_dependency_failure: bool,
+    /// Whether `bazel_bep` wires BEP output into this step's bazel commands
+    #[cfg_attr(not(feature = "bazel"), allow(dead_code))]
+    bep: bool,
+    /// Set by `skip_validation("reason")`: the step's commands skip the
+    /// allowlist, path and env var checks but are still linted
+    skip_validation: bool,
+    /// Shell the commands are validated for; the pipeline's `shell` if unset
+    shell: Option<StepShell>,
+    /// Artifacts downloaded by `needs_artifacts`, checked against the
+    /// artifact_paths of the step that uploads them
+    needs_artifacts: Vec<ArtifactNeed>,
+    /// Profiles this step is generated for; empty means every profile
+    only_profiles: Vec<LitStr>,
+}
 
-        let all_plugins: Vec<&NestedValue> =
-            default_plugins.iter().chain(self.plugins.iter()).collect();
+/// An artifact glob a step downloads from the step with key `key`.
+#[derive(Clone)]
+struct ArtifactNeed {
+    key: String,
+    glob: String,
+    span: proc_macro2::Span,
+}
 
-        assert!(!self.commands.is_empty(), "commands must not be empty");
+/// Retry configuration for command steps
+#[derive(Clone, Default)]
+struct RetryConfig {
+    automatic: Option<NestedValue>,
+    manual: Option<NestedValue>,
+}
 
-        let cmd_token_list: Vec<TokenStream2> = self
-            .commands
-            .iter()
-            .enumerate()
-            .map(|(_idx, cmd_value)| {
-                #[cfg(feature = "bazel")]
-                if matches!(
-                    &cmd_value.0,
-                    CommandSource::Bazel(_) | CommandSource::DynamicBazel { .. }
-                ) {
-                    return cmd_value.to_bazel_tokens_with_validation(_idx);
+impl RetryConfig {
+    /// Parse retry: { automatic: ..., manual: ... }
+    fn parse(input: ParseStream) -> Result<Self> {
+        if !input.peek(syn::token::Brace) {
+            return Err(Error::new(
+                input.span(),
+                "retry expects an object, e.g. retry: { automatic: { limit: 2 } }",
+            ));
+        }
+        let content;
+        braced!(content in input);
+        let mut config = RetryConfig::default();
+        while !content.is_empty() {
+            let field: Ident = content.parse()?;
+            content.parse::<Token![:]>()?;
+            let value = NestedValue::parse_field(&content, "retry")?;
+            match field.to_string().as_str() {
+                "automatic" => config.automatic = Some(value),
+                "manual" => config.manual = Some(value),
+                other => {
+                    return Err(Error::new(
+                        field.span(),
+                        fields::unknown("retry field", other, fields::RETRY_FIELDS.iter().copied()),
+                    ));
                 }
-                cmd_value.to_shell_tokens_with_validation()
-            })
-            .collect();
-
-        let command_tokens = if cmd_token_list.len() == 1 {
-            let cmd = &cmd_token_list[0];
-            quote! { .command(Some(::rust_buildkite::CommandStepCommand::String(#cmd))) }
-        } else {
-            quote! {
-                .commands(Some(::rust_buildkite::CommandStepCommand::Array(vec![
-                    #(#cmd_token_list),*
-                ])))
-            }
-        };
-
-        let label_tokens = if let Some(label) = &self.label {
-            quote! { .label(Some(::rust_buildkite::Label(#label.to_string()))) }
-        } else {
-            quote! {}
-        };
-
-        let key_tokens = if let Some(key) = &self.key {
-            let key_value = key.to_tokens();
-            quote! { .key(Some(#key_value.try_into().expect("invalid key"))) }
-        } else {
-            quote! {}
-        };
-
-        let depends_on_tokens = if !self.depends_on.is_empty() {
-            let deps: Vec<_> = self.depends_on.iter().map(|(d, _)| d).collect();
-            quote! {
-                .depends_on(Some(::rust_buildkite::DependsOn::DependsOnList(
-                    ::rust_buildkite::DependsOnList(vec![
-                        #(::rust_buildkite::DependsOnListItem::String(#deps.to_string())),*
-                    ])
-                )))
             }
-        } else {
-            quote! {}
-        };
-
-        let timeout_tokens = if let Some(timeout) = &self.timeout_in_minutes {
-            quote! { .timeout_in_minutes(Some(::std::num::NonZeroU64::new(#timeout).expect("timeout must be > 0"))) }
-        } else {
-            quote! {}
-        };
+            if content.peek(Token![,]) {
+                content.parse::<Token![,]>()?;
+            }
+        }
+        Ok(config)
+    }
+}
 
-        let soft_fail_tokens = if self.soft_fail {
-            quote! { .soft_fail(Some(::rust_buildkite::SoftFail::Boolean(true))) }
-        } else {
-            quote! {}
-        };
+/// Canonical automatic retry rules behind `.retry_on_agent_loss()`,
+/// `.retry_flaky(limit)` and `retry_preset`.
+#[derive(Clone, Copy)]
+enum RetryPreset {
+    /// `{ "exit_status": -1, "limit": 2 }` and
+    /// `{ "signal_reason": "agent_stop", "limit": 2 }`
+    AgentLoss,
+    /// `{ "exit_status": "*", "limit": <limit> }`
+    Flaky(i64),
+}
 
-        let parallelism_tokens = if let Some(p) = &self.parallelism {
-            quote! { .parallelism(Some(#p)) }
-        } else {
-            quote! {}
-        };
+/// Automatic retry limit for `retry_preset: "flaky"`.
+const FLAKY_RETRY_LIMIT: i64 = 2;
 
-        let artifact_tokens = if !self.artifact_paths.is_empty() {
-            let paths = &self.artifact_paths;
-            quote! {
-                .artifact_paths(Some(::rust_buildkite::CommandStepArtifactPaths::Array(vec![
-                    #(#paths.to_string()),*
-                ])))
-            }
-        } else {
-            quote! {}
-        };
+/// Buildkite's maximum automatic retry limit.
+const MAX_RETRY_LIMIT: i64 = 10;
 
-        let env_tokens = if !self.env.is_empty() {
-            let env_inserts: Vec<TokenStream2> = self
-                .env
-                .iter()
-                .map(|(k, v)| {
-                    let v_tokens = v.to_tokens();
-                    quote! {
-                        __step_env.insert(#k.to_string(), ::rust_buildkite::serde_json::Value::String((#v_tokens).to_string()));
-                    }
-                })
-                .collect();
+const RETRY_PRESET_CONFLICT: &str = "retry presets can't be combined with an explicit retry; \
+     list every rule under retry: { automatic: [...] } instead";
 
-            quote! {
-                .env({
-                    let mut __step_env = ::rust_buildkite::serde_json::Map::new();
-                    #(#env_inserts)*
-                    Some(::rust_buildkite::Env(__step_env))
-                })
-            }
-        } else {
-            quote! {}
-        };
+impl RetryPreset {
+    /// Parse a `retry_preset` name: "flaky" or "agent_loss".
+    fn from_lit(lit: &LitStr) -> Result<Self> {
+        match lit.value().as_str() {
+            "flaky" => Ok(RetryPreset::Flaky(FLAKY_RETRY_LIMIT)),
+            "agent_loss" => Ok(RetryPreset::AgentLoss),
+            other => Err(Error::new(
+                lit.span(),
+                format!(
+                    "unknown retry preset '{}'. Expected \"flaky\" or \"agent_loss\"",
+                    other
+                ),
+            )),
+        }
+    }
 
-        let agents_tokens = if !self.agents.is_empty() {
-            let agent_inserts: Vec<TokenStream2> = self
-                .agents
-                .iter()
-                .map(|(k, v)| {
-                    quote! {
-                        __step_agents.insert(#k.to_string(), ::rust_buildkite::serde_json::Value::String(#v.to_string()));
-                    }
-                })
-                .collect();
+    /// Parse the limit given to `.retry_flaky(limit)`.
+    fn flaky(input: ParseStream) -> Result<Self> {
+        let lit: syn::LitInt = input.parse()?;
+        let limit: i64 = lit.base10_parse()?;
+        if !(1..=MAX_RETRY_LIMIT).contains(&limit) {
+            return Err(Error::new(
+                lit.span(),
+                format!(
+                    "retry_flaky limit must be between 1 and {}",
+                    MAX_RETRY_LIMIT
+                ),
+            ));
+        }
+        Ok(RetryPreset::Flaky(limit))
+    }
 
-            quote! {
-                .agents({
-                    let mut __step_agents = ::rust_buildkite::serde_json::Map::new();
-                    #(#agent_inserts)*
-                    Some(::rust_buildkite::Agents::Object(::rust_buildkite::AgentsObject(__step_agents)))
-                })
-            }
-        } else {
-            quote! {}
+    fn rules(self) -> Vec<NestedValue> {
+        let rule = |condition: (&str, NestedValue), limit: i64| {
+            NestedValue::Object(vec![
+                (condition.0.to_string(), condition.1),
+                ("limit".to_string(), NestedValue::Int(limit)),
+            ])
         };
+        match self {
+            RetryPreset::AgentLoss => vec![
+                rule(("exit_status", NestedValue::Int(-1)), 2),
+                rule(
+                    (
+                        "signal_reason",
+                        NestedValue::String("agent_stop".to_string()),
+                    ),
+                    2,
+                ),
+            ],
+            RetryPreset::Flaky(limit) => vec![rule(
+                ("exit_status", NestedValue::String("*".to_string())),
+                limit,
+            )],
+        }
+    }
 
-        let branches_tokens = if !self.branches.is_empty() {
-            let branches = &self.branches;
-            quote! {
-                .branches(Some(::rust_buildkite::Branches::Array(vec![
-                    #(#branches.to_string()),*
-                ])))
-            }
-        } else {
-            quote! {}
+    /// Append this preset's rules to the automatic array of `retry`.
+    fn apply(self, retry: &mut Option<RetryConfig>) {
+        let config = retry.get_or_insert_with(RetryConfig::default);
+        let mut rules = match config.automatic.take() {
+            Some(NestedValue::Array(rules)) => rules,
+            _ => Vec::new(),
         };
+        rules.extend(self.rules());
+        config.automatic = Some(NestedValue::Array(rules));
+    }
+}
 
-        let if_tokens = if let Some(condition) = &self.if_condition {
-            quote! { .if_(Some(::rust_buildkite::If(#condition.to_string()))) }
-        } else {
-            quote! {}
-        };
+/// Skip value - can be bool or string reason
+#[derive(Clone)]
+enum SkipValue {
+    Bool(bool),
+    Reason(String),
+}
 
-        let cache_tokens = if !self.cache.is_empty() {
-            let paths = &self.cache;
-            quote! {
-                .cache(Some(::rust_buildkite::Cache::Array(vec![
-                    #(#paths.to_string()),*
-                ])))
+impl SkipValue {
+    /// The builder call that sets `skip`.
+    fn to_tokens(&self) -> TokenStream2 {
+        match self {
+            SkipValue::Bool(b) => quote! { .skip(Some(::rust_buildkite::Skip::Boolean(#b))) },
+            SkipValue::Reason(reason) => {
+                quote! { .skip(Some(::rust_buildkite::Skip::String(__rbk_built!(#reason.parse().into_build_result(__rbk_step, "invalid skip reason"))))) }
             }
-        } else {
-            quote! {}
-        };
+        }
+    }
+}
 
-        let retry_tokens = if let Some(retry) = &self.retry {
-            let automatic_tokens = if let Some(auto) = &retry.automatic {
-                let auto_json = auto.to_json_tokens();
-                quote! {
-                    __retry_obj.insert("automatic".to_string(), #auto_json);
-                }
-            } else {
-                quote! {}
-            };
-            let manual_tokens = if let Some(manual) = &retry.manual {
-                let manual_json = manual.to_json_tokens();
-                quote! {
-                    __retry_obj.insert("manual".to_string(), #manual_json);
-                }
-            } else {
-                quote! {}
-            };
-            quote! {
-                .retry({
-                    let mut __retry_obj = ::rust_buildkite::serde_json::Map::new();
-                    #automatic_tokens
-                    #manual_tokens
-                    let __retry_value = ::rust_buildkite::serde_json::Value::Object(__retry_obj);
-                    Some(::rust_buildkite::serde_json::from_value(__retry_value).expect("invalid retry config"))
-                })
-            }
-        } else {
-            quote! {}
-        };
+/// Field definition for block/input steps
+#[derive(Clone)]
+enum FieldDef {
+    Text(TextFieldDef),
+    Select(SelectFieldDef),
+}
 
-        let plugins_tokens = {
-            let plugin_values: Vec<TokenStream2> =
-                all_plugins.iter().map(|p| p.to_json_tokens()).collect();
-            quote! {
-                .plugins({
-                    let __plugins_array = vec![#(#plugin_values),*];
-                    Some(::rust_buildkite::Plugins::List(
-                        ::rust_buildkite::PluginsList(__plugins_array.into_iter().map(|v| {
-                            ::rust_buildkite::serde_json::from_value(v).expect("invalid plugin")
-                        }).collect())
-                    ))
-                })
-            }
-        };
+/// Text field for block/input steps
+#[derive(Clone, Default)]
+struct TextFieldDef {
+    key: String,
+    text: Option<String>,
+    hint: Option<String>,
+    required: Option<bool>,
+    default: Option<String>,
+    format: Option<String>,
+}
 
-        let notify_tokens = if !self.notify.is_empty() {
-            let notify_values: Vec<TokenStream2> =
-                self.notify.iter().map(|n| n.to_json_tokens()).collect();
-            quote! {
-                .notify({
-                    let __notify_array = vec![#(#notify_values),*];
-                    Some(::rust_buildkite::CommandStepNotify(__notify_array.into_iter().map(|v| {
-                        ::rust_buildkite::serde_json::from_value(v).expect("invalid notify")
-                    }).collect()))
-                })
-            }
-        } else {
-            quote! {}
-        };
+/// Select field for block/input steps
+#[derive(Clone, Default)]
+struct SelectFieldDef {
+    key: String,
+    select: Option<String>,
+    hint: Option<String>,
+    required: Option<bool>,
+    default: Option<String>,
+    multiple: Option<bool>,
+    options: Vec<SelectFieldOptionDef>,
+}
 
-        let matrix_tokens = if let Some(matrix) = &self.matrix {
-            let matrix_json = matrix.to_json_tokens();
-            quote! {
-                .matrix({
-                    let __matrix_value = #matrix_json;
-                    Some(::rust_buildkite::serde_json::from_value(__matrix_value).expect("invalid matrix"))
-                })
-            }
-        } else {
-            quote! {}
-        };
+/// Option for select field
+#[derive(Clone)]
+struct SelectFieldOptionDef {
+    label: String,
+    value: String,
+}
 
-        let concurrency_tokens = if let Some(c) = &self.concurrency {
-            quote! { .concurrency(Some(#c)) }
-        } else {
-            quote! {}
-        };
+impl FieldDef {
+    /// The meta-data key the field's value is stored under.
+    fn key(&self) -> &str {
+        match self {
+            FieldDef::Text(f) => &f.key,
+            FieldDef::Select(f) => &f.key,
+        }
+    }
 
-        let concurrency_group_tokens = if let Some(group) = &self.concurrency_group {
-            quote! { .concurrency_group(Some(#group.to_string())) }
-        } else {
-            quote! {}
-        };
+    fn parse(input: ParseStream) -> Result<Self> {
+        let field_type: Ident = input.parse()?;
+        let content;
+        braced!(content in input);
 
-        let skip_tokens = match &self.skip {
-            Some(SkipValue::Bool(true)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::Boolean(true))) }
-            }
-            Some(SkipValue::Bool(false)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::Boolean(false))) }
+        match field_type.to_string().as_str() {
+            "text" => {
+                let mut field = TextFieldDef::default();
+                while !content.is_empty() {
+                    let key: Ident = content.parse()?;
+                    content.parse::<Token![:]>()?;
+                    match key.to_string().as_str() {
+                        "key" => {
+                            let k: LitStr = content.parse()?;
+                            field.key = k.value();
+                        }
+                        "text" | "label" => {
+                            let t: LitStr = content.parse()?;
+                            field.text = Some(t.value());
+                        }
+                        "hint" => {
+                            let h: LitStr = content.parse()?;
+                            field.hint = Some(h.value());
+                        }
+                        "required" => {
+                            let r: syn::LitBool = content.parse()?;
+                            field.required = Some(r.value());
+                        }
+                        "default" => {
+                            let d: LitStr = content.parse()?;
+                            field.default = Some(d.value());
+                        }
+                        "format" => {
+                            let f: LitStr = content.parse()?;
+                            field.format = Some(f.value());
+                        }
+                        other => {
+                            return Err(Error::new(
+                                key.span(),
+                                fields::unknown(
+                                    "text field property",
+                                    other,
+                                    fields::TEXT_FIELD_PROPERTIES.iter().copied(),
+                                ),
+                            ));
+                        }
+                    }
+                    if content.peek(Token![,]) {
+                        content.parse::<Token![,]>()?;
+                    }
+                }
+                Ok(FieldDef::Text(field))
             }
-            Some(SkipValue::Reason(reason)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::String(#reason.parse().expect("invalid skip reason")))) }
+            "select" => {
+                let mut field = SelectFieldDef::default();
+                while !content.is_empty() {
+                    let key: Ident = content.parse()?;
+                    content.parse::<Token![:]>()?;
+                    match key.to_string().as_str() {
+                        "key" => {
+                            let k: LitStr = content.parse()?;
+                            field.key = k.value();
+                        }
+                        "select" | "label" => {
+                            let s: LitStr = content.parse()?;
+                            field.select = Some(s.value());
+                        }
+                        "hint" => {
+                            let h: LitStr = content.parse()?;
+                            field.hint = Some(h.value());
+                        }
+                        "required" => {
+                            let r: syn::LitBool = content.parse()?;
+                            field.required = Some(r.value());
+                        }
+                        "default" => {
+                            let d: LitStr = content.parse()?;
+                            field.default = Some(d.value());
+                        }
+                        "multiple" => {
+                            let m: syn::LitBool = content.parse()?;
+                            field.multiple = Some(m.value());
+                        }
+                        "options" => {
+                            let options_content;
+                            bracketed!(options_content in content);
+                            while !options_content.is_empty() {
+                                let opt_content;
+                                braced!(opt_content in options_content);
+                                let mut label = String::new();
+                                let mut value = String::new();
+                                while !opt_content.is_empty() {
+                                    let opt_key: Ident = opt_content.parse()?;
+                                    opt_content.parse::<Token![:]>()?;
+                                    match opt_key.to_string().as_str() {
+                                        "label" => {
+                                            let l: LitStr = opt_content.parse()?;
+                                            label = l.value();
+                                        }
+                                        "value" => {
+                                            let v: LitStr = opt_content.parse()?;
+                                            value = v.value();
+                                        }
+                                        _ => {}
+                                    }
+                                    if opt_content.peek(Token![,]) {
+                                        opt_content.parse::<Token![,]>()?;
+                                    }
+                                }
+                                field.options.push(SelectFieldOptionDef { label, value });
+                                if options_content.peek(Token![,]) {
+                                    options_content.parse::<Token![,]>()?;
+                                }
+                            }
+                        }
+                        other => {
+                            return Err(Error::new(
+                                key.span(),
+                                fields::unknown(
+                                    "select field property",
+                                    other,
+                                    fields::SELECT_FIELD_PROPERTIES.iter().copied(),
+                                ),
+                            ));
+                        }
+                    }
+                    if content.peek(Token![,]) {
+                        content.parse::<Token![,]>()?;
+                    }
+                }
+                Ok(FieldDef::Select(field))
             }
-            None => quote! {},
-        };
-
-        let priority_tokens = if let Some(p) = &self.priority {
-            quote! { .priority(Some(::rust_buildkite::Priority(#p))) }
-        } else {
-            quote! {}
-        };
-
-        let allow_dependency_failure_tokens = if self.allow_dependency_failure {
-            quote! { .allow_dependency_failure(Some(::rust_buildkite::AllowDependencyFailure(true))) }
-        } else {
-            quote! {}
-        };
-
-        quote! {
-            ::rust_buildkite::PipelineStepsItem::CommandStep(
-                ::rust_buildkite::CommandStep::builder()
-                    #command_tokens
-                    #label_tokens
-                    #key_tokens
-                    #depends_on_tokens
-                    #timeout_tokens
-                    #soft_fail_tokens
-                    #parallelism_tokens
-                    #artifact_tokens
-                    #env_tokens
-                    #agents_tokens
-                    #branches_tokens
-                    #if_tokens
-                    #cache_tokens
-                    #retry_tokens
-                    #plugins_tokens
-                    #notify_tokens
-                    #matrix_tokens
-                    #concurrency_tokens
-                    #concurrency_group_tokens
-                    #skip_tokens
-                    #priority_tokens
-                    #allow_dependency_failure_tokens
-                    .try_into()
-                    .expect("command step construction failed")
-            )
+            other => Err(Error::new(
+                field_type.span(),
+                format!("unknown field type: {}. Expected 'text' or 'select'", other),
+            )),
         }
     }
 
-    fn to_group_step_tokens(&self) -> TokenStream2 {
-        assert!(!self.commands.is_empty(), "commands must not be empty");
-
-        let cmd_token_list: Vec<TokenStream2> = self
-            .commands
-            .iter()
-            .enumerate()
-            .map(|(_idx, cmd_value)| {
-                #[cfg(feature = "bazel")]
-                if matches!(
-                    &cmd_value.0,
-                    CommandSource::Bazel(_) | CommandSource::DynamicBazel { .. }
-                ) {
-                    return cmd_value.to_bazel_tokens_with_validation(_idx);
+    fn expand(&self) -> yaml::Expanded {
+        let mut fields = yaml::Fields::default();
+        match self {
+            FieldDef::Text(f) => {
+                if let Some(t) = &f.text {
+                    fields.set(
+                        "text",
+                        Ok(yaml::string(t)),
+                        quote! { .text(Some(#t.to_string())) },
+                    );
+                }
+                let key = &f.key;
+                fields.set(
+                    "key",
+                    Ok(yaml::string(key)),
+                    quote! { .key(__rbk_built!(#key.parse::<::rust_buildkite::TextFieldKey>().into_build_result(__rbk_step, "invalid key"))) },
+                );
+                if let Some(h) = &f.hint {
+                    fields.set(
+                        "hint",
+                        Ok(yaml::string(h)),
+                        quote! { .hint(Some(#h.to_string())) },
+                    );
+                }
+                if let Some(r) = f.required {
+                    fields.set(
+                        "required",
+                        Ok(yaml::Value::Bool(r)),
+                        quote! { .required(#r) },
+                    );
+                }
+                if let Some(d) = &f.default {
+                    fields.set(
+                        "default",
+                        Ok(yaml::string(d)),
+                        quote! { .default(Some(#d.to_string())) },
+                    );
+                }
+                if let Some(fmt) = &f.format {
+                    fields.set(
+                        "format",
+                        Ok(yaml::string(fmt)),
+                        quote! { .format(Some(#fmt.to_string())) },
+                    );
+                }
+                let (calls, value) = fields.finish();
+                yaml::Expanded {
+                    tokens: quote! {
+                        ::rust_buildkite::FieldsItem::TextField(
+                            __rbk_built!(
+                                ::rust_buildkite::TextField::builder()
+                                    #calls
+                                    .try_into()
+                                    .into_build_result(__rbk_step, "text field construction failed")
+                            )
+                        )
+                    },
+                    value,
                 }
-                cmd_value.to_shell_tokens_with_validation()
-            })
-            .collect();
-
-        let command_tokens = if cmd_token_list.len() == 1 {
-            let cmd = &cmd_token_list[0];
-            quote! { .command(Some(::rust_buildkite::CommandStepCommand::String(#cmd))) }
-        } else {
-            quote! {
-                .commands(Some(::rust_buildkite::CommandStepCommand::Array(vec![
-                    #(#cmd_token_list),*
-                ])))
-            }
-        };
-
-        let label_tokens = if let Some(l) = &self.label {
-            quote! { .label(Some(::rust_buildkite::Label(#l.to_string()))) }
-        } else {
-            quote! {}
-        };
-
-        let key_tokens = if let Some(key) = &self.key {
-            let key_value = key.to_tokens();
-            quote! { .key(Some(#key_value.try_into().expect("invalid key"))) }
-        } else {
-            quote! {}
-        };
-
-        let depends_on_tokens = if !self.depends_on.is_empty() {
-            let deps: Vec<_> = self.depends_on.iter().map(|(d, _)| d).collect();
-            quote! {
-                .depends_on(Some(::rust_buildkite::DependsOn::DependsOnList(
-                    ::rust_buildkite::DependsOnList(vec![
-                        #(::rust_buildkite::DependsOnListItem::String(#deps.to_string())),*
-                    ])
-                )))
-            }
-        } else {
-            quote! {}
-        };
-
-        let timeout_tokens = if let Some(t) = &self.timeout_in_minutes {
-            quote! { .timeout_in_minutes(Some(#t)) }
-        } else {
-            quote! {}
-        };
-
-        let soft_fail_tokens = if self.soft_fail {
-            quote! { .soft_fail(Some(::rust_buildkite::SoftFail::Boolean(true))) }
-        } else {
-            quote! {}
-        };
-
-        let parallelism_tokens = if let Some(p) = &self.parallelism {
-            quote! { .parallelism(Some(#p)) }
-        } else {
-            quote! {}
-        };
-
-        let artifact_tokens = if !self.artifact_paths.is_empty() {
-            let paths: Vec<_> = self.artifact_paths.iter().collect();
-            quote! {
-                .artifact_paths(Some(::rust_buildkite::CommandStepArtifactPaths::Array(vec![
-                    #(#paths.to_string()),*
-                ])))
-            }
-        } else {
-            quote! {}
-        };
-
-        let env_tokens = if !self.env.is_empty() {
-            let inserts: Vec<TokenStream2> = self.env.iter().map(|(k, v)| {
-                let v_tokens = v.to_tokens();
-                quote! { __env_map.insert(#k.to_string(), ::rust_buildkite::serde_json::Value::String((#v_tokens).to_string())); }
-            }).collect();
-            quote! {
-                .env({
-                    let mut __env_map = ::rust_buildkite::serde_json::Map::new();
-                    #(#inserts)*
-                    Some(::rust_buildkite::Env(__env_map))
-                })
-            }
-        } else {
-            quote! {}
-        };
-
-        let agents_tokens = if !self.agents.is_empty() {
-            let inserts: Vec<TokenStream2> = self.agents.iter().map(|(k, v)| {
-                quote! { __agents_map.insert(#k.to_string(), ::rust_buildkite::serde_json::Value::String(#v.to_string())); }
-            }).collect();
-            quote! {
-                .agents({
-                    let mut __agents_map = ::rust_buildkite::serde_json::Map::new();
-                    #(#inserts)*
-                    Some(::rust_buildkite::Agents::Object(::rust_buildkite::AgentsObject(__agents_map)))
-                })
-            }
-        } else {
-            quote! {}
-        };
-
-        let branches_tokens = if !self.branches.is_empty() {
-            let branches: Vec<_> = self.branches.iter().collect();
-            quote! {
-                .branches(Some(::rust_buildkite::Branches::Array(vec![
-                    #(#branches.to_string()),*
-                ])))
-            }
-        } else {
-            quote! {}
-        };
-
-        let if_tokens = if let Some(condition) = &self.if_condition {
-            quote! { .if_(Some(::rust_buildkite::If(#condition.to_string()))) }
-        } else {
-            quote! {}
-        };
-
-        let cache_tokens = if !self.cache.is_empty() {
-            let paths: Vec<_> = self.cache.iter().collect();
-            quote! {
-                .cache(Some(::rust_buildkite::Cache::Array(vec![
-                    #(#paths.to_string()),*
-                ])))
             }
-        } else {
-            quote! {}
-        };
-
-        let retry_tokens = if let Some(retry) = &self.retry {
-            let automatic_tokens = if let Some(auto) = &retry.automatic {
-                let auto_json = auto.to_json_tokens();
-                quote! {
-                    .automatic({
-                        let __auto_value = #auto_json;
-                        Some(::rust_buildkite::serde_json::from_value(__auto_value).expect("invalid automatic retry config"))
-                    })
+            FieldDef::Select(f) => {
+                if let Some(s) = &f.select {
+                    fields.set(
+                        "select",
+                        Ok(yaml::string(s)),
+                        quote! { .select(Some(#s.to_string())) },
+                    );
                 }
-            } else {
-                quote! {}
-            };
-            let manual_tokens = if let Some(manual) = &retry.manual {
-                let manual_json = manual.to_json_tokens();
-                quote! {
-                    .manual({
-                        let __manual_value = #manual_json;
-                        Some(::rust_buildkite::serde_json::from_value(__manual_value).expect("invalid manual retry config"))
-                    })
+                let key = &f.key;
+                fields.set(
+                    "key",
+                    Ok(yaml::string(key)),
+                    quote! { .key(__rbk_built!(#key.parse::<::rust_buildkite::SelectFieldKey>().into_build_result(__rbk_step, "invalid key"))) },
+                );
+                if let Some(h) = &f.hint {
+                    fields.set(
+                        "hint",
+                        Ok(yaml::string(h)),
+                        quote! { .hint(Some(#h.to_string())) },
+                    );
+                }
+                if let Some(r) = f.required {
+                    fields.set(
+                        "required",
+                        Ok(yaml::Value::Bool(r)),
+                        quote! { .required(#r) },
+                    );
+                }
+                if let Some(d) = &f.default {
+                    fields.set(
+                        "default",
+                        Ok(yaml::string(d)),
+                        quote! { .default(::rust_buildkite::SelectFieldDefault::String(#d.to_string())) },
+                    );
+                }
+                if let Some(m) = f.multiple {
+                    fields.set(
+                        "multiple",
+                        Ok(yaml::Value::Bool(m)),
+                        quote! { .multiple(#m) },
+                    );
+                }
+                let (options, options_value) = yaml::sequence(f.options.iter().map(|opt| {
+                    let (label, value) = (&opt.label, &opt.value);
+                    let mut option = yaml::Fields::default();
+                    option.set("label", Ok(yaml::string(label)), quote! { .label(#label.to_string()) });
+                    option.set("value", Ok(yaml::string(value)), quote! { .value(#value.to_string()) });
+                    let (calls, value) = option.finish();
+                    yaml::Expanded {
+                        tokens: quote! {
+                            __rbk_built!(
+                                ::rust_buildkite::SelectFieldOption::builder()
+                                    #calls
+                                    .try_into()
+                                    .into_build_result(__rbk_step, "select option construction failed")
+                            )
+                        },
+                        value,
+                    }
+                }));
+                fields.set(
+                    "options",
+                    options_value,
+                    quote! { .options(vec![#(#options),*]) },
+                );
+                let (calls, value) = fields.finish();
+                yaml::Expanded {
+                    tokens: quote! {
+                        ::rust_buildkite::FieldsItem::SelectField(
+                            __rbk_built!(
+                                ::rust_buildkite::SelectField::builder()
+                                    #calls
+                                    .try_into()
+                                    .into_build_result(__rbk_step, "select field construction failed")
+                            )
+                        )
+                    },
+                    value,
                 }
-            } else {
-                quote! {}
-            };
-            quote! {
-                .retry(Some(
-                    ::rust_buildkite::CommandStepRetry::builder()
-                        #automatic_tokens
-                        #manual_tokens
-                        .try_into()
-                        .expect("invalid retry config")
-                ))
-            }
-        } else {
-            quote! {}
-        };
-
-        let plugins_tokens = if !self.plugins.is_empty() {
-            let plugin_values: Vec<TokenStream2> =
-                self.plugins.iter().map(|p| p.to_json_tokens()).collect();
-            quote! {
-                .plugins({
-                    let __plugins_array = vec![#(#plugin_values),*];
-                    Some(::rust_buildkite::Plugins::List(::rust_buildkite::PluginsList(__plugins_array.into_iter().map(|v| {
-                        ::rust_buildkite::serde_json::from_value(v).expect("invalid plugin")
-                    }).collect())))
-                })
-            }
-        } else {
-            quote! {}
-        };
-
-        let notify_tokens = if !self.notify.is_empty() {
-            let notify_values: Vec<TokenStream2> =
-                self.notify.iter().map(|n| n.to_json_tokens()).collect();
-            quote! {
-                .notify({
-                    let __notify_array = vec![#(#notify_values),*];
-                    Some(::rust_buildkite::CommandStepNotify(__notify_array.into_iter().map(|v| {
-                        ::rust_buildkite::serde_json::from_value(v).expect("invalid notify")
-                    }).collect()))
-                })
             }
-        } else {
-            quote! {}
-        };
-
-        let matrix_tokens = if let Some(matrix) = &self.matrix {
-            let matrix_json = matrix.to_json_tokens();
-            quote! {
-                .matrix({
-                    let __matrix_value = #matrix_json;
-                    Some(::rust_buildkite::serde_json::from_value(__matrix_value).expect("invalid matrix"))
-                })
-            }
-        } else {
-            quote! {}
-        };
+        }
+    }
+}
 
-        let concurrency_tokens = if let Some(c) = &self.concurrency {
-            quote! { .concurrency(Some(#c)) }
-        } else {
-            quote! {}
-        };
+impl CommandStepDef {
+    /// The STEP_DESCRIPTION value to emit, unless the step sets that var itself.
+    fn description_env_value(&self) -> Option<&str> {
+        if !self.description_env || self.env.iter().any(|(k, _)| k == DESCRIPTION_ENV_VAR) {
+            return None;
+        }
+        self.description.as_deref()
+    }
 
-        let concurrency_group_tokens = if let Some(group) = &self.concurrency_group {
-            quote! { .concurrency_group(Some(#group.to_string())) }
-        } else {
-            quote! {}
-        };
+    /// Add a plugin, remembering where a local one was named so its path
+    /// can be checked.
+    fn push_plugin(&mut self, plugin: NestedValue, span: proc_macro2::Span) {
+        if let Some(path) = plugins::local_path(&plugin) {
+            self.local_plugins.push((path.to_string(), span));
+        }
+        self.plugins.push(plugin);
+    }
 
-        let skip_tokens = match &self.skip {
-            Some(SkipValue::Bool(true)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::Boolean(true))) }
-            }
-            Some(SkipValue::Bool(false)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::Boolean(false))) }
-            }
-            Some(SkipValue::Reason(reason)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::String(#reason.parse().expect("invalid skip reason")))) }
-            }
-            None => quote! {},
-        };
+    /// Label and key for `auto_keys` on a step running one static bazel
+    /// command: `:bazel: test //app/...` and `test-app-all`.
+    #[cfg(feature = "bazel")]
+    fn bazel_auto_label(&self) -> Option<(String, String, proc_macro2::Span)> {
+        let [command] = self.commands.as_slice() else {
+            return None;
+        };
+        let CommandSource::Bazel(bazel) = &command.0 else {
+            return None;
+        };
+        let targets = crate::targets::extract_targets_from_args(&bazel.args());
+        let mut label = format!(":bazel: {}", bazel.verb);
+        let mut slug = bazel.verb.clone();
+        for target in &targets {
+            label.push(' ');
+            label.push_str(target);
+            slug.push(' ');
+            slug.push_str(&target.replace("...", "all"));
+        }
+        Some((label, slugify_label(&slug), bazel.span))
+    }
 
-        let priority_tokens = if let Some(p) = &self.priority {
-            quote! { .priority(Some(::rust_buildkite::Priority(#p))) }
-        } else {
-            quote! {}
-        };
+    fn new_with_cmd(cmd_expr: CmdExpr) -> Self {
+        Self {
+            commands: vec![CommandValue::from_cmd(cmd_expr)],
+            label: None,
+            label_origin: None,
+            key: None,
+            description: None,
+            description_env: false,
+            depends_on: Vec::new(),
+            env: Vec::new(),
+            env_spans: Vec::new(),
+            timeout_in_minutes: None,
+            soft_fail: false,
+            parallelism: None,
+            artifact_paths: Vec::new(),
+            agents: Vec::new(),
+            agents_list: false,
+            branches: Vec::new(),
+            if_condition: None,
+            cache: Vec::new(),
+            retry: None,
+            plugins: Vec::new(),
+            local_plugins: Vec::new(),
+            compose_checks: Vec::new(),
+            notify: Vec::new(),
+            matrix: None,
+            concurrency: None,
+            concurrency_group: None,
+            skip: None,
+            priority: None,
+            allow_dependency_failure: false,
+            bep: true,
+            skip_validation: false,
+            shell: None,
+            needs_artifacts: Vec::new(),
+            only_profiles: Vec::new(),
+        }
+    }
 
-        let allow_dependency_failure_tokens = if self.allow_dependency_failure {
-            quote! { .allow_dependency_failure(Some(::rust_buildkite::AllowDependencyFailure(true))) }
-        } else {
-            quote! {}
-        };
+    #[cfg(feature = "bazel")]
+    fn new_with_bazel(bazel_expr: BazelExpr) -> Self {
+        Self {
+            commands: vec![CommandValue::from_bazel(bazel_expr)],
+            label: None,
+            label_origin: None,
+            key: None,
+            description: None,
+            description_env: false,
+            depends_on: Vec::new(),
+            env: Vec::new(),
+            env_spans: Vec::new(),
+            timeout_in_minutes: None,
+            soft_fail: false,
+            parallelism: None,
+            artifact_paths: Vec::new(),
+            agents: Vec::new(),
+            agents_list: false,
+            branches: Vec::new(),
+            if_condition: None,
+            cache: Vec::new(),
+            retry: None,
+            plugins: Vec::new(),
+            local_plugins: Vec::new(),
+            compose_checks: Vec::new(),
+            notify: Vec::new(),
+            matrix: None,
+            concurrency: None,
+            concurrency_group: None,
+            skip: None,
+            priority: None,
+            allow_dependency_failure: false,
+            bep: true,
+            skip_validation: false,
+            shell: None,
+            needs_artifacts: Vec::new(),
+            only_profiles: Vec::new(),
+        }
+    }
 
-        quote! {
-            ::rust_buildkite::GroupStepsItem::CommandStep(
-                ::rust_buildkite::CommandStep::builder()
-                    #command_tokens
-                    #label_tokens
-                    #key_tokens
-                    #depends_on_tokens
-                    #timeout_tokens
-                    #soft_fail_tokens
-                    #parallelism_tokens
-                    #artifact_tokens
-                    #env_tokens
-                    #agents_tokens
-                    #branches_tokens
-                    #if_tokens
-                    #cache_tokens
-                    #retry_tokens
-                    #plugins_tokens
-                    #notify_tokens
-                    #matrix_tokens
-                    #concurrency_tokens
-                    #concurrency_group_tokens
-                    #skip_tokens
-                    #priority_tokens
-                    #allow_dependency_failure_tokens
-                    .try_into()
-                    .expect("command step construction failed")
-            )
+    fn new_empty() -> Self {
+        Self {
+            commands: Vec::new(),
+            label: None,
+            label_origin: None,
+            key: None,
+            description: None,
+            description_env: false,
+            depends_on: Vec::new(),
+            env: Vec::new(),
+            env_spans: Vec::new(),
+            timeout_in_minutes: None,
+            soft_fail: false,
+            parallelism: None,
+            artifact_paths: Vec::new(),
+            agents: Vec::new(),
+            agents_list: false,
+            branches: Vec::new(),
+            if_condition: None,
+            cache: Vec::new(),
+            retry: None,
+            plugins: Vec::new(),
+            local_plugins: Vec::new(),
+            compose_checks: Vec::new(),
+            notify: Vec::new(),
+            matrix: None,
+            concurrency: None,
+            concurrency_group: None,
+            skip: None,
+            priority: None,
+            allow_dependency_failure: false,
+            bep: true,
+            skip_validation: false,
+            shell: None,
+            needs_artifacts: Vec::new(),
+            only_profiles: Vec::new(),
         }
     }
 
-    /// Generate tokens for GroupStepsItem::CommandStep with default plugins merged
-    fn to_group_step_tokens_with_default_plugins(
-        &self,
-        default_plugins: &[NestedValue],
-    ) -> TokenStream2 {
-        if default_plugins.is_empty() {
-            return self.to_group_step_tokens();
-        }
-
-        let all_plugins: Vec<&NestedValue> =
-            default_plugins.iter().chain(self.plugins.iter()).collect();
+    fn get_command_names(&self) -> Vec<(String, proc_macro2::Span)> {
+        self.commands
+            .iter()
+            .map(|cv| (cv.get_command_name(), cv.span()))
+            .collect()
+    }
 
-        assert!(!self.commands.is_empty(), "commands must not be empty");
+    /// Expand this command step as an item of `target`, with pipeline
+    /// default_plugins placed before the step's own plugins.
+    fn expand(&self, target: StepTarget, default_plugins: &[NestedValue]) -> yaml::Expanded {
+        let mut fields = yaml::Fields::default();
 
         let cmd_token_list: Vec<TokenStream2> = self
             .commands
@@ -5570,561 +8496,491 @@ impl CommandStepDef {
             })
             .collect();
 
-        let command_tokens = if cmd_token_list.len() == 1 {
-            let cmd = &cmd_token_list[0];
-            quote! { .command(Some(::rust_buildkite::CommandStepCommand::String(#cmd))) }
-        } else {
-            quote! {
-                .commands(Some(::rust_buildkite::CommandStepCommand::Array(vec![
-                    #(#cmd_token_list),*
-                ])))
-            }
-        };
+        // nb: a plugin-only step has no command at all
+        if let ([cmd], [source]) = (cmd_token_list.as_slice(), self.commands.as_slice()) {
+            fields.set(
+                "command",
+                yaml::command(source),
+                quote! { .command(Some(::rust_buildkite::CommandStepCommand::String(#cmd))) },
+            );
+        } else if !cmd_token_list.is_empty() {
+            fields.set(
+                "commands",
+                self.commands
+                    .iter()
+                    .map(yaml::command)
+                    .collect::<Result<Vec<_>>>()
+                    .map(yaml::Value::Sequence),
+                quote! {
+                    .commands(Some(::rust_buildkite::CommandStepCommand::Array(vec![
+                        #(#cmd_token_list),*
+                    ])))
+                },
+            );
+        }
 
-        let label_tokens = if let Some(l) = &self.label {
-            quote! { .label(Some(::rust_buildkite::Label(#l.to_string()))) }
-        } else {
-            quote! {}
-        };
+        if let Some(label) = &self.label {
+            fields.set(
+                "label",
+                yaml::label(label, "the step label"),
+                quote! { .label(Some(::rust_buildkite::Label(#label.to_string()))) },
+            );
+        }
 
-        let key_tokens = if let Some(key) = &self.key {
+        if let Some(key) = &self.key {
             let key_value = key.to_tokens();
-            quote! { .key(Some(#key_value.try_into().expect("invalid key"))) }
-        } else {
-            quote! {}
-        };
+            fields.set(
+                "key",
+                yaml::key(key),
+                quote! { .key(Some(__rbk_built!(#key_value.try_into().into_build_result(__rbk_step, "invalid key")))) },
+            );
+        }
 
-        let depends_on_tokens = if !self.depends_on.is_empty() {
+        if !self.depends_on.is_empty() {
             let deps: Vec<_> = self.depends_on.iter().map(|(d, _)| d).collect();
-            quote! {
-                .depends_on(Some(::rust_buildkite::DependsOn::DependsOnList(
-                    ::rust_buildkite::DependsOnList(vec![
-                        #(::rust_buildkite::DependsOnListItem::String(#deps.to_string())),*
-                    ])
-                )))
-            }
-        } else {
-            quote! {}
-        };
+            fields.set(
+                "depends_on",
+                Ok(yaml::depends_on(&self.depends_on)),
+                quote! {
+                    .depends_on(Some(::rust_buildkite::helpers::string_deps(&[#(#deps),*])))
+                },
+            );
+        }
 
-        let timeout_tokens = if let Some(t) = &self.timeout_in_minutes {
-            quote! { .timeout_in_minutes(Some(#t)) }
-        } else {
-            quote! {}
-        };
+        if let Some(timeout) = &self.timeout_in_minutes {
+            // nb: parse_timeout rejected 0, so this can't fail
+            let minutes = syn::LitInt::new(timeout.base10_digits(), timeout.span());
+            fields.set(
+                "timeout_in_minutes",
+                yaml::lit_int(timeout),
+                quote! { .timeout_in_minutes(Some(const { ::std::num::NonZeroU64::new(#minutes).unwrap() })) },
+            );
+        }
 
-        let soft_fail_tokens = if self.soft_fail {
-            quote! { .soft_fail(Some(::rust_buildkite::SoftFail::Boolean(true))) }
-        } else {
-            quote! {}
-        };
+        if self.soft_fail {
+            fields.set(
+                "soft_fail",
+                Ok(yaml::Value::Bool(true)),
+                quote! { .soft_fail(Some(::rust_buildkite::SoftFail::Boolean(true))) },
+            );
+        }
 
-        let parallelism_tokens = if let Some(p) = &self.parallelism {
-            quote! { .parallelism(Some(#p)) }
-        } else {
-            quote! {}
-        };
+        if let Some(p) = &self.parallelism {
+            fields.set(
+                "parallelism",
+                yaml::lit_int(p),
+                quote! { .parallelism(Some(#p)) },
+            );
+        }
 
-        let artifact_tokens = if !self.artifact_paths.is_empty() {
-            let paths: Vec<_> = self.artifact_paths.iter().collect();
-            quote! {
-                .artifact_paths(Some(::rust_buildkite::CommandStepArtifactPaths::Array(vec![
-                    #(#paths.to_string()),*
-                ])))
-            }
-        } else {
-            quote! {}
-        };
+        if !self.artifact_paths.is_empty() {
+            let paths = &self.artifact_paths;
+            fields.set(
+                "artifact_paths",
+                Ok(yaml::lit_strs(paths)),
+                quote! {
+                    .artifact_paths(Some(::rust_buildkite::CommandStepArtifactPaths::Array(::rust_buildkite::helpers::string_vec(&[#(#paths),*]))))
+                },
+            );
+        }
 
-        let env_tokens = if !self.env.is_empty() {
-            let inserts: Vec<TokenStream2> = self
+        let description = self.description_env_value();
+        if !self.env.is_empty() || description.is_some() {
+            let description_pair = description.map(|text| quote! { (#DESCRIPTION_ENV_VAR, #text) });
+            let pairs = self
                 .env
                 .iter()
-                .map(|(k, v)| {
-                    let v_tokens = v.to_tokens();
-                    quote! { __env_map.insert(#k.to_string(), ::rust_buildkite::serde_json::Value::String((#v_tokens).to_string())); }
-                })
-                .collect();
-            quote! {
-                .env({
-                    let mut __env_map = ::rust_buildkite::serde_json::Map::new();
-                    #(#inserts)*
-                    Some(::rust_buildkite::Env(__env_map))
-                })
-            }
-        } else {
-            quote! {}
-        };
+                .map(|(k, v)| string_pair_tokens(k, v))
+                .chain(description_pair);
+            let value = yaml::mapping(
+                self.env
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), yaml::dynamic(v, "a step env value")))
+                    .chain(
+                        description
+                            .map(|text| (DESCRIPTION_ENV_VAR.to_string(), Ok(yaml::string(text)))),
+                    ),
+            );
+            fields.set(
+                "env",
+                value,
+                quote! { .env(Some(::rust_buildkite::helpers::env_map(&[#(#pairs),*]))) },
+            );
+        }
 
-        let agents_tokens = if !self.agents.is_empty() {
-            let inserts: Vec<TokenStream2> = self
-                .agents
-                .iter()
-                .map(|(k, v)| {
-                    quote! { __agents_map.insert(#k.to_string(), ::rust_buildkite::serde_json::Value::String(#v.to_string())); }
-                })
-                .collect();
-            quote! {
-                .agents({
-                    let mut __agents_map = ::rust_buildkite::serde_json::Map::new();
-                    #(#inserts)*
-                    Some(::rust_buildkite::Agents::Object(::rust_buildkite::AgentsObject(__agents_map)))
-                })
-            }
-        } else {
-            quote! {}
-        };
+        let agents: Vec<(&str, String)> = self
+            .agents
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.value()))
+            .collect();
+        set_agents(
+            &mut fields,
+            agents.iter().map(|(k, v)| (*k, v.as_str())),
+            self.agents_list,
+        );
 
-        let branches_tokens = if !self.branches.is_empty() {
-            let branches: Vec<_> = self.branches.iter().collect();
-            quote! {
-                .branches(Some(::rust_buildkite::Branches::Array(vec![
-                    #(#branches.to_string()),*
-                ])))
-            }
-        } else {
-            quote! {}
-        };
+        if !self.branches.is_empty() {
+            let branches = &self.branches;
+            fields.set(
+                "branches",
+                Ok(yaml::lit_strs(branches)),
+                quote! {
+                    .branches(Some(::rust_buildkite::Branches::Array(::rust_buildkite::helpers::string_vec(&[#(#branches),*]))))
+                },
+            );
+        }
 
-        let if_tokens = if let Some(condition) = &self.if_condition {
-            quote! { .if_(Some(::rust_buildkite::If(#condition.to_string()))) }
-        } else {
-            quote! {}
-        };
+        if let Some(condition) = &self.if_condition {
+            fields.set(
+                "if",
+                Ok(yaml::string(&condition.value())),
+                quote! { .if_(Some(::rust_buildkite::If(#condition.to_string()))) },
+            );
+        }
 
-        let cache_tokens = if !self.cache.is_empty() {
-            let paths: Vec<_> = self.cache.iter().collect();
-            quote! {
-                .cache(Some(::rust_buildkite::Cache::Array(vec![
-                    #(#paths.to_string()),*
-                ])))
-            }
-        } else {
-            quote! {}
-        };
+        if !self.cache.is_empty() {
+            let paths = &self.cache;
+            fields.set(
+                "cache",
+                Ok(yaml::lit_strs(paths)),
+                quote! {
+                    .cache(Some(::rust_buildkite::Cache::Array(::rust_buildkite::helpers::string_vec(&[#(#paths),*]))))
+                },
+            );
+        }
 
-        let retry_tokens = if let Some(retry) = &self.retry {
-            let automatic_tokens = if let Some(auto) = &retry.automatic {
-                let auto_json = auto.to_json_tokens();
+        if let Some(retry) = &self.retry {
+            let settings: Vec<(&str, &NestedValue)> = [
+                ("automatic", retry.automatic.as_ref()),
+                ("manual", retry.manual.as_ref()),
+            ]
+            .into_iter()
+            .filter_map(|(name, value)| value.map(|v| (name, v)))
+            .collect();
+            let inserts = settings.iter().map(|(name, value)| {
+                let json = value.to_json_tokens();
+                quote! { __retry_obj.insert(#name.to_string(), #json); }
+            });
+            fields.set(
+                "retry",
+                yaml::mapping(
+                    settings
+                        .iter()
+                        .map(|(name, value)| (name.to_string(), Ok(yaml::nested(value)))),
+                ),
                 quote! {
-                    .automatic({
-                        let __auto_value = #auto_json;
-                        Some(::rust_buildkite::serde_json::from_value(__auto_value).expect("invalid automatic retry config"))
+                    .retry({
+                        let mut __retry_obj = ::rust_buildkite::serde_json::Map::new();
+                        #(#inserts)*
+                        let __retry_value = ::rust_buildkite::serde_json::Value::Object(__retry_obj);
+                        Some(__rbk_built!(::rust_buildkite::serde_json::from_value(__retry_value).into_build_result(__rbk_step, "invalid retry config")))
                     })
-                }
-            } else {
-                quote! {}
-            };
-            let manual_tokens = if let Some(manual) = &retry.manual {
-                let manual_json = manual.to_json_tokens();
+                },
+            );
+        }
+
+        if !default_plugins.is_empty() || !self.plugins.is_empty() {
+            let plugins: Vec<&NestedValue> =
+                default_plugins.iter().chain(self.plugins.iter()).collect();
+            let plugin_values = plugins.iter().map(|p| p.to_json_tokens());
+            fields.set(
+                "plugins",
+                Ok(yaml::Value::Sequence(
+                    plugins.iter().map(|p| yaml::nested(p)).collect(),
+                )),
                 quote! {
-                    .manual({
-                        let __manual_value = #manual_json;
-                        Some(::rust_buildkite::serde_json::from_value(__manual_value).expect("invalid manual retry config"))
+                    .plugins({
+                        let __plugins_array = vec![#(#plugin_values),*];
+                        Some(::rust_buildkite::Plugins::List(
+                            ::rust_buildkite::PluginsList(__rbk_built!(__plugins_array
+                                .into_iter()
+                                .map(::rust_buildkite::serde_json::from_value)
+                                .collect::<::std::result::Result<::std::vec::Vec<_>, _>>()
+                                .into_build_result(__rbk_step, "invalid plugin")))
+                        ))
                     })
-                }
-            } else {
-                quote! {}
-            };
-            quote! {
-                .retry(Some(
-                    ::rust_buildkite::CommandStepRetry::builder()
-                        #automatic_tokens
-                        #manual_tokens
-                        .try_into()
-                        .expect("invalid retry config")
-                ))
-            }
-        } else {
-            quote! {}
-        };
-
-        let plugins_tokens = {
-            let plugin_values: Vec<TokenStream2> =
-                all_plugins.iter().map(|p| p.to_json_tokens()).collect();
-            quote! {
-                .plugins({
-                    let __plugins_array = vec![#(#plugin_values),*];
-                    Some(::rust_buildkite::Plugins::List(::rust_buildkite::PluginsList(__plugins_array.into_iter().map(|v| {
-                        ::rust_buildkite::serde_json::from_value(v).expect("invalid plugin")
-                    }).collect())))
-                })
-            }
-        };
+                },
+            );
+        }
 
-        let notify_tokens = if !self.notify.is_empty() {
-            let notify_values: Vec<TokenStream2> =
-                self.notify.iter().map(|n| n.to_json_tokens()).collect();
-            quote! {
-                .notify({
-                    let __notify_array = vec![#(#notify_values),*];
-                    Some(::rust_buildkite::CommandStepNotify(__notify_array.into_iter().map(|v| {
-                        ::rust_buildkite::serde_json::from_value(v).expect("invalid notify")
-                    }).collect()))
-                })
-            }
-        } else {
-            quote! {}
-        };
+        if !self.notify.is_empty() {
+            let notify_values = self.notify.iter().map(|n| n.to_json_tokens());
+            fields.set(
+                "notify",
+                Ok(yaml::Value::Sequence(
+                    self.notify.iter().map(yaml::nested).collect(),
+                )),
+                quote! {
+                    .notify({
+                        let __notify_array = vec![#(#notify_values),*];
+                        Some(::rust_buildkite::CommandStepNotify(__rbk_built!(__notify_array
+                            .into_iter()
+                            .map(::rust_buildkite::serde_json::from_value)
+                            .collect::<::std::result::Result<::std::vec::Vec<_>, _>>()
+                            .into_build_result(__rbk_step, "invalid notify"))))
+                    })
+                },
+            );
+        }
 
-        let matrix_tokens = if let Some(matrix) = &self.matrix {
+        if let Some(matrix) = &self.matrix {
             let matrix_json = matrix.to_json_tokens();
-            quote! {
-                .matrix({
-                    let __matrix_value = #matrix_json;
-                    Some(::rust_buildkite::serde_json::from_value(__matrix_value).expect("invalid matrix"))
-                })
-            }
-        } else {
-            quote! {}
-        };
+            fields.set(
+                "matrix",
+                Ok(yaml::nested(matrix)),
+                quote! {
+                    .matrix({
+                        let __matrix_value = #matrix_json;
+                        Some(__rbk_built!(::rust_buildkite::serde_json::from_value(__matrix_value).into_build_result(__rbk_step, "invalid matrix")))
+                    })
+                },
+            );
+        }
 
-        let concurrency_tokens = if let Some(c) = &self.concurrency {
-            quote! { .concurrency(Some(#c)) }
-        } else {
-            quote! {}
-        };
+        if let Some(c) = &self.concurrency {
+            fields.set(
+                "concurrency",
+                yaml::lit_int(c),
+                quote! { .concurrency(Some(#c)) },
+            );
+        }
 
-        let concurrency_group_tokens = if let Some(group) = &self.concurrency_group {
-            quote! { .concurrency_group(Some(#group.to_string())) }
-        } else {
-            quote! {}
-        };
+        if let Some(group) = &self.concurrency_group {
+            fields.set(
+                "concurrency_group",
+                Ok(yaml::string(&group.value())),
+                quote! { .concurrency_group(Some(#group.to_string())) },
+            );
+        }
 
-        let skip_tokens = match &self.skip {
-            Some(SkipValue::Bool(true)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::Boolean(true))) }
-            }
-            Some(SkipValue::Bool(false)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::Boolean(false))) }
-            }
-            Some(SkipValue::Reason(reason)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::String(#reason.parse().expect("invalid skip reason")))) }
-            }
-            None => quote! {},
-        };
+        if let Some(skip) = &self.skip {
+            fields.set("skip", Ok(yaml::skip(skip)), skip.to_tokens());
+        }
 
-        let priority_tokens = if let Some(p) = &self.priority {
-            quote! { .priority(Some(::rust_buildkite::Priority(#p))) }
-        } else {
-            quote! {}
-        };
+        if let Some(p) = &self.priority {
+            fields.set(
+                "priority",
+                yaml::lit_int(p),
+                quote! { .priority(Some(::rust_buildkite::Priority(#p))) },
+            );
+        }
 
-        let allow_dependency_failure_tokens = if self.allow_dependency_failure {
-            quote! { .allow_dependency_failure(Some(::rust_buildkite::AllowDependencyFailure(true))) }
-        } else {
-            quote! {}
-        };
+        if self.allow_dependency_failure {
+            fields.set(
+                "allow_dependency_failure",
+                Ok(yaml::Value::Bool(true)),
+                quote! { .allow_dependency_failure(Some(::rust_buildkite::AllowDependencyFailure(true))) },
+            );
+        }
 
-        quote! {
-            ::rust_buildkite::GroupStepsItem::CommandStep(
-                ::rust_buildkite::CommandStep::builder()
-                    #command_tokens
-                    #label_tokens
-                    #key_tokens
-                    #depends_on_tokens
-                    #timeout_tokens
-                    #soft_fail_tokens
-                    #parallelism_tokens
-                    #artifact_tokens
-                    #env_tokens
-                    #agents_tokens
-                    #branches_tokens
-                    #if_tokens
-                    #cache_tokens
-                    #retry_tokens
-                    #plugins_tokens
-                    #notify_tokens
-                    #matrix_tokens
-                    #concurrency_tokens
-                    #concurrency_group_tokens
-                    #skip_tokens
-                    #priority_tokens
-                    #allow_dependency_failure_tokens
-                    .try_into()
-                    .expect("command step construction failed")
+        let item = target.item_type();
+        let (calls, value) = fields.finish();
+        let tokens = quote! {
+            #item::CommandStep(
+                __rbk_built!(
+                    ::rust_buildkite::CommandStep::builder()
+                        #calls
+                        .try_into()
+                        .into_build_result(__rbk_step, "construction failed")
+                )
             )
-        }
+        };
+        yaml::Expanded { tokens, value }
     }
 }
 
 struct BlockStepDef {
-    prompt: Option<LitStr>,
+    prompt: Option<syn::Expr>,
+    prompt_origin: Option<ValueOrigin>,
     key: Option<KeyValue>,
+    description: Option<String>,
     depends_on: Vec<(String, proc_macro2::Span)>,
     fields: Vec<FieldDef>,
     allowed_teams: Vec<String>,
     blocked_state: Option<String>,
     branches: Vec<LitStr>,
     if_condition: Option<LitStr>,
-    prompt_text: Option<LitStr>,
+    prompt_text: Option<syn::Expr>,
     allow_dependency_failure: bool,
 }
 
 impl BlockStepDef {
-    fn new(prompt: LitStr) -> Self {
+    fn new(prompt: syn::Expr) -> Self {
         Self {
             prompt: Some(prompt),
+            prompt_origin: None,
             key: None,
+            description: None,
             depends_on: Vec::new(),
             fields: Vec::new(),
             allowed_teams: Vec::new(),
             blocked_state: None,
             branches: Vec::new(),
             if_condition: None,
-            prompt_text: None,
-            allow_dependency_failure: false,
-        }
-    }
-
-    fn new_empty() -> Self {
-        Self {
-            prompt: None,
-            key: None,
-            depends_on: Vec::new(),
-            fields: Vec::new(),
-            allowed_teams: Vec::new(),
-            blocked_state: None,
-            branches: Vec::new(),
-            if_condition: None,
-            prompt_text: None,
-            allow_dependency_failure: false,
-        }
-    }
-
-    fn to_tokens_inner(&self) -> TokenStream2 {
-        let prompt = self.prompt.as_ref().expect("block prompt must be set");
-
-        let key_tokens = if let Some(key) = &self.key {
-            let key_value = key.to_tokens();
-            quote! { .key(Some(#key_value.try_into().expect("invalid key"))) }
-        } else {
-            quote! {}
-        };
-
-        let depends_on_tokens = if !self.depends_on.is_empty() {
-            let deps: Vec<_> = self.depends_on.iter().map(|(d, _)| d).collect();
-            quote! {
-                .depends_on(Some(::rust_buildkite::DependsOn::DependsOnList(
-                    ::rust_buildkite::DependsOnList(vec![
-                        #(::rust_buildkite::DependsOnListItem::String(#deps.to_string())),*
-                    ])
-                )))
-            }
-        } else {
-            quote! {}
-        };
-
-        let fields_tokens = if !self.fields.is_empty() {
-            let field_items: Vec<TokenStream2> =
-                self.fields.iter().map(|f| f.to_tokens_inner()).collect();
-            quote! {
-                .fields(Some(::rust_buildkite::Fields(vec![
-                    #(#field_items),*
-                ])))
-            }
-        } else {
-            quote! {}
-        };
-
-        let allowed_teams_tokens = if !self.allowed_teams.is_empty() {
-            let teams = &self.allowed_teams;
-            quote! {
-                .allowed_teams(Some(::rust_buildkite::AllowedTeams::Array(vec![
-                    #(#teams.to_string()),*
-                ])))
-            }
-        } else {
-            quote! {}
-        };
-
-        let blocked_state_tokens = if let Some(state) = &self.blocked_state {
-            quote! {
-                .blocked_state(match #state {
-                    "passed" => ::rust_buildkite::BlockStepBlockedState::Passed,
-                    "failed" => ::rust_buildkite::BlockStepBlockedState::Failed,
-                    "running" => ::rust_buildkite::BlockStepBlockedState::Running,
-                    _ => ::rust_buildkite::BlockStepBlockedState::Passed,
-                })
-            }
-        } else {
-            quote! {}
-        };
-
-        let branches_tokens = if !self.branches.is_empty() {
-            let branches = &self.branches;
-            quote! {
-                .branches(Some(::rust_buildkite::Branches::Array(vec![
-                    #(#branches.to_string()),*
-                ])))
-            }
-        } else {
-            quote! {}
-        };
-
-        let if_tokens = if let Some(condition) = &self.if_condition {
-            quote! { .if_(Some(::rust_buildkite::If(#condition.to_string()))) }
-        } else {
-            quote! {}
-        };
-
-        let prompt_tokens = if let Some(p) = &self.prompt_text {
-            quote! { .prompt(Some(::rust_buildkite::Prompt(#p.to_string()))) }
-        } else {
-            quote! {}
-        };
-
-        let allow_dependency_failure_tokens = if self.allow_dependency_failure {
-            quote! { .allow_dependency_failure(Some(::rust_buildkite::AllowDependencyFailure(true))) }
-        } else {
-            quote! {}
-        };
+            prompt_text: None,
+            allow_dependency_failure: false,
+        }
+    }
 
-        quote! {
-            ::rust_buildkite::PipelineStepsItem::BlockStep(
-                ::rust_buildkite::BlockStep::builder()
-                    .block(Some(#prompt.to_string()))
-                    #key_tokens
-                    #depends_on_tokens
-                    #fields_tokens
-                    #allowed_teams_tokens
-                    #blocked_state_tokens
-                    #branches_tokens
-                    #if_tokens
-                    #prompt_tokens
-                    #allow_dependency_failure_tokens
-                    .try_into()
-                    .expect("block step construction failed")
-            )
+    fn new_empty() -> Self {
+        Self {
+            prompt: None,
+            prompt_origin: None,
+            key: None,
+            description: None,
+            depends_on: Vec::new(),
+            fields: Vec::new(),
+            allowed_teams: Vec::new(),
+            blocked_state: None,
+            branches: Vec::new(),
+            if_condition: None,
+            prompt_text: None,
+            allow_dependency_failure: false,
         }
     }
 
-    fn to_group_step_tokens(&self) -> TokenStream2 {
+    /// Expand this step as an item of `target`'s steps.
+    fn expand(&self, target: StepTarget) -> yaml::Expanded {
         let prompt = self.prompt.as_ref().expect("block prompt must be set");
+        let mut fields = yaml::Fields::default();
+        fields.set(
+            "block",
+            yaml::label(prompt, "the step prompt"),
+            quote! { .block(Some(#prompt.to_string())) },
+        );
 
-        let key_tokens = if let Some(key) = &self.key {
+        if let Some(key) = &self.key {
             let key_value = key.to_tokens();
-            quote! { .key(Some(#key_value.try_into().expect("invalid key"))) }
-        } else {
-            quote! {}
-        };
+            fields.set(
+                "key",
+                yaml::key(key),
+                quote! { .key(Some(__rbk_built!(#key_value.try_into().into_build_result(__rbk_step, "invalid key")))) },
+            );
+        }
 
-        let depends_on_tokens = if !self.depends_on.is_empty() {
+        if !self.depends_on.is_empty() {
             let deps: Vec<_> = self.depends_on.iter().map(|(d, _)| d).collect();
-            quote! {
-                .depends_on(Some(::rust_buildkite::DependsOn::DependsOnList(
-                    ::rust_buildkite::DependsOnList(vec![
-                        #(::rust_buildkite::DependsOnListItem::String(#deps.to_string())),*
-                    ])
-                )))
-            }
-        } else {
-            quote! {}
-        };
+            fields.set(
+                "depends_on",
+                Ok(yaml::depends_on(&self.depends_on)),
+                quote! {
+                    .depends_on(Some(::rust_buildkite::helpers::string_deps(&[#(#deps),*])))
+                },
+            );
+        }
 
-        let fields_tokens = if !self.fields.is_empty() {
-            let field_items: Vec<TokenStream2> =
-                self.fields.iter().map(|f| f.to_tokens_inner()).collect();
-            quote! {
-                .fields(Some(::rust_buildkite::Fields(vec![
-                    #(#field_items),*
-                ])))
-            }
-        } else {
-            quote! {}
-        };
+        if !self.fields.is_empty() {
+            let (field_items, value) = yaml::sequence(self.fields.iter().map(FieldDef::expand));
+            fields.set(
+                "fields",
+                value,
+                quote! {
+                    .fields(Some(::rust_buildkite::Fields(vec![
+                        #(#field_items),*
+                    ])))
+                },
+            );
+        }
 
-        let allowed_teams_tokens = if !self.allowed_teams.is_empty() {
+        if !self.allowed_teams.is_empty() {
             let teams = &self.allowed_teams;
-            quote! {
-                .allowed_teams(Some(::rust_buildkite::AllowedTeams::Array(vec![
-                    #(#teams.to_string()),*
-                ])))
-            }
-        } else {
-            quote! {}
-        };
+            fields.set(
+                "allowed_teams",
+                Ok(yaml::strings(teams.iter().map(String::as_str))),
+                quote! {
+                    .allowed_teams(Some(::rust_buildkite::AllowedTeams::Array(::rust_buildkite::helpers::string_vec(&[#(#teams),*]))))
+                },
+            );
+        }
 
-        let blocked_state_tokens = if let Some(state) = &self.blocked_state {
-            quote! {
-                .blocked_state(match #state {
-                    "passed" => ::rust_buildkite::BlockStepBlockedState::Passed,
-                    "failed" => ::rust_buildkite::BlockStepBlockedState::Failed,
-                    "running" => ::rust_buildkite::BlockStepBlockedState::Running,
-                    _ => ::rust_buildkite::BlockStepBlockedState::Passed,
-                })
-            }
-        } else {
-            quote! {}
-        };
+        if let Some(state) = &self.blocked_state {
+            fields.set(
+                "blocked_state",
+                Ok(yaml::string(state)),
+                quote! {
+                    .blocked_state(match #state {
+                        "passed" => ::rust_buildkite::BlockStepBlockedState::Passed,
+                        "failed" => ::rust_buildkite::BlockStepBlockedState::Failed,
+                        "running" => ::rust_buildkite::BlockStepBlockedState::Running,
+                        _ => ::rust_buildkite::BlockStepBlockedState::Passed,
+                    })
+                },
+            );
+        }
 
-        let branches_tokens = if !self.branches.is_empty() {
+        if !self.branches.is_empty() {
             let branches = &self.branches;
-            quote! {
-                .branches(Some(::rust_buildkite::Branches::Array(vec![
-                    #(#branches.to_string()),*
-                ])))
-            }
-        } else {
-            quote! {}
-        };
+            fields.set(
+                "branches",
+                Ok(yaml::lit_strs(branches)),
+                quote! {
+                    .branches(Some(::rust_buildkite::Branches::Array(::rust_buildkite::helpers::string_vec(&[#(#branches),*]))))
+                },
+            );
+        }
 
-        let if_tokens = if let Some(condition) = &self.if_condition {
-            quote! { .if_(Some(::rust_buildkite::If(#condition.to_string()))) }
-        } else {
-            quote! {}
-        };
+        if let Some(condition) = &self.if_condition {
+            fields.set(
+                "if",
+                Ok(yaml::string(&condition.value())),
+                quote! { .if_(Some(::rust_buildkite::If(#condition.to_string()))) },
+            );
+        }
 
-        let prompt_tokens = if let Some(p) = &self.prompt_text {
-            quote! { .prompt(Some(::rust_buildkite::Prompt(#p.to_string()))) }
-        } else {
-            quote! {}
-        };
+        if let Some(p) = &self.prompt_text {
+            fields.set(
+                "prompt",
+                yaml::label(p, "the prompt text"),
+                quote! { .prompt(Some(::rust_buildkite::Prompt(#p.to_string()))) },
+            );
+        }
 
-        let allow_dependency_failure_tokens = if self.allow_dependency_failure {
-            quote! { .allow_dependency_failure(Some(::rust_buildkite::AllowDependencyFailure(true))) }
-        } else {
-            quote! {}
-        };
+        if self.allow_dependency_failure {
+            fields.set(
+                "allow_dependency_failure",
+                Ok(yaml::Value::Bool(true)),
+                quote! { .allow_dependency_failure(Some(::rust_buildkite::AllowDependencyFailure(true))) },
+            );
+        }
 
-        quote! {
-            ::rust_buildkite::GroupStepsItem::BlockStep(
-                ::rust_buildkite::BlockStep::builder()
-                    .block(Some(#prompt.to_string()))
-                    #key_tokens
-                    #depends_on_tokens
-                    #fields_tokens
-                    #allowed_teams_tokens
-                    #blocked_state_tokens
-                    #branches_tokens
-                    #if_tokens
-                    #prompt_tokens
-                    #allow_dependency_failure_tokens
-                    .try_into()
-                    .expect("block step construction failed")
+        let item = target.item_type();
+        let (calls, value) = fields.finish();
+        let tokens = quote! {
+            #item::BlockStep(
+                __rbk_built!(
+                    ::rust_buildkite::BlockStep::builder()
+                        #calls
+                        .try_into()
+                        .into_build_result(__rbk_step, "construction failed")
+                )
             )
-        }
+        };
+        yaml::Expanded { tokens, value }
     }
 }
 
 struct InputStepDef {
-    prompt: Option<LitStr>,
+    prompt: Option<syn::Expr>,
+    prompt_origin: Option<ValueOrigin>,
     key: Option<KeyValue>,
+    description: Option<String>,
     depends_on: Vec<(String, proc_macro2::Span)>,
     fields: Vec<FieldDef>,
     allowed_teams: Vec<String>,
     blocked_state: Option<String>,
     branches: Vec<LitStr>,
     if_condition: Option<LitStr>,
-    prompt_text: Option<LitStr>,
+    prompt_text: Option<syn::Expr>,
     allow_dependency_failure: bool,
 }
 
 impl InputStepDef {
-    fn new(prompt: LitStr) -> Self {
+    fn new(prompt: syn::Expr) -> Self {
         Self {
             prompt: Some(prompt),
+            prompt_origin: None,
             key: None,
+            description: None,
             depends_on: Vec::new(),
             fields: Vec::new(),
             allowed_teams: Vec::new(),
@@ -6139,7 +8995,9 @@ impl InputStepDef {
     fn new_empty() -> Self {
         Self {
             prompt: None,
+            prompt_origin: None,
             key: None,
+            description: None,
             depends_on: Vec::new(),
             fields: Vec::new(),
             allowed_teams: Vec::new(),
@@ -6151,251 +9009,407 @@ impl InputStepDef {
         }
     }
 
-    fn to_tokens_inner(&self) -> TokenStream2 {
+    /// Expand this step as an item of `target`'s steps.
+    fn expand(&self, target: StepTarget) -> yaml::Expanded {
         let prompt = self.prompt.as_ref().expect("input prompt must be set");
+        let mut fields = yaml::Fields::default();
+        fields.set(
+            "input",
+            yaml::label(prompt, "the step prompt"),
+            quote! { .input(Some(#prompt.to_string())) },
+        );
 
-        let key_tokens = if let Some(key) = &self.key {
+        if let Some(key) = &self.key {
             let key_value = key.to_tokens();
-            quote! { .key(Some(#key_value.try_into().expect("invalid key"))) }
-        } else {
-            quote! {}
-        };
+            fields.set(
+                "key",
+                yaml::key(key),
+                quote! { .key(Some(__rbk_built!(#key_value.try_into().into_build_result(__rbk_step, "invalid key")))) },
+            );
+        }
 
-        let depends_on_tokens = if !self.depends_on.is_empty() {
+        if !self.depends_on.is_empty() {
             let deps: Vec<_> = self.depends_on.iter().map(|(d, _)| d).collect();
-            quote! {
-                .depends_on(Some(::rust_buildkite::DependsOn::DependsOnList(
-                    ::rust_buildkite::DependsOnList(vec![
-                        #(::rust_buildkite::DependsOnListItem::String(#deps.to_string())),*
-                    ])
-                )))
-            }
-        } else {
-            quote! {}
-        };
+            fields.set(
+                "depends_on",
+                Ok(yaml::depends_on(&self.depends_on)),
+                quote! {
+                    .depends_on(Some(::rust_buildkite::helpers::string_deps(&[#(#deps),*])))
+                },
+            );
+        }
 
-        let fields_tokens = if !self.fields.is_empty() {
-            let field_items: Vec<TokenStream2> =
-                self.fields.iter().map(|f| f.to_tokens_inner()).collect();
-            quote! {
-                .fields(Some(::rust_buildkite::Fields(vec![
-                    #(#field_items),*
-                ])))
-            }
-        } else {
-            quote! {}
-        };
+        if !self.fields.is_empty() {
+            let (field_items, value) = yaml::sequence(self.fields.iter().map(FieldDef::expand));
+            fields.set(
+                "fields",
+                value,
+                quote! {
+                    .fields(Some(::rust_buildkite::Fields(vec![
+                        #(#field_items),*
+                    ])))
+                },
+            );
+        }
 
-        let allowed_teams_tokens = if !self.allowed_teams.is_empty() {
+        if !self.allowed_teams.is_empty() {
             let teams = &self.allowed_teams;
-            quote! {
-                .allowed_teams(Some(::rust_buildkite::AllowedTeams::Array(vec![
-                    #(#teams.to_string()),*
-                ])))
-            }
-        } else {
-            quote! {}
-        };
+            fields.set(
+                "allowed_teams",
+                Ok(yaml::strings(teams.iter().map(String::as_str))),
+                quote! {
+                    .allowed_teams(Some(::rust_buildkite::AllowedTeams::Array(::rust_buildkite::helpers::string_vec(&[#(#teams),*]))))
+                },
+            );
+        }
 
-        let blocked_state_tokens = if let Some(state) = &self.blocked_state {
-            quote! {
-                .blocked_state(match #state {
-                    "passed" => ::rust_buildkite::InputStepBlockedState::Passed,
-                    "failed" => ::rust_buildkite::InputStepBlockedState::Failed,
-                    "running" => ::rust_buildkite::InputStepBlockedState::Running,
-                    _ => ::rust_buildkite::InputStepBlockedState::Passed,
-                })
-            }
-        } else {
-            quote! {}
-        };
+        if let Some(state) = &self.blocked_state {
+            fields.set(
+                "blocked_state",
+                Ok(yaml::string(state)),
+                quote! {
+                    .blocked_state(match #state {
+                        "passed" => ::rust_buildkite::InputStepBlockedState::Passed,
+                        "failed" => ::rust_buildkite::InputStepBlockedState::Failed,
+                        "running" => ::rust_buildkite::InputStepBlockedState::Running,
+                        _ => ::rust_buildkite::InputStepBlockedState::Passed,
+                    })
+                },
+            );
+        }
 
-        let branches_tokens = if !self.branches.is_empty() {
+        if !self.branches.is_empty() {
             let branches = &self.branches;
-            quote! {
-                .branches(Some(::rust_buildkite::Branches::Array(vec![
-                    #(#branches.to_string()),*
-                ])))
-            }
-        } else {
-            quote! {}
-        };
+            fields.set(
+                "branches",
+                Ok(yaml::lit_strs(branches)),
+                quote! {
+                    .branches(Some(::rust_buildkite::Branches::Array(::rust_buildkite::helpers::string_vec(&[#(#branches),*]))))
+                },
+            );
+        }
 
-        let if_tokens = if let Some(condition) = &self.if_condition {
-            quote! { .if_(Some(::rust_buildkite::If(#condition.to_string()))) }
-        } else {
-            quote! {}
-        };
+        if let Some(condition) = &self.if_condition {
+            fields.set(
+                "if",
+                Ok(yaml::string(&condition.value())),
+                quote! { .if_(Some(::rust_buildkite::If(#condition.to_string()))) },
+            );
+        }
 
-        let prompt_tokens = if let Some(p) = &self.prompt_text {
-            quote! { .prompt(Some(::rust_buildkite::Prompt(#p.to_string()))) }
-        } else {
-            quote! {}
-        };
+        if let Some(p) = &self.prompt_text {
+            fields.set(
+                "prompt",
+                yaml::label(p, "the prompt text"),
+                quote! { .prompt(Some(::rust_buildkite::Prompt(#p.to_string()))) },
+            );
+        }
 
-        let allow_dependency_failure_tokens = if self.allow_dependency_failure {
-            quote! { .allow_dependency_failure(Some(::rust_buildkite::AllowDependencyFailure(true))) }
-        } else {
-            quote! {}
-        };
+        if self.allow_dependency_failure {
+            fields.set(
+                "allow_dependency_failure",
+                Ok(yaml::Value::Bool(true)),
+                quote! { .allow_dependency_failure(Some(::rust_buildkite::AllowDependencyFailure(true))) },
+            );
+        }
 
-        quote! {
-            ::rust_buildkite::PipelineStepsItem::InputStep(
-                ::rust_buildkite::InputStep::builder()
-                    .input(Some(#prompt.to_string()))
-                    #key_tokens
-                    #depends_on_tokens
-                    #fields_tokens
-                    #allowed_teams_tokens
-                    #blocked_state_tokens
-                    #branches_tokens
-                    #if_tokens
-                    #prompt_tokens
-                    #allow_dependency_failure_tokens
-                    .try_into()
-                    .expect("input step construction failed")
+        let item = target.item_type();
+        let (calls, value) = fields.finish();
+        let tokens = quote! {
+            #item::InputStep(
+                __rbk_built!(
+                    ::rust_buildkite::InputStep::builder()
+                        #calls
+                        .try_into()
+                        .into_build_result(__rbk_step, "construction failed")
+                )
             )
-        }
+        };
+        yaml::Expanded { tokens, value }
     }
+}
 
-    fn to_group_step_tokens(&self) -> TokenStream2 {
-        let prompt = self.prompt.as_ref().expect("input prompt must be set");
+struct TriggerStepDef {
+    pipeline: Option<LitStr>,
+    label: Option<syn::Expr>,
+    /// Set when the label was computed, e.g. by `comptime_shell!`
+    label_origin: Option<ValueOrigin>,
+    key: Option<KeyValue>,
+    description: Option<String>,
+    depends_on: Vec<(String, proc_macro2::Span)>,
+    async_trigger: bool,
+    build: Option<TriggerBuildConfig>,
+    branches: Vec<LitStr>,
+    if_condition: Option<LitStr>,
+    skip: Option<SkipValue>,
+    soft_fail: bool,
+    allow_dependency_failure: bool,
+}
 
-        let key_tokens = if let Some(key) = &self.key {
-            let key_value = key.to_tokens();
-            quote! { .key(Some(#key_value.try_into().expect("invalid key"))) }
-        } else {
-            quote! {}
-        };
+/// Longest `build.message` accepted on a trigger step. The message titles the
+/// triggered build, so anything longer is likely a pasted log.
+const TRIGGER_MESSAGE_MAX: usize = 1000;
 
-        let depends_on_tokens = if !self.depends_on.is_empty() {
-            let deps: Vec<_> = self.depends_on.iter().map(|(d, _)| d).collect();
-            quote! {
-                .depends_on(Some(::rust_buildkite::DependsOn::DependsOnList(
-                    ::rust_buildkite::DependsOnList(vec![
-                        #(::rust_buildkite::DependsOnListItem::String(#deps.to_string())),*
-                    ])
-                )))
-            }
-        } else {
-            quote! {}
-        };
+/// Build configuration for trigger step
+#[derive(Clone, Default)]
+struct TriggerBuildConfig {
+    branch: Option<String>,
+    commit: Option<String>,
+    message: Option<String>,
+    env: Vec<(String, NestedValue)>,
+    meta_data: Vec<(String, NestedValue)>,
+    /// String values Buildkite interpolates, with the span of each literal,
+    /// for checking their `$VAR` references
+    interpolated: Vec<(String, proc_macro2::Span)>,
+}
 
-        let fields_tokens = if !self.fields.is_empty() {
-            let field_items: Vec<TokenStream2> =
-                self.fields.iter().map(|f| f.to_tokens_inner()).collect();
-            quote! {
-                .fields(Some(::rust_buildkite::Fields(vec![
-                    #(#field_items),*
-                ])))
-            }
-        } else {
-            quote! {}
-        };
+impl TriggerBuildConfig {
+    /// Parse build: { branch: "...", commit: "...", message: "...", env: {...}, meta_data: {...} }
+    fn parse(input: ParseStream) -> Result<Self> {
+        let content;
+        braced!(content in input);
+        let mut config = TriggerBuildConfig::default();
 
-        let allowed_teams_tokens = if !self.allowed_teams.is_empty() {
-            let teams = &self.allowed_teams;
-            quote! {
-                .allowed_teams(Some(::rust_buildkite::AllowedTeams::Array(vec![
-                    #(#teams.to_string()),*
-                ])))
-            }
-        } else {
-            quote! {}
-        };
+        while !content.is_empty() {
+            let key = Self::parse_key(&content)?;
+            content.parse::<Token![:]>()?;
 
-        let blocked_state_tokens = if let Some(state) = &self.blocked_state {
-            quote! {
-                .blocked_state(match #state {
-                    "passed" => ::rust_buildkite::InputStepBlockedState::Passed,
-                    "failed" => ::rust_buildkite::InputStepBlockedState::Failed,
-                    "running" => ::rust_buildkite::InputStepBlockedState::Running,
-                    _ => ::rust_buildkite::InputStepBlockedState::Passed,
-                })
+            match key.0.as_str() {
+                "branch" => {
+                    let lit: LitStr = content.parse()?;
+                    Self::check_branch(&lit)?;
+                    config.interpolated.push((lit.value(), lit.span()));
+                    config.branch = Some(lit.value());
+                }
+                "commit" => {
+                    let lit: LitStr = content.parse()?;
+                    Self::check_commit(&lit)?;
+                    config.interpolated.push((lit.value(), lit.span()));
+                    config.commit = Some(lit.value());
+                }
+                "message" => {
+                    let lit: LitStr = content.parse()?;
+                    Self::check_message(&lit)?;
+                    config.interpolated.push((lit.value(), lit.span()));
+                    config.message = Some(lit.value());
+                }
+                "env" => {
+                    config.env =
+                        Self::parse_scalar_map(&content, "build.env", &mut config.interpolated)?;
+                }
+                "meta_data" => {
+                    config.meta_data = Self::parse_scalar_map(
+                        &content,
+                        "build.meta_data",
+                        &mut config.interpolated,
+                    )?;
+                }
+                other => {
+                    return Err(Error::new(
+                        key.1,
+                        format!(
+                            "unknown trigger build field: {}. Expected: branch, commit, message, env, meta_data",
+                            other
+                        ),
+                    ));
+                }
             }
-        } else {
-            quote! {}
-        };
 
-        let branches_tokens = if !self.branches.is_empty() {
-            let branches = &self.branches;
-            quote! {
-                .branches(Some(::rust_buildkite::Branches::Array(vec![
-                    #(#branches.to_string()),*
-                ])))
+            if content.peek(Token![,]) {
+                content.parse::<Token![,]>()?;
             }
-        } else {
-            quote! {}
-        };
+        }
+
+        Ok(config)
+    }
 
-        let if_tokens = if let Some(condition) = &self.if_condition {
-            quote! { .if_(Some(::rust_buildkite::If(#condition.to_string()))) }
+    /// `build.commit` must be "HEAD" or a commit SHA, abbreviated to no fewer
+    /// than 7 hex digits. Buildkite doesn't resolve refs like "HEAD~1", so the
+    /// triggered build would fail only after upload.
+    fn check_commit(lit: &LitStr) -> Result<()> {
+        let commit = lit.value();
+        if commit == "HEAD" || !interpolated_vars(&commit).is_empty() {
+            return Ok(());
+        }
+        let is_sha =
+            (7..=40).contains(&commit.len()) && commit.chars().all(|c| c.is_ascii_hexdigit());
+        if is_sha {
+            return Ok(());
+        }
+        Err(Error::new(
+            lit.span(),
+            format!(
+                "build.commit \"{}\" is not a commit Buildkite can build: use \"HEAD\", a SHA of 7 to 40 hex characters, or a variable such as \"$BUILDKITE_COMMIT\"",
+                commit
+            ),
+        ))
+    }
+
+    /// `build.branch` is a git branch name, which can't hold whitespace or
+    /// start with a dash.
+    fn check_branch(lit: &LitStr) -> Result<()> {
+        let branch = lit.value();
+        if !interpolated_vars(&branch).is_empty() {
+            return Ok(());
+        }
+        let problem = if branch.is_empty() {
+            "is empty"
+        } else if branch.chars().any(char::is_whitespace) {
+            "contains whitespace"
+        } else if branch.starts_with('-') {
+            "starts with '-'"
         } else {
-            quote! {}
+            return Ok(());
         };
+        Err(Error::new(
+            lit.span(),
+            format!(
+                "build.branch \"{}\" {}, so it isn't a valid git branch name",
+                branch, problem
+            ),
+        ))
+    }
+
+    fn check_message(lit: &LitStr) -> Result<()> {
+        let len = lit.value().chars().count();
+        if len > TRIGGER_MESSAGE_MAX {
+            return Err(Error::new(
+                lit.span(),
+                format!(
+                    "build.message is {} characters, but a triggered build's message is limited to {}",
+                    len, TRIGGER_MESSAGE_MAX
+                ),
+            ));
+        }
+        Ok(())
+    }
 
-        let prompt_tokens = if let Some(p) = &self.prompt_text {
-            quote! { .prompt(Some(::rust_buildkite::Prompt(#p.to_string()))) }
+    fn parse_key(input: ParseStream) -> Result<(String, proc_macro2::Span)> {
+        if input.peek(LitStr) {
+            let lit: LitStr = input.parse()?;
+            Ok((lit.value(), lit.span()))
         } else {
-            quote! {}
-        };
+            let ident: Ident = input.parse()?;
+            Ok((
+                strip_raw_ident(&ident.to_string()).to_string(),
+                ident.span(),
+            ))
+        }
+    }
+
+    /// Parse a map of scalar values. Buildkite env and meta-data values are
+    /// scalars, so nested objects and arrays are rejected. String values are
+    /// added to `interpolated`.
+    fn parse_scalar_map(
+        input: ParseStream,
+        field: &str,
+        interpolated: &mut Vec<(String, proc_macro2::Span)>,
+    ) -> Result<Vec<(String, NestedValue)>> {
+        let content;
+        braced!(content in input);
+        let mut pairs = Vec::new();
+
+        while !content.is_empty() {
+            let (key, _) = Self::parse_key(&content)?;
+            content.parse::<Token![:]>()?;
+            let value_span = content.span();
+            let value = NestedValue::parse_field(&content, "build")?;
+            let kind = match &value {
+                NestedValue::Object(_) => Some("an object"),
+                NestedValue::Array(_) => Some("an array"),
+                NestedValue::String(_) | NestedValue::Int(_) | NestedValue::Bool(_) => None,
+            };
+            if let Some(kind) = kind {
+                return Err(Error::new(
+                    value_span,
+                    format!(
+                        "{} value for '{}' is {}, but Buildkite only accepts scalar values here (string, number, or bool)",
+                        field, key, kind
+                    ),
+                ));
+            }
+            if let NestedValue::String(text) = &value {
+                interpolated.push((text.clone(), value_span));
+            }
+            pairs.push((key, value));
+            if content.peek(Token![,]) {
+                content.parse::<Token![,]>()?;
+            }
+        }
 
-        let allow_dependency_failure_tokens = if self.allow_dependency_failure {
-            quote! { .allow_dependency_failure(Some(::rust_buildkite::AllowDependencyFailure(true))) }
-        } else {
-            quote! {}
-        };
+        Ok(pairs)
+    }
 
-        quote! {
-            ::rust_buildkite::GroupStepsItem::InputStep(
-                ::rust_buildkite::InputStep::builder()
-                    .input(Some(#prompt.to_string()))
-                    #key_tokens
-                    #depends_on_tokens
-                    #fields_tokens
-                    #allowed_teams_tokens
-                    #blocked_state_tokens
-                    #branches_tokens
-                    #if_tokens
-                    #prompt_tokens
-                    #allow_dependency_failure_tokens
-                    .try_into()
-                    .expect("input step construction failed")
-            )
+    /// The `TriggerStepBuild` builder calls, and the build config they set.
+    fn expand(&self) -> (TokenStream2, Result<yaml::Value>) {
+        let mut fields = yaml::Fields::default();
+        if let Some(b) = &self.branch {
+            fields.set(
+                "branch",
+                Ok(yaml::string(b)),
+                quote! { .branch(#b.to_string()) },
+            );
+        }
+        if let Some(c) = &self.commit {
+            fields.set(
+                "commit",
+                Ok(yaml::string(c)),
+                quote! { .commit(#c.to_string()) },
+            );
+        }
+        if let Some(m) = &self.message {
+            fields.set(
+                "message",
+                Ok(yaml::string(m)),
+                quote! { .message(#m.to_string()) },
+            );
+        }
+        if !self.env.is_empty() {
+            let env_inserts = self.env.iter().map(|(k, v)| {
+                let v_json = v.to_json_tokens();
+                quote! { __build_env.insert(#k.to_string(), #v_json); }
+            });
+            fields.set(
+                "env",
+                Ok(yaml::nested_mapping(&self.env)),
+                quote! {
+                    .env({
+                        let mut __build_env = ::rust_buildkite::serde_json::Map::new();
+                        #(#env_inserts)*
+                        Some(::rust_buildkite::Env(__build_env))
+                    })
+                },
+            );
+        }
+        if !self.meta_data.is_empty() {
+            let md_inserts = self.meta_data.iter().map(|(k, v)| {
+                let v_json = v.to_json_tokens();
+                quote! { __build_meta.insert(#k.to_string(), #v_json); }
+            });
+            fields.set(
+                "meta_data",
+                Ok(yaml::nested_mapping(&self.meta_data)),
+                quote! {
+                    .meta_data({
+                        let mut __build_meta = ::rust_buildkite::serde_json::Map::new();
+                        #(#md_inserts)*
+                        __build_meta
+                    })
+                },
+            );
         }
+        fields.finish()
     }
 }
 
-struct TriggerStepDef {
-    pipeline: Option<LitStr>,
-    label: Option<syn::Expr>,
-    key: Option<KeyValue>,
-    depends_on: Vec<(String, proc_macro2::Span)>,
-    async_trigger: bool,
-    build: Option<TriggerBuildConfig>,
-    branches: Vec<LitStr>,
-    if_condition: Option<LitStr>,
-    skip: Option<SkipValue>,
-    soft_fail: bool,
-    allow_dependency_failure: bool,
-}
-
-/// Build configuration for trigger step
-#[derive(Clone, Default)]
-struct TriggerBuildConfig {
-    branch: Option<String>,
-    commit: Option<String>,
-    message: Option<String>,
-    env: Vec<(String, String)>,
-    meta_data: Vec<(String, String)>,
-}
-
 impl TriggerStepDef {
     fn new(pipeline: LitStr) -> Self {
         Self {
             pipeline: Some(pipeline),
             label: None,
+            label_origin: None,
             key: None,
+            description: None,
             depends_on: Vec::new(),
             async_trigger: false,
             build: None,
@@ -6411,7 +9425,9 @@ impl TriggerStepDef {
         Self {
             pipeline: None,
             label: None,
+            label_origin: None,
             key: None,
+            description: None,
             depends_on: Vec::new(),
             async_trigger: false,
             build: None,
@@ -6423,547 +9439,1149 @@ impl TriggerStepDef {
         }
     }
 
-    fn to_tokens_inner(&self) -> TokenStream2 {
+    /// Expand this step as an item of `target`'s steps.
+    fn expand(&self, target: StepTarget) -> yaml::Expanded {
         let pipeline = self
             .pipeline
             .as_ref()
             .expect("trigger pipeline must be set");
+        let mut fields = yaml::Fields::default();
+        fields.set(
+            "trigger",
+            Ok(yaml::string(&pipeline.value())),
+            quote! { .trigger(#pipeline.to_string()) },
+        );
 
-        let label_tokens = if let Some(label) = &self.label {
-            quote! { .label(Some(::rust_buildkite::Label(#label.to_string()))) }
-        } else {
-            quote! {}
-        };
+        if let Some(label) = &self.label {
+            fields.set(
+                "label",
+                yaml::label(label, "the step label"),
+                quote! { .label(Some(::rust_buildkite::Label(#label.to_string()))) },
+            );
+        }
 
-        let key_tokens = if let Some(key) = &self.key {
+        if let Some(key) = &self.key {
             let key_value = key.to_tokens();
-            quote! { .key(Some(#key_value.try_into().expect("invalid key"))) }
-        } else {
-            quote! {}
-        };
+            fields.set(
+                "key",
+                yaml::key(key),
+                quote! { .key(Some(__rbk_built!(#key_value.try_into().into_build_result(__rbk_step, "invalid key")))) },
+            );
+        }
 
-        let depends_on_tokens = if !self.depends_on.is_empty() {
+        if !self.depends_on.is_empty() {
             let deps: Vec<_> = self.depends_on.iter().map(|(d, _)| d).collect();
-            quote! {
-                .depends_on(Some(::rust_buildkite::DependsOn::DependsOnList(
-                    ::rust_buildkite::DependsOnList(vec![
-                        #(::rust_buildkite::DependsOnListItem::String(#deps.to_string())),*
-                    ])
-                )))
-            }
-        } else {
-            quote! {}
-        };
+            fields.set(
+                "depends_on",
+                Ok(yaml::depends_on(&self.depends_on)),
+                quote! {
+                    .depends_on(Some(::rust_buildkite::helpers::string_deps(&[#(#deps),*])))
+                },
+            );
+        }
 
-        let async_tokens = if self.async_trigger {
-            quote! { .async_(true) }
-        } else {
-            quote! {}
-        };
+        if self.async_trigger {
+            fields.set(
+                "async",
+                Ok(yaml::Value::Bool(true)),
+                quote! { .async_(true) },
+            );
+        }
 
-        let build_tokens = if let Some(build) = &self.build {
-            let branch_tokens = if let Some(b) = &build.branch {
-                quote! { .branch(#b.to_string()) }
-            } else {
-                quote! {}
-            };
-            let commit_tokens = if let Some(c) = &build.commit {
-                quote! { .commit(#c.to_string()) }
-            } else {
-                quote! {}
-            };
-            let message_tokens = if let Some(m) = &build.message {
-                quote! { .message(#m.to_string()) }
-            } else {
-                quote! {}
-            };
-            let env_tokens = if !build.env.is_empty() {
-                let env_inserts: Vec<TokenStream2> = build.env.iter().map(|(k, v)| {
-                    quote! { __build_env.insert(#k.to_string(), ::rust_buildkite::serde_json::Value::String(#v.to_string())); }
-                }).collect();
+        if let Some(build) = &self.build {
+            let (calls, value) = build.expand();
+            fields.set(
+                "build",
+                value,
                 quote! {
-                    .env({
-                        let mut __build_env = ::rust_buildkite::serde_json::Map::new();
-                        #(#env_inserts)*
-                        Some(::rust_buildkite::Env(__build_env))
-                    })
-                }
-            } else {
-                quote! {}
-            };
-            let meta_data_tokens = if !build.meta_data.is_empty() {
-                let md_inserts: Vec<TokenStream2> = build.meta_data.iter().map(|(k, v)| {
-                    quote! { __build_meta.insert(#k.to_string(), ::rust_buildkite::serde_json::Value::String(#v.to_string())); }
-                }).collect();
+                    .build(Some(
+                        __rbk_built!(
+                            ::rust_buildkite::TriggerStepBuild::builder()
+                                #calls
+                                .try_into()
+                                .into_build_result(__rbk_step, "build config construction failed")
+                        )
+                    ))
+                },
+            );
+        }
+
+        if !self.branches.is_empty() {
+            let branches = &self.branches;
+            fields.set(
+                "branches",
+                Ok(yaml::lit_strs(branches)),
                 quote! {
-                    .meta_data({
-                        let mut __build_meta = ::rust_buildkite::serde_json::Map::new();
-                        #(#md_inserts)*
-                        __build_meta
-                    })
-                }
-            } else {
-                quote! {}
-            };
-            quote! {
-                .build(Some(
-                    ::rust_buildkite::TriggerStepBuild::builder()
-                        #branch_tokens
-                        #commit_tokens
-                        #message_tokens
-                        #env_tokens
-                        #meta_data_tokens
+                    .branches(Some(::rust_buildkite::Branches::Array(::rust_buildkite::helpers::string_vec(&[#(#branches),*]))))
+                },
+            );
+        }
+
+        if let Some(condition) = &self.if_condition {
+            fields.set(
+                "if",
+                Ok(yaml::string(&condition.value())),
+                quote! { .if_(Some(::rust_buildkite::If(#condition.to_string()))) },
+            );
+        }
+
+        if let Some(skip) = &self.skip {
+            fields.set("skip", Ok(yaml::skip(skip)), skip.to_tokens());
+        }
+
+        if self.soft_fail {
+            fields.set(
+                "soft_fail",
+                Ok(yaml::Value::Bool(true)),
+                quote! { .soft_fail(Some(::rust_buildkite::SoftFail::Boolean(true))) },
+            );
+        }
+
+        if self.allow_dependency_failure {
+            fields.set(
+                "allow_dependency_failure",
+                Ok(yaml::Value::Bool(true)),
+                quote! { .allow_dependency_failure(Some(::rust_buildkite::AllowDependencyFailure(true))) },
+            );
+        }
+
+        let item = target.item_type();
+        let (calls, value) = fields.finish();
+        let tokens = quote! {
+            #item::TriggerStep(
+                __rbk_built!(
+                    ::rust_buildkite::TriggerStep::builder()
+                        #calls
                         .try_into()
-                        .expect("build config construction failed")
-                ))
-            }
-        } else {
-            quote! {}
+                        .into_build_result(__rbk_step, "construction failed")
+                )
+            )
         };
+        yaml::Expanded { tokens, value }
+    }
+}
 
-        let branches_tokens = if !self.branches.is_empty() {
-            let branches = &self.branches;
-            quote! {
-                .branches(Some(::rust_buildkite::Branches::Array(vec![
-                    #(#branches.to_string()),*
-                ])))
+struct GroupStepDef {
+    label: Option<syn::Expr>,
+    /// Set when the label was computed, e.g. by `comptime_shell!`
+    label_origin: Option<ValueOrigin>,
+    key: Option<KeyValue>,
+    description: Option<String>,
+    depends_on: Vec<(String, proc_macro2::Span)>,
+    steps: Vec<StepDef>,
+    if_condition: Option<LitStr>,
+    skip: Option<SkipValue>,
+    notify: Vec<NotifyValue>,
+    allow_dependency_failure: bool,
+    defaults: Option<StepDefaults>,
+    /// Prepended to every key defined inside the group
+    key_prefix: Option<LitStr>,
+    /// Set when the group was generated from a sharded `bazel_test` step
+    shards: Option<proc_macro2::Span>,
+    /// Profiles this group is generated for; empty means every profile
+    only_profiles: Vec<LitStr>,
+}
+
+/// Defaults merged into nested command steps that don't set the field themselves
+#[derive(Clone, Default)]
+struct StepDefaults {
+    agents: Vec<(String, LitStr)>,
+    env: Vec<(EnvKey, DynamicValue)>,
+    timeout_in_minutes: Option<syn::LitInt>,
+    retry: Option<RetryConfig>,
+}
+
+impl StepDefaults {
+    /// Parse defaults: { agents: {...}, env: {...}, timeout_in_minutes: N, retry: {...} }
+    fn parse(input: ParseStream) -> Result<Self> {
+        let content;
+        braced!(content in input);
+        let mut defaults = StepDefaults::default();
+
+        while !content.is_empty() {
+            let field: Ident = content.parse()?;
+            content.parse::<Token![:]>()?;
+
+            match field.to_string().as_str() {
+                "agents" => {
+                    defaults.agents.extend(parse_agents(&content)?.0);
+                }
+                "env" => {
+                    let env_content;
+                    braced!(env_content in content);
+                    while !env_content.is_empty() {
+                        let env_key = EnvKey::parse(&env_content)?;
+                        env_content.parse::<Token![:]>()?;
+                        let env_value = DynamicValue::parse(&env_content)?;
+                        defaults.env.push((env_key, env_value));
+                        if env_content.peek(Token![,]) {
+                            env_content.parse::<Token![,]>()?;
+                        }
+                    }
+                }
+                "timeout_in_minutes" => {
+                    defaults.timeout_in_minutes = Some(parse_timeout(&content)?);
+                }
+                "retry" => {
+                    defaults.retry = Some(RetryConfig::parse(&content)?);
+                }
+                other => {
+                    return Err(Error::new(
+                        field.span(),
+                        format!(
+                            "unknown defaults field: {}. Expected: agents, env, timeout_in_minutes, retry",
+                            other
+                        ),
+                    ));
+                }
             }
-        } else {
-            quote! {}
-        };
 
-        let if_tokens = if let Some(condition) = &self.if_condition {
-            quote! { .if_(Some(::rust_buildkite::If(#condition.to_string()))) }
-        } else {
-            quote! {}
-        };
+            if content.peek(Token![,]) {
+                content.parse::<Token![,]>()?;
+            }
+        }
+
+        Ok(defaults)
+    }
+
+    /// Merge defaults into a command step. Agents and env merge per key;
+    /// values set on the step always win.
+    fn apply(&self, step: &mut CommandStepDef) {
+        for (key, value) in &self.agents {
+            if !step.agents.iter().any(|(k, _)| k == key) {
+                step.agents.push((key.clone(), value.clone()));
+            }
+        }
+        for (key, value) in &self.env {
+            if !step.env.iter().any(|(k, _)| *key == *k) {
+                step.env.push((key.to_string(), value.clone()));
+            }
+        }
+        if step.timeout_in_minutes.is_none() {
+            step.timeout_in_minutes = self.timeout_in_minutes.clone();
+        }
+        if step.retry.is_none() {
+            step.retry = self.retry.clone();
+        }
+    }
+}
+
+/// A named env overlay declared under the pipeline's `profiles:` field.
+struct Profile {
+    name: Ident,
+    env: Vec<(EnvKey, DynamicValue)>,
+}
+
+impl Profile {
+    /// Parse profiles: { name: { env: { KEY: value, ... } }, ... }
+    fn parse_list(input: ParseStream) -> Result<Vec<Self>> {
+        let content;
+        braced!(content in input);
+        let mut profiles: Vec<Profile> = Vec::new();
 
-        let skip_tokens = match &self.skip {
-            Some(SkipValue::Bool(true)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::Boolean(true))) }
+        while !content.is_empty() {
+            let name: Ident = content.parse()?;
+            content.parse::<Token![:]>()?;
+            if profiles.iter().any(|p| p.name == name) {
+                return Err(Error::new(
+                    name.span(),
+                    format!("duplicate profile: '{}'", name),
+                ));
             }
-            Some(SkipValue::Bool(false)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::Boolean(false))) }
+
+            let body;
+            braced!(body in content);
+            let mut env = Vec::new();
+            while !body.is_empty() {
+                let field: Ident = body.parse()?;
+                body.parse::<Token![:]>()?;
+                match field.to_string().as_str() {
+                    "env" => {
+                        let env_content;
+                        braced!(env_content in body);
+                        while !env_content.is_empty() {
+                            let var_name = EnvKey::parse(&env_content)?;
+                            env_content.parse::<Token![:]>()?;
+                            let var_value = DynamicValue::parse(&env_content)?;
+                            env.push((var_name, var_value));
+                            if env_content.peek(Token![,]) {
+                                env_content.parse::<Token![,]>()?;
+                            }
+                        }
+                    }
+                    other => {
+                        return Err(Error::new(
+                            field.span(),
+                            format!("unknown profile field: {}. Expected: env", other),
+                        ));
+                    }
+                }
+                if body.peek(Token![,]) {
+                    body.parse::<Token![,]>()?;
+                }
             }
-            Some(SkipValue::Reason(reason)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::String(#reason.parse().expect("invalid skip reason")))) }
+            profiles.push(Profile { name, env });
+
+            if content.peek(Token![,]) {
+                content.parse::<Token![,]>()?;
             }
-            None => quote! {},
-        };
+        }
 
-        let soft_fail_tokens = if self.soft_fail {
-            quote! { .soft_fail(Some(::rust_buildkite::SoftFail::Boolean(true))) }
-        } else {
-            quote! {}
-        };
+        Ok(profiles)
+    }
+}
 
-        let allow_dependency_failure_tokens = if self.allow_dependency_failure {
-            quote! { .allow_dependency_failure(Some(::rust_buildkite::AllowDependencyFailure(true))) }
-        } else {
-            quote! {}
-        };
+/// Parse the `profile:` selector. It must be known at expansion time: a
+/// string literal, `env!(...)` or `comptime_shell!(...)`.
+fn parse_profile_selector(input: ParseStream) -> Result<(String, proc_macro2::Span)> {
+    let span = input.span();
+    match DynamicValue::parse(input)? {
+        DynamicValue::Literal(name) | DynamicValue::Computed(name, _) => {
+            Ok((name.trim().to_string(), span))
+        }
+        _ => Err(Error::new(
+            span,
+            "profile must be known at expansion time: use a string literal, env!(...) or comptime_shell!(...); use `if:` for runtime branching",
+        )),
+    }
+}
+
+/// Parse only_profiles: ["name", ...]
+fn parse_only_profiles(input: ParseStream) -> Result<Vec<LitStr>> {
+    let content;
+    let bracket = bracketed!(content in input);
+    let mut names = Vec::new();
+    while !content.is_empty() {
+        names.push(content.parse::<LitStr>()?);
+        if content.peek(Token![,]) {
+            content.parse::<Token![,]>()?;
+        }
+    }
+    if names.is_empty() {
+        return Err(Error::new(
+            bracket.span.join(),
+            "only_profiles requires at least one profile name",
+        ));
+    }
+    Ok(names)
+}
+
+/// Merge the selected profile's env over the pipeline env and drop steps
+/// whose `only_profiles` doesn't name it. Steps restricted to a profile are
+/// dropped when no profile is selected.
+fn apply_profile(
+    profiles: &[Profile],
+    selected: Option<&(String, proc_macro2::Span)>,
+    env: &mut Option<Vec<(EnvKey, DynamicValue)>>,
+    steps: &mut Vec<StepDef>,
+) -> Result<()> {
+    let declared = || {
+        if profiles.is_empty() {
+            "none".to_string()
+        } else {
+            profiles
+                .iter()
+                .map(|p| p.name.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+    };
+    let is_declared = |name: &str| profiles.iter().any(|p| p.name == name);
+
+    let mut restricted = Vec::new();
+    collect_only_profiles(steps, &mut restricted);
+    let errors: Vec<Error> = restricted
+        .into_iter()
+        .filter(|lit| !is_declared(&lit.value()))
+        .map(|lit| {
+            Error::new(
+                lit.span(),
+                format!(
+                    "unknown profile '{}' in only_profiles. Declared profiles: {}",
+                    lit.value(),
+                    declared()
+                ),
+            )
+        })
+        .collect();
+    if let Some(err) = combine_errors(errors) {
+        return Err(err);
+    }
+
+    let selected = match selected {
+        Some((name, span)) => {
+            let Some(profile) = profiles.iter().find(|p| p.name == name) else {
+                return Err(Error::new(
+                    *span,
+                    format!(
+                        "unknown profile '{}'. Declared profiles: {}",
+                        name,
+                        declared()
+                    ),
+                ));
+            };
+            if !profile.env.is_empty() {
+                let base = env.get_or_insert_with(Vec::new);
+                for (var, value) in &profile.env {
+                    match base.iter_mut().find(|(k, _)| k == var) {
+                        Some(existing) => existing.1 = value.clone(),
+                        None => base.push((var.clone(), value.clone())),
+                    }
+                }
+            }
+            Some(name.as_str())
+        }
+        None => None,
+    };
+    retain_profile_steps(steps, selected);
+    Ok(())
+}
+
+fn collect_only_profiles(steps: &[StepDef], out: &mut Vec<LitStr>) {
+    for step in steps {
+        out.extend(step.only_profiles().iter().cloned());
+        if let StepDef::Group(g) = step {
+            collect_only_profiles(&g.steps, out);
+        }
+    }
+}
 
-        quote! {
-            ::rust_buildkite::PipelineStepsItem::TriggerStep(
-                ::rust_buildkite::TriggerStep::builder()
-                    .trigger(#pipeline.to_string())
-                    #label_tokens
-                    #key_tokens
-                    #depends_on_tokens
-                    #async_tokens
-                    #build_tokens
-                    #branches_tokens
-                    #if_tokens
-                    #skip_tokens
-                    #soft_fail_tokens
-                    #allow_dependency_failure_tokens
-                    .try_into()
-                    .expect("trigger step construction failed")
-            )
+fn retain_profile_steps(steps: &mut Vec<StepDef>, selected: Option<&str>) {
+    steps.retain(|step| {
+        let only = step.only_profiles();
+        only.is_empty() || selected.is_some_and(|name| only.iter().any(|p| p.value() == name))
+    });
+    for step in steps.iter_mut() {
+        if let StepDef::Group(g) = step {
+            retain_profile_steps(&mut g.steps, selected);
         }
     }
+}
 
-    fn to_group_step_tokens(&self) -> TokenStream2 {
-        let pipeline = self
-            .pipeline
-            .as_ref()
-            .expect("trigger pipeline must be set");
+/// Prepend `prefix` to every literal key in `steps` (including nested groups)
+/// and rewrite depends_on entries and `needs_artifacts` that reference one of
+/// those keys. References to keys defined outside `steps` are left untouched.
+fn apply_key_prefix(steps: &mut [StepDef], prefix: &str) -> Result<()> {
+    let mut defined = Vec::new();
+    for step in steps.iter() {
+        step.collect_keys(&mut defined);
+    }
+    let local: HashSet<String> = defined.into_iter().map(|(key, _)| key).collect();
+    prefix_keys(steps, prefix, &local)
+}
 
-        let label_tokens = if let Some(label) = &self.label {
-            quote! { .label(Some(::rust_buildkite::Label(#label.to_string()))) }
-        } else {
-            quote! {}
-        };
+fn prefix_keys(steps: &mut [StepDef], prefix: &str, local: &HashSet<String>) -> Result<()> {
+    prefix_key_literals(steps, prefix);
+    prefix_references(steps, prefix, local)
+}
 
-        let key_tokens = if let Some(key) = &self.key {
-            let key_value = key.to_tokens();
-            quote! { .key(Some(#key_value.try_into().expect("invalid key"))) }
-        } else {
-            quote! {}
-        };
+fn prefix_key_literals(steps: &mut [StepDef], prefix: &str) {
+    for step in steps {
+        if let Some(KeyValue::Literal(key, _)) = step.key_mut() {
+            *key = format!("{}{}", prefix, key);
+        }
+        if let StepDef::Group(g) = step {
+            prefix_key_literals(&mut g.steps, prefix);
+        }
+    }
+}
 
-        let depends_on_tokens = if !self.depends_on.is_empty() {
-            let deps: Vec<_> = self.depends_on.iter().map(|(d, _)| d).collect();
-            quote! {
-                .depends_on(Some(::rust_buildkite::DependsOn::DependsOnList(
-                    ::rust_buildkite::DependsOnList(vec![
-                        #(::rust_buildkite::DependsOnListItem::String(#deps.to_string())),*
-                    ])
-                )))
+/// Prepend `prefix` to the depends_on entries and `needs_artifacts` in
+/// `steps` (including nested groups) that name one of the `local` keys.
+fn prefix_references(steps: &mut [StepDef], prefix: &str, local: &HashSet<String>) -> Result<()> {
+    for step in steps {
+        for (dep, _) in step.depends_on_mut() {
+            if local.contains(dep.as_str()) {
+                *dep = format!("{}{}", prefix, dep);
             }
-        } else {
-            quote! {}
-        };
+        }
+        match step {
+            StepDef::Command(c) => {
+                // nb: each need's download is the command at its position
+                for (position, need) in c.needs_artifacts.iter_mut().enumerate() {
+                    if local.contains(need.key.as_str()) {
+                        need.key = format!("{}{}", prefix, need.key);
+                        c.commands[position] =
+                            CommandValue::from_cmd(CmdExpr::artifact_download(need)?);
+                    }
+                }
+            }
+            StepDef::Group(g) => prefix_references(&mut g.steps, prefix, local)?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
 
-        let async_tokens = if self.async_trigger {
-            quote! { .async_(Some(true)) }
-        } else {
-            quote! {}
-        };
+/// Parse `plugin_versions: { "docker-compose": "v5.5.0" }`, the versions the
+/// plugin helpers use in place of their defaults.
+fn parse_plugin_versions(input: ParseStream) -> Result<Vec<(LitStr, LitStr)>> {
+    let content;
+    braced!(content in input);
+    let mut versions: Vec<(LitStr, LitStr)> = Vec::new();
+    while !content.is_empty() {
+        let name: LitStr = content.parse()?;
+        content.parse::<Token![:]>()?;
+        let version: LitStr = content.parse()?;
+        if name.value() != compose::PLUGIN {
+            return Err(Error::new(
+                name.span(),
+                format!(
+                    "plugin_versions only pins plugins added by helpers; expected \"{}\"",
+                    compose::PLUGIN
+                ),
+            ));
+        }
+        if versions.iter().any(|(n, _)| n.value() == name.value()) {
+            return Err(Error::new(
+                name.span(),
+                format!("{} is pinned more than once", name.value()),
+            ));
+        }
+        let value = version.value();
+        if value.is_empty() || value.contains(['#', ' ', '\t', '\n']) {
+            return Err(Error::new(
+                version.span(),
+                "plugin version must be a tag, branch or commit, e.g. \"v5.5.0\"",
+            ));
+        }
+        versions.push((name, version));
+        if content.peek(Token![,]) {
+            content.parse::<Token![,]>()?;
+        }
+    }
+    Ok(versions)
+}
 
-        let build_tokens = if let Some(build) = &self.build {
-            let branch_tokens = if let Some(b) = &build.branch {
-                quote! { .branch(#b.to_string()) }
-            } else {
-                quote! {}
-            };
-            let commit_tokens = if let Some(c) = &build.commit {
-                quote! { .commit(#c.to_string()) }
-            } else {
-                quote! {}
-            };
-            let message_tokens = if let Some(m) = &build.message {
-                quote! { .message(#m.to_string()) }
-            } else {
-                quote! {}
-            };
-            let env_tokens = if !build.env.is_empty() {
-                let env_inserts: Vec<TokenStream2> = build.env.iter().map(|(k, v)| {
-                    quote! { __build_env.insert(#k.to_string(), ::rust_buildkite::serde_json::Value::String(#v.to_string())); }
-                }).collect();
-                quote! {
-                    .env({
-                        let mut __build_env = ::rust_buildkite::serde_json::Map::new();
-                        #(#env_inserts)*
-                        Some(::rust_buildkite::Env(__build_env))
-                    })
-                }
-            } else {
-                quote! {}
-            };
-            let meta_data_tokens = if !build.meta_data.is_empty() {
-                let md_inserts: Vec<TokenStream2> = build.meta_data.iter().map(|(k, v)| {
-                    quote! { __build_meta.insert(#k.to_string(), ::rust_buildkite::serde_json::Value::String(#v.to_string())); }
-                }).collect();
-                quote! {
-                    .meta_data({
-                        let mut __build_meta = ::rust_buildkite::serde_json::Map::new();
-                        #(#md_inserts)*
-                        __build_meta
-                    })
+/// Set the version of every plugin added by `.docker_compose(...)`,
+/// including those in groups.
+fn pin_helper_plugins(steps: &mut [StepDef], version: &str) {
+    for step in steps {
+        match step {
+            StepDef::Command(c) => {
+                for check in &c.compose_checks {
+                    if let Some(NestedValue::Object(pairs)) = c.plugins.get_mut(check.plugin_index)
+                        && let Some((key, _)) = pairs.first_mut()
+                    {
+                        *key = compose::plugin_key(version);
+                    }
                 }
-            } else {
-                quote! {}
-            };
-            quote! {
-                .build(Some(
-                    ::rust_buildkite::TriggerStepBuild::builder()
-                        #branch_tokens
-                        #commit_tokens
-                        #message_tokens
-                        #env_tokens
-                        #meta_data_tokens
-                        .try_into()
-                        .expect("build config construction failed")
-                ))
             }
-        } else {
-            quote! {}
-        };
+            StepDef::Group(g) => pin_helper_plugins(&mut g.steps, version),
+            _ => {}
+        }
+    }
+}
 
-        let branches_tokens = if !self.branches.is_empty() {
-            let branches = &self.branches;
-            quote! {
-                .branches(Some(::rust_buildkite::Branches::Array(vec![
-                    #(#branches.to_string()),*
-                ])))
-            }
-        } else {
-            quote! {}
-        };
+/// Env var that carries a command step's description under `description_env: true`
+const DESCRIPTION_ENV_VAR: &str = "STEP_DESCRIPTION";
 
-        let if_tokens = if let Some(condition) = &self.if_condition {
-            quote! { .if_(Some(::rust_buildkite::If(#condition.to_string()))) }
-        } else {
-            quote! {}
-        };
+/// Have every command step, including those in groups, emit its description
+/// as STEP_DESCRIPTION.
+fn enable_description_env(steps: &mut [StepDef]) {
+    for step in steps {
+        match step {
+            StepDef::Command(c) => c.description_env = true,
+            StepDef::Group(g) => enable_description_env(&mut g.steps),
+            _ => {}
+        }
+    }
+}
 
-        let skip_tokens = match &self.skip {
-            Some(SkipValue::Bool(true)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::Boolean(true))) }
-            }
-            Some(SkipValue::Bool(false)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::Boolean(false))) }
+/// Derive a key from the label of every command, trigger, block and input
+/// step that doesn't set one; unlabeled bazel steps are labeled from their
+/// verb and targets first. Derived keys inside a group carry the group's
+/// key prefix, so they share a namespace with the (already prefixed) explicit keys,
+/// and references to them by their unprefixed key are rewritten the same way.
+fn derive_auto_keys(steps: &mut [StepDef]) -> Result<()> {
+    let mut defined = Vec::new();
+    for step in steps.iter() {
+        step.collect_keys(&mut defined);
+    }
+    let explicit: HashSet<String> = defined.into_iter().map(|(key, _)| key).collect();
+    let mut derived = HashSet::new();
+    assign_auto_keys(steps, "", &explicit, &mut derived)
+}
+
+fn assign_auto_keys(
+    steps: &mut [StepDef],
+    scope: &str,
+    explicit: &HashSet<String>,
+    derived: &mut HashSet<String>,
+) -> Result<()> {
+    for step in steps {
+        if let StepDef::Group(g) = &mut *step {
+            let prefix = g.key_prefix.as_ref().map(|p| p.value()).unwrap_or_default();
+            let group_scope = format!("{}{}", scope, prefix);
+            let outer = derived.clone();
+            assign_auto_keys(&mut g.steps, &group_scope, explicit, derived)?;
+            if !prefix.is_empty() {
+                let local: HashSet<String> = derived
+                    .difference(&outer)
+                    .filter_map(|key| key.strip_prefix(&group_scope).map(str::to_string))
+                    .collect();
+                prefix_references(&mut g.steps, &group_scope, &local)?;
             }
-            Some(SkipValue::Reason(reason)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::String(#reason.parse().expect("invalid skip reason")))) }
+            continue;
+        }
+        // Unlabeled bazel steps get a label and key naming their targets
+        #[cfg(feature = "bazel")]
+        let bazel_slug = match &mut *step {
+            StepDef::Command(c) if c.label.is_none() => {
+                c.bazel_auto_label().map(|(label, slug, span)| {
+                    c.label = Some(syn::Expr::Lit(syn::ExprLit {
+                        attrs: Vec::new(),
+                        lit: syn::Lit::Str(LitStr::new(&label, span)),
+                    }));
+                    slug
+                })
             }
-            None => quote! {},
+            _ => None,
         };
-
-        let soft_fail_tokens = if self.soft_fail {
-            quote! { .soft_fail(Some(::rust_buildkite::SoftFail::Boolean(true))) }
-        } else {
-            quote! {}
+        #[cfg(not(feature = "bazel"))]
+        let bazel_slug: Option<String> = None;
+        if step.key_mut().is_some() {
+            continue;
+        }
+        let Some((label, span)) = step.auto_key_label() else {
+            continue;
         };
+        let slug = bazel_slug.unwrap_or_else(|| slugify_label(&label));
+        if slug.is_empty() {
+            continue;
+        }
+        let base = format!("{}{}", scope, slug);
+        if explicit.contains(&base) {
+            return Err(Error::new(
+                span,
+                format!(
+                    "key '{}' derived from label '{}' collides with an explicit step key. Set a key on this step",
+                    base, label
+                ),
+            ));
+        }
+        let mut key = base.clone();
+        let mut suffix = 1;
+        while derived.contains(&key) || explicit.contains(&key) {
+            suffix += 1;
+            key = format!("{}-{}", base, suffix);
+        }
+        if key != base {
+            diag::warn(
+                span,
+                format!(
+                    "key '{}' derived from label '{}' is already in use, using '{}' instead",
+                    base, label, key
+                ),
+            );
+        }
+        derived.insert(key.clone());
+        if let Some(slot) = step.key_slot() {
+            *slot = Some(KeyValue::Literal(key, span));
+        }
+    }
+    Ok(())
+}
 
-        let allow_dependency_failure_tokens = if self.allow_dependency_failure {
-            quote! { .allow_dependency_failure(Some(::rust_buildkite::AllowDependencyFailure(true))) }
-        } else {
-            quote! {}
-        };
+/// Whether `pattern` matches `path`, Buildkite style: `*` and `?` stay within
+/// one path segment and `**` spans any number of them.
+fn glob_matches(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let path: Vec<char> = path.chars().collect();
+    fn match_from(pattern: &[char], path: &[char]) -> bool {
+        match pattern {
+            [] => path.is_empty(),
+            ['*', '*', rest @ ..] => {
+                let rest = rest.strip_prefix(&['/']).unwrap_or(rest);
+                (0..=path.len()).any(|i| path.get(i..).is_some_and(|tail| match_from(rest, tail)))
+            }
+            ['*', rest @ ..] => {
+                let segment = path.iter().position(|c| *c == '/').unwrap_or(path.len());
+                (0..=segment).any(|i| path.get(i..).is_some_and(|tail| match_from(rest, tail)))
+            }
+            ['?', rest @ ..] => {
+                matches!(path.first(), Some(c) if *c != '/')
+                    && match_from(rest, path.get(1..).unwrap_or_default())
+            }
+            [c, rest @ ..] => {
+                path.first() == Some(c) && match_from(rest, path.get(1..).unwrap_or_default())
+            }
+        }
+    }
+    match_from(&pattern, &path)
+}
 
-        quote! {
-            ::rust_buildkite::GroupStepsItem::TriggerStep(
-                ::rust_buildkite::TriggerStep::builder()
-                    .trigger(#pipeline.to_string())
-                    #label_tokens
-                    #key_tokens
-                    #depends_on_tokens
-                    #async_tokens
-                    #build_tokens
-                    #branches_tokens
-                    #if_tokens
-                    #skip_tokens
-                    #soft_fail_tokens
-                    #allow_dependency_failure_tokens
-                    .try_into()
-                    .expect("trigger step construction failed")
-            )
+/// Whether an uploaded artifact glob and a downloaded one can name the same
+/// files: equal, or either one matches the other read as a path.
+fn globs_overlap(uploaded: &str, downloaded: &str) -> bool {
+    let normalize = |glob: &str| glob.trim_start_matches("./").to_string();
+    let (uploaded, downloaded) = (normalize(uploaded), normalize(downloaded));
+    uploaded == downloaded
+        || glob_matches(&uploaded, &downloaded)
+        || glob_matches(&downloaded, &uploaded)
+}
+
+/// Turn a step label into a key: emoji shortcodes such as `:rust:` are
+/// dropped, ASCII letters and digits are lowercased and every other run of
+/// characters becomes a single dash.
+fn slugify_label(label: &str) -> String {
+    let shortcode = regex::Regex::new(r":[a-zA-Z0-9_+-]+:").expect("valid shortcode regex");
+    let stripped = shortcode.replace_all(label, " ");
+
+    let mut slug = String::new();
+    for c in stripped.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
         }
     }
+    slug.trim_end_matches('-').to_string()
 }
 
-struct GroupStepDef {
-    label: Option<syn::Expr>,
-    key: Option<KeyValue>,
-    depends_on: Vec<(String, proc_macro2::Span)>,
-    steps: Vec<StepDef>,
-    if_condition: Option<LitStr>,
-    skip: Option<SkipValue>,
-    notify: Vec<NestedValue>,
-    allow_dependency_failure: bool,
+/// Validate a step key against the Buildkite schema: it must be non-empty
+/// and must not resemble a UUID.
+fn validate_step_key(key: &str) -> std::result::Result<(), String> {
+    if key.is_empty() {
+        return Err("step key must not be empty".to_string());
+    }
+    let groups: Vec<&str> = key.split('-').collect();
+    let looks_like_uuid = groups.len() == 5
+        && groups
+            .iter()
+            .zip([8, 4, 4, 4, 12])
+            .all(|(g, len)| g.len() == len && g.chars().all(|c| c.is_ascii_hexdigit()));
+    if looks_like_uuid {
+        return Err(format!(
+            "invalid step key '{}': keys must not resemble a UUID",
+            key
+        ));
+    }
+    Ok(())
 }
 
 impl GroupStepDef {
+    /// Apply the group key prefix to nested keys and their local references.
+    fn apply_key_prefix(&mut self) -> Result<()> {
+        match &self.key_prefix {
+            Some(prefix) => apply_key_prefix(&mut self.steps, &prefix.value()),
+            None => Ok(()),
+        }
+    }
+
+    /// Apply group defaults to nested command steps.
+    fn apply_defaults(&mut self) {
+        let Some(defaults) = &self.defaults else {
+            return;
+        };
+        for nested in &mut self.steps {
+            if let StepDef::Command(cmd_step) = nested {
+                defaults.apply(cmd_step);
+            }
+        }
+    }
+
+    /// Wrap the shards of a sharded `bazel_test` step in a group that takes
+    /// over the step's key, label and dependency settings, so `depends_on`
+    /// the original key waits for every shard.
+    #[cfg(feature = "bazel")]
+    fn from_shards(
+        step: CommandStepDef,
+        shard_steps: Vec<CommandStepDef>,
+        count: &syn::LitInt,
+    ) -> Result<Self> {
+        let Some((key, key_span)) = step.key.as_ref().and_then(|k| k.as_literal()) else {
+            return Err(Error::new(
+                count.span(),
+                "shards needs a literal key; shard steps are keyed `<key>-1`, `<key>-2`, ...",
+            ));
+        };
+        let key = key.to_string();
+        let label: syn::Expr = step
+            .label
+            .clone()
+            .unwrap_or_else(|| syn::parse_quote!(#key));
+        let total = shard_steps.len();
+
+        let mut group = Self::new(label.clone());
+        group.key = step.key;
+        group.depends_on = step.depends_on;
+        group.if_condition = step.if_condition;
+        group.skip = step.skip;
+        group.allow_dependency_failure = step.allow_dependency_failure;
+        group.shards = Some(count.span());
+
+        for (index, mut shard) in shard_steps.into_iter().enumerate() {
+            let n = index + 1;
+            shard.key = Some(KeyValue::Literal(format!("{}-{}", key, n), key_span));
+            shard.label = Some(match &label {
+                syn::Expr::Lit(syn::ExprLit {
+                    lit: syn::Lit::Str(lit),
+                    ..
+                }) => {
+                    let text = format!("{} ({}/{})", lit.value(), n, total);
+                    syn::parse_quote!(#text)
+                }
+                other => syn::parse_quote!(format!("{} ({}/{})", #other, #n, #total)),
+            });
+            shard.depends_on.clear();
+            shard.if_condition = None;
+            shard.skip = None;
+            shard.allow_dependency_failure = false;
+            group.steps.push(StepDef::Command(shard));
+        }
+        Ok(group)
+    }
+
+    /// Sharded `bazel_test` steps expand into a group, and groups can't nest.
+    fn reject_sharded_steps(&self) -> Result<()> {
+        for nested in &self.steps {
+            if let StepDef::Group(inner) = nested
+                && let Some(span) = inner.shards
+            {
+                return Err(Error::new(
+                    span,
+                    "a sharded bazel_test expands into a group and can't be nested inside another group; move it to the top level",
+                ));
+            }
+        }
+        Ok(())
+    }
+
     fn new(label: syn::Expr) -> Self {
         Self {
             label: Some(label),
+            label_origin: None,
             key: None,
+            description: None,
             depends_on: Vec::new(),
             steps: Vec::new(),
             if_condition: None,
             skip: None,
             notify: Vec::new(),
             allow_dependency_failure: false,
+            defaults: None,
+            key_prefix: None,
+            shards: None,
+            only_profiles: Vec::new(),
         }
     }
 
     fn new_empty() -> Self {
         Self {
             label: None,
+            label_origin: None,
             key: None,
+            description: None,
             depends_on: Vec::new(),
             steps: Vec::new(),
             if_condition: None,
             skip: None,
             notify: Vec::new(),
             allow_dependency_failure: false,
+            defaults: None,
+            key_prefix: None,
+            shards: None,
+            only_profiles: Vec::new(),
         }
     }
 
-    fn to_tokens_inner(&self) -> TokenStream2 {
+    /// Expand this group with pipeline default_plugins applied to its
+    /// nested command steps.
+    fn expand(&self, default_plugins: &[NestedValue]) -> yaml::Expanded {
         let label = self.label.as_ref().expect("group label must be set");
+        let mut fields = yaml::Fields::default();
+        fields.set(
+            "group",
+            yaml::label(label, "the group label"),
+            quote! { .group(Some(#label.to_string())) },
+        );
 
-        let key_tokens = if let Some(key) = &self.key {
+        if let Some(key) = &self.key {
             let key_value = key.to_tokens();
-            quote! { .key(Some(#key_value.try_into().expect("invalid key"))) }
-        } else {
-            quote! {}
-        };
+            fields.set(
+                "key",
+                yaml::key(key),
+                quote! { .key(Some(__rbk_built!(#key_value.try_into().into_build_result(__rbk_step, "invalid key")))) },
+            );
+        }
 
-        let depends_on_tokens = if !self.depends_on.is_empty() {
+        if !self.depends_on.is_empty() {
             let deps: Vec<_> = self.depends_on.iter().map(|(d, _)| d).collect();
-            quote! {
-                .depends_on(Some(::rust_buildkite::DependsOn::DependsOnList(
-                    ::rust_buildkite::DependsOnList(vec![
-                        #(::rust_buildkite::DependsOnListItem::String(#deps.to_string())),*
-                    ])
-                )))
-            }
-        } else {
-            quote! {}
-        };
+            fields.set(
+                "depends_on",
+                Ok(yaml::depends_on(&self.depends_on)),
+                quote! {
+                    .depends_on(Some(::rust_buildkite::helpers::string_deps(&[#(#deps),*])))
+                },
+            );
+        }
 
-        let nested_steps: Vec<TokenStream2> = self
-            .steps
-            .iter()
-            .map(|s| s.to_group_step_tokens())
-            .collect();
-        let steps_tokens = quote! {
-            .steps(::rust_buildkite::GroupSteps(vec![
-                #(#nested_steps),*
-            ]))
-        };
+        if let Some(condition) = &self.if_condition {
+            fields.set(
+                "if",
+                Ok(yaml::string(&condition.value())),
+                quote! { .if_(Some(::rust_buildkite::If(#condition.to_string()))) },
+            );
+        }
 
-        let if_tokens = if let Some(condition) = &self.if_condition {
-            quote! { .if_(Some(::rust_buildkite::If(#condition.to_string()))) }
-        } else {
-            quote! {}
-        };
+        if let Some(skip) = &self.skip {
+            fields.set("skip", Ok(yaml::skip(skip)), skip.to_tokens());
+        }
 
-        let skip_tokens = match &self.skip {
-            Some(SkipValue::Bool(true)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::Boolean(true))) }
-            }
-            Some(SkipValue::Bool(false)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::Boolean(false))) }
-            }
-            Some(SkipValue::Reason(reason)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::String(#reason.parse().expect("invalid skip reason")))) }
-            }
-            None => quote! {},
-        };
+        if !self.notify.is_empty() {
+            let items: Vec<TokenStream2> = self.notify.iter().map(|n| n.to_tokens()).collect();
+            fields.set(
+                "notify",
+                Ok(yaml::Value::Sequence(
+                    self.notify.iter().map(NotifyValue::to_value).collect(),
+                )),
+                quote! {
+                    .notify(Some(::rust_buildkite::BuildNotify(vec![#(#items),*])))
+                },
+            );
+        }
 
-        let notify_tokens = if !self.notify.is_empty() {
-            let notify_values: Vec<TokenStream2> =
-                self.notify.iter().map(|n| n.to_json_tokens()).collect();
-            quote! {
-                .notify({
-                    let __notify_array = vec![#(#notify_values),*];
-                    Some(::rust_buildkite::BuildNotify(__notify_array.into_iter().map(|v| {
-                        ::rust_buildkite::serde_json::from_value(v).expect("invalid notify")
-                    }).collect()))
-                })
-            }
-        } else {
-            quote! {}
-        };
+        if self.allow_dependency_failure {
+            fields.set(
+                "allow_dependency_failure",
+                Ok(yaml::Value::Bool(true)),
+                quote! { .allow_dependency_failure(Some(::rust_buildkite::AllowDependencyFailure(true))) },
+            );
+        }
 
-        let allow_dependency_failure_tokens = if self.allow_dependency_failure {
-            quote! { .allow_dependency_failure(Some(::rust_buildkite::AllowDependencyFailure(true))) }
-        } else {
-            quote! {}
-        };
+        let (nested_steps, steps_value) = yaml::sequence(
+            self.steps
+                .iter()
+                .map(|s| s.expand(StepTarget::Group, default_plugins)),
+        );
+        fields.set(
+            "steps",
+            steps_value,
+            quote! {
+                .steps(::rust_buildkite::GroupSteps(vec![
+                    #(#nested_steps),*
+                ]))
+            },
+        );
 
-        quote! {
+        let (calls, value) = fields.finish();
+        let tokens = quote! {
             ::rust_buildkite::PipelineStepsItem::GroupStep(
-                ::rust_buildkite::GroupStep::builder()
-                    .group(Some(#label.to_string()))
-                    #key_tokens
-                    #depends_on_tokens
-                    #steps_tokens
-                    #if_tokens
-                    #skip_tokens
-                    #notify_tokens
-                    #allow_dependency_failure_tokens
-                    .try_into()
-                    .expect("group step construction failed")
+                __rbk_built!(
+                    ::rust_buildkite::GroupStep::builder()
+                        #calls
+                        .try_into()
+                        .into_build_result(__rbk_step, "construction failed")
+                )
             )
+        };
+        yaml::Expanded { tokens, value }
+    }
+}
+
+/// A cmd! string split into its `{name}` placeholders, which `format!` fills
+/// in at runtime from identifiers in scope. `{{` and `}}` are literal braces,
+/// and braces after `$` (`${VAR}`) or around anything but an identifier are
+/// left to the shell.
+#[derive(Clone)]
+struct CmdTemplate {
+    /// Placeholder names with their byte ranges, braces included
+    placeholders: Vec<(String, std::ops::Range<usize>)>,
+    /// The command bashrs lints: placeholders replaced by same-length dummy
+    /// words and escapes collapsed
+    lint_text: String,
+    /// Byte offset in the template of each byte of `lint_text`, plus its end
+    offsets: Vec<usize>,
+    /// The command as a `format!` string
+    format_string: String,
+    /// Whether the template uses `{{` or `}}`
+    escaped: bool,
+}
+
+impl CmdTemplate {
+    fn parse(command: &str) -> Self {
+        let mut template = CmdTemplate {
+            placeholders: Vec::new(),
+            lint_text: String::with_capacity(command.len()),
+            offsets: Vec::with_capacity(command.len() + 1),
+            format_string: String::with_capacity(command.len()),
+            escaped: false,
+        };
+        let mut i = 0;
+        while i < command.len() {
+            let rest = &command[i..];
+            if rest.starts_with("{{") || rest.starts_with("}}") {
+                template.lint_text.push_str(&rest[..1]);
+                template.offsets.push(i);
+                template.format_string.push_str(&rest[..2]);
+                template.escaped = true;
+                i += 2;
+                continue;
+            }
+            if rest.starts_with('{')
+                && !command[..i].ends_with('$')
+                && let Some(end) = rest.find('}')
+                && is_format_ident(&rest[1..end])
+            {
+                let len = end + 1;
+                template
+                    .placeholders
+                    .push((rest[1..end].to_string(), i..i + len));
+                template.lint_text.push_str(&"x".repeat(len));
+                template.offsets.extend(i..i + len);
+                template.format_string.push_str(&rest[..len]);
+                i += len;
+                continue;
+            }
+            let c = rest.chars().next().unwrap_or_default();
+            template.lint_text.push(c);
+            template.offsets.extend(i..i + c.len_utf8());
+            template.format_string.push(c);
+            if c == '{' || c == '}' {
+                template.format_string.push(c);
+            }
+            i += c.len_utf8();
         }
+        template.offsets.push(command.len());
+        template
     }
 
-    /// Generate tokens with default plugins applied to nested steps
-    fn to_tokens_with_default_plugins(&self, default_plugins: &[NestedValue]) -> TokenStream2 {
-        let label = self.label.as_ref().expect("group label must be set");
+    /// Map a byte range in `lint_text` back to the template.
+    fn template_range(&self, range: std::ops::Range<usize>) -> Option<std::ops::Range<usize>> {
+        Some(*self.offsets.get(range.start)?..*self.offsets.get(range.end)?)
+    }
+}
 
-        let key_tokens = if let Some(key) = &self.key {
-            let key_value = key.to_tokens();
-            quote! { .key(Some(#key_value.try_into().expect("invalid key"))) }
-        } else {
-            quote! {}
-        };
+/// The program run by commands generated for `set_metadata`, `metadata` and
+/// `needs_artifacts`.
+const AGENT_COMMAND: &str = "buildkite-agent";
 
-        let depends_on_tokens = if !self.depends_on.is_empty() {
-            let deps: Vec<_> = self.depends_on.iter().map(|(d, _)| d).collect();
-            quote! {
-                .depends_on(Some(::rust_buildkite::DependsOn::DependsOnList(
-                    ::rust_buildkite::DependsOnList(vec![
-                        #(::rust_buildkite::DependsOnListItem::String(#deps.to_string())),*
-                    ])
-                )))
-            }
-        } else {
-            quote! {}
-        };
+/// Placeholder a runtime meta-data value is bound to.
+const METADATA_VALUE_ARG: &str = "__rbk_metadata_value";
 
-        let nested_steps: Vec<TokenStream2> = self
-            .steps
-            .iter()
-            .map(|s| s.to_group_step_tokens_with_default_plugins(default_plugins))
-            .collect();
-        let steps_tokens = quote! {
-            .steps(::rust_buildkite::GroupSteps(vec![
-                #(#nested_steps),*
-            ]))
-        };
+/// Shell-quote a word for a generated `cmd!` template, doubling braces so
+/// they aren't read as placeholders.
+fn quote_word(word: &str) -> String {
+    rust_buildkite_validation::shell_quote(word)
+        .replace('{', "{{")
+        .replace('}', "}}")
+}
+
+/// Whether `name` can be captured by a `format!` string: a plain identifier.
+fn is_format_ident(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && name != "_"
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Environment variable capping the threads that lint commands; `1` lints
+/// them one after another on the expansion thread
+const LINT_THREADS_ENV: &str = "RUST_BUILDKITE_LINT_THREADS";
+
+/// bashrs recurses on nested shell constructs, so lint threads get a stack
+/// closer to the compiler's own than the 2 MiB default
+const LINT_STACK_SIZE: usize = 16 * 1024 * 1024;
+
+fn lint_threads() -> usize {
+    std::env::var(LINT_THREADS_ENV)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+}
 
-        let if_tokens = if let Some(condition) = &self.if_condition {
-            quote! { .if_(Some(::rust_buildkite::If(#condition.to_string()))) }
-        } else {
-            quote! {}
-        };
+/// Lint every command that is still waiting for bashrs.
+///
+/// Commands are independent, so a pipeline with many of them is linted on
+/// several threads. Errors are sorted by source position before they are
+/// combined, so diagnostics don't depend on which thread finished first.
+fn lint_commands(commands: &mut [&mut CmdExpr]) -> Result<()> {
+    lint_commands_on(commands, lint_threads())
+}
 
-        let skip_tokens = match &self.skip {
-            Some(SkipValue::Bool(true)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::Boolean(true))) }
-            }
-            Some(SkipValue::Bool(false)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::Boolean(false))) }
-            }
-            Some(SkipValue::Reason(reason)) => {
-                quote! { .skip(Some(::rust_buildkite::Skip::String(#reason.parse().expect("invalid skip reason")))) }
-            }
-            None => quote! {},
-        };
+fn lint_commands_on(commands: &mut [&mut CmdExpr], threads: usize) -> Result<()> {
+    let outcomes = {
+        let texts: Vec<Option<(&str, StepShell)>> =
+            commands.iter().map(|c| c.pending_lint()).collect();
+        lint_texts(&texts, threads)
+    };
+    let mut errors = Vec::new();
+    for (cmd, outcome) in commands.iter_mut().zip(outcomes) {
+        if let Some((outcome, elapsed)) = outcome
+            && let Err(err) = cmd.finish_lint(outcome, elapsed)
+        {
+            errors.push(err);
+        }
+    }
+    errors.sort_by_key(|err| {
+        let start = err.span().start();
+        (start.line, start.column)
+    });
+    combine_errors(errors).map_or(Ok(()), Err)
+}
 
-        let notify_tokens = if !self.notify.is_empty() {
-            let notify_values: Vec<TokenStream2> =
-                self.notify.iter().map(|n| n.to_json_tokens()).collect();
-            quote! {
-                .notify({
-                    let __notify_array = vec![#(#notify_values),*];
-                    Some(::rust_buildkite::BuildNotify(__notify_array.into_iter().map(|v| {
-                        ::rust_buildkite::serde_json::from_value(v).expect("invalid notify")
-                    }).collect()))
-                })
-            }
-        } else {
-            quote! {}
-        };
+/// bashrs outcome and lint time for each text, in the same order.
+fn lint_texts(
+    texts: &[Option<(&str, StepShell)>],
+    threads: usize,
+) -> Vec<Option<(BashLintOutcome, std::time::Duration)>> {
+    fn lint((text, shell): (&str, StepShell)) -> (BashLintOutcome, std::time::Duration) {
+        let start = std::time::Instant::now();
+        let outcome = CmdExpr::lint_with_bashrs(text, shell);
+        (outcome, start.elapsed())
+    }
 
-        let allow_dependency_failure_tokens = if self.allow_dependency_failure {
-            quote! { .allow_dependency_failure(Some(::rust_buildkite::AllowDependencyFailure(true))) }
-        } else {
-            quote! {}
-        };
+    let pending = texts.iter().flatten().count();
+    let threads = threads.min(pending);
+    if threads <= 1 {
+        return texts.iter().map(|text| text.map(lint)).collect();
+    }
 
-        quote! {
-            ::rust_buildkite::PipelineStepsItem::GroupStep(
-                ::rust_buildkite::GroupStep::builder()
-                    .group(Some(#label.to_string()))
-                    #key_tokens
-                    #depends_on_tokens
-                    #steps_tokens
-                    #if_tokens
-                    #skip_tokens
-                    #notify_tokens
-                    #allow_dependency_failure_tokens
-                    .try_into()
-                    .expect("group step construction failed")
-            )
+    let next = &AtomicUsize::new(0);
+    let mut results: Vec<Option<(BashLintOutcome, std::time::Duration)>> =
+        texts.iter().map(|_| None).collect();
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .filter_map(|_| {
+                std::thread::Builder::new()
+                    .stack_size(LINT_STACK_SIZE)
+                    .spawn_scoped(scope, move || {
+                        let mut done = Vec::new();
+                        loop {
+                            let i = next.fetch_add(1, Ordering::Relaxed);
+                            let Some(text) = texts.get(i) else {
+                                return done;
+                            };
+                            if let Some(text) = text {
+                                done.push((i, lint(*text)));
+                            }
+                        }
+                    })
+                    .ok()
+            })
+            .collect();
+        for worker in workers {
+            let done = worker
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+            for (i, outcome) in done {
+                if let Some(slot) = results.get_mut(i) {
+                    *slot = Some(outcome);
+                }
+            }
+        }
+    });
+    // nb: if no thread could be spawned, nothing was linted above
+    for (slot, text) in results.iter_mut().zip(texts) {
+        if slot.is_none()
+            && let Some(text) = text
+        {
+            *slot = Some(lint(*text));
         }
     }
+    results
 }
 
 /// Represents a parsed command from a string literal.
@@ -6979,37 +10597,357 @@ struct CmdExpr {
     undefined_vars: Vec<String>,
     /// Span for error reporting
     span: proc_macro2::Span,
+    /// The source literal, for pointing at positions inside the command
+    literal: proc_macro2::Literal,
+    /// Written with `unchecked_cmd!`: not linted by bashrs, and only allowed in
+    /// pipelines that set `allow_unchecked_commands: true`
+    unchecked: bool,
+    /// Names of `{name}` placeholders filled in at runtime
+    placeholders: Vec<String>,
+    /// The command as a `format!` string, when it has placeholders or `{{`/`}}`
+    format_string: Option<String>,
+    /// Placeholders a helper binds to runtime values, which are shell-quoted
+    /// when the command is built
+    bound_args: Vec<(Ident, TokenStream2)>,
+    /// Generated by `set_metadata`, `metadata` or `needs_artifacts`: its
+    /// `buildkite-agent` call is allowed even when allowed_commands leaves it out
+    helper: bool,
+    /// Time spent in bashrs, for the `RUST_BUILDKITE_PROFILE` report
+    lint_time: std::time::Duration,
+    /// Shell bashrs lints the command for, from the step's `shell`
+    shell: StepShell,
+    /// Template still waiting for bashrs; `None` once linted, or for
+    /// `unchecked_cmd!`
+    unlinted: Option<CmdTemplate>,
+    /// ShellCheck codes from `cmd!("...", allow: [...])`, which bashrs may
+    /// report for this command without failing it
+    allow: Vec<String>,
 }
 
 impl CmdExpr {
     /// Parse a command from a string literal and validate with bashrs.
     /// Path existence is validated separately at pipeline level with expect_paths context.
     fn from_lit_str(lit: &LitStr) -> Result<Self> {
+        let mut cmd = Self::parse_lit_str(lit)?;
+        lint_commands(&mut [&mut cmd])?;
+        Ok(cmd)
+    }
+
+    /// Parse a command from a string literal, leaving the bashrs lint for
+    /// [`lint_commands`] so a pipeline's commands can be linted together.
+    ///
+    /// `{name}` placeholders are linted as plain words and filled in at runtime;
+    /// one in the command name is rejected, since the allowlist can't check it.
+    fn parse_lit_str(lit: &LitStr) -> Result<Self> {
         let command = lit.value();
         let span = lit.span();
+        let literal = lit.token();
+        let template = CmdTemplate::parse(&command);
+
+        let name_start = command.len() - command.trim_start().len();
+        let name_end = command[name_start..]
+            .find(char::is_whitespace)
+            .map_or(command.len(), |i| name_start + i);
+        if let Some((name, range)) = template
+            .placeholders
+            .iter()
+            .find(|(_, range)| range.start < name_end)
+        {
+            let span = literal_subspan(&literal, &command, range.clone()).unwrap_or(span);
+            return Err(Error::new(
+                span,
+                format!(
+                    "placeholder {{{}}} can't be used as the command name: commands are checked \
+                     against allowed_commands at compile time, so the name must be written out",
+                    name
+                ),
+            ));
+        }
 
-        let undefined_vars = match Self::validate_with_bashrs(&command) {
-            Ok(vars) => vars,
-            Err(e) => return Err(Error::new(span, e)),
+        let command_name = Self::extract_command_name(&template.lint_text);
+        let format_string = (template.escaped || !template.placeholders.is_empty())
+            .then(|| template.format_string.clone());
+
+        Ok(CmdExpr {
+            command,
+            command_name,
+            undefined_vars: Vec::new(),
+            span,
+            literal,
+            unchecked: false,
+            placeholders: template
+                .placeholders
+                .iter()
+                .map(|(name, _)| name.clone())
+                .collect(),
+            format_string,
+            bound_args: Vec::new(),
+            helper: false,
+            lint_time: std::time::Duration::ZERO,
+            shell: StepShell::Bash,
+            unlinted: Some(template),
+            allow: Vec::new(),
+        })
+    }
+
+    /// Parse what follows the literal in `cmd!("...", allow: ["SC2002"])`:
+    /// ShellCheck codes bashrs may report for this command without failing
+    /// it, or listing it under the shell_info lint.
+    fn parse_allow(&mut self, input: ParseStream) -> Result<()> {
+        if input.is_empty() {
+            return Ok(());
+        }
+        input.parse::<Token![,]>()?;
+        if input.is_empty() {
+            return Ok(());
+        }
+        let option: Ident = input.parse()?;
+        if option != "allow" {
+            return Err(Error::new(
+                option.span(),
+                format!("unknown cmd! option: {}. Expected: allow", option),
+            ));
+        }
+        input.parse::<Token![:]>()?;
+        for (code, span) in parse_dep_list(input)? {
+            let digits = code.strip_prefix("SC").unwrap_or_default();
+            if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(Error::new(
+                    span,
+                    format!(
+                        "allow expects ShellCheck codes such as \"SC2002\", got {:?}",
+                        code
+                    ),
+                ));
+            }
+            self.allow.push(code);
+        }
+        if input.peek(Token![,]) {
+            input.parse::<Token![,]>()?;
+        }
+        Ok(())
+    }
+
+    /// The text bashrs still has to lint and the shell to lint it for, if
+    /// the command hasn't been linted.
+    fn pending_lint(&self) -> Option<(&str, StepShell)> {
+        self.unlinted
+            .as_ref()
+            .map(|t| (t.lint_text.as_str(), self.shell))
+    }
+
+    /// Record the bashrs outcome for [`pending_lint`](Self::pending_lint),
+    /// pointing a lint error at the first issue in the literal that the
+    /// command's `allow:` list doesn't name.
+    fn finish_lint(
+        &mut self,
+        outcome: BashLintOutcome,
+        elapsed: std::time::Duration,
+    ) -> Result<()> {
+        let Some(template) = self.unlinted.take() else {
+            return Ok(());
         };
+        self.lint_time = elapsed;
+        match outcome.check(&self.allow) {
+            Ok(vars) => {
+                self.undefined_vars = vars;
+                Ok(())
+            }
+            Err((e, range)) => {
+                let span = range
+                    .and_then(|r| template.template_range(r))
+                    .and_then(|r| literal_subspan(&self.literal, &self.command, r))
+                    .unwrap_or(self.span);
+                if self.shell == StepShell::Sh {
+                    return Err(Error::new(
+                        span,
+                        format!(
+                            "{}\nlinted as POSIX sh because of `shell: \"sh\"`, which only changes \
+                             validation; the agent's shell setting decides what runs the command",
+                            e
+                        ),
+                    ));
+                }
+                Err(Error::new(span, e))
+            }
+        }
+    }
+
+    /// Build the `buildkite-agent meta-data set` command for `set_metadata`
+    /// and `metadata`. A literal value is quoted now; any other value is bound
+    /// to a placeholder and quoted when the pipeline is built.
+    fn metadata_set(key: &LitStr, value: &DynamicValue) -> Result<Self> {
+        if key.value().is_empty() {
+            return Err(Error::new(key.span(), "meta-data key must not be empty"));
+        }
+        let quoted_value = match value {
+            DynamicValue::Literal(text) | DynamicValue::Computed(text, _) => quote_word(text),
+            _ => format!("{{{}}}", METADATA_VALUE_ARG),
+        };
+        let mut cmd = Self::agent_command(
+            &format!(
+                "meta-data set {} {}",
+                quote_word(&key.value()),
+                quoted_value
+            ),
+            key.span(),
+        )?;
+        if value.as_literal().is_none() {
+            let ident = Ident::new(METADATA_VALUE_ARG, key.span());
+            cmd.bound_args.push((ident, value.to_tokens()));
+        }
+        Ok(cmd)
+    }
+
+    /// Build the `buildkite-agent artifact download` command for
+    /// `needs_artifacts`.
+    fn artifact_download(need: &ArtifactNeed) -> Result<Self> {
+        Self::agent_command(
+            &format!(
+                "artifact download {} . --step {}",
+                quote_word(&need.glob),
+                quote_word(&need.key)
+            ),
+            need.span,
+        )
+    }
 
+    /// A generated `buildkite-agent` call, linted like any other command.
+    /// `args` is a `cmd!` template: literal braces must already be doubled.
+    fn agent_command(args: &str, span: proc_macro2::Span) -> Result<Self> {
+        let template = LitStr::new(&format!("{} {}", AGENT_COMMAND, args), span);
+        let mut cmd = Self::from_lit_str(&template)?;
+        cmd.helper = true;
+        Ok(cmd)
+    }
+
+    /// Parse an `unchecked_cmd!` command. Only the command name is kept for
+    /// the allowlist; bashrs never sees the command, so env vars it references
+    /// aren't validated either.
+    fn unchecked_from_lit_str(lit: &LitStr) -> Result<Self> {
+        let command = lit.value();
+        if command.trim().is_empty() {
+            return Err(Error::new(
+                lit.span(),
+                "unchecked_cmd! requires a non-empty command",
+            ));
+        }
         let command_name = Self::extract_command_name(&command);
 
         Ok(CmdExpr {
             command,
             command_name,
-            undefined_vars,
-            span,
+            undefined_vars: Vec::new(),
+            span: lit.span(),
+            literal: lit.token(),
+            unchecked: true,
+            placeholders: Vec::new(),
+            format_string: None,
+            bound_args: Vec::new(),
+            helper: false,
+            lint_time: std::time::Duration::ZERO,
+            shell: StepShell::Bash,
+            unlinted: None,
+            allow: Vec::new(),
         })
     }
 
+    /// Span of a byte range within the command, falling back to the whole literal.
+    fn span_of(&self, range: std::ops::Range<usize>) -> proc_macro2::Span {
+        literal_subspan(&self.literal, &self.command, range).unwrap_or(self.span)
+    }
+
+    /// Span and caret snippet for the command name, used by allowlist errors.
+    fn locate_command_name(&self) -> (proc_macro2::Span, String) {
+        let start = self.command.find(&self.command_name).unwrap_or(0);
+        let range = start..start + self.command_name.len();
+        (
+            self.span_of(range.clone()),
+            caret_snippet(&self.command, range),
+        )
+    }
+
+    /// Span and caret snippet for the first reference to `$var` in the command.
+    fn locate_var(&self, var: &str) -> (proc_macro2::Span, String) {
+        match find_var_reference(&self.command, var) {
+            Some(range) => (
+                self.span_of(range.clone()),
+                caret_snippet(&self.command, range),
+            ),
+            None => (self.span, String::new()),
+        }
+    }
+
     /// Validate the command string using bashrs linter.
     /// Returns Ok with list of undefined vars (SC2154), or Err for other issues.
     /// Undefined vars are passed to pipeline-level validation against env/expect_env.
     fn validate_with_bashrs(command: &str) -> std::result::Result<Vec<String>, String> {
+        Self::lint_with_bashrs(command, StepShell::Bash)
+            .check(&[])
+            .map_err(|(msg, _)| msg)
+    }
+
+    /// Like validate_with_bashrs, but keeps each issue with its code and byte
+    /// range within `command`, so a command's `allow:` list can drop some and
+    /// the error can point at the first one left.
+    ///
+    /// Results are memoized by command and shell for the life of the
+    /// compiler process; generated pipelines often repeat the same command
+    /// many times and bashrs parsing dominates expansion time.
+    fn lint_with_bashrs(command: &str, shell: StepShell) -> BashLintOutcome {
+        let key = (command.to_string(), shell);
+        if let Some(cached) = BASH_LINT_CACHE
+            .lock()
+            .ok()
+            .and_then(|cache| cache.as_ref()?.get(&key).cloned())
+        {
+            return cached;
+        }
+        let outcome = Self::run_bashrs(command, shell);
+        if let Ok(mut cache) = BASH_LINT_CACHE.lock() {
+            cache
+                .get_or_insert_with(HashMap::new)
+                .insert(key, outcome.clone());
+        }
+        outcome
+    }
+
+    /// bashrs diagnostics below warning level, such as a useless `cat`, each
+    /// with the span it points at, leaving out codes in the command's `allow:`
+    /// list. Unlike errors and warnings these aren't cached, since
+    /// they're only wanted when the shell_info lint is enabled.
+    fn info_diagnostics(&self) -> Vec<(proc_macro2::Span, String)> {
+        use bashrs::linter::{Severity, lint_shell};
+
+        if self.unchecked || self.helper {
+            return Vec::new();
+        }
+        let template = CmdTemplate::parse(&self.command);
+        let command = &template.lint_text;
+        let result = lint_shell(&format!("{}\n{}", self.shell.shebang(), command));
+        result
+            .diagnostics
+            .iter()
+            .filter(|d| d.severity != Severity::Error && d.severity != Severity::Warning)
+            .filter(|d| !self.allow.iter().any(|code| *code == d.code))
+            .map(|d| {
+                let span = diagnostic_range(
+                    command,
+                    (d.span.start_line, d.span.start_col),
+                    (d.span.end_line, d.span.end_col),
+                )
+                .and_then(|r| template.template_range(r))
+                .and_then(|r| literal_subspan(&self.literal, &self.command, r))
+                .unwrap_or(self.span);
+                (span, format!("{}: {}", d.code, d.message))
+            })
+            .collect()
+    }
+
+    fn run_bashrs(command: &str, shell: StepShell) -> BashLintOutcome {
         use bashrs::linter::{Severity, lint_shell};
 
-        let script = format!("#!/bin/bash\n{}", command);
+        let script = format!("{}\n{}", shell.shebang(), command);
         let result = lint_shell(&script);
         let undefined_vars: Vec<String> = result
             .diagnostics
@@ -7026,24 +10964,35 @@ impl CmdExpr {
             })
             .collect();
 
-        let issues: Vec<_> = result
+        let issues = result
             .diagnostics
             .iter()
             .filter(|d| {
                 (d.severity == Severity::Error || d.severity == Severity::Warning)
                     && d.code != "SC2154"
             })
+            .map(|d| {
+                let range = diagnostic_range(
+                    command,
+                    (d.span.start_line, d.span.start_col),
+                    (d.span.end_line, d.span.end_col),
+                );
+                let snippet = range
+                    .clone()
+                    .map(|r| caret_snippet(command, r))
+                    .unwrap_or_default();
+                ShellIssue {
+                    code: d.code.to_string(),
+                    message: format!("  [{}] {}{}", d.code, d.message, snippet),
+                    range,
+                }
+            })
             .collect();
 
-        if !issues.is_empty() {
-            let error_msgs: Vec<String> = issues
-                .iter()
-                .map(|d| format!("  [{}] {}", d.code, d.message))
-                .collect();
-            return Err(format!("Shell lint issues:\n{}", error_msgs.join("\n")));
+        BashLintOutcome {
+            undefined_vars,
+            issues,
         }
-
-        Ok(undefined_vars)
     }
 
     /// Extract the command name (first word) from a shell command.
@@ -7053,40 +11002,23 @@ impl CmdExpr {
 
     /// Check if the command exists on the filesystem (for path-based commands).
     /// Returns Ok(()) if valid, Err with message if path doesn't exist.
-    /// For relative paths (./foo), resolves against the workspace root detected from
-    /// RUST_SCRIPT_BASE_PATH or CARGO_MANIFEST_DIR, not the current working directory.
+    /// For relative paths (./foo), resolves against `base` when the pipeline sets
+    /// path_base, otherwise the workspace root detected from RUST_SCRIPT_BASE_PATH
+    /// or CARGO_MANIFEST_DIR, not the current working directory.
     /// This handles the case where rust-script compiles from a cache directory.
     fn validate_path_exists(
         command_name: &str,
         allow_missing: &[&str],
+        base: Option<&std::path::Path>,
     ) -> std::result::Result<(), String> {
-        use std::path::PathBuf;
-
         if allow_missing.contains(&command_name) {
             return Ok(());
         }
 
         let is_relative = command_name.starts_with("./") || command_name.contains('/');
         if command_name.starts_with('/') || is_relative {
-            let path: PathBuf = if command_name.starts_with('/') {
-                PathBuf::from(command_name)
-            } else {
-                let rel = command_name.strip_prefix("./").unwrap_or(command_name);
-                #[cfg(feature = "bazel")]
-                {
-                    if let Ok(workspace) = crate::bazel::find_bazel_workspace_from_env() {
-                        workspace.join(rel)
-                    } else {
-                        PathBuf::from(command_name)
-                    }
-                }
-                #[cfg(not(feature = "bazel"))]
-                {
-                    let _ = rel;
-                    PathBuf::from(command_name)
-                }
-            };
-
+            let path = Self::resolve_path(command_name, base);
+            crate::track::track_file(&path);
             if !path.exists() {
                 return Err(format!(
                     "Command path '{}' does not exist on the build machine.\n\
@@ -7111,21 +11043,106 @@ impl CmdExpr {
         Ok(())
     }
 
+    /// Resolve a path written in the pipeline the way [`Self::validate_path_exists`]
+    /// does: absolute paths as-is, relative ones against `base` or the workspace root.
+    fn resolve_path(path: &str, base: Option<&std::path::Path>) -> std::path::PathBuf {
+        use std::path::PathBuf;
+
+        if path.starts_with('/') {
+            return PathBuf::from(path);
+        }
+        let rel = path.strip_prefix("./").unwrap_or(path);
+        if let Some(base) = base {
+            return base.join(rel);
+        }
+        #[cfg(feature = "bazel")]
+        if let Ok(workspace) = crate::bazel::find_bazel_workspace_from_env() {
+            return workspace.join(rel);
+        }
+        PathBuf::from(path)
+    }
+
+    /// Check that a local plugin (`./path` or `../path`) is a directory with a
+    /// plugin.yml, resolving it like a command path. Errors name the absolute
+    /// path that was checked.
+    fn validate_plugin_path(
+        plugin: &str,
+        allow_missing: &[&str],
+        base: Option<&std::path::Path>,
+    ) -> std::result::Result<(), String> {
+        if allow_missing.contains(&plugin) {
+            return Ok(());
+        }
+        let dir = Self::resolve_path(plugin, base);
+        let dir = std::path::absolute(&dir).unwrap_or(dir);
+        let manifest = dir.join("plugin.yml");
+        crate::track::track_file(&manifest);
+        if manifest.is_file() {
+            return Ok(());
+        }
+        let problem = if dir.is_dir() {
+            format!("has no plugin.yml (checked {})", manifest.display())
+        } else {
+            format!("does not exist (checked {})", dir.display())
+        };
+        Err(format!(
+            "Local plugin '{}' {}.\n\
+             If this plugin will exist at runtime, add it to expect_paths.",
+            plugin, problem
+        ))
+    }
+
     /// Generate code that produces the command string with runtime validation.
     fn to_tokens(&self) -> TokenStream2 {
-        let cmd = &self.command;
+        let cmd = self.text_tokens();
         let cmd_name = &self.command_name;
 
         if cmd_name.starts_with('/') || cmd_name.starts_with("./") || cmd_name.contains('/') {
             quote! {
                 {
                     ::rust_buildkite::validation::validate_path(#cmd_name);
-                    #cmd.to_string()
+                    #cmd
                 }
             }
         } else {
-            quote! { #cmd.to_string() }
+            cmd
+        }
+    }
+
+    /// Code producing the command string: the literal itself, or a `format!`
+    /// call filling in placeholders from identifiers at the call site.
+    fn text_tokens(&self) -> TokenStream2 {
+        match &self.format_string {
+            Some(format_string) => {
+                let format_string = LitStr::new(format_string, self.span);
+                let bindings = self.bound_args.iter().map(|(ident, value)| {
+                    quote! {
+                        let #ident = ::rust_buildkite::validation::shell_quote(&(#value).to_string());
+                    }
+                });
+                quote! {
+                    {
+                        #(#bindings)*
+                        format!(#format_string)
+                    }
+                }
+            }
+            None => {
+                let cmd = &self.command;
+                quote! { #cmd.to_string() }
+            }
+        }
+    }
+
+    /// The command as it runs, when it has no placeholders.
+    fn static_text(&self) -> Option<String> {
+        if !self.placeholders.is_empty() {
+            return None;
         }
+        Some(match &self.format_string {
+            Some(format_string) => format_string.replace("{{", "{").replace("}}", "}"),
+            None => self.command.clone(),
+        })
     }
 }
 
@@ -7145,30 +11162,120 @@ impl CmdExpr {
 /// // Complex command with operators
 /// let c = cmd!("npm install && npm test");
 ///
-/// // With Rust format interpolation
+/// // With a placeholder filled in at runtime
 /// let env = "production";
-/// let c = cmd!(&format!("./deploy.sh {}", env));
+/// let c = cmd!("./deploy.sh {env}");
+///
+/// // Let bashrs report a ShellCheck code without failing the command
+/// let c = cmd!("cat package.json | jq .version", allow: ["SC2002"]);
 /// ```
 #[proc_macro]
 pub fn cmd(input: TokenStream) -> TokenStream {
-    let lit = match syn::parse::<LitStr>(input) {
-        Ok(lit) => lit,
-        Err(_) => {
-            return syn::Error::new(
+    let parse = |input: ParseStream| {
+        let lit: LitStr = input.parse().map_err(|_| {
+            Error::new(
                 proc_macro2::Span::call_site(),
                 "cmd! requires a string literal, e.g., cmd!(\"npm install\")",
             )
-            .to_compile_error()
-            .into();
-        }
+        })?;
+        let mut cmd_expr = CmdExpr::parse_lit_str(&lit)?;
+        cmd_expr.parse_allow(input)?;
+        lint_commands(&mut [&mut cmd_expr])?;
+        Ok(cmd_expr)
     };
 
-    match CmdExpr::from_lit_str(&lit) {
+    match syn::parse::Parser::parse(parse, input) {
         Ok(cmd_expr) => cmd_expr.to_tokens().into(),
         Err(err) => err.to_compile_error().into(),
     }
 }
 
+/// Expand a condition helper outside `pipeline!` to its string literal.
+fn expand_condition_helper(name: &str, input: TokenStream) -> TokenStream {
+    match conditions::resolve(name, proc_macro2::Span::call_site(), input.into()) {
+        Ok(value) => LitStr::new(&value, proc_macro2::Span::call_site())
+            .to_token_stream()
+            .into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// A conditional that passes on the given branch, e.g.
+/// `on_branch!("main")` is `build.branch == 'main'`. A `*` in the name
+/// matches any run of characters: `on_branch!("release/*")`.
+///
+/// Usable as an `if` value in `pipeline!`, or anywhere a `&str` is expected.
+#[proc_macro]
+pub fn on_branch(input: TokenStream) -> TokenStream {
+    expand_condition_helper("on_branch", input)
+}
+
+/// A conditional that passes on builds not triggered by a pull request:
+/// `build.pull_request.id == null`.
+#[proc_macro]
+pub fn not_pull_request(input: TokenStream) -> TokenStream {
+    expand_condition_helper("not_pull_request", input)
+}
+
+/// A conditional that passes on tag builds: `build.tag != null`. With a
+/// pattern, only on matching tags, e.g. `on_tag!("v*")`.
+#[proc_macro]
+pub fn on_tag(input: TokenStream) -> TokenStream {
+    expand_condition_helper("on_tag", input)
+}
+
+/// A conditional that passes on pull requests into the given base branch,
+/// e.g. `pr_base_branch!("release/*")`.
+#[proc_macro]
+pub fn pr_base_branch(input: TokenStream) -> TokenStream {
+    expand_condition_helper("pr_base_branch", input)
+}
+
+/// Joins conditionals with `&&`, e.g.
+/// `all!(on_branch!("main"), not_pull_request!())`.
+#[proc_macro]
+pub fn all(input: TokenStream) -> TokenStream {
+    expand_condition_helper("all", input)
+}
+
+/// Joins conditionals with `||`, e.g. `any!(on_branch!("main"), on_tag!())`.
+#[proc_macro]
+pub fn any(input: TokenStream) -> TokenStream {
+    expand_condition_helper("any", input)
+}
+
+/// Expand a cargo helper outside `pipeline!` to a checked command, as `cmd!` does.
+fn expand_cargo_helper(name: &str, input: TokenStream) -> TokenStream {
+    let expanded =
+        cargo::expand_standalone(name, input.into()).and_then(|lit| CmdExpr::from_lit_str(&lit));
+    match expanded {
+        Ok(cmd_expr) => cmd_expr.to_tokens().into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// `cargo test` with typed options, e.g.
+/// `cargo_test!(package: "api", features: ["postgres"], no_default_features: true)`.
+///
+/// Usable in command position in `pipeline!`, or anywhere `cmd!` is.
+#[proc_macro]
+pub fn cargo_test(input: TokenStream) -> TokenStream {
+    expand_cargo_helper("cargo_test", input)
+}
+
+/// `cargo build` with typed options, e.g. `cargo_build!(release: true, locked: true)`.
+#[proc_macro]
+pub fn cargo_build(input: TokenStream) -> TokenStream {
+    expand_cargo_helper("cargo_build", input)
+}
+
+/// `cargo clippy` with typed options, e.g.
+/// `cargo_clippy!(all_targets: true, extra_args: ["--", "-D", "warnings"])`.
+#[proc_macro]
+pub fn cargo_clippy(input: TokenStream) -> TokenStream {
+    expand_cargo_helper("cargo_clippy", input)
+}
+
 /// A macro for defining Bazel commands with compile-time validation.
 ///
 /// This macro accepts a **string literal** containing a Bazel command.
@@ -7204,14 +11311,46 @@ pub fn bazel(input: TokenStream) -> TokenStream {
         }
     };
 
-    match BazelExpr::from_lit_str(
-        &parsed.command,
-        parsed.validate_targets,
-        parsed.dry_run,
-        &parsed.custom_verbs,
-    ) {
-        Ok(bazel_expr) => bazel_expr.to_tokens().into(),
-        Err(err) => err.to_compile_error().into(),
+    diag::clear();
+    track::clear();
+    bazel_report::clear();
+    let checked = config::load()
+        .map_err(|e| Error::new(parsed.command.span(), e))
+        .and_then(|config| {
+            let mut custom_verbs = config.bazel.custom_verbs;
+            custom_verbs.extend(parsed.custom_verbs.iter().cloned());
+            BazelExpr::from_lit_str(
+                &parsed.command,
+                parsed.validate_targets,
+                parsed.dry_run,
+                &custom_verbs,
+            )
+        })
+        .and_then(|bazel_expr| {
+            if BazelValidation::Strict.effective() != BazelValidation::Off {
+                bazel_expr.run_bazel_checks(true, None)?;
+            } else {
+                bazel_expr.report_skipped("RUST_BUILDKITE_BAZEL_OFFLINE=1 is set");
+            }
+            Ok(bazel_expr)
+        });
+    bazel_report::finish();
+    match checked {
+        Ok(bazel_expr) => {
+            let tracked = track::take();
+            let warnings = diag::take();
+            let tokens = bazel_expr.to_tokens();
+            if warnings.is_empty() && tracked.is_empty() {
+                tokens.into()
+            } else {
+                quote! {{ #tracked #warnings #tokens }}.into()
+            }
+        }
+        Err(err) => {
+            diag::clear();
+            track::clear();
+            err.to_compile_error().into()
+        }
     }
 }
 
@@ -7222,49 +11361,106 @@ pub fn comptime(input: TokenStream) -> TokenStream {
     input
 }
 
-/// Marker for runtime expressions. Skips compile-time validation.
-#[cfg(feature = "bazel")]
-#[proc_macro]
-pub fn runtime(input: TokenStream) -> TokenStream {
-    input
+/// Marker for runtime expressions. Skips compile-time validation.
+#[cfg(feature = "bazel")]
+#[proc_macro]
+pub fn runtime(input: TokenStream) -> TokenStream {
+    input
+}
+
+/// Runs a shell command at compile time, returns stdout as a string literal.
+/// Works with rust-script since proc macros run during compilation. The
+/// command may be followed by `cwd: "..."` (`"manifest_dir"`,
+/// `"workspace_root"` or a path relative to the script) and `env: { ... }`.
+#[cfg(feature = "bazel")]
+#[proc_macro]
+pub fn comptime_shell(input: TokenStream) -> TokenStream {
+    let cmd: shell::ShellCommand = match syn::parse(input) {
+        Ok(cmd) => cmd,
+        Err(e) => return e.to_compile_error().into(),
+    };
+
+    if cmd.is_list() {
+        return match cmd.run_lines() {
+            Ok(lines) => quote! { [#(#lines),*] }.into(),
+            Err(e) => e.to_compile_error().into(),
+        };
+    }
+    match cmd.run() {
+        Ok(stdout) => quote! { #stdout }.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// Resolve an `env!("NAME")` value during expansion, as rustc would, so the
+/// pipeline is validated against the real value. A variable that isn't set
+/// is an error at its name, using `env!`'s custom message when one is given.
+fn resolve_env_macro(mac: &syn::Macro) -> Result<String> {
+    let args = mac
+        .parse_body_with(syn::punctuated::Punctuated::<LitStr, Token![,]>::parse_terminated)
+        .map_err(|_| Error::new_spanned(mac, "env! requires a string literal variable name"))?;
+    let Some(name) = args.first() else {
+        return Err(Error::new_spanned(
+            mac,
+            "env! requires a string literal variable name",
+        ));
+    };
+    std::env::var(name.value()).map_err(|_| {
+        let message = args.get(1).map(LitStr::value).unwrap_or_else(|| {
+            format!(
+                "environment variable `{}` not defined at compile time",
+                name.value()
+            )
+        });
+        Error::new(name.span(), message)
+    })
 }
 
-/// Runs a shell command at compile time, returns stdout as a string literal.
-/// Works with rust-script since proc macros run during compilation.
+/// Runs `bazel info <key>` in the Bazel workspace at compile time, returns the
+/// output as a string literal.
 #[cfg(feature = "bazel")]
 #[proc_macro]
-pub fn comptime_shell(input: TokenStream) -> TokenStream {
-    let cmd: LitStr = match syn::parse(input) {
+pub fn bazel_info(input: TokenStream) -> TokenStream {
+    let key: LitStr = match syn::parse(input) {
         Ok(lit) => lit,
         Err(e) => return e.to_compile_error().into(),
     };
 
-    let output = std::process::Command::new("sh")
-        .arg("-c")
-        .arg(cmd.value())
-        .output();
-
-    match output {
-        Ok(out) if out.status.success() => {
-            let stdout = String::from_utf8_lossy(&out.stdout).trim().to_string();
-            quote! { #stdout }.into()
+    diag::clear();
+    match run_bazel_info(&key) {
+        Ok(value) => {
+            let warnings = diag::take();
+            if warnings.is_empty() {
+                quote! { #value }.into()
+            } else {
+                quote! {{ #warnings #value }}.into()
+            }
         }
-        Ok(out) => {
-            let stderr = String::from_utf8_lossy(&out.stderr);
-            let code = out.status.code().unwrap_or(-1);
-            Error::new(
-                cmd.span(),
-                format!("Command failed (exit {}): {}", code, stderr),
-            )
-            .to_compile_error()
-            .into()
+        Err(e) => {
+            diag::clear();
+            e.to_compile_error().into()
         }
-        Err(e) => Error::new(cmd.span(), format!("Failed to run command: {}", e))
-            .to_compile_error()
-            .into(),
     }
 }
 
+/// Run `bazel info` for a `bazel_info!` key, warning about keys Bazel isn't
+/// known to print, or an error at the key if it fails.
+#[cfg(feature = "bazel")]
+fn run_bazel_info(key: &LitStr) -> Result<String> {
+    let name = key.value();
+    if !bazel::INFO_KEYS.contains(&name.as_str()) {
+        diag::warn(
+            key.span(),
+            format!(
+                "unknown bazel info key '{}'; did you mean '{}'?",
+                name,
+                closest_matches(&name, bazel::INFO_KEYS.iter().copied(), 1).join("")
+            ),
+        );
+    }
+    bazel::info(&name, &bazel_defaults::binary()).map_err(|e| Error::new(key.span(), e))
+}
+
 #[cfg(feature = "bazel")]
 struct BazelMacroInput {
     command: LitStr,
@@ -7350,18 +11546,208 @@ const VALID_BAZEL_VERBS: &[&str] = &[
 #[cfg(feature = "bazel")]
 #[derive(Clone)]
 struct BazelExpr {
-    /// The full command string (without "bazel" prefix)
+    /// The full command string (without the binary prefix)
     command: String,
+    /// Startup options written before the verb, e.g. `--bazelrc=ci/.bazelrc`
+    startup: Vec<String>,
+    /// Binary the emitted command runs, `bazel` unless the pipeline sets
+    /// `bazel_binary`
+    binary: String,
+    /// Directory the emitted command `cd`s into, from the step's `workspace`
+    /// or the pipeline's `bazel_workspace`
+    workspace_dir: Option<String>,
     /// The verb (build, test, run, etc.)
     verb: String,
     /// Undefined shell variables found in the command
     undefined_vars: Vec<String>,
     /// Source span for error reporting
     span: proc_macro2::Span,
+    /// Checks that invoke bazel, run once parsing is done
+    deferred: Option<BazelDeferredChecks>,
+    /// Record what validation did with this command in the dry-run report
+    dry_run: bool,
+}
+
+/// Checks for a bazel command that need the bazel binary, run after parsing
+/// so the pipeline's `bazel_validation` mode applies to them.
+#[cfg(feature = "bazel")]
+#[derive(Clone)]
+struct BazelDeferredChecks {
+    /// None when no Bazel workspace was found
+    workspace: Option<std::path::PathBuf>,
+    current_pkg: Option<String>,
+    /// BUILD-file parsing couldn't find a target; confirm it with `bazel query`
+    query_targets: bool,
+    canonicalize_flags: bool,
+    /// Check `--config` names against the workspace's bazelrc files
+    check_configs: bool,
+    dry_run: bool,
+}
+
+/// The `bazel_validation` pipeline field.
+#[cfg(feature = "bazel")]
+#[derive(Clone, Copy, Default, PartialEq)]
+enum BazelValidation {
+    /// Failing to run bazel is a compile error
+    #[default]
+    Strict,
+    /// A missing bazel binary or workspace is a warning and the checks that
+    /// need them are skipped
+    Warn,
+    /// Never invoke bazel; only syntactic checks run
+    Off,
+}
+
+#[cfg(feature = "bazel")]
+impl BazelValidation {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let lit: LitStr = input.parse()?;
+        match lit.value().as_str() {
+            "strict" => Ok(Self::Strict),
+            "warn" => Ok(Self::Warn),
+            "off" => Ok(Self::Off),
+            other => Err(Error::new(
+                lit.span(),
+                format!(
+                    "unknown bazel_validation mode '{}'. Expected: strict, warn, off",
+                    other
+                ),
+            )),
+        }
+    }
+
+    /// `RUST_BUILDKITE_BAZEL_OFFLINE=1` turns validation off regardless of
+    /// the pipeline's setting.
+    fn effective(self) -> Self {
+        if std::env::var("RUST_BUILDKITE_BAZEL_OFFLINE").is_ok_and(|v| v == "1") {
+            Self::Off
+        } else {
+            self
+        }
+    }
+}
+
+/// The `bazel_bep` pipeline field: where bazel steps write their Build Event
+/// Protocol JSON, and whether the file is uploaded as an artifact.
+#[cfg(feature = "bazel")]
+struct BazelBep {
+    path: LitStr,
+    upload: bool,
+}
+
+#[cfg(feature = "bazel")]
+impl BazelBep {
+    /// Parse bazel_bep: { path: "bep.json", upload: true }
+    fn parse(input: ParseStream) -> Result<Self> {
+        let content;
+        let brace = braced!(content in input);
+        let mut path: Option<LitStr> = None;
+        let mut upload = true;
+        while !content.is_empty() {
+            let key: Ident = content.parse()?;
+            content.parse::<Token![:]>()?;
+            match key.to_string().as_str() {
+                "path" => {
+                    let lit: LitStr = content.parse()?;
+                    let value = lit.value();
+                    if value.is_empty() || value.contains(char::is_whitespace) {
+                        return Err(Error::new(
+                            lit.span(),
+                            "bazel_bep path must be a file path without whitespace, e.g. \"bep.json\"",
+                        ));
+                    }
+                    path = Some(lit);
+                }
+                "upload" => {
+                    let val: syn::LitBool = content.parse()?;
+                    upload = val.value();
+                }
+                other => {
+                    return Err(Error::new(
+                        key.span(),
+                        format!("unknown bazel_bep field: {}. Expected: path, upload", other),
+                    ));
+                }
+            }
+            if content.peek(Token![,]) {
+                content.parse::<Token![,]>()?;
+            }
+        }
+        let path = path.unwrap_or_else(|| LitStr::new("bep.json", brace.span.join()));
+        Ok(Self { path, upload })
+    }
+
+    /// Add the BEP flag to the bazel commands of every step that hasn't opted
+    /// out with `bep: false`, and the file to the step's artifact_paths when
+    /// `upload` is set. Commands that already choose a BEP JSON file keep it.
+    fn apply(&self, steps: &mut [StepDef]) {
+        let flag = format!("{}={}", bep::JSON_FILE_FLAG, self.path.value());
+        for step in steps {
+            match step {
+                StepDef::Group(g) => self.apply(&mut g.steps),
+                StepDef::Command(c) if c.bep => {
+                    let mut wired = None;
+                    for command in &mut c.commands {
+                        if command.add_bep_flag(&flag) && wired.is_none() {
+                            wired = Some(command.workspace_dir().map(str::to_string));
+                        }
+                    }
+                    // bazel writes the file relative to the directory it runs in
+                    let Some(workspace_dir) = wired else {
+                        continue;
+                    };
+                    let path = match workspace_dir {
+                        Some(dir) if !self.path.value().starts_with('/') => {
+                            LitStr::new(&format!("{}/{}", dir, self.path.value()), self.path.span())
+                        }
+                        _ => self.path.clone(),
+                    };
+                    if self.upload && !c.artifact_paths.iter().any(|p| p.value() == path.value()) {
+                        c.artifact_paths.push(path);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
 }
 
 #[cfg(feature = "bazel")]
 impl BazelExpr {
+    /// The expression of a `query`, `cquery` or `aquery` command.
+    fn query_expression(verb: &str, command: &str) -> Option<String> {
+        if !bazel::is_query_verb(verb) {
+            return None;
+        }
+        let rest = command
+            .split_once(char::is_whitespace)
+            .map_or("", |(_, rest)| rest);
+        Some(bazel::expression_from_args(rest)).filter(|expr| !expr.is_empty())
+    }
+
+    /// Whether the command names plain target patterns rather than a query
+    /// expression built from functions and operators.
+    fn has_plain_targets(verb: &str, command: &str) -> bool {
+        Self::query_expression(verb, command)
+            .is_none_or(|expr| StepDef::validate_target_patterns(&expr).is_ok())
+    }
+
+    /// Arguments after the verb. A query expression is dropped unless it is a
+    /// plain pattern list, so its operators aren't mistaken for flags or targets.
+    fn args(&self) -> Vec<&str> {
+        let args = self.command.split_whitespace().skip(1);
+        if Self::has_plain_targets(&self.verb, &self.command) {
+            args.collect()
+        } else {
+            args.filter(|a| a.starts_with("--")).collect()
+        }
+    }
+
+    /// Single-quote a query expression so the shell passes it to bazel intact.
+    fn quote_query_expression(expr: &str) -> String {
+        format!("'{}'", expr.replace('\'', "'\\''"))
+    }
+
     fn quote_flag_values(command: &str) -> String {
         let mut result = String::with_capacity(command.len() + 32);
         let mut chars = command.chars().peekable();
@@ -7436,6 +11822,15 @@ impl BazelExpr {
             Err(e) => return Err(Error::new(span, e)),
         };
 
+        let raw = command;
+        let (startup, verb_offset) = Self::split_startup_options(&raw).map_err(|(e, range)| {
+            Error::new(
+                literal_subspan(&lit.token(), &raw, range).unwrap_or(span),
+                e,
+            )
+        })?;
+        let command = raw[verb_offset..].to_string();
+
         let verb = Self::extract_verb(&command);
         if verb.is_empty() {
             return Err(Error::new(span, "bazel! command is empty"));
@@ -7453,67 +11848,610 @@ impl BazelExpr {
             ));
         }
 
+        if !custom_verbs.contains(&verb)
+            && let Err((e, range)) = Self::check_flags_for_verb(&command, &verb)
+        {
+            let range = range.start + verb_offset..range.end + verb_offset;
+            let span = literal_subspan(&lit.token(), &raw, range).unwrap_or(span);
+            return Err(Error::new(span, e));
+        }
+
+        let is_custom = custom_verbs.contains(&verb);
+        let defaults = bazel_defaults::flags_for(&verb, is_custom);
+        let command = if defaults.is_empty() {
+            command
+        } else {
+            if !is_custom {
+                Self::check_flag_literals(&verb, &defaults)?;
+            }
+            let values: Vec<String> = defaults.iter().map(|f| f.value()).collect();
+            bazel_defaults::merge(&command, &values)
+        };
+
+        if !is_custom && let Err((e, word)) = Self::check_flag_combinations(&command, &verb) {
+            // Point at the option in the literal; defaults have no span of their own
+            let mut offset = 0;
+            let mut range = None;
+            for w in raw.split_whitespace() {
+                let start = offset + raw[offset..].find(w).unwrap_or(0);
+                offset = start + w.len();
+                if w == word {
+                    range = Some(start..offset);
+                }
+            }
+            let span = range
+                .and_then(|range| literal_subspan(&lit.token(), &raw, range))
+                .unwrap_or(span);
+            return Err(Error::new(span, e));
+        }
+
+        if verb == "run" && validate_targets {
+            let args: Vec<&str> = command.split_whitespace().skip(1).collect();
+            targets::validate_run_target(&args).map_err(|e| Error::new(span, e))?;
+        }
+
+        if let Some(expr) = Self::query_expression(&verb, &command) {
+            let warnings =
+                bazel::validate_query_expression(&expr).map_err(|e| Error::new(span, e))?;
+            for warning in warnings {
+                diag::warn(span, warning);
+            }
+        }
+        let plain_targets = Self::has_plain_targets(&verb, &command);
+
+        let mut deferred = None;
         if undefined_vars.is_empty()
             && !matches!(
                 verb.as_str(),
                 "info" | "version" | "clean" | "shutdown" | "help"
             )
         {
-            match bazel::find_bazel_workspace_and_script_dir() {
-                Ok((workspace, script_dir)) => {
-                    let current_pkg = targets::get_current_package(&workspace, &script_dir);
-                    let args: Vec<&str> = command.split_whitespace().skip(1).collect();
-
-                    match bazel::fast_validate_targets(&args, &workspace, current_pkg.as_deref()) {
-                        Ok(()) => {}
-                        Err(fast_err) => {
-                            if validate_targets {
-                                if let Err(query_err) = bazel::validate_with_query(
-                                    &verb,
-                                    &args,
-                                    &workspace,
-                                    current_pkg.as_deref(),
-                                ) {
-                                    return Err(Error::new(
-                                        span,
-                                        format!("Target validation failed: {}", query_err),
-                                    ));
-                                }
-                            } else {
-                                return Err(Error::new(
-                                    span,
-                                    format!("Target validation failed: {}", fast_err),
-                                ));
-                            }
-                        }
+            let mut checks = BazelDeferredChecks {
+                workspace: None,
+                current_pkg: None,
+                query_targets: false,
+                canonicalize_flags: !custom_verbs.iter().any(|v| v == &verb),
+                check_configs: !custom_verbs.iter().any(|v| v == &verb),
+                dry_run,
+            };
+            let found = bazel::find_bazel_workspace_and_script_dir();
+            // A workspace the pipeline names must exist; a detected one may not
+            if let Err(e) = &found
+                && bazel_defaults::workspace().is_some()
+            {
+                return Err(Error::new(span, e));
+            }
+            if let Ok((workspace, script_dir)) = found {
+                let current_pkg = targets::get_current_package(&workspace, &script_dir);
+                let args: Vec<&str> = command.split_whitespace().skip(1).collect();
+
+                let fast_result = if plain_targets {
+                    bazel::fast_validate_targets(&args, &workspace, current_pkg.as_deref())
+                } else {
+                    Ok(())
+                };
+                match fast_result {
+                    Ok(()) => {}
+                    // Resolved by one bazel query for the whole pipeline
+                    Err(_) if validate_targets => checks.query_targets = true,
+                    Err(fast_err) => {
+                        return Err(Error::new(
+                            span,
+                            format!("Target validation failed: {}", fast_err),
+                        ));
+                    }
+                }
+                checks.workspace = Some(workspace);
+                checks.current_pkg = current_pkg;
+            }
+            deferred = Some(checks);
+        }
+
+        Ok(BazelExpr {
+            command,
+            startup,
+            binary: bazel_defaults::binary(),
+            workspace_dir: bazel_defaults::workspace(),
+            verb,
+            undefined_vars,
+            span,
+            deferred,
+            dry_run,
+        })
+    }
+
+    /// Split the startup options off the front of `command`, returning them
+    /// and the byte offset of the verb. Anything before the verb must be a
+    /// known startup option; the error carries the offending word's range.
+    fn split_startup_options(
+        command: &str,
+    ) -> std::result::Result<(Vec<String>, usize), (String, std::ops::Range<usize>)> {
+        let mut startup = Vec::new();
+        let mut offset = 0;
+        let mut takes_value = false;
+        for word in command.split_whitespace() {
+            let start = offset + command[offset..].find(word).unwrap_or(0);
+            offset = start + word.len();
+            if takes_value {
+                startup.push(word.to_string());
+                takes_value = false;
+                continue;
+            }
+            if !word.starts_with('-') {
+                return Ok((startup, start));
+            }
+            if let Err(e) = Self::check_startup_option(word) {
+                return Err((e, start..offset));
+            }
+            takes_value = rust_buildkite_validation::flags::startup_option_takes_value(word);
+            startup.push(word.to_string());
+        }
+        Ok((startup, command.len()))
+    }
+
+    /// Check that `word`, written before the verb, is a known startup option.
+    fn check_startup_option(word: &str) -> std::result::Result<(), String> {
+        use rust_buildkite_validation::flags::{FlagScope, flag_scope, startup_option_names};
+        if flag_scope(word) == Some(FlagScope::Startup) {
+            return Ok(());
+        }
+        let name = word.split_once('=').map_or(word, |(name, _)| name);
+        let known: Vec<String> = startup_option_names()
+            .iter()
+            .map(|n| format!("--{}", n))
+            .collect();
+        Err(format!(
+            "'{}' is not a bazel startup option; options before the command must be one of {}. \
+             Other options go after the command, e.g. `bazel build {} //...`",
+            name,
+            known.join(", "),
+            word
+        ))
+    }
+
+    /// Startup options that choose which rc files bazel reads, passed along
+    /// when validation runs bazel so it sees the same configs.
+    fn rc_startup_options(&self) -> Vec<&str> {
+        const RC_OPTIONS: &[&str] = &[
+            "bazelrc",
+            "ignore_all_rc_files",
+            "home_rc",
+            "system_rc",
+            "workspace_rc",
+        ];
+        let mut options = Vec::new();
+        let mut words = self.startup.iter();
+        while let Some(word) = words.next() {
+            let name = word.trim_start_matches('-');
+            let name = name.split_once('=').map_or(name, |(name, _)| name);
+            let name = name.strip_prefix("no").unwrap_or(name);
+            if RC_OPTIONS.contains(&name) {
+                options.push(word.as_str());
+                if rust_buildkite_validation::flags::startup_option_takes_value(word)
+                    && let Some(value) = words.next()
+                {
+                    options.push(value.as_str());
+                }
+            } else if rust_buildkite_validation::flags::startup_option_takes_value(word) {
+                words.next();
+            }
+        }
+        options
+    }
+
+    /// The emitted command line: binary, startup options, then the command.
+    fn invocation(&self) -> String {
+        let mut parts = vec![self.binary.as_str()];
+        parts.extend(self.startup.iter().map(|s| s.as_str()));
+        parts.push(&self.command);
+        parts.join(" ")
+    }
+
+    /// Check the options between the verb and any `--` against the flag table,
+    /// returning the byte range of the first offending option.
+    fn check_flags_for_verb(
+        command: &str,
+        verb: &str,
+    ) -> std::result::Result<(), (String, std::ops::Range<usize>)> {
+        let mut offset = 0;
+        for (index, word) in command.split_whitespace().enumerate() {
+            let start = offset + command[offset..].find(word).unwrap_or(0);
+            offset = start + word.len();
+            if index == 0 {
+                continue;
+            }
+            if word == "--" {
+                break;
+            }
+            bazel::check_flag_for_verb(verb, word).map_err(|e| (e, start..offset))?;
+        }
+        Ok(())
+    }
+
+    /// Parse `startup_options: ["--bazelrc=ci/.bazelrc", "--batch"]`, checking
+    /// each word against the known startup options.
+    fn parse_startup_options(input: ParseStream) -> Result<Vec<String>> {
+        let content;
+        bracketed!(content in input);
+        let mut startup = Vec::new();
+        while !content.is_empty() {
+            let option: LitStr = content.parse()?;
+            let value = option.value();
+            let mut takes_value = false;
+            for word in value.split_whitespace() {
+                if !takes_value {
+                    Self::check_startup_option(word).map_err(|e| Error::new(option.span(), e))?;
+                }
+                takes_value = !takes_value
+                    && rust_buildkite_validation::flags::startup_option_takes_value(word);
+                startup.push(word.to_string());
+            }
+            if content.peek(Token![,]) {
+                content.parse::<Token![,]>()?;
+            }
+        }
+        Ok(startup)
+    }
+
+    /// Check the options before any `--` for combinations bazel rejects or that
+    /// make the command pointless, returning the error and the later option.
+    fn check_flag_combinations(
+        command: &str,
+        verb: &str,
+    ) -> std::result::Result<(), (String, String)> {
+        let flags: Vec<&str> = command
+            .split_whitespace()
+            .skip(1)
+            .take_while(|w| *w != "--")
+            .filter(|w| w.starts_with("--"))
+            .collect();
+        rust_buildkite_validation::flags::check_flag_combinations(
+            verb,
+            &flags,
+            &bazel_defaults::exclusive_configs(),
+        )
+        .map_err(|(e, index)| (e, flags[index].to_string()))
+    }
+
+    /// Check the literals of a structured step's `flags:` list against `verb`,
+    /// pointing at the offending literal.
+    fn check_flag_literals(verb: &str, flags: &[LitStr]) -> Result<()> {
+        for flag in flags {
+            let value = flag.value();
+            for word in value.split_whitespace() {
+                if word == "--" {
+                    return Ok(());
+                }
+                bazel::check_flag_for_verb(verb, word).map_err(|e| Error::new(flag.span(), e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse `bazel_defaults: { test: ["--test_output=errors"], all: [...] }`.
+    /// Verb lists are checked against their verb here; `all` flags are
+    /// checked against each command's verb when they're applied.
+    fn parse_defaults(
+        input: ParseStream,
+        custom_verbs: &[String],
+    ) -> Result<Vec<(String, Vec<LitStr>)>> {
+        let content;
+        braced!(content in input);
+        let mut defaults = Vec::new();
+        while !content.is_empty() {
+            let verb: Ident = content.parse()?;
+            content.parse::<Token![:]>()?;
+            let name = strip_raw_ident(&verb.to_string()).to_string();
+            if name != "all" && !Self::is_valid_verb(&name, custom_verbs) {
+                return Err(Error::new(
+                    verb.span(),
+                    format!(
+                        "Unknown bazel command '{}' in bazel_defaults. Use `all` for every command, or declare it in custom_verbs first",
+                        name
+                    ),
+                ));
+            }
+
+            let flags_content;
+            bracketed!(flags_content in content);
+            let mut flags = Vec::new();
+            while !flags_content.is_empty() {
+                let flag: LitStr = flags_content.parse()?;
+                if let Some(word) = flag
+                    .value()
+                    .split_whitespace()
+                    .find(|w| !w.starts_with('-'))
+                {
+                    return Err(Error::new(
+                        flag.span(),
+                        format!(
+                            "bazel_defaults only takes flags, not '{}'; put targets and program arguments on the step",
+                            word
+                        ),
+                    ));
+                }
+                flags.push(flag);
+                if flags_content.peek(Token![,]) {
+                    flags_content.parse::<Token![,]>()?;
+                }
+            }
+            if name != "all" && !custom_verbs.contains(&name) {
+                Self::check_flag_literals(&name, &flags)?;
+            }
+            defaults.push((name, flags));
+
+            if content.peek(Token![,]) {
+                content.parse::<Token![,]>()?;
+            }
+        }
+        Ok(defaults)
+    }
+
+    /// Run the checks that invoke bazel. `pipeline!` queries targets for all
+    /// steps at once and passes `query_targets: false`, along with the labels
+    /// the query resolved for the dry-run report.
+    fn run_bazel_checks(&self, query_targets: bool, resolved: Option<Vec<String>>) -> Result<()> {
+        let mut report = self.dry_run.then(|| self.report_entry());
+        if let Some(entry) = &mut report {
+            entry.resolved = resolved;
+        }
+        let result = self.run_checks_with_report(query_targets, &mut report);
+        if let Some(entry) = report {
+            bazel_report::record(entry);
+        }
+        result
+    }
+
+    fn run_checks_with_report(
+        &self,
+        query_targets: bool,
+        report: &mut Option<bazel_report::Entry>,
+    ) -> Result<()> {
+        use bazel_report::{Status, note};
+
+        let Some(checks) = &self.deferred else {
+            note(
+                report,
+                "bazel",
+                Status::Skipped,
+                Some("nothing to check with bazel".to_string()),
+            );
+            return Ok(());
+        };
+        let Some(workspace) = &checks.workspace else {
+            warn_no_workspace_once();
+            note(
+                report,
+                "bazel",
+                Status::Skipped,
+                Some("no Bazel workspace found".to_string()),
+            );
+            return Ok(());
+        };
+        let args = self.args();
+        let failed = |report: &mut Option<bazel_report::Entry>, name, e: &str| {
+            note(report, name, Status::Failed, Some(e.to_string()));
+        };
+
+        if !checks.query_targets {
+            note(
+                report,
+                "targets",
+                Status::Passed,
+                Some("found in BUILD files".to_string()),
+            );
+        } else if !query_targets {
+            note(
+                report,
+                "targets",
+                Status::Passed,
+                Some("resolved by the pipeline's target query".to_string()),
+            );
+        } else {
+            match bazel::validate_with_query(
+                &self.verb,
+                &args,
+                workspace,
+                &self.binary,
+                checks.current_pkg.as_deref(),
+            ) {
+                Ok(labels) => {
+                    if let Some(entry) = report {
+                        let mut labels: Vec<String> = labels.into_keys().collect();
+                        labels.sort();
+                        entry.resolved = Some(labels);
                     }
+                    note(report, "targets", Status::Passed, None);
+                }
+                Err(e) => {
+                    failed(report, "targets", &e);
+                    return Err(Error::new(
+                        self.span,
+                        format!("Target validation failed: {}", e),
+                    ));
+                }
+            }
+        }
+
+        let rc_options = self.rc_startup_options();
+        if !checks.check_configs {
+            note(
+                report,
+                "configs",
+                Status::Skipped,
+                Some("custom verb".to_string()),
+            );
+        } else if !rc_options.is_empty() {
+            note(
+                report,
+                "configs",
+                Status::Skipped,
+                Some(format!("rc files chosen by {}", rc_options.join(" "))),
+            );
+        } else if let Err(e) = Self::check_config_names(&args, workspace) {
+            failed(report, "configs", &e);
+            return Err(Error::new(self.span, e));
+        } else {
+            note(report, "configs", Status::Passed, None);
+        }
+
+        if let Err(e) = bazel::check_platform_labels(
+            &args,
+            workspace,
+            &self.binary,
+            checks.current_pkg.as_deref(),
+        ) {
+            failed(report, "platforms", &e);
+            return Err(Error::new(self.span, e));
+        }
+        note(report, "platforms", Status::Passed, None);
+
+        let unknown_cpus = bazel::unknown_cpus(&args, workspace);
+        for cpu in &unknown_cpus {
+            let hint = closest_matches(
+                cpu,
+                rust_buildkite_validation::flags::COMMON_CPUS
+                    .iter()
+                    .copied(),
+                1,
+            )
+            .into_iter()
+            .find(|name| edit_distance(cpu, name) <= (cpu.len() / 3).max(1))
+            .map(|name| format!("; did you mean '{}'?", name))
+            .unwrap_or_default();
+            diag::warn(
+                self.span,
+                format!(
+                    "--cpu value '{}' is not a common CPU and is not set in the workspace's bazelrc files{}",
+                    cpu, hint
+                ),
+            );
+        }
+        let detail = (!unknown_cpus.is_empty())
+            .then(|| format!("unknown CPUs: {}", unknown_cpus.join(", ")));
+        note(report, "cpu", Status::Passed, detail);
+
+        if !checks.canonicalize_flags {
+            note(
+                report,
+                "canonicalize_flags",
+                Status::Skipped,
+                Some("custom verb".to_string()),
+            );
+        } else if let Err(e) =
+            bazel::canonicalize_flags(&self.verb, &rc_options, &args, workspace, &self.binary)
+        {
+            failed(report, "canonicalize_flags", &e);
+            return Err(Error::new(self.span, e));
+        } else {
+            note(report, "canonicalize_flags", Status::Passed, None);
+        }
 
-                    let is_custom_verb = custom_verbs.iter().any(|v| v == &verb);
-                    if !is_custom_verb
-                        && let Err(e) = bazel::canonicalize_flags(&verb, &args, &workspace)
-                    {
-                        return Err(Error::new(span, e));
-                    }
+        if matches!(self.verb.as_str(), "test" | "coverage") {
+            let unmatched = bazel::unmatched_test_tags(
+                &args,
+                workspace,
+                &self.binary,
+                checks.current_pkg.as_deref(),
+            );
+            for tag in &unmatched {
+                diag::warn(
+                    self.span,
+                    format!(
+                        "--test_tag_filters tag '{}' matches none of the selected tests; check the spelling",
+                        tag
+                    ),
+                );
+            }
+            let detail = (!unmatched.is_empty())
+                .then(|| format!("unmatched tags: {}", unmatched.join(", ")));
+            note(report, "test_tag_filters", Status::Passed, detail);
+        }
 
-                    if dry_run && let Err(e) = Self::run_dry_run(&verb, &command, span) {
-                        let err_msg = e.to_string();
-                        if !err_msg.contains("Could not find bazel workspace") {
-                            return Err(e);
-                        }
+        if checks.dry_run {
+            match Self::run_dry_run(
+                &self.verb,
+                &rc_options,
+                &self.command,
+                workspace,
+                &self.binary,
+                self.span,
+            ) {
+                Ok(()) => note(report, "dry_run", Status::Passed, None),
+                Err(e) => {
+                    let err_msg = e.to_string();
+                    if !err_msg.contains("Could not find bazel workspace") {
+                        failed(report, "dry_run", &err_msg);
+                        return Err(e);
                     }
-                }
-                Err(_) => {
-                    warn_no_workspace_once();
+                    note(report, "dry_run", Status::Skipped, Some(err_msg));
                 }
             }
         }
+        Ok(())
+    }
 
-        Ok(BazelExpr {
-            command,
-            verb,
-            undefined_vars,
-            span,
-        })
+    /// A dry-run report entry for this command, with no checks noted yet.
+    fn report_entry(&self) -> bazel_report::Entry {
+        let targets = crate::targets::extract_targets_from_args(&self.args());
+        bazel_report::Entry::new(self.span, &self.verb, &self.command, targets)
+    }
+
+    /// Record in the dry-run report that no bazel checks ran for this command.
+    fn report_skipped(&self, reason: &str) {
+        if self.dry_run {
+            let mut entry = self.report_entry();
+            entry.check(
+                "bazel",
+                bazel_report::Status::Skipped,
+                Some(reason.to_string()),
+            );
+            bazel_report::record(entry);
+        }
+    }
+
+    /// Check every `--config=NAME` before `--` against the configs defined in
+    /// the workspace's bazelrc files. Skipped when no rc file defines any
+    /// config, and for names built from shell variables.
+    fn check_config_names(
+        args: &[&str],
+        workspace: &std::path::Path,
+    ) -> std::result::Result<(), String> {
+        let mut names = Vec::new();
+        let mut words = args.iter().take_while(|a| **a != "--");
+        while let Some(word) = words.next() {
+            if let Some(name) = word.strip_prefix("--config=") {
+                names.push(*name);
+            } else if *word == "--config"
+                && let Some(name) = words.next()
+            {
+                names.push(*name);
+            }
+        }
+        names.retain(|name| !name.contains('$'));
+        if names.is_empty() {
+            return Ok(());
+        }
+
+        let defined = bazel::config_names(workspace);
+        if defined.is_empty() {
+            return Ok(());
+        }
+        let Some(unknown) = names.into_iter().find(|name| !defined.contains(*name)) else {
+            return Ok(());
+        };
+        let hint = closest_matches(unknown, defined.iter().map(|s| s.as_str()), 1)
+            .into_iter()
+            .find(|name| edit_distance(unknown, name) <= (unknown.len() / 3).max(1))
+            .map(|name| format!("\nDid you mean '{}'?", name))
+            .unwrap_or_else(|| {
+                let all: Vec<&str> = defined.iter().map(|s| s.as_str()).collect();
+                format!("\nDefined configs: {}", all.join(", "))
+            });
+        Err(format!(
+            "--config={} is not defined in .bazelrc, tools/bazel.rc or the files they import{}",
+            unknown, hint
+        ))
     }
 
     fn is_valid_verb(verb: &str, custom_verbs: &[String]) -> bool {
@@ -7521,16 +12459,21 @@ impl BazelExpr {
     }
 
     /// Run bazel with --nobuild to perform analysis phase without building.
-    fn run_dry_run(verb: &str, command: &str, span: proc_macro2::Span) -> Result<()> {
+    fn run_dry_run(
+        verb: &str,
+        startup: &[&str],
+        command: &str,
+        workspace: &std::path::Path,
+        binary: &str,
+        span: proc_macro2::Span,
+    ) -> Result<()> {
         if matches!(verb, "info" | "version" | "clean" | "shutdown" | "help") {
             return Ok(());
         }
 
-        let workspace = Self::find_workspace_for_span(span)?;
-
         let args: Vec<&str> = command.split_whitespace().skip(1).collect();
 
-        let result = match bep::dry_run(verb, &args, &workspace) {
+        let result = match bep::dry_run(verb, startup, &args, workspace, binary) {
             Ok(r) => r,
             Err(e) => return Err(Error::new(span, format!("Dry run failed:\n{}", e))),
         };
@@ -7571,13 +12514,6 @@ impl BazelExpr {
         Ok(())
     }
 
-    /// Find bazel workspace from environment variables.
-    /// Uses RUST_SCRIPT_BASE_PATH (for rust-script) or CARGO_MANIFEST_DIR.
-    fn find_workspace_for_span(span: proc_macro2::Span) -> Result<std::path::PathBuf> {
-        bazel::find_bazel_workspace_from_env()
-            .map_err(|e| Error::new(span, format!("Bazel validation failed: {}", e)))
-    }
-
     fn extract_verb(command: &str) -> String {
         command.split_whitespace().next().unwrap_or("").to_string()
     }
@@ -7590,11 +12526,10 @@ impl BazelExpr {
     }
 
     fn to_tokens(&self) -> TokenStream2 {
-        let cmd_string = format!("bazel {}", &self.command);
+        let cmd_string = self.invocation();
         let verb = &self.verb;
-        let command = &self.command;
 
-        let args: Vec<&str> = command.split_whitespace().collect();
+        let args = self.args();
         let targets = crate::targets::extract_targets_from_args(&args);
         let targets_str = targets.join(" ");
 
@@ -7848,3 +12783,695 @@ mod quote_flag_values_tests {
         );
     }
 }
+
+#[cfg(all(test, feature = "bazel"))]
+mod config_names_tests {
+    use super::BazelExpr;
+    use tempfile::tempdir;
+
+    fn workspace_with_rc(contents: &str) -> tempfile::TempDir {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join(".bazelrc"), contents).unwrap();
+        dir
+    }
+
+    #[test]
+    fn accepts_defined_configs() {
+        let dir = workspace_with_rc("build:remote --jobs=50\ntest:ci --test_output=errors\n");
+        let args = ["--config=remote", "--config", "ci", "//..."];
+        assert!(BazelExpr::check_config_names(&args, dir.path()).is_ok());
+    }
+
+    #[test]
+    fn suggests_closest_config() {
+        let dir = workspace_with_rc("build:remote --jobs=50\n");
+        let err =
+            BazelExpr::check_config_names(&["--config=remot", "//..."], dir.path()).unwrap_err();
+        assert!(err.contains("--config=remot is not defined"));
+        assert!(err.contains("Did you mean 'remote'?"));
+    }
+
+    #[test]
+    fn skips_variables_and_args_after_separator() {
+        let dir = workspace_with_rc("build:remote --jobs=50\n");
+        let args = ["--config=$CONFIG", "//...", "--", "--config=other"];
+        assert!(BazelExpr::check_config_names(&args, dir.path()).is_ok());
+    }
+
+    #[test]
+    fn skips_workspaces_without_configs() {
+        let dir = tempdir().unwrap();
+        assert!(BazelExpr::check_config_names(&["--config=anything"], dir.path()).is_ok());
+    }
+}
+
+#[cfg(all(test, feature = "bazel"))]
+mod bazel_binary_tests {
+    use super::missing_bazel_binary;
+    use tempfile::tempdir;
+
+    #[test]
+    fn checks_paths_in_the_workspace() {
+        let dir = tempdir().unwrap();
+        let reason = missing_bazel_binary("./tools/bazel", dir.path()).unwrap();
+        assert!(reason.ends_with("tools/bazel does not exist"));
+
+        std::fs::create_dir(dir.path().join("tools")).unwrap();
+        std::fs::write(dir.path().join("tools/bazel"), "").unwrap();
+        assert_eq!(missing_bazel_binary("./tools/bazel", dir.path()), None);
+    }
+
+    #[test]
+    fn checks_names_on_path() {
+        let dir = tempdir().unwrap();
+        assert_eq!(
+            missing_bazel_binary("no-such-bazelisk", dir.path()),
+            Some("no-such-bazelisk is not on PATH".to_string())
+        );
+        assert_eq!(missing_bazel_binary("sh", dir.path()), None);
+    }
+}
+
+#[cfg(test)]
+mod expansion_size_tests {
+    use super::*;
+
+    fn token_count(tokens: TokenStream2) -> usize {
+        tokens
+            .into_iter()
+            .map(|tree| match tree {
+                proc_macro2::TokenTree::Group(group) => 1 + token_count(group.stream()),
+                _ => 1,
+            })
+            .sum()
+    }
+
+    /// Expand a pipeline of `steps` command steps, each with `width` env
+    /// vars, agent tags and depends_on entries.
+    fn expand(steps: usize, width: usize) -> TokenStream2 {
+        let mut list = Vec::new();
+        for i in 0..width {
+            list.push(format!(r#"command(cmd!("echo dep")).key("dep-{}")"#, i));
+        }
+        let deps: Vec<String> = (0..width).map(|i| format!("\"dep-{}\"", i)).collect();
+        let env: Vec<String> = (0..width)
+            .map(|i| format!("VAR_{}: \"value\"", i))
+            .collect();
+        let agents: Vec<String> = (0..width).map(|i| format!("tag{}: \"x\"", i)).collect();
+        for i in 0..steps {
+            list.push(format!(
+                r#"command {{ command: cmd!("echo step"), key: "step-{}", depends_on: [{}], env: {{ {} }}, agents: {{ {} }} }}"#,
+                i,
+                deps.join(", "),
+                env.join(", "),
+                agents.join(", "),
+            ));
+        }
+        let source = format!(
+            r#"allowed_commands: ["echo"], steps: [{}]"#,
+            list.join(", ")
+        );
+        let pipeline: PipelineDef = syn::parse_str(&source).unwrap();
+        pipeline.generate(&mut timing::Timings::disabled()).unwrap()
+    }
+
+    #[test]
+    fn map_entries_expand_to_a_few_tokens_each() {
+        let narrow = token_count(expand(1, 1));
+        let wide = token_count(expand(1, 11));
+        // 10 more deps, env vars and agent tags, plus the 10 dep steps
+        let dep_steps = token_count(expand(0, 11)) - token_count(expand(0, 1));
+        let per_entry = (wide - narrow - dep_steps) / 30;
+        assert!(
+            per_entry <= 6,
+            "each env var, agent tag or dependency costs {} tokens",
+            per_entry
+        );
+    }
+
+    #[test]
+    fn large_pipelines_use_shared_helpers() {
+        let expanded = expand(300, 3);
+        let text = expanded.to_string();
+        assert!(text.contains("helpers :: env_map"));
+        assert!(text.contains("helpers :: agents_map"));
+        assert!(text.contains("helpers :: string_deps"));
+        assert!(!text.contains("Map :: new"));
+    }
+}
+
+// nb: on nightly warnings go straight to rustc instead of the expansion
+#[cfg(all(test, not(feature = "nightly")))]
+mod step_limit_tests {
+    use super::*;
+
+    /// The warnings `lints::check` queues for four steps under `lints`.
+    fn warnings(lints: &str) -> String {
+        let source = format!(
+            r#"lints: {{ {} }}, steps: [command(cmd!("echo a")), command(cmd!("echo b")), command(cmd!("echo c")), command(cmd!("echo d"))]"#,
+            lints
+        );
+        let pipeline: PipelineDef = syn::parse_str(&source).unwrap();
+        diag::clear();
+        let mut errors = Vec::new();
+        lints::check(&pipeline, &mut errors);
+        assert!(errors.is_empty());
+        diag::take().to_string()
+    }
+
+    #[test]
+    fn warns_near_the_limit() {
+        assert!(warnings("max_steps: 5").contains("over 80% of the limit of 5"));
+        assert!(!warnings("max_steps: 6").contains("80%"));
+    }
+
+    #[test]
+    fn allow_silences_the_warning() {
+        assert!(warnings("max_steps: 5, RBK0015: allow").is_empty());
+        assert!(warnings("max_steps: allow, max_steps: 5").is_empty());
+    }
+}
+
+#[cfg(all(test, not(feature = "nightly")))]
+mod shell_info_tests {
+    use super::*;
+
+    /// The warnings `lints::check` queues for a step running `command`.
+    fn warnings(lints: &str, command: &str) -> String {
+        let source = format!(r#"lints: {{ {} }}, steps: [command({})]"#, lints, command);
+        let pipeline: PipelineDef = syn::parse_str(&source).unwrap();
+        diag::clear();
+        let mut errors = Vec::new();
+        lints::check(&pipeline, &mut errors);
+        assert!(errors.is_empty());
+        diag::take().to_string()
+    }
+
+    #[test]
+    fn warn_reports_info_diagnostics() {
+        let command = r#"cmd!("cat notes.txt | grep todo")"#;
+        assert!(warnings("shell_info: warn", command).contains("SC2002"));
+        assert!(warnings("shell_info: allow", command).is_empty());
+    }
+
+    #[test]
+    fn cmd_allow_drops_its_codes() {
+        let command = r#"cmd!("cat notes.txt | grep todo", allow: ["SC2002"])"#;
+        assert!(warnings("shell_info: warn", command).is_empty());
+    }
+}
+
+#[cfg(test)]
+mod lint_commands_tests {
+    use super::*;
+
+    /// A pipeline of `count` distinct commands where every tenth one has a
+    /// lint issue. `salt` keeps the commands out of the lint cache of earlier
+    /// runs.
+    fn pipeline(count: usize, salt: &str) -> PipelineDef {
+        let steps: Vec<String> = (0..count)
+            .map(|i| {
+                let command = if i % 10 == 7 {
+                    format!("echo {}-{} $UNQUOTED_{}", salt, i, i)
+                } else {
+                    format!("test -n \"$OUT_{}\" && echo {}-{}", i, salt, i)
+                };
+                format!(
+                    r#"command(cmd!("{}")).key("step-{}")"#,
+                    command.replace('"', "\\\""),
+                    i
+                )
+            })
+            .collect();
+        syn::parse_str(&format!("steps: [{}]", steps.join(",\n"))).unwrap()
+    }
+
+    fn lint(pipeline: &mut PipelineDef, threads: usize) -> Option<String> {
+        let mut commands = Vec::new();
+        for step in &mut pipeline.steps {
+            step.collect_shell_commands_mut(StepShell::Bash, &mut commands);
+        }
+        lint_commands_on(&mut commands, threads)
+            .err()
+            .map(|err| err.to_compile_error().to_string())
+    }
+
+    fn undefined_vars(pipeline: &mut PipelineDef) -> Vec<Vec<String>> {
+        let mut commands = Vec::new();
+        for step in &mut pipeline.steps {
+            step.collect_shell_commands_mut(StepShell::Bash, &mut commands);
+        }
+        commands.iter().map(|c| c.undefined_vars.clone()).collect()
+    }
+
+    #[test]
+    fn test_parallel_matches_serial() {
+        // Distinct salts of the same length, so both runs call bashrs rather
+        // than one reading the other's results from the lint cache
+        let mut parallel = pipeline(60, "pmatch");
+        let mut serial = pipeline(60, "smatch");
+        let parallel_errors = lint(&mut parallel, 4).map(|e| e.replace("pmatch-", "smatch-"));
+        let serial_errors = lint(&mut serial, 1);
+        assert!(parallel_errors.is_some());
+        assert_eq!(parallel_errors, serial_errors);
+        assert_eq!(undefined_vars(&mut parallel), undefined_vars(&mut serial));
+        assert!(
+            undefined_vars(&mut parallel)
+                .first()
+                .is_some_and(|vars| vars == &["OUT_0"])
+        );
+    }
+
+    #[test]
+    fn test_errors_sorted_by_position() {
+        let mut pipeline = pipeline(40, "sorted");
+        let errors = lint(&mut pipeline, 8).unwrap();
+        let positions: Vec<usize> = (0..40)
+            .filter(|i| i % 10 == 7)
+            .map(|i| errors.find(&format!("sorted-{} ", i)).unwrap())
+            .collect();
+        assert!(positions.is_sorted(), "{}", errors);
+    }
+
+    #[test]
+    fn test_commands_are_linted_once() {
+        let mut pipeline = pipeline(3, "once");
+        assert!(lint(&mut pipeline, 2).is_none());
+        let mut commands = Vec::new();
+        for step in &mut pipeline.steps {
+            step.collect_shell_commands_mut(StepShell::Bash, &mut commands);
+        }
+        assert!(commands.iter().all(|c| c.pending_lint().is_none()));
+        assert!(lint_commands_on(&mut commands, 2).is_ok());
+    }
+
+    #[test]
+    fn test_shell_info_is_warn_or_allow() {
+        let pipeline: PipelineDef =
+            syn::parse_str(r#"lints: { shell_info: warn }, steps: []"#).unwrap();
+        assert!(pipeline.lints.shell_info.level == lints::LintLevel::Warn);
+        let err = syn::parse_str::<PipelineDef>(r#"lints: { RBK0027: deny }, steps: []"#)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("takes allow or warn"), "{}", err);
+    }
+
+    #[test]
+    fn test_step_shell_overrides_pipeline_default() {
+        let mut pipeline: PipelineDef = syn::parse_str(
+            r#"shell: "sh", steps: [
+                command(cmd!("echo a")).key("a"),
+                command(cmd!("echo b")).key("b").shell("bash"),
+                group("g").key("g").steps([command(cmd!("echo c")).key("c")])
+            ]"#,
+        )
+        .unwrap();
+        let default = pipeline.shell;
+        let mut commands = Vec::new();
+        for step in &mut pipeline.steps {
+            step.collect_shell_commands_mut(default, &mut commands);
+        }
+        let shells: Vec<StepShell> = commands.iter().map(|c| c.shell).collect();
+        assert_eq!(shells, [StepShell::Sh, StepShell::Bash, StepShell::Sh]);
+        assert!(POSIX_BUILTINS.iter().all(|b| !BASH_BUILTINS.contains(b)));
+    }
+
+    /// Compare serial and parallel linting of a 500-command pipeline:
+    /// `cargo test -p rust-buildkite-macros --release lint_benchmark -- --ignored --nocapture`
+    #[test]
+    #[ignore = "benchmark"]
+    fn lint_benchmark_500_commands() {
+        let mut serial = pipeline(500, "serial");
+        let mut parallel = pipeline(500, "parallel");
+        let start = std::time::Instant::now();
+        lint(&mut serial, 1);
+        let serial_time = start.elapsed();
+        let threads = lint_threads();
+        let start = std::time::Instant::now();
+        lint(&mut parallel, threads);
+        let parallel_time = start.elapsed();
+        eprintln!(
+            "500 commands: serial {:?}, {} threads {:?} ({:.1}x)",
+            serial_time,
+            threads,
+            parallel_time,
+            serial_time.as_secs_f64() / parallel_time.as_secs_f64()
+        );
+    }
+}
+
+#[cfg(test)]
+mod local_plugin_tests {
+    use super::*;
+
+    fn plugin_dir(root: &std::path::Path, rel: &str) -> std::path::PathBuf {
+        let dir = root.join(rel);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("plugin.yml"), "name: deploy\n").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_checks_plugin_yml() {
+        let root = tempfile::tempdir().unwrap();
+        let base = root.path().join("repo");
+        plugin_dir(&base, ".buildkite/plugins/deploy");
+        plugin_dir(root.path(), "shared/lint");
+        std::fs::create_dir_all(base.join(".buildkite/plugins/empty")).unwrap();
+
+        let check = |plugin: &str, allow: &[&str]| {
+            CmdExpr::validate_plugin_path(plugin, allow, Some(&base))
+        };
+        assert!(check("./.buildkite/plugins/deploy", &[]).is_ok());
+        assert!(check("../shared/lint", &[]).is_ok());
+
+        let err = check("./.buildkite/plugins/empty", &[]).unwrap_err();
+        let manifest = base.join(".buildkite/plugins/empty/plugin.yml");
+        assert!(err.contains("has no plugin.yml"), "{}", err);
+        assert!(err.contains(&manifest.display().to_string()), "{}", err);
+
+        let err = check("./.buildkite/plugins/missing", &[]).unwrap_err();
+        let dir = base.join(".buildkite/plugins/missing");
+        assert!(err.contains("does not exist"), "{}", err);
+        assert!(err.contains(&dir.display().to_string()), "{}", err);
+        assert!(err.contains("expect_paths"), "{}", err);
+
+        assert!(
+            check(
+                "./.buildkite/plugins/missing",
+                &["./.buildkite/plugins/missing"]
+            )
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_collects_local_plugins_from_every_syntax() {
+        let pipeline: PipelineDef = syn::parse_str(
+            r##"
+            default_plugins: [{ "./.buildkite/plugins/setup": { verbose: true } }, "docker-login#v2.1.0"],
+            steps: [
+                command(cmd!("echo a")).key("a").plugin("./plugins/a", { x: 1 }).plugin("docker#v5", {}),
+                command { command: cmd!("echo b"), key: "b", plugins: ["../plugins/b", { "ecr#v2": {} }] },
+                command { command: cmd!("echo c"), key: "c", plugins_yaml: "- ./plugins/c:\n    x: 1\n- cache#v1" },
+            ]
+            "##,
+        )
+        .unwrap();
+        let names = |plugins: &[(String, proc_macro2::Span)]| -> Vec<String> {
+            plugins.iter().map(|(name, _)| name.clone()).collect()
+        };
+        assert_eq!(
+            names(&pipeline.default_local_plugins),
+            ["./.buildkite/plugins/setup"]
+        );
+        let local: Vec<Vec<String>> = pipeline
+            .steps
+            .iter()
+            .map(|step| match step {
+                StepDef::Command(cmd) => names(&cmd.local_plugins),
+                _ => Vec::new(),
+            })
+            .collect();
+        assert_eq!(local, [["./plugins/a"], ["../plugins/b"], ["./plugins/c"]]);
+    }
+}
+
+#[cfg(test)]
+mod env_name_tests {
+    use super::*;
+
+    #[test]
+    fn test_env_key_accepts_idents_and_strings() {
+        let key: EnvKey = syn::parse::Parser::parse_str(EnvKey::parse, "RUST_LOG").unwrap();
+        assert_eq!(key.to_string(), "RUST_LOG");
+        let key: EnvKey =
+            syn::parse::Parser::parse_str(EnvKey::parse, r#""APP.CONFIG-PATH""#).unwrap();
+        assert_eq!(key.to_string(), "APP.CONFIG-PATH");
+    }
+
+    #[test]
+    fn test_check_env_name() {
+        assert!(check_env_name("CI").is_ok());
+        assert!(check_env_name("_PRIVATE2").is_ok());
+        assert!(check_env_name("my.dotted.key").is_ok());
+        let err = check_env_name("2FA_TOKEN").unwrap_err();
+        assert!(err.contains("starts with a digit"), "{}", err);
+        let err = check_env_name("BUILD NUMBER").unwrap_err();
+        assert!(err.contains("whitespace"), "{}", err);
+        let err = check_env_name("A=B").unwrap_err();
+        assert!(err.contains("'='"), "{}", err);
+        assert!(check_env_name("").is_err());
+    }
+
+    #[test]
+    fn test_trigger_build_formats() {
+        let lit = |value: &str| LitStr::new(value, proc_macro2::Span::call_site());
+        for commit in [
+            "HEAD",
+            "a1b2c3d",
+            "$BUILDKITE_COMMIT",
+            "${BUILDKITE_COMMIT}",
+        ] {
+            assert!(
+                TriggerBuildConfig::check_commit(&lit(commit)).is_ok(),
+                "{}",
+                commit
+            );
+        }
+        for commit in ["HEAD~1", "a1b2c3", "main", "a".repeat(41).as_str()] {
+            assert!(
+                TriggerBuildConfig::check_commit(&lit(commit)).is_err(),
+                "{}",
+                commit
+            );
+        }
+        assert!(TriggerBuildConfig::check_branch(&lit("release/1.4")).is_ok());
+        let err = TriggerBuildConfig::check_branch(&lit("my branch")).unwrap_err();
+        assert!(err.to_string().contains("whitespace"), "{}", err);
+        let err = TriggerBuildConfig::check_branch(&lit("-main")).unwrap_err();
+        assert!(err.to_string().contains("starts with '-'"), "{}", err);
+        let long = "x".repeat(TRIGGER_MESSAGE_MAX + 1);
+        assert!(TriggerBuildConfig::check_message(&lit(&long)).is_err());
+    }
+}
+
+#[cfg(test)]
+mod shorthand_tests {
+    use super::*;
+
+    fn step(source: &str) -> StepDef {
+        syn::parse_str(source).unwrap_or_else(|e| panic!("{}: {}", source, e))
+    }
+
+    #[test]
+    fn test_command_flags_and_trailing_commas() {
+        for source in [
+            r#"command { command: cmd!("make"), soft_fail, allow_dependency_failure }"#,
+            r#"command { command: cmd!("make"), soft_fail, allow_dependency_failure, }"#,
+            r#"command { command: cmd!("make"), soft_fail: true, allow_dependency_failure: true, }"#,
+        ] {
+            let StepDef::Command(c) = step(source) else {
+                panic!("{}", source)
+            };
+            assert!(c.soft_fail && c.allow_dependency_failure, "{}", source);
+        }
+        let StepDef::Command(c) = step(r#"command { soft_fail: false, command: cmd!("make") }"#)
+        else {
+            panic!()
+        };
+        assert!(!c.soft_fail);
+    }
+
+    #[test]
+    fn test_other_step_flags_and_trailing_commas() {
+        let StepDef::Wait(w) = step("wait { continue_on_failure, allow_dependency_failure, }")
+        else {
+            panic!()
+        };
+        assert!(w.continue_on_failure && w.allow_dependency_failure);
+
+        let StepDef::Block(b) = step(r#"block { block: "Deploy?", allow_dependency_failure, }"#)
+        else {
+            panic!()
+        };
+        assert!(b.allow_dependency_failure);
+
+        let StepDef::Input(i) = step(
+            r#"input { input: "Release notes", fields: [text { key: "notes", text: "Notes", },], allow_dependency_failure }"#,
+        ) else {
+            panic!()
+        };
+        assert!(i.allow_dependency_failure);
+
+        let StepDef::Trigger(t) =
+            step(r#"trigger { trigger: "deploy", r#async, soft_fail, allow_dependency_failure, }"#)
+        else {
+            panic!()
+        };
+        assert!(t.async_trigger && t.soft_fail && t.allow_dependency_failure);
+
+        let StepDef::Group(g) = step(
+            r#"group { group: "Tests", steps: [command { command: cmd!("make test"), soft_fail, },], allow_dependency_failure, }"#,
+        ) else {
+            panic!()
+        };
+        assert!(g.allow_dependency_failure);
+        assert!(matches!(&g.steps[0], StepDef::Command(c) if c.soft_fail));
+    }
+
+    #[test]
+    fn test_only_flags_take_shorthand() {
+        let err = syn::parse_str::<StepDef>(r#"command { command: cmd!("make"), key }"#)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("expected `:`"), "{}", err);
+    }
+
+    #[test]
+    fn test_notify_trailing_commas() {
+        let notify = |source: &str| {
+            syn::parse::Parser::parse_str(NotifyValue::parse, source)
+                .unwrap_or_else(|e| panic!("{}: {}", source, e))
+        };
+        assert!(matches!(
+            notify(r##"{ slack: "#ci", }"##),
+            NotifyValue::Slack { if_: None, .. }
+        ));
+        assert!(matches!(
+            notify(r##"{ slack: "#ci", r#if: "build.state == 'failed'", }"##),
+            NotifyValue::Slack { if_: Some(_), .. }
+        ));
+        assert!(matches!(
+            notify("{ github_check: {}, }"),
+            NotifyValue::GithubCheck
+        ));
+        let err =
+            syn::parse::Parser::parse_str(NotifyValue::parse, r##"{ slack: "#ci", when: "x" }"##)
+                .err()
+                .unwrap();
+        assert!(err.to_string().contains("unknown notify field"), "{}", err);
+    }
+}
+
+#[cfg(test)]
+mod notify_tests {
+    use super::*;
+
+    fn notify(source: &str) -> Result<NotifyValue> {
+        syn::parse::Parser::parse_str(NotifyValue::parse, source)
+    }
+
+    #[test]
+    fn test_if_in_any_position() {
+        for source in [
+            r##"{ slack: "#ci", r#if: "build.state == 'failed'" }"##,
+            r##"{ r#if: "build.state == 'failed'", slack: "#ci" }"##,
+        ] {
+            let Ok(NotifyValue::Slack { channel, if_ }) = notify(source) else {
+                panic!("{}", source)
+            };
+            assert_eq!(channel, "#ci");
+            assert_eq!(if_.as_deref(), Some("build.state == 'failed'"));
+        }
+        let Ok(NotifyValue::GithubCommitStatus { context, if_ }) = notify(
+            r#"{ r#if: "build.branch == 'main'", github_commit_status: { context: "ci" } }"#,
+        ) else {
+            panic!()
+        };
+        assert_eq!(context.as_deref(), Some("ci"));
+        assert!(if_.is_some());
+    }
+
+    #[test]
+    fn test_rejects_malformed_entries() {
+        let err = |source: &str| notify(source).err().unwrap().to_string();
+        assert!(
+            err(r##"{ slack: "#ci", webhok: "https://example.com" }"##)
+                .contains("unknown notify field: webhok; did you mean 'webhook'?")
+        );
+        assert!(
+            err(r##"{ slack: "#ci", email: "a@example.com" }"##).contains("both slack and email")
+        );
+        assert!(err(r##"{ slack: "#ci", slack: "#ops" }"##).contains("more than once"));
+        assert!(err(r#"{ r#if: "build.tag == null" }"#).contains("has no type"));
+        assert!(
+            err(r#"{ github_check: {}, r#if: "build.tag == null" }"#).contains("don't take an if")
+        );
+    }
+
+    #[test]
+    fn test_rejects_malformed_github_entries() {
+        let err = |source: &str| notify(source).err().unwrap().to_string();
+        assert!(
+            err(r#"{ github_commit_status: "ci" }"#)
+                .contains(r#"github_commit_status expects an object, e.g. github_commit_status: { context: "..." }"#)
+        );
+        assert!(
+            err(r#"{ github_commit_status: { contxt: "ci" } }"#)
+                .contains("unknown github_commit_status field: contxt; did you mean 'context'?")
+        );
+        assert!(
+            err(r#"{ github_check: "ci" }"#)
+                .contains("github_check expects an object, e.g. github_check: {}")
+        );
+        assert!(
+            err(r#"{ github_check: { context: "ci" } }"#)
+                .contains("unknown github_check field: context")
+        );
+    }
+}
+
+#[cfg(test)]
+mod agents_tests {
+    use super::*;
+
+    fn agents(source: &str) -> Result<(Vec<(String, LitStr)>, bool)> {
+        syn::parse::Parser::parse_str(parse_agents, source)
+    }
+
+    #[test]
+    fn test_list_form() {
+        let (tags, list) = agents(r#"["queue=default-v2", "os=linux",]"#).unwrap();
+        assert!(list);
+        let tags: Vec<(String, String)> = tags.into_iter().map(|(k, v)| (k, v.value())).collect();
+        assert_eq!(
+            tags,
+            [
+                ("queue".to_string(), "default-v2".to_string()),
+                ("os".to_string(), "linux".to_string())
+            ]
+        );
+        assert!(!agents(r#"{ queue: "default" }"#).unwrap().1);
+    }
+
+    #[test]
+    fn test_rejects_malformed_list_entries() {
+        let err = |source: &str| agents(source).err().unwrap().to_string();
+        assert!(err(r#"["queue"]"#).contains("exactly one '='"));
+        assert!(err(r#"["queue=a=b"]"#).contains("exactly one '='"));
+        assert!(err(r#"["=linux"]"#).contains("empty key"));
+        assert!(err(r#"["os="]"#).contains("empty value"));
+    }
+
+    #[test]
+    fn test_queue_conflicts_with_agents() {
+        let err = |source: &str| {
+            syn::parse_str::<PipelineDef>(source)
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        for source in [
+            r#"queue: "default", agents: { os: "linux" }, steps: []"#,
+            r#"steps: [command(cmd!("echo hi")).agents("os", "linux").queue("default")]"#,
+            r#"steps: [command { command: cmd!("echo hi"), queue: "default", agents: ["os=linux"] }]"#,
+        ] {
+            assert!(
+                err(source).contains("both queue and agents are set"),
+                "{}",
+                source
+            );
+        }
+    }
+}
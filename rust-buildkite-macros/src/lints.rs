@@ -0,0 +1,1467 @@
+//! Pipeline lints configured through the `lints:` field.
+//!
+//! Each lint has a level (`allow`, `warn` or `deny`). Warnings are printed
+//! during expansion; denied lints become compile errors at the offending span.
+//! The step count limit is enforced by default, and its threshold can be raised here.
+//!
+//! Error codes from [`crate::codes`] are accepted as lint names too, which sets
+//! the level of that check (e.g. `RBK0005: warn`). Checks that aren't lints
+//! default to deny.
+
+#[cfg(feature = "bazel")]
+use crate::BazelExpr;
+use crate::agent;
+use crate::codes::{self, Code};
+use crate::context::PipelineContext;
+use crate::diag;
+use crate::{
+    CommandValue, DynamicValue, EnvKey, NestedValue, PipelineDef, StepDef, ValueOrigin,
+    caret_snippet, closest_matches, edit_distance, expand_known_env_list, find_var_reference,
+    interpolated_vars, literal_subspan, malformed_interpolation,
+};
+use proc_macro2::Span;
+#[cfg(feature = "bazel")]
+use quote::ToTokens;
+use std::cell::RefCell;
+#[cfg(feature = "bazel")]
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use syn::parse::ParseStream;
+use syn::{Error, Ident, Result, Token, braced};
+
+const LINT_NAMES: &str = "no_structure, no_structure_min_steps, redundant_depends_on, parallelism_with_matrix, parallel_vars_without_parallelism, parallelism_without_sharding, bazel_test_timeout, duplicate_bazel_targets, unknown_label_var, unreachable_condition, shadowed_env, lowercase_env_name, bare_ident_value, shell_info, unkeyed_block_fields, unknown_meta_data, unread_meta_data, max_steps, max_group_steps";
+
+/// Variables Buildkite only sets on jobs of a step with parallelism
+const PARALLEL_JOB_VARS: &[&str] = &["BUILDKITE_PARALLEL_JOB", "BUILDKITE_PARALLEL_JOB_COUNT"];
+
+/// Test splitters that shard work themselves without reading the parallel job vars
+const SHARDING_TOOLS: &[&str] = &[
+    "bktec",
+    "buildkite-test-collector",
+    "knapsack",
+    "split_tests",
+    "--partition",
+    "--shard",
+];
+
+/// Bazel verbs that run tests
+const BAZEL_TEST_VERBS: &[&str] = &["test", "coverage"];
+
+/// Buildkite's limit on the number of steps a single pipeline upload may create
+const DEFAULT_MAX_STEPS: usize = 500;
+
+/// Fields where bare_ident_value is denied by default; elsewhere it's allowed
+const BARE_IDENT_DENIED_FIELDS: &[&str] = &["retry", "matrix"];
+
+thread_local! {
+    /// Bare identifiers read as strings while parsing, with the field each was
+    /// in. The `lints:` field may come after the steps, so they're checked
+    /// once the whole pipeline is parsed.
+    static BARE_IDENTS: RefCell<Vec<(&'static str, Ident)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Note a bare identifier read as a string in the value of `field`.
+pub fn record_bare_ident(field: &'static str, ident: &Ident) {
+    BARE_IDENTS.with(|b| b.borrow_mut().push((field, ident.clone())));
+}
+
+/// Drop noted identifiers, e.g. left over from an expansion that failed to parse.
+pub fn clear_bare_idents() {
+    BARE_IDENTS.with(|b| b.borrow_mut().clear());
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub enum LintLevel {
+    #[default]
+    Allow,
+    Warn,
+    Deny,
+}
+
+/// A configured lint level, with the span of its setting for deny errors.
+#[derive(Clone, Copy)]
+pub struct Lint {
+    pub level: LintLevel,
+    pub span: Span,
+}
+
+impl Default for Lint {
+    fn default() -> Self {
+        Self {
+            level: LintLevel::Allow,
+            span: Span::call_site(),
+        }
+    }
+}
+
+/// Parse a lint level name.
+fn parse_level(word: &str) -> std::result::Result<LintLevel, String> {
+    match word {
+        "allow" => Ok(LintLevel::Allow),
+        "warn" => Ok(LintLevel::Warn),
+        "deny" => Ok(LintLevel::Deny),
+        other => Err(format!(
+            "unknown lint level: {}. Expected: allow, warn, deny",
+            other
+        )),
+    }
+}
+
+/// A value given for a lint: a level name or a threshold.
+pub enum Setting<'a> {
+    Level(&'a str),
+    Count(usize),
+}
+
+/// Why a lint setting was rejected: its name, or the value given for it.
+pub enum SetError {
+    Name(String),
+    Value(String),
+}
+
+impl std::fmt::Display for SetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SetError::Name(msg) | SetError::Value(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl Lint {
+    fn from_setting(
+        name: &str,
+        setting: Setting,
+        span: Span,
+    ) -> std::result::Result<Self, SetError> {
+        match setting {
+            Setting::Level(word) => Ok(Self {
+                level: parse_level(word).map_err(SetError::Value)?,
+                span,
+            }),
+            Setting::Count(_) => Err(SetError::Value(format!(
+                "{} takes a level: allow, warn or deny",
+                name
+            ))),
+        }
+    }
+
+    /// Print a warning or record an error at `span`, depending on the level.
+    fn report(&self, errors: &mut Vec<Error>, code: &Code, span: Span, message: String) {
+        match self.level {
+            LintLevel::Allow => {}
+            LintLevel::Warn => diag::warn(span, format!("[{}] {}", code.id, message)),
+            LintLevel::Deny => errors.push(code.error(span, message)),
+        }
+    }
+}
+
+/// Lint settings from the `lints: { ... }` pipeline field.
+pub struct LintConfig {
+    /// Flag pipelines with many command steps but no wait steps or depends_on edges (off by default)
+    pub no_structure: Lint,
+    /// Number of command steps above which `no_structure` fires
+    pub no_structure_min_steps: usize,
+    /// Flag depends_on edges already implied by a wait step between the two steps (off by default)
+    pub redundant_depends_on: Lint,
+    /// Flag command steps that set both parallelism and matrix (warns by default)
+    pub parallelism_with_matrix: Lint,
+    /// Flag commands reading BUILDKITE_PARALLEL_JOB(_COUNT) on steps without parallelism (denied by default)
+    pub parallel_vars_without_parallelism: Lint,
+    /// Flag parallel steps whose commands never shard their work (warns by default)
+    pub parallelism_without_sharding: Lint,
+    /// Flag bazel test steps with neither timeout_in_minutes nor --test_timeout (off by default)
+    pub bazel_test_timeout: Lint,
+    /// Flag steps running the same bazel command over the same targets (off by default)
+    pub duplicate_bazel_targets: Lint,
+    /// Flag labels interpolating variables that are neither Buildkite's nor declared (warns by default)
+    pub unknown_label_var: Lint,
+    /// Flag step conditions and branch filters that contradict pipeline_context (warns by default)
+    pub unreachable_condition: Lint,
+    /// Flag step env entries that repeat or override a literal pipeline env value (warns by default)
+    pub shadowed_env: Lint,
+    /// Flag env var names with lowercase letters, which shell variables use (warns by default)
+    pub lowercase_env_name: Lint,
+    /// Flag bare identifiers read as strings in retry, matrix, notify and build
+    /// values; unset, it's denied in retry and matrix and allowed elsewhere
+    pub bare_ident_value: Option<Lint>,
+    /// Print bashrs info-level diagnostics for commands as warnings (off by
+    /// default; can't be denied)
+    pub shell_info: Lint,
+    /// Flag block and input steps without a key whose fields a later step
+    /// reads with `buildkite-agent meta-data get` (warns by default)
+    pub unkeyed_block_fields: Lint,
+    /// Flag commands reading meta-data that no field, `meta-data set` or
+    /// `external_meta_data` provides (denied by default)
+    pub unknown_meta_data: Lint,
+    /// Flag block and input fields no command reads (off by default, since
+    /// scripts the macro can't see often read them)
+    pub unread_meta_data: Lint,
+    /// Maximum number of steps after group and matrix/parallelism expansion
+    pub max_steps: usize,
+    /// Span of a configured `max_steps`, where limit errors point
+    pub max_steps_span: Span,
+    /// Optional maximum number of expanded steps inside a single group
+    pub max_group_steps: Option<usize>,
+    /// Levels set by error code for checks that aren't named lints
+    pub overrides: HashMap<&'static str, Lint>,
+}
+
+impl Default for LintConfig {
+    fn default() -> Self {
+        Self {
+            no_structure: Lint::default(),
+            no_structure_min_steps: 5,
+            redundant_depends_on: Lint::default(),
+            parallelism_with_matrix: Lint {
+                level: LintLevel::Warn,
+                span: Span::call_site(),
+            },
+            parallel_vars_without_parallelism: Lint {
+                level: LintLevel::Deny,
+                span: Span::call_site(),
+            },
+            parallelism_without_sharding: Lint {
+                level: LintLevel::Warn,
+                span: Span::call_site(),
+            },
+            bazel_test_timeout: Lint::default(),
+            duplicate_bazel_targets: Lint::default(),
+            unknown_label_var: Lint {
+                level: LintLevel::Warn,
+                span: Span::call_site(),
+            },
+            unreachable_condition: Lint {
+                level: LintLevel::Warn,
+                span: Span::call_site(),
+            },
+            shadowed_env: Lint {
+                level: LintLevel::Warn,
+                span: Span::call_site(),
+            },
+            lowercase_env_name: Lint {
+                level: LintLevel::Warn,
+                span: Span::call_site(),
+            },
+            bare_ident_value: None,
+            shell_info: Lint::default(),
+            unkeyed_block_fields: Lint {
+                level: LintLevel::Warn,
+                span: Span::call_site(),
+            },
+            unknown_meta_data: Lint {
+                level: LintLevel::Deny,
+                span: Span::call_site(),
+            },
+            unread_meta_data: Lint::default(),
+            max_steps: DEFAULT_MAX_STEPS,
+            max_steps_span: Span::call_site(),
+            max_group_steps: None,
+            overrides: HashMap::new(),
+        }
+    }
+}
+
+impl LintConfig {
+    /// Parse lints: { no_structure: warn, no_structure_min_steps: 8, redundant_depends_on: deny },
+    /// on top of the levels already set.
+    pub fn parse_into(&mut self, input: ParseStream) -> Result<()> {
+        let content;
+        braced!(content in input);
+
+        while !content.is_empty() {
+            let name: Ident = content.parse()?;
+            content.parse::<Token![:]>()?;
+
+            let result = if content.peek(syn::LitInt) {
+                let lit: syn::LitInt = content.parse()?;
+                self.set(
+                    &name.to_string(),
+                    Setting::Count(lit.base10_parse()?),
+                    lit.span(),
+                )
+                .map_err(|e| (e, lit.span()))
+            } else {
+                let level: Ident = content.parse()?;
+                self.set(
+                    &name.to_string(),
+                    Setting::Level(&level.to_string()),
+                    level.span(),
+                )
+                .map_err(|e| (e, level.span()))
+            };
+            match result {
+                Ok(()) => {}
+                Err((SetError::Name(msg), _)) => return Err(Error::new(name.span(), msg)),
+                Err((SetError::Value(msg), span)) => return Err(Error::new(span, msg)),
+            }
+
+            if content.peek(Token![,]) {
+                content.parse::<Token![,]>()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Apply one setting from `lints:` or the config file's `[lints]` table.
+    /// `span` is where deny errors for the lint point.
+    pub fn set(
+        &mut self,
+        name: &str,
+        setting: Setting,
+        span: Span,
+    ) -> std::result::Result<(), SetError> {
+        let count = |setting: Setting| match setting {
+            Setting::Count(count) => Ok(count),
+            Setting::Level(_) => Err(SetError::Value(format!("{} takes a number", name))),
+        };
+
+        // An error code for a named lint configures that lint
+        let code = codes::lookup(name);
+        match code.map_or(name, |c| c.name) {
+            "no_structure" => self.no_structure = Lint::from_setting(name, setting, span)?,
+            "no_structure_min_steps" => self.no_structure_min_steps = count(setting)?,
+            "redundant_depends_on" => {
+                self.redundant_depends_on = Lint::from_setting(name, setting, span)?;
+            }
+            "parallelism_with_matrix" => {
+                self.parallelism_with_matrix = Lint::from_setting(name, setting, span)?;
+            }
+            "parallel_vars_without_parallelism" => {
+                self.parallel_vars_without_parallelism = Lint::from_setting(name, setting, span)?;
+            }
+            "parallelism_without_sharding" => {
+                self.parallelism_without_sharding = Lint::from_setting(name, setting, span)?;
+            }
+            "bazel_test_timeout" => {
+                self.bazel_test_timeout = Lint::from_setting(name, setting, span)?;
+            }
+            "duplicate_bazel_targets" => {
+                self.duplicate_bazel_targets = Lint::from_setting(name, setting, span)?;
+            }
+            "unknown_label_var" => {
+                self.unknown_label_var = Lint::from_setting(name, setting, span)?;
+            }
+            "unreachable_condition" => {
+                self.unreachable_condition = Lint::from_setting(name, setting, span)?;
+            }
+            "shadowed_env" => {
+                self.shadowed_env = Lint::from_setting(name, setting, span)?;
+            }
+            "lowercase_env_name" => {
+                self.lowercase_env_name = Lint::from_setting(name, setting, span)?;
+            }
+            "bare_ident_value" => {
+                self.bare_ident_value = Some(Lint::from_setting(name, setting, span)?);
+            }
+            "shell_info" => {
+                let lint = Lint::from_setting(name, setting, span)?;
+                if lint.level == LintLevel::Deny {
+                    return Err(SetError::Value(format!(
+                        "{} takes allow or warn: bashrs info diagnostics never fail the build",
+                        name
+                    )));
+                }
+                self.shell_info = lint;
+            }
+            "unkeyed_block_fields" => {
+                self.unkeyed_block_fields = Lint::from_setting(name, setting, span)?;
+            }
+            "unknown_meta_data" => {
+                self.unknown_meta_data = Lint::from_setting(name, setting, span)?;
+            }
+            "unread_meta_data" => {
+                self.unread_meta_data = Lint::from_setting(name, setting, span)?;
+            }
+            // nb: a number is the limit; a level configures the check, like RBK0015
+            "max_steps" => match setting {
+                Setting::Count(limit) => {
+                    self.max_steps = limit;
+                    self.max_steps_span = span;
+                }
+                Setting::Level(_) => {
+                    self.overrides.insert(
+                        codes::STEP_LIMIT.id,
+                        Lint::from_setting(name, setting, span)?,
+                    );
+                }
+            },
+            "max_group_steps" => self.max_group_steps = Some(count(setting)?),
+            other => match code {
+                Some(code) if code.id == codes::UNKNOWN_FIELD.id => {
+                    return Err(SetError::Name(format!(
+                        "{} ({}) is a parse error and can't be given a level",
+                        other, code.description
+                    )));
+                }
+                Some(code) => {
+                    self.overrides
+                        .insert(code.id, Lint::from_setting(name, setting, span)?);
+                }
+                None if other.starts_with("RBK") => {
+                    let known: Vec<String> = codes::ALL
+                        .iter()
+                        .map(|c| format!("{} ({})", c.id, c.description))
+                        .collect();
+                    return Err(SetError::Name(format!(
+                        "unknown error code: {}. Known codes:\n{}",
+                        other,
+                        known.join("\n")
+                    )));
+                }
+                None => {
+                    return Err(SetError::Name(format!(
+                        "unknown lint: {}. Expected: {}, or an error code such as RBK0005",
+                        other, LINT_NAMES
+                    )));
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Report a check that isn't a named lint: an error unless its code was
+    /// set to `allow` or `warn`.
+    pub fn report(&self, errors: &mut Vec<Error>, code: &Code, span: Span, message: String) {
+        let level = self.level(code);
+        Lint { level, span }.report(errors, code, span, message);
+    }
+
+    /// The level a check that isn't a named lint runs at: deny unless set.
+    fn level(&self, code: &Code) -> LintLevel {
+        self.overrides
+            .get(code.id)
+            .map_or(LintLevel::Deny, |lint| lint.level)
+    }
+}
+
+/// Run every enabled lint against the parsed pipeline.
+pub fn check(pipeline: &PipelineDef, errors: &mut Vec<Error>) {
+    let config = &pipeline.lints;
+    check_no_structure(&pipeline.steps, config, errors);
+    check_redundant_depends_on(&pipeline.steps, config, errors);
+    check_parallelism_with_matrix(&pipeline.steps, config, errors);
+    check_parallel_job_vars(&pipeline.steps, config, errors);
+    check_bazel_test_timeout(&pipeline.steps, config, errors);
+    check_label_vars(pipeline, config, errors);
+    check_unreachable_conditions(pipeline, config, errors);
+    check_shadowed_env(pipeline, config, errors);
+    check_lowercase_env_names(pipeline, config, errors);
+    check_bare_idents(config, errors);
+    check_shell_info(&pipeline.steps, config, errors);
+    check_unkeyed_block_fields(&pipeline.steps, config, errors);
+    check_metadata_flow(pipeline, config, errors);
+    check_step_count(&pipeline.steps, config, errors);
+}
+
+fn check_no_structure(steps: &[StepDef], config: &LintConfig, errors: &mut Vec<Error>) {
+    if config.no_structure.level == LintLevel::Allow {
+        return;
+    }
+    let mut commands = 0;
+    let mut structured = false;
+    count_structure(steps, &mut commands, &mut structured);
+    if structured || commands <= config.no_structure_min_steps {
+        return;
+    }
+    config.no_structure.report(
+        errors,
+        &codes::NO_STRUCTURE,
+        config.no_structure.span,
+        format!(
+            "pipeline has {} command steps but no wait steps or depends_on, so they all run in parallel. Add `wait` or depends_on if they should run in order",
+            commands
+        ),
+    );
+}
+
+fn count_structure(steps: &[StepDef], commands: &mut usize, structured: &mut bool) {
+    for step in steps {
+        if !step.get_depends_on().is_empty() {
+            *structured = true;
+        }
+        match step {
+            StepDef::Command(_) => *commands += 1,
+            StepDef::Wait(_) => *structured = true,
+            StepDef::Group(g) => count_structure(&g.steps, commands, structured),
+            StepDef::Block(_) | StepDef::Input(_) | StepDef::Trigger(_) => {}
+        }
+    }
+}
+
+/// A depends_on edge is redundant when an unconditional wait step sits between
+/// the dependency and the dependent at the top level of the pipeline.
+fn check_redundant_depends_on(steps: &[StepDef], config: &LintConfig, errors: &mut Vec<Error>) {
+    if config.redundant_depends_on.level == LintLevel::Allow {
+        return;
+    }
+
+    // Top-level position of every key; keys inside a group take the group's position
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    let mut waits = Vec::new();
+    for (index, step) in steps.iter().enumerate() {
+        if let StepDef::Wait(w) = step
+            && w.if_condition.is_none()
+        {
+            waits.push(index);
+        }
+        let mut keys = Vec::new();
+        step.collect_keys(&mut keys);
+        for (key, _) in keys {
+            positions.insert(key, index);
+        }
+    }
+
+    for (index, step) in steps.iter().enumerate() {
+        let mut deps = Vec::new();
+        step.collect_depends_on(&mut deps);
+        for (dep, span) in deps {
+            let Some(&target) = positions.get(&dep) else {
+                continue;
+            };
+            if waits.iter().any(|&w| target < w && w < index) {
+                config.redundant_depends_on.report(
+                    errors,
+                    &codes::REDUNDANT_DEPENDS_ON,
+                    span,
+                    format!(
+                        "depends_on '{}' is redundant: a wait step already runs this step after '{}'",
+                        dep, dep
+                    ),
+                );
+            }
+        }
+    }
+}
+
+/// The step's key, quoted with a leading space, for messages that may be
+/// printed as warnings far from the step; empty when it has no key.
+fn step_name(step: &StepDef) -> String {
+    step.get_key()
+        .map(|(key, _)| format!(" '{}'", key))
+        .unwrap_or_default()
+}
+
+/// Steps whose `if` or branches filter contradicts `pipeline_context`.
+fn check_unreachable_conditions(
+    pipeline: &PipelineDef,
+    config: &LintConfig,
+    errors: &mut Vec<Error>,
+) {
+    fn check(
+        steps: &[StepDef],
+        context: &PipelineContext,
+        config: &LintConfig,
+        errors: &mut Vec<Error>,
+    ) {
+        for step in steps {
+            if let Some(condition) = step.if_condition()
+                && let Some(reason) = context.contradiction(&condition.value())
+            {
+                config.unreachable_condition.report(
+                    errors,
+                    &codes::UNREACHABLE_CONDITION,
+                    condition.span(),
+                    format!(
+                        "{} never runs: its `if` can't be true given pipeline_context, since {}",
+                        step.describe(),
+                        reason
+                    ),
+                );
+                // nb: a group that never runs hides whatever its steps say
+                continue;
+            }
+            let filters = step.branch_filters();
+            if let Some(first) = filters.first() {
+                let patterns: Vec<String> = filters.iter().map(|b| b.value()).collect();
+                if let Some(reason) = context.filtered_out(&patterns) {
+                    config.unreachable_condition.report(
+                        errors,
+                        &codes::UNREACHABLE_CONDITION,
+                        first.span(),
+                        format!(
+                            "{} never runs: its branches filter ({}) can't match given pipeline_context, since {}",
+                            step.describe(),
+                            patterns.join(", "),
+                            reason
+                        ),
+                    );
+                    continue;
+                }
+            }
+            if let StepDef::Group(group) = step {
+                check(&group.steps, context, config, errors);
+            }
+        }
+    }
+
+    if config.unreachable_condition.level == LintLevel::Allow {
+        return;
+    }
+    if let Some(context) = &pipeline.pipeline_context {
+        check(&pipeline.steps, context, config, errors);
+    }
+}
+
+/// A step env entry with a different literal than the pipeline env gives the
+/// variable a different value in that step only; one with the same literal
+/// is redundant. Runtime and comptime values can't be compared, and neither
+/// can entries merged in from group defaults.
+fn check_shadowed_env(pipeline: &PipelineDef, config: &LintConfig, errors: &mut Vec<Error>) {
+    fn check(
+        steps: &[StepDef],
+        pipeline_env: &[(EnvKey, DynamicValue)],
+        config: &LintConfig,
+        errors: &mut Vec<Error>,
+    ) {
+        for step in steps {
+            match step {
+                StepDef::Command(c) => {
+                    // nb: the step's own entries come first in `env`; defaults are appended
+                    for ((name, step_value), span) in c.env.iter().zip(&c.env_spans) {
+                        let Some(value) = step_value.as_literal() else {
+                            continue;
+                        };
+                        let Some((pipeline_name, pipeline_entry)) =
+                            pipeline_env.iter().find(|(n, _)| n == name)
+                        else {
+                            continue;
+                        };
+                        let Some(pipeline_value) = pipeline_entry.as_literal() else {
+                            continue;
+                        };
+                        if value == pipeline_value {
+                            config.shadowed_env.report(
+                                errors,
+                                &codes::SHADOWED_ENV,
+                                *span,
+                                format!(
+                                    "{} sets {} to {:?}, which the pipeline env already does; remove the step's entry{}",
+                                    step.describe(),
+                                    name,
+                                    value,
+                                    step_value.origin_note()
+                                ),
+                            );
+                            continue;
+                        }
+                        config.shadowed_env.report(
+                            errors,
+                            &codes::SHADOWED_ENV,
+                            *span,
+                            format!(
+                                "{} sets {} to {:?}, shadowing the pipeline env's {:?}{}",
+                                step.describe(),
+                                name,
+                                value,
+                                pipeline_value,
+                                step_value.origin_note()
+                            ),
+                        );
+                        config.shadowed_env.report(
+                            errors,
+                            &codes::SHADOWED_ENV,
+                            pipeline_name.span(),
+                            format!(
+                                "pipeline env sets {} to {:?}, which {} overrides with {:?}{}",
+                                name,
+                                pipeline_value,
+                                step.describe(),
+                                value,
+                                pipeline_entry.origin_note()
+                            ),
+                        );
+                    }
+                }
+                StepDef::Group(g) => check(&g.steps, pipeline_env, config, errors),
+                _ => {}
+            }
+        }
+    }
+
+    if config.shadowed_env.level == LintLevel::Allow {
+        return;
+    }
+    if let Some(env) = &pipeline.env {
+        check(&pipeline.steps, env, config, errors);
+    }
+}
+
+/// Env var names with lowercase letters, in pipeline, step and group default
+/// env blocks and `expect_env`. Shells use lowercase names for their own
+/// variables, so these can clash with a script's locals.
+fn check_lowercase_env_names(pipeline: &PipelineDef, config: &LintConfig, errors: &mut Vec<Error>) {
+    fn collect(steps: &[StepDef], names: &mut Vec<(String, Span)>) {
+        for step in steps {
+            match step {
+                StepDef::Command(c) => {
+                    names.extend(
+                        c.env
+                            .iter()
+                            .zip(&c.env_spans)
+                            .map(|((n, _), s)| (n.clone(), *s)),
+                    );
+                }
+                StepDef::Group(g) => {
+                    if let Some(defaults) = &g.defaults {
+                        names.extend(defaults.env.iter().map(|(k, _)| (k.to_string(), k.span())));
+                    }
+                    collect(&g.steps, names);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if config.lowercase_env_name.level == LintLevel::Allow {
+        return;
+    }
+    let mut names: Vec<(String, Span)> = pipeline
+        .env
+        .iter()
+        .flatten()
+        .map(|(k, _)| (k.to_string(), k.span()))
+        .collect();
+    names.extend(
+        pipeline
+            .expect_env_names
+            .iter()
+            .map(|lit| (lit.value(), lit.span())),
+    );
+    collect(&pipeline.steps, &mut names);
+    for (name, span) in names {
+        if name.chars().any(|c| c.is_lowercase()) {
+            config.lowercase_env_name.report(
+                errors,
+                &codes::LOWERCASE_ENV_NAME,
+                span,
+                format!(
+                    "env var name '{}' has lowercase letters, which shells use for their own variables; use '{}'",
+                    name,
+                    name.to_uppercase()
+                ),
+            );
+        }
+    }
+}
+
+/// bashrs info-level diagnostics, such as a useless `cat`, which the shell
+/// lint otherwise drops. Commands aren't linted again unless this is enabled.
+fn check_shell_info(steps: &[StepDef], config: &LintConfig, errors: &mut Vec<Error>) {
+    if config.shell_info.level == LintLevel::Allow {
+        return;
+    }
+    for step in steps {
+        match step {
+            StepDef::Command(c) => {
+                for (span, message) in c.commands.iter().flat_map(|cmd| cmd.info_diagnostics()) {
+                    config
+                        .shell_info
+                        .report(errors, &codes::SHELL_INFO, span, message);
+                }
+            }
+            StepDef::Group(g) => check_shell_info(&g.steps, config, errors),
+            _ => {}
+        }
+    }
+}
+
+/// A block or input step with no key can't be named in depends_on, so a later
+/// step reading its fields has nothing to make it wait for the unblock.
+/// Steps after a block in the same list, including inside groups, are checked.
+fn check_unkeyed_block_fields(steps: &[StepDef], config: &LintConfig, errors: &mut Vec<Error>) {
+    if config.unkeyed_block_fields.level == LintLevel::Allow {
+        return;
+    }
+    for (index, step) in steps.iter().enumerate() {
+        let (prompt, fields) = match step {
+            StepDef::Block(b) if b.key.is_none() => (&b.prompt, &b.fields),
+            StepDef::Input(i) if i.key.is_none() => (&i.prompt, &i.fields),
+            StepDef::Group(g) => {
+                check_unkeyed_block_fields(&g.steps, config, errors);
+                continue;
+            }
+            _ => continue,
+        };
+        let Some(prompt) = prompt else {
+            continue;
+        };
+        let read = steps[index + 1..].iter().find_map(|later| {
+            fields
+                .iter()
+                .find_map(|field| metadata_reader(later, field.key()))
+        });
+        if let Some((reader, field)) = read {
+            config.unkeyed_block_fields.report(
+                errors,
+                &codes::UNKEYED_BLOCK_FIELDS,
+                syn::spanned::Spanned::span(prompt),
+                format!(
+                    "{} has no key, but {} reads its field '{}' with `buildkite-agent meta-data get`. Give the {} a key and add it to that step's depends_on so the step waits for the unblock",
+                    step.describe(),
+                    reader.describe(),
+                    field,
+                    step.kind()
+                ),
+            );
+        }
+    }
+}
+
+/// The command step reading meta-data `field`: `step` itself, or a step
+/// inside it when it's a group.
+fn metadata_reader<'a, 'f>(step: &'a StepDef, field: &'f str) -> Option<(&'a StepDef, &'f str)> {
+    match step {
+        StepDef::Command(c) => c
+            .commands
+            .iter()
+            .filter_map(|command| command.command_text())
+            .flat_map(agent::calls)
+            .any(|call| call.metadata_get().is_some_and(|(key, _)| *key == field))
+            .then_some((step, field)),
+        StepDef::Group(g) => g.steps.iter().find_map(|s| metadata_reader(s, field)),
+        StepDef::Wait(_) | StepDef::Block(_) | StepDef::Input(_) | StepDef::Trigger(_) => None,
+    }
+}
+
+/// Meta-data a command reads should come from a block or input field, a
+/// `meta-data set` elsewhere in the pipeline, or `external_meta_data`; and a
+/// field should be read by some command. Only literal command text is seen.
+fn check_metadata_flow(pipeline: &PipelineDef, config: &LintConfig, errors: &mut Vec<Error>) {
+    if config.unknown_meta_data.level == LintLevel::Allow
+        && config.unread_meta_data.level == LintLevel::Allow
+    {
+        return;
+    }
+
+    let mut fields = Vec::new();
+    let mut commands = Vec::new();
+    collect_metadata_steps(&pipeline.steps, &mut fields, &mut commands);
+
+    let mut set = Vec::new();
+    let mut reads = Vec::new();
+    for (step, command) in commands {
+        let Some(text) = command.command_text() else {
+            continue;
+        };
+        for call in agent::calls(text) {
+            if let Some((key, _)) = call.metadata_set() {
+                set.push(*key);
+            }
+            if let Some((key, range)) = call.metadata_get() {
+                reads.push((step, command, *key, range.clone()));
+            }
+        }
+    }
+
+    let external: Vec<String> = pipeline
+        .external_meta_data
+        .iter()
+        .map(|lit| lit.value())
+        .collect();
+    let known: Vec<&str> = fields
+        .iter()
+        .map(|(key, _, _)| *key)
+        .chain(set.iter().copied())
+        .chain(external.iter().map(String::as_str))
+        .collect();
+    for (step, command, key, range) in &reads {
+        if known.contains(key) {
+            continue;
+        }
+        let (span, snippet) = command.locate(range.clone());
+        let suggestion = closest_matches(key, known.iter().copied(), 1)
+            .into_iter()
+            .find(|name| edit_distance(key, name) <= (key.len() / 4).max(1))
+            .map(|name| format!("\nDid you mean '{}'?", name))
+            .unwrap_or_default();
+        config.unknown_meta_data.report(
+            errors,
+            &codes::UNKNOWN_META_DATA,
+            span,
+            format!(
+                "{} reads meta-data '{}', but no field or `meta-data set` in this pipeline provides it.{}{}\n\
+                 Add a field with key: \"{}\", or declare a value set outside this pipeline: external_meta_data: [\"{}\"]",
+                step.describe(),
+                key,
+                snippet,
+                suggestion,
+                key,
+                key
+            ),
+        );
+    }
+
+    for (field, step, span) in &fields {
+        if reads.iter().any(|(_, _, key, _)| key == field) {
+            continue;
+        }
+        config.unread_meta_data.report(
+            errors,
+            &codes::UNREAD_META_DATA,
+            *span,
+            format!(
+                "{} has a field '{}' that no command reads with `buildkite-agent meta-data get {}`",
+                step.describe(),
+                field,
+                field
+            ),
+        );
+    }
+}
+
+/// Block and input field keys, with their step and the span of its prompt,
+/// and every command with its step.
+fn collect_metadata_steps<'a>(
+    steps: &'a [StepDef],
+    fields: &mut Vec<(&'a str, &'a StepDef, Span)>,
+    commands: &mut Vec<(&'a StepDef, &'a CommandValue)>,
+) {
+    for step in steps {
+        let (prompt, step_fields) = match step {
+            StepDef::Block(b) => (&b.prompt, &b.fields),
+            StepDef::Input(i) => (&i.prompt, &i.fields),
+            StepDef::Command(c) => {
+                commands.extend(c.commands.iter().map(|command| (step, command)));
+                continue;
+            }
+            StepDef::Group(g) => {
+                collect_metadata_steps(&g.steps, fields, commands);
+                continue;
+            }
+            StepDef::Wait(_) | StepDef::Trigger(_) => continue,
+        };
+        let span = prompt
+            .as_ref()
+            .map_or_else(Span::call_site, syn::spanned::Spanned::span);
+        fields.extend(step_fields.iter().map(|field| (field.key(), step, span)));
+    }
+}
+
+/// Bare identifiers in retry, matrix, notify and build values are read as
+/// strings, so a typo like `automatic: ture` reaches Buildkite as "ture".
+fn check_bare_idents(config: &LintConfig, errors: &mut Vec<Error>) {
+    let found = BARE_IDENTS.with(|b| std::mem::take(&mut *b.borrow_mut()));
+    for (field, ident) in found {
+        let lint = config.bare_ident_value.unwrap_or(Lint {
+            level: if BARE_IDENT_DENIED_FIELDS.contains(&field) {
+                LintLevel::Deny
+            } else {
+                LintLevel::Allow
+            },
+            span: Span::call_site(),
+        });
+        let name = ident.to_string();
+        let hint = match ["true", "false"]
+            .into_iter()
+            .find(|keyword| edit_distance(&name, keyword) <= 2)
+        {
+            Some(keyword) => format!(" (did you mean `{}`?)", keyword),
+            None => String::new(),
+        };
+        lint.report(
+            errors,
+            &codes::BARE_IDENT_VALUE,
+            ident.span(),
+            format!(
+                "bare identifier `{}` in {} is read as the string \"{}\"{}; write \"{}\" if a string was intended",
+                name, field, name, hint, name
+            ),
+        );
+    }
+}
+
+/// Buildkite interpolates `$VAR` in labels at upload time, and a name it
+/// doesn't know renders literally in the UI. Names must be Buildkite
+/// variables or declared in pipeline env or expect_env; an empty or
+/// unterminated `${` is always an error. Errors about a computed label
+/// point at the macro that computed it and quote the value.
+fn check_label_vars(pipeline: &PipelineDef, config: &LintConfig, errors: &mut Vec<Error>) {
+    type Label<'a> = (&'a syn::LitStr, Option<&'a ValueOrigin>);
+    fn collect<'a>(steps: &'a [StepDef], labels: &mut Vec<Label<'a>>) {
+        for step in steps {
+            labels.extend(step.label_lit().map(|label| (label, step.label_origin())));
+            if let StepDef::Group(g) = step {
+                collect(&g.steps, labels);
+            }
+        }
+    }
+
+    let mut labels = Vec::new();
+    collect(&pipeline.steps, &mut labels);
+    // nb: names from an expect_env const aren't known, so only syntax is checked
+    let declared = pipeline.env_var_sets().map(|(_, declared)| declared);
+    let buildkite_vars = expand_known_env_list("BUILDKITE_ENV").unwrap_or_default();
+    for (label, origin) in labels {
+        let text = label.value();
+        let literal = label.token();
+        // nb: a computed label's literal isn't in the source, so there's no
+        // position inside it to point at
+        let span_of = |range: std::ops::Range<usize>| match origin {
+            Some(_) => label.span(),
+            None => literal_subspan(&literal, &text, range).unwrap_or(label.span()),
+        };
+        let note = origin
+            .map(|origin| format!("\n{}", origin.describe(&text)))
+            .unwrap_or_default();
+        if let Some(range) = malformed_interpolation(&text) {
+            config.report(
+                errors,
+                &codes::MALFORMED_LABEL_INTERPOLATION,
+                span_of(range.clone()),
+                format!(
+                    "malformed `${{` in label, which Buildkite would show as written.{}\nClose it as ${{NAME}}, or write $$ for a literal dollar sign{}",
+                    caret_snippet(&text, range),
+                    note
+                ),
+            );
+            continue;
+        }
+        let Some(declared) = &declared else {
+            continue;
+        };
+        if config.unknown_label_var.level == LintLevel::Allow {
+            continue;
+        }
+        for var in interpolated_vars(&text) {
+            if declared.contains(&var) || buildkite_vars.contains(&var.as_str()) {
+                continue;
+            }
+            let range = find_var_reference(&text, &var);
+            let span = range.clone().map_or(label.span(), span_of);
+            let snippet = range.map(|r| caret_snippet(&text, r)).unwrap_or_default();
+            let candidates = declared
+                .iter()
+                .map(|s| s.as_str())
+                .chain(buildkite_vars.iter().copied());
+            let suggestion = closest_matches(&var, candidates, 1)
+                .into_iter()
+                .find(|name| edit_distance(&var, name) <= (var.len() / 4).max(1))
+                .map(|name| format!("\nDid you mean '{}'?", name))
+                .unwrap_or_default();
+            config.unknown_label_var.report(
+                errors,
+                &codes::UNKNOWN_LABEL_VAR,
+                span,
+                format!(
+                    "label references ${}, which is not a Buildkite variable or declared in env or expect_env, so it will show as written.{}{}{}",
+                    var, snippet, suggestion, note
+                ),
+            );
+        }
+    }
+}
+
+/// Parallelism on a matrix step runs every matrix combination that many
+/// times, which is almost never what was meant.
+fn check_parallelism_with_matrix(steps: &[StepDef], config: &LintConfig, errors: &mut Vec<Error>) {
+    for step in steps {
+        match step {
+            StepDef::Command(c) => {
+                if let (Some(parallelism), Some(matrix)) = (&c.parallelism, &c.matrix) {
+                    config.parallelism_with_matrix.report(
+                        errors,
+                        &codes::PARALLELISM_WITH_MATRIX,
+                        parallelism.span(),
+                        format!(
+                            "step{} sets both parallelism and matrix, so each of the {} matrix jobs runs {} times. Remove one of them",
+                            step_name(step),
+                            matrix_size(matrix),
+                            parallelism
+                        ),
+                    );
+                }
+            }
+            StepDef::Group(g) => check_parallelism_with_matrix(&g.steps, config, errors),
+            StepDef::Wait(_) | StepDef::Block(_) | StepDef::Input(_) | StepDef::Trigger(_) => {}
+        }
+    }
+}
+
+/// Cross-check BUILDKITE_PARALLEL_JOB usage against parallelism. Both vars are
+/// in BUILDKITE_ENV, so regular env validation accepts them on any step.
+fn check_parallel_job_vars(steps: &[StepDef], config: &LintConfig, errors: &mut Vec<Error>) {
+    for step in steps {
+        match step {
+            StepDef::Command(c) => {
+                let mut shards = false;
+                for command in &c.commands {
+                    let Some(text) = command.command_text() else {
+                        // Dynamic commands can't be inspected; assume they shard
+                        shards = true;
+                        continue;
+                    };
+                    let used = interpolated_vars(text)
+                        .into_iter()
+                        .find(|var| PARALLEL_JOB_VARS.contains(&var.as_str()));
+                    if SHARDING_TOOLS.iter().any(|tool| text.contains(tool)) {
+                        shards = true;
+                    }
+                    let Some(var) = used else {
+                        continue;
+                    };
+                    shards = true;
+                    if c.parallelism.is_none() {
+                        let (span, _) = command.locate_var(&var);
+                        config.parallel_vars_without_parallelism.report(
+                            errors,
+                            &codes::PARALLEL_VARS_WITHOUT_PARALLELISM,
+                            span,
+                            format!(
+                                "command uses ${} but its step{} has no parallelism, so the variable will be empty. Set parallelism on the step",
+                                var,
+                                step_name(step)
+                            ),
+                        );
+                    }
+                }
+                if let Some(parallelism) = &c.parallelism
+                    && !shards
+                {
+                    config.parallelism_without_sharding.report(
+                        errors,
+                        &codes::PARALLELISM_WITHOUT_SHARDING,
+                        parallelism.span(),
+                        format!(
+                            "step{} runs {} parallel jobs but its commands never read $BUILDKITE_PARALLEL_JOB or $BUILDKITE_PARALLEL_JOB_COUNT, so every job does the same work",
+                            step_name(step),
+                            parallelism
+                        ),
+                    );
+                }
+            }
+            StepDef::Group(g) => check_parallel_job_vars(&g.steps, config, errors),
+            StepDef::Wait(_) | StepDef::Block(_) | StepDef::Input(_) | StepDef::Trigger(_) => {}
+        }
+    }
+}
+
+/// A hung bazel test holds its agent until Buildkite's own timeout, so test
+/// steps should bound themselves with `timeout_in_minutes` or `--test_timeout`.
+/// Group `defaults` are applied at parse time and count as the step's own.
+fn check_bazel_test_timeout(steps: &[StepDef], config: &LintConfig, errors: &mut Vec<Error>) {
+    if config.bazel_test_timeout.level == LintLevel::Allow {
+        return;
+    }
+    for step in steps {
+        match step {
+            StepDef::Command(c) => {
+                if c.timeout_in_minutes.is_some() {
+                    continue;
+                }
+                for command in &c.commands {
+                    // Dynamic flags may carry --test_timeout; they can't be inspected
+                    let Some(text) = command.command_text() else {
+                        continue;
+                    };
+                    if !runs_bazel_tests(command.is_bazel(), text)
+                        || text.contains("--test_timeout")
+                    {
+                        continue;
+                    }
+                    config.bazel_test_timeout.report(
+                        errors,
+                        &codes::BAZEL_TEST_TIMEOUT,
+                        command.span(),
+                        format!(
+                            "bazel test step{} has no timeout, so a hung test blocks its agent. Set timeout_in_minutes on the step or pass --test_timeout",
+                            step_name(step)
+                        ),
+                    );
+                    break;
+                }
+            }
+            StepDef::Group(g) => check_bazel_test_timeout(&g.steps, config, errors),
+            StepDef::Wait(_) | StepDef::Block(_) | StepDef::Input(_) | StepDef::Trigger(_) => {}
+        }
+    }
+}
+
+/// What makes two bazel commands do the same work: workspace, verb, sorted
+/// options, arguments after `--`, the labels their patterns expanded to, and
+/// the env and agents of their steps.
+#[cfg(feature = "bazel")]
+#[derive(PartialEq)]
+struct BazelWork<'a> {
+    workspace: Option<&'a str>,
+    verb: &'a str,
+    options: Vec<&'a str>,
+    trailing: Vec<&'a str>,
+    labels: &'a BTreeSet<String>,
+    env: Vec<(&'a str, String)>,
+    agents: Vec<(&'a str, String)>,
+}
+
+/// Flag bazel commands that repeat another step's work. `expanded` holds the
+/// labels each command's patterns matched in the pipeline-wide target query,
+/// so `//app/...` and `//app:all //app/lib:all` compare by what they build.
+#[cfg(feature = "bazel")]
+pub fn check_duplicate_bazel_targets(
+    steps: &[StepDef],
+    expanded: &[(&BazelExpr, BTreeSet<String>)],
+    config: &LintConfig,
+    errors: &mut Vec<Error>,
+) {
+    fn command_steps<'a>(steps: &'a [StepDef], out: &mut Vec<&'a StepDef>) {
+        for step in steps {
+            match step {
+                StepDef::Command(_) => out.push(step),
+                StepDef::Group(g) => command_steps(&g.steps, out),
+                StepDef::Wait(_) | StepDef::Block(_) | StepDef::Input(_) | StepDef::Trigger(_) => {}
+            }
+        }
+    }
+
+    if config.duplicate_bazel_targets.level == LintLevel::Allow {
+        return;
+    }
+    let mut all = Vec::new();
+    command_steps(steps, &mut all);
+
+    let mut seen: Vec<(BazelWork, usize)> = Vec::new();
+    for (expr, labels) in expanded {
+        let Some((index, StepDef::Command(step))) =
+            all.iter().copied().enumerate().find(|(_, s)| {
+                matches!(s, StepDef::Command(c) if c.commands.iter().any(|cmd| {
+                    cmd.pending_bazel_checks().is_some_and(|e| std::ptr::eq(e, *expr))
+                }))
+            })
+        else {
+            continue;
+        };
+        if labels.is_empty() {
+            continue;
+        }
+
+        let args = expr.args();
+        let split = args.iter().position(|a| *a == "--").unwrap_or(args.len());
+        let mut options: Vec<&str> = args[..split]
+            .iter()
+            .filter(|a| a.starts_with('-'))
+            .copied()
+            .collect();
+        options.sort_unstable();
+        options.dedup();
+        let mut env: Vec<(&str, String)> = step
+            .env
+            .iter()
+            .map(|(name, value)| {
+                let value = match value {
+                    DynamicValue::Literal(s) | DynamicValue::Computed(s, _) => s.clone(),
+                    DynamicValue::Comptime(e) | DynamicValue::Runtime(e) => {
+                        e.to_token_stream().to_string()
+                    }
+                };
+                (name.as_str(), value)
+            })
+            .collect();
+        env.sort();
+        let mut agents: Vec<(&str, String)> = step
+            .agents
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.value()))
+            .collect();
+        agents.sort();
+        let work = BazelWork {
+            workspace: expr.workspace_dir.as_deref(),
+            verb: &expr.verb,
+            options,
+            trailing: args[split..].to_vec(),
+            labels,
+            env,
+            agents,
+        };
+
+        match seen.iter().find(|(other, other_index)| *other == work && *other_index != index) {
+            Some((_, other_index)) => config.duplicate_bazel_targets.report(
+                errors,
+                &codes::DUPLICATE_BAZEL_TARGETS,
+                expr.span,
+                format!(
+                    "step {} runs `bazel {}` over the same {} targets with the same flags as step {}. Remove one of them or merge them into a single step",
+                    describe(all[index], index),
+                    expr.verb,
+                    labels.len(),
+                    describe(all[*other_index], *other_index)
+                ),
+            ),
+            None => seen.push((work, index)),
+        }
+    }
+}
+
+/// Whether a command runs `bazel test` or `bazel coverage`. Text of a parsed
+/// bazel command starts at the verb; a shell command starts with the binary,
+/// possibly followed by startup options.
+fn runs_bazel_tests(is_bazel: bool, text: &str) -> bool {
+    let mut words = text.split_whitespace();
+    if !is_bazel {
+        let Some(program) = words.next() else {
+            return false;
+        };
+        let program = program.rsplit('/').next().unwrap_or(program);
+        if !matches!(program, "bazel" | "bazelisk") {
+            return false;
+        }
+    }
+    words
+        .find(|word| !word.starts_with('-'))
+        .is_some_and(|verb| BAZEL_TEST_VERBS.contains(&verb))
+}
+
+/// Number of jobs a step expands to. Groups contribute their nested steps;
+/// command steps multiply by parallelism and statically known matrix size.
+fn expansion(step: &StepDef) -> usize {
+    match step {
+        StepDef::Command(c) => {
+            let parallelism = c
+                .parallelism
+                .as_ref()
+                .and_then(|p| p.base10_parse::<usize>().ok())
+                .unwrap_or(1)
+                .max(1);
+            let matrix = c.matrix.as_ref().map(matrix_size).unwrap_or(1);
+            parallelism.saturating_mul(matrix)
+        }
+        StepDef::Group(g) => g.steps.iter().map(expansion).sum(),
+        StepDef::Wait(_) | StepDef::Block(_) | StepDef::Input(_) | StepDef::Trigger(_) => 1,
+    }
+}
+
+/// Combinations in a matrix: the list length for a simple matrix, or the
+/// product of the `setup` dimensions. Adjustments are not counted.
+fn matrix_size(matrix: &NestedValue) -> usize {
+    let setup = match matrix {
+        NestedValue::Array(items) => return items.len().max(1),
+        NestedValue::Object(pairs) => pairs.iter().find(|(k, _)| k == "setup").map(|(_, v)| v),
+        NestedValue::String(_) | NestedValue::Int(_) | NestedValue::Bool(_) => None,
+    };
+    match setup {
+        Some(NestedValue::Array(items)) => items.len().max(1),
+        Some(NestedValue::Object(dimensions)) => dimensions
+            .iter()
+            .map(|(_, values)| match values {
+                NestedValue::Array(items) => items.len().max(1),
+                NestedValue::String(_)
+                | NestedValue::Int(_)
+                | NestedValue::Bool(_)
+                | NestedValue::Object(_) => 1,
+            })
+            .fold(1, usize::saturating_mul),
+        Some(NestedValue::String(_) | NestedValue::Int(_) | NestedValue::Bool(_)) | None => 1,
+    }
+}
+
+/// Human-readable name for a step in messages: its key, else its label.
+fn describe(step: &StepDef, index: usize) -> String {
+    if let Some((key, _)) = step.get_key() {
+        return format!("'{}'", key);
+    }
+    let label = match step {
+        StepDef::Command(c) => c.label.as_ref(),
+        StepDef::Trigger(t) => t.label.as_ref(),
+        StepDef::Group(g) => g.label.as_ref(),
+        StepDef::Wait(_) | StepDef::Block(_) | StepDef::Input(_) => None,
+    };
+    match label {
+        Some(syn::Expr::Lit(syn::ExprLit {
+            lit: syn::Lit::Str(s),
+            ..
+        })) => format!("'{}'", s.value()),
+        _ => format!("step #{}", index + 1),
+    }
+}
+
+/// Top three steps by expansion factor, formatted for an error message.
+fn top_contributors(steps: &[StepDef]) -> String {
+    let mut factors: Vec<(usize, String)> = steps
+        .iter()
+        .enumerate()
+        .map(|(i, step)| (expansion(step), describe(step, i)))
+        .collect();
+    factors.sort_by(|a, b| b.0.cmp(&a.0));
+    factors
+        .into_iter()
+        .take(3)
+        .map(|(count, name)| {
+            let noun = if count == 1 { "step" } else { "steps" };
+            format!("{} ({} {})", name, count, noun)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Enforce the pipeline step limit (warning at 80%) and the optional per-group limit.
+fn check_step_count(steps: &[StepDef], config: &LintConfig, errors: &mut Vec<Error>) {
+    if let Some(max_group) = config.max_group_steps {
+        for step in steps {
+            check_group_count(step, config, max_group, errors);
+        }
+    }
+
+    let total: usize = steps.iter().map(expansion).sum();
+    let limit = config.max_steps;
+    if total > limit {
+        config.report(
+            errors,
+            &codes::STEP_LIMIT,
+            config.max_steps_span,
+            format!(
+                "pipeline expands to {} steps, which exceeds the limit of {}. Biggest contributors: {}. Raise the limit with `lints: {{ max_steps: N }}` if your organization allows more",
+                total,
+                limit,
+                top_contributors(steps)
+            ),
+        );
+        return;
+    }
+    // Close to the limit only warns, and `allow` silences it with the error
+    if total.saturating_mul(5) >= limit.saturating_mul(4)
+        && config.level(&codes::STEP_LIMIT) != LintLevel::Allow
+    {
+        diag::warn(
+            config.max_steps_span,
+            format!(
+                "[{}] pipeline expands to {} steps, over 80% of the limit of {}. Biggest contributors: {}",
+                codes::STEP_LIMIT.id,
+                total,
+                limit,
+                top_contributors(steps)
+            ),
+        );
+    }
+}
+
+fn check_group_count(
+    step: &StepDef,
+    config: &LintConfig,
+    max_group: usize,
+    errors: &mut Vec<Error>,
+) {
+    let StepDef::Group(g) = step else {
+        return;
+    };
+    let total: usize = g.steps.iter().map(expansion).sum();
+    if total > max_group {
+        let span = g
+            .label
+            .as_ref()
+            .map(syn::spanned::Spanned::span)
+            .unwrap_or_else(Span::call_site);
+        config.report(
+            errors,
+            &codes::GROUP_STEP_LIMIT,
+            span,
+            format!(
+                "group expands to {} steps, which exceeds max_group_steps of {}. Biggest contributors: {}",
+                total,
+                max_group,
+                top_contributors(&g.steps)
+            ),
+        );
+        return;
+    }
+    for nested in &g.steps {
+        check_group_count(nested, config, max_group, errors);
+    }
+}
@@ -0,0 +1,591 @@
+//! Plugin configs written as YAML: `.plugin_yaml(name, yaml)` and
+//! `plugins_yaml:`.
+//!
+//! Deeply nested plugin configs are easier to paste from a plugin's README
+//! than to transliterate into braces. The YAML is parsed during expansion
+//! into the same [`NestedValue`] the braced syntax produces, so the emitted
+//! JSON is identical. Syntax errors point at the line within the literal.
+//!
+//! When the workspace has a `plugin-schemas/<plugin>/plugin.yml`, the config
+//! is checked against that file's `configuration` schema, which covers the
+//! JSON Schema keywords plugins use in practice: `type`, `enum`, `properties`,
+//! `required`, `additionalProperties`, `items`, `minItems`, `minimum`,
+//! `maximum`, `anyOf` and `oneOf`. Other keywords are ignored.
+//!
+//! Plugins named by a relative path (`./.buildkite/plugins/deploy`) live in
+//! the repository, so the pipeline checks that the directory and its
+//! plugin.yml exist, wherever the plugin was written.
+
+use crate::{NestedValue, caret_snippet, closest_matches, edit_distance, literal_subspan};
+use proc_macro2::Span;
+use serde_yaml::Value;
+use std::path::{Path, PathBuf};
+use syn::parse::ParseStream;
+use syn::{Error, LitStr, Result, Token, braced};
+
+/// Directory, searched for from the script directory upwards, holding
+/// `<plugin>/plugin.yml` files
+pub const SCHEMA_DIR: &str = "plugin-schemas";
+
+/// Parse `("name#version", r#"...yaml..."#)` into a one-plugin object.
+pub fn parse_plugin(input: ParseStream) -> Result<NestedValue> {
+    let name: LitStr = input.parse()?;
+    input.parse::<Token![,]>()?;
+    let yaml: LitStr = input.parse()?;
+    let config = load(&yaml)?;
+    if config.is_null() {
+        return Err(Error::new(
+            yaml.span(),
+            "plugin config is empty; use plugin(name, {}) for a plugin without config",
+        ));
+    }
+    check_schema(&name.value(), &config, &yaml)?;
+    Ok(NestedValue::Object(vec![(
+        name.value(),
+        to_nested(&config, "config", &yaml)?,
+    )]))
+}
+
+/// Whether `name` is a plugin checked out with the repository rather than
+/// one fetched by name.
+pub fn is_local(name: &str) -> bool {
+    name.starts_with("./") || name.starts_with("../")
+}
+
+/// The name of `plugin` when it's a local path.
+pub fn local_path(plugin: &NestedValue) -> Option<&str> {
+    let name = match plugin {
+        NestedValue::String(name) => name,
+        NestedValue::Object(pairs) => &pairs.first()?.0,
+        _ => return None,
+    };
+    is_local(name).then_some(name.as_str())
+}
+
+/// The span of the plugin name at the front of `input`: the key of a braced
+/// `{ "name": config }` entry, or the next token otherwise.
+pub fn key_span(input: ParseStream) -> Span {
+    let fork = input.fork();
+    let key = (|| -> Result<Span> {
+        let content;
+        braced!(content in fork);
+        Ok(content.span())
+    })();
+    key.unwrap_or_else(|_| input.span())
+}
+
+/// Parse a YAML list of plugins, as written under `plugins:` in pipeline.yml.
+/// Each plugin comes with the span of its name within the literal.
+pub fn parse_plugin_list(input: ParseStream) -> Result<Vec<(NestedValue, Span)>> {
+    let yaml: LitStr = input.parse()?;
+    let Value::Sequence(items) = load(&yaml)? else {
+        return Err(Error::new(
+            yaml.span(),
+            "plugins_yaml must be a YAML list, e.g. `- docker#v5.12.0:` followed by its config",
+        ));
+    };
+    let text = yaml.value();
+    let name_span = |name: &str| {
+        text.find(name)
+            .and_then(|start| literal_subspan(&yaml.token(), &text, start..start + name.len()))
+            .unwrap_or(yaml.span())
+    };
+    let mut plugins = Vec::new();
+    for item in &items {
+        match item {
+            Value::String(name) => {
+                plugins.push((NestedValue::String(name.clone()), name_span(name)))
+            }
+            Value::Mapping(map) if map.len() == 1 => {
+                let Some((Value::String(name), config)) = map.iter().next() else {
+                    return Err(Error::new(yaml.span(), "plugin names must be strings"));
+                };
+                if config.is_null() {
+                    // nb: `- name:` with nothing after it is the same as `- name`
+                    plugins.push((NestedValue::String(name.clone()), name_span(name)));
+                    continue;
+                }
+                check_schema(name, config, &yaml)?;
+                let path = format!("`{}`", name);
+                plugins.push((
+                    NestedValue::Object(vec![(name.clone(), to_nested(config, &path, &yaml)?)]),
+                    name_span(name),
+                ));
+            }
+            _ => {
+                return Err(Error::new(
+                    yaml.span(),
+                    "each plugins_yaml entry must be a plugin name, or a single `name: config` mapping",
+                ));
+            }
+        }
+    }
+    Ok(plugins)
+}
+
+/// Parse the literal as YAML, pointing syntax errors at their line.
+fn load(yaml: &LitStr) -> Result<Value> {
+    let text = yaml.value();
+    serde_yaml::from_str(&text).map_err(|e| {
+        let Some(location) = e.location() else {
+            return Error::new(yaml.span(), format!("invalid YAML: {}", e));
+        };
+        let index = location.index().min(text.len().saturating_sub(1));
+        let range = index..(index + 1).min(text.len());
+        let span = literal_subspan(&yaml.token(), &text, range.clone()).unwrap_or(yaml.span());
+        // nb: serde_yaml repeats the location at the end of its message
+        let message = e.to_string();
+        let message = message
+            .split(" at line ")
+            .next()
+            .unwrap_or(&message)
+            .to_string();
+        Error::new(
+            span,
+            format!(
+                "invalid YAML at line {}, column {} of the literal: {}{}",
+                location.line(),
+                location.column(),
+                message,
+                caret_snippet(&text, range)
+            ),
+        )
+    })
+}
+
+/// Convert parsed YAML to the braced syntax's value tree. `path` names the
+/// value in errors.
+fn to_nested(value: &Value, path: &str, yaml: &LitStr) -> Result<NestedValue> {
+    let unsupported = |what: &str| {
+        Error::new(
+            yaml.span(),
+            format!(
+                "{} is {}, which plugin configs can't hold; quote it to pass it as a string",
+                path, what
+            ),
+        )
+    };
+    match value {
+        Value::String(s) => Ok(NestedValue::String(s.clone())),
+        Value::Bool(b) => Ok(NestedValue::Bool(*b)),
+        Value::Number(n) => n
+            .as_i64()
+            .map(NestedValue::Int)
+            .ok_or_else(|| unsupported("a non-integer number")),
+        Value::Null => Err(unsupported("null")),
+        Value::Sequence(items) => items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| to_nested(item, &format!("{}[{}]", path, i), yaml))
+            .collect::<Result<Vec<_>>>()
+            .map(NestedValue::Array),
+        Value::Mapping(map) => {
+            let mut pairs = Vec::new();
+            for (key, value) in map {
+                let Value::String(key) = key else {
+                    return Err(Error::new(
+                        yaml.span(),
+                        format!("{} has a key that isn't a string", path),
+                    ));
+                };
+                let value = to_nested(value, &format!("{}.{}", path, key), yaml)?;
+                pairs.push((key.clone(), value));
+            }
+            Ok(NestedValue::Object(pairs))
+        }
+        Value::Tagged(_) => Err(unsupported("a tagged value")),
+    }
+}
+
+/// The directory of plugin.yml files for the code being compiled.
+fn schema_dir() -> Option<PathBuf> {
+    let start = std::env::var("RUST_SCRIPT_BASE_PATH")
+        .or_else(|_| std::env::var("CARGO_MANIFEST_DIR"))
+        .ok()?;
+    find_schema_dir(Path::new(&start))
+}
+
+fn find_schema_dir(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .map(|dir| dir.join(SCHEMA_DIR))
+        .find(|dir| dir.is_dir())
+}
+
+/// The directory name of a plugin reference: `docker-compose#v4.16.0` is
+/// `docker-compose`, `my-org/deploy#v1` is `my-org/deploy`, and a repository
+/// URL is its last path segment.
+fn plugin_dir_name(name: &str) -> &str {
+    let name = name.split('#').next().unwrap_or(name);
+    if name.contains("://") || name.contains('@') {
+        let last = name.rsplit(['/', ':']).next().unwrap_or(name);
+        last.strip_suffix(".git").unwrap_or(last)
+    } else {
+        name
+    }
+}
+
+/// Check `config` against the plugin's schema, when the workspace has one.
+fn check_schema(name: &str, config: &Value, yaml: &LitStr) -> Result<()> {
+    let Some(dir) = schema_dir() else {
+        return Ok(());
+    };
+    check_schema_in(&dir, name, config, yaml)
+}
+
+fn check_schema_in(dir: &Path, name: &str, config: &Value, yaml: &LitStr) -> Result<()> {
+    let path = dir.join(plugin_dir_name(name)).join("plugin.yml");
+    crate::track::track_file(&path);
+    let Ok(contents) = std::fs::read_to_string(&path) else {
+        return Ok(());
+    };
+    let plugin: Value = serde_yaml::from_str(&contents).map_err(|e| {
+        Error::new(
+            yaml.span(),
+            format!("could not parse {}: {}", path.display(), e),
+        )
+    })?;
+    let Some(schema) = plugin.get("configuration") else {
+        return Ok(());
+    };
+    let mut problems = Vec::new();
+    validate(schema, config, "config", &mut problems);
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(Error::new(
+        yaml.span(),
+        format!(
+            "{} config doesn't match the schema in {}:\n{}",
+            name,
+            path.display(),
+            problems
+                .iter()
+                .map(|p| format!("  - {}", p))
+                .collect::<Vec<_>>()
+                .join("\n")
+        ),
+    ))
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_i64() || n.is_u64() => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Sequence(_) => "array",
+        Value::Mapping(_) => "object",
+        Value::Tagged(_) => "tagged value",
+    }
+}
+
+fn has_type(value: &Value, ty: &str) -> bool {
+    let actual = kind(value);
+    actual == ty || (ty == "number" && actual == "integer")
+}
+
+/// Record where `value` breaks `schema`, naming values by `path`.
+fn validate(schema: &Value, value: &Value, path: &str, problems: &mut Vec<String>) {
+    if !schema.is_mapping() {
+        return;
+    }
+
+    let types: Vec<&str> = match schema.get("type") {
+        Some(Value::String(ty)) => vec![ty.as_str()],
+        Some(Value::Sequence(tys)) => tys.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+    if !types.is_empty() && !types.iter().any(|ty| has_type(value, ty)) {
+        problems.push(format!(
+            "{}: expected {}, found {}",
+            path,
+            types.join(" or "),
+            kind(value)
+        ));
+        return;
+    }
+
+    if let Some(Value::Sequence(allowed)) = schema.get("enum")
+        && !allowed.contains(value)
+    {
+        let allowed: Vec<String> = allowed.iter().map(render).collect();
+        problems.push(format!(
+            "{}: {} is not one of {}",
+            path,
+            render(value),
+            allowed.join(", ")
+        ));
+    }
+
+    for keyword in ["anyOf", "oneOf"] {
+        if let Some(Value::Sequence(options)) = schema.get(keyword) {
+            let matches = options.iter().any(|option| {
+                let mut scratch = Vec::new();
+                validate(option, value, path, &mut scratch);
+                scratch.is_empty()
+            });
+            if !matches {
+                problems.push(format!("{}: matches none of the allowed forms", path));
+            }
+        }
+    }
+
+    if let Value::Number(n) = value
+        && let Some(n) = n.as_f64()
+    {
+        if let Some(min) = schema.get("minimum").and_then(Value::as_f64)
+            && n < min
+        {
+            problems.push(format!("{}: {} is less than the minimum {}", path, n, min));
+        }
+        if let Some(max) = schema.get("maximum").and_then(Value::as_f64)
+            && n > max
+        {
+            problems.push(format!("{}: {} is more than the maximum {}", path, n, max));
+        }
+    }
+
+    if let Value::Sequence(items) = value {
+        if let Some(min) = schema.get("minItems").and_then(Value::as_u64)
+            && (items.len() as u64) < min
+        {
+            problems.push(format!("{}: needs at least {} items", path, min));
+        }
+        if let Some(item_schema) = schema.get("items") {
+            for (i, item) in items.iter().enumerate() {
+                validate(item_schema, item, &format!("{}[{}]", path, i), problems);
+            }
+        }
+    }
+
+    if let Value::Mapping(map) = value {
+        let properties = schema.get("properties").and_then(Value::as_mapping);
+        if let Some(Value::Sequence(required)) = schema.get("required") {
+            for key in required.iter().filter_map(Value::as_str) {
+                if !map.contains_key(key) {
+                    problems.push(format!("{}: missing required property `{}`", path, key));
+                }
+            }
+        }
+        let closed = schema.get("additionalProperties") == Some(&Value::Bool(false));
+        for (key, item) in map {
+            let Some(key) = key.as_str() else {
+                continue;
+            };
+            let item_path = format!("{}.{}", path, key);
+            match properties.and_then(|p| p.get(key)) {
+                Some(item_schema) => validate(item_schema, item, &item_path, problems),
+                None if closed => {
+                    let known = properties
+                        .into_iter()
+                        .flat_map(|p| p.keys())
+                        .filter_map(Value::as_str);
+                    let suggestion = closest_matches(key, known, 1)
+                        .into_iter()
+                        .find(|name| edit_distance(key, name) <= (key.len() / 3).max(1))
+                        .map(|name| format!(" (did you mean `{}`?)", name))
+                        .unwrap_or_default();
+                    problems.push(format!(
+                        "{}: unknown property `{}`{}",
+                        path, key, suggestion
+                    ));
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+fn render(value: &Value) -> String {
+    match value {
+        Value::String(s) => format!("{:?}", s),
+        other => serde_yaml::to_string(other)
+            .map(|s| s.trim_end().to_string())
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lit(text: &str) -> LitStr {
+        LitStr::new(text, proc_macro2::Span::call_site())
+    }
+
+    fn plugin(source: &str) -> Result<NestedValue> {
+        syn::parse::Parser::parse_str(parse_plugin, source)
+    }
+
+    #[test]
+    fn test_yaml_matches_braced_syntax() {
+        let from_yaml = plugin(
+            r##""docker-compose#v4.16.0", r#"
+run: app
+config:
+  - docker-compose.yml
+  - docker-compose.ci.yml
+env: [CI]
+mount-buildkite-agent: true
+retries: 2
+"#"##,
+        )
+        .unwrap();
+        let braced = syn::parse::Parser::parse_str(
+            NestedValue::parse,
+            r#"{ "docker-compose#v4.16.0": {
+                run: "app",
+                config: ["docker-compose.yml", "docker-compose.ci.yml"],
+                env: ["CI"],
+                "mount-buildkite-agent": true,
+                retries: 2
+            } }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            from_yaml.to_json_tokens().to_string(),
+            braced.to_json_tokens().to_string()
+        );
+    }
+
+    #[test]
+    fn test_syntax_error_has_line() {
+        let err = plugin(r#""docker#v5", "image: app\ncommand: a: b\n""#)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("invalid YAML at line 2"), "{}", err);
+        assert!(err.contains("mapping values are not allowed"), "{}", err);
+        assert!(err.contains("command: a: b"), "{}", err);
+    }
+
+    #[test]
+    fn test_rejects_values_braces_cannot_express() {
+        let err = plugin(r#""docker#v5", "image: app\nratio: 0.5""#)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(
+            err.contains("config.ratio is a non-integer number"),
+            "{}",
+            err
+        );
+        let err = plugin(r#""docker#v5", "image:""#)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("config.image is null"), "{}", err);
+        assert!(plugin(r#""docker#v5", """#).is_err());
+    }
+
+    #[test]
+    fn test_plugin_list() {
+        let plugins = syn::parse::Parser::parse_str(
+            parse_plugin_list,
+            r#""- docker-login#v2.1.0\n- docker#v5.12.0:\n    image: node\n- ecr#v2.9.0:\n""#,
+        )
+        .unwrap();
+        assert_eq!(plugins.len(), 3);
+        assert!(matches!(&plugins[0].0, NestedValue::String(s) if s == "docker-login#v2.1.0"));
+        assert!(
+            matches!(&plugins[1].0, NestedValue::Object(pairs) if pairs[0].0 == "docker#v5.12.0")
+        );
+        assert!(matches!(&plugins[2].0, NestedValue::String(s) if s == "ecr#v2.9.0"));
+
+        let err = syn::parse::Parser::parse_str(parse_plugin_list, r#""image: node""#)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("must be a YAML list"));
+    }
+
+    #[test]
+    fn test_local_path() {
+        let local = |source: &str| {
+            let plugin = syn::parse::Parser::parse_str(NestedValue::parse, source).unwrap();
+            local_path(&plugin).map(str::to_string)
+        };
+        assert_eq!(
+            local(r#"{ "./.buildkite/plugins/deploy": { env: "prod" } }"#).as_deref(),
+            Some("./.buildkite/plugins/deploy")
+        );
+        assert_eq!(
+            local(r#""../shared/plugins/lint""#).as_deref(),
+            Some("../shared/plugins/lint")
+        );
+        assert_eq!(local(r#"{ "docker#v5.12.0": { image: "node" } }"#), None);
+        assert_eq!(local(r#""my-org/deploy#v1""#), None);
+    }
+
+    #[test]
+    fn test_plugin_dir_name() {
+        assert_eq!(plugin_dir_name("docker-compose#v4.16.0"), "docker-compose");
+        assert_eq!(plugin_dir_name("my-org/deploy#v1"), "my-org/deploy");
+        assert_eq!(
+            plugin_dir_name("ssh://git@github.com/my-org/deploy-buildkite-plugin.git#v1"),
+            "deploy-buildkite-plugin"
+        );
+        assert_eq!(plugin_dir_name("docker"), "docker");
+    }
+
+    #[test]
+    fn test_schema_validation() {
+        let dir = tempfile::tempdir().unwrap();
+        let schemas = dir.path().join(SCHEMA_DIR);
+        std::fs::create_dir_all(schemas.join("docker-compose")).unwrap();
+        std::fs::write(
+            schemas.join("docker-compose/plugin.yml"),
+            r#"
+name: Docker Compose
+configuration:
+  properties:
+    run: { type: string }
+    config: { type: [string, array], items: { type: string } }
+    retries: { type: integer, minimum: 0 }
+    pull-policy: { enum: [always, missing] }
+  required: [run]
+  additionalProperties: false
+"#,
+        )
+        .unwrap();
+        let nested = dir.path().join("ci/pipelines");
+        std::fs::create_dir_all(&nested).unwrap();
+        assert_eq!(find_schema_dir(&nested), Some(schemas.clone()));
+
+        let check = |yaml: &str| {
+            let config: Value = serde_yaml::from_str(yaml).unwrap();
+            check_schema_in(&schemas, "docker-compose#v4.16.0", &config, &lit(yaml))
+        };
+        check("run: app\nconfig: [a.yml]\nretries: 2").unwrap();
+        let err = check("config: 3\nretries: -1\npull_policy: always\npull-policy: never")
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("missing required property `run`"), "{}", err);
+        assert!(
+            err.contains("config.config: expected string or array, found integer"),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains("config.retries: -1 is less than the minimum 0"),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains("unknown property `pull_policy` (did you mean `pull-policy`?)"),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains(r#"config.pull-policy: "never" is not one of "always", "missing""#),
+            "{}",
+            err
+        );
+
+        // plugins without a schema file aren't checked
+        let config: Value = serde_yaml::from_str("anything: 1").unwrap();
+        check_schema_in(&schemas, "docker#v5", &config, &lit("anything: 1")).unwrap();
+    }
+}
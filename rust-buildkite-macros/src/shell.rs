@@ -0,0 +1,494 @@
+//! `comptime_shell!`: run a shell command while the pipeline expands.
+//!
+//! `comptime_shell!("git rev-parse --short HEAD")` runs `sh -c` and stands
+//! for its trimmed stdout, both as the standalone macro and as a value inside
+//! `pipeline!`. Both go through [`ShellCommand`], so they accept the same
+//! options:
+//!
+//! - `cwd: "..."`: the directory to run in. `"manifest_dir"` is the script's
+//!   directory (`RUST_SCRIPT_BASE_PATH`, else `CARGO_MANIFEST_DIR`), the base
+//!   `path_base` is relative to. `"workspace_root"` is the Bazel workspace
+//!   with the `bazel` feature, else the Cargo workspace holding the script.
+//!   Anything else is a path relative to the script's directory, like
+//!   `path_base`.
+//! - `env: { NAME: "value" }`: variables added to the command's environment.
+//! - `expect: "..."`: what the output must look like. `"single_line"`, the
+//!   default, is exactly one non-empty line; `"non_empty"` is any output but
+//!   nothing; `"lines"` makes the output a list of its non-blank lines, for
+//!   list positions such as `expect_env` and `matrix`.
+//!
+//! Without `cwd` the command runs wherever rustc was started, which differs
+//! between cargo and rust-script.
+//!
+//! Output spliced into a bazel command is checked once the command is
+//! assembled (see [`Spliced`]), since the agent runs it through a shell.
+
+use proc_macro2::Span;
+use std::path::{Path, PathBuf};
+use syn::parse::{Parse, ParseStream};
+use syn::{Error, Ident, LitStr, Result, Token, braced};
+
+/// Options after the command string
+const OPTIONS: &[&str] = &["cwd", "env", "expect"];
+
+/// Values of `expect`
+const EXPECTATIONS: &[&str] = &["single_line", "non_empty", "lines"];
+
+/// Output lines quoted in an unmet-expectation error before the rest are
+/// only counted
+const QUOTED_LINES: usize = 5;
+
+/// Characters that change a command's structure when output holding them is
+/// spliced in unquoted
+#[cfg(feature = "bazel")]
+const METACHARACTERS: &[char] = &[
+    ';', '&', '|', '<', '>', '(', ')', '`', '$', '\'', '"', '\\', '\n',
+];
+
+/// What `expect` requires of the output.
+#[derive(Clone, Copy, PartialEq)]
+enum Expect {
+    SingleLine,
+    NonEmpty,
+    Lines,
+}
+
+/// A parsed `comptime_shell!` invocation.
+pub struct ShellCommand {
+    command: LitStr,
+    cwd: Option<LitStr>,
+    env: Vec<(String, LitStr)>,
+    expect: Expect,
+    expect_span: Option<Span>,
+}
+
+/// Whether `input` starts with a `comptime_shell!(...)` invocation.
+pub fn peek(input: ParseStream) -> bool {
+    input.peek(Ident)
+        && input.peek2(Token![!])
+        && input
+            .fork()
+            .parse::<Ident>()
+            .is_ok_and(|name| name == "comptime_shell")
+}
+
+/// Parse a `comptime_shell!(...)` invocation; see [`peek`].
+pub fn parse_invocation(input: ParseStream) -> Result<ShellCommand> {
+    input.parse::<syn::Macro>()?.parse_body()
+}
+
+impl Parse for ShellCommand {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let command: LitStr = input.parse().map_err(|e| {
+            Error::new(
+                e.span(),
+                "comptime_shell! requires a string literal command",
+            )
+        })?;
+        let mut cwd = None;
+        let mut env = Vec::new();
+        let mut expect = Expect::SingleLine;
+        let mut expect_span = None;
+        let mut seen: Vec<String> = Vec::new();
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+            let option: Ident = input.parse()?;
+            let name = option.to_string();
+            if seen.contains(&name) {
+                return Err(Error::new(
+                    option.span(),
+                    format!("{} is set more than once", name),
+                ));
+            }
+            seen.push(name.clone());
+            input.parse::<Token![:]>()?;
+            match name.as_str() {
+                "cwd" => cwd = Some(input.parse()?),
+                "expect" => {
+                    let lit: LitStr = input.parse()?;
+                    expect = match lit.value().as_str() {
+                        "single_line" => Expect::SingleLine,
+                        "non_empty" => Expect::NonEmpty,
+                        "lines" => Expect::Lines,
+                        other => {
+                            return Err(Error::new(
+                                lit.span(),
+                                crate::fields::unknown(
+                                    "comptime_shell! expectation",
+                                    other,
+                                    EXPECTATIONS.iter().copied(),
+                                ),
+                            ));
+                        }
+                    };
+                    expect_span = Some(lit.span());
+                }
+                "env" => {
+                    let content;
+                    braced!(content in input);
+                    while !content.is_empty() {
+                        let key = crate::EnvKey::parse(&content)?;
+                        content.parse::<Token![:]>()?;
+                        env.push((key.to_string(), content.parse()?));
+                        if !content.is_empty() {
+                            content.parse::<Token![,]>()?;
+                        }
+                    }
+                }
+                _ => {
+                    return Err(Error::new(
+                        option.span(),
+                        crate::fields::unknown(
+                            "comptime_shell! option",
+                            &name,
+                            OPTIONS.iter().copied(),
+                        ),
+                    ));
+                }
+            }
+        }
+        Ok(Self {
+            command,
+            cwd,
+            env,
+            expect,
+            expect_span,
+        })
+    }
+}
+
+impl ShellCommand {
+    /// Whether the output is a list (`expect: "lines"`).
+    pub fn is_list(&self) -> bool {
+        self.expect == Expect::Lines
+    }
+
+    /// Run the command for a single value: its trimmed stdout, checked
+    /// against `expect`.
+    pub fn run(&self) -> Result<String> {
+        if self.is_list() {
+            return Err(Error::new(
+                self.expect_span.unwrap_or(self.command.span()),
+                "expect: \"lines\" gives a list, but a single value is needed here; \
+                 use it where a list is accepted, such as expect_env or matrix",
+            ));
+        }
+        let output = self.output()?;
+        let lines: Vec<&str> = output.lines().collect();
+        let problem = match (self.expect, lines.len()) {
+            (_, 0) => Some("printed nothing"),
+            (Expect::SingleLine, n) if n > 1 => Some("printed more than one line"),
+            _ => None,
+        };
+        match problem {
+            Some(problem) => Err(self.unexpected(problem, &lines)),
+            None => Ok(output),
+        }
+    }
+
+    /// Run the command for a list: each non-blank line of output with
+    /// `expect: "lines"`, otherwise the single value [`run`](Self::run) gives.
+    pub fn run_lines(&self) -> Result<Vec<String>> {
+        if !self.is_list() {
+            return Ok(vec![self.run()?]);
+        }
+        Ok(self
+            .output()?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Error for output that doesn't meet `expect`, quoting what was printed.
+    fn unexpected(&self, problem: &str, lines: &[&str]) -> Error {
+        let wanted = match self.expect {
+            Expect::SingleLine => {
+                "a single line (expect: \"non_empty\" allows several, expect: \"lines\" makes a list)"
+            }
+            _ => "some output",
+        };
+        let mut message = format!("comptime_shell! {}, expected {}", problem, wanted);
+        if !lines.is_empty() {
+            let quoted: Vec<String> = lines
+                .iter()
+                .take(QUOTED_LINES)
+                .map(|line| format!("{:?}", line))
+                .collect();
+            message.push_str(&format!("; got {}", quoted.join(", ")));
+            if lines.len() > QUOTED_LINES {
+                message.push_str(&format!(" and {} more", lines.len() - QUOTED_LINES));
+            }
+        }
+        Error::new(self.command.span(), message)
+    }
+
+    /// Run the command and return its trimmed stdout, or an error at the
+    /// command literal naming the directory it ran in when `cwd` was set.
+    fn output(&self) -> Result<String> {
+        let mut process = std::process::Command::new("sh");
+        process.arg("-c").arg(self.command.value());
+        let cwd = self.cwd.as_ref().map(resolve_cwd).transpose()?;
+        if let Some(dir) = &cwd {
+            process.current_dir(dir);
+        }
+        for (name, value) in &self.env {
+            process.env(name, value.value());
+        }
+        let location = cwd
+            .map(|dir| format!(" in {}", dir.display()))
+            .unwrap_or_default();
+
+        match process.output() {
+            Ok(out) if out.status.success() => {
+                Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+            }
+            Ok(out) => {
+                let stderr = String::from_utf8_lossy(&out.stderr);
+                let code = out.status.code().unwrap_or(-1);
+                Err(Error::new(
+                    self.command.span(),
+                    format!(
+                        "Command failed{} (exit {}): {}",
+                        location,
+                        code,
+                        stderr.trim()
+                    ),
+                ))
+            }
+            Err(e) => Err(Error::new(
+                self.command.span(),
+                format!("Failed to run command{}: {}", location, e),
+            )),
+        }
+    }
+}
+
+/// `comptime_shell!` output spliced into a bazel command's target patterns,
+/// flags or args.
+#[cfg(feature = "bazel")]
+pub struct Spliced {
+    pub output: String,
+    pub span: Span,
+    /// From `target_patterns`, which query verbs quote as a whole
+    pub target: bool,
+}
+
+#[cfg(feature = "bazel")]
+impl Spliced {
+    /// Check the output against `command`, the command after substitution:
+    /// bashrs must accept the command, and output that isn't quoted as a
+    /// whole may not hold shell metacharacters, which would run as shell
+    /// syntax rather than reach bazel as arguments.
+    pub fn check(&self, command: &str, is_query: bool) -> Result<()> {
+        if let Err((message, _)) =
+            crate::CmdExpr::lint_with_bashrs(command, crate::StepShell::Bash).check(&[])
+        {
+            return Err(Error::new(
+                self.span,
+                format!(
+                    "comptime_shell! output {:?} makes an invalid command `{}`: {}",
+                    self.output, command, message
+                ),
+            ));
+        }
+        if !(self.target && is_query)
+            && let Some(c) = self.output.chars().find(|c| METACHARACTERS.contains(c))
+        {
+            return Err(Error::new(
+                self.span,
+                format!(
+                    "comptime_shell! output {:?} contains {:?}, which the shell would \
+                     interpret in `{}`; print only bazel arguments",
+                    self.output, c, command
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The directory the pipeline script lives in, which `path_base` and
+/// relative `cwd`s are resolved against.
+pub fn script_dir() -> PathBuf {
+    std::env::var("RUST_SCRIPT_BASE_PATH")
+        .or_else(|_| std::env::var("CARGO_MANIFEST_DIR"))
+        .map(PathBuf::from)
+        .unwrap_or_default()
+}
+
+/// The directory `cwd` names. It must exist, since `sh` would otherwise fail
+/// with an error that doesn't say which directory was meant.
+fn resolve_cwd(cwd: &LitStr) -> Result<PathBuf> {
+    let dir = match cwd.value().as_str() {
+        "manifest_dir" => script_dir(),
+        "workspace_root" => workspace_root(),
+        path => script_dir().join(path),
+    };
+    if !dir.is_dir() {
+        return Err(Error::new(
+            cwd.span(),
+            format!(
+                "comptime_shell! working directory {} does not exist",
+                dir.display()
+            ),
+        ));
+    }
+    Ok(dir)
+}
+
+/// The Bazel workspace when the `bazel` feature finds one, otherwise the
+/// nearest directory above the script whose Cargo.toml has a `[workspace]`,
+/// otherwise the script's directory.
+fn workspace_root() -> PathBuf {
+    #[cfg(feature = "bazel")]
+    if let Ok(workspace) = crate::bazel::find_bazel_workspace_from_env() {
+        return workspace;
+    }
+    let start = script_dir();
+    start
+        .ancestors()
+        .find(|dir| is_cargo_workspace(dir))
+        .map(Path::to_path_buf)
+        .unwrap_or(start)
+}
+
+fn is_cargo_workspace(dir: &Path) -> bool {
+    std::fs::read_to_string(dir.join("Cargo.toml"))
+        .is_ok_and(|manifest| manifest.lines().any(|line| line.trim() == "[workspace]"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shell(source: &str) -> Result<String> {
+        syn::parse_str::<ShellCommand>(source)?.run()
+    }
+
+    #[cfg(feature = "bazel")]
+    fn spliced(output: &str, target: bool) -> Spliced {
+        Spliced {
+            output: output.to_string(),
+            span: Span::call_site(),
+            target,
+        }
+    }
+
+    #[test]
+    fn test_runs_in_cwd_with_env() {
+        assert_eq!(shell(r#""pwd", cwd: "/""#).unwrap(), "/");
+        assert_eq!(
+            shell(r#""echo $GREETING-$OTHER", env: { GREETING: "hi", "OTHER": "there" },"#)
+                .unwrap(),
+            "hi-there"
+        );
+        let root = shell(r#""pwd", cwd: "workspace_root""#).unwrap();
+        assert!(Path::new(&root).join("Cargo.toml").exists(), "{}", root);
+        assert_eq!(
+            shell(r#""pwd", cwd: "manifest_dir""#).unwrap(),
+            script_dir().to_string_lossy()
+        );
+    }
+
+    #[test]
+    fn test_failures_name_the_directory() {
+        let err = shell(r#""echo nope >&2; exit 2", cwd: "/""#)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "Command failed in / (exit 2): nope");
+        let err = shell(r#""exit 2""#).unwrap_err().to_string();
+        assert_eq!(err, "Command failed (exit 2): ");
+        let err = shell(r#""pwd", cwd: "no/such/dir""#)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("no/such/dir does not exist"), "{}", err);
+    }
+
+    #[test]
+    fn test_expectations() {
+        assert_eq!(shell(r#""echo main""#).unwrap(), "main");
+        let err = shell(r#""true""#).unwrap_err().to_string();
+        assert!(err.contains("printed nothing"), "{}", err);
+        let err = shell(r#""printf 'a\nb\n'""#).unwrap_err().to_string();
+        assert!(
+            err.contains(r#"printed more than one line, expected a single line"#)
+                && err.ends_with(r#"got "a", "b""#),
+            "{}",
+            err
+        );
+        let err = shell(r#""seq 7""#).unwrap_err().to_string();
+        assert!(err.ends_with(r#""5" and 2 more"#), "{}", err);
+
+        assert_eq!(
+            shell(r#""printf 'a\nb'", expect: "non_empty""#).unwrap(),
+            "a\nb"
+        );
+        let err = shell(r#""true", expect: "non_empty""#)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("printed nothing"), "{}", err);
+
+        let lines = |source: &str| syn::parse_str::<ShellCommand>(source)?.run_lines();
+        assert_eq!(
+            lines(r#""printf 'a\n\n  b  \n'", expect: "lines""#).unwrap(),
+            ["a", "b"]
+        );
+        assert!(lines(r#""true", expect: "lines""#).unwrap().is_empty());
+        assert_eq!(lines(r#""echo one""#).unwrap(), ["one"]);
+        let err = shell(r#""echo a", expect: "lines""#)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("gives a list"), "{}", err);
+    }
+
+    #[test]
+    #[cfg(feature = "bazel")]
+    fn test_spliced_output_is_checked() {
+        assert!(
+            spliced("//app:test --jobs=4", false)
+                .check("bazel test //app:test --jobs=4", false)
+                .is_ok()
+        );
+        let err = spliced("//app:test; rm -rf /", false)
+            .check("bazel test //app:test; rm -rf /", false)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("contains ';'"), "{}", err);
+        let err = spliced("--define=name=it's", false)
+            .check("bazel build --define=name=it's //...", false)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("it's"), "{}", err);
+        let err = spliced(r#"--define=name="x""#, false)
+            .check(r#"bazel build --define=name="x" //..."#, false)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains(r#"contains '"'"#), "{}", err);
+        // Query expressions are quoted as a whole
+        assert!(
+            spliced(r#"attr("tags", "manual", //...)"#, true)
+                .check(r#"bazel query 'attr("tags", "manual", //...)'"#, true)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_rejects_bad_options() {
+        let err = |source: &str| {
+            syn::parse_str::<ShellCommand>(source)
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert!(err(r#""pwd", cdw: "/""#).contains("did you mean 'cwd'?"));
+        assert!(err(r#""pwd", cwd: "/", cwd: "/tmp""#).contains("more than once"));
+        assert!(err(r#""pwd", env: { "A B": "x" }"#).contains("whitespace"));
+        assert!(err("pwd").contains("requires a string literal command"));
+        assert!(err(r#""pwd", expect: "single""#).contains("did you mean 'single_line'?"));
+    }
+}
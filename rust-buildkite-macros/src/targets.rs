@@ -2,6 +2,7 @@
 
 pub use rust_buildkite_validation::bazel::labels::{
     extract_targets_from_args, get_current_package, is_external_repo, is_wildcard_pattern,
-    should_skip_validation as should_skip_fast_validation,
+    resolve_label, should_skip_validation as should_skip_fast_validation, split_run_args,
+    validate_run_target,
 };
-pub use rust_buildkite_validation::bazel::targets::validate_target_exists;
+pub use rust_buildkite_validation::bazel::targets::{find_build_file, validate_target_exists};
@@ -0,0 +1,196 @@
+//! Expansion timing report, enabled with `RUST_BUILDKITE_PROFILE=1`.
+//!
+//! `pipeline!` times its phases into a [`Timings`] that is passed through
+//! `PipelineDef::generate`, and prints a table to stderr once expansion is
+//! done. Shell linting is listed per command as well, since one slow command
+//! usually explains a slow pipeline. When the variable is unset, [`Timings`]
+//! records nothing and each phase runs as if it weren't wrapped.
+
+use proc_macro2::Span;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// Environment variable that enables the report
+pub const ENV: &str = "RUST_BUILDKITE_PROFILE";
+
+/// How many of the slowest commands to list
+const WORST_COMMANDS: usize = 5;
+
+/// Phase durations for one expansion.
+pub struct Timings {
+    enabled: bool,
+    /// Phases in the order they first ran, with their accumulated time
+    phases: Vec<(&'static str, Duration)>,
+    /// Shell lint time per command
+    commands: Vec<(String, Duration)>,
+}
+
+impl Timings {
+    pub fn from_env() -> Self {
+        Self {
+            enabled: std::env::var(ENV).is_ok_and(|v| v == "1"),
+            phases: Vec::new(),
+            commands: Vec::new(),
+        }
+    }
+
+    /// Timings that record nothing, for tests that call `generate` directly.
+    #[cfg(test)]
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            phases: Vec::new(),
+            commands: Vec::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Run `f`, adding its duration to `phase`.
+    pub fn time<T>(&mut self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+        if !self.enabled {
+            return f();
+        }
+        let start = Instant::now();
+        let out = f();
+        self.record(phase, start.elapsed());
+        out
+    }
+
+    /// Add `elapsed` to `phase`.
+    pub fn record(&mut self, phase: &'static str, elapsed: Duration) {
+        if !self.enabled {
+            return;
+        }
+        match self.phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += elapsed,
+            None => self.phases.push((phase, elapsed)),
+        }
+    }
+
+    /// Record how long each command took to lint, for the slowest-commands
+    /// list. Commands are linted in parallel, so these don't add up to the
+    /// shell linting phase.
+    pub fn record_commands(&mut self, commands: Vec<(String, Duration)>) {
+        if self.enabled {
+            self.commands = commands;
+        }
+    }
+
+    /// The summary table, or `None` when profiling is off.
+    pub fn report(&self, span: Span) -> Option<String> {
+        if !self.enabled {
+            return None;
+        }
+        let total: Duration = self.phases.iter().map(|(_, d)| *d).sum();
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "rust-buildkite: expansion profile for {}:{}",
+            span.file(),
+            span.start().line
+        );
+        for (phase, elapsed) in &self.phases {
+            let _ = writeln!(out, "  {:<20} {:>10}", phase, format_duration(*elapsed));
+        }
+        let _ = writeln!(out, "  {:<20} {:>10}", "total", format_duration(total));
+
+        let mut worst: Vec<&(String, Duration)> =
+            self.commands.iter().filter(|(_, d)| !d.is_zero()).collect();
+        worst.sort_by(|a, b| b.1.cmp(&a.1));
+        if !worst.is_empty() {
+            let _ = writeln!(out, "  slowest commands to lint:");
+            for (command, elapsed) in worst.into_iter().take(WORST_COMMANDS) {
+                let _ = writeln!(
+                    out,
+                    "    {:>10}  {}",
+                    format_duration(*elapsed),
+                    truncate(command, 60)
+                );
+            }
+        }
+        Some(out)
+    }
+
+    /// Print the report to stderr when profiling is on.
+    pub fn finish(&self, span: Span) {
+        if let Some(report) = self.report(span) {
+            eprint!("{}", report);
+        }
+    }
+}
+
+fn format_duration(d: Duration) -> String {
+    format!("{:.2}ms", d.as_secs_f64() * 1000.0)
+}
+
+/// The first line of `command`, cut to `max` chars.
+fn truncate(command: &str, max: usize) -> String {
+    let line = command.lines().next().unwrap_or_default();
+    if line.chars().count() <= max && line.len() == command.len() {
+        return line.to_string();
+    }
+    let cut: String = line.chars().take(max).collect();
+    format!("{}...", cut)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled() -> Timings {
+        Timings {
+            enabled: true,
+            phases: Vec::new(),
+            commands: Vec::new(),
+        }
+    }
+
+    /// The duration in the report row for `phase`.
+    fn row<'a>(report: &'a str, phase: &str) -> Option<&'a str> {
+        report
+            .lines()
+            .find_map(|line| line.trim_start().strip_prefix(phase)?.strip_prefix(' '))
+            .map(str::trim)
+    }
+
+    #[test]
+    fn test_disabled_records_nothing() {
+        let mut timings = Timings::disabled();
+        assert_eq!(timings.time("parsing", || 7), 7);
+        timings.record("parsing", Duration::from_millis(3));
+        timings.record_commands(vec![("make".to_string(), Duration::from_millis(1))]);
+        assert!(timings.phases.is_empty());
+        assert!(timings.commands.is_empty());
+        assert!(timings.report(Span::call_site()).is_none());
+    }
+
+    #[test]
+    fn test_report_lists_phases_and_slowest_commands() {
+        let mut timings = enabled();
+        timings.record("parsing", Duration::from_millis(4));
+        timings.record("shell linting", Duration::from_millis(6));
+        timings.record("path checks", Duration::from_millis(2));
+        timings.record("path checks", Duration::from_millis(1));
+        timings.record_commands(vec![
+            ("cargo build".to_string(), Duration::from_millis(2)),
+            (
+                "cargo test\necho done".to_string(),
+                Duration::from_millis(4),
+            ),
+            ("cached".to_string(), Duration::ZERO),
+        ]);
+
+        let report = timings.report(Span::call_site()).unwrap();
+        assert_eq!(row(&report, "parsing"), Some("4.00ms"), "{}", report);
+        assert_eq!(row(&report, "path checks"), Some("3.00ms"), "{}", report);
+        assert_eq!(row(&report, "shell linting"), Some("6.00ms"), "{}", report);
+        assert_eq!(row(&report, "total"), Some("13.00ms"), "{}", report);
+        let test = report.find("cargo test...").unwrap();
+        let build = report.find("cargo build").unwrap();
+        assert!(test < build, "{}", report);
+        assert!(!report.contains("cached"), "{}", report);
+    }
+}
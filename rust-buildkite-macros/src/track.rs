@@ -0,0 +1,101 @@
+//! Rebuild tracking for files read during expansion.
+//!
+//! Validation reads files the compiler doesn't know about: script paths
+//! checked for existence, the workspace config file, bazel BUILD and bazelrc
+//! files. Without tracking, editing one of them leaves the old expansion (and
+//! its validation result) in place until a clean build.
+//!
+//! Code that touches the filesystem calls [`track_file`]; the expansion
+//! drains the queue with [`take`]. On stable each file becomes a dead
+//! `include_bytes!`, which cargo records as a dependency of the crate. With
+//! the `nightly` feature, files are registered through
+//! `proc_macro::tracked_path` instead.
+
+use proc_macro2::TokenStream as TokenStream2;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+thread_local! {
+    static TRACKED: RefCell<BTreeSet<PathBuf>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+/// Make the current expansion depend on `path`. Directories and missing
+/// files are ignored, since neither can be included.
+pub fn track_file(path: &Path) {
+    if !path.is_file() {
+        return;
+    }
+    let Ok(path) = path.canonicalize() else {
+        return;
+    };
+    TRACKED.with(|t| t.borrow_mut().insert(path));
+}
+
+/// Drop tracked files, e.g. when expansion fails and only errors are shown.
+pub fn clear() {
+    TRACKED.with(|t| t.borrow_mut().clear());
+}
+
+fn drain() -> BTreeSet<PathBuf> {
+    TRACKED.with(|t| std::mem::take(&mut *t.borrow_mut()))
+}
+
+/// Register tracked files, returning any tokens the expansion must include.
+#[cfg(not(feature = "nightly"))]
+pub fn take() -> TokenStream2 {
+    drain()
+        .into_iter()
+        .map(|path| {
+            let path = path.to_string_lossy().into_owned();
+            quote::quote! { const _: &[u8] = include_bytes!(#path); }
+        })
+        .collect()
+}
+
+/// Register tracked files, returning any tokens the expansion must include.
+#[cfg(feature = "nightly")]
+pub fn take() -> TokenStream2 {
+    for path in drain() {
+        proc_macro::tracked_path::path(path.to_string_lossy());
+    }
+    TokenStream2::new()
+}
+
+#[cfg(all(test, not(feature = "nightly")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracked_files_become_include_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("deploy.sh");
+        std::fs::write(&script, "#!/bin/sh\n").unwrap();
+
+        clear();
+        track_file(&script);
+        track_file(&script);
+        track_file(dir.path());
+        track_file(&dir.path().join("missing.sh"));
+
+        let tokens = take().to_string();
+        let canonical = script.canonicalize().unwrap();
+        assert_eq!(tokens.matches("include_bytes").count(), 1);
+        assert!(tokens.contains(&format!("{:?}", canonical.to_string_lossy())));
+        assert!(take().is_empty());
+    }
+
+    #[test]
+    fn path_validation_tracks_scripts() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("deploy.sh");
+        std::fs::write(&script, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        clear();
+        crate::CmdExpr::validate_path_exists("./deploy.sh", &[], Some(dir.path())).unwrap();
+        assert!(take().to_string().contains("deploy.sh"));
+    }
+}
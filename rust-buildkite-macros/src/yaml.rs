@@ -0,0 +1,292 @@
+//! Compile-time YAML emission for `emit_yaml`.
+//!
+//! Token generation records every builder call the macro expands to together
+//! with the value that call sets (see [`Fields`]), and `emit_yaml` serializes
+//! those values with serde_yaml, so the file holds the pipeline the macro
+//! builds. Only fully static pipelines can be emitted; a value computed at
+//! runtime has nothing to write, and emitting it is a compile error.
+
+use crate::{CommandSource, CommandValue, DynamicValue, KeyValue, NestedValue, SkipValue};
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use serde_yaml::Mapping;
+use syn::{Error, LitStr, Result};
+
+pub use serde_yaml::Value;
+
+/// Resolve the `emit_yaml` path. Absolute paths are used as-is; relative paths
+/// go under `OUT_DIR` when the crate has a build script, otherwise under the
+/// workspace root (`RUST_SCRIPT_BASE_PATH` or `CARGO_MANIFEST_DIR`).
+pub fn resolve_output_path(path: &str) -> std::path::PathBuf {
+    use std::path::PathBuf;
+
+    let path = PathBuf::from(path);
+    if path.is_absolute() {
+        return path;
+    }
+    let base = std::env::var("OUT_DIR")
+        .or_else(|_| std::env::var("RUST_SCRIPT_BASE_PATH"))
+        .or_else(|_| std::env::var("CARGO_MANIFEST_DIR"))
+        .map(PathBuf::from)
+        .unwrap_or_default();
+    base.join(path)
+}
+
+/// Serialize the pipeline and write it to the `emit_yaml` target.
+pub fn emit(pipeline: &Value, target: &LitStr) -> Result<()> {
+    let rendered = serde_yaml::to_string(pipeline).map_err(|e| {
+        Error::new(
+            target.span(),
+            format!("emit_yaml: failed to serialize the pipeline: {}", e),
+        )
+    })?;
+    let path = resolve_output_path(&target.value());
+
+    if let Some(parent) = path.parent()
+        && !parent.as_os_str().is_empty()
+        && let Err(e) = std::fs::create_dir_all(parent)
+    {
+        return Err(Error::new(
+            target.span(),
+            format!(
+                "emit_yaml: failed to create directory '{}': {}",
+                parent.display(),
+                e
+            ),
+        ));
+    }
+    std::fs::write(&path, rendered).map_err(|e| {
+        Error::new(
+            target.span(),
+            format!("emit_yaml: failed to write '{}': {}", path.display(), e),
+        )
+    })
+}
+
+/// A step, field or pipeline as the macro expands it: the expression that
+/// builds it and the value `emit_yaml` writes for it.
+pub struct Expanded {
+    pub tokens: TokenStream2,
+    pub value: Result<Value>,
+}
+
+/// The fields one builder sets, in the order the generated code sets them.
+/// Each builder call is recorded with the value it sets, so a field can't
+/// reach the built pipeline without reaching the emitted file too.
+#[derive(Default)]
+pub struct Fields {
+    calls: TokenStream2,
+    values: Vec<(&'static str, Result<Value>)>,
+}
+
+impl Fields {
+    /// Record `call`, the builder call that sets `name` to `value`.
+    pub fn set(&mut self, name: &'static str, value: Result<Value>, call: TokenStream2) {
+        self.calls.extend(call);
+        self.values.push((name, value));
+    }
+
+    /// The recorded builder calls, and the mapping of the values they set.
+    pub fn finish(self) -> (TokenStream2, Result<Value>) {
+        let value = self
+            .values
+            .into_iter()
+            .map(|(name, value)| Ok((Value::from(name), value?)))
+            .collect::<Result<Mapping>>()
+            .map(Value::Mapping);
+        (self.calls, value)
+    }
+}
+
+/// Split expanded items into their expressions and the sequence of their values.
+pub fn sequence(items: impl IntoIterator<Item = Expanded>) -> (Vec<TokenStream2>, Result<Value>) {
+    let (tokens, values): (Vec<_>, Vec<_>) = items
+        .into_iter()
+        .map(|item| (item.tokens, item.value))
+        .unzip();
+    let value = values
+        .into_iter()
+        .collect::<Result<Vec<_>>>()
+        .map(Value::Sequence);
+    (tokens, value)
+}
+
+/// A mapping of string keys, failing on the first value that is computed at runtime.
+pub fn mapping(pairs: impl IntoIterator<Item = (String, Result<Value>)>) -> Result<Value> {
+    pairs
+        .into_iter()
+        .map(|(key, value)| Ok((Value::String(key), value?)))
+        .collect::<Result<Mapping>>()
+        .map(Value::Mapping)
+}
+
+pub fn dynamic_error(span: Span, what: &str) -> Error {
+    Error::new(
+        span,
+        format!(
+            "emit_yaml requires a fully static pipeline, but {} is computed at runtime",
+            what
+        ),
+    )
+}
+
+pub fn string(s: &str) -> Value {
+    Value::String(s.to_string())
+}
+
+pub fn strings<'a>(items: impl IntoIterator<Item = &'a str>) -> Value {
+    Value::Sequence(items.into_iter().map(string).collect())
+}
+
+pub fn lit_strs(items: &[LitStr]) -> Value {
+    Value::Sequence(items.iter().map(|l| Value::String(l.value())).collect())
+}
+
+pub fn lit_int(lit: &syn::LitInt) -> Result<Value> {
+    Ok(Value::from(lit.base10_parse::<i64>()?))
+}
+
+/// Text the builder takes as an expression, such as a label or prompt. It
+/// is static when it is a plain string literal.
+pub fn label(expr: &syn::Expr, what: &str) -> Result<Value> {
+    if let syn::Expr::Lit(syn::ExprLit {
+        lit: syn::Lit::Str(s),
+        ..
+    }) = expr
+    {
+        Ok(Value::String(s.value()))
+    } else {
+        Err(dynamic_error(syn::spanned::Spanned::span(expr), what))
+    }
+}
+
+pub fn key(key: &KeyValue) -> Result<Value> {
+    match key {
+        KeyValue::Literal(s, _) => Ok(string(s)),
+        KeyValue::Runtime(expr) => Err(dynamic_error(
+            syn::spanned::Spanned::span(expr.as_ref()),
+            "the step key",
+        )),
+    }
+}
+
+fn literal(value: &DynamicValue, what: &str) -> Result<String> {
+    match value {
+        DynamicValue::Literal(s) | DynamicValue::Computed(s, _) => Ok(s.clone()),
+        DynamicValue::Comptime(expr) | DynamicValue::Runtime(expr) => {
+            Err(dynamic_error(syn::spanned::Spanned::span(expr), what))
+        }
+    }
+}
+
+pub fn dynamic(value: &DynamicValue, what: &str) -> Result<Value> {
+    literal(value, what).map(Value::String)
+}
+
+pub fn depends_on(deps: &[(String, Span)]) -> Value {
+    strings(deps.iter().map(|(d, _)| d.as_str()))
+}
+
+pub fn skip(value: &SkipValue) -> Value {
+    match value {
+        SkipValue::Bool(b) => Value::Bool(*b),
+        SkipValue::Reason(reason) => string(reason),
+    }
+}
+
+/// The value `NestedValue::to_json_tokens` builds.
+pub fn nested(value: &NestedValue) -> Value {
+    match value {
+        NestedValue::String(s) => string(s),
+        NestedValue::Int(i) => Value::from(*i),
+        NestedValue::Bool(b) => Value::Bool(*b),
+        NestedValue::Object(pairs) => nested_mapping(pairs),
+        NestedValue::Array(items) => Value::Sequence(items.iter().map(nested).collect()),
+    }
+}
+
+pub fn nested_mapping(pairs: &[(String, NestedValue)]) -> Value {
+    Value::Mapping(pairs.iter().map(|(k, v)| (string(k), nested(v))).collect())
+}
+
+/// The command line a command step runs. Bazel commands are written the way
+/// `get_command_string` assembles them, once every part is known.
+pub fn command(command: &CommandValue) -> Result<Value> {
+    match &command.0 {
+        CommandSource::Shell(cmd) => cmd
+            .static_text()
+            .map(Value::String)
+            .ok_or_else(|| dynamic_error(cmd.span, "the command")),
+        CommandSource::Runtime { span, .. } => Err(dynamic_error(*span, "the command")),
+        #[cfg(feature = "bazel")]
+        CommandSource::Bazel(_) => Ok(Value::String(command.get_command_string())),
+        #[cfg(feature = "bazel")]
+        CommandSource::DynamicBazel {
+            flags,
+            target,
+            args,
+            ..
+        } => {
+            let parts = flags
+                .iter()
+                .map(|f| (f, "a bazel flag"))
+                .chain(target.iter().map(|t| (t, "a bazel target")))
+                .chain(args.iter().map(|a| (a, "a bazel argument")));
+            for (value, what) in parts {
+                literal(value, what)?;
+            }
+            Ok(Value::String(command.get_command_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quote::quote;
+
+    #[test]
+    fn fields_pair_builder_calls_with_values() {
+        let mut fields = Fields::default();
+        fields.set("key", Ok(string("build")), quote! { .key("build") });
+        fields.set(
+            "soft_fail",
+            Ok(Value::Bool(true)),
+            quote! { .soft_fail(true) },
+        );
+        let (calls, value) = fields.finish();
+        assert_eq!(
+            calls.to_string(),
+            quote! { .key("build") .soft_fail(true) }.to_string()
+        );
+        assert_eq!(
+            serde_yaml::to_string(&value.unwrap()).unwrap(),
+            "key: build\nsoft_fail: true\n"
+        );
+    }
+
+    #[test]
+    fn runtime_value_fails_only_the_emitted_value() {
+        let mut fields = Fields::default();
+        fields.set(
+            "label",
+            Err(dynamic_error(Span::call_site(), "the step label")),
+            quote! { .label(name) },
+        );
+        let (calls, value) = fields.finish();
+        assert!(!calls.is_empty());
+        let err = value.unwrap_err().to_string();
+        assert!(
+            err.contains("the step label is computed at runtime"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn strings_that_look_like_other_scalars_stay_strings() {
+        let texts = ["true", "3", "", "a: b", "line\nbreak", "*"];
+        let yaml = serde_yaml::to_string(&strings(texts)).unwrap();
+        let parsed: Vec<String> = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(parsed, texts);
+    }
+}
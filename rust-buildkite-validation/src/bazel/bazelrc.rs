@@ -0,0 +1,214 @@
+//! Config names and option values defined in a workspace's bazelrc files.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+/// rc files Bazel reads from the workspace root.
+const WORKSPACE_RC_FILES: &[&str] = &[".bazelrc", "tools/bazel.rc"];
+
+/// Collect every `--config` name defined by the workspace's rc files,
+/// following `import` and `try-import` lines. Missing files are skipped.
+pub fn config_names(workspace: &Path) -> BTreeSet<String> {
+    collect_all(workspace, |contents| parse_rc(contents).0)
+}
+
+/// Collect every value the workspace's rc files give any of `options`, e.g.
+/// the CPUs in `build:arm --cpu=aarch64`, following imports.
+pub fn option_values(workspace: &Path, options: &[&str]) -> BTreeSet<String> {
+    collect_all(workspace, |contents| {
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.starts_with('#'))
+            .flat_map(|line| {
+                let words: Vec<&str> = line.split_whitespace().skip(1).collect();
+                super::flags::option_values(&words, options)
+            })
+            .collect()
+    })
+}
+
+/// Every rc file that exists among the workspace's rc files and the files
+/// they import.
+pub fn rc_files(workspace: &Path) -> Vec<PathBuf> {
+    let mut values = BTreeSet::new();
+    let mut visited = Vec::new();
+    for file in WORKSPACE_RC_FILES {
+        collect(
+            &workspace.join(file),
+            workspace,
+            &|_: &str| Vec::new(),
+            &mut values,
+            &mut visited,
+        );
+    }
+    visited.retain(|path| path.is_file());
+    visited
+}
+
+fn collect_all(workspace: &Path, extract: impl Fn(&str) -> Vec<String>) -> BTreeSet<String> {
+    let mut values = BTreeSet::new();
+    let mut visited = Vec::new();
+    for file in WORKSPACE_RC_FILES {
+        collect(
+            &workspace.join(file),
+            workspace,
+            &extract,
+            &mut values,
+            &mut visited,
+        );
+    }
+    values
+}
+
+fn collect(
+    path: &Path,
+    workspace: &Path,
+    extract: &impl Fn(&str) -> Vec<String>,
+    values: &mut BTreeSet<String>,
+    visited: &mut Vec<PathBuf>,
+) {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    if visited.contains(&path) {
+        return;
+    }
+    visited.push(path.clone());
+    let Ok(contents) = std::fs::read_to_string(&path) else {
+        return;
+    };
+    values.extend(extract(&contents));
+    let (_, imports) = parse_rc(&contents);
+    for import in imports {
+        let resolved = import.replace("%workspace%", &workspace.to_string_lossy());
+        let resolved = Path::new(&resolved);
+        if resolved.is_absolute() {
+            collect(resolved, workspace, extract, values, visited);
+        } else {
+            collect(
+                &workspace.join(resolved),
+                workspace,
+                extract,
+                values,
+                visited,
+            );
+        }
+    }
+}
+
+/// Split rc file contents into the config names it defines (`build:remote ...`
+/// defines `remote`) and the paths it imports.
+pub fn parse_rc(contents: &str) -> (Vec<String>, Vec<String>) {
+    let mut names = Vec::new();
+    let mut imports = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            continue;
+        };
+        match command {
+            "import" | "try-import" => {
+                if let Some(path) = words.next() {
+                    imports.push(path.trim_matches('"').to_string());
+                }
+            }
+            _ => {
+                if let Some((_, name)) = command.split_once(':')
+                    && !name.is_empty()
+                    && !names.iter().any(|n| n == name)
+                {
+                    names.push(name.to_string());
+                }
+            }
+        }
+    }
+    (names, imports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_rc() {
+        let (names, imports) = parse_rc(
+            "# comment\n\
+             build --jobs=8\n\
+             build:remote --remote_executor=grpc://x\n\
+             test:remote --test_output=errors\n\
+             common:ci --announce_rc\n\
+             try-import %workspace%/user.bazelrc\n",
+        );
+        assert_eq!(names, vec!["remote", "ci"]);
+        assert_eq!(imports, vec!["%workspace%/user.bazelrc"]);
+    }
+
+    #[test]
+    fn test_config_names_follows_imports() {
+        let dir = tempdir().unwrap();
+        std::fs::write(
+            dir.path().join(".bazelrc"),
+            "build:remote --jobs=50\nimport %workspace%/ci.bazelrc\ntry-import %workspace%/missing.bazelrc\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("ci.bazelrc"),
+            "build:ci --config=remote\nimport %workspace%/.bazelrc\n",
+        )
+        .unwrap();
+        std::fs::create_dir(dir.path().join("tools")).unwrap();
+        std::fs::write(dir.path().join("tools/bazel.rc"), "test:debug -c dbg\n").unwrap();
+
+        let names = config_names(dir.path());
+        let names: Vec<&str> = names.iter().map(|s| s.as_str()).collect();
+        assert_eq!(names, vec!["ci", "debug", "remote"]);
+    }
+
+    #[test]
+    fn test_option_values_follows_imports() {
+        let dir = tempdir().unwrap();
+        std::fs::write(
+            dir.path().join(".bazelrc"),
+            "build:arm --cpu=aarch64\n# build --cpu=commented\nimport %workspace%/ci.bazelrc\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("ci.bazelrc"),
+            "build:ci --host_cpu rv64gc\n",
+        )
+        .unwrap();
+
+        let cpus = option_values(dir.path(), &["cpu", "host_cpu"]);
+        let cpus: Vec<&str> = cpus.iter().map(|s| s.as_str()).collect();
+        assert_eq!(cpus, vec!["aarch64", "rv64gc"]);
+    }
+
+    #[test]
+    fn test_config_names_without_rc_files() {
+        let dir = tempdir().unwrap();
+        assert!(config_names(dir.path()).is_empty());
+    }
+
+    #[test]
+    fn test_rc_files_follows_imports() {
+        let dir = tempdir().unwrap();
+        std::fs::write(
+            dir.path().join(".bazelrc"),
+            "import %workspace%/ci.bazelrc\ntry-import %workspace%/user.bazelrc\n",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("ci.bazelrc"), "build:ci -c opt\n").unwrap();
+
+        let files = rc_files(dir.path());
+        let names: Vec<String> = files
+            .iter()
+            .filter_map(|path| path.file_name())
+            .map(|name| name.to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, vec![".bazelrc", "ci.bazelrc"]);
+    }
+}
@@ -111,6 +111,517 @@ pub fn validate_flags_with_bazel(
     }
 }
 
+/// Which bazel commands accept an option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlagScope {
+    /// Startup option; only valid before the command, e.g. `bazel --batch build`
+    Startup,
+    /// Accepted by every command
+    Common,
+    /// Build option, inherited by test, run, coverage, cquery, aquery and friends
+    Build,
+    /// Only meaningful when tests run: test and coverage (and cquery, which inherits them)
+    Test,
+    /// Only meaningful for `bazel run`
+    Run,
+    /// Query output options for query, cquery and aquery
+    Query,
+}
+
+impl FlagScope {
+    fn accepted_by(self, verb: &str) -> bool {
+        match self {
+            FlagScope::Startup => false,
+            FlagScope::Common => true,
+            FlagScope::Build => verb != "query",
+            FlagScope::Test => matches!(verb, "test" | "coverage" | "cquery"),
+            FlagScope::Run => verb == "run",
+            FlagScope::Query => matches!(verb, "query" | "cquery" | "aquery"),
+        }
+    }
+
+    fn commands(self) -> &'static str {
+        match self {
+            FlagScope::Startup => "the bazel client, before the command",
+            FlagScope::Common => "any command",
+            FlagScope::Build => "build and the commands that inherit its options",
+            FlagScope::Test => "test and coverage",
+            FlagScope::Run => "run",
+            FlagScope::Query => "query, cquery and aquery",
+        }
+    }
+}
+
+/// Commonly used options and where they apply. Options not listed here are
+/// accepted as-is so new Bazel releases and custom flags keep working.
+const KNOWN_FLAGS: &[(&str, FlagScope)] = &[
+    // Startup options
+    ("batch", FlagScope::Startup),
+    ("bazelrc", FlagScope::Startup),
+    ("block_for_lock", FlagScope::Startup),
+    ("home_rc", FlagScope::Startup),
+    ("host_jvm_args", FlagScope::Startup),
+    ("ignore_all_rc_files", FlagScope::Startup),
+    ("install_base", FlagScope::Startup),
+    ("max_idle_secs", FlagScope::Startup),
+    ("output_base", FlagScope::Startup),
+    ("output_user_root", FlagScope::Startup),
+    ("server_javabase", FlagScope::Startup),
+    ("system_rc", FlagScope::Startup),
+    ("workspace_rc", FlagScope::Startup),
+    // Common options
+    ("announce_rc", FlagScope::Common),
+    ("color", FlagScope::Common),
+    ("config", FlagScope::Common),
+    ("curses", FlagScope::Common),
+    ("enable_bzlmod", FlagScope::Common),
+    ("keep_going", FlagScope::Common),
+    ("show_progress", FlagScope::Common),
+    ("show_progress_rate_limit", FlagScope::Common),
+    ("show_timestamps", FlagScope::Common),
+    ("ui_event_filters", FlagScope::Common),
+    // Build options
+    ("action_env", FlagScope::Build),
+    ("bes_backend", FlagScope::Build),
+    ("bes_results_url", FlagScope::Build),
+    ("build_event_json_file", FlagScope::Build),
+    ("build_event_text_file", FlagScope::Build),
+    ("build_tag_filters", FlagScope::Build),
+    ("build_tests_only", FlagScope::Build),
+    ("compilation_mode", FlagScope::Build),
+    ("copt", FlagScope::Build),
+    ("cpu", FlagScope::Build),
+    ("cxxopt", FlagScope::Build),
+    ("define", FlagScope::Build),
+    ("disk_cache", FlagScope::Build),
+    ("features", FlagScope::Build),
+    ("host_copt", FlagScope::Build),
+    ("host_platform", FlagScope::Build),
+    ("jobs", FlagScope::Build),
+    ("linkopt", FlagScope::Build),
+    ("output_groups", FlagScope::Build),
+    ("platforms", FlagScope::Build),
+    ("remote_cache", FlagScope::Build),
+    ("remote_download_outputs", FlagScope::Build),
+    ("remote_executor", FlagScope::Build),
+    ("remote_header", FlagScope::Build),
+    ("remote_timeout", FlagScope::Build),
+    ("remote_upload_local_results", FlagScope::Build),
+    ("repo_env", FlagScope::Build),
+    ("run_under", FlagScope::Build),
+    ("sandbox_debug", FlagScope::Build),
+    ("spawn_strategy", FlagScope::Build),
+    ("stamp", FlagScope::Build),
+    ("strategy", FlagScope::Build),
+    ("subcommands", FlagScope::Build),
+    ("test_lang_filters", FlagScope::Build),
+    ("test_size_filters", FlagScope::Build),
+    ("test_tag_filters", FlagScope::Build),
+    ("test_timeout_filters", FlagScope::Build),
+    ("verbose_failures", FlagScope::Build),
+    ("workspace_status_command", FlagScope::Build),
+    // Test options
+    ("cache_test_results", FlagScope::Test),
+    ("flaky_test_attempts", FlagScope::Test),
+    ("runs_per_test", FlagScope::Test),
+    ("test_arg", FlagScope::Test),
+    ("test_env", FlagScope::Test),
+    ("test_filter", FlagScope::Test),
+    ("test_keep_going", FlagScope::Test),
+    ("test_output", FlagScope::Test),
+    ("test_sharding_strategy", FlagScope::Test),
+    ("test_strategy", FlagScope::Test),
+    ("test_summary", FlagScope::Test),
+    ("test_timeout", FlagScope::Test),
+    ("test_tmpdir", FlagScope::Test),
+    ("test_verbose_timeout_warnings", FlagScope::Test),
+    // Run options
+    ("script_path", FlagScope::Run),
+    // Query options
+    ("implicit_deps", FlagScope::Query),
+    ("include_artifacts", FlagScope::Query),
+    ("include_commandline", FlagScope::Query),
+    ("infer_universe_scope", FlagScope::Query),
+    ("order_output", FlagScope::Query),
+    ("output", FlagScope::Query),
+    ("query_file", FlagScope::Query),
+    ("tool_deps", FlagScope::Query),
+    ("universe_scope", FlagScope::Query),
+];
+
+/// Look up the scope of an option such as `--jobs=8` or `--nokeep_going`.
+/// Returns `None` for anything not in the table, including Starlark flags
+/// (`--//pkg:flag`, `--@repo//:flag`) and short options.
+pub fn flag_scope(flag: &str) -> Option<FlagScope> {
+    let name = flag.strip_prefix("--")?;
+    let name = name.split_once('=').map_or(name, |(name, _)| name);
+    let lookup = |name: &str| {
+        KNOWN_FLAGS
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map(|(_, scope)| *scope)
+    };
+    lookup(name).or_else(|| name.strip_prefix("no").and_then(lookup))
+}
+
+/// Startup options that take no value, each also accepted with a `no` prefix.
+const STARTUP_BOOLEAN_OPTIONS: &[&str] = &[
+    "batch",
+    "block_for_lock",
+    "home_rc",
+    "ignore_all_rc_files",
+    "system_rc",
+    "workspace_rc",
+];
+
+/// Names of the known startup options, for error messages.
+pub fn startup_option_names() -> Vec<&'static str> {
+    KNOWN_FLAGS
+        .iter()
+        .filter(|(_, scope)| *scope == FlagScope::Startup)
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+/// Whether a startup option given without `=` takes the next word as its
+/// value, as in `--output_base /tmp/out`.
+pub fn startup_option_takes_value(flag: &str) -> bool {
+    let Some(name) = flag.strip_prefix("--") else {
+        return false;
+    };
+    if name.contains('=') || flag_scope(flag) != Some(FlagScope::Startup) {
+        return false;
+    }
+    let name = name
+        .strip_prefix("no")
+        .filter(|n| STARTUP_BOOLEAN_OPTIONS.contains(n))
+        .unwrap_or(name);
+    !STARTUP_BOOLEAN_OPTIONS.contains(&name)
+}
+
+/// Remote execution options whose value is an endpoint URL.
+const REMOTE_URL_FLAGS: &[&str] = &["remote_cache", "remote_executor"];
+
+/// Remote execution options whose value is a `Name=Value` header.
+const REMOTE_HEADER_FLAGS: &[&str] =
+    &["remote_header", "remote_cache_header", "remote_exec_header"];
+
+const REMOTE_URL_SCHEMES: &[&str] = &["grpc", "grpcs", "http", "https", "unix"];
+
+/// Options whose value is a comma-separated list of platform or toolchain labels.
+pub const LABEL_LIST_FLAGS: &[&str] = &[
+    "platforms",
+    "host_platform",
+    "extra_toolchains",
+    "extra_execution_platforms",
+];
+
+/// Options whose value names a CPU.
+pub const CPU_FLAGS: &[&str] = &["cpu", "host_cpu"];
+
+/// `--cpu` values used by Bazel's built-in toolchains and the common
+/// Android, Apple and Windows ones.
+pub const COMMON_CPUS: &[&str] = &[
+    "k8",
+    "x86_64",
+    "piii",
+    "aarch64",
+    "arm",
+    "armeabi-v7a",
+    "arm64-v8a",
+    "x86",
+    "ppc",
+    "ppc64le",
+    "s390x",
+    "riscv64",
+    "darwin",
+    "darwin_x86_64",
+    "darwin_arm64",
+    "darwin_arm64e",
+    "ios_x86_64",
+    "ios_arm64",
+    "ios_arm64e",
+    "ios_sim_arm64",
+    "tvos_x86_64",
+    "tvos_arm64",
+    "tvos_sim_arm64",
+    "watchos_x86_64",
+    "watchos_arm64_32",
+    "watchos_arm64",
+    "visionos_arm64",
+    "visionos_sim_arm64",
+    "x64_windows",
+    "x64_windows_msvc",
+    "x64_windows_msys",
+    "x64_x86_windows",
+    "arm64_windows",
+    "freebsd",
+    "openbsd",
+    "wasm32",
+    "wasm64",
+];
+
+/// Values of `options` among the options before any `--`, given either as
+/// `--name=value` or `--name value`. Comma-separated lists are split.
+pub fn option_values(args: &[&str], options: &[&str]) -> Vec<String> {
+    let mut values = Vec::new();
+    let mut words = args.iter().take_while(|a| **a != "--");
+    while let Some(word) = words.next() {
+        let Some(option) = word.strip_prefix("--") else {
+            continue;
+        };
+        let value = match option.split_once('=') {
+            Some((name, value)) if options.contains(&name) => value,
+            None if options.contains(&option) => match words.next() {
+                Some(value) => value,
+                None => continue,
+            },
+            _ => continue,
+        };
+        values.extend(
+            value
+                .trim_matches(|c| c == '\'' || c == '"')
+                .split(',')
+                .filter(|v| !v.is_empty())
+                .map(str::to_string),
+        );
+    }
+    values
+}
+
+/// Check that `label` is an absolute label or target pattern, e.g.
+/// `//platforms:linux_arm64` or `@toolchains//:all`.
+fn check_label_syntax(name: &str, label: &str) -> Result<(), String> {
+    let (repo, rest) = match label.find("//") {
+        Some(start) => label.split_at(start),
+        None => (label, ""),
+    };
+    let repo_valid = repo.is_empty()
+        || (repo.starts_with('@')
+            && repo
+                .trim_start_matches('@')
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | '~' | '+')));
+    let target_valid = match rest.split_once(':') {
+        Some((_, target)) => !target.is_empty() && !target.contains(':'),
+        None => rest.len() > 2 && !rest.ends_with('/'),
+    };
+    if !repo_valid || !rest.starts_with("//") || !target_valid {
+        return Err(format!(
+            "'{}' needs absolute labels such as //platforms:linux_arm64, got '{}'",
+            name, label
+        ));
+    }
+    Ok(())
+}
+
+/// Check the value of a remote execution option given as `--name=value`,
+/// and the label syntax of platform and toolchain options. Values built from
+/// shell variables are left to env validation.
+pub fn check_flag_value(flag: &str) -> Result<(), String> {
+    let Some((name, value)) = flag.split_once('=') else {
+        return Ok(());
+    };
+    let option = name.trim_start_matches('-');
+    if LABEL_LIST_FLAGS.contains(&option) && !value.contains('$') {
+        let labels = value.trim_matches(|c| c == '\'' || c == '"');
+        if labels.is_empty() {
+            return Err(format!("'{}' needs a value", name));
+        }
+        for label in labels.split(',') {
+            check_label_syntax(name, label)?;
+        }
+        return Ok(());
+    }
+    let is_url = REMOTE_URL_FLAGS.contains(&option);
+    let is_header = REMOTE_HEADER_FLAGS.contains(&option);
+    if !is_url && !is_header && option != "remote_instance_name" {
+        return Ok(());
+    }
+    let value = value.trim_matches(|c| c == '\'' || c == '"');
+    if value.contains('$') {
+        return Ok(());
+    }
+    if value.trim().is_empty() {
+        return Err(format!("'{}' needs a value", name));
+    }
+    if value.contains(char::is_whitespace) {
+        return Err(format!("'{}' value '{}' contains whitespace", name, value));
+    }
+
+    if is_url {
+        let valid = match value.split_once("://") {
+            Some((scheme, rest)) => REMOTE_URL_SCHEMES.contains(&scheme) && !rest.is_empty(),
+            None => value
+                .strip_prefix("unix:")
+                .is_some_and(|path| !path.is_empty()),
+        };
+        if !valid {
+            return Err(format!(
+                "'{}' needs a URL with a scheme ({}), got '{}'",
+                name,
+                REMOTE_URL_SCHEMES.join(", "),
+                value
+            ));
+        }
+    } else if is_header {
+        let valid = value
+            .split_once('=')
+            .is_some_and(|(header, _)| !header.is_empty());
+        if !valid {
+            return Err(format!(
+                "'{}' needs a header as Name=Value, got '{}'",
+                name, value
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Options that can be given more than once, each occurrence adding to the
+/// last rather than replacing it.
+pub const REPEATABLE_FLAGS: &[&str] = &[
+    "action_env",
+    "config",
+    "copt",
+    "cxxopt",
+    "define",
+    "extra_toolchains",
+    "features",
+    "flaky_test_attempts",
+    "host_copt",
+    "host_jvm_args",
+    "linkopt",
+    "output_groups",
+    "per_file_copt",
+    "remote_header",
+    "repo_env",
+    "runs_per_test",
+    "strategy",
+    "test_arg",
+    "test_env",
+    "ui_event_filters",
+];
+
+/// Options that leave nothing for some verbs to do.
+const POINTLESS_FOR_VERBS: &[(&str, &[&str])] = &[("nobuild", &["test", "coverage", "run"])];
+
+/// Check the options of one command for combinations that bazel rejects or
+/// that make the command pointless: an option that defeats the verb, two
+/// values for a single-valued option (including `--foo` with `--nofoo`), and
+/// `--config` names declared mutually exclusive. Returns the message and the
+/// index in `flags` of the later option involved. Only options in the flag
+/// table are checked for duplicates.
+pub fn check_flag_combinations(
+    verb: &str,
+    flags: &[&str],
+    exclusive_configs: &[Vec<String>],
+) -> Result<(), (String, usize)> {
+    let mut seen: Vec<(&str, &str, &str)> = Vec::new();
+    let mut configs: Vec<(&str, &str)> = Vec::new();
+    for (index, &flag) in flags.iter().enumerate() {
+        let Some(option) = flag.strip_prefix("--") else {
+            continue;
+        };
+        let (name, value) = option.split_once('=').unwrap_or((option, ""));
+        if POINTLESS_FOR_VERBS
+            .iter()
+            .any(|(pointless, verbs)| *pointless == name && verbs.contains(&verb))
+        {
+            return Err((
+                format!(
+                    "'--{}' skips the build, so `bazel {}` would have nothing to {}",
+                    name,
+                    verb,
+                    if verb == "run" { "run" } else { "test" }
+                ),
+                index,
+            ));
+        }
+
+        if name == "config" && !value.contains('$') {
+            for group in exclusive_configs {
+                if !group.iter().any(|c| c == value) {
+                    continue;
+                }
+                if let Some((_, other_flag)) = configs
+                    .iter()
+                    .find(|(other, _)| *other != value && group.iter().any(|c| c == other))
+                {
+                    return Err((
+                        format!(
+                            "'{}' and '{}' are declared mutually exclusive in bazel_exclusive_configs",
+                            other_flag, flag
+                        ),
+                        index,
+                    ));
+                }
+            }
+            configs.push((value, flag));
+            continue;
+        }
+
+        if flag_scope(flag).is_none_or(|scope| scope == FlagScope::Startup) {
+            continue;
+        }
+        let (base, value) = match name.strip_prefix("no") {
+            Some(base) if !option.contains('=') && flag_scope(&format!("--{}", base)).is_some() => {
+                (base, "false")
+            }
+            _ if value.is_empty() => (name, "true"),
+            _ => (name, value),
+        };
+        if REPEATABLE_FLAGS.contains(&base) || value.contains('$') {
+            continue;
+        }
+        if let Some((_, _, earlier)) = seen
+            .iter()
+            .find(|(other, other_value, _)| *other == base && *other_value != value)
+        {
+            return Err((
+                format!(
+                    "'{}' conflicts with '{}' earlier in the command; '--{}' takes a single value",
+                    flag, earlier, base
+                ),
+                index,
+            ));
+        }
+        seen.push((base, value, flag));
+    }
+    Ok(())
+}
+
+/// Check that `flag` is accepted by `bazel <verb>` and that remote execution
+/// options have well-formed values. Unknown options pass, and only startup
+/// options are checked for commands outside the main build/test/run/query
+/// family.
+pub fn check_flag_for_verb(verb: &str, flag: &str) -> Result<(), String> {
+    const SCOPED_VERBS: &[&str] = &[
+        "build", "test", "run", "coverage", "query", "cquery", "aquery",
+    ];
+    check_flag_value(flag)?;
+    let Some(scope) = flag_scope(flag) else {
+        return Ok(());
+    };
+    let name = flag.split_once('=').map_or(flag, |(name, _)| name);
+    if scope == FlagScope::Startup {
+        return Err(format!(
+            "'{}' is a startup option and must come before the command, \
+             e.g. `bazel {} {} ...`",
+            name, flag, verb
+        ));
+    }
+    if !SCOPED_VERBS.contains(&verb) || scope.accepted_by(verb) {
+        return Ok(());
+    }
+    Err(format!(
+        "'{}' only applies to {}, not `bazel {}`",
+        name,
+        scope.commands(),
+        verb
+    ))
+}
+
 /// Validate flags from string.
 pub fn validate_flags_str_with_bazel(
     verb: &str,
@@ -125,6 +636,137 @@ pub fn validate_flags_str_with_bazel(
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_remote_flag_values() {
+        for flag in [
+            "--remote_cache=grpcs://cache.example.com",
+            "--remote_executor=grpc://localhost:8980",
+            "--remote_cache=https://cache.example.com/prefix",
+            "--remote_cache=unix:/tmp/cache.sock",
+            "--remote_instance_name=projects/ci/instances/default",
+            "--remote_header=x-buildbuddy-api-key=abc123",
+            "--remote_cache=$CACHE_URL",
+            "--remote_header=\"Authorization=Bearer ${TOKEN}\"",
+            "--remote_cache",
+        ] {
+            assert_eq!(check_flag_value(flag), Ok(()), "{}", flag);
+        }
+    }
+
+    #[test]
+    fn test_invalid_remote_flag_values() {
+        let err = check_flag_value("--remote_cache=cache.example.com").unwrap_err();
+        assert!(err.contains("needs a URL with a scheme"), "{}", err);
+        let err = check_flag_value("--remote_executor=ftp://exec").unwrap_err();
+        assert!(err.contains("got 'ftp://exec'"), "{}", err);
+        let err = check_flag_value("--remote_executor=").unwrap_err();
+        assert_eq!(err, "'--remote_executor' needs a value");
+        let err = check_flag_value("--remote_instance_name=''").unwrap_err();
+        assert_eq!(err, "'--remote_instance_name' needs a value");
+        let err = check_flag_value("--remote_header=x-api-key").unwrap_err();
+        assert!(err.contains("Name=Value"), "{}", err);
+        let err = check_flag_value("--remote_cache=\"grpcs://cache .example.com\"").unwrap_err();
+        assert!(err.contains("whitespace"), "{}", err);
+    }
+
+    #[test]
+    fn test_flag_combinations() {
+        let exclusive = vec![vec!["remote".to_string(), "local".to_string()]];
+        let ok = |verb, flags: &[&str]| check_flag_combinations(verb, flags, &exclusive);
+
+        assert!(ok("build", &["--nobuild"]).is_ok());
+        assert!(
+            ok(
+                "build",
+                &["--config=remote", "--config=ci", "--copt=-O2", "--copt=-g"]
+            )
+            .is_ok()
+        );
+        assert!(ok("test", &["--test_output=errors", "--test_output=errors"]).is_ok());
+        assert!(ok("build", &["--jobs=$JOBS", "--jobs=8"]).is_ok());
+
+        let (err, index) = ok("test", &["--keep_going", "--nobuild"]).unwrap_err();
+        assert_eq!(index, 1);
+        assert!(err.contains("nothing to test"), "{}", err);
+
+        let (err, index) =
+            ok("build", &["--config=local", "--jobs=4", "--config=remote"]).unwrap_err();
+        assert_eq!(index, 2);
+        assert!(
+            err.contains("'--config=local' and '--config=remote'"),
+            "{}",
+            err
+        );
+
+        let (err, index) = ok("build", &["--jobs=4", "--jobs=8"]).unwrap_err();
+        assert_eq!(index, 1);
+        assert!(
+            err.contains("'--jobs=8' conflicts with '--jobs=4'"),
+            "{}",
+            err
+        );
+
+        let (err, _) = ok("build", &["--keep_going", "--nokeep_going"]).unwrap_err();
+        assert!(
+            err.contains("'--nokeep_going' conflicts with '--keep_going'"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_startup_option_takes_value() {
+        assert!(startup_option_takes_value("--output_base"));
+        assert!(startup_option_takes_value("--host_jvm_args"));
+        assert!(!startup_option_takes_value("--output_base=/tmp/out"));
+        assert!(!startup_option_takes_value("--batch"));
+        assert!(!startup_option_takes_value("--nobatch"));
+        assert!(!startup_option_takes_value("--noworkspace_rc"));
+        assert!(!startup_option_takes_value("--jobs"));
+        assert!(startup_option_names().contains(&"bazelrc"));
+    }
+
+    #[test]
+    fn test_label_flag_values() {
+        for flag in [
+            "--platforms=//platforms:linux_arm64",
+            "--platforms=@platforms//host",
+            "--extra_toolchains=//toolchains:all,@rules_cc//cc:toolchain",
+            "--host_platform=@local_config_platform//:host",
+            "--extra_execution_platforms=//platforms/...",
+            "--platforms=$PLATFORM",
+        ] {
+            assert!(check_flag_value(flag).is_ok(), "{}", flag);
+        }
+        for flag in [
+            "--platforms=platforms:linux_arm64",
+            "--platforms=//platforms:",
+            "--extra_toolchains=//toolchains:all,",
+            "--platforms=",
+            "--host_platform=@bad repo//:host",
+        ] {
+            assert!(check_flag_value(flag).is_err(), "{}", flag);
+        }
+    }
+
+    #[test]
+    fn test_option_values() {
+        let args = [
+            "--platforms=//platforms:a,//platforms:b",
+            "--cpu",
+            "k8",
+            "--host_cpu='aarch64'",
+            "//app:all",
+            "--",
+            "--cpu=ignored",
+        ];
+        assert_eq!(
+            option_values(&args, LABEL_LIST_FLAGS),
+            vec!["//platforms:a", "//platforms:b"]
+        );
+        assert_eq!(option_values(&args, CPU_FLAGS), vec!["k8", "aarch64"]);
+    }
+
     #[test]
     fn test_make_cache_key_deterministic() {
         let key1 = make_cache_key("build", &["--config=ci"]);
@@ -156,4 +798,47 @@ mod tests {
             .collect();
         assert_eq!(filtered, vec!["--config=ci"]);
     }
+
+    #[test]
+    fn test_flag_scope_lookup() {
+        assert_eq!(flag_scope("--jobs=8"), Some(FlagScope::Build));
+        assert_eq!(flag_scope("--test_output=errors"), Some(FlagScope::Test));
+        assert_eq!(flag_scope("--nokeep_going"), Some(FlagScope::Common));
+        assert_eq!(flag_scope("--output_base=/tmp/x"), Some(FlagScope::Startup));
+        assert_eq!(flag_scope("--//my:flag=true"), None);
+        assert_eq!(flag_scope("--@repo//:flag"), None);
+        assert_eq!(flag_scope("--some_future_flag"), None);
+        assert_eq!(flag_scope("-c"), None);
+    }
+
+    #[test]
+    fn test_check_flag_for_verb() {
+        assert!(check_flag_for_verb("test", "--test_output=errors").is_ok());
+        assert!(check_flag_for_verb("coverage", "--test_filter=Foo").is_ok());
+        assert!(check_flag_for_verb("test", "--jobs=8").is_ok());
+        assert!(check_flag_for_verb("query", "--output=label_kind").is_ok());
+        assert!(check_flag_for_verb("build", "--config=ci").is_ok());
+        assert!(check_flag_for_verb("build", "--//my:flag=true").is_ok());
+        assert!(check_flag_for_verb("build", "--unknown_new_flag").is_ok());
+
+        let err = check_flag_for_verb("build", "--test_filter=Foo").unwrap_err();
+        assert!(err.contains("'--test_filter' only applies to test and coverage"));
+        assert!(err.contains("not `bazel build`"));
+        assert!(check_flag_for_verb("query", "--jobs=8").is_err());
+        assert!(check_flag_for_verb("build", "--output=label").is_err());
+        assert!(check_flag_for_verb("build", "--script_path=run.sh").is_err());
+    }
+
+    #[test]
+    fn test_startup_option_after_verb_rejected() {
+        let err = check_flag_for_verb("build", "--output_base=/tmp/x").unwrap_err();
+        assert!(err.contains("startup option"));
+        assert!(check_flag_for_verb("test", "--nobatch").is_err());
+    }
+
+    #[test]
+    fn test_other_verbs_only_check_startup_options() {
+        assert!(check_flag_for_verb("fetch", "--test_filter=Foo").is_ok());
+        assert!(check_flag_for_verb("fetch", "--output_user_root=/tmp").is_err());
+    }
 }
@@ -66,6 +66,59 @@ pub fn extract_targets_from_args(args: &[&str]) -> Vec<String> {
         .collect()
 }
 
+/// Options whose value may follow as a separate word, e.g. `-c opt`.
+const OPTIONS_WITH_SEPARATE_VALUE: &[&str] = &[
+    "-c",
+    "-j",
+    "--compilation_mode",
+    "--config",
+    "--jobs",
+    "--run_under",
+    "--script_path",
+];
+
+/// Check that `bazel run` names exactly one target and that it isn't a
+/// wildcard. Arguments after `--` go to the program and are ignored.
+pub fn validate_run_target(args: &[&str]) -> Result<(), String> {
+    let mut targets = Vec::new();
+    let mut words = args.iter().take_while(|a| **a != "--");
+    while let Some(word) = words.next() {
+        if OPTIONS_WITH_SEPARATE_VALUE.contains(word) {
+            words.next();
+        } else if !word.starts_with('-') {
+            targets.push(*word);
+        }
+    }
+    match targets.as_slice() {
+        [] => Err("bazel run needs a target to run".to_string()),
+        [target] if is_wildcard_pattern(target) || target.ends_with(":all-targets") => {
+            Err(format!(
+                "bazel run needs a single target, not the wildcard pattern '{}'",
+                target
+            ))
+        }
+        [_] => Ok(()),
+        _ => Err(format!(
+            "bazel run takes exactly one target, got {}: {}. Pass program arguments after `--`",
+            targets.len(),
+            targets.join(" ")
+        )),
+    }
+}
+
+/// Split `bazel run` target patterns at a `--` word; everything after it is
+/// passed to the program untouched.
+pub fn split_run_args(patterns: &str) -> (String, Vec<String>) {
+    let words: Vec<&str> = patterns.split_whitespace().collect();
+    match words.iter().position(|w| *w == "--") {
+        Some(pos) => (
+            words[..pos].join(" "),
+            words[pos + 1..].iter().map(|w| w.to_string()).collect(),
+        ),
+        None => (patterns.to_string(), Vec::new()),
+    }
+}
+
 /// Get current package from workspace and script paths.
 pub fn get_current_package(
     workspace: &std::path::Path,
@@ -185,4 +238,34 @@ mod tests {
             Some("".to_string())
         );
     }
+
+    #[test]
+    fn test_validate_run_target() {
+        assert!(validate_run_target(&["//tools:fmt"]).is_ok());
+        assert!(validate_run_target(&["//tools:fmt", "--", "--check", "src"]).is_ok());
+        assert!(validate_run_target(&["-c", "opt", "--config", "ci", "//tools:fmt"]).is_ok());
+        assert!(validate_run_target(&["--jobs=4", ":fmt"]).is_ok());
+
+        let err = validate_run_target(&["//..."]).unwrap_err();
+        assert!(err.contains("wildcard pattern '//...'"), "{}", err);
+        assert!(validate_run_target(&["//tools:all"]).is_err());
+        let err = validate_run_target(&["//a:b", "//c:d"]).unwrap_err();
+        assert!(err.contains("exactly one target, got 2"), "{}", err);
+        assert!(validate_run_target(&["--", "//tools:fmt"]).is_err());
+    }
+
+    #[test]
+    fn test_split_run_args() {
+        assert_eq!(
+            split_run_args("//tools:fmt -- --check src"),
+            (
+                "//tools:fmt".to_string(),
+                vec!["--check".to_string(), "src".to_string()]
+            )
+        );
+        assert_eq!(
+            split_run_args("//tools:fmt"),
+            ("//tools:fmt".to_string(), vec![])
+        );
+    }
 }
@@ -1,7 +1,9 @@
 //! Bazel target and flag validation.
 
+pub mod bazelrc;
 pub mod flags;
 pub mod labels;
+pub mod query;
 pub mod targets;
 
 use crate::{FlagsCacheEntry, should_skip_validation, state, validation_failure};
@@ -0,0 +1,366 @@
+//! Syntax checks for `bazel query`, `cquery` and `aquery` expressions.
+
+/// Query functions and the number of arguments they accept.
+const FUNCTIONS: &[(&str, usize, usize)] = &[
+    ("allpaths", 2, 2),
+    ("allrdeps", 1, 2),
+    ("attr", 3, 3),
+    ("buildfiles", 1, 1),
+    ("config", 2, 2),
+    ("deps", 1, 2),
+    ("filter", 2, 2),
+    ("inputs", 2, 2),
+    ("kind", 2, 2),
+    ("labels", 2, 2),
+    ("loadfiles", 1, 1),
+    ("mnemonic", 2, 2),
+    ("outputs", 2, 2),
+    ("rbuildfiles", 1, usize::MAX),
+    ("rdeps", 2, 3),
+    ("same_pkg_direct_rdeps", 1, 1),
+    ("set", 0, usize::MAX),
+    ("siblings", 1, 1),
+    ("some", 1, 2),
+    ("somepath", 2, 2),
+    ("tests", 1, 1),
+    ("visible", 2, 2),
+];
+
+const OPERATORS: &[&str] = &["union", "+", "intersect", "^", "except", "-"];
+
+/// Whether a query verb's residue should be checked as an expression.
+pub fn is_query_verb(verb: &str) -> bool {
+    matches!(verb, "query" | "cquery" | "aquery")
+}
+
+/// Check the syntax of a query expression: balanced parentheses, function
+/// arity and `let` bindings. Returns warnings for functions this table
+/// doesn't know, since Bazel keeps adding them.
+pub fn validate_query_expression(expr: &str) -> Result<Vec<String>, String> {
+    let invalid = |reason: String| format!("Invalid query expression '{}': {}", expr, reason);
+
+    let mut depth = 0usize;
+    for token in tokenize(expr) {
+        match token {
+            Token::Open => depth += 1,
+            Token::Close if depth == 0 => return Err(invalid("unexpected ')'".to_string())),
+            Token::Close => depth -= 1,
+            _ => {}
+        }
+    }
+    if depth > 0 {
+        return Err(invalid(format!("missing ')' ({} unclosed '(')", depth)));
+    }
+
+    let mut parser = Parser {
+        tokens: tokenize(expr),
+        pos: 0,
+        warnings: Vec::new(),
+    };
+    parser.expr().map_err(invalid)?;
+    if let Some(token) = parser.peek() {
+        return Err(invalid(format!("unexpected {}", token.describe())));
+    }
+    Ok(parser.warnings)
+}
+
+/// Pull the query expression out of everything after the verb in a
+/// `bazel!` string, dropping `--flag`s and shell quoting.
+pub fn expression_from_args(args: &str) -> String {
+    let mut words = Vec::new();
+    let mut after_separator = false;
+    for word in shell_words(args) {
+        if !after_separator && word == "--" {
+            after_separator = true;
+        } else if after_separator || !word.starts_with("--") {
+            words.push(word);
+        }
+    }
+    words.join(" ")
+}
+
+/// Split on whitespace, keeping single- and double-quoted runs together.
+fn shell_words(s: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut in_word = false;
+    let mut quote: Option<char> = None;
+    for c in s.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => current.push(c),
+            None if c == '\'' || c == '"' => {
+                quote = Some(c);
+                in_word = true;
+            }
+            None if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            }
+            None => {
+                current.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(current);
+    }
+    words
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token<'a> {
+    Word(&'a str),
+    Open,
+    Close,
+    Comma,
+}
+
+impl Token<'_> {
+    fn describe(&self) -> String {
+        match self {
+            Token::Word(w) => format!("'{}'", w),
+            Token::Open => "'('".to_string(),
+            Token::Close => "')'".to_string(),
+            Token::Comma => "','".to_string(),
+        }
+    }
+}
+
+fn tokenize(expr: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            ',' => tokens.push(Token::Comma),
+            '\'' | '"' => {
+                let mut end = expr.len();
+                for (i, next) in chars.by_ref() {
+                    if next == c {
+                        end = i;
+                        break;
+                    }
+                }
+                tokens.push(Token::Word(&expr[start + 1..end]));
+            }
+            c if c.is_whitespace() => {}
+            _ => {
+                let mut end = expr.len();
+                while let Some(&(i, next)) = chars.peek() {
+                    if next.is_whitespace() || matches!(next, '(' | ')' | ',') {
+                        end = i;
+                        break;
+                    }
+                    chars.next();
+                }
+                tokens.push(Token::Word(&expr[start..end]));
+            }
+        }
+    }
+    tokens
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+    warnings: Vec<String>,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<Token<'a>> {
+        let token = self.peek();
+        self.pos += 1;
+        token
+    }
+
+    /// A sequence of primaries joined by set operators. Adjacent primaries are
+    /// accepted too, since Bazel joins multiple arguments with spaces.
+    fn expr(&mut self) -> Result<(), String> {
+        self.primary()?;
+        loop {
+            match self.peek() {
+                Some(Token::Word("in")) | Some(Token::Close) | Some(Token::Comma) | None => {
+                    return Ok(());
+                }
+                Some(Token::Word(w)) if OPERATORS.contains(&w) => {
+                    self.pos += 1;
+                    if matches!(self.peek(), None | Some(Token::Close) | Some(Token::Comma)) {
+                        return Err(format!("'{}' needs an expression on both sides", w));
+                    }
+                    self.primary()?;
+                }
+                Some(_) => self.primary()?,
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<(), String> {
+        match self.bump() {
+            None => Err("expression ends unexpectedly".to_string()),
+            Some(Token::Open) => {
+                self.expr()?;
+                match self.bump() {
+                    Some(Token::Close) => Ok(()),
+                    _ => Err("missing ')'".to_string()),
+                }
+            }
+            Some(token @ (Token::Close | Token::Comma)) => {
+                Err(format!("unexpected {}", token.describe()))
+            }
+            Some(Token::Word(w)) if OPERATORS.contains(&w) => {
+                Err(format!("'{}' needs an expression on both sides", w))
+            }
+            Some(Token::Word("let")) => self.let_binding(),
+            Some(Token::Word(name)) if self.peek() == Some(Token::Open) => {
+                self.pos += 1;
+                self.call(name)
+            }
+            Some(Token::Word(_)) => Ok(()),
+        }
+    }
+
+    /// `let name = expr in expr`
+    fn let_binding(&mut self) -> Result<(), String> {
+        match (self.bump(), self.bump()) {
+            (Some(Token::Word(_)), Some(Token::Word("="))) => {}
+            _ => return Err("expected `let name = expr in expr`".to_string()),
+        }
+        self.expr()?;
+        if self.bump() != Some(Token::Word("in")) {
+            return Err("expected 'in' after let binding".to_string());
+        }
+        self.expr()
+    }
+
+    /// Arguments of `name(`, through the closing parenthesis.
+    fn call(&mut self, name: &str) -> Result<(), String> {
+        let mut count = 0;
+        if name == "set" {
+            while let Some(Token::Word(_)) = self.peek() {
+                self.pos += 1;
+                count += 1;
+            }
+        } else if self.peek() != Some(Token::Close) {
+            loop {
+                self.expr()?;
+                count += 1;
+                if self.peek() != Some(Token::Comma) {
+                    break;
+                }
+                self.pos += 1;
+            }
+        }
+        if self.bump() != Some(Token::Close) {
+            return Err(format!("missing ')' after {}() arguments", name));
+        }
+
+        match FUNCTIONS.iter().find(|(known, _, _)| *known == name) {
+            Some(&(_, min, max)) if count < min || count > max => {
+                let expected = if min == max {
+                    min.to_string()
+                } else if max == usize::MAX {
+                    format!("at least {}", min)
+                } else {
+                    format!("{} or {}", min, max)
+                };
+                let plural = if expected == "1" { "" } else { "s" };
+                Err(format!(
+                    "{}() takes {} argument{}, got {}",
+                    name, expected, plural, count
+                ))
+            }
+            Some(_) => Ok(()),
+            None => {
+                self.warnings
+                    .push(format!("unknown query function '{}'", name));
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_expressions() {
+        for expr in [
+            "//...",
+            "deps(//app:all)",
+            "deps(//app:all, 2)",
+            "rdeps(//..., //lib:core)",
+            "kind(cc_library, deps(//app:main))",
+            "attr(tags, 'manual', //...)",
+            "tests(//app/...) except //app/slow:all",
+            "somepath(//app:main, //lib:core) + allpaths(//a, //b)",
+            "set(//a //b //c)",
+            "let x = deps(//app:main) in $x - //lib:core",
+            "filter(\"\\.cc$\", //app/...)",
+        ] {
+            assert_eq!(validate_query_expression(expr), Ok(vec![]), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_unbalanced_parentheses() {
+        let err = validate_query_expression("deps(//app:all").unwrap_err();
+        assert!(err.contains("missing ')'"), "{}", err);
+        let err = validate_query_expression("deps(//app:all))").unwrap_err();
+        assert!(err.contains("unexpected ')'"), "{}", err);
+    }
+
+    #[test]
+    fn test_arity() {
+        let err = validate_query_expression("deps(//a, 1, 2)").unwrap_err();
+        assert!(
+            err.contains("deps() takes 1 or 2 arguments, got 3"),
+            "{}",
+            err
+        );
+        let err = validate_query_expression("somepath(//a)").unwrap_err();
+        assert!(
+            err.contains("somepath() takes 2 arguments, got 1"),
+            "{}",
+            err
+        );
+        let err = validate_query_expression("tests()").unwrap_err();
+        assert!(err.contains("tests() takes 1 argument, got 0"), "{}", err);
+    }
+
+    #[test]
+    fn test_dangling_operator() {
+        assert!(validate_query_expression("//a union").is_err());
+        assert!(validate_query_expression("except //a").is_err());
+    }
+
+    #[test]
+    fn test_unknown_function_warns() {
+        assert_eq!(
+            validate_query_expression("newfunc(//a)"),
+            Ok(vec!["unknown query function 'newfunc'".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_expression_from_args() {
+        assert_eq!(
+            expression_from_args("--output=label 'deps(//app:all)'"),
+            "deps(//app:all)"
+        );
+        assert_eq!(
+            expression_from_args("--keep_going -- \"kind(rule, //...)\""),
+            "kind(rule, //...)"
+        );
+        assert_eq!(expression_from_args("//..."), "//...");
+    }
+}
@@ -4,7 +4,7 @@
 //! Set `BUILDKITE_VALIDATION_WARN_ONLY=1` to warn instead of panic.
 
 use std::collections::HashSet;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Mutex, OnceLock};
 
 #[cfg(feature = "bazel")]
@@ -71,6 +71,27 @@ fn state() -> &'static Mutex<ValidationState> {
     })
 }
 
+/// Files that mark the root of a Bazel workspace. bzlmod-only repos have
+/// `MODULE.bazel` (or `REPO.bazel`) and no `WORKSPACE` at all.
+pub const BAZEL_WORKSPACE_MARKERS: &[&str] =
+    &["MODULE.bazel", "REPO.bazel", "WORKSPACE.bazel", "WORKSPACE"];
+
+/// Whether `dir` contains any of [`BAZEL_WORKSPACE_MARKERS`].
+pub fn is_bazel_workspace_root(dir: &Path) -> bool {
+    BAZEL_WORKSPACE_MARKERS
+        .iter()
+        .any(|marker| dir.join(marker).is_file())
+}
+
+/// The nearest ancestor of `start` (including `start`) that is a Bazel
+/// workspace root, so a nested workspace wins over the one around it.
+pub fn find_bazel_workspace_root(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .find(|dir| is_bazel_workspace_root(dir))
+        .map(Path::to_path_buf)
+}
+
 /// Find workspace root (Bazel or Cargo).
 pub fn find_workspace() -> Option<PathBuf> {
     if let Ok(dir) = std::env::var("BUILD_WORKSPACE_DIRECTORY") {
@@ -87,7 +108,7 @@ pub fn find_workspace() -> Option<PathBuf> {
 
     let mut dir = start.as_path();
     loop {
-        if dir.join("MODULE.bazel").exists() || dir.join("WORKSPACE").exists() {
+        if is_bazel_workspace_root(dir) {
             return Some(dir.to_path_buf());
         }
         if dir.join("Cargo.toml").exists()
@@ -109,8 +130,7 @@ pub fn find_workspace() -> Option<PathBuf> {
 pub fn find_bazel_workspace() -> Option<PathBuf> {
     if let Ok(dir) = std::env::var("BUILD_WORKSPACE_DIRECTORY") {
         let path = PathBuf::from(&dir);
-        if path.exists() && (path.join("MODULE.bazel").exists() || path.join("WORKSPACE").exists())
-        {
+        if is_bazel_workspace_root(&path) {
             return Some(path);
         }
     }
@@ -119,18 +139,7 @@ pub fn find_bazel_workspace() -> Option<PathBuf> {
         .or_else(|_| std::env::var("CARGO_MANIFEST_DIR"))
         .ok()
         .map(PathBuf::from)?;
-
-    let mut dir = start.as_path();
-    loop {
-        if dir.join("MODULE.bazel").exists() || dir.join("WORKSPACE").exists() {
-            return Some(dir.to_path_buf());
-        }
-        match dir.parent() {
-            Some(parent) => dir = parent,
-            None => break,
-        }
-    }
-    None
+    find_bazel_workspace_root(&start)
 }
 
 /// Initialize validation state early.
@@ -232,6 +241,21 @@ pub fn validate_env_vars(names: &[&str]) {
     }
 }
 
+/// Quote `value` as a single shell word. Words made only of characters the
+/// shell treats literally are returned unchanged; anything else is wrapped in
+/// single quotes, with embedded single quotes written as `'\''`.
+pub fn shell_quote(value: &str) -> String {
+    let plain = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-./:@%+=,".contains(c));
+    if plain {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    }
+}
+
 /// Check if path exists without panicking.
 pub fn check_path_exists(path: &str) -> Result<(), String> {
     let guard = match state().lock() {
@@ -264,6 +288,41 @@ pub fn check_path_exists(path: &str) -> Result<(), String> {
     }
 }
 
+/// Check a command built at runtime before it goes into a step. The command
+/// must be non-empty and free of NUL bytes; when `allowed` is non-empty, its
+/// first word (after any `NAME=value` assignments) must be listed there.
+/// Path commands like `./deploy.sh` aren't checked against `allowed`, as at
+/// compile time.
+pub fn check_command(command: &str, allowed: &[&str]) -> Result<(), String> {
+    if should_skip_validation() {
+        return Ok(());
+    }
+    if command.trim().is_empty() {
+        return Err("command is empty".to_string());
+    }
+    if command.contains('\0') {
+        return Err(format!("command {:?} contains a NUL byte", command));
+    }
+    if allowed.is_empty() {
+        return Ok(());
+    }
+    let Some(name) = command.split_whitespace().find(|word| {
+        !word
+            .split_once('=')
+            .is_some_and(|(var, _)| !var.is_empty() && !var.contains('/'))
+    }) else {
+        return Ok(());
+    };
+    if name.contains('/') || allowed.contains(&name) {
+        Ok(())
+    } else {
+        Err(format!(
+            "command '{}' is not in allowed_commands (from {:?})",
+            name, command
+        ))
+    }
+}
+
 #[cfg(feature = "bazel")]
 pub use bazel::{
     flags, labels, targets, validate_flags, validate_flags_str, validate_target, validate_targets,
@@ -304,6 +363,93 @@ mod tests {
         validate_paths(&[]);
     }
 
+    /// Create `files` (relative paths) under a fresh temp dir.
+    fn fixture(files: &[&str]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for file in files {
+            let path = dir.path().join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_bzlmod_only_workspace() {
+        let dir = fixture(&["MODULE.bazel", "app/BUILD.bazel"]);
+        assert_eq!(
+            find_bazel_workspace_root(&dir.path().join("app")),
+            Some(dir.path().to_path_buf())
+        );
+    }
+
+    #[test]
+    fn test_repo_bazel_and_workspace_bazel_markers() {
+        for marker in ["REPO.bazel", "WORKSPACE.bazel", "WORKSPACE"] {
+            let dir = fixture(&[marker, "pkg/BUILD"]);
+            assert_eq!(
+                find_bazel_workspace_root(&dir.path().join("pkg")),
+                Some(dir.path().to_path_buf()),
+                "{}",
+                marker
+            );
+        }
+    }
+
+    #[test]
+    fn test_hybrid_workspace() {
+        let dir = fixture(&["MODULE.bazel", "WORKSPACE", "tools/ci/BUILD"]);
+        assert_eq!(
+            find_bazel_workspace_root(&dir.path().join("tools/ci")),
+            Some(dir.path().to_path_buf())
+        );
+    }
+
+    #[test]
+    fn test_nested_workspace_prefers_nearest() {
+        let dir = fixture(&[
+            "WORKSPACE",
+            "third_party/lib/MODULE.bazel",
+            "third_party/lib/src/BUILD",
+        ]);
+        assert_eq!(
+            find_bazel_workspace_root(&dir.path().join("third_party/lib/src")),
+            Some(dir.path().join("third_party/lib"))
+        );
+        assert_eq!(
+            find_bazel_workspace_root(&dir.path().join("third_party")),
+            Some(dir.path().to_path_buf())
+        );
+    }
+
+    #[test]
+    fn test_marker_directories_are_ignored() {
+        let dir = fixture(&["pkg/BUILD"]);
+        std::fs::create_dir(dir.path().join("WORKSPACE")).unwrap();
+        assert!(!is_bazel_workspace_root(dir.path()));
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("v1.2.3"), "v1.2.3");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("a b"), "'a b'");
+        assert_eq!(shell_quote("$(rm -rf /); `id`"), "'$(rm -rf /); `id`'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn test_check_command() {
+        assert!(check_command("cargo test", &[]).is_ok());
+        assert!(check_command("cargo test", &["cargo"]).is_ok());
+        assert!(check_command("RUST_LOG=debug cargo test", &["cargo"]).is_ok());
+        assert!(check_command("./deploy.sh prod", &["cargo"]).is_ok());
+        assert!(check_command("  ", &[]).is_err());
+        assert!(check_command("echo \0", &[]).is_err());
+        let err = check_command("curl https://example.com", &["cargo"]).unwrap_err();
+        assert!(err.contains("'curl'"), "{}", err);
+    }
+
     #[test]
     #[cfg(not(feature = "bazel"))]
     fn test_validation_stubs_are_noop() {
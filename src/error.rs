@@ -68,3 +68,36 @@ impl From<serde_json::Error> for BuildkiteError {
         }
     }
 }
+
+/// Error returned by `try_pipeline!` when a step fails to build at runtime,
+/// e.g. a `runtime!` key that isn't a valid step key
+#[derive(Debug, Clone)]
+pub struct BuildError {
+    /// The step that failed, e.g. `command step 'build'`
+    pub step: String,
+    pub message: String,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.step, self.message)
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Converts a fallible builder result into a [`BuildError`] tagged with the
+/// step being built. Used by the code `pipeline!` generates.
+#[doc(hidden)]
+pub trait IntoBuildResult<T> {
+    fn into_build_result(self, step: &str, what: &str) -> Result<T, BuildError>;
+}
+
+impl<T, E: fmt::Display> IntoBuildResult<T> for Result<T, E> {
+    fn into_build_result(self, step: &str, what: &str) -> Result<T, BuildError> {
+        self.map_err(|e| BuildError {
+            step: step.to_string(),
+            message: format!("{}: {}", what, e),
+        })
+    }
+}
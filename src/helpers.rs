@@ -0,0 +1,52 @@
+//! Small constructors the `pipeline!` expansion calls instead of open-coding
+//! map and list building for every step. Keeping these out of the generated
+//! code shrinks the expansion of large pipelines considerably.
+
+use crate::{Agents, AgentsList, AgentsObject, DependsOn, DependsOnList, DependsOnListItem, Env};
+use serde_json::{Map, Value};
+
+fn string_map(pairs: &[(&str, &str)]) -> Map<String, Value> {
+    let mut map = Map::new();
+    for (key, value) in pairs {
+        map.insert((*key).to_string(), Value::String((*value).to_string()));
+    }
+    map
+}
+
+/// Env vars with string values, e.g. `env_map(&[("CI", "true")])`.
+/// A repeated name keeps the last value.
+#[must_use]
+pub fn env_map(vars: &[(&str, &str)]) -> Env {
+    Env(string_map(vars))
+}
+
+/// Agent tags as an `agents` object, e.g. `agents_map(&[("queue", "linux")])`.
+#[must_use]
+pub fn agents_map(tags: &[(&str, &str)]) -> Agents {
+    Agents::Object(AgentsObject(string_map(tags)))
+}
+
+/// Agent tags as an `agents` list of `key=value` strings, e.g.
+/// `agents_list(&["queue=linux"])`.
+#[must_use]
+pub fn agents_list(tags: &[&str]) -> Agents {
+    Agents::List(AgentsList(
+        tags.iter().map(|tag| (*tag).to_string()).collect(),
+    ))
+}
+
+/// A depends_on list of plain step keys.
+#[must_use]
+pub fn string_deps(keys: &[&str]) -> DependsOn {
+    DependsOn::DependsOnList(DependsOnList(
+        keys.iter()
+            .map(|key| DependsOnListItem::String((*key).to_string()))
+            .collect(),
+    ))
+}
+
+/// Owned copies of `items`, for list fields such as branches and cache.
+#[must_use]
+pub fn string_vec(items: &[&str]) -> Vec<String> {
+    items.iter().map(|item| (*item).to_string()).collect()
+}
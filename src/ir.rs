@@ -0,0 +1,150 @@
+//! Serializable intermediate representation of a `pipeline!` definition.
+//!
+//! Enabled with the `ir` feature. [`pipeline_ir!`](crate::pipeline_ir) takes
+//! the same input as `pipeline!`, runs the same compile-time validation, and
+//! evaluates to a [`PipelineIr`] instead of a pipeline. Linters and
+//! visualizers can walk the steps without re-parsing the generated YAML.
+//!
+//! ```ignore
+//! use rust_buildkite::pipeline_ir;
+//!
+//! let ir = pipeline_ir! {
+//!     steps: [
+//!         command(cmd!("cargo build")).key("build"),
+//!         command(cmd!("cargo test")).key("test").depends_on("build")
+//!     ]
+//! };
+//! assert_eq!(ir.dependents("build").len(), 1);
+//! ```
+//!
+//! # Stability
+//!
+//! The IR follows the crate's semver. Fields and variants are only added,
+//! never removed or renamed, within a major version, so every type is
+//! `#[non_exhaustive]` and deserializes with defaults for missing fields.
+//! [`IR_VERSION`] is bumped whenever the serialized shape changes.
+
+use serde::{Deserialize, Serialize};
+
+/// Version of the serialized IR shape, recorded in [`PipelineIr::version`].
+pub const IR_VERSION: u32 = 2;
+
+/// A validated pipeline definition.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct PipelineIr {
+    /// [`IR_VERSION`] of the macro that produced this value
+    pub version: u32,
+    /// Top-level steps in definition order; group members are nested
+    pub steps: Vec<StepIr>,
+    /// Names of the pipeline-level env vars
+    pub env: Vec<String>,
+    /// Env vars commands may reference, or `None` when `expect_env`
+    /// names a const that is only known at runtime
+    pub allowed_env: Option<Vec<String>>,
+}
+
+impl Default for PipelineIr {
+    fn default() -> Self {
+        Self {
+            version: IR_VERSION,
+            steps: Vec::new(),
+            env: Vec::new(),
+            allowed_env: Some(Vec::new()),
+        }
+    }
+}
+
+/// The kind of a pipeline step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum StepKind {
+    Command,
+    Wait,
+    Block,
+    Input,
+    Trigger,
+    Group,
+}
+
+/// A single step, with the metadata validation extracted from it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct StepIr {
+    pub kind: StepKind,
+    /// Literal key; `None` when unset or only known at runtime
+    pub key: Option<String>,
+    /// Literal label, or the source text of a label computed at runtime
+    pub label: Option<String>,
+    /// Doc comments on the step, or its `description`
+    pub description: Option<String>,
+    pub depends_on: Vec<String>,
+    /// The step's `if` condition
+    pub condition: Option<String>,
+    /// Commands of a command step, in order
+    pub commands: Vec<CommandIr>,
+    /// Steps nested in a group
+    pub steps: Vec<StepIr>,
+}
+
+impl Default for StepIr {
+    fn default() -> Self {
+        Self {
+            kind: StepKind::Command,
+            key: None,
+            label: None,
+            description: None,
+            depends_on: Vec::new(),
+            condition: None,
+            commands: Vec::new(),
+            steps: Vec::new(),
+        }
+    }
+}
+
+/// A command and what validation learned about it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct CommandIr {
+    /// Command string; `None` when it is built at runtime
+    pub text: Option<String>,
+    /// Name the allowlist was checked against, e.g. `cargo`
+    pub name: String,
+    /// Env vars the command references that nothing defines
+    pub undefined_vars: Vec<String>,
+}
+
+impl PipelineIr {
+    #[doc(hidden)]
+    #[must_use]
+    pub fn __from_expansion(json: &str) -> Self {
+        serde_json::from_str(json).expect("pipeline_ir! produced invalid IR")
+    }
+
+    /// Every step, depth first, with group members after their group.
+    #[must_use]
+    pub fn all_steps(&self) -> Vec<&StepIr> {
+        fn walk<'a>(steps: &'a [StepIr], out: &mut Vec<&'a StepIr>) {
+            for step in steps {
+                out.push(step);
+                walk(&step.steps, out);
+            }
+        }
+        let mut out = Vec::new();
+        walk(&self.steps, &mut out);
+        out
+    }
+
+    /// Steps that list `key` in their depends_on.
+    #[must_use]
+    pub fn dependents(&self, key: &str) -> Vec<&StepIr> {
+        self.all_steps()
+            .into_iter()
+            .filter(|step| step.depends_on.iter().any(|dep| dep == key))
+            .collect()
+    }
+}
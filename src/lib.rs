@@ -148,10 +148,17 @@
 // allowed in fields like env, retry, plugins, etc. maybe can clean those types up later.
 pub use serde_json;
 
-pub use rust_buildkite_macros::{cmd, pipeline, register};
+pub use rust_buildkite_macros::{cmd, pipeline, register, try_pipeline};
+
+pub use rust_buildkite_macros::{all, any, not_pull_request, on_branch, on_tag, pr_base_branch};
+
+pub use rust_buildkite_macros::{cargo_build, cargo_clippy, cargo_test};
 
 #[cfg(feature = "bazel")]
-pub use rust_buildkite_macros::{bazel, comptime, comptime_shell, runtime};
+pub use rust_buildkite_macros::{bazel, bazel_info, comptime, comptime_shell, runtime};
+
+#[cfg(feature = "ir")]
+pub use rust_buildkite_macros::pipeline_ir;
 
 pub use inventory;
 
@@ -160,6 +167,11 @@ pub use registry::*;
 
 pub mod validation;
 
+pub mod helpers;
+
+#[cfg(feature = "ir")]
+pub mod ir;
+
 /// Discovers all available commands from the host machine's PATH at compile time.
 ///
 /// Note: The `pipeline!` macro automatically uses host PATH discovery by default,
@@ -23,6 +23,12 @@ mod bazel_macro {
         assert_eq!(c, "bazel run //foo:bar");
     }
 
+    #[test]
+    fn run_command_with_program_args() {
+        let c = bazel!("run //tools:fmt -- --check --diff");
+        assert_eq!(c, "bazel run //tools:fmt -- --check --diff");
+    }
+
     #[test]
     fn simple_query() {
         let c = bazel!("query //...");
@@ -41,6 +47,15 @@ mod bazel_macro {
         assert_eq!(c, "bazel version");
     }
 
+    #[test]
+    fn startup_options_before_verb() {
+        let c = bazel!("--bazelrc=ci/.bazelrc --output_base /tmp/out test //...");
+        assert_eq!(
+            c,
+            "bazel --bazelrc=ci/.bazelrc --output_base /tmp/out test //..."
+        );
+    }
+
     #[test]
     fn build_with_flags() {
         let c = bazel!("build //... --jobs=4");
@@ -65,6 +80,12 @@ mod bazel_macro {
         assert_eq!(c, "bazel build @external//pkg:target");
     }
 
+    #[test]
+    fn query_expression_command() {
+        let c = bazel!("query 'rdeps(//..., //lib:core)'");
+        assert_eq!(c, "bazel query 'rdeps(//..., //lib:core)'");
+    }
+
     #[test]
     fn cquery_command() {
         let c = bazel!("cquery //...");
@@ -140,6 +161,40 @@ mod shorthand_macros {
         assert!(yaml.contains("label: test all"));
     }
 
+    #[test]
+    fn bazel_test_retry_preset() {
+        use rust_buildkite::serde_json::{json, to_value};
+
+        let p = pipeline! {
+            env: {},
+            steps: [
+                bazel_test {
+                    target_patterns: "//...",
+                    key: "test",
+                    retry_preset: "flaky"
+                },
+                bazel_test {
+                    target_patterns: "//...",
+                    key: "test-ci",
+                    retry_preset: ["agent_loss", "flaky"]
+                }
+            ]
+        };
+        let value = to_value(&p).unwrap();
+        assert_eq!(
+            value["steps"][0]["retry"],
+            json!({ "automatic": [{ "exit_status": "*", "limit": 2 }] })
+        );
+        assert_eq!(
+            value["steps"][1]["retry"],
+            json!({ "automatic": [
+                { "exit_status": -1, "limit": 2 },
+                { "signal_reason": "agent_stop", "limit": 2 },
+                { "exit_status": "*", "limit": 2 }
+            ] })
+        );
+    }
+
     #[test]
     fn bazel_run_basic() {
         let p = pipeline! {
@@ -186,6 +241,21 @@ mod shorthand_macros {
         assert!(yaml.contains("command: bazel run //foo:bar -- --help"));
     }
 
+    #[test]
+    fn bazel_run_with_args_in_target_patterns() {
+        let p = pipeline! {
+            steps: [
+                bazel_run {
+                    target_patterns: "//tools:fmt -- --check",
+                    args: ["src"],
+                    label: "check formatting"
+                }
+            ]
+        };
+        let yaml = serde_yaml::to_string(&p).unwrap();
+        assert!(yaml.contains("command: bazel run //tools:fmt -- --check src"));
+    }
+
     #[test]
     fn bazel_run_with_flags_and_args() {
         let p = pipeline! {
@@ -234,6 +304,22 @@ mod shorthand_macros {
         assert!(yaml.contains("command: bazel query //..."));
     }
 
+    #[test]
+    fn bazel_query_expression_is_quoted() {
+        let p = pipeline! {
+            env: {},
+            steps: [
+                bazel_query {
+                    target_patterns: "kind(cc_binary, deps(//...))",
+                    label: "query binaries",
+                    key: "query"
+                }
+            ]
+        };
+        let yaml = serde_yaml::to_string(&p).unwrap();
+        assert!(yaml.contains("bazel query 'kind(cc_binary, deps(//...))'"));
+    }
+
     #[test]
     fn bazel_command_with_verb() {
         let p = pipeline! {
@@ -716,6 +802,27 @@ mod comptime_shell_tests {
     use super::*;
     use rust_buildkite::comptime_shell;
 
+    #[test]
+    fn shell_lines_standalone() {
+        let targets = comptime_shell!("printf '//app:a\n//app:b\n'", expect: "lines");
+        assert_eq!(targets, ["//app:a", "//app:b"]);
+    }
+
+    #[test]
+    fn shell_cwd_and_env() {
+        let dir = comptime_shell!("pwd", cwd: "manifest_dir");
+        assert_eq!(dir, env!("CARGO_MANIFEST_DIR"));
+
+        let p = pipeline! {
+            env: {
+                MANIFEST: comptime_shell!("ls $NAME", cwd: "workspace_root", env: { NAME: "Cargo.toml" })
+            },
+            steps: [command(cmd!("echo test")).key("test")]
+        };
+        let json = rust_buildkite::serde_json::to_value(&p).unwrap();
+        assert_eq!(json["env"]["MANIFEST"], "Cargo.toml");
+    }
+
     #[test]
     fn shell_echo_targets() {
         let p = pipeline! {
@@ -935,6 +1042,7 @@ mod step_options {
     fn with_parallelism() {
         let p = pipeline! {
             env: {},
+            lints: { parallelism_without_sharding: allow },
             steps: [
                 bazel_test {
                     target_patterns: "//...",
@@ -1172,6 +1280,28 @@ mod validation_options {
         assert!(yaml.contains("bazel deploy //app:server"));
     }
 
+    #[test]
+    fn pipeline_level_custom_verbs_inside_groups() {
+        let p = pipeline! {
+            custom_verbs: ["lint"],
+            steps: [
+                group {
+                    group: "Checks",
+                    key: "checks",
+                    steps: [
+                        bazel_lint { target_patterns: "//app/...", key: "lint_app" }
+                    ]
+                },
+                group("More checks")
+                    .key("more")
+                    .steps([bazel_lint { target_patterns: "//lib/...", key: "lint_lib" }])
+            ]
+        };
+        let yaml = serde_yaml::to_string(&p).unwrap();
+        assert!(yaml.contains("bazel lint //app/..."));
+        assert!(yaml.contains("bazel lint //lib/..."));
+    }
+
     #[test]
     fn pipeline_level_multiple_custom_verbs() {
         let p = pipeline! {
@@ -2026,3 +2156,322 @@ mod skip_comptime_validation {
         assert!(yaml.contains("//dynamic:target"));
     }
 }
+
+mod bazel_validation_modes {
+    use super::*;
+
+    fn strict() -> String {
+        let p = pipeline! {
+            steps: [
+                bazel_test {
+                    target_patterns: "//app:main_test",
+                    flags: "--config=ci",
+                    key: "test"
+                }
+            ]
+        };
+        serde_yaml::to_string(&p).unwrap()
+    }
+
+    #[test]
+    fn off_generates_same_pipeline() {
+        let p = pipeline! {
+            bazel_validation: "off",
+            steps: [
+                bazel_test {
+                    target_patterns: "//app:main_test",
+                    flags: "--config=ci",
+                    key: "test"
+                }
+            ]
+        };
+        assert_eq!(serde_yaml::to_string(&p).unwrap(), strict());
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn warn_generates_same_pipeline() {
+        let p = pipeline! {
+            bazel_validation: "warn",
+            steps: [
+                bazel_test {
+                    target_patterns: "//app:main_test",
+                    flags: "--config=ci",
+                    key: "test"
+                }
+            ]
+        };
+        assert_eq!(serde_yaml::to_string(&p).unwrap(), strict());
+    }
+}
+
+mod bazel_defaults {
+    use super::*;
+
+    #[test]
+    fn prepended_to_structured_steps() {
+        let p = pipeline! {
+            bazel_defaults: {
+                all: ["--announce_rc"],
+                test: ["--test_output=errors", "--keep_going"]
+            },
+            steps: [
+                bazel_test { target_patterns: "//...", flags: ["--test_output=all"], key: "test" },
+                bazel_build { target_patterns: "//...", key: "build" }
+            ]
+        };
+        let yaml = serde_yaml::to_string(&p).unwrap();
+        assert!(
+            yaml.contains("command: bazel test --announce_rc --keep_going --test_output=all //...")
+        );
+        assert!(yaml.contains("command: bazel build --announce_rc //..."));
+    }
+
+    #[test]
+    fn prepended_to_inline_bazel_commands() {
+        let p = pipeline! {
+            bazel_defaults: { test: ["--keep_going"] },
+            steps: [
+                command {
+                    command: bazel!("test //app/..."),
+                    key: "test"
+                }
+            ]
+        };
+        let yaml = serde_yaml::to_string(&p).unwrap();
+        assert!(yaml.contains("bazel test --keep_going //app/..."));
+    }
+
+    #[test]
+    fn standalone_bazel_macro_is_unaffected() {
+        let _p = pipeline! {
+            bazel_defaults: { build: ["--keep_going"] },
+            steps: [bazel_build { target_patterns: "//...", key: "build" }]
+        };
+        assert_eq!(bazel!("build //..."), "bazel build //...");
+    }
+}
+
+mod bazel_binary {
+    use super::*;
+
+    #[test]
+    fn replaces_command_prefix() {
+        let p = pipeline! {
+            allowed_commands: ["bazelisk"],
+            bazel_binary: "bazelisk",
+            steps: [
+                bazel_build { target_patterns: "//...", key: "build" },
+                command {
+                    command: bazel!("test //app/..."),
+                    key: "test"
+                }
+            ]
+        };
+        let yaml = serde_yaml::to_string(&p).unwrap();
+        assert!(yaml.contains("command: bazelisk build //..."));
+        assert!(yaml.contains("bazelisk test //app/..."));
+        assert!(!yaml.contains("bazel build"));
+    }
+
+    #[test]
+    fn defaults_to_bazel() {
+        let p = pipeline! {
+            steps: [bazel_build { target_patterns: "//...", key: "build" }]
+        };
+        let yaml = serde_yaml::to_string(&p).unwrap();
+        assert!(yaml.contains("command: bazel build //..."));
+    }
+}
+
+mod auto_keys {
+    use super::*;
+
+    #[test]
+    #[allow(deprecated)]
+    fn derives_key_and_label_from_targets() {
+        let p = pipeline! {
+            auto_keys: true,
+            steps: [
+                bazel_test { target_patterns: "//app/..." },
+                bazel_build { target_patterns: "//app:server", label: "Server" },
+                bazel_build { target_patterns: "//lib/...", key: "lib" },
+                command {
+                    command: bazel!("test //app/..."),
+                    depends_on: ["test-app-all", "server", "lib"]
+                }
+            ]
+        };
+        let yaml = serde_yaml::to_string(&p).unwrap();
+        assert!(yaml.contains("key: test-app-all\n"));
+        assert!(yaml.contains(":bazel: test //app/..."));
+        assert!(yaml.contains("key: server"));
+        assert!(yaml.contains(":bazel: build //lib/..."));
+        assert!(yaml.contains("key: lib"));
+        assert!(yaml.contains("key: test-app-all-2"));
+    }
+}
+
+mod bazel_bep {
+    use super::*;
+
+    #[test]
+    fn adds_flag_and_artifact_to_bazel_steps() {
+        let p = pipeline! {
+            bazel_bep: { path: "bep.json", upload: true },
+            steps: [
+                bazel_test { target_patterns: "//app/...", key: "test" },
+                command {
+                    command: bazel!("build //app/..."),
+                    key: "build"
+                },
+                command {
+                    command: cmd!("echo done"),
+                    key: "shell"
+                }
+            ]
+        };
+        let yaml = serde_yaml::to_string(&p).unwrap();
+        assert!(yaml.contains("bazel test --build_event_json_file=bep.json //app/..."));
+        assert!(yaml.contains("bazel build --build_event_json_file=bep.json //app/..."));
+        // Flag and artifact on the two bazel steps, nothing on the shell step
+        assert_eq!(yaml.matches("- bep.json").count(), 2);
+        assert_eq!(yaml.matches("bep.json").count(), 4);
+        assert!(yaml.contains("command: echo done"));
+    }
+
+    #[test]
+    fn respects_opt_out_and_existing_flag() {
+        let p = pipeline! {
+            bazel_bep: { path: "events.json", upload: false },
+            steps: [
+                bazel_build { target_patterns: "//...", key: "build", bep: false },
+                bazel_test {
+                    target_patterns: "//...",
+                    flags: ["--build_event_json_file=mine.json"],
+                    key: "test"
+                },
+                bazel_test { target_patterns: "//app/...", key: "app" }
+            ]
+        };
+        let yaml = serde_yaml::to_string(&p).unwrap();
+        assert!(yaml.contains("command: bazel build //..."));
+        assert!(yaml.contains("mine.json"));
+        assert_eq!(yaml.matches("events.json").count(), 1);
+        assert!(!yaml.contains("artifact_paths"));
+    }
+}
+
+mod bazel_workspace {
+    use super::*;
+
+    #[test]
+    fn step_workspace_runs_command_in_subshell() {
+        let p = pipeline! {
+            bazel_validation: "off",
+            steps: [
+                bazel_build {
+                    target_patterns: "//cpp/...",
+                    workspace: "examples/pipeline/bazel",
+                    key: "cpp"
+                },
+                bazel_build { target_patterns: "//app/...", key: "app" }
+            ]
+        };
+        let yaml = serde_yaml::to_string(&p).unwrap();
+        assert!(yaml.contains("(cd examples/pipeline/bazel && bazel build //cpp/...)"));
+        assert!(yaml.contains("command: bazel build //app/..."));
+    }
+
+    #[test]
+    fn pipeline_workspace_applies_to_every_bazel_command() {
+        let p = pipeline! {
+            bazel_validation: "off",
+            bazel_workspace: "examples/pipeline/bazel/",
+            steps: [
+                bazel_test { target_patterns: "//cpp/...", key: "test" },
+                command {
+                    command: bazel!("build //cpp/..."),
+                    key: "build"
+                },
+                command {
+                    command: cmd!("echo done"),
+                    key: "shell"
+                }
+            ]
+        };
+        let yaml = serde_yaml::to_string(&p).unwrap();
+        assert!(yaml.contains("(cd examples/pipeline/bazel && bazel test //cpp/...)"));
+        assert!(yaml.contains("(cd examples/pipeline/bazel && bazel build //cpp/...)"));
+        assert!(yaml.contains("command: echo done"));
+    }
+
+    #[test]
+    fn bep_artifact_is_relative_to_the_workspace() {
+        let p = pipeline! {
+            bazel_validation: "off",
+            bazel_bep: { path: "bep.json" },
+            steps: [
+                bazel_build {
+                    target_patterns: "//cpp/...",
+                    workspace: "examples/pipeline/bazel",
+                    key: "cpp"
+                }
+            ]
+        };
+        let yaml = serde_yaml::to_string(&p).unwrap();
+        assert!(yaml.contains("- examples/pipeline/bazel/bep.json"));
+    }
+}
+
+mod startup_options {
+    use super::*;
+
+    #[test]
+    fn placed_before_the_verb() {
+        let p = pipeline! {
+            bazel_defaults: { test: ["--test_output=errors"] },
+            steps: [
+                bazel_test {
+                    target_patterns: "//...",
+                    startup_options: ["--bazelrc=ci/.bazelrc", "--batch"],
+                    key: "test"
+                },
+                command {
+                    commands: [
+                        bazel_build {
+                            target_patterns: "//app/...",
+                            startup_options: ["--output_base /tmp/out"]
+                        }
+                    ],
+                    key: "build"
+                }
+            ]
+        };
+        let yaml = serde_yaml::to_string(&p).unwrap();
+        assert!(
+            yaml.contains("bazel --bazelrc=ci/.bazelrc --batch test --test_output=errors //...")
+        );
+        assert!(yaml.contains("bazel --output_base /tmp/out build //app/..."));
+    }
+}
+
+mod agent_keys {
+    use super::*;
+
+    #[test]
+    fn string_keys_on_bazel_steps() {
+        let p = pipeline! {
+            steps: [
+                bazel_test {
+                    target_patterns: "//...",
+                    key: "test",
+                    agents: { "aws:instance-type": "m5.large", queue: "bazel" }
+                }
+            ]
+        };
+        let json = rust_buildkite::serde_json::to_value(&p).unwrap();
+        assert_eq!(json["steps"][0]["agents"]["aws:instance-type"], "m5.large");
+        assert_eq!(json["steps"][0]["agents"]["queue"], "bazel");
+    }
+}
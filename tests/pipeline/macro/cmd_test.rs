@@ -144,6 +144,211 @@ mod runtime_interpolation {
     }
 }
 
+mod placeholders {
+    use super::*;
+
+    const REGION: &str = "us-east-1";
+
+    #[test]
+    fn const_placeholder() {
+        let c = cmd!("aws s3 ls --region {REGION}");
+        assert_eq!(c, "aws s3 ls --region us-east-1");
+    }
+
+    #[test]
+    fn local_placeholders() {
+        let bucket = "artifacts";
+        let c = cmd!("aws s3 cp out.tar s3://{bucket}/out.tar --region {REGION}");
+        assert_eq!(
+            c,
+            "aws s3 cp out.tar s3://artifacts/out.tar --region us-east-1"
+        );
+    }
+
+    #[test]
+    fn escaped_and_shell_braces_pass_through() {
+        let c = cmd!("echo {{REGION}} ${HOME} {REGION}");
+        assert_eq!(c, "echo {REGION} ${HOME} us-east-1");
+        let c = cmd!("find . -name '*.tmp' -exec rm {} +");
+        assert_eq!(c, "find . -name '*.tmp' -exec rm {} +");
+    }
+
+    #[test]
+    fn placeholder_in_pipeline() {
+        let pipeline = pipeline! {
+            allowed_commands: ["aws"],
+            steps: [
+                command(cmd!("aws s3 sync dist s3://site --region {REGION}")).key("sync")
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("aws s3 sync dist s3://site --region us-east-1"));
+    }
+}
+
+mod metadata {
+    use super::*;
+
+    fn json(pipeline: &impl serde::Serialize) -> String {
+        rust_buildkite::serde_json::to_string(pipeline).unwrap()
+    }
+
+    #[test]
+    fn set_metadata_literal_outside_allowlist() {
+        let pipeline = pipeline! {
+            allowed_commands: ["make"],
+            steps: [
+                command(cmd!("make release"))
+                    .key("release")
+                    .set_metadata("release-version", "1.2.3 beta")
+            ]
+        };
+
+        let json = json(&pipeline);
+        assert!(json.contains("make release"));
+        assert!(json.contains("buildkite-agent meta-data set release-version '1.2.3 beta'"));
+    }
+
+    #[test]
+    fn set_metadata_runtime_value_is_quoted() {
+        let version = "v1; rm -rf {/}".to_string();
+        let pipeline = pipeline! {
+            allowed_commands: ["make"],
+            steps: [
+                command(cmd!("make release")).set_metadata("release-version", version)
+            ]
+        };
+
+        let json = json(&pipeline);
+        assert!(json.contains("buildkite-agent meta-data set release-version 'v1; rm -rf {/}'"));
+    }
+
+    #[test]
+    fn metadata_step() {
+        let pipeline = pipeline! {
+            steps: [
+                command(cmd!("echo deploy")).key("deploy"),
+                metadata("deploy target", "it's prod").depends_on("deploy")
+            ]
+        };
+
+        let json = json(&pipeline);
+        assert!(json.contains(r#""key":"set-metadata-deploy-target""#));
+        assert!(json.contains("Set meta-data deploy target"));
+        assert!(json.contains(r#"buildkite-agent meta-data set 'deploy target' 'it'\\''s prod'"#));
+    }
+}
+
+mod needs_artifacts {
+    use super::*;
+
+    #[test]
+    fn downloads_and_depends_on_producers() {
+        let pipeline = pipeline! {
+            allowed_commands: ["make"],
+            steps: [
+                command(cmd!("make dist")).key("build").artifact_paths("dist/**"),
+                command(cmd!("make docs")).key("docs").artifact_paths("site/*.html;site/*.css"),
+                command(cmd!("make deploy"))
+                    .key("deploy")
+                    .needs_artifacts("build", "dist/app.tar")
+                    .needs_artifacts("docs", "site/*.html")
+            ]
+        };
+
+        let value = rust_buildkite::serde_json::to_value(&pipeline).unwrap();
+        let deploy = &value["steps"][2];
+        assert_eq!(
+            deploy["commands"],
+            rust_buildkite::serde_json::json!([
+                "buildkite-agent artifact download dist/app.tar . --step build",
+                "buildkite-agent artifact download 'site/*.html' . --step docs",
+                "make deploy"
+            ])
+        );
+        assert_eq!(
+            deploy["depends_on"],
+            rust_buildkite::serde_json::json!(["build", "docs"])
+        );
+    }
+
+    #[test]
+    fn downloads_from_prefixed_keys() {
+        let pipeline = pipeline! {
+            allowed_commands: ["make"],
+            steps: [
+                group("Backend")
+                    .key_prefix("backend-")
+                    .step(command(cmd!("make dist")).key("build").artifact_paths("dist/**"))
+                    .step(
+                        command(cmd!("make deploy"))
+                            .key("deploy")
+                            .needs_artifacts("build", "dist/app.tar")
+                    )
+            ]
+        };
+
+        let value = rust_buildkite::serde_json::to_value(&pipeline).unwrap();
+        let deploy = &value["steps"][0]["steps"][1];
+        assert_eq!(
+            deploy["commands"],
+            rust_buildkite::serde_json::json!([
+                "buildkite-agent artifact download dist/app.tar . --step backend-build",
+                "make deploy"
+            ])
+        );
+        assert_eq!(
+            deploy["depends_on"],
+            rust_buildkite::serde_json::json!(["backend-build"])
+        );
+    }
+}
+
+mod cargo_helpers {
+    use super::*;
+    use rust_buildkite::{cargo_build, cargo_test};
+
+    #[test]
+    fn helpers_expand_to_command_strings() {
+        let c = cargo_build!(release: true, locked: true);
+        assert_eq!(c, "cargo build --release --locked");
+        let c = cargo_test!(workspace: true, exclude: ["docs", "bench"]);
+        assert_eq!(c, "cargo test --workspace --exclude docs --exclude bench");
+    }
+
+    #[test]
+    fn helpers_in_command_position() {
+        let pipeline = pipeline! {
+            allowed_commands: ["cargo"],
+            steps: [
+                command(cargo_test!(package: "api", features: ["postgres", "tls"], no_default_features: true))
+                    .key("test"),
+                command {
+                    commands: [
+                        cargo_build!(release: true),
+                        cargo_clippy!(all_targets: true, extra_args: ["--", "-D", "warnings"])
+                    ],
+                    key: "lint"
+                }
+            ]
+        };
+
+        let value = rust_buildkite::serde_json::to_value(&pipeline).unwrap();
+        assert_eq!(
+            value["steps"][0]["command"],
+            "cargo test --package api --features postgres,tls --no-default-features"
+        );
+        assert_eq!(
+            value["steps"][1]["commands"],
+            rust_buildkite::serde_json::json!([
+                "cargo build --release",
+                "cargo clippy --all-targets -- -D warnings"
+            ])
+        );
+    }
+}
+
 mod pipeline_integration {
     use super::*;
 
@@ -322,6 +527,21 @@ mod allowed_commands {
         assert!(yaml.contains("cargo build"));
     }
 
+    #[test]
+    fn explicit_allowlist_includes_shell_builtins() {
+        let pipeline = pipeline! {
+            allowed_commands: ["make"],
+            steps: [
+                command(cmd!("cd app && make")).key("build"),
+                command(cmd!("export CI=1")).key("export")
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("cd app && make"));
+        assert!(yaml.contains("export CI=1"));
+    }
+
     #[test]
     fn default_uses_host_path_commands() {
         let pipeline = pipeline! {
@@ -402,6 +622,20 @@ mod expect_env {
         assert!(yaml.contains("$ALLOWED_VAR"));
     }
 
+    #[test]
+    fn error_code_allows_undefined_var() {
+        let pipeline = pipeline! {
+            expect_env: ["HOME"],
+            lints: { RBK0005: allow },
+            steps: [
+                command(cmd!(r#"echo "$SET_BY_AGENT_HOOK""#)).key("test")
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("$SET_BY_AGENT_HOOK"));
+    }
+
     #[test]
     fn shell_builtins_allowed() {
         let pipeline = pipeline! {
@@ -592,6 +826,13 @@ EOF"#
     //     assert!(c.contains("jq"));
     // }
 
+    // allow: lets the command through despite the false positive
+    #[test]
+    fn jq_dollar_in_single_quotes_with_allow() {
+        let c = cmd!(r#"jq '{$var}' file.json"#, allow: ["SC2086"]);
+        assert!(c.contains("jq"));
+    }
+
     // FAILS: [SC2086] - escaping doesn't help either
     // #[test]
     // fn jq_escaped_dollar_fails() {
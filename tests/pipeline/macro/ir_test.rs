@@ -0,0 +1,87 @@
+//! Tests for the pipeline_ir! macro (requires the `ir` feature)
+
+use rust_buildkite::ir::{IR_VERSION, PipelineIr, StepKind};
+use rust_buildkite::pipeline_ir;
+
+#[test]
+fn ir_describes_steps_and_commands() {
+    let ir = pipeline_ir! {
+        env: { CI: "true" },
+        steps: [
+            command(cmd!("cargo build")).key("build").label("Build"),
+            wait,
+            group("Tests")
+                .key("tests")
+                .steps([
+                    command(cmd!("cargo test")).key("unit").depends_on("build"),
+                    command(cmd!("cargo clippy")).key("lint").depends_on("build")
+                ]),
+            command(cmd!("echo done")).depends_on("tests").r#if("build.branch == 'main'")
+        ]
+    };
+
+    assert_eq!(ir.version, IR_VERSION);
+    assert_eq!(ir.env, vec!["CI".to_string()]);
+    let [build, wait, tests, done] = ir.steps.as_slice() else {
+        panic!("expected 4 top-level steps, got {}", ir.steps.len());
+    };
+    assert_eq!(build.kind, StepKind::Command);
+    assert_eq!(build.label.as_deref(), Some("Build"));
+    let command = build.commands.first().unwrap();
+    assert_eq!(command.name, "cargo");
+    assert_eq!(command.text.as_deref(), Some("cargo build"));
+    assert_eq!(wait.kind, StepKind::Wait);
+    assert_eq!(tests.kind, StepKind::Group);
+    assert_eq!(tests.steps.len(), 2);
+    assert_eq!(done.condition.as_deref(), Some("build.branch == 'main'"));
+    assert_eq!(ir.all_steps().len(), 6);
+    assert_eq!(ir.dependents("build").len(), 2);
+}
+
+#[test]
+fn ir_round_trips_through_json() {
+    let ir = pipeline_ir! {
+        steps: [
+            command(cmd!("cargo build")).key("build")
+        ]
+    };
+
+    let json = serde_json::to_string(&ir).unwrap();
+    let parsed: PipelineIr = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed, ir);
+    assert!(json.contains("\"kind\":\"command\""));
+}
+
+#[test]
+fn ir_carries_step_descriptions() {
+    let ir = pipeline_ir! {
+        steps: [
+            /// Runs the full integration suite
+            /// against staging
+            command(cmd!("cargo test")).key("integration"),
+            command(cmd!("cargo build")).key("build").description("Release build"),
+            /// Everything after the build
+            group("Checks").steps([
+                /// Wait for the build
+                wait
+            ]),
+            command(cmd!("echo done"))
+        ]
+    };
+
+    let descriptions: Vec<Option<&str>> = ir
+        .all_steps()
+        .iter()
+        .map(|step| step.description.as_deref())
+        .collect();
+    assert_eq!(
+        descriptions,
+        [
+            Some("Runs the full integration suite\nagainst staging"),
+            Some("Release build"),
+            Some("Everything after the build"),
+            Some("Wait for the build"),
+            None,
+        ]
+    );
+}
@@ -196,6 +196,7 @@ mod object_literal {
     #[test]
     fn command_with_timeout_parallelism_artifacts() {
         let pipeline = pipeline! {
+            lints: { parallelism_without_sharding: allow },
             steps: [
                 command {
                     command: cmd!("npm test"),
@@ -461,6 +462,52 @@ mod object_literal {
         assert!(yaml.contains("meta_data:"));
     }
 
+    #[test]
+    fn trigger_build_scalar_values() {
+        let pipeline = pipeline! {
+            steps: [
+                trigger {
+                    trigger: "deploy-service",
+                    build: {
+                        env: { REPLICAS: 3, DRY_RUN: false },
+                        meta_data: { retries: 3, canary: true, region: "us-east-1" }
+                    }
+                }
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("REPLICAS: 3"));
+        assert!(yaml.contains("DRY_RUN: false"));
+        assert!(yaml.contains("retries: 3"));
+        assert!(yaml.contains("canary: true"));
+        assert!(yaml.contains("region: us-east-1"));
+    }
+
+    #[test]
+    fn trigger_build_env_references() {
+        let pipeline = pipeline! {
+            expect_env: ["DEPLOY_TARGET"],
+            steps: [
+                trigger {
+                    trigger: "deploy-service",
+                    build: {
+                        branch: "$BUILDKITE_BRANCH",
+                        commit: "${BUILDKITE_COMMIT}",
+                        message: "Deploy to $DEPLOY_TARGET, costs $$5",
+                        env: { TARGET: "$DEPLOY_TARGET" },
+                        meta_data: { source: "$BUILDKITE_PIPELINE_SLUG" }
+                    }
+                }
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("branch: $BUILDKITE_BRANCH"));
+        assert!(yaml.contains("commit: ${BUILDKITE_COMMIT}"));
+        assert!(yaml.contains("TARGET: $DEPLOY_TARGET"));
+    }
+
     #[test]
     fn wait_simple() {
         let pipeline = pipeline! {
@@ -487,6 +534,35 @@ mod object_literal {
         assert!(yaml.contains("if: build.branch == 'main'"));
     }
 
+    #[test]
+    fn wait_with_schema_fields() {
+        let pipeline = pipeline! {
+            steps: [
+                command { command: cmd!("make build"), key: "build" },
+                wait {
+                    key: "after-build",
+                    depends_on: ["build"],
+                    branches: ["main", "release/*"],
+                    allow_dependency_failure: true
+                },
+                group {
+                    group: "Deploy",
+                    steps: [
+                        wait { depends_on: ["build"], branches: ["main"] }
+                    ]
+                }
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("key: after-build"), "{}", yaml);
+        assert!(yaml.contains("- main\n"), "{}", yaml);
+        assert!(yaml.contains("release/*"), "{}", yaml);
+        assert!(yaml.contains("allow_dependency_failure: true"), "{}", yaml);
+        assert_eq!(yaml.matches("depends_on:").count(), 2, "{}", yaml);
+        assert_eq!(yaml.matches("branches:").count(), 2, "{}", yaml);
+    }
+
     #[test]
     fn group_basic() {
         let pipeline = pipeline! {
@@ -535,6 +611,149 @@ mod object_literal {
         assert!(yaml.contains("notify:"));
         assert!(yaml.contains("allow_dependency_failure: true"));
     }
+
+    #[test]
+    fn pipeline_key_prefix() {
+        let pipeline = pipeline! {
+            key_prefix: "svc-",
+            steps: [
+                command {
+                    command: cmd!("cargo build"),
+                    key: "build"
+                },
+                group {
+                    group: "Tests",
+                    key: "tests",
+                    key_prefix: "unit-",
+                    depends_on: ["build"],
+                    steps: [
+                        command {
+                            command: cmd!("cargo test"),
+                            key: "run"
+                        }
+                    ]
+                }
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("key: svc-build"));
+        assert!(yaml.contains("key: svc-tests"));
+        assert!(yaml.contains("key: svc-unit-run"));
+        assert!(yaml.contains("- svc-build"));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn auto_keys_from_labels() {
+        let pipeline = pipeline! {
+            auto_keys: true,
+            steps: [
+                command {
+                    command: cmd!("cargo build"),
+                    label: ":rust: Build Release"
+                },
+                command {
+                    command: cmd!("cargo test"),
+                    label: "Test"
+                },
+                command {
+                    command: cmd!("cargo test --release"),
+                    label: "Test"
+                },
+                block {
+                    block: "Deploy?"
+                },
+                command {
+                    command: cmd!("echo deploy"),
+                    label: "Ship",
+                    key: "ship",
+                    depends_on: ["build-release", "test-2", "deploy"]
+                }
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("key: build-release"));
+        assert!(yaml.contains("key: test\n"));
+        assert!(yaml.contains("key: test-2"));
+        assert!(yaml.contains("key: deploy"));
+        assert!(yaml.contains("key: ship"));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn lints_at_warn_level_still_build() {
+        let pipeline = pipeline! {
+            lints: {
+                no_structure: warn,
+                no_structure_min_steps: 1,
+                redundant_depends_on: warn
+            },
+            steps: [
+                command {
+                    command: cmd!("cargo build"),
+                    key: "build"
+                },
+                command {
+                    command: cmd!("cargo test"),
+                    key: "test"
+                }
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("key: build"));
+        assert!(yaml.contains("key: test"));
+    }
+
+    #[test]
+    fn concurrency_group_policy() {
+        let pipeline = pipeline! {
+            expect_env: [BUILDKITE_ENV],
+            concurrency_group_policy: { require_var: "BUILDKITE_PIPELINE_SLUG" },
+            steps: [
+                command {
+                    command: cmd!("echo deploy"),
+                    concurrency: 1,
+                    concurrency_group: "${BUILDKITE_PIPELINE_SLUG}/deploy"
+                }
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("concurrency_group: ${BUILDKITE_PIPELINE_SLUG}/deploy"));
+    }
+
+    #[test]
+    fn group_defaults() {
+        let pipeline = pipeline! {
+            steps: [
+                group {
+                    group: "Tests",
+                    defaults: {
+                        agents: { queue: "test" },
+                        timeout_in_minutes: 15
+                    },
+                    steps: [
+                        command { command: cmd!("npm run unit"), key: "unit" },
+                        command {
+                            command: cmd!("npm run e2e"),
+                            key: "e2e",
+                            agents: { queue: "e2e" },
+                            timeout_in_minutes: 60
+                        }
+                    ]
+                }
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert_eq!(yaml.matches("queue: test").count(), 1);
+        assert!(yaml.contains("queue: e2e"));
+        assert!(yaml.contains("timeout_in_minutes: 15"));
+        assert!(yaml.contains("timeout_in_minutes: 60"));
+    }
 }
 
 mod fluent {
@@ -625,6 +844,7 @@ mod fluent {
     #[test]
     fn command_with_timeout_parallelism_artifacts() {
         let pipeline = pipeline! {
+            lints: { parallelism_without_sharding: allow },
             steps: [
                 command(cmd!("npm test"))
                     .timeout_in_minutes(30)
@@ -714,6 +934,58 @@ mod fluent {
         assert!(yaml.contains("docker#v5.0.0"));
     }
 
+    #[test]
+    fn plugin_yaml_matches_braced_plugin() {
+        use rust_buildkite::serde_json::to_value;
+
+        let braced = pipeline! {
+            steps: [
+                command(cmd!("npm test"))
+                    .key("test")
+                    .plugin("docker-compose#v4.16.0", {
+                        run: "app",
+                        config: ["docker-compose.yml", "docker-compose.ci.yml"],
+                        "mount-buildkite-agent": true,
+                        retries: 2
+                    }),
+                command {
+                    command: cmd!("npm run lint"),
+                    key: "lint",
+                    plugins: [
+                        "docker-login#v2.1.0",
+                        { "docker#v5.12.0": { image: "node:18", environment: ["CI"] } }
+                    ]
+                }
+            ]
+        };
+        let from_yaml = pipeline! {
+            steps: [
+                command(cmd!("npm test"))
+                    .key("test")
+                    .plugin_yaml("docker-compose#v4.16.0", r#"
+                        run: app
+                        config:
+                          - docker-compose.yml
+                          - docker-compose.ci.yml
+                        mount-buildkite-agent: true
+                        retries: 2
+                    "#),
+                command {
+                    command: cmd!("npm run lint"),
+                    key: "lint",
+                    plugins_yaml: r#"
+                        - docker-login#v2.1.0
+                        - docker#v5.12.0:
+                            image: node:18
+                            environment: [CI]
+                    "#
+                }
+            ]
+        };
+
+        assert_eq!(to_value(&from_yaml).unwrap(), to_value(&braced).unwrap());
+    }
+
     #[test]
     fn command_with_notify_slack() {
         let pipeline = pipeline! {
@@ -743,6 +1015,43 @@ mod fluent {
         assert!(yaml.contains("manual:"));
     }
 
+    #[test]
+    fn command_with_retry_presets() {
+        use rust_buildkite::serde_json::{json, to_value};
+
+        let pipeline = pipeline! {
+            steps: [
+                command(cmd!("npm test")).key("agent-loss").retry_on_agent_loss(),
+                command(cmd!("npm test")).key("flaky").retry_flaky(3),
+                command(cmd!("npm test"))
+                    .key("both")
+                    .retry_on_agent_loss()
+                    .retry_flaky(1)
+            ]
+        };
+
+        let value = to_value(&pipeline).unwrap();
+        assert_eq!(
+            value["steps"][0]["retry"],
+            json!({ "automatic": [
+                { "exit_status": -1, "limit": 2 },
+                { "signal_reason": "agent_stop", "limit": 2 }
+            ] })
+        );
+        assert_eq!(
+            value["steps"][1]["retry"],
+            json!({ "automatic": [{ "exit_status": "*", "limit": 3 }] })
+        );
+        assert_eq!(
+            value["steps"][2]["retry"],
+            json!({ "automatic": [
+                { "exit_status": -1, "limit": 2 },
+                { "signal_reason": "agent_stop", "limit": 2 },
+                { "exit_status": "*", "limit": 1 }
+            ] })
+        );
+    }
+
     #[test]
     fn block_basic() {
         let pipeline = pipeline! {
@@ -785,6 +1094,55 @@ mod fluent {
         assert!(yaml.contains("fields:"));
     }
 
+    #[test]
+    fn block_fields_array_and_team_list() {
+        let fluent = pipeline! {
+            steps: [
+                block("Deploy?")
+                    .key("approval")
+                    .allowed_teams(["admins", "release"])
+                    .fields([
+                        text { key: "reason", text: "Reason" },
+                        select { key: "env", select: "Environment", options: [{ label: "Prod", value: "prod" }] }
+                    ])
+            ]
+        };
+        let object = pipeline! {
+            steps: [
+                block {
+                    block: "Deploy?",
+                    key: "approval",
+                    allowed_teams: ["admins", "release"],
+                    fields: [
+                        text { key: "reason", text: "Reason" },
+                        select { key: "env", select: "Environment", options: [{ label: "Prod", value: "prod" }] }
+                    ]
+                }
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&fluent).unwrap();
+        assert!(yaml.contains("- admins"));
+        assert!(yaml.contains("- release"));
+        assert!(yaml.contains("key: reason"));
+        assert!(yaml.contains("key: env"));
+        assert_eq!(yaml, serde_yaml::to_string(&object).unwrap());
+    }
+
+    #[test]
+    fn allowed_teams_slugs() {
+        let pipeline = pipeline! {
+            steps: [
+                block("Deploy?").allowed_teams("sre-2").allowed_teams(["release-eng", "qa"])
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("- sre-2"));
+        assert!(yaml.contains("- release-eng"));
+        assert!(yaml.contains("- qa"));
+    }
+
     #[test]
     fn input_basic() {
         let pipeline = pipeline! {
@@ -822,6 +1180,44 @@ mod fluent {
         assert!(yaml.contains("prompt: Fill in the form"));
     }
 
+    #[test]
+    fn input_fields_array_and_team_list() {
+        let fluent = pipeline! {
+            steps: [
+                input("Enter details")
+                    .key("details")
+                    .allowed_teams(["platform", "release"])
+                    .field(text { key: "version", text: "Version" })
+                    .fields([
+                        text { key: "notes", text: "Notes" },
+                        select { key: "tier", select: "Tier", options: [{ label: "Gold", value: "gold" }] }
+                    ])
+            ]
+        };
+        let object = pipeline! {
+            steps: [
+                input {
+                    input: "Enter details",
+                    key: "details",
+                    allowed_teams: ["platform", "release"],
+                    fields: [
+                        text { key: "version", text: "Version" },
+                        text { key: "notes", text: "Notes" },
+                        select { key: "tier", select: "Tier", options: [{ label: "Gold", value: "gold" }] }
+                    ]
+                }
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&fluent).unwrap();
+        assert!(yaml.contains("- platform"));
+        assert!(yaml.contains("- release"));
+        assert!(yaml.contains("key: version"));
+        assert!(yaml.contains("key: notes"));
+        assert!(yaml.contains("key: tier"));
+        assert_eq!(yaml, serde_yaml::to_string(&object).unwrap());
+    }
+
     #[test]
     fn trigger_basic() {
         let pipeline = pipeline! {
@@ -932,48 +1328,170 @@ mod fluent {
         assert!(yaml.contains("notify:"));
         assert!(yaml.contains("allow_dependency_failure: true"));
     }
-}
 
-mod complex {
-    use super::*;
+    #[test]
+    fn group_notify_slack() {
+        let pipeline = pipeline! {
+            steps: [
+                group("Integration")
+                    .step(command(cmd!("echo test")))
+                    .notify([
+                        { slack: "#builds", r#if: "build.state == 'failed'" },
+                        { github_commit_status: { context: "integration" } }
+                    ])
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("#builds"));
+        assert!(yaml.contains("if: build.state == 'failed'"));
+        assert!(yaml.contains("context: integration"));
+    }
 
     #[test]
-    fn mixed_syntax_pipeline() {
+    fn group_chained_steps() {
         let pipeline = pipeline! {
-            env: {
-                CI: "true",
-                NODE_ENV: "test"
-            },
             steps: [
-                command(cmd!("npm install")).label("Install").key("install"),
-                command {
-                    command: cmd!("npm test"),
-                    label: "Test",
-                    key: "test",
-                    depends_on: ["install"]
-                },
-                wait,
-                block {
-                    block: "Deploy?",
-                    key: "approval"
-                },
-                trigger("deploy").r#async()
+                group("Checks")
+                    .step(command(cmd!("cargo fmt --check")).key("fmt"))
+                    .step(command(cmd!("cargo clippy")).key("clippy"))
+                    .step(command(cmd!("cargo test")).key("test").depends_on("fmt"))
             ]
         };
 
         let yaml = serde_yaml::to_string(&pipeline).unwrap();
-        assert!(yaml.contains("CI:"));
-        assert!(yaml.contains("npm install"));
-        assert!(yaml.contains("npm test"));
-        assert!(yaml.contains("wait"));
-        assert!(yaml.contains("block: Deploy?"));
-        assert!(yaml.contains("trigger: deploy"));
+        assert!(yaml.contains("key: fmt"));
+        assert!(yaml.contains("key: clippy"));
+        assert!(yaml.contains("key: test"));
+        assert!(yaml.contains("- fmt"));
     }
 
     #[test]
-    fn realistic_cicd_pipeline() {
+    fn group_defaults_merge_agents() {
         let pipeline = pipeline! {
-            env: { CI: "true" },
+            steps: [
+                group("Checks")
+                    .defaults({ agents: { queue: "linux", os: "ubuntu" }, timeout_in_minutes: 10 })
+                    .step(command(cmd!("cargo fmt --check")).key("fmt"))
+                    .step(command(cmd!("cargo test")).key("test").agents({ queue: "large" }))
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert_eq!(yaml.matches("queue: linux").count(), 1);
+        assert!(yaml.contains("queue: large"));
+        assert_eq!(yaml.matches("os: ubuntu").count(), 2);
+        assert_eq!(yaml.matches("timeout_in_minutes: 10").count(), 2);
+    }
+
+    #[test]
+    fn group_key_prefix() {
+        let pipeline = pipeline! {
+            steps: [
+                command(cmd!("echo setup")).key("setup"),
+                group("Backend")
+                    .key_prefix("backend-")
+                    .step(command(cmd!("cargo build")).key("build").depends_on("setup"))
+                    .step(command(cmd!("cargo test")).key("test").depends_on("build")),
+                group("Frontend")
+                    .key_prefix("frontend-")
+                    .step(command(cmd!("npm run build")).key("build"))
+                    .step(command(cmd!("npm test")).key("test").depends_on("build")),
+                command(cmd!("echo deploy")).depends_on("backend-test").depends_on("frontend-test")
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("key: backend-build"));
+        assert!(yaml.contains("key: frontend-build"));
+        assert!(yaml.contains("- backend-build"));
+        assert!(yaml.contains("- frontend-build"));
+        assert!(yaml.contains("- setup"));
+        assert!(!yaml.contains("backend-setup"));
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn group_key_prefix_with_auto_keys() {
+        let pipeline = pipeline! {
+            auto_keys: true,
+            steps: [
+                group("Backend")
+                    .key_prefix("backend-")
+                    .step(command(cmd!("cargo build")).label("Build"))
+                    .step(command(cmd!("cargo test")).label("Test").depends_on("build")),
+                command(cmd!("echo deploy")).label("Deploy").depends_on("backend-test")
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("key: backend-build"));
+        assert!(yaml.contains("key: backend-test"));
+        assert!(yaml.contains("- backend-build"));
+        assert!(yaml.contains("- backend-test"));
+        assert!(!yaml.contains("- build\n"));
+    }
+
+    #[test]
+    fn grouped_step_with_timeout_and_default_plugins() {
+        let pipeline = pipeline! {
+            default_plugins: [
+                { "docker#v5.0.0": { image: "rust:latest" } }
+            ],
+            steps: [
+                command(cmd!("cargo build")).key("build").timeout_in_minutes(10),
+                group("Tests")
+                    .step(command(cmd!("cargo test")).key("test").timeout_in_minutes(30))
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("timeout_in_minutes: 10"));
+        assert!(yaml.contains("timeout_in_minutes: 30"));
+        assert_eq!(yaml.matches("docker#v5.0.0").count(), 2);
+    }
+}
+
+mod complex {
+    use super::*;
+
+    #[test]
+    fn mixed_syntax_pipeline() {
+        let pipeline = pipeline! {
+            env: {
+                CI: "true",
+                NODE_ENV: "test"
+            },
+            steps: [
+                command(cmd!("npm install")).label("Install").key("install"),
+                command {
+                    command: cmd!("npm test"),
+                    label: "Test",
+                    key: "test",
+                    depends_on: ["install"]
+                },
+                wait,
+                block {
+                    block: "Deploy?",
+                    key: "approval"
+                },
+                trigger("deploy").r#async()
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("CI:"));
+        assert!(yaml.contains("npm install"));
+        assert!(yaml.contains("npm test"));
+        assert!(yaml.contains("wait"));
+        assert!(yaml.contains("block: Deploy?"));
+        assert!(yaml.contains("trigger: deploy"));
+    }
+
+    #[test]
+    fn realistic_cicd_pipeline() {
+        let pipeline = pipeline! {
+            env: { CI: "true" },
             steps: [
                 command {
                     command: cmd!("cargo fmt --check && cargo clippy"),
@@ -1157,6 +1675,71 @@ mod if_conditions {
         let yaml = serde_yaml::to_string(&pipeline).unwrap();
         assert!(yaml.contains("if:"));
     }
+
+    #[test]
+    fn condition_helpers() {
+        use rust_buildkite::serde_json::to_value;
+
+        let pipeline = pipeline! {
+            steps: [
+                command {
+                    command: cmd!("echo deploy"),
+                    key: "deploy",
+                    r#if: all!(on_branch!("main"), not_pull_request!())
+                },
+                command(cmd!("echo release"))
+                    .key("release")
+                    .r#if(any!(on_tag!(), pr_base_branch!("release/*")))
+            ]
+        };
+        let value = to_value(&pipeline).unwrap();
+        assert_eq!(
+            value["steps"][0]["if"],
+            "build.branch == 'main' && build.pull_request.id == null"
+        );
+        assert_eq!(
+            value["steps"][1]["if"],
+            "build.tag != null || build.pull_request.base_branch =~ /^release\\/.*$/"
+        );
+    }
+
+    #[test]
+    fn condition_helpers_outside_pipeline() {
+        const DEPLOY: &str = rust_buildkite::on_branch!("main");
+        assert_eq!(DEPLOY, "build.branch == 'main'");
+        assert_eq!(
+            rust_buildkite::all!(on_tag!("v*"), not_pull_request!()),
+            "build.tag =~ /^v.*$/ && build.pull_request.id == null"
+        );
+    }
+
+    #[test]
+    fn skip_helpers_emit_if() {
+        let pipeline = pipeline! {
+            steps: [
+                command(cmd!("./deploy.sh"))
+                    .key("deploy")
+                    .skip_unless("build.branch == 'main'"),
+                command(cmd!("npm test"))
+                    .key("test")
+                    .skip_if("build.pull_request.draft == true"),
+                command(cmd!("npm run e2e"))
+                    .key("e2e")
+                    .skip_if(any!(on_tag!(), on_branch!("release/*")))
+            ]
+        };
+        let json = rust_buildkite::serde_json::to_value(&pipeline).unwrap();
+        assert_eq!(json["steps"][0]["if"], "build.branch == 'main'");
+        assert_eq!(
+            json["steps"][1]["if"],
+            "!(build.pull_request.draft == true)"
+        );
+        assert_eq!(
+            json["steps"][2]["if"],
+            "!(build.tag != null || build.branch =~ /^release\\/.*$/)"
+        );
+        assert!(json["steps"][1].get("skip").is_none());
+    }
 }
 
 mod multiple_commands {
@@ -1338,3 +1921,1007 @@ mod dynamic_label_and_key {
         assert!(yaml.contains("label: Trigger downstream"));
     }
 }
+
+mod emit_yaml {
+    use rust_buildkite::pipeline;
+
+    #[test]
+    fn writes_static_pipeline_to_out_dir() {
+        let _p = pipeline! {
+            emit_yaml: "emit_yaml_test.yml",
+            env: { CI: "true" },
+            steps: [
+                command(cmd!("echo hello")).label("Say Hello").key("hello"),
+                wait,
+                command {
+                    command: cmd!("echo world"),
+                    key: "world",
+                    depends_on: ["hello"],
+                    agents: { queue: "default" }
+                }
+            ]
+        };
+
+        let yaml =
+            std::fs::read_to_string(concat!(env!("OUT_DIR"), "/emit_yaml_test.yml")).unwrap();
+        assert!(yaml.contains("CI: 'true'"));
+        assert!(yaml.contains("- command: echo hello"));
+        assert!(yaml.contains("label: Say Hello"));
+        assert!(yaml.contains("- wait\n"));
+        assert!(yaml.contains("depends_on:\n  - hello"));
+        assert!(yaml.contains("queue: default"));
+    }
+
+    #[test]
+    fn writes_wait_step_fields() {
+        let _p = pipeline! {
+            emit_yaml: "emit_yaml_wait_test.yml",
+            steps: [
+                command(cmd!("echo build")).key("build"),
+                wait {
+                    key: "release-gate",
+                    branches: ["main"],
+                    allow_dependency_failure: true
+                }
+            ]
+        };
+
+        let yaml =
+            std::fs::read_to_string(concat!(env!("OUT_DIR"), "/emit_yaml_wait_test.yml")).unwrap();
+        assert!(
+            yaml.contains("- wait: ''\n  key: release-gate\n  branches:\n  - main\n  allow_dependency_failure: true\n"),
+            "{}",
+            yaml
+        );
+    }
+
+    #[test]
+    fn writes_wait_for_as_a_keyed_wait() {
+        let _p = pipeline! {
+            emit_yaml: "emit_yaml_wait_for_test.yml",
+            steps: [
+                command(cmd!("echo lint")).key("lint"),
+                command(cmd!("echo test")).key("test"),
+                wait_for(["lint", "test"])
+            ]
+        };
+
+        let yaml =
+            std::fs::read_to_string(concat!(env!("OUT_DIR"), "/emit_yaml_wait_for_test.yml"))
+                .unwrap();
+        assert!(
+            yaml.contains(
+                "- wait: ''\n  key: wait-for-lint-test\n  depends_on:\n  - lint\n  - test\n"
+            ),
+            "{}",
+            yaml
+        );
+    }
+}
+
+mod try_pipeline {
+    use rust_buildkite::{
+        BuildError, IntoBuildResult, JsonSchemaForBuildkitePipelineConfigurationFiles, try_pipeline,
+    };
+
+    fn build(
+        key_name: &str,
+    ) -> Result<JsonSchemaForBuildkitePipelineConfigurationFiles, BuildError> {
+        let p = try_pipeline! {
+            steps: [
+                command(cmd!("echo build")).key(runtime!(key_name.to_string())),
+                group("Tests").step(command(cmd!("echo test")).label("Test"))
+            ]
+        }?;
+        Ok(p)
+    }
+
+    #[test]
+    fn returns_pipeline_on_success() {
+        let p = build("build").unwrap();
+        let yaml = serde_yaml::to_string(&p).unwrap();
+        assert!(yaml.contains("key: build"));
+        assert!(yaml.contains("group: Tests"));
+    }
+
+    fn shard_pipeline(
+        shard: &str,
+    ) -> Result<Option<JsonSchemaForBuildkitePipelineConfigurationFiles>, std::num::ParseIntError>
+    {
+        let p = rust_buildkite::pipeline! {
+            steps: [
+                command(cmd!("echo shard"))
+                    .key(runtime!(format!("shard-{}", shard.parse::<u32>()?)))
+                    .label(runtime!(if shard == "0" { return Ok(None) } else { "Shard" }))
+            ]
+        };
+        Ok(Some(p))
+    }
+
+    #[test]
+    fn runtime_values_in_pipeline_use_the_callers_fn() {
+        let p = shard_pipeline("3").unwrap().unwrap();
+        let yaml = serde_yaml::to_string(&p).unwrap();
+        assert!(yaml.contains("key: shard-3"), "{}", yaml);
+        assert!(shard_pipeline("x").is_err());
+        assert!(shard_pipeline("0").unwrap().is_none());
+    }
+
+    fn try_shard_pipeline(
+        shard: &str,
+    ) -> Result<
+        Option<Result<JsonSchemaForBuildkitePipelineConfigurationFiles, BuildError>>,
+        std::num::ParseIntError,
+    > {
+        let p = try_pipeline! {
+            steps: [
+                command(cmd!("echo shard"))
+                    .key(runtime!(format!("shard-{}", shard.parse::<u32>()?)))
+                    .label(runtime!(if shard == "0" { return Ok(None) } else { "Shard" }))
+            ]
+        };
+        Ok(Some(p))
+    }
+
+    #[test]
+    fn runtime_values_in_try_pipeline_use_the_callers_fn() {
+        let p = try_shard_pipeline("3").unwrap().unwrap().unwrap();
+        let yaml = serde_yaml::to_string(&p).unwrap();
+        assert!(yaml.contains("key: shard-3"), "{}", yaml);
+        assert!(try_shard_pipeline("x").is_err());
+        assert!(try_shard_pipeline("0").unwrap().is_none());
+    }
+
+    #[test]
+    fn build_error_names_the_step() {
+        let err = "x"
+            .parse::<u32>()
+            .into_build_result("command step 'build'", "invalid key")
+            .unwrap_err();
+        assert_eq!(err.step, "command step 'build'");
+        assert_eq!(
+            err.to_string(),
+            "command step 'build': invalid key: invalid digit found in string"
+        );
+    }
+}
+
+mod path_base {
+    use super::*;
+
+    #[test]
+    fn relative_paths_resolve_against_path_base() {
+        let pipeline = pipeline! {
+            path_base: "rust-buildkite-macros",
+            steps: [
+                command(cmd!("./fetch_protos.sh")).key("protos")
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("command: ./fetch_protos.sh"));
+    }
+}
+
+mod skip_validation {
+    use super::*;
+
+    #[test]
+    fn exempts_step_from_command_path_and_env_checks() {
+        let pipeline = pipeline! {
+            allowed_commands: ["echo"],
+            steps: [
+                command(cmd!("gpu-burn --device \"$GPU_DEVICE_ID\" 60"))
+                    .key("burn")
+                    .skip_validation("only exists on gpu agents"),
+                command {
+                    command: cmd!("./agent-only/warm-cache.sh"),
+                    key: "warm",
+                    skip_validation: "script is baked into the agent image"
+                },
+                group("GPU").step(
+                    command(cmd!("nvidia-smi"))
+                        .key("smi")
+                        .skip_validation("driver tools only on gpu agents")
+                )
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("gpu-burn"));
+        assert!(yaml.contains("./agent-only/warm-cache.sh"));
+        assert!(yaml.contains("nvidia-smi"));
+        assert!(!yaml.contains("skip_validation"));
+        assert!(!yaml.contains("gpu agents"));
+    }
+}
+
+mod unchecked_cmd {
+    use super::*;
+
+    #[test]
+    fn accepted_with_pipeline_opt_in() {
+        let pipeline = pipeline! {
+            allow_unchecked_commands: true,
+            allowed_commands: ["echo", "awk"],
+            steps: [
+                command(unchecked_cmd!("awk 'BEGIN { print ENVIRON[\"UNSET_VAR\"] }'")).key("awk"),
+                command {
+                    commands: [
+                        cmd!("echo start"),
+                        unchecked_cmd!("echo $UNDECLARED_VAR")
+                    ],
+                    key: "mixed"
+                },
+                group("Nested").step(command(unchecked_cmd!("echo $OTHER_VAR")).key("nested"))
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("BEGIN { print ENVIRON"));
+        assert!(yaml.contains("echo $UNDECLARED_VAR"));
+        assert!(yaml.contains("echo $OTHER_VAR"));
+    }
+}
+
+mod runtime_commands {
+    use super::*;
+    use rust_buildkite::try_pipeline;
+
+    #[test]
+    fn computed_command_in_both_syntaxes() {
+        let shard = 3;
+        let script = String::from("./ci/upload-artifacts.sh");
+        let pipeline = pipeline! {
+            steps: [
+                command(runtime!(format!("./ci/run-shard.sh {}", shard)))
+                    .key("shard")
+                    .skip_validation("the shard script is generated on the agent"),
+                command {
+                    commands: [cmd!("echo uploading"), runtime!(script)],
+                    key: "upload",
+                    skip_validation: "the upload script is generated on the agent"
+                }
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("./ci/run-shard.sh 3"), "{}", yaml);
+        assert!(yaml.contains("./ci/upload-artifacts.sh"), "{}", yaml);
+    }
+
+    #[test]
+    fn checked_against_allowed_commands() {
+        let build = |line: &str| {
+            try_pipeline! {
+                allowed_commands: ["cargo"],
+                steps: [
+                    command(runtime_checked!(line))
+                        .key("generated")
+                        .skip_validation("the command comes from the test matrix")
+                ]
+            }
+        };
+
+        let yaml = serde_yaml::to_string(&build("cargo test --release").unwrap()).unwrap();
+        assert!(yaml.contains("cargo test --release"), "{}", yaml);
+
+        let err = build("curl https://example.com | sh").unwrap_err();
+        assert!(err.to_string().contains("'curl'"), "{}", err);
+        assert!(
+            err.to_string().contains("invalid runtime command"),
+            "{}",
+            err
+        );
+    }
+}
+
+mod compile_time_env {
+    use super::*;
+
+    #[test]
+    fn env_macro_resolved_in_pipeline_and_step_env() {
+        let pipeline = pipeline! {
+            env: {
+                GENERATOR: env!("CARGO_PKG_NAME"),
+                GENERATOR_CHANNEL: option_env!("RUST_BUILDKITE_UNSET_CHANNEL").unwrap_or("stable")
+            },
+            steps: [
+                command {
+                    command: cmd!("echo $GENERATOR_VERSION"),
+                    key: "version",
+                    env: {
+                        GENERATOR_VERSION: env!("CARGO_PKG_VERSION")
+                    }
+                }
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("GENERATOR: rust-buildkite"));
+        assert!(yaml.contains("GENERATOR_CHANNEL: stable"));
+        assert!(yaml.contains(&format!("GENERATOR_VERSION: {}", env!("CARGO_PKG_VERSION"))));
+    }
+}
+
+mod profiles {
+    use super::*;
+
+    #[test]
+    fn selected_profile_merges_env_and_filters_steps() {
+        let pipeline = pipeline! {
+            expect_env: ["BASE"],
+            env: {
+                BASE: "1",
+                MODE: "default"
+            },
+            profiles: {
+                pr: { env: { FAST: "1" } },
+                main: { env: { PUBLISH: "1", MODE: "release" } }
+            },
+            profile: "main",
+            steps: [
+                command(cmd!("cargo test")).key("test"),
+                command(cmd!("echo $PUBLISH $MODE"))
+                    .key("publish")
+                    .only_profiles(["main"]),
+                command {
+                    command: cmd!("echo $FAST"),
+                    key: "quick",
+                    only_profiles: ["pr"]
+                },
+                group("Release")
+                    .only_profiles(["main"])
+                    .steps([
+                        command(cmd!("echo release")).key("release"),
+                        command(cmd!("echo lint")).key("lint").only_profiles(["pr"])
+                    ])
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("BASE: '1'"));
+        assert!(yaml.contains("PUBLISH: '1'"));
+        assert!(yaml.contains("MODE: release"));
+        assert!(!yaml.contains("MODE: default"));
+        assert!(!yaml.contains("FAST"));
+        assert!(yaml.contains("key: publish"));
+        assert!(yaml.contains("key: release"));
+        assert!(!yaml.contains("key: quick"));
+        assert!(!yaml.contains("key: lint"));
+    }
+
+    #[test]
+    fn unselected_profile_drops_restricted_steps() {
+        let pipeline = pipeline! {
+            profiles: {
+                pr: { env: { FAST: "1" } },
+                main: { env: { PUBLISH: "1" } }
+            },
+            steps: [
+                command(cmd!("cargo test")).key("test"),
+                command(cmd!("echo publish")).key("publish").only_profiles(["main"])
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("key: test"));
+        assert!(!yaml.contains("key: publish"));
+        assert!(!yaml.contains("PUBLISH"));
+    }
+
+    #[test]
+    fn profile_selected_at_expansion_time() {
+        let pipeline = pipeline! {
+            profiles: {
+                pr: { env: { SELECTED: "no" } },
+                main: { env: { SELECTED: "yes" } }
+            },
+            profile: comptime_shell!("echo main"),
+            steps: [
+                command(cmd!("echo ok")).key("ok")
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("SELECTED: yes"));
+    }
+}
+
+mod lazy {
+    use super::*;
+    use rust_buildkite::JsonSchemaForBuildkitePipelineConfigurationFiles as Pipeline;
+
+    fn lazy_pipeline() -> &'static Pipeline {
+        pipeline! {
+            lazy: true,
+            env: { CI: "true" },
+            steps: [
+                command(cmd!("cargo test")).key("test").label("Test")
+            ]
+        }
+    }
+
+    #[test]
+    fn lazy_pipeline_is_built_once() {
+        let first = lazy_pipeline();
+        let second = lazy_pipeline();
+        assert!(std::ptr::eq(first, second));
+
+        let eager = pipeline! {
+            env: { CI: "true" },
+            steps: [
+                command(cmd!("cargo test")).key("test").label("Test")
+            ]
+        };
+        assert_eq!(
+            serde_yaml::to_string(first).unwrap(),
+            serde_yaml::to_string(&eager).unwrap()
+        );
+    }
+}
+
+mod label_interpolation {
+    use super::*;
+
+    #[test]
+    fn known_label_vars_are_accepted() {
+        let pipeline = pipeline! {
+            lints: { unknown_label_var: deny },
+            env: { REGION: "us-east-1" },
+            expect_env: ["RELEASE_CHANNEL"],
+            steps: [
+                command(cmd!("cargo build")).label("Build ${BUILDKITE_BRANCH} in $REGION"),
+                block("Release $RELEASE_CHANNEL for $$5?"),
+                group("Deploy ${BUILDKITE_TAG:-untagged}")
+                    .step(command(cmd!("cargo test")).label("Test $BUILDKITE_COMMIT"))
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("Build ${BUILDKITE_BRANCH} in $REGION"));
+        assert!(yaml.contains("$$5?"));
+    }
+}
+
+mod step_descriptions {
+    use super::*;
+
+    #[test]
+    fn doc_comments_are_not_emitted_by_default() {
+        let documented = pipeline! {
+            steps: [
+                /// Runs the full integration suite against staging
+                command(cmd!("cargo test")).key("integration")
+            ]
+        };
+        let plain = pipeline! {
+            steps: [
+                command(cmd!("cargo test")).key("integration")
+            ]
+        };
+        assert_eq!(
+            serde_yaml::to_string(&documented).unwrap(),
+            serde_yaml::to_string(&plain).unwrap()
+        );
+    }
+
+    #[test]
+    fn description_env_sets_step_description() {
+        let pipeline = pipeline! {
+            description_env: true,
+            steps: [
+                /// Runs the full integration suite against staging
+                command(cmd!("cargo test")).key("integration"),
+                command {
+                    command: cmd!("cargo build"),
+                    key: "build",
+                    description: "Release build",
+                    env: { STEP_DESCRIPTION: "set by hand" }
+                },
+                group("Lint").step(command(cmd!("cargo clippy")).description("Clippy"))
+            ]
+        };
+
+        let json = rust_buildkite::serde_json::to_value(&pipeline).unwrap();
+        assert_eq!(
+            json["steps"][0]["env"]["STEP_DESCRIPTION"],
+            "Runs the full integration suite against staging"
+        );
+        assert_eq!(json["steps"][1]["env"]["STEP_DESCRIPTION"], "set by hand");
+        assert_eq!(
+            json["steps"][2]["steps"][0]["env"]["STEP_DESCRIPTION"],
+            "Clippy"
+        );
+    }
+}
+
+mod docker_compose {
+    use super::*;
+    use rust_buildkite::serde_json::to_value;
+
+    #[test]
+    fn helper_matches_handwritten_plugin() {
+        let helper = pipeline! {
+            expect_paths: ["docker-compose.ci.yml"],
+            steps: [
+                command(cmd!("npm test"))
+                    .key("test")
+                    .docker_compose(
+                        run: "app",
+                        config: "docker-compose.ci.yml",
+                        mount_buildkite_agent: true,
+                        env: ["CI"]
+                    )
+            ]
+        };
+        let handwritten = pipeline! {
+            steps: [
+                command(cmd!("npm test"))
+                    .key("test")
+                    .plugin("docker-compose#v5.4.1", {
+                        run: "app",
+                        config: "docker-compose.ci.yml",
+                        "mount-buildkite-agent": true,
+                        env: ["CI"]
+                    })
+            ]
+        };
+        assert_eq!(to_value(&helper).unwrap(), to_value(&handwritten).unwrap());
+        assert_eq!(
+            serde_yaml::to_string(&helper).unwrap(),
+            serde_yaml::to_string(&handwritten).unwrap()
+        );
+    }
+
+    #[test]
+    fn plugin_versions_pins_the_helper() {
+        let pipeline = pipeline! {
+            plugin_versions: { "docker-compose": "v5.5.0" },
+            expect_paths: ["docker-compose.ci.yml"],
+            steps: [
+                group("Tests").step(
+                    command(cmd!("npm test"))
+                        .plugin("docker-login#v2.1.0", { username: "ci" })
+                        .docker_compose(run: "app", config: "docker-compose.ci.yml")
+                )
+            ]
+        };
+        let json = to_value(&pipeline).unwrap();
+        let plugins = &json["steps"][0]["steps"][0]["plugins"];
+        assert!(plugins[0].get("docker-login#v2.1.0").is_some());
+        assert_eq!(plugins[1]["docker-compose#v5.5.0"]["run"], "app");
+    }
+}
+
+mod env_keys {
+    use super::*;
+
+    #[test]
+    fn string_keys_allow_names_that_arent_idents() {
+        let pipeline = pipeline! {
+            env: { "APP.CONFIG-PATH": "ci.toml", CI: "true" },
+            steps: [
+                command(cmd!("cargo test"))
+                    .key("test")
+                    .env("CARGO.PROFILE", "ci"),
+                command {
+                    command: cmd!("cargo build"),
+                    key: "build",
+                    env: { "BUILD-MODE": "release" }
+                },
+                group("Lint")
+                    .defaults({ env: { "LINT.LEVEL": "strict" } })
+                    .step(command(cmd!("cargo clippy")).key("clippy"))
+            ]
+        };
+
+        let json = rust_buildkite::serde_json::to_value(&pipeline).unwrap();
+        assert_eq!(json["env"]["APP.CONFIG-PATH"], "ci.toml");
+        assert_eq!(json["steps"][0]["env"]["CARGO.PROFILE"], "ci");
+        assert_eq!(json["steps"][1]["env"]["BUILD-MODE"], "release");
+        assert_eq!(json["steps"][2]["steps"][0]["env"]["LINT.LEVEL"], "strict");
+    }
+}
+
+mod agent_keys {
+    use super::*;
+
+    #[test]
+    fn string_keys_allow_tags_that_arent_idents() {
+        let pipeline = pipeline! {
+            agents: { "aws:region": "us-east-1", queue: "default" },
+            steps: [
+                command(cmd!("cargo test"))
+                    .key("test")
+                    .agents("aws:instance-type", "m5.large"),
+                command(cmd!("cargo bench"))
+                    .key("bench")
+                    .agents({ "aws:instance-type": "c5.xlarge", queue: "bench" }),
+                command {
+                    command: cmd!("cargo build"),
+                    key: "build",
+                    agents: { "aws:instance-type": "m5.2xlarge" }
+                },
+                group("Lint")
+                    .defaults({ agents: { "aws:instance-type": "t3.medium" } })
+                    .step(command(cmd!("cargo clippy")).key("clippy"))
+            ]
+        };
+
+        let json = rust_buildkite::serde_json::to_value(&pipeline).unwrap();
+        assert_eq!(json["agents"]["aws:region"], "us-east-1");
+        assert_eq!(json["agents"]["queue"], "default");
+        assert_eq!(json["steps"][0]["agents"]["aws:instance-type"], "m5.large");
+        assert_eq!(json["steps"][1]["agents"]["aws:instance-type"], "c5.xlarge");
+        assert_eq!(json["steps"][1]["agents"]["queue"], "bench");
+        assert_eq!(
+            json["steps"][2]["agents"]["aws:instance-type"],
+            "m5.2xlarge"
+        );
+        assert_eq!(
+            json["steps"][3]["steps"][0]["agents"]["aws:instance-type"],
+            "t3.medium"
+        );
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("aws:instance-type: m5.large"), "{}", yaml);
+    }
+}
+
+mod comptime_shell_output {
+    use super::*;
+
+    #[test]
+    fn lines_fill_list_positions() {
+        let pipeline = pipeline! {
+            expect_env: [comptime_shell!("printf 'DEPLOY_TARGET\nREGION\n'", expect: "lines")],
+            steps: [
+                command(cmd!("echo $DEPLOY_TARGET $REGION"))
+                    .key("deploy")
+                    .matrix(comptime_shell!("printf 'node:18\n\nnode:20\n'", expect: "lines")),
+                command {
+                    command: cmd!("echo test"),
+                    key: "test",
+                    matrix: ["node:16", comptime_shell!("echo node:22")]
+                }
+            ]
+        };
+
+        let json = rust_buildkite::serde_json::to_value(&pipeline).unwrap();
+        assert_eq!(
+            json["steps"][0]["matrix"],
+            rust_buildkite::serde_json::json!(["node:18", "node:20"])
+        );
+        assert_eq!(
+            json["steps"][1]["matrix"],
+            rust_buildkite::serde_json::json!(["node:16", "node:22"])
+        );
+    }
+
+    #[test]
+    fn non_empty_keeps_every_line() {
+        let pipeline = pipeline! {
+            env: { NOTES: comptime_shell!("printf 'first\nsecond'", expect: "non_empty") },
+            steps: [command(cmd!("echo test")).key("test")]
+        };
+
+        let json = rust_buildkite::serde_json::to_value(&pipeline).unwrap();
+        assert_eq!(json["env"]["NOTES"], "first\nsecond");
+    }
+}
+
+mod agents_list_and_queue {
+    use super::*;
+
+    #[test]
+    fn list_form_and_queue_shorthand() {
+        let pipeline = pipeline! {
+            agents: ["queue=default-v2", "os=linux"],
+            steps: [
+                command(cmd!("cargo test")).key("test").queue("test-runners"),
+                command(cmd!("cargo bench"))
+                    .key("bench")
+                    .agents(["queue=bench", "aws:instance-type=c5.xlarge"]),
+                command {
+                    command: cmd!("cargo build"),
+                    key: "build",
+                    queue: "builders"
+                },
+                command {
+                    command: cmd!("cargo doc"),
+                    key: "doc",
+                    agents: ["queue=docs"]
+                }
+            ]
+        };
+
+        let json = rust_buildkite::serde_json::to_value(&pipeline).unwrap();
+        assert_eq!(
+            json["agents"],
+            rust_buildkite::serde_json::json!(["queue=default-v2", "os=linux"])
+        );
+        assert_eq!(json["steps"][0]["agents"]["queue"], "test-runners");
+        assert_eq!(
+            json["steps"][1]["agents"],
+            rust_buildkite::serde_json::json!(["queue=bench", "aws:instance-type=c5.xlarge"])
+        );
+        assert_eq!(json["steps"][2]["agents"]["queue"], "builders");
+        assert_eq!(
+            json["steps"][3]["agents"],
+            rust_buildkite::serde_json::json!(["queue=docs"])
+        );
+    }
+
+    #[test]
+    fn pipeline_queue_shorthand() {
+        let pipeline = pipeline! {
+            queue: "default-v2",
+            steps: [command(cmd!("echo test")).key("test")]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("queue: default-v2"), "{}", yaml);
+    }
+}
+
+mod bare_ident_values {
+    use super::*;
+
+    #[test]
+    fn allowed_by_default_outside_retry_and_matrix() {
+        let pipeline = pipeline! {
+            steps: [
+                trigger {
+                    trigger: "deploy-service",
+                    key: "deploy",
+                    build: { env: { TARGET: prod } }
+                }
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("TARGET: prod"), "{}", yaml);
+    }
+
+    #[test]
+    fn lint_can_allow_them_in_matrix() {
+        let pipeline = pipeline! {
+            lints: { bare_ident_value: allow },
+            steps: [
+                command(cmd!("echo test")).key("test").matrix([linux, macos])
+            ]
+        };
+
+        let json = rust_buildkite::serde_json::to_value(&pipeline).unwrap();
+        assert_eq!(
+            json["steps"][0]["matrix"],
+            rust_buildkite::serde_json::json!(["linux", "macos"])
+        );
+    }
+}
+
+mod step_shell {
+    use super::*;
+
+    #[test]
+    fn shell_only_changes_validation() {
+        let pipeline = pipeline! {
+            shell: "sh",
+            steps: [
+                command(cmd!("cargo test")).key("test"),
+                command(cmd!("cargo build")).key("build").shell("bash"),
+                command {
+                    command: cmd!("cargo doc"),
+                    key: "doc",
+                    shell: "sh"
+                }
+            ]
+        };
+
+        let json = rust_buildkite::serde_json::to_value(&pipeline).unwrap();
+        assert_eq!(json["steps"][0]["command"], "cargo test");
+        assert_eq!(json["steps"][1]["command"], "cargo build");
+        assert_eq!(json["steps"][2]["command"], "cargo doc");
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(!yaml.contains("shell"), "{}", yaml);
+    }
+}
+
+mod plugin_only_steps {
+    use super::*;
+
+    #[test]
+    fn steps_without_a_command() {
+        let pipeline = pipeline! {
+            steps: [
+                plugin_step("docker-compose#v5.4.1", { run: "app" })
+                    .key("app-tests")
+                    .env(CI, "true"),
+                command {
+                    key: "lint",
+                    agents: { queue: "linters" },
+                    plugins: [{ "docker-compose#v5.4.1": { run: "lint" } }]
+                }
+            ]
+        };
+
+        let json = rust_buildkite::serde_json::to_value(&pipeline).unwrap();
+        for step in json["steps"].as_array().unwrap() {
+            assert!(step.get("command").is_none(), "{}", step);
+            assert!(step.get("commands").is_none(), "{}", step);
+        }
+        assert_eq!(
+            json["steps"][0]["plugins"][0]["docker-compose#v5.4.1"]["run"],
+            "app"
+        );
+        assert_eq!(json["steps"][0]["env"]["CI"], "true");
+        assert_eq!(json["steps"][1]["agents"]["queue"], "linters");
+    }
+}
+
+mod computed_labels_and_skip_reasons {
+    use super::*;
+
+    #[test]
+    fn computed_at_expansion_time() {
+        let pipeline = pipeline! {
+            steps: [
+                command(cmd!("cargo test"))
+                    .key("test")
+                    .label(env!("CARGO_PKG_NAME"))
+                    .skip(comptime_shell!("echo flaky on arm64")),
+                group {
+                    group: comptime_shell!("echo Deploy"),
+                    key: "deploy",
+                    steps: [command(cmd!("echo deploy")).key("deploy-prod")]
+                }
+            ]
+        };
+
+        let json = rust_buildkite::serde_json::to_value(&pipeline).unwrap();
+        assert_eq!(json["steps"][0]["label"], env!("CARGO_PKG_NAME"));
+        assert_eq!(json["steps"][0]["skip"], "flaky on arm64");
+        assert_eq!(json["steps"][1]["group"], "Deploy");
+    }
+}
+
+mod computed_prompts {
+    use super::*;
+
+    #[test]
+    fn comptime_shell_prompts_serialize_as_strings() {
+        let pipeline = pipeline! {
+            steps: [
+                command(cmd!("cargo build")).key("build"),
+                block(comptime_shell!("echo Release 1.4?"))
+                    .key("release")
+                    .prompt(comptime_shell!("echo Ship 1.4 to production")),
+                input {
+                    input: comptime!(comptime_shell!("echo Notes for 1.4")),
+                    key: "notes"
+                }
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("block: Release 1.4?"));
+        assert!(yaml.contains("prompt: Ship 1.4 to production"));
+        assert!(yaml.contains("input: Notes for 1.4"));
+    }
+
+    #[test]
+    fn runtime_labels_are_built_when_the_pipeline_is() {
+        let shard = 3;
+        let pipeline = pipeline! {
+            steps: [
+                command(cmd!("cargo test"))
+                    .key("test")
+                    .label(runtime!(format!("Test shard {}", shard))),
+                block(runtime!(format!("Promote shard {}?", shard))).key("promote")
+            ]
+        };
+
+        let json = rust_buildkite::serde_json::to_value(&pipeline).unwrap();
+        assert_eq!(json["steps"][0]["label"], "Test shard 3");
+        assert_eq!(json["steps"][1]["block"], "Promote shard 3?");
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn auto_keys_derive_from_computed_prompts() {
+        let pipeline = pipeline! {
+            auto_keys: true,
+            steps: [
+                command(cmd!("cargo build")).label(comptime_shell!("echo Build")),
+                block(comptime_shell!("echo Deploy?"))
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("key: build"));
+        assert!(yaml.contains("key: deploy"));
+    }
+}
+
+mod wait_for {
+    use super::*;
+
+    #[test]
+    fn expands_to_a_keyed_wait_with_depends_on() {
+        let pipeline = pipeline! {
+            steps: [
+                command(cmd!("cargo build")).key("build"),
+                command(cmd!("cargo clippy")).key("lint"),
+                wait_for(["build", "lint"]),
+                group {
+                    group: "Deploy",
+                    key: "deploy",
+                    steps: [
+                        command(cmd!("echo stage")).key("stage"),
+                        wait_for(["stage"]),
+                        command(cmd!("echo ship")).key("ship")
+                    ]
+                }
+            ]
+        };
+
+        let json = rust_buildkite::serde_json::to_value(&pipeline).unwrap();
+        let wait = &json["steps"][2];
+        assert_eq!(wait["key"], "wait-for-build-lint");
+        assert_eq!(
+            wait["depends_on"],
+            rust_buildkite::serde_json::json!(["build", "lint"])
+        );
+        let group_wait = &json["steps"][3]["steps"][1];
+        assert_eq!(group_wait["key"], "wait-for-stage");
+        assert_eq!(
+            group_wait["depends_on"],
+            rust_buildkite::serde_json::json!(["stage"])
+        );
+    }
+
+    #[test]
+    fn wait_object_takes_a_depends_on_list() {
+        let pipeline = pipeline! {
+            steps: [
+                command(cmd!("cargo build")).key("build"),
+                command(cmd!("cargo clippy")).key("lint"),
+                wait { depends_on: ["build", "lint"] }
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("- build\n"), "{}", yaml);
+        assert!(yaml.contains("- lint\n"), "{}", yaml);
+    }
+}
+
+mod meta_data_flow {
+    use super::*;
+
+    #[test]
+    fn reads_resolve_to_fields_sets_and_external_keys() {
+        let pipeline = pipeline! {
+            external_meta_data: ["upstream-sha"],
+            steps: [
+                input("Release details")
+                    .key("details")
+                    .field(text { key: "release-notes", text: "Notes" }),
+                metadata("release-channel", "stable"),
+                command(cmd!("echo \"$(buildkite-agent meta-data get release-notes)\""))
+                    .key("release")
+                    .depends_on("details"),
+                command(cmd!("buildkite-agent meta-data get release-channel"))
+                    .key("channel")
+                    .depends_on("set-metadata-release-channel"),
+                command(cmd!("buildkite-agent meta-data get --default HEAD upstream-sha"))
+                    .key("upstream")
+            ]
+        };
+
+        let yaml = serde_yaml::to_string(&pipeline).unwrap();
+        assert!(yaml.contains("key: release-notes"), "{}", yaml);
+        assert!(yaml.contains("meta-data get upstream-sha"), "{}", yaml);
+    }
+}
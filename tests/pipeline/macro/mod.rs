@@ -5,3 +5,6 @@ mod registry_test;
 
 #[cfg(feature = "bazel")]
 mod bazel_test;
+
+#[cfg(feature = "ir")]
+mod ir_test;
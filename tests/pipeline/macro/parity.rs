@@ -1,7 +1,8 @@
 //! Parity test between the pipeline! macro and the builder API
 //!
 //! This test constructs pipelines using both approaches and verifies
-//! they produce identical YAML output.
+//! they produce identical YAML output. The `emit_yaml` tests check, per step
+//! kind, that the file the macro writes matches the pipeline it builds.
 
 use rust_buildkite::*;
 
@@ -78,6 +79,7 @@ fn build_pipeline_with_builder() -> JsonSchemaForBuildkitePipelineConfigurationF
     );
     let wait_step = PipelineStepsItem::WaitStep(
         WaitStep::builder()
+            .wait(Some(String::new()))
             .continue_on_failure(true)
             .if_(If("build.branch == 'main'".to_string()))
             .try_into()
@@ -204,6 +206,7 @@ fn build_pipeline_with_macro() -> JsonSchemaForBuildkitePipelineConfigurationFil
             CI: "true",
             NODE_ENV: "test"
         },
+        lints: { parallelism_without_sharding: allow },
         steps: [
             command {
                 command: cmd!("echo test"),
@@ -335,3 +338,288 @@ fn test_builder_and_macro_parity() {
         "Builder and macro must produce identical YAML"
     );
 }
+
+/// Maps and lists built through `rust_buildkite::helpers` serialize exactly
+/// like the open-coded builder equivalents, including runtime env values.
+#[test]
+fn test_helper_expansion_parity() {
+    let version = String::from("1.2.3");
+
+    let mut env_map = serde_json::Map::new();
+    env_map.insert(
+        "CI".to_string(),
+        serde_json::Value::String("true".to_string()),
+    );
+    let mut step_env = serde_json::Map::new();
+    step_env.insert(
+        "VERSION".to_string(),
+        serde_json::Value::String(version.clone()),
+    );
+    step_env.insert(
+        "MODE".to_string(),
+        serde_json::Value::String("release".to_string()),
+    );
+    let mut agents = serde_json::Map::new();
+    agents.insert(
+        "queue".to_string(),
+        serde_json::Value::String("linux".to_string()),
+    );
+    agents.insert(
+        "arch".to_string(),
+        serde_json::Value::String("arm64".to_string()),
+    );
+
+    let builder_pipeline: JsonSchemaForBuildkitePipelineConfigurationFiles =
+        JsonSchemaForBuildkitePipelineConfigurationFiles::builder()
+            .env(Some(Env(env_map)))
+            .steps(PipelineSteps(vec![
+                PipelineStepsItem::CommandStep(
+                    CommandStep::builder()
+                        .command(Some(CommandStepCommand::String("echo a".to_string())))
+                        .key(Some("a".to_string().try_into().expect("key")))
+                        .try_into()
+                        .expect("step a"),
+                ),
+                PipelineStepsItem::CommandStep(
+                    CommandStep::builder()
+                        .command(Some(CommandStepCommand::String("echo b".to_string())))
+                        .key(Some("b".to_string().try_into().expect("key")))
+                        .try_into()
+                        .expect("step b"),
+                ),
+                PipelineStepsItem::CommandStep(
+                    CommandStep::builder()
+                        .command(Some(CommandStepCommand::String("echo c".to_string())))
+                        .key(Some("c".to_string().try_into().expect("key")))
+                        .depends_on(Some(DependsOn::DependsOnList(DependsOnList(vec![
+                            DependsOnListItem::String("a".to_string()),
+                            DependsOnListItem::String("b".to_string()),
+                        ]))))
+                        .env(Some(Env(step_env)))
+                        .agents(Some(Agents::Object(AgentsObject(agents))))
+                        .branches(Some(Branches::Array(vec!["main".to_string()])))
+                        .try_into()
+                        .expect("step c"),
+                ),
+            ]))
+            .try_into()
+            .expect("pipeline construction failed");
+
+    let macro_pipeline = pipeline! {
+        env: { CI: "true" },
+        steps: [
+            command(cmd!("echo a")).key("a"),
+            command(cmd!("echo b")).key("b"),
+            command {
+                command: cmd!("echo c"),
+                key: "c",
+                depends_on: ["a", "b"],
+                env: { VERSION: runtime!(version), MODE: "release" },
+                agents: { queue: "linux", arch: "arm64" },
+                branches: ["main"]
+            }
+        ]
+    };
+
+    assert_eq!(
+        serde_yaml::to_string(&builder_pipeline).unwrap(),
+        serde_yaml::to_string(&macro_pipeline).unwrap()
+    );
+}
+
+/// Read an `emit_yaml` file back through the schema types and check it
+/// serializes exactly like the pipeline the macro built. Reading it back
+/// fills in schema defaults such as `blocked_state` the same way on both
+/// sides, so any difference is a field `emit_yaml` wrote differently.
+fn assert_emitted_parity(file: &str, built: &JsonSchemaForBuildkitePipelineConfigurationFiles) {
+    let emitted =
+        std::fs::read_to_string(std::path::Path::new(env!("OUT_DIR")).join(file)).unwrap();
+    let parsed: JsonSchemaForBuildkitePipelineConfigurationFiles =
+        serde_yaml::from_str(&emitted).unwrap();
+    assert_eq!(
+        serde_yaml::to_string(&parsed).unwrap(),
+        serde_yaml::to_string(built).unwrap(),
+        "emit_yaml wrote:\n{}",
+        emitted
+    );
+}
+
+#[test]
+fn test_emit_yaml_command_parity() {
+    let built = pipeline! {
+        emit_yaml: "parity_command.yml",
+        env: { CI: "true" },
+        lints: { parallelism_without_sharding: allow },
+        steps: [
+            command(cmd!("echo build")).key("build"),
+            command {
+                commands: [cmd!("echo test"), cmd!("echo done")],
+                label: "Run Tests",
+                key: "test",
+                depends_on: ["build"],
+                env: { DEBUG: "1" },
+                agents: { queue: "default" },
+                branches: ["main", "develop"],
+                cache: ["node_modules"],
+                condition: "build.branch == 'main'",
+                timeout_in_minutes: 30,
+                soft_fail: true,
+                parallelism: 4,
+                artifact_paths: ["coverage/**/*"],
+                concurrency: 2,
+                concurrency_group: "test/main",
+                priority: 5,
+                allow_dependency_failure: true,
+                retry: {
+                    automatic: { limit: 3 },
+                    manual: { allowed: true }
+                },
+                plugins: [
+                    { "docker#v5.0.0": { image: "node:18" } }
+                ],
+                notify: [
+                    { slack: "#builds" }
+                ]
+            }
+        ]
+    };
+    assert_emitted_parity("parity_command.yml", &built);
+}
+
+#[test]
+fn test_emit_yaml_wait_parity() {
+    let built = pipeline! {
+        emit_yaml: "parity_wait.yml",
+        steps: [
+            command(cmd!("echo lint")).key("lint"),
+            wait,
+            wait {
+                key: "gate",
+                continue_on_failure: true,
+                r#if: "build.branch == 'main'",
+                branches: ["main"],
+                allow_dependency_failure: true
+            },
+            wait_for(["lint", "gate"])
+        ]
+    };
+    assert_emitted_parity("parity_wait.yml", &built);
+}
+
+#[test]
+fn test_emit_yaml_block_parity() {
+    let built = pipeline! {
+        emit_yaml: "parity_block.yml",
+        steps: [
+            command(cmd!("echo test")).key("test"),
+            block {
+                block: "Deploy to Production?",
+                key: "approval",
+                depends_on: ["test"],
+                allowed_teams: ["platform-team"],
+                blocked_state: "running",
+                branches: ["main"],
+                r#if: "build.branch == 'main'",
+                prompt: "Are you sure?",
+                allow_dependency_failure: true,
+                fields: [
+                    text { key: "release", text: "Release name", required: true },
+                    select {
+                        key: "region",
+                        select: "Region",
+                        options: [
+                            { label: "US", value: "us" },
+                            { label: "EU", value: "eu" }
+                        ]
+                    }
+                ]
+            },
+            command(cmd!(r#"echo "$(buildkite-agent meta-data get release) $(buildkite-agent meta-data get region)""#))
+                .depends_on("approval")
+        ]
+    };
+    assert_emitted_parity("parity_block.yml", &built);
+}
+
+#[test]
+fn test_emit_yaml_input_parity() {
+    let built = pipeline! {
+        emit_yaml: "parity_input.yml",
+        steps: [
+            input {
+                input: "Enter configuration",
+                key: "config",
+                allowed_teams: ["admins"],
+                blocked_state: "running",
+                branches: ["main"],
+                r#if: "build.branch == 'main'",
+                prompt: "Fill in form",
+                fields: [
+                    text { key: "target", hint: "Host to deploy to", default: "staging" }
+                ]
+            },
+            command(cmd!(r#"echo "$(buildkite-agent meta-data get target)""#))
+                .depends_on("config")
+        ]
+    };
+    assert_emitted_parity("parity_input.yml", &built);
+}
+
+#[test]
+fn test_emit_yaml_trigger_parity() {
+    let built = pipeline! {
+        emit_yaml: "parity_trigger.yml",
+        steps: [
+            command(cmd!("echo build")).key("build"),
+            trigger {
+                trigger: "deploy-pipeline",
+                label: "Deploy",
+                key: "deploy",
+                depends_on: ["build"],
+                r#async: true,
+                build: {
+                    branch: "main",
+                    commit: "HEAD",
+                    message: "Auto deploy",
+                    env: { TARGET: "production" },
+                    meta_data: { deploy_id: "123" }
+                },
+                branches: ["main"],
+                r#if: "build.branch == 'main'",
+                soft_fail: true,
+                allow_dependency_failure: true
+            }
+        ]
+    };
+    assert_emitted_parity("parity_trigger.yml", &built);
+}
+
+#[test]
+fn test_emit_yaml_group_parity() {
+    let built = pipeline! {
+        emit_yaml: "parity_group.yml",
+        steps: [
+            command(cmd!("echo build")).key("build"),
+            group {
+                group: "Test Suite",
+                key: "suite",
+                depends_on: ["build"],
+                steps: [
+                    command {
+                        command: cmd!("npm run integration"),
+                        label: "Integration Tests",
+                        key: "integration"
+                    },
+                    wait,
+                    command(cmd!("npm run e2e")).key("e2e")
+                ],
+                r#if: "build.branch == 'main'",
+                notify: [
+                    { slack: "#results" }
+                ],
+                allow_dependency_failure: true
+            }
+        ]
+    };
+    assert_emitted_parity("parity_group.yml", &built);
+}
@@ -8,21 +8,92 @@ fn ui_tests() {
     let t = trybuild::TestCases::new();
 
     t.compile_fail("tests/ui/cmd_not_in_allowlist.rs");
+    t.compile_fail("tests/ui/cmd_close_to_builtin.rs");
+    t.compile_fail("tests/ui/cmd_placeholder_command_name.rs");
     t.compile_fail("tests/ui/missing_absolute_path.rs");
     t.compile_fail("tests/ui/missing_relative_path.rs");
     t.compile_fail("tests/ui/missing_implicit_relative_path.rs");
     t.compile_fail("tests/ui/raw_string_rejected.rs");
+    t.compile_fail("tests/ui/unchecked_cmd_without_opt_in.rs");
     t.compile_fail("tests/ui/undefined_env_var.rs");
+    t.compile_fail("tests/ui/env_macro_unset_var.rs");
     t.compile_fail("tests/ui/undefined_variable.rs");
+    t.compile_fail("tests/ui/skip_validation_without_reason.rs");
     t.compile_fail("tests/ui/register_on_struct.rs");
     t.compile_fail("tests/ui/register_unknown_attr.rs");
+    t.compile_fail("tests/ui/emit_yaml_dynamic_label.rs");
+    t.compile_fail("tests/ui/trigger_meta_data_nested.rs");
+    t.compile_fail("tests/ui/trigger_build_env_typo.rs");
+    t.compile_fail("tests/ui/allowed_teams_invalid_slug.rs");
+    t.compile_fail("tests/ui/allowed_teams_empty.rs");
+    t.compile_fail("tests/ui/key_prefix_duplicate.rs");
+    t.compile_fail("tests/ui/notify_on_trigger.rs");
+    t.compile_fail("tests/ui/notify_email_on_group.rs");
+    t.compile_fail("tests/ui/trigger_priority_unsupported.rs");
+    t.compile_fail("tests/ui/group_branches_unsupported.rs");
+    t.compile_fail("tests/ui/retry_preset_with_explicit_retry.rs");
+    t.compile_fail("tests/ui/needs_artifacts_unmatched.rs");
+    t.compile_fail("tests/ui/needs_artifacts_unknown_key.rs");
+    t.compile_fail("tests/ui/needs_artifacts_prefixed_unmatched.rs");
+    t.compile_fail("tests/ui/profile_undeclared.rs");
+    t.compile_fail("tests/ui/lazy_runtime_key.rs");
+    t.compile_fail("tests/ui/auto_key_collision.rs");
+    t.compile_fail("tests/ui/lint_redundant_depends_on.rs");
+    t.compile_fail("tests/ui/lint_bazel_test_timeout.rs");
+    t.compile_fail("tests/ui/step_limit_exceeded.rs");
+    t.compile_fail("tests/ui/parallelism_out_of_range.rs");
+    t.compile_fail("tests/ui/parallelism_with_matrix.rs");
+    t.compile_fail("tests/ui/concurrency_group_policy.rs");
+    t.compile_fail("tests/ui/parallel_job_without_parallelism.rs");
+    t.compile_fail("tests/ui/timeout_zero.rs");
+    t.compile_fail("tests/ui/label_unknown_var.rs");
+    t.compile_fail("tests/ui/label_malformed_interpolation.rs");
+    t.compile_fail("tests/ui/unreachable_condition.rs");
+    t.compile_fail("tests/ui/step_attribute_not_doc.rs");
+    t.compile_fail("tests/ui/cargo_helper_exclusive_options.rs");
+    t.compile_fail("tests/ui/skip_if_with_explicit_if.rs");
+    t.compile_fail("tests/ui/shadowed_env.rs");
+    t.compile_fail("tests/ui/invalid_env_name.rs");
+    t.compile_fail("tests/ui/unknown_field_suggestion.rs");
+    t.compile_fail("tests/ui/multiple_errors.rs");
+    t.compile_fail("tests/ui/duplicate_key_across_groups.rs");
+    t.compile_fail("tests/ui/comptime_shell_failure.rs");
+    t.compile_fail("tests/ui/comptime_shell_multiline.rs");
+    t.compile_fail("tests/ui/runtime_command_unacknowledged.rs");
+    t.compile_fail("tests/ui/retry_bare_ident.rs");
+    t.compile_fail("tests/ui/retry_unknown_field.rs");
+    t.compile_fail("tests/ui/defaults_retry_unknown_field.rs");
+    t.compile_fail("tests/ui/defaults_retry_not_object.rs");
+    t.compile_fail("tests/ui/shell_unknown.rs");
+    t.compile_fail("tests/ui/skip_reason_computed_too_long.rs");
+    t.compile_fail("tests/ui/trigger_build_commit_ref.rs");
+    t.compile_fail("tests/ui/wait_for_unknown_key.rs");
+    t.compile_fail("tests/ui/unkeyed_block_fields.rs");
+    t.compile_fail("tests/ui/meta_data_unknown_key.rs");
 
     #[cfg(feature = "bazel")]
     {
         t.compile_fail("tests/ui/bazel_empty_command.rs");
         t.compile_fail("tests/ui/bazel_invalid_verb.rs");
         t.compile_fail("tests/ui/bazel_invalid_target_pattern.rs");
+        t.compile_fail("tests/ui/bazel_flag_wrong_verb.rs");
+        t.compile_fail("tests/ui/bazel_startup_flag_after_verb.rs");
+        t.compile_fail("tests/ui/bazel_unknown_startup_option.rs");
+        t.compile_fail("tests/ui/bazel_query_unbalanced.rs");
+        t.compile_fail("tests/ui/bazel_run_wildcard.rs");
+        t.compile_fail("tests/ui/bazel_shards_wrong_verb.rs");
+        t.compile_fail("tests/ui/bazel_defaults_wrong_verb.rs");
+        t.compile_fail("tests/ui/bazel_remote_cache_bad_url.rs");
+        t.compile_fail("tests/ui/bazel_platforms_bad_label.rs");
+        t.compile_fail("tests/ui/bazel_exclusive_configs.rs");
+        t.compile_fail("tests/ui/bazel_nobuild_test.rs");
         t.pass("tests/ui/bazel_comptime_const.rs");
         t.pass("tests/ui/bazel_runtime_skips_validation.rs");
     }
+
+    #[cfg(not(feature = "bazel"))]
+    {
+        t.compile_fail("tests/ui/bazel_step_without_feature.rs");
+        t.compile_fail("tests/ui/custom_verbs_without_feature.rs");
+    }
 }
@@ -0,0 +1,15 @@
+// This test verifies that an empty allowed_teams list is rejected, since it
+// would leave nobody able to unblock the step
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            input {
+                input: "Release notes",
+                allowed_teams: []
+            }
+        ]
+    };
+}
@@ -0,0 +1,11 @@
+// This test verifies that allowed_teams entries must be Buildkite team slugs
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            block("Deploy?").allowed_teams(["admins", "Platform Team"])
+        ]
+    };
+}
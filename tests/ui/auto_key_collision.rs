@@ -0,0 +1,13 @@
+// This test verifies that a key derived from a label may not collide with an explicit key
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        auto_keys: true,
+        steps: [
+            command(cmd!("echo a")).key("build"),
+            command(cmd!("echo b")).label("Build")
+        ]
+    };
+}
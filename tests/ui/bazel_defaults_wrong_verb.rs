@@ -0,0 +1,12 @@
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        bazel_defaults: {
+            build: ["--config=remote", "--test_output=errors"]
+        },
+        steps: [
+            bazel_build { target_patterns: "//...", key: "build" }
+        ]
+    };
+}
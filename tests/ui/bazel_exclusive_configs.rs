@@ -0,0 +1,13 @@
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        bazel_exclusive_configs: [["remote", "local"]],
+        steps: [
+            command {
+                command: bazel!("build --config=remote --config=local //..."),
+                label: "build"
+            }
+        ]
+    };
+}
@@ -0,0 +1,13 @@
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            bazel_build {
+                target_patterns: "//...",
+                flags: ["--jobs=4", "--test_filter=Foo"],
+                label: "build"
+            }
+        ]
+    };
+}
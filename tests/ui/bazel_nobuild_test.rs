@@ -0,0 +1,12 @@
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            command {
+                command: bazel!("test --nobuild //..."),
+                label: "test"
+            }
+        ]
+    };
+}
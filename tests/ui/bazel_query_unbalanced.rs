@@ -0,0 +1,12 @@
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            bazel_query {
+                target_patterns: "deps(//app:all",
+                label: "deps"
+            }
+        ]
+    };
+}
@@ -0,0 +1,13 @@
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            bazel_build {
+                target_patterns: "//...",
+                key: "build",
+                flags: ["--config=ci", "--remote_cache=cache.example.com"]
+            }
+        ]
+    };
+}
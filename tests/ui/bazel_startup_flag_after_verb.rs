@@ -0,0 +1,12 @@
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            command {
+                command: bazel!("build --output_base=/tmp/out //..."),
+                label: "build"
+            }
+        ]
+    };
+}
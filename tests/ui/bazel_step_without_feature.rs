@@ -0,0 +1,13 @@
+// This test verifies that bazel steps without the bazel feature point at Cargo.toml
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            bazel_test {
+                target_patterns: "//..."
+            }
+        ]
+    };
+}
@@ -0,0 +1,11 @@
+// This test verifies that cargo helpers reject options cargo won't accept together
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            command(cargo_test!(all_features: true, no_default_features: true)).key("test")
+        ]
+    };
+}
@@ -0,0 +1,12 @@
+// This test verifies that a command close to a shell builtin suggests the builtin
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        allowed_commands: ["make"],
+        steps: [
+            command(cmd!("exprt FOO=1")).key("build")
+        ]
+    };
+}
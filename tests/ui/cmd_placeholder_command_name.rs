@@ -0,0 +1,7 @@
+// This test verifies that a placeholder can't be used as the command name
+
+use rust_buildkite::cmd;
+
+fn main() {
+    let _c = cmd!("{TOOL} s3 ls");
+}
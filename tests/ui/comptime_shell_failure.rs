@@ -0,0 +1,16 @@
+// This test verifies that a failing comptime_shell! inside a step env value is a
+// compile error at the command, not a proc macro panic
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            command {
+                command: cmd!("echo hello"),
+                key: "hello",
+                env: { VERSION: comptime_shell!("echo 'no version file' >&2; exit 3") }
+            }
+        ]
+    };
+}
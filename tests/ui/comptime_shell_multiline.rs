@@ -0,0 +1,16 @@
+// This test verifies that comptime_shell! output with more than one line is a
+// compile error quoting what was printed, since `expect` defaults to a single line
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            command {
+                command: cmd!("echo hello"),
+                key: "hello",
+                env: { VERSION: comptime_shell!("printf 'a\nb\n'") }
+            }
+        ]
+    };
+}
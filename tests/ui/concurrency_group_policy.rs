@@ -0,0 +1,12 @@
+// This test verifies that concurrency groups must follow concurrency_group_policy
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        concurrency_group_policy: { require_var: "BUILDKITE_PIPELINE_SLUG" },
+        steps: [
+            command(cmd!("echo deploy")).concurrency(1).concurrency_group("deploy")
+        ]
+    };
+}
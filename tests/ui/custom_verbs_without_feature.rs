@@ -0,0 +1,12 @@
+// This test verifies that custom_verbs without the bazel feature points at Cargo.toml
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        custom_verbs: ["mobile-install"],
+        steps: [
+            command(cmd!("echo hello")).key("hello")
+        ]
+    };
+}
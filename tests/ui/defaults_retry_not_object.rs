@@ -0,0 +1,20 @@
+// This test verifies that a retry default that isn't an object is an error
+// rather than being dropped
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            group {
+                group: "Tests",
+                defaults: {
+                    retry: 2
+                },
+                steps: [
+                    command { command: cmd!("cargo test"), key: "test" }
+                ]
+            }
+        ]
+    };
+}
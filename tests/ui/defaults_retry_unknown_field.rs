@@ -0,0 +1,20 @@
+// This test verifies that a retry default with a field other than automatic
+// or manual is an error rather than being dropped
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            group {
+                group: "Tests",
+                defaults: {
+                    retry: { automatc: { limit: 2 } }
+                },
+                steps: [
+                    command { command: cmd!("cargo test"), key: "test" }
+                ]
+            }
+        ]
+    };
+}
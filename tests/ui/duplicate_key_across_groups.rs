@@ -0,0 +1,14 @@
+// This test verifies that duplicate key errors point at both definitions, even across groups
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            group("Frontend")
+                .step(command(cmd!("npm test")).key("test")),
+            group("Backend")
+                .step(command(cmd!("cargo test")).key("test"))
+        ]
+    };
+}
@@ -0,0 +1,16 @@
+// This test verifies that emit_yaml rejects pipelines with values computed at runtime
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _label = String::from("dynamic");
+    let _pipeline = pipeline! {
+        emit_yaml: "emit_yaml_dynamic_label.yml",
+        steps: [
+            command {
+                command: cmd!("echo hello"),
+                label: _label
+            }
+        ]
+    };
+}
@@ -0,0 +1,14 @@
+// This test verifies that env! in a pipeline env block names a missing variable
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        env: {
+            GENERATOR_SHA: env!("RUST_BUILDKITE_TEST_UNSET_SHA")
+        },
+        steps: [
+            command(cmd!("echo hello")).key("hello")
+        ]
+    };
+}
@@ -0,0 +1,18 @@
+// This test verifies that branches on a group step gives a targeted error,
+// since the Buildkite schema doesn't allow branch filters on groups
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            group {
+                group: "Deploy",
+                branches: ["main"],
+                steps: [
+                    command { command: cmd!("make deploy") }
+                ]
+            }
+        ]
+    };
+}
@@ -0,0 +1,15 @@
+// This test verifies that env var names are checked once string keys allow
+// names that aren't Rust identifiers
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        env: {
+            "BUILD NUMBER": "1"
+        },
+        steps: [
+            command(cmd!("cargo test")).key("test")
+        ]
+    };
+}
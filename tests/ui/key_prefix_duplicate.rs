@@ -0,0 +1,14 @@
+// This test verifies that duplicate keys are detected after group key prefixes are applied
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            command(cmd!("echo a")).key("backend-build"),
+            group("Backend")
+                .key_prefix("backend-")
+                .step(command(cmd!("echo b")).key("build"))
+        ]
+    };
+}
@@ -0,0 +1,11 @@
+// This test verifies that an unterminated `${` in a step label is an error
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            command(cmd!("cargo build")).label("Deploy ${BUILDKITE_TAG")
+        ]
+    };
+}
@@ -0,0 +1,13 @@
+// This test verifies that a misspelled variable in a step label is an error
+// when the unknown_label_var lint is set to deny
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        lints: { unknown_label_var: deny },
+        steps: [
+            command(cmd!("cargo build")).label("Deploy $BUILDKITE_TAGG")
+        ]
+    };
+}
@@ -0,0 +1,14 @@
+// This test verifies that a lazy pipeline rejects runtime! values, since the
+// pipeline is built once and every later call would see the first value
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _key = "build";
+    let _pipeline = pipeline! {
+        lazy: true,
+        steps: [
+            command(cmd!("cargo build")).key(runtime!(_key))
+        ]
+    };
+}
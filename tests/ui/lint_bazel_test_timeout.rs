@@ -0,0 +1,16 @@
+// This test verifies that a bazel test step without any timeout is denied
+// when the bazel_test_timeout lint is set to deny
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        allowed_commands: ["bazel"],
+        lints: { bazel_test_timeout: deny },
+        steps: [
+            command(cmd!("bazel test --test_timeout=300 //lib/...")).key("lib"),
+            command(cmd!("bazel test //app/...")).key("app").timeout_in_minutes(30),
+            command(cmd!("bazel test //tools/...")).key("tools")
+        ]
+    };
+}
@@ -0,0 +1,15 @@
+// This test verifies that a depends_on edge already implied by a wait step is denied
+// when the redundant_depends_on lint is set to deny
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        lints: { redundant_depends_on: deny },
+        steps: [
+            command(cmd!("cargo build")).key("build"),
+            wait,
+            command(cmd!("cargo test")).key("test").depends_on("build")
+        ]
+    };
+}
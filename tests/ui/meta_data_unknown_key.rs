@@ -0,0 +1,17 @@
+// This test verifies that a command reading meta-data no field sets is an
+// error naming the step and the key, with a suggestion for a near miss
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            block("Release?")
+                .key("release")
+                .field(text { key: "release-version", text: "Version" }),
+            command(cmd!("echo \"$(buildkite-agent meta-data get release-versoin)\""))
+                .key("deploy")
+                .depends_on("release")
+        ]
+    };
+}
@@ -0,0 +1,13 @@
+// This test verifies that validation errors are reported together instead of one at a time
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            command(cmd!("echo a")).key("build"),
+            command(cmd!("echo b")).key("build"),
+            command(cmd!("echo c")).depends_on("missing")
+        ]
+    };
+}
@@ -0,0 +1,16 @@
+// This test verifies that needs_artifacts inside a key_prefix group is checked
+// against the prefixed key of the step it names
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        allowed_commands: ["make"],
+        steps: [
+            group("Backend")
+                .key_prefix("backend-")
+                .step(command(cmd!("make coverage")).key("build").artifact_paths("coverage/**"))
+                .step(command(cmd!("make deploy")).needs_artifacts("build", "dist/**"))
+        ]
+    };
+}
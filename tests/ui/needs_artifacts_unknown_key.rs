@@ -0,0 +1,13 @@
+// This test verifies that needs_artifacts must name an existing step key
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        allowed_commands: ["make"],
+        steps: [
+            command(cmd!("make dist")).key("build").artifact_paths("dist/**"),
+            command(cmd!("make deploy")).needs_artifacts("biuld", "dist/**")
+        ]
+    };
+}
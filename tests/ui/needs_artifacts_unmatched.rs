@@ -0,0 +1,14 @@
+// This test verifies that needs_artifacts requires the named step to upload
+// artifacts matching the requested glob
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        allowed_commands: ["make"],
+        steps: [
+            command(cmd!("make coverage")).key("build").artifact_paths("coverage/**"),
+            command(cmd!("make deploy")).needs_artifacts("build", "dist/**")
+        ]
+    };
+}
@@ -0,0 +1,16 @@
+// This test verifies that group steps reject notification kinds that
+// Buildkite only accepts at the build level
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            group {
+                group: "Deploy",
+                steps: [command(cmd!("echo deploy"))],
+                notify: [{ email: "team@example.com" }]
+            }
+        ]
+    };
+}
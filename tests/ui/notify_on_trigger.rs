@@ -0,0 +1,15 @@
+// This test verifies that notify on a trigger step gives a targeted error,
+// since the Buildkite schema only allows notify on command and group steps
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            trigger {
+                trigger: "deploy-pipeline",
+                notify: [{ slack: "#deploys" }]
+            }
+        ]
+    };
+}
@@ -0,0 +1,13 @@
+// This test verifies that reading BUILDKITE_PARALLEL_JOB on a step without
+// parallelism is an error, since Buildkite leaves the variable empty
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        expect_env: [BUILDKITE_ENV],
+        steps: [
+            command(cmd!("echo \"$BUILDKITE_PARALLEL_JOB\"")).key("test")
+        ]
+    };
+}
@@ -0,0 +1,14 @@
+// This test verifies that parallelism: 1 is rejected since it has no effect
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            command {
+                command: cmd!("cargo test"),
+                parallelism: 1
+            }
+        ]
+    };
+}
@@ -0,0 +1,13 @@
+// This test verifies that parallelism combined with matrix is an error
+// when the parallelism_with_matrix lint is set to deny
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        lints: { parallelism_with_matrix: deny, parallelism_without_sharding: allow },
+        steps: [
+            command(cmd!("cargo test")).matrix(["linux", "macos"]).parallelism(4)
+        ]
+    };
+}
@@ -0,0 +1,17 @@
+// This test verifies that selecting a profile that isn't declared under
+// profiles is rejected and the declared profiles are listed
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        profiles: {
+            pr: { env: { FAST: "1" } },
+            main: { env: { PUBLISH: "1" } }
+        },
+        profile: "release",
+        steps: [
+            command(cmd!("cargo test")).key("test")
+        ]
+    };
+}
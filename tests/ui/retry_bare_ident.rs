@@ -0,0 +1,14 @@
+// This test verifies that a bare identifier in a retry config is an error
+// rather than being read as a string, catching typos like `ture` for `true`
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            command(cmd!("cargo test"))
+                .key("test")
+                .retry({ manual: { allowed: ture } })
+        ]
+    };
+}
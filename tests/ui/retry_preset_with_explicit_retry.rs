@@ -0,0 +1,14 @@
+// This test verifies that retry presets can't be mixed with an explicit retry,
+// since it would be ambiguous which rules apply
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            command(cmd!("npm test"))
+                .retry_automatic(3)
+                .retry_flaky(2)
+        ]
+    };
+}
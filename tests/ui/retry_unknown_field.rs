@@ -0,0 +1,14 @@
+// This test verifies that an unknown field in a step's retry is an error
+// rather than being dropped
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            command(cmd!("cargo test"))
+                .key("test")
+                .retry({ manul: { allowed: false } })
+        ]
+    };
+}
@@ -0,0 +1,12 @@
+// This test verifies that a runtime! command is a compile error unless its
+// step acknowledges the skipped checks with skip_validation("reason")
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            command(runtime!(format!("./deploy.sh {}", "staging"))).key("deploy")
+        ]
+    };
+}
@@ -0,0 +1,18 @@
+// This test verifies that step env entries overriding or repeating a pipeline
+// env value are errors when the shadowed_env lint is set to deny
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        lints: { shadowed_env: deny },
+        env: {
+            RUST_LOG: "info",
+            CI: "true"
+        },
+        steps: [
+            command(cmd!("cargo test")).key("test").env(RUST_LOG, "debug"),
+            group("Lint").step(command(cmd!("cargo clippy")).key("clippy").env(CI, "true"))
+        ]
+    };
+}
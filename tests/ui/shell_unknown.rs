@@ -0,0 +1,12 @@
+// This test verifies that shell only accepts "sh" or "bash", and that the
+// error says it doesn't change which shell runs the command
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            command(cmd!("cargo test")).key("test").shell("zsh")
+        ]
+    };
+}
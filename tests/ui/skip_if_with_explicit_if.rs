@@ -0,0 +1,14 @@
+// This test verifies that skip_if can't be combined with an explicit `if`,
+// since both would set the same field with contradictory conditions
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            command(cmd!("npm test"))
+                .r#if("build.branch == 'main'")
+                .skip_if("build.pull_request.draft == true")
+        ]
+    };
+}
@@ -0,0 +1,15 @@
+// This test verifies that a skip reason computed by comptime_shell! is
+// checked against Buildkite's 70 character limit, and that the error says
+// the value was computed and quotes it
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            command(cmd!("cargo test"))
+                .key("test")
+                .skip(comptime_shell!("printf 'flaky on arm64 until the runner image is rebuilt with the new toolchain'"))
+        ]
+    };
+}
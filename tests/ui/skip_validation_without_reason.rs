@@ -0,0 +1,11 @@
+// This test verifies that skip_validation requires a reason
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            command(cmd!("gpu-burn 60")).key("burn").skip_validation()
+        ]
+    };
+}
@@ -0,0 +1,13 @@
+// This test verifies that only doc comments are accepted before a step;
+// other attributes would suggest conditional compilation that never happens
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            #[cfg(test)]
+            command(cmd!("cargo test")).key("test")
+        ]
+    };
+}
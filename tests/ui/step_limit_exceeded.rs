@@ -0,0 +1,14 @@
+// This test verifies that the expanded step count is checked against max_steps
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        lints: { max_steps: 4, parallelism_without_sharding: allow },
+        steps: [
+            command(cmd!("cargo test")).key("unit").parallelism(3),
+            command(cmd!("cargo build")).key("matrix").matrix(["linux", "macos"]),
+            command(cmd!("echo done")).key("done")
+        ]
+    };
+}
@@ -0,0 +1,11 @@
+// This test verifies that timeout_in_minutes: 0 is rejected at compile time
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            command(cmd!("cargo test")).timeout_in_minutes(0)
+        ]
+    };
+}
@@ -0,0 +1,17 @@
+// This test verifies that a trigger build commit Buildkite can't resolve,
+// such as a relative ref, is a compile error at the literal
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            trigger {
+                trigger: "deploy-service",
+                build: {
+                    commit: "HEAD~1"
+                }
+            }
+        ]
+    };
+}
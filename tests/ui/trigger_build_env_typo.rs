@@ -0,0 +1,18 @@
+// This test verifies that misspelled env var references in trigger build
+// fields produce a compile error with a suggestion
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        expect_env: ["DEPLOY_TARGET"],
+        steps: [
+            trigger {
+                trigger: "deploy-service",
+                build: {
+                    commit: "$BUILDKITE_COMIT"
+                }
+            }
+        ]
+    };
+}
@@ -0,0 +1,17 @@
+// This test verifies that nested objects in trigger build meta_data are rejected,
+// since Buildkite meta-data values are scalars
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            trigger {
+                trigger: "deploy-service",
+                build: {
+                    meta_data: { config: { region: "us-east-1" } }
+                }
+            }
+        ]
+    };
+}
@@ -0,0 +1,12 @@
+// This test verifies that priority on a trigger step gives a targeted error,
+// since the Buildkite schema only allows priority on command steps
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            trigger("deploy-pipeline").priority(5)
+        ]
+    };
+}
@@ -0,0 +1,11 @@
+// This test verifies that unchecked_cmd! requires allow_unchecked_commands on the pipeline
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            command(unchecked_cmd!("echo 'unbalanced")).key("echo")
+        ]
+    };
+}
@@ -0,0 +1,18 @@
+// This test verifies that a block step without a key is flagged when a later
+// step reads its fields, since nothing can make that step wait for the unblock
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        lints: { unkeyed_block_fields: deny },
+        steps: [
+            command(cmd!("make release")).key("build"),
+            block {
+                block: "Release?",
+                fields: [text { key: "release-name", text: "Release name" }]
+            },
+            command(cmd!("echo \"$(buildkite-agent meta-data get release-name)\"")).key("deploy")
+        ]
+    };
+}
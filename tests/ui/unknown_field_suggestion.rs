@@ -0,0 +1,15 @@
+// This test verifies that a misspelled step field names the closest valid one
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            command {
+                command: cmd!("cargo test"),
+                key: "test",
+                timout_in_minutes: 10
+            }
+        ]
+    };
+}
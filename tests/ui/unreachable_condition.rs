@@ -0,0 +1,14 @@
+// This test verifies that a step condition contradicting pipeline_context is
+// an error when the unreachable_condition lint is set to deny
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        pipeline_context: { branches: ["main"] },
+        lints: { unreachable_condition: deny },
+        steps: [
+            command(cmd!("make deploy")).key("deploy").r#if("build.branch == 'master'")
+        ]
+    };
+}
@@ -0,0 +1,14 @@
+// This test verifies that wait_for checks the keys it waits for like any
+// other depends_on
+
+use rust_buildkite::pipeline;
+
+fn main() {
+    let _pipeline = pipeline! {
+        steps: [
+            command(cmd!("cargo build")).key("build"),
+            wait_for(["build", "lnit"]),
+            command(cmd!("cargo test")).key("test")
+        ]
+    };
+}